@@ -1,7 +1,7 @@
 use std::error::Error as StdError;
 use std::io::{self, ErrorKind};
 
-/// KCP protocol errors
+/// KCP protocol and session errors
 #[derive(Debug, thiserror::Error)]
 pub enum Error {
     #[error("conv inconsistent, expected {0}, found {1}")]
@@ -30,6 +30,16 @@ pub enum Error {
     UserBufTooBig,
     #[error("user's recv buffer is too small")]
     UserBufTooSmall,
+    #[error("peer didn't answer the handshake")]
+    HandshakeTimeout,
+    #[error("peer unreachable, retransmissions exhausted")]
+    PeerUnreachable,
+    #[error("invalid config: {0}")]
+    InvalidConfig(String),
+    #[error("session is closed")]
+    Closed,
+    #[error("peer closed the session")]
+    PeerClosed,
 }
 
 fn make_io_error<T>(kind: ErrorKind, msg: T) -> io::Error
@@ -53,6 +63,11 @@ impl From<Error> for io::Error {
             Error::UnsupportedCmd(..) => ErrorKind::Other,
             Error::UserBufTooBig => ErrorKind::Other,
             Error::UserBufTooSmall => ErrorKind::Other,
+            Error::HandshakeTimeout => ErrorKind::TimedOut,
+            Error::PeerUnreachable => ErrorKind::HostUnreachable,
+            Error::InvalidConfig(..) => ErrorKind::InvalidInput,
+            Error::Closed => ErrorKind::BrokenPipe,
+            Error::PeerClosed => ErrorKind::BrokenPipe,
         };
 
         make_io_error(kind, err)
@@ -4,7 +4,7 @@ use std::cmp;
 use std::cmp::Ordering;
 use std::collections::VecDeque;
 use std::fmt::{self, Debug};
-use std::io::{self, Cursor, Read, Write};
+use std::io::{self, Cursor, IoSlice, Read, Write};
 
 use bytes::{Buf, BufMut, BytesMut};
 
@@ -20,6 +20,14 @@ const KCP_CMD_PUSH: u8 = 81; // cmd: push data
 const KCP_CMD_ACK: u8 = 82; // cmd: ack
 const KCP_CMD_WASK: u8 = 83; // cmd: window probe (ask)
 const KCP_CMD_WINS: u8 = 84; // cmd: window size (tell)
+const KCP_CMD_DROP: u8 = 85; // cmd: push expired at its deadline, payload dropped
+const KCP_CMD_FIN: u8 = 86; // cmd: end of stream, nothing is sent after it
+
+// Compressed header: conv, flag, [frg], wnd, then ts, sn and una as 16-bit deltas, [len]
+const KCP_COMPRESSED: u8 = 0x80; // flag: compressed header, no cmd has the high bit set
+const KCP_COMPRESSED_ACK: u8 = 0x01; // flag: an ACK without len, a PUSH otherwise
+const KCP_COMPRESSED_FRG: u8 = 0x02; // flag: frg follows, 0 otherwise
+const KCP_CAP_HEADER_COMPRESSION: u8 = 0x01; // frg of window probes: compress towards the sender
 
 const KCP_ASK_SEND: u32 = 1; // need to send IKCP_CMD_WASK
 const KCP_ASK_TELL: u32 = 2; // need to send IKCP_CMD_WINS
@@ -27,12 +35,17 @@ const KCP_ASK_TELL: u32 = 2; // need to send IKCP_CMD_WINS
 const KCP_WND_SND: u16 = 32;
 const KCP_WND_RCV: u16 = 128; // must >= max fragment size
 
+/// Segments one message may take at most, `send` refuses longer ones
+pub const KCP_MAX_FRAGMENTS: usize = KCP_WND_RCV as usize - 1;
+
 const KCP_MTU_DEF: usize = 1400;
 // const KCP_ACK_FAST: u32 = 3;
 
 const KCP_INTERVAL: u32 = 100;
 /// KCP Header size
 pub const KCP_OVERHEAD: usize = 24;
+/// Smallest header, a compressed ACK
+pub const KCP_MIN_OVERHEAD: usize = 13;
 const KCP_DEADLINK: u32 = 20;
 
 const KCP_THRESH_INIT: u16 = 2;
@@ -42,22 +55,88 @@ const KCP_PROBE_INIT: u32 = 7000; // 7 secs to probe window size
 const KCP_PROBE_LIMIT: u32 = 120000; // up to 120 secs to probe window
 const KCP_FASTACK_LIMIT: u32 = 5; // max times to trigger fastack
 
+/// Segments seen by `input`: `(acked (sn, len), received push sn)`
+pub type InputResult = (Vec<(u32, usize)>, Vec<u32>);
+/// Segments touched by `flush`: `((retransmitted, lost sn), newly sent (sn, len))`
+pub type FlushResult = ((bool, Vec<u32>), Vec<(u32, usize)>);
+
+/// Header size of the first segment in raw buffer, `KCP_OVERHEAD` unless it is compressed
+pub fn header_len(buf: &[u8]) -> usize {
+    match buf.get(4) {
+        Some(&flag) if flag & KCP_COMPRESSED != 0 => compressed_header_len(flag),
+        _ => KCP_OVERHEAD,
+    }
+}
+
+fn compressed_header_len(flag: u8) -> usize {
+    let mut len = KCP_MIN_OVERHEAD;
+    if flag & KCP_COMPRESSED_ACK == 0 {
+        len += 2;
+    }
+    if flag & KCP_COMPRESSED_FRG != 0 {
+        len += 1;
+    }
+    len
+}
+
 /// Read `conv` from raw buffer
 pub fn get_conv(mut buf: &[u8]) -> u32 {
-    assert!(buf.len() >= KCP_OVERHEAD as usize);
+    assert!(buf.len() >= header_len(buf));
     buf.get_u32_le()
 }
 
 /// Set `conv` to raw buffer
 pub fn set_conv(mut buf: &mut [u8], conv: u32) {
-    assert!(buf.len() >= KCP_OVERHEAD as usize);
+    assert!(buf.len() >= header_len(buf));
     buf.put_u32_le(conv)
 }
 
-/// Get `sn` from raw buffer
+/// Get `sn` from raw buffer, only its lower 16 bits if the header is compressed
 pub fn get_sn(buf: &[u8]) -> u32 {
-    assert!(buf.len() >= KCP_OVERHEAD as usize);
-    (&buf[12..]).get_u32_le()
+    assert!(buf.len() >= header_len(buf));
+    let flag = buf[4];
+    if flag & KCP_COMPRESSED == 0 {
+        return (&buf[12..]).get_u32_le();
+    }
+    let offset = if flag & KCP_COMPRESSED_FRG != 0 { 10 } else { 9 };
+    (&buf[offset..]).get_u16_le() as u32
+}
+
+/// Whether every segment in raw buffer is an ACK or a window probe, carrying no stream data
+pub fn is_ack_only(mut buf: &[u8]) -> bool {
+    if buf.len() < KCP_MIN_OVERHEAD {
+        return false;
+    }
+    while buf.len() >= KCP_MIN_OVERHEAD {
+        let flag = buf[4];
+        if flag & KCP_COMPRESSED != 0 {
+            if flag & KCP_COMPRESSED_ACK == 0 {
+                return false;
+            }
+            buf = &buf[KCP_MIN_OVERHEAD..];
+            continue;
+        }
+        if buf.len() < KCP_OVERHEAD {
+            return false;
+        }
+        let len = (&buf[20..]).get_u32_le() as usize;
+        if !matches!(flag, KCP_CMD_ACK | KCP_CMD_WASK | KCP_CMD_WINS) || len > buf.len() - KCP_OVERHEAD {
+            return false;
+        }
+        buf = &buf[KCP_OVERHEAD + len..];
+    }
+    buf.is_empty()
+}
+
+// The value whose lower 16 bits are `low` nearest to `reference`
+#[inline]
+fn expand_delta(low: u16, reference: u32) -> u32 {
+    reference.wrapping_add(low.wrapping_sub(reference as u16) as i16 as u32)
+}
+
+#[inline]
+fn fits_delta(value: u32, reference: u32) -> bool {
+    (-0x8000..0x8000).contains(&timediff(value, reference))
 }
 
 #[inline]
@@ -67,7 +146,44 @@ fn bound(lower: u32, v: u32, upper: u32) -> u32 {
 
 #[inline]
 fn timediff(later: u32, earlier: u32) -> i32 {
-    later as i32 - earlier as i32
+    later.wrapping_sub(earlier) as i32
+}
+
+/// Reads the concatenation of `IoSlice`s front to back
+struct SliceCursor<'a, 'b> {
+    bufs: &'a [IoSlice<'b>],
+    offset: usize,
+    remaining: usize,
+}
+
+impl<'a, 'b> SliceCursor<'a, 'b> {
+    fn new(bufs: &'a [IoSlice<'b>]) -> Self {
+        SliceCursor {
+            bufs,
+            offset: 0,
+            remaining: bufs.iter().map(|b| b.len()).sum(),
+        }
+    }
+
+    fn remaining(&self) -> usize {
+        self.remaining
+    }
+
+    /// Append the next `n` bytes to `dst`
+    fn copy_to(&mut self, dst: &mut BytesMut, mut n: usize) {
+        while n > 0 {
+            let current = &self.bufs[0][self.offset..];
+            let take = cmp::min(n, current.len());
+            dst.extend_from_slice(&current[..take]);
+            n -= take;
+            self.remaining -= take;
+            self.offset += take;
+            if self.offset == self.bufs[0].len() {
+                self.bufs = &self.bufs[1..];
+                self.offset = 0;
+            }
+        }
+    }
 }
 
 #[derive(Default, Clone, Debug)]
@@ -83,6 +199,8 @@ struct KcpSegment {
     rto: u32,
     fastack: u32,
     xmit: u32,
+    /// Stop retransmitting the payload at this time
+    deadline: Option<u32>,
     data: BytesMut,
 }
 
@@ -100,6 +218,7 @@ impl KcpSegment {
             rto: 0,
             fastack: 0,
             xmit: 0,
+            deadline: None,
             data,
         }
     }
@@ -125,8 +244,47 @@ impl KcpSegment {
         buf.put_slice(&self.data);
     }
 
+    /// Compressed PUSH or ACK, for deltas the peer can expand against its own state
+    fn encode_compressed(&self, buf: &mut BytesMut) {
+        let mut flag = KCP_COMPRESSED;
+        if self.cmd == KCP_CMD_ACK {
+            flag |= KCP_COMPRESSED_ACK;
+        }
+        if self.frg != 0 {
+            flag |= KCP_COMPRESSED_FRG;
+        }
+        buf.put_u32_le(self.conv);
+        buf.put_u8(flag);
+        if self.frg != 0 {
+            buf.put_u8(self.frg);
+        }
+        buf.put_u16_le(self.wnd);
+        buf.put_u16_le(self.ts as u16);
+        if self.cmd == KCP_CMD_ACK {
+            // Relative to una, both are known to the sender
+            buf.put_u16_le(self.sn.wrapping_sub(self.una) as u16);
+            buf.put_u16_le(self.una as u16);
+        } else {
+            buf.put_u16_le(self.sn as u16);
+            buf.put_u16_le(self.una as u16);
+            buf.put_u16_le(self.data.len() as u16);
+            buf.put_slice(&self.data);
+        }
+    }
+
     fn encoded_len(&self) -> usize {
-        KCP_OVERHEAD as usize + self.data.len()
+        KCP_OVERHEAD + self.data.len()
+    }
+
+    fn compressed_len(&self) -> usize {
+        let mut len = KCP_MIN_OVERHEAD + self.data.len();
+        if self.cmd != KCP_CMD_ACK {
+            len += 2;
+        }
+        if self.frg != 0 {
+            len += 1;
+        }
+        len
     }
 }
 
@@ -176,6 +334,10 @@ pub struct Kcp<Output: Write> {
     rx_rto: u32,
     /// Minimal resend timeout
     rx_minrto: u32,
+    /// Maximal resend timeout
+    rx_maxrto: u32,
+    /// RTO growth of a retransmitted segment, `None` for KCP's own (x2, x1.5 with nodelay)
+    backoff: Option<f32>,
 
     /// Send window
     snd_wnd: u16,
@@ -197,6 +359,14 @@ pub struct Kcp<Output: Write> {
     /// Next flush interval
     ts_flush: u32,
     xmit: u32,
+    /// Segments sent again after enough later ones were acknowledged
+    fast_xmit: u32,
+    /// Bytes of segments sent again, after their RTO or a fast retransmit
+    retransmitted_bytes: u64,
+    /// Segments received ahead of a gap
+    out_of_order: u32,
+    /// ACKs of segments acknowledged before
+    dup_acks: u32,
 
     /// Enable nodelay
     nodelay: bool,
@@ -236,6 +406,26 @@ pub struct Kcp<Output: Write> {
     /// use external congestion control
     external_cc: bool,
 
+    /// ACKs wait for `flush_ack` instead of going out with every `flush`
+    delayed_ack: bool,
+    /// Longest time the peer holds an ACK, added to the RTO
+    peer_ack_delay: u32,
+
+    /// Compress PUSH and ACK headers once the peer asked for it
+    header_compression: bool,
+    /// The peer's window probes asked for compressed headers
+    rmt_header_compression: bool,
+    /// Latest `ts` of the peer's clock, the reference of compressed PUSHes
+    rmt_ts: Option<u32>,
+    /// Latest own `ts` echoed by the peer's ACKs, the peer's `rmt_ts` is at least that
+    ts_acked: Option<u32>,
+
+    /// Segments dropped at their deadline, since the last `take_expired`
+    expired: Vec<u32>,
+
+    /// The peer's FIN was reached in order
+    rcv_fin: bool,
+
     output: KcpOutput<Output>,
 }
 
@@ -254,6 +444,8 @@ impl<Output: Write> Debug for Kcp<Output> {
             .field("rx_srtt", &self.rx_srtt)
             .field("rx_rto", &self.rx_rto)
             .field("rx_minrto", &self.rx_minrto)
+            .field("rx_maxrto", &self.rx_maxrto)
+            .field("backoff", &self.backoff)
             .field("snd_wnd", &self.snd_wnd)
             .field("rcv_wnd", &self.rcv_wnd)
             .field("rmt_wnd", &self.rmt_wnd)
@@ -263,6 +455,9 @@ impl<Output: Write> Debug for Kcp<Output> {
             .field("interval", &self.interval)
             .field("ts_flush", &self.ts_flush)
             .field("xmit", &self.xmit)
+            .field("fast_xmit", &self.fast_xmit)
+            .field("out_of_order", &self.out_of_order)
+            .field("dup_acks", &self.dup_acks)
             .field("nodelay", &self.nodelay)
             .field("updated", &self.updated)
             .field("ts_probe", &self.ts_probe)
@@ -273,6 +468,7 @@ impl<Output: Write> Debug for Kcp<Output> {
             .field("rcv_queue.len", &self.rcv_queue.len())
             .field("snd_buf.len", &self.snd_buf.len())
             .field("rcv_buf.len", &self.rcv_buf.len())
+            .field("rcv_fin", &self.rcv_fin)
             .field("acklist.len", &self.acklist.len())
             .field("buf.len", &self.buf.len())
             .field("fastresend", &self.fastresend)
@@ -319,7 +515,7 @@ impl<Output: Write> Kcp<Output> {
             mss: KCP_MTU_DEF - KCP_OVERHEAD,
             stream,
 
-            buf: BytesMut::with_capacity((KCP_MTU_DEF + KCP_OVERHEAD) as usize * 3),
+            buf: BytesMut::with_capacity((KCP_MTU_DEF + KCP_OVERHEAD) * 3),
 
             snd_queue: VecDeque::new(),
             rcv_queue: VecDeque::new(),
@@ -334,6 +530,8 @@ impl<Output: Write> Kcp<Output> {
             rx_rttval: 0,
             rx_rto: KCP_RTO_DEF,
             rx_minrto: KCP_RTO_MIN,
+            rx_maxrto: KCP_RTO_MAX,
+            backoff: None,
 
             current: 0,
             interval: KCP_INTERVAL,
@@ -345,31 +543,77 @@ impl<Output: Write> Kcp<Output> {
             fastlimit: KCP_FASTACK_LIMIT,
             nocwnd: false,
             xmit: 0,
+            fast_xmit: 0,
+            retransmitted_bytes: 0,
+            out_of_order: 0,
+            dup_acks: 0,
             dead_link: KCP_DEADLINK,
 
             input_conv: false,
             output: KcpOutput(output),
 
-            external_cc: true,
+            external_cc: false,
+            delayed_ack: false,
+            peer_ack_delay: 0,
+            header_compression: false,
+            rmt_header_compression: false,
+            rmt_ts: None,
+            ts_acked: None,
+            expired: Vec::new(),
+            rcv_fin: false,
         }
     }
 
     // move available data from rcv_buf -> rcv_queue
     pub fn move_buf(&mut self) {
-        while !self.rcv_buf.is_empty() {
-            let nrcv_que = self.rcv_queue.len();
-            {
-                let seg = self.rcv_buf.front().unwrap();
-                if seg.sn == self.rcv_nxt && nrcv_que < self.rcv_wnd as usize {
-                    self.rcv_nxt += 1;
+        loop {
+            while !self.rcv_buf.is_empty() {
+                let nrcv_que = self.rcv_queue.len();
+                {
+                    let seg = self.rcv_buf.front().unwrap();
+                    // The FIN takes no room in rcv_queue
+                    if seg.sn == self.rcv_nxt && (nrcv_que < self.rcv_wnd as usize || seg.cmd == KCP_CMD_FIN) {
+                        self.rcv_nxt += 1;
+                    } else {
+                        break;
+                    }
+                }
+
+                let seg = self.rcv_buf.pop_front().unwrap();
+                if seg.cmd == KCP_CMD_FIN {
+                    trace!("recv fin sn={}", seg.sn);
+                    self.rcv_fin = true;
                 } else {
-                    break;
+                    self.rcv_queue.push_back(seg);
                 }
             }
 
-            let seg = self.rcv_buf.pop_front().unwrap();
-            self.rcv_queue.push_back(seg);
+            // Purged messages free room in rcv_queue
+            if !self.purge_dropped() {
+                break;
+            }
+        }
+    }
+
+    // remove complete messages with an expired fragment from rcv_queue
+    fn purge_dropped(&mut self) -> bool {
+        let mut purged = false;
+        let mut start = 0;
+        while start < self.rcv_queue.len() {
+            let end = match self.rcv_queue.iter().skip(start).position(|seg| seg.frg == 0) {
+                Some(n) => start + n,
+                None => break,
+            };
+
+            if self.rcv_queue.range(start..=end).any(|seg| seg.cmd == KCP_CMD_DROP) {
+                trace!("recv drop expired sn={}..={}", self.rcv_queue[start].sn, self.rcv_queue[end].sn);
+                self.rcv_queue.drain(start..=end);
+                purged = true;
+            } else {
+                start = end + 1;
+            }
         }
+        purged
     }
 
     /// Receive data from buffer
@@ -412,6 +656,52 @@ impl<Output: Write> Kcp<Output> {
         Ok(cur.position() as usize)
     }
 
+    /// Take the next message whole, with the number of segments it took in `rcv_queue`
+    ///
+    /// A message of one segment is handed over without a copy.
+    pub fn recv_message(&mut self) -> KcpResult<(BytesMut, usize)> {
+        let peeksize = self.peeksize()?;
+        let segments = self.rcv_queue.front().map_or(0, |seg| seg.frg as usize + 1);
+        let recover = self.rcv_queue.len() >= self.rcv_wnd as usize;
+
+        let message = if segments == 1 {
+            let seg = self.rcv_queue.pop_front().unwrap();
+            trace!("recv sn={}", seg.sn);
+            seg.data
+        } else {
+            let mut message = BytesMut::with_capacity(peeksize);
+            for seg in self.rcv_queue.drain(..segments) {
+                trace!("recv sn={}", seg.sn);
+                message.extend_from_slice(&seg.data);
+            }
+            message
+        };
+
+        self.move_buf();
+
+        if self.rcv_queue.len() < self.rcv_wnd as usize && recover {
+            self.probe |= KCP_ASK_TELL;
+        }
+
+        Ok((message, segments))
+    }
+
+    /// Copy the next message into `buf` without consuming it, bytes beyond `buf` are left out
+    pub fn peek(&self, buf: &mut [u8]) -> KcpResult<usize> {
+        self.peeksize()?;
+
+        let mut len = 0;
+        for seg in &self.rcv_queue {
+            let n = seg.data.len().min(buf.len() - len);
+            buf[len..len + n].copy_from_slice(&seg.data[..n]);
+            len += n;
+            if seg.frg == 0 || len == buf.len() {
+                break;
+            }
+        }
+        Ok(len)
+    }
+
     /// Check buffer size without actually consuming it
     pub fn peeksize(&self) -> KcpResult<usize> {
         match self.rcv_queue.front() {
@@ -440,18 +730,61 @@ impl<Output: Write> Kcp<Output> {
     }
 
     /// Send bytes into buffer
-    pub fn send(&mut self, mut buf: &[u8]) -> KcpResult<usize> {
+    pub fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        self.send_segments(SliceCursor::new(&[IoSlice::new(buf)]), None)
+    }
+
+    /// Send bytes into buffer, their retransmission stops at `deadline` (the clock passed to `update`)
+    ///
+    /// Once expired, the peer skips the whole message instead of waiting for it. Stream mode has no
+    /// messages to skip and refuses deadlines with `InvalidConfig`.
+    pub fn send_with_deadline(&mut self, buf: &[u8], deadline: u32) -> KcpResult<usize> {
+        self.send_segments(SliceCursor::new(&[IoSlice::new(buf)]), Some(deadline))
+    }
+
+    /// Queue the end of the stream behind everything sent so far
+    ///
+    /// It is delivered reliably and in order like data, the peer's `fin_received` turns true once
+    /// it arrived.
+    pub fn send_fin(&mut self) {
+        let mut segment = KcpSegment::new_with_data(BytesMut::new());
+        segment.cmd = KCP_CMD_FIN;
+        self.snd_queue.push_back(segment);
+    }
+
+    /// The peer's FIN arrived and everything it sent before waits in `rcv_queue`
+    #[inline]
+    pub fn fin_received(&self) -> bool {
+        self.rcv_fin
+    }
+
+    /// Send the concatenation of `bufs` as one message, without joining them first
+    pub fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> KcpResult<usize> {
+        self.send_segments(SliceCursor::new(bufs), None)
+    }
+
+    /// `send_vectored` whose retransmission stops at `deadline`
+    pub fn send_vectored_with_deadline(&mut self, bufs: &[IoSlice<'_>], deadline: u32) -> KcpResult<usize> {
+        self.send_segments(SliceCursor::new(bufs), Some(deadline))
+    }
+
+    fn send_segments(&mut self, mut buf: SliceCursor<'_, '_>, deadline: Option<u32>) -> KcpResult<usize> {
         let mut sent_size = 0;
 
         assert!(self.mss > 0);
 
+        // Dropping expired bytes would cut a hole into the middle of the stream
+        if self.stream && deadline.is_some() {
+            return Err(Error::InvalidConfig("send deadlines need message mode".to_owned()));
+        }
+
         // append to previous segment in streaming mode (if possible)
         if self.stream {
             if let Some(old) = self.snd_queue.back_mut() {
                 let l = old.data.len();
-                if l < self.mss as usize {
-                    let capacity = self.mss as usize - l;
-                    let extend = cmp::min(buf.len(), capacity);
+                if l < self.mss && old.deadline == deadline && old.cmd != KCP_CMD_FIN {
+                    let capacity = self.mss - l;
+                    let extend = cmp::min(buf.remaining(), capacity);
 
                     trace!(
                         "send stream mss={} last length={} extend={}",
@@ -460,40 +793,39 @@ impl<Output: Write> Kcp<Output> {
                         extend
                     );
 
-                    let (lf, rt) = buf.split_at(extend);
-                    old.data.extend_from_slice(lf);
-                    buf = rt;
+                    buf.copy_to(&mut old.data, extend);
 
                     old.frg = 0;
                     sent_size += extend;
                 }
             }
 
-            if buf.is_empty() {
+            if buf.remaining() == 0 {
                 return Ok(sent_size);
             }
         }
 
-        let count = if buf.len() <= self.mss as usize {
+        let count = if buf.remaining() <= self.mss {
             1
         } else {
-            (buf.len() + self.mss as usize - 1) / self.mss as usize
+            buf.remaining().div_ceil(self.mss)
         };
 
         if count >= KCP_WND_RCV as usize {
-            debug!("send bufsize={} mss={} too large", buf.len(), self.mss);
+            debug!("send bufsize={} mss={} too large", buf.remaining(), self.mss);
             return Err(Error::UserBufTooBig);
         }
 
         let count = cmp::max(1, count);
 
         for i in 0..count {
-            let size = cmp::min(self.mss as usize, buf.len());
+            let size = cmp::min(self.mss, buf.remaining());
 
-            let (lf, rt) = buf.split_at(size);
+            let mut data = BytesMut::with_capacity(size);
+            buf.copy_to(&mut data, size);
 
-            let mut new_segment = KcpSegment::new_with_data(lf.into());
-            buf = rt;
+            let mut new_segment = KcpSegment::new_with_data(data);
+            new_segment.deadline = deadline;
 
             new_segment.frg = if self.stream {
                 0
@@ -513,19 +845,19 @@ impl<Output: Write> Kcp<Output> {
             self.rx_srtt = rtt;
             self.rx_rttval = rtt / 2;
         } else {
-            let delta = if rtt > self.rx_srtt {
-                rtt - self.rx_srtt
-            } else {
-                self.rx_srtt - rtt
-            };
-            self.rx_rttval = (3 * self.rx_rttval + delta) / 4;
-            self.rx_srtt = (7 * self.rx_srtt + rtt) / 8;
+            // A forged timestamp yields an RTT of up to 2^31, averages of such fit u32 but their sums don't
+            let delta = rtt.abs_diff(self.rx_srtt);
+            self.rx_rttval = ((3 * self.rx_rttval as u64 + delta as u64) / 4) as u32;
+            self.rx_srtt = ((7 * self.rx_srtt as u64 + rtt as u64) / 8) as u32;
             if self.rx_srtt < 1 {
                 self.rx_srtt = 1;
             }
         }
-        let rto = self.rx_srtt + cmp::max(self.interval, 4 * self.rx_rttval);
-        self.rx_rto = bound(self.rx_minrto, rto, KCP_RTO_MAX);
+        let rto = self
+            .rx_srtt
+            .saturating_add(cmp::max(self.interval, self.rx_rttval.saturating_mul(4)))
+            .saturating_add(self.peer_ack_delay);
+        self.rx_rto = bound(self.rx_minrto, rto, self.rx_maxrto);
     }
 
     #[inline]
@@ -541,7 +873,7 @@ impl<Output: Write> Kcp<Output> {
             return;
         }
 
-        let mut i = 0 as usize;
+        let mut i = 0_usize;
         while i < self.snd_buf.len() {
             match sn.cmp(&self.snd_buf[i].sn) {
                 Ordering::Equal => {
@@ -549,7 +881,7 @@ impl<Output: Write> Kcp<Output> {
                     break;
                 }
                 Ordering::Less => break,
-                _ => i = i + 1,
+                _ => i += 1,
             }
         }
     }
@@ -612,6 +944,9 @@ impl<Output: Write> Kcp<Output> {
         }
 
         if !repeat {
+            if sn != self.rcv_nxt {
+                self.out_of_order += 1;
+            }
             self.rcv_buf.insert(new_index, new_segment);
         }
 
@@ -625,6 +960,12 @@ impl<Output: Write> Kcp<Output> {
         self.input_conv = true;
     }
 
+    /// Ask the peer for its window size on the next flush, any answer carries its `conv`
+    #[inline]
+    pub fn ask_window(&mut self) {
+        self.probe |= KCP_ASK_SEND;
+    }
+
     /// Check if Kcp is waiting for the next input
     #[inline]
     pub fn waiting_conv(&self) -> bool {
@@ -643,18 +984,38 @@ impl<Output: Write> Kcp<Output> {
         self.conv
     }
 
+    // Fields of a compressed header, `flag` already read
+    fn expand_header(&self, flag: u8, buf: &mut Cursor<&[u8]>) -> (u8, u8, u16, u32, u32, u32, usize) {
+        let frg = if flag & KCP_COMPRESSED_FRG != 0 { buf.get_u8() } else { 0 };
+        let wnd = buf.get_u16_le();
+        let ts = buf.get_u16_le();
+        let sn = buf.get_u16_le();
+        let una = expand_delta(buf.get_u16_le(), self.snd_una);
+        if flag & KCP_COMPRESSED_ACK != 0 {
+            // Echoes our own clock
+            let sn = una.wrapping_add(sn as i16 as u32);
+            (KCP_CMD_ACK, frg, wnd, expand_delta(ts, self.current), sn, una, 0)
+        } else {
+            let ts = expand_delta(ts, self.rmt_ts.unwrap_or(0));
+            let len = buf.get_u16_le() as usize;
+            (KCP_CMD_PUSH, frg, wnd, ts, expand_delta(sn, self.rcv_nxt), una, len)
+        }
+    }
+
     /// Call this when you received a packet from raw connection
-    pub fn input(&mut self, buf: &[u8]) -> KcpResult<(Vec<(u32, usize)>, Vec<(u32)>)> {
+    pub fn input(&mut self, buf: &[u8]) -> KcpResult<InputResult> {
         let input_size = buf.len();
         let mut acked_sns = Vec::new();
         let mut received_push_sns = Vec::new();
+        // Segments this datagram's `una` fields acknowledged
+        let mut una_acked = Vec::new();
         trace!("[RI] {} bytes", buf.len());
 
-        if buf.len() < KCP_OVERHEAD as usize {
+        if buf.len() < header_len(buf) {
             debug!(
                 "input bufsize={} too small, at least {}",
                 buf.len(),
-                KCP_OVERHEAD
+                header_len(buf)
             );
             return Err(Error::InvalidSegmentSize(buf.len()));
         }
@@ -665,7 +1026,7 @@ impl<Output: Write> Kcp<Output> {
         let mut latest_ts = 0;
 
         let mut buf = Cursor::new(buf);
-        while buf.remaining() >= KCP_OVERHEAD as usize {
+        while buf.remaining() >= header_len(buf.chunk()) {
             let conv = buf.get_u32_le();
             if conv != self.conv {
                 // This allows getting conv from this call, which allows us to allocate
@@ -680,15 +1041,20 @@ impl<Output: Write> Kcp<Output> {
                 }
             }
 
-            let cmd = buf.get_u8();
-            let frg = buf.get_u8();
-            let wnd = buf.get_u16_le();
-            let ts = buf.get_u32_le();
-            let sn = buf.get_u32_le();
-            let una = buf.get_u32_le();
-            let len = buf.get_u32_le() as usize;
+            let (cmd, frg, wnd, ts, sn, una, len) = match buf.get_u8() {
+                flag if flag & KCP_COMPRESSED != 0 => self.expand_header(flag, &mut buf),
+                cmd => {
+                    let frg = buf.get_u8();
+                    let wnd = buf.get_u16_le();
+                    let ts = buf.get_u32_le();
+                    let sn = buf.get_u32_le();
+                    let una = buf.get_u32_le();
+                    let len = buf.get_u32_le() as usize;
+                    (cmd, frg, wnd, ts, sn, una, len)
+                }
+            };
 
-            if buf.remaining() < len as usize {
+            if buf.remaining() < len {
                 debug!(
                     "input bufsize={} payload length={} remaining={} not match",
                     input_size,
@@ -700,9 +1066,32 @@ impl<Output: Write> Kcp<Output> {
 
             match cmd {
                 KCP_CMD_ACK => {
+                    // Acknowledged before this datagram, or twice in it. Segments an earlier `una` of this
+                    // datagram removed were still waiting
+                    let waiting = |sn: u32| {
+                        let by_sn = |seg: &KcpSegment| timediff(seg.sn, sn).cmp(&0);
+                        self.snd_buf.binary_search_by(by_sn).is_ok() || una_acked.contains(&sn)
+                    };
+                    let dup = timediff(sn, old_una) < 0
+                        || acked_sns.iter().any(|&(acked, _)| acked == sn)
+                        || (timediff(sn, self.snd_nxt) < 0 && !waiting(sn));
+                    if dup {
+                        self.dup_acks += 1;
+                    }
                     acked_sns.push((sn, len));
+                    let newer = self.ts_acked.is_none_or(|acked| timediff(ts, acked) > 0);
+                    if newer && timediff(self.current, ts) >= 0 {
+                        self.ts_acked = Some(ts);
+                    }
                 },
-                KCP_CMD_PUSH | KCP_CMD_WASK | KCP_CMD_WINS => {}
+                KCP_CMD_PUSH | KCP_CMD_DROP | KCP_CMD_FIN => {
+                    if self.rmt_ts.is_none_or(|rmt_ts| timediff(ts, rmt_ts) > 0) {
+                        self.rmt_ts = Some(ts);
+                    }
+                }
+                KCP_CMD_WASK | KCP_CMD_WINS => {
+                    self.rmt_header_compression = frg & KCP_CAP_HEADER_COMPRESSION != 0;
+                }
                 _ => {
                     debug!("input cmd={} unrecognized", cmd);
                     return Err(Error::UnsupportedCmd(cmd));
@@ -711,7 +1100,13 @@ impl<Output: Write> Kcp<Output> {
 
             self.rmt_wnd = wnd;
 
-            self.parse_una(una);
+            while let Some(seg) = self.snd_buf.front() {
+                if timediff(una, seg.sn) <= 0 {
+                    break;
+                }
+                una_acked.push(seg.sn);
+                self.snd_buf.pop_front();
+            }
             self.shrink_buf();
 
             let mut has_read_data = false;
@@ -758,9 +1153,9 @@ impl<Output: Write> Kcp<Output> {
                         if timediff(sn, self.rcv_nxt) >= 0 {
                             received_push_sns.push(sn);
                         
-                            let mut sbuf = BytesMut::with_capacity(len as usize);
+                            let mut sbuf = BytesMut::with_capacity(len);
                             unsafe {
-                                sbuf.set_len(len as usize);
+                                sbuf.set_len(len);
                             }
                             buf.read_exact(&mut sbuf).unwrap();
                             has_read_data = true;
@@ -779,6 +1174,29 @@ impl<Output: Write> Kcp<Output> {
                         }
                     }
                 }
+                KCP_CMD_DROP | KCP_CMD_FIN => {
+                    trace!("input cmd={} sn={} ts={}", cmd, sn, ts);
+
+                    // Takes the place of the expired segment or ends the stream, `move_buf` discards
+                    // the dropped message and consumes the FIN
+                    if timediff(sn, self.rcv_nxt + self.rcv_wnd as u32) < 0 {
+                        self.ack_push(sn, ts);
+
+                        if timediff(sn, self.rcv_nxt) >= 0 {
+                            let mut segment = KcpSegment::new_with_data(BytesMut::new());
+
+                            segment.conv = conv;
+                            segment.cmd = cmd;
+                            segment.frg = frg;
+                            segment.wnd = wnd;
+                            segment.ts = ts;
+                            segment.sn = sn;
+                            segment.una = una;
+
+                            self.parse_data(segment);
+                        }
+                    }
+                }
                 KCP_CMD_WASK => {
                     // ready to send back IKCP_CMD_WINS in ikcp_flush
                     // tell remote my window size
@@ -803,27 +1221,25 @@ impl<Output: Write> Kcp<Output> {
             self.parse_fastack(max_ack, latest_ts);
         }
 
-        if !self.external_cc {
-            if timediff(self.snd_una, old_una) > 0 && self.cwnd < self.rmt_wnd {
-                let mss = self.mss;
-                if self.cwnd < self.ssthresh {
-                    self.cwnd += 1;
-                    self.incr += mss;
-                } else {
-                    if self.incr < mss {
-                        self.incr = mss;
-                    }
-                    self.incr += (mss * mss) / self.incr + (mss / 16);
-                    if (self.cwnd as usize + 1) * mss <= self.incr {
-                        // self.cwnd += 1;
-                        self.cwnd = ((self.incr + mss - 1) / if mss > 0 { mss } else { 1 }) as u16;
-                    }
+        if !self.external_cc && timediff(self.snd_una, old_una) > 0 && self.cwnd < self.rmt_wnd {
+            let mss = self.mss;
+            if self.cwnd < self.ssthresh {
+                self.cwnd += 1;
+                self.incr += mss;
+            } else {
+                if self.incr < mss {
+                    self.incr = mss;
                 }
-                if self.cwnd > self.rmt_wnd {
-                    self.cwnd = self.rmt_wnd;
-                    self.incr = self.rmt_wnd as usize * mss;
+                self.incr += (mss * mss) / self.incr + (mss / 16);
+                if (self.cwnd as usize + 1) * mss <= self.incr {
+                    // self.cwnd += 1;
+                    self.cwnd = ((self.incr + mss - 1) / if mss > 0 { mss } else { 1 }) as u16;
                 }
             }
+            if self.cwnd > self.rmt_wnd {
+                self.cwnd = self.rmt_wnd;
+                self.incr = self.rmt_wnd as usize * mss;
+            }
         }
 
         Ok((acked_sns, received_push_sns))
@@ -838,17 +1254,30 @@ impl<Output: Write> Kcp<Output> {
         }
     }
 
+    // Both peers agreed on compressed headers and the una of ours can be expanded by the peer, whose
+    // snd_una lags by less than the receive window
+    fn compress_headers(&self) -> bool {
+        self.header_compression && self.rmt_header_compression && self.rcv_wnd < 0x8000
+    }
+
     fn _flush_ack(&mut self, segment: &mut KcpSegment) -> KcpResult<()> {
+        let compress = self.compress_headers();
         // flush acknowledges
         // while let Some((sn, ts)) = self.acklist.pop_front() {
         for &(sn, ts) in &self.acklist {
-            if self.buf.len() + KCP_OVERHEAD as usize > self.mtu as usize {
+            if self.buf.len() + KCP_OVERHEAD > self.mtu {
                 self.output.write_all(&self.buf)?;
                 self.buf.clear();
             }
             segment.sn = sn;
             segment.ts = ts;
-            segment.encode(&mut self.buf);
+            // The echoed ts is expanded against the peer's clock, it must not be too old
+            let ts_recent = self.rmt_ts.is_some_and(|rmt_ts| (0..0x4000).contains(&timediff(rmt_ts, ts)));
+            if compress && ts_recent && fits_delta(sn, segment.una) {
+                segment.encode_compressed(&mut self.buf);
+            } else {
+                segment.encode(&mut self.buf);
+            }
         }
         self.acklist.clear();
 
@@ -885,7 +1314,12 @@ impl<Output: Write> Kcp<Output> {
 
     fn _flush_probe_commands(&mut self, cmd: u8, segment: &mut KcpSegment) -> KcpResult<()> {
         segment.cmd = cmd;
-        if self.buf.len() + KCP_OVERHEAD as usize > self.mtu as usize {
+        segment.frg = if self.header_compression {
+            KCP_CAP_HEADER_COMPRESSION
+        } else {
+            0
+        };
+        if self.buf.len() + KCP_OVERHEAD > self.mtu {
             self.output.write_all(&self.buf)?;
             self.buf.clear();
         }
@@ -922,11 +1356,16 @@ impl<Output: Write> Kcp<Output> {
             ..Default::default()
         };
 
-        self._flush_ack(&mut segment)
+        self._flush_ack(&mut segment)?;
+        if !self.buf.is_empty() {
+            self.output.write_all(&self.buf)?;
+            self.buf.clear();
+        }
+        Ok(())
     }
 
     /// Flush pending data in buffer.
-    pub fn flush(&mut self) -> KcpResult<((bool, Vec<u32>), Vec<(u32, usize)>)> {
+    pub fn flush(&mut self) -> KcpResult<FlushResult> {
         if !self.updated {
             debug!("flush updated() must be called at least once");
             return Err(Error::NeedUpdate);
@@ -940,7 +1379,9 @@ impl<Output: Write> Kcp<Output> {
             ..Default::default()
         };
 
-        self._flush_ack(&mut segment)?;
+        if !self.delayed_ack {
+            self._flush_ack(&mut segment)?;
+        }
         self.probe_wnd_size();
         self.flush_probe_commands(&mut segment)?;
 
@@ -959,7 +1400,9 @@ impl<Output: Write> Kcp<Output> {
             match self.snd_queue.pop_front() {
                 Some(mut new_segment) => {
                     new_segment.conv = self.conv;
-                    new_segment.cmd = KCP_CMD_PUSH;
+                    if new_segment.cmd != KCP_CMD_FIN {
+                        new_segment.cmd = KCP_CMD_PUSH;
+                    }
                     new_segment.wnd = segment.wnd;
                     new_segment.ts = self.current;
                     new_segment.sn = self.snd_nxt;
@@ -976,11 +1419,17 @@ impl<Output: Write> Kcp<Output> {
             }
         }
 
+        // The peer expands sn against its rcv_nxt and ts against the latest one it received, which is at
+        // least the latest one it acknowledged
+        let compress_push = self.compress_headers()
+            && timediff(self.snd_nxt, self.snd_una) < 0x8000
+            && self.ts_acked.is_some_and(|acked| (0..0x8000).contains(&timediff(self.current, acked)));
+
         // calculate resent
         let resent = if self.fastresend > 0 {
             self.fastresend
         } else {
-            u32::max_value()
+            u32::MAX
         };
 
         let rtomin = if !self.nodelay { self.rx_rto >> 3 } else { 0 };
@@ -992,32 +1441,54 @@ impl<Output: Write> Kcp<Output> {
         for snd_segment in &mut self.snd_buf {
             let mut need_send = false;
 
+            // Past its deadline only the header is sent, until the peer acknowledges it
+            let expired = snd_segment.deadline.is_some_and(|deadline| timediff(self.current, deadline) >= 0);
+            if expired && snd_segment.cmd == KCP_CMD_PUSH {
+                trace!("flush expire sn={} xmit={}", snd_segment.sn, snd_segment.xmit);
+                snd_segment.cmd = KCP_CMD_DROP;
+                snd_segment.data.clear();
+                self.expired.push(snd_segment.sn);
+                if snd_segment.xmit > 0 {
+                    snd_segment.resendts = self.current;
+                }
+            }
+
             if snd_segment.xmit == 0 {
                 need_send = true;
                 snd_segment.xmit += 1;
                 snd_segment.rto = self.rx_rto;
                 snd_segment.resendts = self.current + snd_segment.rto + rtomin;
             } else if timediff(self.current, snd_segment.resendts) >= 0 {
+                // Timed out after its last allowed retransmission
+                if snd_segment.xmit > self.dead_link {
+                    self.state = -1; // (IUINT32)-1
+                    continue;
+                }
                 need_send = true;
                 snd_segment.xmit += 1;
                 self.xmit += 1;
-                if !self.nodelay {
-                    snd_segment.rto += cmp::max(snd_segment.rto, self.rx_rto);
-                } else {
-                    let step = snd_segment.rto; // (kcp->nodelay < 2) ? ((IINT32)(segment->rto)) : kcp->rx_rto;
-                    snd_segment.rto += step / 2;
-                }
+                let rto = match self.backoff {
+                    Some(backoff) => (snd_segment.rto as f32 * backoff) as u32,
+                    None if !self.nodelay => snd_segment.rto + cmp::max(snd_segment.rto, self.rx_rto),
+                    // (kcp->nodelay < 2) ? ((IINT32)(segment->rto)) : kcp->rx_rto;
+                    None => snd_segment.rto + snd_segment.rto / 2,
+                };
+                snd_segment.rto = cmp::min(rto, self.rx_maxrto);
                 snd_segment.resendts = self.current + snd_segment.rto;
-                lost = true;
-                sns_of_lost.push(snd_segment.sn);
-            } else if snd_segment.fastack >= resent {
-                if snd_segment.xmit <= self.fastlimit || self.fastlimit <= 0 {
-                    need_send = true;
-                    snd_segment.xmit += 1;
-                    snd_segment.fastack = 0;
-                    snd_segment.resendts = self.current + snd_segment.rto;
-                    change += 1;
+                // A dropped payload isn't lost
+                if snd_segment.cmd == KCP_CMD_PUSH {
+                    lost = true;
+                    sns_of_lost.push(snd_segment.sn);
                 }
+            } else if snd_segment.fastack >= resent
+                && (snd_segment.xmit <= self.fastlimit || self.fastlimit == 0)
+            {
+                need_send = true;
+                snd_segment.xmit += 1;
+                self.fast_xmit += 1;
+                snd_segment.fastack = 0;
+                snd_segment.resendts = self.current + snd_segment.rto;
+                change += 1;
             }
 
             if need_send {
@@ -1025,17 +1496,26 @@ impl<Output: Write> Kcp<Output> {
                 snd_segment.wnd = segment.wnd;
                 snd_segment.una = self.rcv_nxt;
 
-                let need = KCP_OVERHEAD as usize + snd_segment.data.len();
+                let compress =
+                    compress_push && snd_segment.cmd == KCP_CMD_PUSH && snd_segment.data.len() <= 0xffff;
+                let need = if compress {
+                    snd_segment.compressed_len()
+                } else {
+                    snd_segment.encoded_len()
+                };
+                if snd_segment.xmit > 1 {
+                    self.retransmitted_bytes += need as u64;
+                }
 
-                if self.buf.len() + need > self.mtu as usize {
+                if self.buf.len() + need > self.mtu {
                     self.output.write_all(&self.buf)?;
                     self.buf.clear();
                 }
 
-                snd_segment.encode(&mut self.buf);
-
-                if snd_segment.xmit >= self.dead_link {
-                    self.state = -1; // (IUINT32)-1
+                if compress {
+                    snd_segment.encode_compressed(&mut self.buf);
+                } else {
+                    snd_segment.encode(&mut self.buf);
                 }
             }
         }
@@ -1079,7 +1559,7 @@ impl<Output: Write> Kcp<Output> {
     /// Update state every 10ms ~ 100ms.
     ///
     /// Or you can ask `check` when to call this again.
-    pub fn update(&mut self, current: u32) -> KcpResult<((bool, Vec<u32>), Vec<(u32, usize)>)> {
+    pub fn update(&mut self, current: u32) -> KcpResult<FlushResult> {
         self.current = current;
 
         if !self.updated {
@@ -1089,7 +1569,7 @@ impl<Output: Write> Kcp<Output> {
 
         let mut slap = timediff(self.current, self.ts_flush);
 
-        if slap >= 10000 || slap < -10000 {
+        if !(-10000..10000).contains(&slap) {
             self.ts_flush = self.current;
             slap = 0;
         }
@@ -1115,7 +1595,7 @@ impl<Output: Write> Kcp<Output> {
         }
 
         let mut ts_flush = self.ts_flush;
-        let mut tm_packet = u32::max_value();
+        let mut tm_packet = u32::MAX;
 
         if timediff(current, ts_flush) >= 10000 || timediff(current, ts_flush) < -10000 {
             ts_flush = current;
@@ -1156,7 +1636,7 @@ impl<Output: Write> Kcp<Output> {
         self.mtu = mtu;
         self.mss = self.mtu - KCP_OVERHEAD;
 
-        let target_size = ((mtu + KCP_OVERHEAD) * 3) as usize;
+        let target_size = (mtu + KCP_OVERHEAD) * 3;
         if target_size > self.buf.capacity() {
             self.buf.reserve(target_size - self.buf.capacity());
         }
@@ -1171,13 +1651,13 @@ impl<Output: Write> Kcp<Output> {
     }
 
     /// Set check interval
-    pub fn set_interval(&mut self, mut interval: u32) {
-        if interval > 5000 {
-            interval = 5000;
-        } else if interval < 10 {
-            interval = 10;
-        }
-        self.interval = interval;
+    pub fn set_interval(&mut self, interval: u32) {
+        self.interval = interval.clamp(10, 5000);
+    }
+
+    /// Check interval
+    pub fn interval(&self) -> u32 {
+        self.interval
     }
 
     /// Set nodelay
@@ -1212,17 +1692,42 @@ impl<Output: Write> Kcp<Output> {
 
 
 
+    /// Segments whose deadline passed before they were acknowledged, since the last call
+    pub fn take_expired(&mut self) -> Vec<u32> {
+        std::mem::take(&mut self.expired)
+    }
+
+    /// Writer the output packets go to
+    pub fn output_mut(&mut self) -> &mut Output {
+        &mut self.output.0
+    }
+
     /// raw data sending for SCReAM packets without KCP header
     pub fn output_raw(&mut self, data: &[u8]) -> io::Result<usize> {
         self.output.write(data)
     }
 
+    /// Time passed to the last `update` or `advance`
+    pub fn current(&self) -> u32 {
+        self.current
+    }
+
+    /// Move the clock forward without flushing
+    ///
+    /// `input` measures RTTs on this clock, advanced before each input they are exact instead of
+    /// rounded down to the last `update`. Earlier times are ignored.
+    pub fn advance(&mut self, current: u32) {
+        if timediff(current, self.current) > 0 {
+            self.current = current;
+        }
+    }
+
     pub fn get_rcv_nxt(&self) -> u32 {
-        return self.rcv_nxt
+        self.rcv_nxt
     }
 
     pub fn get_una(&self) -> u32 {
-        return self.snd_una;
+        self.snd_una
     }
 
 
@@ -1230,7 +1735,7 @@ impl<Output: Write> Kcp<Output> {
     /// set maximum window size: `sndwnd=32`, `rcvwnd=32` by default
     pub fn set_wndsize(&mut self, sndwnd: u16, rcvwnd: u16) {
         if sndwnd > 0 {
-            self.snd_wnd = sndwnd as u16;
+            self.snd_wnd = sndwnd;
         }
 
         if rcvwnd > 0 {
@@ -1250,17 +1755,110 @@ impl<Output: Write> Kcp<Output> {
         self.rcv_wnd
     }
 
+    /// Set `rcv_wnd` without the floor of `set_wndsize`, e.g. to bound the memory of unread data
+    ///
+    /// It never drops below the fragments of the message at the head of `rcv_queue`, which could
+    /// otherwise never complete. With `rcv_queue` empty it may close entirely. A grown window is told
+    /// to the peer at the next flush, it may be waiting for it.
+    pub fn set_rcv_wnd(&mut self, rcvwnd: u16) {
+        let head = self.rcv_queue.front().map_or(0, |seg| seg.frg as u16 + 1);
+        let rcvwnd = cmp::max(rcvwnd, head);
+        let grown = rcvwnd > self.rcv_wnd;
+        self.rcv_wnd = rcvwnd;
+
+        if grown {
+            self.probe |= KCP_ASK_TELL;
+            self.move_buf();
+        }
+    }
+
+    /// Segments received in order but not yet read by `recv`
+    #[inline]
+    pub fn rcv_queue_len(&self) -> usize {
+        self.rcv_queue.len()
+    }
+
+    /// Bytes held for `recv`, including segments received out of order
+    pub fn rcv_buffered(&self) -> usize {
+        self.rcv_queue.iter().chain(self.rcv_buf.iter()).map(|seg| seg.data.len()).sum()
+    }
+
     /// Get `waitsnd`, how many packet is waiting to be sent
     #[inline]
     pub fn wait_snd(&self) -> usize {
         self.snd_buf.len() + self.snd_queue.len()
     }
 
+    /// Segments queued by `send` that the send window hasn't let out yet
+    #[inline]
+    pub fn snd_queue_len(&self) -> usize {
+        self.snd_queue.len()
+    }
+
+    /// Get `xmit`, how many segments were retransmitted after their RTO expired
+    #[inline]
+    pub fn xmit(&self) -> u32 {
+        self.xmit
+    }
+
+    /// Segments sent again after enough later ones were acknowledged, before their RTO
+    #[inline]
+    pub fn fast_xmit(&self) -> u32 {
+        self.fast_xmit
+    }
+
+    /// Bytes of segments sent again after their RTO or a fast retransmit, headers included
+    #[inline]
+    pub fn retransmitted_bytes(&self) -> u64 {
+        self.retransmitted_bytes
+    }
+
+    /// Segments received ahead of a gap, a missing segment or reordering on the way
+    #[inline]
+    pub fn out_of_order(&self) -> u32 {
+        self.out_of_order
+    }
+
+    /// ACKs of segments acknowledged before, by an earlier ACK or `una`
+    #[inline]
+    pub fn dup_acks(&self) -> u32 {
+        self.dup_acks
+    }
+
     /// Enable / disable external congestion contol
     pub fn set_external_congestion_control(&mut self, enabled: bool) {
         self.external_cc = enabled;
     }
 
+    /// Leave pending ACKs to `flush_ack` instead of sending them on every `flush`
+    pub fn set_delayed_ack(&mut self, delayed: bool) {
+        self.delayed_ack = delayed;
+    }
+
+    /// Longest time (ms) the peer holds an ACK, added to the RTO so segments aren't resent meanwhile. A peer
+    /// acknowledging on its next flush holds them up to its interval. 0 by default
+    pub fn set_peer_ack_delay(&mut self, delay: u32) {
+        self.peer_ack_delay = delay;
+    }
+
+    /// Send PUSH and ACK headers compressed to 13 to 16 bytes once the peer's window probes asked for it,
+    /// and ask for it in our own
+    pub fn set_header_compression(&mut self, enabled: bool) {
+        self.header_compression = enabled;
+    }
+
+    /// Both peers agreed on compressed headers
+    #[inline]
+    pub fn header_compression(&self) -> bool {
+        self.header_compression && self.rmt_header_compression
+    }
+
+    /// ACKs waiting to be sent
+    #[inline]
+    pub fn ack_pending(&self) -> usize {
+        self.acklist.len()
+    }
+
     /// Get `rmt_wnd`, remote window size
     #[inline]
     pub fn rmt_wnd(&self) -> u16 {
@@ -1273,6 +1871,19 @@ impl<Output: Write> Kcp<Output> {
         self.rx_minrto = rto;
     }
 
+    /// Set `rx_maxrto`, the current RTO is lowered to it
+    #[inline]
+    pub fn set_rx_maxrto(&mut self, rto: u32) {
+        self.rx_maxrto = rto;
+        self.rx_rto = cmp::min(self.rx_rto, rto);
+    }
+
+    /// Multiply the RTO of a segment by `backoff` on every retransmission, at least 1
+    #[inline]
+    pub fn set_backoff(&mut self, backoff: f32) {
+        self.backoff = Some(backoff);
+    }
+
     /// Set `fastresend`
     #[inline]
     pub fn set_fast_resend(&mut self, fr: u32) {
@@ -1282,7 +1893,7 @@ impl<Output: Write> Kcp<Output> {
     /// KCP header size
     #[inline]
     pub fn header_len() -> usize {
-        KCP_OVERHEAD as usize
+        KCP_OVERHEAD
     }
 
     /// Enabled stream or not
@@ -1297,7 +1908,20 @@ impl<Output: Write> Kcp<Output> {
         self.mss
     }
 
-    /// Set maximum resend times
+    /// Nothing to send, acknowledge or probe, `update` has nothing to do until `send` or `input`
+    #[inline]
+    pub fn is_idle(&self) -> bool {
+        self.snd_buf.is_empty()
+            && self.snd_queue.is_empty()
+            && self.acklist.is_empty()
+            && self.probe == 0
+            && self.rmt_wnd != 0
+    }
+
+    /// Set maximum resend times, a segment timing out after that many retransmissions kills the link
+    ///
+    /// Unlike the C implementation, which declares the link dead as soon as the `dead_link`th
+    /// transmission was sent, the last retransmission gets its full RTO to be acknowledged.
     #[inline]
     pub fn set_maximum_resend_times(&mut self, dead_link: u32) {
         self.dead_link = dead_link;
@@ -15,7 +15,10 @@ pub mod prelude {
 }
 
 pub use error::Error;
-pub use kcp::{get_conv, get_sn, set_conv, Kcp, KCP_OVERHEAD};
+pub use kcp::{
+    get_conv, get_sn, header_len, is_ack_only, set_conv, FlushResult, InputResult, Kcp, KCP_MAX_FRAGMENTS,
+    KCP_MIN_OVERHEAD, KCP_OVERHEAD,
+};
 
 /// KCP result
 pub type KcpResult<T> = Result<T, Error>;
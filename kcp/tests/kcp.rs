@@ -6,7 +6,7 @@ extern crate time;
 
 use std::cell::RefCell;
 use std::collections::VecDeque;
-use std::io::{self, Cursor, ErrorKind, Read, Write};
+use std::io::{self, Cursor, ErrorKind, IoSlice, Read, Write};
 use std::rc::Rc;
 use std::thread::sleep;
 use std::time::Duration;
@@ -14,7 +14,7 @@ use std::time::Duration;
 use bytes::buf::{Buf, BufMut};
 use bytes::BytesMut;
 
-use kcp::Kcp;
+use kcp::{Error, Kcp, KCP_MIN_OVERHEAD, KCP_OVERHEAD};
 
 #[derive(Debug)]
 struct DelayPacket {
@@ -213,6 +213,414 @@ impl Write for KcpOutput {
     }
 }
 
+// Collects every datagram written, the test decides which ones arrive
+#[derive(Clone, Default)]
+struct Wire(Rc<RefCell<VecDeque<Vec<u8>>>>);
+
+impl Write for Wire {
+    fn write(&mut self, data: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().push_back(data.to_vec());
+        Ok(data.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn run_deadline() {
+    let wire = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire.clone());
+    let mut kcp2 = Kcp::new(0x11223344, Wire::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(false, 10, 0, true);
+
+    let start = 1000;
+    kcp1.update(start).unwrap();
+    kcp2.update(start).unwrap();
+
+    // 3 fragments, the first one gets lost
+    let late = [1u8; 60];
+    kcp1.send_with_deadline(&late, start + 100).unwrap();
+    kcp1.send(b"ON TIME").unwrap();
+    kcp1.flush().unwrap();
+
+    let packets: Vec<Vec<u8>> = wire.0.borrow_mut().drain(..).collect();
+    assert_eq!(packets.len(), 4);
+    for packet in &packets[1..] {
+        kcp2.input(packet).unwrap();
+    }
+    let mut buf = [0u8; 128];
+    assert!(kcp2.recv(&mut buf).is_err());
+
+    // Nothing expired before the deadline
+    kcp1.update(start + 50).unwrap();
+    assert!(kcp1.take_expired().is_empty());
+
+    kcp1.update(start + 150).unwrap();
+    assert_eq!(kcp1.take_expired(), vec![0, 1, 2]);
+    for packet in wire.0.borrow_mut().drain(..) {
+        kcp2.input(&packet).unwrap();
+    }
+
+    // The late message is skipped instead of blocking the one behind it
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"ON TIME");
+    assert!(kcp2.recv(&mut buf).is_err());
+
+    // A stream has no message boundaries to skip to
+    let mut stream = Kcp::new_stream(0x11223344, Wire::default());
+    assert!(stream.send_with_deadline(b"LATE", start + 100).is_err());
+    assert_eq!(stream.wait_snd(), 0);
+}
+
+fn run_vectored() {
+    let wire = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire.clone());
+    let mut kcp2 = Kcp::new(0x11223344, Wire::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp1.update(1000).unwrap();
+    kcp2.update(1000).unwrap();
+
+    // Slices straddle segment boundaries, empty ones are skipped
+    let header = [7u8; 10];
+    let payload: Vec<u8> = (0..60).collect();
+    let bufs = [IoSlice::new(&header), IoSlice::new(&[]), IoSlice::new(&payload)];
+    assert_eq!(kcp1.send_vectored(&bufs).unwrap(), 70);
+    kcp1.flush().unwrap();
+
+    for packet in wire.0.borrow_mut().drain(..) {
+        kcp2.input(&packet).unwrap();
+    }
+    let mut buf = [0u8; 128];
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..10], &header);
+    assert_eq!(&buf[10..n], &payload[..]);
+}
+
+fn run_peek() {
+    let wire = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire.clone());
+    let mut kcp2 = Kcp::new(0x11223344, Wire::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp1.update(1000).unwrap();
+    kcp2.update(1000).unwrap();
+
+    let payload: Vec<u8> = (0..60).collect();
+    kcp1.send(&payload).unwrap();
+    kcp1.send(b"NEXT").unwrap();
+    kcp1.flush().unwrap();
+    // One segment per packet, the last fragment of the first message is held back
+    let mut packets = wire.0.borrow_mut().drain(..).collect::<Vec<_>>();
+    let last_fragment = packets.remove(2);
+    for packet in packets {
+        kcp2.input(&packet).unwrap();
+    }
+
+    let mut buf = [0u8; 128];
+    assert!(matches!(kcp2.peek(&mut buf), Err(Error::ExpectingFragment)));
+    kcp2.input(&last_fragment).unwrap();
+
+    // A short buffer gets the start of the message, nothing is consumed
+    let mut prefix = [0u8; 4];
+    assert_eq!(kcp2.peek(&mut prefix).unwrap(), 4);
+    assert_eq!(prefix, [0, 1, 2, 3]);
+    assert_eq!(kcp2.peek(&mut buf).unwrap(), 60);
+    assert_eq!(&buf[..60], &payload[..]);
+
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], &payload[..]);
+    assert_eq!(kcp2.peek(&mut buf).unwrap(), 4);
+    assert_eq!(&buf[..4], b"NEXT");
+}
+
+fn run_recv_message() {
+    let wire = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire.clone());
+    let mut kcp2 = Kcp::new(0x11223344, Wire::default());
+    kcp1.set_mtu(50).unwrap();
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp1.update(1000).unwrap();
+    kcp2.update(1000).unwrap();
+
+    let payload: Vec<u8> = (0..60).collect();
+    kcp1.send(&payload).unwrap();
+    kcp1.send(b"NEXT").unwrap();
+    kcp1.flush().unwrap();
+    for packet in wire.0.borrow_mut().drain(..) {
+        kcp2.input(&packet).unwrap();
+    }
+
+    // Whole messages, with the segments they took in the window
+    let (message, segments) = kcp2.recv_message().unwrap();
+    assert_eq!(&message[..], &payload[..]);
+    assert_eq!(segments, 3);
+    let (message, segments) = kcp2.recv_message().unwrap();
+    assert_eq!(&message[..], b"NEXT");
+    assert_eq!(segments, 1);
+    assert!(matches!(kcp2.recv_message(), Err(Error::RecvQueueEmpty)));
+}
+
+fn run_fin() {
+    let wire = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire.clone());
+    let mut kcp2 = Kcp::new(0x11223344, Wire::default());
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp1.update(1000).unwrap();
+    kcp2.update(1000).unwrap();
+
+    kcp1.send(b"REQUEST").unwrap();
+    kcp1.send_fin();
+    kcp1.flush().unwrap();
+
+    // The FIN arrives first but is ordered behind the data
+    let packets: Vec<Vec<u8>> = wire.0.borrow_mut().drain(..).collect();
+    assert_eq!(packets.len(), 1);
+    let (data, fin) = packets[0].split_at(packets[0].len() - 24);
+    kcp2.input(fin).unwrap();
+    assert!(!kcp2.fin_received());
+    kcp2.input(data).unwrap();
+    assert!(kcp2.fin_received());
+
+    let mut buf = [0u8; 128];
+    let n = kcp2.recv(&mut buf).unwrap();
+    assert_eq!(&buf[..n], b"REQUEST");
+    assert!(kcp2.recv(&mut buf).is_err());
+}
+
+fn run_dead_link() {
+    let wire = Wire::default();
+    let mut kcp = Kcp::new(0x11223344, wire.clone());
+    kcp.set_nodelay(false, 10, 0, true);
+    kcp.set_rx_maxrto(100);
+    kcp.set_backoff(1.0);
+    kcp.set_maximum_resend_times(2);
+    kcp.update(1000).unwrap();
+
+    kcp.send(b"NOBODY LISTENS").unwrap();
+    let mut sent_at = Vec::new();
+    let mut current = 1000;
+    while !kcp.is_dead_link() && current < 3000 {
+        current += 10;
+        kcp.update(current).unwrap();
+        sent_at.extend(wire.0.borrow_mut().drain(..).map(|_| current));
+    }
+
+    // Sent once, retransmitted twice at the capped RTO, then given up on
+    assert_eq!(sent_at.len(), 3);
+    assert!(kcp.is_dead_link());
+    assert!(sent_at[2] - sent_at[1] <= 110);
+    assert!(current - sent_at[2] <= 110);
+    assert_eq!(kcp.xmit(), 2);
+    assert_eq!(kcp.retransmitted_bytes(), 2 * (KCP_OVERHEAD + 14) as u64);
+}
+
+fn run_delayed_ack() {
+    let wire1 = Wire::default();
+    let wire2 = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire1.clone());
+    let mut kcp2 = Kcp::new(0x11223344, wire2.clone());
+    kcp1.set_nodelay(false, 10, 0, true);
+    kcp2.set_delayed_ack(true);
+    kcp1.update(1000).unwrap();
+    kcp2.update(1000).unwrap();
+
+    kcp1.send(b"FIRST").unwrap();
+    kcp1.send(b"SECOND").unwrap();
+    kcp1.flush().unwrap();
+    for packet in wire1.0.borrow_mut().drain(..) {
+        kcp2.input(&packet).unwrap();
+    }
+
+    // Held back by flush, both go out together in one datagram
+    kcp2.flush().unwrap();
+    assert!(wire2.0.borrow().is_empty());
+    assert_eq!(kcp2.ack_pending(), 2);
+    assert!(!kcp2.is_idle());
+    kcp2.flush_ack().unwrap();
+    assert_eq!(kcp2.ack_pending(), 0);
+    let packets: Vec<Vec<u8>> = wire2.0.borrow_mut().drain(..).collect();
+    assert_eq!(packets.len(), 1);
+    assert_eq!(packets[0].len(), 2 * KCP_OVERHEAD);
+
+    kcp1.input(&packets[0]).unwrap();
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+fn run_forged_ack() {
+    let wire = Wire::default();
+    let mut kcp = Kcp::new(0x11223344, wire.clone());
+    kcp.set_nodelay(false, 10, 0, true);
+    kcp.set_rx_maxrto(200);
+    kcp.update(1000).unwrap();
+
+    // ACKs of segments never sent, echoing timestamps an RTT of up to 2^31 ms ago
+    for ts in [1000u32.wrapping_sub(0x7FFF_FFFF), 0x8000_0000, 1000u32.wrapping_sub(0x7FFF_FFFF), 999] {
+        let mut ack = BytesMut::new();
+        ack.put_u32_le(0x11223344);
+        ack.put_u8(82);
+        ack.put_u8(0);
+        ack.put_u16_le(128);
+        ack.put_u32_le(ts);
+        ack.put_u32_le(0x8000_0000);
+        ack.put_u32_le(0);
+        ack.put_u32_le(0);
+        kcp.input(&ack).unwrap();
+    }
+    kcp.send(b"HELLO").unwrap();
+    kcp.flush().unwrap();
+    wire.0.borrow_mut().clear();
+
+    // The RTO stays capped, the segment is retransmitted
+    let mut current = 1000;
+    while wire.0.borrow().is_empty() && current < 2000 {
+        current += 10;
+        kcp.update(current).unwrap();
+    }
+    assert!(current <= 1250);
+    assert_eq!(kcp.wait_snd(), 1);
+}
+
+fn run_arq_counters() {
+    let wire1 = Wire::default();
+    let wire2 = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire1.clone());
+    let mut kcp2 = Kcp::new(0x11223344, wire2.clone());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.update(1000).unwrap();
+    kcp2.update(1000).unwrap();
+
+    // Four segments in datagrams of their own, the first is lost
+    for i in 0..4u8 {
+        kcp1.send(&[i; 1000]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    let mut segments: VecDeque<Vec<u8>> = wire1.0.borrow_mut().drain(..).collect();
+    assert_eq!(segments.len(), 4);
+    segments.pop_front();
+
+    // The others arrive ahead of the gap, their ACKs in two datagrams
+    let mut acks = Vec::new();
+    for batch in [2, 1] {
+        for segment in segments.drain(..batch) {
+            kcp2.input(&segment).unwrap();
+        }
+        kcp2.flush().unwrap();
+        acks.extend(wire2.0.borrow_mut().drain(..));
+    }
+    assert_eq!(kcp2.out_of_order(), 3);
+    assert_eq!(acks.len(), 2);
+
+    // Skipped twice, the first segment is sent again before its RTO
+    for ack in &acks {
+        kcp1.input(ack).unwrap();
+    }
+    kcp1.flush().unwrap();
+    assert_eq!(kcp1.fast_xmit(), 1);
+    assert_eq!(kcp1.xmit(), 0);
+    assert_eq!(kcp1.dup_acks(), 0);
+
+    // It fills the gap in order, a replayed ACK datagram acknowledges twice
+    for segment in wire1.0.borrow_mut().drain(..) {
+        kcp2.input(&segment).unwrap();
+    }
+    assert_eq!(kcp2.out_of_order(), 3);
+    kcp1.input(&acks[0]).unwrap();
+    assert_eq!(kcp1.dup_acks(), 2);
+
+    // In order and without loss, the `una` of the first ACK in a datagram doesn't make the others duplicates
+    let wire1 = Wire::default();
+    let wire2 = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire1.clone());
+    let mut kcp2 = Kcp::new(0x11223344, wire2.clone());
+    kcp1.set_nodelay(true, 10, 2, true);
+    kcp1.update(1000).unwrap();
+    kcp2.update(1000).unwrap();
+    for i in 0..4u8 {
+        kcp1.send(&[i; 1000]).unwrap();
+    }
+    kcp1.flush().unwrap();
+    for segment in wire1.0.borrow_mut().drain(..) {
+        kcp2.input(&segment).unwrap();
+    }
+    kcp2.flush().unwrap();
+    let acks: Vec<Vec<u8>> = wire2.0.borrow_mut().drain(..).collect();
+    assert_eq!(acks.len(), 1);
+    kcp1.input(&acks[0]).unwrap();
+    assert_eq!(kcp2.out_of_order(), 0);
+    assert_eq!(kcp1.dup_acks(), 0);
+    assert_eq!(kcp1.wait_snd(), 0);
+}
+
+// Window probe and answer, as sent by the handshake
+fn handshake(kcp1: &mut Kcp<Wire>, wire1: &Wire, kcp2: &mut Kcp<Wire>, wire2: &Wire) {
+    kcp1.ask_window();
+    kcp1.flush().unwrap();
+    for packet in wire1.0.borrow_mut().drain(..) {
+        kcp2.input(&packet).unwrap();
+    }
+    kcp2.flush().unwrap();
+    for packet in wire2.0.borrow_mut().drain(..) {
+        kcp1.input(&packet).unwrap();
+    }
+}
+
+fn run_header_compression() {
+    let wire1 = Wire::default();
+    let wire2 = Wire::default();
+    let mut kcp1 = Kcp::new(0x11223344, wire1.clone());
+    let mut kcp2 = Kcp::new(0x11223344, wire2.clone());
+    for kcp in [&mut kcp1, &mut kcp2] {
+        kcp.set_nodelay(false, 10, 0, true);
+        kcp.set_header_compression(true);
+        kcp.update(1000).unwrap();
+    }
+    handshake(&mut kcp1, &wire1, &mut kcp2, &wire2);
+    assert!(kcp1.header_compression());
+    assert!(kcp2.header_compression());
+
+    // sn passes 16 bits and ts wraps its 16 bits several times
+    let mut current = 1000;
+    let mut buf = [0u8; 16];
+    for i in 0..70_000u32 {
+        current += 10;
+        kcp1.send(&i.to_le_bytes()).unwrap();
+        kcp1.update(current).unwrap();
+        for packet in wire1.0.borrow_mut().drain(..) {
+            // Only the first PUSH goes out whole, before any ts of ours was acknowledged
+            assert_eq!(packet.len(), if i == 0 { KCP_OVERHEAD + 4 } else { 15 + 4 });
+            kcp2.input(&packet).unwrap();
+        }
+        let n = kcp2.recv(&mut buf).unwrap();
+        assert_eq!(&buf[..n], &i.to_le_bytes());
+
+        kcp2.update(current).unwrap();
+        for packet in wire2.0.borrow_mut().drain(..) {
+            assert_eq!(packet.len(), KCP_MIN_OVERHEAD);
+            assert!(kcp::is_ack_only(&packet));
+            kcp1.input(&packet).unwrap();
+        }
+        assert_eq!(kcp1.wait_snd(), 0);
+    }
+    assert_eq!(kcp1.xmit(), 0);
+
+    // Headers stay whole unless both peers ask for compression
+    let wire3 = Wire::default();
+    let mut kcp3 = Kcp::new(0x11223344, wire3.clone());
+    let wire4 = Wire::default();
+    let mut kcp4 = Kcp::new(0x11223344, wire4.clone());
+    kcp3.set_header_compression(true);
+    kcp3.update(1000).unwrap();
+    kcp4.update(1000).unwrap();
+    handshake(&mut kcp3, &wire3, &mut kcp4, &wire4);
+    assert!(!kcp3.header_compression());
+    kcp3.send(b"WHOLE").unwrap();
+    kcp3.flush().unwrap();
+    assert_eq!(wire3.0.borrow_mut().pop_front().unwrap().len(), KCP_OVERHEAD + 5);
+}
+
 #[derive(Debug)]
 enum TestMode {
     Default,
@@ -392,4 +800,54 @@ mod tests {
     fn kcp_massive_lost_fast() {
         run(TestMode::Fast, 1000, 50);
     }
+
+    #[test]
+    fn kcp_deadline() {
+        run_deadline();
+    }
+
+    #[test]
+    fn kcp_vectored() {
+        run_vectored();
+    }
+
+    #[test]
+    fn kcp_peek() {
+        run_peek();
+    }
+
+    #[test]
+    fn kcp_recv_message() {
+        run_recv_message();
+    }
+
+    #[test]
+    fn kcp_fin() {
+        run_fin();
+    }
+
+    #[test]
+    fn kcp_dead_link() {
+        run_dead_link();
+    }
+
+    #[test]
+    fn kcp_forged_ack() {
+        run_forged_ack();
+    }
+
+    #[test]
+    fn kcp_delayed_ack() {
+        run_delayed_ack();
+    }
+
+    #[test]
+    fn kcp_arq_counters() {
+        run_arq_counters();
+    }
+
+    #[test]
+    fn kcp_header_compression() {
+        run_header_compression();
+    }
 }
@@ -0,0 +1,419 @@
+//! Vergleich von KCP mit SCReAM gegen UDP und TCP
+//!
+//! Alle Transporte laufen lokal mit demselben Verkehrsmuster. Jede Nachricht beginnt mit ihrer Sendezeit,
+//! der Empfänger misst daraus die Einwegverzögerung. UDP schickt Datagramme bis zur MTU, gepaced wie von
+//! KCP, aber ohne Zuverlässigkeit und Congestion Control. `--emulate` gilt nicht für TCP.
+
+use std::future::poll_fn;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use serde::Serialize;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::oneshot;
+use tokio_kcp::{DatagramTransport, KcpConfig, KcpListener, KcpStream, StreamMode};
+
+use crate::echo::unix_micros;
+use crate::results::Distribution;
+use crate::traffic::Pattern;
+use crate::{bind_transport, percentile, ClientArgs, Emulation, RateMode};
+
+const TIMESTAMP_LEN: usize = 8;
+
+// Spätester Empfang nach Testende, falls das Ende des Senders nicht ankommt
+const GRACE: Duration = Duration::from_secs(2);
+
+// UDP kennt kein Ende, nach dem letzten Senden noch so lange empfangen
+const UDP_DRAIN: Duration = Duration::from_millis(500);
+
+const PACING_CREDIT: Duration = Duration::from_millis(5);
+
+/// Ergebnis eines Transports
+struct TransportReport {
+    name: &'static str,
+    elapsed: Duration,
+    sent_bytes: u64,
+    sent_messages: u64,
+    received: Received,
+    /// Bytes auf der Leitung pro gesendetem Nutzbyte, `None` wenn unbekannt
+    wire_overhead: Option<f64>,
+}
+
+impl TransportReport {
+    fn sent_kbps(&self) -> f64 {
+        self.sent_bytes as f64 * 8.0 / 1000.0 / self.elapsed.as_secs_f64()
+    }
+
+    fn received_kbps(&self) -> f64 {
+        self.received.bytes as f64 * 8.0 / 1000.0 / self.elapsed.as_secs_f64()
+    }
+
+    fn loss_percent(&self) -> f64 {
+        if self.sent_messages == 0 {
+            return 0.0;
+        }
+        self.sent_messages.saturating_sub(self.received.messages) as f64 * 100.0 / self.sent_messages as f64
+    }
+}
+
+/// Beim Empfänger gezählte Nachrichten
+#[derive(Default)]
+struct Received {
+    bytes: u64,
+    messages: u64,
+    delays: Vec<Duration>,
+}
+
+impl Received {
+    fn record(&mut self, message: &[u8]) {
+        if message.len() < TIMESTAMP_LEN {
+            return;
+        }
+        let sent_us = u64::from_le_bytes(message[..TIMESTAMP_LEN].try_into().unwrap());
+        self.bytes += message.len() as u64;
+        self.messages += 1;
+        self.delays.push(Duration::from_micros(unix_micros().saturating_sub(sent_us)));
+    }
+}
+
+/// Nimmt einzelne Nachrichten des Verkehrsmusters an
+trait MessageSink {
+    async fn send_message(&mut self, message: &[u8]) -> io::Result<()>;
+}
+
+#[derive(Default)]
+struct Sent {
+    bytes: u64,
+    messages: u64,
+    elapsed: Duration,
+}
+
+/// `pattern` über `sink` abspielen, Bursts in Nachrichten zu höchstens `chunk` Bytes
+async fn play<S: MessageSink>(
+    sink: &mut S,
+    mut pattern: Pattern,
+    duration: Duration,
+    chunk: usize,
+    mut bitrate: impl FnMut() -> f32,
+) -> Sent {
+    let chunk = chunk.max(TIMESTAMP_LEN);
+    let mut message = vec![1u8; chunk];
+    let mut sent = Sent::default();
+    let start_time = Instant::now();
+    let mut next_send = tokio::time::Instant::now();
+
+    'send: while start_time.elapsed() < duration {
+        let (burst_len, interval) = pattern.next(bitrate());
+        let mut remaining = burst_len;
+        while remaining > 0 {
+            let len = remaining.clamp(TIMESTAMP_LEN, chunk);
+            message[..TIMESTAMP_LEN].copy_from_slice(&unix_micros().to_le_bytes());
+            if let Err(e) = sink.send_message(&message[..len]).await {
+                eprintln!("Vergleich: Sende-Fehler: {}", e);
+                break 'send;
+            }
+            sent.bytes += len as u64;
+            sent.messages += 1;
+            remaining = remaining.saturating_sub(len);
+        }
+        next_send += interval;
+        tokio::time::sleep_until(next_send).await;
+    }
+
+    sent.elapsed = start_time.elapsed();
+    sent
+}
+
+/// KCP mit SCReAM, dann UDP und TCP mit derselben Rate, nacheinander auf localhost
+pub async fn run(config: KcpConfig, args: ClientArgs, emulation: Emulation) -> io::Result<()> {
+    if config.stream_mode != StreamMode::Message {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "der Vergleich braucht den Nachrichtenmodus (stream_mode = message)",
+        ));
+    }
+    let port = args.connect.port();
+    let addr = |offset: u16| SocketAddr::from(([127, 0, 0, 1], port.wrapping_add(offset)));
+
+    println!("Vergleich: KCP mit SCReAM für {} Sekunden...", args.duration.as_secs());
+    let kcp = run_kcp(&config, &args, addr(0), emulation).await?;
+
+    // Im SCReAM-Modus bekommen die anderen Transporte die mittlere Rate von KCP
+    let bitrate = match args.rate_mode {
+        RateMode::Fixed(bps) => bps,
+        RateMode::Scream => (kcp.sent_kbps() * 1000.0) as f32,
+    };
+    println!("Vergleich: UDP mit {:.0} kbps...", bitrate / 1000.0);
+    let pacing_rate = bitrate * config.scream.packet_pacing_headroom;
+    let udp = run_udp(&args, addr(1), emulation, bitrate, pacing_rate, config.mtu).await?;
+    println!("Vergleich: TCP mit {:.0} kbps...", bitrate / 1000.0);
+    let tcp = run_tcp(&args, addr(2), bitrate).await?;
+
+    let reports = [kcp, udp, tcp];
+    print_comparison(&reports, emulation.link.is_some());
+    if let Some(ref path) = args.output {
+        write_json(path, &reports)?;
+        println!("Ergebnisse geschrieben nach {}", path.display());
+    }
+    Ok(())
+}
+
+struct KcpSink<'a>(&'a mut KcpStream);
+
+impl MessageSink for KcpSink<'_> {
+    async fn send_message(&mut self, message: &[u8]) -> io::Result<()> {
+        self.0.send(message).await?;
+        Ok(())
+    }
+}
+
+async fn run_kcp(
+    config: &KcpConfig,
+    args: &ClientArgs,
+    addr: SocketAddr,
+    emulation: Emulation,
+) -> io::Result<TransportReport> {
+    let listener_transport = bind_transport(addr, emulation.for_socket(0)).await?;
+    let mut listener = KcpListener::from_transport(config.clone(), listener_transport).await?;
+    let deadline = tokio::time::Instant::now() + args.duration + GRACE;
+    let payload = args.payload;
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = vec![0u8; payload.max(TIMESTAMP_LEN)];
+        let mut received = Received::default();
+        loop {
+            match tokio::time::timeout_at(deadline, stream.recv(&mut buf)).await {
+                Ok(Ok(0)) | Err(..) => break,
+                Ok(Ok(n)) => received.record(&buf[..n]),
+                Ok(Err(e)) => {
+                    eprintln!("Vergleich: KCP-Empfangs-Fehler: {}", e);
+                    break;
+                }
+            }
+        }
+        Ok::<_, io::Error>(received)
+    });
+
+    let transport = bind_transport(SocketAddr::from(([127, 0, 0, 1], 0)), emulation.for_socket(1)).await?;
+    let mut stream = KcpStream::connect_with_transport(config, transport, addr).await?;
+    let target_bitrate_rx = stream.get_target_bitrate_receiver();
+    let pattern = Pattern::new(args.traffic, args.video, args.payload, emulation.seed)?;
+    let rate_mode = args.rate_mode;
+    let sent = play(&mut KcpSink(&mut stream), pattern, args.duration, args.payload, || match rate_mode {
+        RateMode::Scream => *target_bitrate_rx.borrow(),
+        RateMode::Fixed(bps) => bps,
+    })
+    .await;
+    stream.shutdown().await?;
+    let wire_bytes = stream.stats().bytes_sent;
+
+    let received = receiver.await.expect("Empfangs-Task konnte nicht beendet werden.")?;
+    Ok(TransportReport {
+        name: "KCP+SCReAM",
+        elapsed: sent.elapsed,
+        sent_bytes: sent.bytes,
+        sent_messages: sent.messages,
+        received,
+        wire_overhead: (sent.bytes > 0).then(|| wire_bytes as f64 / sent.bytes as f64),
+    })
+}
+
+/// Datagramme gleichmäßig mit `pacing_rate` senden
+struct UdpSink {
+    transport: Arc<dyn DatagramTransport>,
+    target: SocketAddr,
+    pacing_rate: f32,
+    next_slot: tokio::time::Instant,
+}
+
+impl MessageSink for UdpSink {
+    async fn send_message(&mut self, message: &[u8]) -> io::Result<()> {
+        tokio::time::sleep_until(self.next_slot).await;
+        poll_fn(|cx| self.transport.poll_send_to(cx, message, self.target)).await?;
+        let spacing = Duration::from_secs_f32((message.len() * 8) as f32 / self.pacing_rate.max(1.0));
+        // Verspätete Timer holt der nächste Slot bis zu `PACING_CREDIT` wieder auf
+        self.next_slot = self.next_slot.max(tokio::time::Instant::now() - PACING_CREDIT) + spacing;
+        Ok(())
+    }
+}
+
+async fn run_udp(
+    args: &ClientArgs,
+    addr: SocketAddr,
+    emulation: Emulation,
+    bitrate: f32,
+    pacing_rate: f32,
+    mtu: usize,
+) -> io::Result<TransportReport> {
+    let receiver_transport = bind_transport(addr, emulation.for_socket(0)).await?;
+    let (done_tx, mut done_rx) = oneshot::channel::<()>();
+    let test_end = tokio::time::Instant::now() + args.duration + GRACE;
+    let receiver = tokio::spawn(async move {
+        let mut buf = vec![0u8; 65536];
+        let mut received = Received::default();
+        let mut deadline = test_end;
+        let mut sender_done = false;
+        loop {
+            tokio::select! {
+                result = poll_fn(|cx| receiver_transport.poll_recv_from(cx, &mut buf)) => match result {
+                    Ok((n, ..)) => received.record(&buf[..n]),
+                    Err(e) => {
+                        eprintln!("Vergleich: UDP-Empfangs-Fehler: {}", e);
+                        break;
+                    }
+                },
+                _ = &mut done_rx, if !sender_done => {
+                    sender_done = true;
+                    deadline = deadline.min(tokio::time::Instant::now() + UDP_DRAIN);
+                }
+                _ = tokio::time::sleep_until(deadline) => break,
+            }
+        }
+        received
+    });
+
+    let mut sink = UdpSink {
+        transport: bind_transport(SocketAddr::from(([127, 0, 0, 1], 0)), emulation.for_socket(1)).await?,
+        target: addr,
+        pacing_rate,
+        next_slot: tokio::time::Instant::now(),
+    };
+    let pattern = Pattern::new(args.traffic, args.video, args.payload, emulation.seed)?;
+    let sent = play(&mut sink, pattern, args.duration, args.payload.min(mtu), || bitrate).await;
+    let _ = done_tx.send(());
+
+    let received = receiver.await.expect("Empfangs-Task konnte nicht beendet werden.");
+    Ok(TransportReport {
+        name: "UDP",
+        elapsed: sent.elapsed,
+        sent_bytes: sent.bytes,
+        sent_messages: sent.messages,
+        received,
+        wire_overhead: Some(1.0),
+    })
+}
+
+/// Nachrichten mit vorangestellter Länge, damit der Empfänger sie wieder trennen kann
+struct TcpSink(TcpStream);
+
+impl MessageSink for TcpSink {
+    async fn send_message(&mut self, message: &[u8]) -> io::Result<()> {
+        self.0.write_u32_le(message.len() as u32).await?;
+        self.0.write_all(message).await
+    }
+}
+
+async fn run_tcp(args: &ClientArgs, addr: SocketAddr, bitrate: f32) -> io::Result<TransportReport> {
+    let listener = TcpListener::bind(addr).await?;
+    let deadline = tokio::time::Instant::now() + args.duration + GRACE;
+    let receiver = tokio::spawn(async move {
+        let (mut stream, _) = listener.accept().await?;
+        let mut buf = Vec::new();
+        let mut received = Received::default();
+        let receive_one = async {
+            loop {
+                let len = match stream.read_u32_le().await {
+                    Ok(len) => len as usize,
+                    Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => break,
+                    Err(e) => return Err(e),
+                };
+                buf.resize(len, 0);
+                stream.read_exact(&mut buf).await?;
+                received.record(&buf);
+            }
+            Ok(())
+        };
+        if let Ok(Err(e)) = tokio::time::timeout_at(deadline, receive_one).await {
+            eprintln!("Vergleich: TCP-Empfangs-Fehler: {}", e);
+        }
+        Ok::<_, io::Error>(received)
+    });
+
+    let stream = TcpStream::connect(addr).await?;
+    stream.set_nodelay(true)?;
+    let mut sink = TcpSink(stream);
+    let pattern = Pattern::new(args.traffic, args.video, args.payload, 0)?;
+    let sent = play(&mut sink, pattern, args.duration, args.payload, || bitrate).await;
+    sink.0.shutdown().await?;
+
+    let received = receiver.await.expect("Empfangs-Task konnte nicht beendet werden.")?;
+    Ok(TransportReport {
+        name: "TCP",
+        elapsed: sent.elapsed,
+        sent_bytes: sent.bytes,
+        sent_messages: sent.messages,
+        received,
+        wire_overhead: None,
+    })
+}
+
+fn print_comparison(reports: &[TransportReport], emulated: bool) {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    println!("\n----------------------------------------");
+    println!(
+        "{:<12} {:>12} {:>12} {:>9} {:>9} {:>28}",
+        "Transport", "Gesendet", "Empfangen", "Verlust", "Overhead", "Verzögerung p50/p95/p99"
+    );
+    for report in reports {
+        let mut delays = report.received.delays.clone();
+        delays.sort();
+        let overhead = match report.wire_overhead {
+            Some(overhead) => format!("{:.3}", overhead),
+            None => "-".to_string(),
+        };
+        println!(
+            "{:<12} {:>7.0} kbps {:>7.0} kbps {:>7.2} % {:>9} {:>8.1} / {:>6.1} / {:>6.1} ms",
+            report.name,
+            report.sent_kbps(),
+            report.received_kbps(),
+            report.loss_percent(),
+            overhead,
+            ms(percentile(&delays, 50.0)),
+            ms(percentile(&delays, 95.0)),
+            ms(percentile(&delays, 99.0)),
+        );
+    }
+    if emulated {
+        println!("Hinweis: TCP läuft ohne die emulierte Strecke.");
+    }
+    println!("----------------------------------------");
+}
+
+#[derive(Serialize)]
+struct TransportResult {
+    transport: &'static str,
+    duration_s: f64,
+    sent_bytes: u64,
+    received_bytes: u64,
+    sent_messages: u64,
+    received_messages: u64,
+    loss_percent: f64,
+    wire_overhead: Option<f64>,
+    delay_ms: Distribution,
+}
+
+fn write_json(path: &std::path::Path, reports: &[TransportReport]) -> io::Result<()> {
+    use std::io::Write;
+
+    let results: Vec<_> = reports
+        .iter()
+        .map(|report| TransportResult {
+            transport: report.name,
+            duration_s: report.elapsed.as_secs_f64(),
+            sent_bytes: report.sent_bytes,
+            received_bytes: report.received.bytes,
+            sent_messages: report.sent_messages,
+            received_messages: report.received.messages,
+            loss_percent: report.loss_percent(),
+            wire_overhead: report.wire_overhead,
+            delay_ms: Distribution::of_durations(&report.received.delays),
+        })
+        .collect();
+    let mut out = io::BufWriter::new(std::fs::File::create(path)?);
+    serde_json::to_writer_pretty(&mut out, &results)?;
+    writeln!(out)?;
+    out.flush()
+}
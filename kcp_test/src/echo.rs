@@ -0,0 +1,218 @@
+//! Latenzmessung über Echos mit Zeitstempel
+//!
+//! Jede Nachricht beginnt mit Sequenznummer, Sendezeit des Clients und Empfangszeit des Servers
+//! (Mikrosekunden seit der Unix-Epoche, little endian). Der Server trägt seine Empfangszeit ein
+//! und schickt die Nachricht zurück. Die Einwegverzögerung stimmt nur bei synchronen Uhren.
+
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use tokio::io::AsyncWriteExt;
+use tokio_kcp::KcpStream;
+
+use crate::{percentile, ClientArgs, ClientReport, RateMode};
+
+/// Sequenznummer, Sendezeit, Empfangszeit
+pub const HEADER_LEN: usize = 24;
+
+// Nach dem letzten Senden so lange auf ausstehende Echos warten
+const ECHO_GRACE: Duration = Duration::from_secs(2);
+const ECHO_POLL: Duration = Duration::from_millis(100);
+
+pub fn unix_micros() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_micros() as u64
+}
+
+fn read_u64(buf: &[u8], offset: usize) -> u64 {
+    u64::from_le_bytes(buf[offset..offset + 8].try_into().unwrap())
+}
+
+/// Empfangszeit des Servers in eine empfangene Nachricht eintragen
+pub fn stamp(message: &mut [u8]) {
+    if message.len() >= HEADER_LEN {
+        message[16..24].copy_from_slice(&unix_micros().to_le_bytes());
+    }
+}
+
+/// Messwerte der Echos eines Streams
+#[derive(Default)]
+struct EchoStats {
+    received: u64,
+    rtts: Vec<Duration>,
+    one_way_ms: Vec<f64>,
+    /// Interarrival-Jitter nach RFC 3550 auf dem Hinweg, in ms
+    jitter_ms: f64,
+    last_transit_us: Option<i64>,
+}
+
+impl EchoStats {
+    fn record(&mut self, message: &[u8], now_us: u64) {
+        let sent_us = read_u64(message, 8);
+        let server_us = read_u64(message, 16);
+
+        self.received += 1;
+        self.rtts.push(Duration::from_micros(now_us.saturating_sub(sent_us)));
+
+        let transit_us = server_us as i64 - sent_us as i64;
+        self.one_way_ms.push(transit_us as f64 / 1000.0);
+        if let Some(last) = self.last_transit_us {
+            let d_ms = (transit_us - last).abs() as f64 / 1000.0;
+            self.jitter_ms += (d_ms - self.jitter_ms) / 16.0;
+        }
+        self.last_transit_us = Some(transit_us);
+    }
+}
+
+/// Echo-Messung über `stream`, sendet wie im Durchsatzmodus mit `args.rate_mode`
+pub async fn run(
+    mut stream: KcpStream,
+    args: &ClientArgs,
+    id: usize,
+    rtt_samples: Arc<Mutex<Vec<Duration>>>,
+) -> std::io::Result<ClientReport> {
+    let payload = args.payload.max(HEADER_LEN);
+    let mut target_bitrate_rx = stream.get_target_bitrate_receiver();
+    let start_time = Instant::now();
+
+    let sent = AtomicU64::new(0);
+    let sent_bytes = AtomicU64::new(0);
+    let done = AtomicBool::new(false);
+    let mut stats = EchoStats::default();
+
+    {
+        let (mut reader, mut writer) = stream.split();
+
+        let send_loop = async {
+            let mut message = vec![0u8; payload];
+            let mut next_send = tokio::time::Instant::now();
+            let mut seq = 0u64;
+            while start_time.elapsed() < args.duration {
+                message[..8].copy_from_slice(&seq.to_le_bytes());
+                message[8..16].copy_from_slice(&unix_micros().to_le_bytes());
+                message[16..24].fill(0);
+                match writer.send(&message).await {
+                    Ok(n) => {
+                        sent.fetch_add(1, Ordering::Relaxed);
+                        sent_bytes.fetch_add(n as u64, Ordering::Relaxed);
+                    }
+                    Err(e) => {
+                        eprintln!("Client #{} sending Exception: {}", id, e);
+                        break;
+                    }
+                }
+                seq += 1;
+
+                let bitrate = match args.rate_mode {
+                    RateMode::Scream => *target_bitrate_rx.borrow_and_update(),
+                    RateMode::Fixed(bps) => bps,
+                };
+                let interval = if bitrate > 0.0 {
+                    Duration::from_secs_f32((payload * 8) as f32 / bitrate)
+                } else {
+                    Duration::from_millis(100)
+                };
+                next_send += interval;
+                tokio::time::sleep_until(next_send).await;
+            }
+            done.store(true, Ordering::Release);
+        };
+
+        let recv_loop = async {
+            let mut buf = vec![0u8; payload.max(8192)];
+            let deadline = tokio::time::Instant::from_std(start_time + args.duration + ECHO_GRACE);
+            loop {
+                if done.load(Ordering::Acquire) && stats.received >= sent.load(Ordering::Relaxed) {
+                    break;
+                }
+                let now = tokio::time::Instant::now();
+                if now >= deadline {
+                    break;
+                }
+                // Kurz warten, damit das Ende des Senders bemerkt wird, auch wenn kein Echo mehr kommt
+                match tokio::time::timeout_at(deadline.min(now + ECHO_POLL), reader.recv(&mut buf)).await {
+                    Err(..) => continue,
+                    Ok(Ok(0)) => break,
+                    Ok(Ok(n)) if n >= HEADER_LEN => stats.record(&buf[..n], unix_micros()),
+                    Ok(Ok(n)) => eprintln!("Client #{}: Echo mit {} Bytes zu kurz", id, n),
+                    Ok(Err(e)) => {
+                        eprintln!("Client #{} Empfangs-Fehler: {}", id, e);
+                        break;
+                    }
+                }
+            }
+        };
+
+        tokio::join!(send_loop, recv_loop);
+    }
+
+    let elapsed = start_time.elapsed();
+    let kcp_stats = stream.stats();
+    stream.shutdown().await?;
+
+    print_echo_summary(id, sent.load(Ordering::Relaxed), &stats);
+
+    let rtt_samples = std::mem::take(&mut *rtt_samples.lock().unwrap());
+    Ok(ClientReport {
+        id,
+        sent_bytes: sent_bytes.load(Ordering::Relaxed),
+        elapsed,
+        rtt_samples,
+        packets_lost: kcp_stats.packets_lost,
+        retransmits: kcp_stats.retransmits,
+        timeseries: Vec::new(),
+    })
+}
+
+// Obergrenzen der Histogrammklassen in ms, die letzte Klasse ist offen
+const HISTOGRAM_BOUNDS_MS: [u64; 10] = [1, 2, 5, 10, 20, 50, 100, 200, 500, 1000];
+
+fn print_echo_summary(id: usize, sent: u64, stats: &EchoStats) {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+    let mut rtts = stats.rtts.clone();
+    rtts.sort();
+    let mut one_way = stats.one_way_ms.clone();
+    one_way.sort_by(f64::total_cmp);
+    let one_way_at = |p: f64| {
+        if one_way.is_empty() {
+            return 0.0;
+        }
+        one_way[(p / 100.0 * (one_way.len() - 1) as f64).round() as usize]
+    };
+
+    println!("\n[Echo #{}] {} gesendet, {} Echos empfangen", id, sent, stats.received);
+    println!(
+        "[Echo #{}] RTT p50 {:.1} ms | p95 {:.1} ms | p99 {:.1} ms",
+        id,
+        ms(percentile(&rtts, 50.0)),
+        ms(percentile(&rtts, 95.0)),
+        ms(percentile(&rtts, 99.0)),
+    );
+    println!(
+        "[Echo #{}] Einweg p50 {:.1} ms | p95 {:.1} ms | p99 {:.1} ms | Jitter {:.2} ms",
+        id,
+        one_way_at(50.0),
+        one_way_at(95.0),
+        one_way_at(99.0),
+        stats.jitter_ms,
+    );
+
+    if rtts.is_empty() {
+        return;
+    }
+    let mut counts = [0usize; HISTOGRAM_BOUNDS_MS.len() + 1];
+    for rtt in &rtts {
+        let rtt_ms = rtt.as_millis() as u64;
+        let class = HISTOGRAM_BOUNDS_MS.iter().position(|&bound| rtt_ms < bound).unwrap_or(HISTOGRAM_BOUNDS_MS.len());
+        counts[class] += 1;
+    }
+    let max = counts.iter().copied().max().unwrap_or(1).max(1);
+    for (class, &count) in counts.iter().enumerate() {
+        let label = match (class.checked_sub(1).map(|i| HISTOGRAM_BOUNDS_MS[i]), HISTOGRAM_BOUNDS_MS.get(class)) {
+            (lower, Some(upper)) => format!("{:>4}-{:<4} ms", lower.unwrap_or(0), upper),
+            (Some(lower), None) => format!("{:>4}+     ms", lower),
+            (None, None) => unreachable!(),
+        };
+        println!("[Echo #{}] {} {:>7} {}", id, label, count, "#".repeat(count * 40 / max));
+    }
+}
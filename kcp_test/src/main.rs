@@ -1,13 +1,240 @@
+use clap::{Args, Parser, Subcommand};
+use serde::Serialize;
 use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
 use tokio::io::AsyncWriteExt; // Wichtig für stream.shutdown()
-use tokio_kcp::{KcpConfig, KcpListener, KcpStream};
+use tokio::net::UdpSocket;
+use tokio_kcp::{
+    CsvSink, DatagramTransport, EmulatedTransport, KcpConfig, KcpEvent, KcpListener, KcpStream, SimLinkConfig,
+    StreamMode,
+};
+use traffic::{Pattern, Traffic, VideoArgs};
+
+mod compare;
+mod echo;
+mod results;
+mod traffic;
+
+/// Durchsatztest für SCReAM über KCP
+#[derive(Parser)]
+struct Cli {
+    /// KCP-Konfiguration als TOML-Datei, sonst Standardwerte mit SCReAM
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+    /// Gesendete Pakete über eine emulierte Strecke schicken, z. B. `loss=1%,delay=40ms±10ms,rate=10mbps`
+    #[arg(long, global = true, value_parser = parse_emulation)]
+    emulate: Option<SimLinkConfig>,
+    /// Seed für Verlust und Jitter der emulierten Strecke und die Framegrößen von `--traffic video`
+    #[arg(long, global = true, default_value_t = 0)]
+    seed: u64,
+    /// Ohne Rolle laufen Server und Client zusammen auf localhost
+    #[command(subcommand)]
+    role: Option<Role>,
+}
+
+#[derive(Subcommand)]
+enum Role {
+    /// Empfängt die Streams der Clients
+    Server {
+        /// Adresse, auf der der Server lauscht
+        #[arg(long, default_value = "0.0.0.0:22333")]
+        listen: SocketAddr,
+        /// Anzahl Streams, nach deren Ende sich der Server beendet
+        #[arg(long, default_value_t = 1)]
+        streams: usize,
+        /// Nachrichten mit Empfangszeit zurückschicken, für `--echo` der Clients
+        #[arg(long)]
+        echo: bool,
+    },
+    /// Sendet an einen Server
+    Client(ClientArgs),
+    /// Server und Client im selben Prozess, der Server lauscht auf dem Port von `--connect`
+    Local(ClientArgs),
+    /// KCP mit SCReAM, UDP und TCP lokal mit demselben Verkehrsmuster vergleichen, ein Stream pro Transport
+    ///
+    /// Belegt die Ports ab dem von `--connect`. Im SCReAM-Modus senden UDP und TCP mit der mittleren Rate von KCP.
+    Compare(ClientArgs),
+}
+
+#[derive(Args, Clone)]
+struct ClientArgs {
+    /// Adresse des Servers
+    #[arg(long, default_value = "127.0.0.1:22333")]
+    connect: SocketAddr,
+    /// Testdauer, z. B. `90s` oder `2m`
+    #[arg(long, default_value = "90s", value_parser = humantime::parse_duration)]
+    duration: Duration,
+    /// Bytes pro `send`, größere Frames werden aufgeteilt
+    #[arg(long, default_value_t = 4096)]
+    payload: usize,
+    /// Verkehrsmodell: `bulk` schreibt gleichmäßig, `video` schickt Frame-Bursts mit fester Bildrate
+    #[arg(long, value_enum, default_value_t = Traffic::Bulk)]
+    traffic: Traffic,
+    #[command(flatten)]
+    video: VideoArgs,
+    /// Senderate: `scream` folgt der Zielbitrate, `fixed:5mbps` sendet konstant
+    #[arg(long, default_value = "scream")]
+    rate_mode: RateMode,
+    /// Anzahl paralleler Client-Streams, die sich den Engpass teilen
+    #[arg(long, default_value_t = 1)]
+    streams: usize,
+    /// Ergebnisse maschinenlesbar schreiben: `.csv` als Zeitreihe pro Sekunde, sonst JSON mit Zusammenfassung
+    #[arg(long)]
+    output: Option<PathBuf>,
+    /// Latenz und Jitter über Echos mit Zeitstempel messen, der Server braucht ebenfalls `--echo`
+    #[arg(long)]
+    echo: bool,
+}
+
+impl Default for ClientArgs {
+    fn default() -> ClientArgs {
+        ClientArgs {
+            connect: "127.0.0.1:22333".parse().unwrap(),
+            duration: Duration::from_secs(90),
+            payload: 4096,
+            traffic: Traffic::Bulk,
+            video: VideoArgs::default(),
+            rate_mode: RateMode::Scream,
+            streams: 1,
+            output: None,
+            echo: false,
+        }
+    }
+}
+
+/// Rate, mit der die Clients Daten erzeugen
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum RateMode {
+    /// Zielbitrate des Congestion Controllers
+    Scream,
+    /// Feste Bitrate in bps
+    Fixed(f32),
+}
+
+impl FromStr for RateMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<RateMode, String> {
+        if s == "scream" {
+            return Ok(RateMode::Scream);
+        }
+        let rate = s
+            .strip_prefix("fixed:")
+            .ok_or_else(|| format!("erwartet `scream` oder `fixed:<rate>`, nicht `{}`", s))?;
+        parse_rate(rate).map(|bps| RateMode::Fixed(bps as f32))
+    }
+}
+
+/// Bitrate wie `5mbps`, `800kbps` oder `64000` in bps
+fn parse_rate(rate: &str) -> Result<f64, String> {
+    let lower = rate.to_ascii_lowercase();
+    let (number, factor) = [("gbps", 1e9), ("mbps", 1e6), ("kbps", 1e3), ("bps", 1.0)]
+        .iter()
+        .find_map(|&(unit, factor)| lower.strip_suffix(unit).map(|number| (number, factor)))
+        .unwrap_or((&lower, 1.0));
+    match number.trim().parse::<f64>() {
+        Ok(bps) if bps > 0.0 => Ok(bps * factor),
+        _ => Err(format!("ungültige Rate `{}`", rate)),
+    }
+}
+
+/// Strecke aus `loss=1%,delay=40ms±10ms,rate=10mbps,queue=200ms`, fehlende Werte bleiben neutral
+fn parse_emulation(spec: &str) -> Result<SimLinkConfig, String> {
+    let duration = |value: &str| humantime::parse_duration(value.trim()).map_err(|e| format!("`{}`: {}", value, e));
+
+    let mut link = SimLinkConfig::default();
+    for part in spec.split(',').map(str::trim).filter(|part| !part.is_empty()) {
+        let (key, value) = part
+            .split_once('=')
+            .ok_or_else(|| format!("erwartet `schlüssel=wert`, nicht `{}`", part))?;
+        match key.trim() {
+            "loss" => {
+                let value = value.trim();
+                let loss = match value.strip_suffix('%') {
+                    Some(percent) => percent.trim().parse::<f64>().map(|p| p / 100.0),
+                    None => value.parse::<f64>(),
+                };
+                link.loss = match loss {
+                    Ok(loss) if (0.0..=1.0).contains(&loss) => loss,
+                    _ => return Err(format!("ungültiger Verlust `{}`", value)),
+                };
+            }
+            "delay" => {
+                // `40ms±10ms` verteilt die Verzögerung gleichmäßig auf 30..50 ms
+                match value.split_once('±').or_else(|| value.split_once("+-")) {
+                    Some((delay, jitter)) => {
+                        let (delay, jitter) = (duration(delay)?, duration(jitter)?);
+                        link.delay = delay.saturating_sub(jitter);
+                        link.jitter = (delay - link.delay) + jitter;
+                    }
+                    None => link.delay = duration(value)?,
+                }
+            }
+            "rate" => link.bandwidth = Some(parse_rate(value.trim())? as u64),
+            "queue" => link.queue_limit = duration(value)?,
+            key => return Err(format!("unbekannter Parameter `{}`", key)),
+        }
+    }
+    Ok(link)
+}
+
+/// UDP-Socket auf `addr`, mit `--emulate` hinter der emulierten Strecke
+async fn bind_transport(addr: SocketAddr, emulation: Emulation) -> std::io::Result<Arc<dyn DatagramTransport>> {
+    let udp = Arc::new(UdpSocket::bind(addr).await?);
+    Ok(match emulation.link {
+        Some(link) => EmulatedTransport::new(udp, link, emulation.seed),
+        None => udp,
+    })
+}
 
 #[tokio::main]
 async fn main() -> std::io::Result<()> {
+    let cli = Cli::parse();
+    let config = load_config(cli.config.as_deref())?;
+    let emulation = Emulation {
+        link: cli.emulate,
+        seed: cli.seed,
+    };
+
+    match cli.role {
+        Some(Role::Server { listen, streams, echo }) => run_server(config, listen, streams, echo, emulation).await,
+        Some(Role::Client(args)) => run_clients(config, args, emulation).await,
+        Some(Role::Local(args)) => run_local(config, args, emulation).await,
+        Some(Role::Compare(args)) => compare::run(config, args, emulation).await,
+        None => run_local(config, ClientArgs::default(), emulation).await,
+    }
+}
+
+/// Emulierte Strecke für die gesendeten Pakete, `link` ist `None` ohne `--emulate`
+#[derive(Clone, Copy)]
+struct Emulation {
+    link: Option<SimLinkConfig>,
+    seed: u64,
+}
+
+impl Emulation {
+    /// Jeder Socket bekommt eigene Zufallszahlen
+    fn for_socket(self, index: u64) -> Emulation {
+        Emulation {
+            seed: self.seed.wrapping_add(index),
+            ..self
+        }
+    }
+}
+
+/// Server und Client im selben Prozess, über localhost
+async fn run_local(config: KcpConfig, args: ClientArgs, emulation: Emulation) -> std::io::Result<()> {
+    let listen = SocketAddr::new([0, 0, 0, 0].into(), args.connect.port());
+
     // Server in einem Hintergrund-Task starten
-    let server_handle = tokio::spawn(async {
-        if let Err(e) = run_server().await {
+    let server_config = config.clone();
+    let streams = args.streams;
+    let echo = args.echo;
+    let server_handle = tokio::spawn(async move {
+        if let Err(e) = run_server(server_config, listen, streams, echo, emulation).await {
             eprintln!("Server-Fehler: {}", e);
         }
     });
@@ -15,8 +242,7 @@ async fn main() -> std::io::Result<()> {
     // Dem Server einen Moment Zeit zum Starten geben
     tokio::time::sleep(Duration::from_secs(1)).await;
 
-    // Client im Vordergrund ausführen
-    if let Err(e) = run_client().await {
+    if let Err(e) = run_clients(config, args, emulation).await {
         eprintln!("Client-Fehler: {}", e);
     }
 
@@ -27,108 +253,319 @@ async fn main() -> std::io::Result<()> {
     Ok(())
 }
 
-async fn run_server() -> std::io::Result<()> {
-    let mut config = KcpConfig::default();
-    config.nodelay.nc = true;
-    config.use_external_congestion_control = true;
-    let mut listener = KcpListener::bind(config, "0.0.0.0:22333").await?;
-    println!("Server lauscht auf 0.0.0.0:22333");
+async fn run_clients(mut config: KcpConfig, args: ClientArgs, emulation: Emulation) -> std::io::Result<()> {
+    // Alle Clients teilen sich eine CSV, die Zeilen unterscheiden sich in der conv
+    // SCReAM-Werte des Senders für visualizer/plotter.py mitschreiben
+    config.metrics = Arc::new(CsvSink::new("scream_log.csv")?);
 
-    let (mut stream, addr) = listener.accept().await?;
-    println!("Server: Verbindung von {} akzeptiert", addr);
+    let clients: Vec<_> = (0..args.streams)
+        .map(|id| {
+            let config = config.clone();
+            let args = args.clone();
+            let emulation = emulation.for_socket(id as u64 + 1);
+            tokio::spawn(async move { run_client(config, args, id, emulation).await })
+        })
+        .collect();
+
+    let mut reports = Vec::with_capacity(args.streams);
+    for client in clients {
+        match client.await.expect("Client-Task konnte nicht beendet werden.") {
+            Ok(report) => reports.push(report),
+            Err(e) => eprintln!("Client-Fehler: {}", e),
+        }
+    }
+    print_summary(&reports);
+    if let Some(ref path) = args.output {
+        results::write(path, &reports)?;
+        println!("Ergebnisse geschrieben nach {}", path.display());
+    }
+    Ok(())
+}
+
+/// Konfiguration aus der TOML-Datei laden, sonst Standardwerte mit SCReAM
+fn load_config(path: Option<&Path>) -> std::io::Result<KcpConfig> {
+    match path {
+        Some(path) => KcpConfig::from_toml_file(path),
+        None => {
+            let mut config = KcpConfig::default();
+            config.nodelay.nc = true;
+            config.use_external_congestion_control = true;
+            Ok(config)
+        }
+    }
+}
+
+async fn run_server(
+    config: KcpConfig,
+    listen: SocketAddr,
+    streams: usize,
+    echo: bool,
+    emulation: Emulation,
+) -> std::io::Result<()> {
+    let transport = bind_transport(listen, emulation).await?;
+    let mut listener = KcpListener::from_transport(config, transport).await?;
+    println!("Server lauscht auf {}, erwartet {} Stream(s)", listen, streams);
 
-    let mut buf = vec![0u8; 8192];
-    let mut total_received_bytes = 0;
-    let mut last_stat_time = Instant::now();
     let start_time = Instant::now();
+    let mut receivers = Vec::with_capacity(streams);
+    for id in 0..streams {
+        let (stream, addr) = listener.accept().await?;
+        println!("Server: Verbindung {} von {} akzeptiert", id, addr);
+        receivers.push(tokio::spawn(receive(stream, addr, id, echo)));
+    }
+
+    for receiver in receivers {
+        receiver.await.expect("Empfangs-Task konnte nicht beendet werden.");
+    }
+    let total_duration = start_time.elapsed().as_secs_f64();
+    println!("Server-Task beendet nach {:.2} Sekunden.", total_duration);
+    Ok(())
+}
+
+async fn receive(mut stream: KcpStream, addr: SocketAddr, id: usize, echo: bool) {
+    let mut buf = vec![0u8; 8192];
+    let mut throughput_rx = stream.throughput_receiver();
 
     loop {
         match stream.recv(&mut buf).await {
             Ok(0) => {
-                println!("\nServer: Verbindung von {} sauber geschlossen.", addr);
+                println!("\nServer: Verbindung {} von {} sauber geschlossen.", id, addr);
                 break;
             }
             Ok(n) => {
-                total_received_bytes += n;
-                if last_stat_time.elapsed() >= Duration::from_secs(2) {
-                    let rate_kbps = (total_received_bytes as f64 * 8.0) / (last_stat_time.elapsed().as_secs_f64() * 1000.0);
-                    println!("[Server] Empfangsdurchsatz der letzten 2s: {:.2} kbps", rate_kbps);
-                    total_received_bytes = 0;
-                    last_stat_time = Instant::now();
+                if echo {
+                    echo::stamp(&mut buf[..n]);
+                    if let Err(e) = stream.send(&buf[..n]).await {
+                        eprintln!("Server Echo-Fehler auf Verbindung {}: {}", id, e);
+                        break;
+                    }
+                }
+                // Die Session meldet einmal pro Sekunde, Header eingeschlossen
+                if throughput_rx.has_changed().unwrap_or(false) {
+                    let sample = *throughput_rx.borrow_and_update();
+                    println!(
+                        "[Server #{}] Empfangsdurchsatz der letzten {:.1}s: {:.2} kbps",
+                        id,
+                        sample.interval.as_secs_f64(),
+                        sample.recv_bps / 1000.0
+                    );
                 }
             }
             Err(e) => {
-                eprintln!("Server Empfangs-Fehler: {}", e);
+                eprintln!("Server Empfangs-Fehler auf Verbindung {}: {}", id, e);
                 break;
             }
         }
     }
-    let total_duration = start_time.elapsed().as_secs_f64();
-    println!("Server-Task beendet nach {:.2} Sekunden.", total_duration);
-    Ok(())
 }
 
-async fn run_client() -> std::io::Result<()> {
-    let mut config = KcpConfig::default();
-    config.nodelay.nc = true;
-    config.use_external_congestion_control = true;
-    let server_addr: SocketAddr = "127.0.0.1:22333".parse().unwrap();
+/// Ergebnis eines Client-Streams
+struct ClientReport {
+    id: usize,
+    sent_bytes: u64,
+    elapsed: Duration,
+    /// Geglättete RTT bei jedem RTT-Tick des Controllers
+    rtt_samples: Vec<Duration>,
+    /// Vom Congestion Controller als verloren gemeldete Segmente
+    packets_lost: u64,
+    retransmits: u32,
+    /// Ein Eintrag pro Sekunde
+    timeseries: Vec<SecondSample>,
+}
+
+/// Zustand eines Streams am Ende einer Sekunde
+#[derive(Debug, Clone, Copy, Serialize)]
+struct SecondSample {
+    /// Sekunden seit Testbeginn
+    t: f64,
+    throughput_kbps: f64,
+    srtt_ms: f64,
+    target_bitrate_kbps: f64,
+    packets_lost: u64,
+    retransmits: u32,
+}
+
+impl ClientReport {
+    fn throughput_kbps(&self) -> f64 {
+        (self.sent_bytes as f64 * 8.0 / 1000.0) / self.elapsed.as_secs_f64()
+    }
+}
 
-    println!("Client: Verbinde mit {}", server_addr);
-    let mut stream = KcpStream::connect(&config, server_addr).await?;
-    println!("Client: Verbunden.");
+async fn run_client(config: KcpConfig, args: ClientArgs, id: usize, emulation: Emulation) -> std::io::Result<ClientReport> {
+    let server_addr = args.connect;
+    let local_addr = match server_addr {
+        SocketAddr::V4(..) => SocketAddr::from(([0, 0, 0, 0], 0)),
+        SocketAddr::V6(..) => SocketAddr::from(([0u16; 8], 0)),
+    };
 
-    let data_to_send = vec![1u8; 4096];
+    println!("Client #{}: Verbinde mit {}", id, server_addr);
+    let transport = bind_transport(local_addr, emulation).await?;
+    let mut stream = KcpStream::connect_with_transport(&config, transport, server_addr).await?;
+    println!("Client #{}: Verbunden.", id);
+
+    let rtt_samples = Arc::new(Mutex::new(Vec::new()));
+    {
+        let rtt_samples = rtt_samples.clone();
+        stream.set_event_handler(move |event| {
+            if let KcpEvent::RttUpdate { srtt, .. } = event {
+                rtt_samples.lock().unwrap().push(srtt);
+            }
+        });
+    }
+
+    if args.echo {
+        if config.stream_mode != StreamMode::Message {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "--echo braucht den Nachrichtenmodus (stream_mode = message)",
+            ));
+        }
+        return echo::run(stream, &args, id, rtt_samples).await;
+    }
+
+    let mut pattern = Pattern::new(args.traffic, args.video, args.payload, emulation.seed)?;
+
+    let data_to_send = vec![1u8; args.payload];
     let mut total_sent_bytes: u64 = 0;
     let start_time = Instant::now();
-    let test_duration = Duration::from_secs(90);
+    let test_duration = args.duration;
+
+    match args.traffic {
+        Traffic::Bulk => println!("Client #{}: Sende Daten für {} Sekunden...", id, test_duration.as_secs()),
+        Traffic::Video => println!(
+            "Client #{}: Sende Video mit {} fps für {} Sekunden...",
+            id,
+            args.video.fps,
+            test_duration.as_secs()
+        ),
+    }
 
-    println!("Client: Sende Daten für {} Sekunden...", test_duration.as_secs());
-    
     let mut target_bitrate_rx = stream.get_target_bitrate_receiver();
 
-    while start_time.elapsed() < test_duration {
+    let mut next_send = tokio::time::Instant::now();
+    let mut timeseries = Vec::new();
+    let mut last_sample = Instant::now();
+    let mut sample_bytes: u64 = 0;
+
+    'send: while start_time.elapsed() < test_duration {
         // check bitrate
-        tokio::select! {
-            _ = target_bitrate_rx.changed() => { }
-            
-            _ = tokio::time::sleep(Duration::from_millis(1)) => { }
-        }    
-
-        let target_bitrate_bps = *target_bitrate_rx.borrow();
-        let bits_to_send = (data_to_send.len() * 8) as f32;
-        let sleep_duration_secs = if target_bitrate_bps > 0.0 {
-            bits_to_send / target_bitrate_bps
-        } else {
-            0.1
-        };
+        if args.rate_mode == RateMode::Scream && !pattern.is_video() {
+            tokio::select! {
+                _ = target_bitrate_rx.changed() => { }
 
-        match stream.send(&data_to_send).await {
-            Ok(n) => {
-                total_sent_bytes += n as u64;
+                _ = tokio::time::sleep(Duration::from_millis(1)) => { }
             }
-            Err(e) => {
-                eprintln!("Client sending Exception: {}", e);
-                break;
+        }
+
+        let target_bitrate_bps = match args.rate_mode {
+            RateMode::Scream => *target_bitrate_rx.borrow(),
+            RateMode::Fixed(bps) => bps,
+        };
+        let (burst_len, interval) = pattern.next(target_bitrate_bps);
+
+        // Frames am Stück in Nachrichten zu höchstens `payload` Bytes senden
+        let mut remaining = burst_len;
+        while remaining > 0 {
+            match stream.send(&data_to_send[..remaining.min(data_to_send.len())]).await {
+                Ok(n) => {
+                    total_sent_bytes += n as u64;
+                    sample_bytes += n as u64;
+                    remaining -= n;
+                }
+                Err(e) => {
+                    eprintln!("Client #{} sending Exception: {}", id, e);
+                    break 'send;
+                }
             }
         }
 
-        tokio::time::sleep(Duration::from_secs_f32(sleep_duration_secs)).await;
+        if last_sample.elapsed() >= Duration::from_secs(1) {
+            let stats = stream.stats();
+            timeseries.push(SecondSample {
+                t: start_time.elapsed().as_secs_f64(),
+                throughput_kbps: sample_bytes as f64 * 8.0 / 1000.0 / last_sample.elapsed().as_secs_f64(),
+                srtt_ms: stats.srtt.as_secs_f64() * 1000.0,
+                target_bitrate_kbps: stats.target_bitrate as f64 / 1000.0,
+                packets_lost: stats.packets_lost,
+                retransmits: stats.retransmits,
+            });
+            sample_bytes = 0;
+            last_sample = Instant::now();
+        }
 
+        if args.rate_mode == RateMode::Scream && !pattern.is_video() {
+            tokio::time::sleep(interval).await;
+        } else {
+            // Bis zum nächsten Sollzeitpunkt schlafen, damit sich Timer-Verspätungen nicht aufsummieren
+            next_send += interval;
+            tokio::time::sleep_until(next_send).await;
+        }
     }
 
-    let elapsed_secs = start_time.elapsed().as_secs_f64();
-    let send_throughput_kbps = (total_sent_bytes as f64 * 8.0 / 1000.0) / elapsed_secs;
-    
-    println!("\n----------------------------------------");
-    println!("Client: Test beendet.");
-    println!("Gesamtdauer: {:.2} Sekunden", elapsed_secs);
-    println!("Gesendet: {} bytes | Avg. Rate: {:.2} kbps", total_sent_bytes, send_throughput_kbps);
-    // Die Empfangsanzeige wird nicht mehr benötigt
-    // println!("Empfangen: {} bytes | Avg. Rate: {:.2} kbps", total_received_bytes, recv_throughput_kbps);
-    println!("----------------------------------------");
-
+    let elapsed = start_time.elapsed();
+    let stats = stream.stats();
     stream.shutdown().await?;
 
-    Ok(())
-}
\ No newline at end of file
+    let rtt_samples = std::mem::take(&mut *rtt_samples.lock().unwrap());
+    Ok(ClientReport {
+        id,
+        sent_bytes: total_sent_bytes,
+        elapsed,
+        rtt_samples,
+        packets_lost: stats.packets_lost,
+        retransmits: stats.retransmits,
+        timeseries,
+    })
+}
+
+/// `p`-Perzentil (0..=100) der sortierten `samples`
+fn percentile(sorted: &[Duration], p: f64) -> Duration {
+    if sorted.is_empty() {
+        return Duration::ZERO;
+    }
+    let rank = (p / 100.0 * (sorted.len() - 1) as f64).round() as usize;
+    sorted[rank]
+}
+
+/// Jain's Fairness-Index, 1.0 wenn sich alle Streams den Engpass gleich teilen
+fn fairness_index(throughputs: &[f64]) -> f64 {
+    let sum: f64 = throughputs.iter().sum();
+    let sum_squares: f64 = throughputs.iter().map(|x| x * x).sum();
+    if sum_squares == 0.0 {
+        return 0.0;
+    }
+    sum * sum / (throughputs.len() as f64 * sum_squares)
+}
+
+fn print_summary(reports: &[ClientReport]) {
+    let ms = |d: Duration| d.as_secs_f64() * 1000.0;
+
+    println!("\n----------------------------------------");
+    println!("Client: Test beendet, {} Stream(s).", reports.len());
+    for report in reports {
+        let mut rtts = report.rtt_samples.clone();
+        rtts.sort();
+        println!(
+            "Stream #{}: {:.2} s | Gesendet: {} bytes | Avg. Rate: {:.2} kbps | RTT p50 {:.1} ms, p99 {:.1} ms",
+            report.id,
+            report.elapsed.as_secs_f64(),
+            report.sent_bytes,
+            report.throughput_kbps(),
+            ms(percentile(&rtts, 50.0)),
+            ms(percentile(&rtts, 99.0)),
+        );
+    }
+
+    let throughputs: Vec<f64> = reports.iter().map(ClientReport::throughput_kbps).collect();
+    let mut rtts: Vec<Duration> = reports.iter().flat_map(|r| r.rtt_samples.iter().copied()).collect();
+    rtts.sort();
+    println!("Gesamt: {:.2} kbps", throughputs.iter().sum::<f64>());
+    println!("Fairness-Index (Jain): {:.3}", fairness_index(&throughputs));
+    println!(
+        "RTT über alle Streams: p50 {:.1} ms | p90 {:.1} ms | p99 {:.1} ms",
+        ms(percentile(&rtts, 50.0)),
+        ms(percentile(&rtts, 90.0)),
+        ms(percentile(&rtts, 99.0)),
+    );
+    println!("----------------------------------------");
+}
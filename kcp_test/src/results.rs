@@ -0,0 +1,135 @@
+//! Maschinenlesbare Ergebnisse für automatisierte Messreihen
+
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+use std::time::Duration;
+
+use serde::Serialize;
+
+use crate::{fairness_index, ClientReport, SecondSample};
+
+#[derive(Serialize)]
+struct Results<'a> {
+    streams: Vec<StreamResult<'a>>,
+    total: TotalResult,
+}
+
+#[derive(Serialize)]
+struct StreamResult<'a> {
+    id: usize,
+    duration_s: f64,
+    sent_bytes: u64,
+    /// Über die Sekunden-Stichproben
+    throughput_kbps: Distribution,
+    srtt_ms: Distribution,
+    packets_lost: u64,
+    retransmits: u32,
+    timeseries: &'a [SecondSample],
+}
+
+#[derive(Serialize)]
+struct TotalResult {
+    sent_bytes: u64,
+    throughput_kbps: f64,
+    fairness_index: f64,
+    srtt_ms: Distribution,
+    packets_lost: u64,
+    retransmits: u32,
+}
+
+/// Mittelwert und Perzentile einer Messgröße, alles `0` ohne Stichproben
+#[derive(Serialize, Default)]
+pub struct Distribution {
+    mean: f64,
+    min: f64,
+    p5: f64,
+    p50: f64,
+    p95: f64,
+    p99: f64,
+    max: f64,
+}
+
+impl Distribution {
+    fn new(mut values: Vec<f64>) -> Distribution {
+        if values.is_empty() {
+            return Distribution::default();
+        }
+        values.sort_by(f64::total_cmp);
+        let at = |p: f64| values[(p / 100.0 * (values.len() - 1) as f64).round() as usize];
+        Distribution {
+            mean: values.iter().sum::<f64>() / values.len() as f64,
+            min: values[0],
+            p5: at(5.0),
+            p50: at(50.0),
+            p95: at(95.0),
+            p99: at(99.0),
+            max: values[values.len() - 1],
+        }
+    }
+
+    pub fn of_durations(samples: &[Duration]) -> Distribution {
+        Distribution::new(samples.iter().map(|d| d.as_secs_f64() * 1000.0).collect())
+    }
+}
+
+/// `reports` nach `path` schreiben, `.csv` als Zeitreihe, sonst als JSON
+pub fn write(path: &Path, reports: &[ClientReport]) -> io::Result<()> {
+    let mut out = BufWriter::new(File::create(path)?);
+    if path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("csv")) {
+        write_csv(&mut out, reports)?;
+    } else {
+        serde_json::to_writer_pretty(&mut out, &results(reports))?;
+        writeln!(out)?;
+    }
+    out.flush()
+}
+
+fn results(reports: &[ClientReport]) -> Results<'_> {
+    let streams = reports
+        .iter()
+        .map(|report| StreamResult {
+            id: report.id,
+            duration_s: report.elapsed.as_secs_f64(),
+            sent_bytes: report.sent_bytes,
+            throughput_kbps: Distribution::new(report.timeseries.iter().map(|s| s.throughput_kbps).collect()),
+            srtt_ms: Distribution::of_durations(&report.rtt_samples),
+            packets_lost: report.packets_lost,
+            retransmits: report.retransmits,
+            timeseries: &report.timeseries,
+        })
+        .collect();
+
+    let throughputs: Vec<f64> = reports.iter().map(ClientReport::throughput_kbps).collect();
+    let rtts: Vec<Duration> = reports.iter().flat_map(|r| r.rtt_samples.iter().copied()).collect();
+    let total = TotalResult {
+        sent_bytes: reports.iter().map(|r| r.sent_bytes).sum(),
+        throughput_kbps: throughputs.iter().sum(),
+        fairness_index: fairness_index(&throughputs),
+        srtt_ms: Distribution::of_durations(&rtts),
+        packets_lost: reports.iter().map(|r| r.packets_lost).sum(),
+        retransmits: reports.iter().map(|r| r.retransmits).sum(),
+    };
+
+    Results { streams, total }
+}
+
+fn write_csv<W: Write>(out: &mut W, reports: &[ClientReport]) -> io::Result<()> {
+    writeln!(out, "stream,t,throughput_kbps,srtt_ms,target_bitrate_kbps,packets_lost,retransmits")?;
+    for report in reports {
+        for sample in &report.timeseries {
+            writeln!(
+                out,
+                "{},{:.3},{:.2},{:.2},{:.2},{},{}",
+                report.id,
+                sample.t,
+                sample.throughput_kbps,
+                sample.srtt_ms,
+                sample.target_bitrate_kbps,
+                sample.packets_lost,
+                sample.retransmits
+            )?;
+        }
+    }
+    Ok(())
+}
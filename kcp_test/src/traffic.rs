@@ -0,0 +1,123 @@
+//! Verkehrsmodelle der Clients
+//!
+//! `bulk` schreibt gleichmäßig `payload` Bytes, `video` schickt Frames als Bursts im Takt der Bildrate.
+//! Die Framegröße folgt der Zielbitrate, alle `gop` Frames kommt ein größeres I-Frame.
+
+use std::io;
+use std::time::Duration;
+
+use clap::{Args, ValueEnum};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Traffic {
+    /// Gleichmäßige Schreibvorgänge mit `payload` Bytes
+    Bulk,
+    /// Frame-Bursts mit fester Bildrate
+    Video,
+}
+
+/// Parameter des Videomodells
+#[derive(Args, Debug, Clone, Copy)]
+pub struct VideoArgs {
+    /// Bilder pro Sekunde
+    #[arg(long, default_value_t = 30.0)]
+    pub fps: f64,
+    /// Abstand der I-Frames in Frames
+    #[arg(long, default_value_t = 30)]
+    pub gop: u32,
+    /// Größe eines I-Frames relativ zu einem P-Frame
+    #[arg(long, default_value_t = 5.0)]
+    pub iframe_ratio: f64,
+    /// Zufällige Abweichung der Framegröße, 0.2 = ±20 %
+    #[arg(long, default_value_t = 0.2)]
+    pub frame_variance: f64,
+}
+
+impl Default for VideoArgs {
+    fn default() -> VideoArgs {
+        VideoArgs {
+            fps: 30.0,
+            gop: 30,
+            iframe_ratio: 5.0,
+            frame_variance: 0.2,
+        }
+    }
+}
+
+/// Erzeugt die Größen aufeinanderfolgender Frames
+pub struct VideoModel {
+    args: VideoArgs,
+    frame: u64,
+    rng: StdRng,
+}
+
+impl VideoModel {
+    pub fn new(args: VideoArgs, seed: u64) -> VideoModel {
+        VideoModel {
+            args,
+            frame: 0,
+            rng: StdRng::seed_from_u64(seed),
+        }
+    }
+
+    pub fn frame_interval(&self) -> Duration {
+        Duration::from_secs_f64(1.0 / self.args.fps)
+    }
+
+    /// Größe des nächsten Frames in Bytes, im Mittel über eine GOP ergibt sich `bitrate` bps
+    pub fn next_frame_size(&mut self, bitrate: f32) -> usize {
+        let gop = self.args.gop.max(1) as f64;
+        let ratio = self.args.iframe_ratio.max(1.0);
+        let p_frame = bitrate as f64 / 8.0 / self.args.fps * gop / (gop - 1.0 + ratio);
+
+        let is_iframe = self.frame.is_multiple_of(self.args.gop.max(1) as u64);
+        self.frame += 1;
+
+        let size = if is_iframe { p_frame * ratio } else { p_frame };
+        let variance = self.args.frame_variance.clamp(0.0, 1.0);
+        let factor = if variance > 0.0 {
+            1.0 + self.rng.gen_range(-variance..=variance)
+        } else {
+            1.0
+        };
+        ((size * factor) as usize).max(1)
+    }
+}
+
+/// Zeitplan der Schreibvorgänge eines Clients
+pub struct Pattern {
+    payload: usize,
+    video: Option<VideoModel>,
+}
+
+impl Pattern {
+    pub fn new(traffic: Traffic, video: VideoArgs, payload: usize, seed: u64) -> io::Result<Pattern> {
+        let video = match traffic {
+            Traffic::Bulk => None,
+            Traffic::Video if video.fps > 0.0 => Some(VideoModel::new(video, seed)),
+            Traffic::Video => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "--fps muss größer als 0 sein"));
+            }
+        };
+        Ok(Pattern { payload, video })
+    }
+
+    /// Frames werden im Takt der Bildrate gesendet, unabhängig von der Bitrate
+    pub fn is_video(&self) -> bool {
+        self.video.is_some()
+    }
+
+    /// Länge des nächsten Bursts und Abstand zum folgenden bei `bitrate` bps
+    pub fn next(&mut self, bitrate: f32) -> (usize, Duration) {
+        match self.video {
+            // Ein Frame pro Intervall, die Größe folgt der Bitrate
+            Some(ref mut video) => (video.next_frame_size(bitrate), video.frame_interval()),
+            None => {
+                let bits_to_send = (self.payload * 8) as f32;
+                let sleep_duration_secs = if bitrate > 0.0 { bits_to_send / bitrate } else { 0.1 };
+                (self.payload, Duration::from_secs_f32(sleep_duration_secs))
+            }
+        }
+    }
+}
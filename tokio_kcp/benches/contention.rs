@@ -0,0 +1,136 @@
+//! Throughput of sessions read and written at the same time
+//!
+//! Reader, writer, input and update of a session all go through it. Both sides send while they receive,
+//! with a controller that never limits the rate, what is left to measure is how they get along.
+//!
+//! ```plain
+//! cargo bench -p tokio_kcp --bench contention
+//! ```
+
+use std::time::{Duration, Instant};
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::{Builder, Runtime};
+use tokio_kcp::{CongestionController, KcpConfig, KcpListener, KcpNoDelayConfig, KcpReadHalf, KcpStream, KcpWriteHalf};
+
+// Messages each side sends per iteration
+const MESSAGES: usize = 1000;
+
+#[derive(Debug)]
+struct Unlimited;
+
+impl CongestionController for Unlimited {
+    fn on_packet_sent(&mut self, _sn: u32, _size: usize) {}
+
+    fn on_ack(&mut self, _sn: u32, _ack_time: std::time::Instant) {}
+
+    fn on_loss(&mut self, _sn: u32) {}
+
+    fn on_rtt(&mut self) {}
+
+    fn get_target_bitrate(&self) -> f32 {
+        10_000_000_000.0
+    }
+
+    fn get_pacing_rate(&self) -> f32 {
+        10_000_000_000.0
+    }
+
+    fn get_congestion_window(&self) -> f32 {
+        f32::MAX
+    }
+
+    fn get_s_rtt(&self) -> f32 {
+        0.0
+    }
+}
+
+fn config() -> KcpConfig {
+    KcpConfig {
+        nodelay: KcpNoDelayConfig::fastest(),
+        snd_wnd: 512,
+        rcv_wnd: 512,
+        ..Default::default()
+    }
+}
+
+type Halves = Vec<(KcpReadHalf, KcpWriteHalf)>;
+
+// The listener has to outlive its sessions
+async fn connect() -> (KcpListener, Halves) {
+    let mut listener = KcpListener::bind_with_controller(config(), "127.0.0.1:0", || Box::new(Unlimited))
+        .await
+        .unwrap();
+    let addr = listener.local_addr().unwrap();
+    let mut client = KcpStream::connect_with_controller(&config(), addr, Box::new(Unlimited))
+        .await
+        .unwrap();
+    let (sent, accepted) = tokio::join!(client.send(b"HELLO"), listener.accept());
+    sent.unwrap();
+    let (mut server, _) = accepted.unwrap();
+    let mut buf = [0u8; 16];
+    server.recv(&mut buf).await.unwrap();
+    (listener, vec![client.into_split(), server.into_split()])
+}
+
+// Both sides send `MESSAGES` of `size` bytes while reading the other's, reader and writer on tasks of their own
+async fn duplex(halves: Halves, size: usize) -> (Duration, Halves) {
+    let start = Instant::now();
+    let mut tasks = Vec::new();
+    for (mut reader, mut writer) in halves {
+        let write = tokio::spawn(async move {
+            let message = vec![0x5A; size];
+            for _ in 0..MESSAGES {
+                writer.send(&message).await.unwrap();
+            }
+            writer
+        });
+        let read = tokio::spawn(async move {
+            let mut buf = vec![0u8; size];
+            let mut received = 0;
+            while received < MESSAGES * size {
+                received += reader.recv(&mut buf).await.unwrap();
+            }
+            reader
+        });
+        tasks.push((read, write));
+    }
+
+    let mut halves = Vec::new();
+    for (read, write) in tasks {
+        halves.push((read.await.unwrap(), write.await.unwrap()));
+    }
+    (start.elapsed(), halves)
+}
+
+fn runtime() -> Runtime {
+    Builder::new_multi_thread().worker_threads(4).enable_all().build().unwrap()
+}
+
+fn bench_duplex(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("duplex");
+    group.sample_size(10);
+
+    for size in [64, 1024] {
+        // Bytes moved in both directions
+        group.throughput(Throughput::Bytes((2 * MESSAGES * size) as u64));
+        let (_listener, halves) = rt.block_on(connect());
+        let mut halves = Some(halves);
+        group.bench_with_input(BenchmarkId::from_parameter(size), &size, |b, &size| {
+            b.iter_custom(|iters| {
+                let mut total = Duration::ZERO;
+                for _ in 0..iters {
+                    let (elapsed, returned) = rt.block_on(duplex(halves.take().unwrap(), size));
+                    halves = Some(returned);
+                    total += elapsed;
+                }
+                total
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_duplex);
+criterion_main!(benches);
@@ -0,0 +1,230 @@
+//! Costs on the per-packet paths of a session
+//!
+//! - `input`: KCP segments through `KcpSocket::input` until their messages wait for the reader
+//! - `flush`: queued messages cut into segments and handed to the pacer queue
+//! - `pacer`: packets through the pacer queue
+//! - `feedback`: congestion feedback encoded by the receiver and decoded by the sender
+//! - `scream`: SCReAM taking the acknowledgements of one feedback packet
+//!
+//! ```plain
+//! cargo bench -p tokio_kcp --features bench --bench hot_paths
+//! ```
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    net::SocketAddr,
+    rc::Rc,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Instant,
+};
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use kcp::Kcp;
+use tokio::runtime::{Builder, Runtime};
+use tokio_kcp::{
+    bench::{decode_feedback, encode_feedback, FeedbackPacketInfo, Queue, Socket},
+    CongestionController, DatagramTransport, EcnCodepoint, FeedbackFormat, KcpConfig, KcpNoDelayConfig,
+    PacerQueuePolicy, ScreamCongestionControl,
+};
+
+const CONV: u32 = 0x1234;
+// Messages, packets or acknowledgements per iteration
+const BATCH: usize = 64;
+const PAYLOAD: usize = 1024;
+
+/// Transport sending into the void
+#[derive(Debug)]
+struct Discard;
+
+impl DatagramTransport for Discard {
+    fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], _target: SocketAddr) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_recv_from(
+        &self,
+        _cx: &mut Context<'_>,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(peer())
+    }
+}
+
+/// KCP output keeping every packet
+#[derive(Clone, Default)]
+struct Capture(Rc<RefCell<Vec<Vec<u8>>>>);
+
+impl Write for Capture {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().push(buf.to_vec());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+fn peer() -> SocketAddr {
+    "127.0.0.1:4000".parse().unwrap()
+}
+
+// Pacer tasks of the sockets run here
+fn runtime() -> Runtime {
+    Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap()
+}
+
+// Without KCP's congestion window a flush sends the whole batch
+fn socket() -> Socket {
+    let config = KcpConfig {
+        nodelay: KcpNoDelayConfig::fastest(),
+        ..Default::default()
+    };
+    Socket::new(&config, CONV, Arc::new(Discard), peer()).unwrap()
+}
+
+// `BATCH` messages of one segment each, as a peer sends them
+fn segments() -> Vec<Vec<u8>> {
+    let capture = Capture::default();
+    let mut kcp = Kcp::new(CONV, capture.clone());
+    kcp.set_nodelay(true, 10, 2, true);
+    kcp.set_wndsize(BATCH as u16, BATCH as u16);
+    kcp.update(0).unwrap();
+    for _ in 0..BATCH {
+        kcp.send(&[0x5A; PAYLOAD]).unwrap();
+    }
+    kcp.flush().unwrap();
+    capture.0.take()
+}
+
+fn bench_input(c: &mut Criterion) {
+    let rt = runtime();
+    let _guard = rt.enter();
+    let packets = segments();
+    assert_eq!(packets.len(), BATCH);
+
+    let mut group = c.benchmark_group("input");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("segments", |b| {
+        b.iter_batched(
+            socket,
+            |mut socket| {
+                for packet in &packets {
+                    socket.input(packet).unwrap();
+                }
+                socket
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn bench_flush(c: &mut Criterion) {
+    let rt = runtime();
+    let _guard = rt.enter();
+
+    let mut group = c.benchmark_group("flush");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("segments", |b| {
+        b.iter_batched(
+            || {
+                let mut socket = socket();
+                for _ in 0..BATCH {
+                    socket.send(&[0x5A; PAYLOAD]).unwrap();
+                }
+                socket
+            },
+            |mut socket| {
+                socket.flush().unwrap();
+                socket
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+fn bench_pacer(c: &mut Criterion) {
+    let queue = Queue::new(BATCH, PacerQueuePolicy::Block, 1500);
+    let packet = [0x5A; 1200];
+
+    let mut group = c.benchmark_group("pacer");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("push_pop", |b| {
+        b.iter(|| {
+            for _ in 0..BATCH {
+                queue.push(&packet).unwrap();
+            }
+            while queue.pop().is_some() {}
+        })
+    });
+    group.finish();
+}
+
+fn bench_feedback(c: &mut Criterion) {
+    let entries: Vec<_> = (0..BATCH as u32)
+        .map(|sn| FeedbackPacketInfo {
+            seq_number: sn,
+            reception_time_ms: 1_700_000_000_000 + sn as u64,
+            ecn: EcnCodepoint::NotEct,
+        })
+        .collect();
+    let report_time_ms = 1_700_000_000_000 + BATCH as u64;
+
+    let mut group = c.benchmark_group("feedback");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    for (name, format) in [("native", FeedbackFormat::Native), ("rfc8888", FeedbackFormat::Rfc8888)] {
+        let mut buf = Vec::new();
+        group.bench_function(BenchmarkId::new("encode", name), |b| {
+            b.iter(|| {
+                buf.clear();
+                encode_feedback(format, &entries, report_time_ms, &mut buf);
+            })
+        });
+        group.bench_function(BenchmarkId::new("decode", name), |b| {
+            b.iter(|| decode_feedback(format, &buf, BATCH as u32 - 1, 0))
+        });
+    }
+    group.finish();
+}
+
+fn bench_scream(c: &mut Criterion) {
+    // The receiver's feedback acknowledging `BATCH` packets
+    let mut receiver = ScreamCongestionControl::new();
+    let now = Instant::now();
+    for sn in 0..BATCH as u32 {
+        receiver.on_packet_received(sn, now, EcnCodepoint::NotEct);
+    }
+    let mut feedback = Vec::new();
+    assert!(receiver.write_feedback(&mut feedback, usize::MAX));
+
+    let mut group = c.benchmark_group("scream");
+    group.throughput(Throughput::Elements(BATCH as u64));
+    group.bench_function("acks", |b| {
+        b.iter_batched(
+            || {
+                let mut sender = ScreamCongestionControl::new();
+                for sn in 0..BATCH as u32 {
+                    sender.on_packet_sent(sn, 1200);
+                }
+                sender
+            },
+            |mut sender| {
+                sender.on_feedback(&feedback, Instant::now());
+                sender
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_input, bench_flush, bench_pacer, bench_feedback, bench_scream);
+criterion_main!(benches);
@@ -1,4 +1,4 @@
-use std::{net::SocketAddr, str};
+use std::net::SocketAddr;
 
 use tokio::io::{stdin, AsyncReadExt, AsyncWriteExt};
 use tokio_kcp::{KcpConfig, KcpStream};
@@ -19,6 +19,6 @@ async fn main() {
         let n = i.read(&mut buffer).await.unwrap();
         stream.write_all(&buffer[..n]).await.unwrap();
 
-        let n = stream.read(&mut buffer).await.unwrap();
+        let _n = stream.read(&mut buffer).await.unwrap();
     }
 }
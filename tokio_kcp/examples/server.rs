@@ -1,12 +1,12 @@
 use std::{net::SocketAddr, time::Duration};
 
 use byte_string::ByteStr;
-use log::{debug, error, info};
 use tokio::{
     io::{AsyncReadExt, AsyncWriteExt},
     time,
 };
 use tokio_kcp::{KcpConfig, KcpListener};
+use tracing::{debug, error, info};
 
 #[tokio::main]
 async fn main() {
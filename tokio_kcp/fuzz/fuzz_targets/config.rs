@@ -0,0 +1,27 @@
+#![no_main]
+
+use kcp::Kcp;
+use libfuzzer_sys::fuzz_target;
+use tokio_kcp::KcpConfig;
+use tokio_kcp_fuzz::socket;
+
+fuzz_target!(|data: &[u8]| {
+    let Ok(text) = std::str::from_utf8(data) else {
+        return;
+    };
+    for config in [KcpConfig::from_toml_str(text), KcpConfig::from_json_str(text)] {
+        let Ok(config) = config else {
+            continue;
+        };
+        if config.validate().is_err() {
+            continue;
+        }
+        // A valid config applies and makes a working socket
+        let mut kcp = Kcp::new(1, std::io::sink());
+        config.apply_config(&mut kcp);
+        let mut socket = socket(&config).expect("valid config refused");
+        let _ = socket.send(&[0x5A; 4096]);
+        let _ = socket.flush();
+        let _ = socket.update();
+    }
+});
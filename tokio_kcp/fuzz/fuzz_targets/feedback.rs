@@ -0,0 +1,29 @@
+#![no_main]
+
+use std::time::{Duration, Instant};
+
+use libfuzzer_sys::fuzz_target;
+use tokio_kcp::{
+    bench::{decode_feedback, DATAGRAM_SN_FLAG},
+    CongestionController, FeedbackFormat, ScreamCongestionControl, ScreamConfig,
+};
+
+// Sequence numbers a sender has in flight, half of them datagrams
+const IN_FLIGHT: u32 = 32;
+
+fuzz_target!(|input: (bool, u32, u32, Vec<u8>)| {
+    let (rfc8888, highest_sn, highest_datagram_sn, data) = input;
+    let format = if rfc8888 { FeedbackFormat::Rfc8888 } else { FeedbackFormat::Native };
+    let _ = decode_feedback(format, &data, highest_sn, highest_datagram_sn | DATAGRAM_SN_FLAG);
+
+    let mut scream = ScreamCongestionControl::with_config(ScreamConfig::default(), format);
+    for sn in 0..IN_FLIGHT / 2 {
+        scream.on_packet_sent(sn, 1000);
+        scream.on_packet_sent(sn | DATAGRAM_SN_FLAG, 1000);
+    }
+    let now = Instant::now();
+    scream.on_feedback(&data, now + Duration::from_millis(50));
+    scream.on_rtt();
+    scream.on_feedback(&data, now + Duration::from_millis(100));
+    let _ = scream.stats();
+});
@@ -0,0 +1,34 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use tokio_kcp::{FecConfig, FeedbackFormat, KcpConfig, StreamMode};
+use tokio_kcp_fuzz::socket;
+
+// The first byte picks the session setup, every datagram is followed by a read, every other by a send
+fuzz_target!(|input: (u8, Vec<Vec<u8>>)| {
+    let (setup, packets) = input;
+    let mut config = KcpConfig::default();
+    if setup & 1 != 0 {
+        config.feedback_format = FeedbackFormat::Rfc8888;
+    }
+    if setup & 2 != 0 {
+        config.fec = Some(FecConfig::default());
+    }
+    if setup & 4 != 0 {
+        config.stream_mode = StreamMode::ByteStream;
+    }
+    let Some(mut socket) = socket(&config) else {
+        return;
+    };
+
+    let mut buf = vec![0; 64 * 1024];
+    for (i, packet) in packets.iter().enumerate() {
+        let _ = socket.input(packet);
+        let _ = socket.recv(&mut buf);
+        if i % 2 == 1 {
+            let _ = socket.send(&packet[..packet.len().min(4096)]);
+            let _ = socket.update();
+        }
+    }
+    let _ = socket.flush();
+});
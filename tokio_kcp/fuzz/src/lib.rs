@@ -0,0 +1,61 @@
+//! Fuzz targets feeding network data and configs to the session internals
+//!
+//! - `input`: datagrams of any kind through `KcpSocket::input`, interleaved with reads, sends and updates
+//! - `feedback`: congestion feedback of both formats through the decoders and `ScreamCongestionControl`
+//! - `config`: TOML and JSON configs through validation, `apply_config` and socket creation
+//!
+//! ```plain
+//! cd tokio_kcp && cargo +nightly fuzz run input
+//! ```
+//!
+//! None of them may panic, malformed data is dropped and invalid configs are refused.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{Arc, OnceLock},
+    task::{Context, Poll},
+};
+
+use tokio::runtime::{Builder, Runtime};
+use tokio_kcp::{bench::Socket, DatagramTransport, EcnCodepoint, KcpConfig};
+
+pub const CONV: u32 = 0x1234;
+
+/// Transport sending into the void
+#[derive(Debug)]
+struct Discard;
+
+impl DatagramTransport for Discard {
+    fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], _target: SocketAddr) -> Poll<io::Result<usize>> {
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_recv_from(
+        &self,
+        _cx: &mut Context<'_>,
+        _buf: &mut [u8],
+    ) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+        Poll::Pending
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(peer())
+    }
+}
+
+fn peer() -> SocketAddr {
+    SocketAddr::from(([127, 0, 0, 1], 4000))
+}
+
+/// Runtime of the pacer tasks, its worker lets them end once their socket is dropped
+pub fn runtime() -> &'static Runtime {
+    static RUNTIME: OnceLock<Runtime> = OnceLock::new();
+    RUNTIME.get_or_init(|| Builder::new_multi_thread().worker_threads(1).enable_all().build().unwrap())
+}
+
+/// Socket of `CONV` sending into the void, `None` if `config` is refused
+pub fn socket(config: &KcpConfig) -> Option<Socket> {
+    let _guard = runtime().enter();
+    Socket::new(config, CONV, Arc::new(Discard), peer()).ok()
+}
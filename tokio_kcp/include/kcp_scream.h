@@ -0,0 +1,40 @@
+/* C bindings of tokio_kcp, built with `cargo rustc -p tokio_kcp --release --features ffi --crate-type cdylib` */
+
+#ifndef KCP_SCREAM_H
+#define KCP_SCREAM_H
+
+#include <stddef.h>
+#include <stdint.h>
+#include <sys/types.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct KcpScreamHandle KcpScreamHandle;
+
+/* New target bitrate in bits per second, runs on the handle's runtime thread */
+typedef void (*KcpScreamBitrateCallback)(void *user_data, float target_bitrate);
+
+/* Connect to "host:port", config_json may be NULL, timeout_ms 0 skips waiting for the handshake. NULL on failure */
+KcpScreamHandle *kcp_scream_connect(const char *addr, const char *config_json, uint32_t timeout_ms);
+
+/* Bytes queued or -1 */
+ssize_t kcp_scream_send(KcpScreamHandle *handle, const uint8_t *data, size_t len);
+
+/* Bytes received or -1, blocks until data arrives */
+ssize_t kcp_scream_recv(KcpScreamHandle *handle, uint8_t *buf, size_t len);
+
+float kcp_scream_get_target_bitrate(const KcpScreamHandle *handle);
+
+/* NULL callback removes it */
+void kcp_scream_set_bitrate_callback(KcpScreamHandle *handle, KcpScreamBitrateCallback callback, void *user_data);
+
+/* Flush, close and free the handle */
+void kcp_scream_close(KcpScreamHandle *handle);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* KCP_SCREAM_H */
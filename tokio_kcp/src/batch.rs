@@ -0,0 +1,172 @@
+//! Batched UDP I/O
+//!
+//! On Linux up to `KcpConfig::udp_batch_size` datagrams are moved per `sendmmsg` / `recvmmsg`
+//! call, elsewhere (and with a batch size of 1) every datagram takes its own syscall.
+//! Other transports batch through `DatagramTransport::poll_send_batch` / `poll_recv_batch`.
+
+use std::{io, net::SocketAddr};
+
+use bytes::BytesMut;
+use futures_util::future;
+
+use crate::{ecn::EcnCodepoint, transport::DatagramTransport};
+
+/// Largest UDP payload
+const MAX_DATAGRAM_SIZE: usize = 65536;
+
+/// Buffers for up to `batch_size` received datagrams
+pub struct RecvBatch {
+    buffers: Vec<Vec<u8>>,
+    meta: Vec<(usize, SocketAddr, EcnCodepoint)>,
+}
+
+impl RecvBatch {
+    pub fn new(batch_size: usize) -> RecvBatch {
+        let batch_size = batch_size.max(1);
+        RecvBatch {
+            buffers: vec![vec![0u8; MAX_DATAGRAM_SIZE]; batch_size],
+            meta: vec![(0, SocketAddr::from(([0, 0, 0, 0], 0)), EcnCodepoint::NotEct); batch_size],
+        }
+    }
+
+    /// Datagram `i` with its source address and ECN codepoint
+    pub fn get_mut(&mut self, i: usize) -> (&mut [u8], SocketAddr, EcnCodepoint) {
+        let (n, addr, ecn) = self.meta[i];
+        (&mut self.buffers[i][..n], addr, ecn)
+    }
+
+    /// Receive at least one datagram, returns the number received
+    ///
+    /// `NotEct` is reported when `ecn` is disabled.
+    pub async fn recv(&mut self, transport: &dyn DatagramTransport, ecn: bool) -> io::Result<usize> {
+        let (buffers, meta) = (&mut self.buffers, &mut self.meta);
+        let n = future::poll_fn(|cx| transport.poll_recv_batch(cx, buffers, meta)).await?;
+        if !ecn {
+            for (_, _, codepoint) in &mut self.meta[..n] {
+                *codepoint = EcnCodepoint::NotEct;
+            }
+        }
+        Ok(n)
+    }
+}
+
+/// Send all `packets` to `addr`, batched when more than one is queued
+pub async fn send_batch(transport: &dyn DatagramTransport, packets: &[BytesMut], addr: SocketAddr) -> io::Result<()> {
+    let mut sent = 0;
+    while sent < packets.len() {
+        sent += future::poll_fn(|cx| transport.poll_send_batch(cx, &packets[sent..], addr)).await?;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+pub(crate) mod sys {
+    use std::{io, mem, net::SocketAddr, os::unix::io::RawFd, ptr};
+
+    use bytes::BytesMut;
+
+    use crate::ecn::{sys as ecn_sys, EcnCodepoint};
+
+    // Room for a single int-sized control message per datagram, aligned for cmsghdr
+    type Control = [u64; 8];
+
+    pub fn recvmmsg(
+        fd: RawFd,
+        buffers: &mut [Vec<u8>],
+        meta: &mut [(usize, SocketAddr, EcnCodepoint)],
+    ) -> io::Result<usize> {
+        let batch_size = buffers.len();
+        let mut addrs: Vec<libc::sockaddr_storage> = vec![unsafe { mem::zeroed() }; batch_size];
+        let mut iovs: Vec<libc::iovec> = buffers
+            .iter_mut()
+            .map(|buf| libc::iovec {
+                iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+                iov_len: buf.len(),
+            })
+            .collect();
+        let mut controls: Vec<Control> = vec![[0u64; 8]; batch_size];
+
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(batch_size);
+        for i in 0..batch_size {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = &mut addrs[i] as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+            msg.msg_hdr.msg_iov = &mut iovs[i];
+            msg.msg_hdr.msg_iovlen = 1;
+            msg.msg_hdr.msg_control = controls[i].as_mut_ptr() as *mut libc::c_void;
+            msg.msg_hdr.msg_controllen = mem::size_of::<Control>() as _;
+            msgs.push(msg);
+        }
+
+        let n = unsafe { libc::recvmmsg(fd, msgs.as_mut_ptr(), batch_size as _, 0, ptr::null_mut()) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let n = n as usize;
+        for i in 0..n {
+            let ecn = unsafe { ecn_sys::cmsg_ecn(&msgs[i].msg_hdr) };
+            let addr = ecn_sys::sockaddr_to_socket_addr(&addrs[i])?;
+            meta[i] = (msgs[i].msg_len as usize, addr, ecn);
+        }
+        Ok(n)
+    }
+
+    pub fn sendmmsg(fd: RawFd, packets: &[BytesMut], addr: &SocketAddr) -> io::Result<usize> {
+        let (mut addr, addr_len) = ecn_sys::socket_addr_to_sockaddr(addr);
+        let mut iovs: Vec<libc::iovec> = packets
+            .iter()
+            .map(|packet| libc::iovec {
+                iov_base: packet.as_ptr() as *mut libc::c_void,
+                iov_len: packet.len(),
+            })
+            .collect();
+
+        let mut msgs: Vec<libc::mmsghdr> = Vec::with_capacity(packets.len());
+        for iov in iovs.iter_mut() {
+            let mut msg: libc::mmsghdr = unsafe { mem::zeroed() };
+            msg.msg_hdr.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+            msg.msg_hdr.msg_namelen = addr_len;
+            msg.msg_hdr.msg_iov = iov;
+            msg.msg_hdr.msg_iovlen = 1;
+            msgs.push(msg);
+        }
+
+        let n = unsafe { libc::sendmmsg(fd, msgs.as_mut_ptr(), msgs.len() as _, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(n as usize)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::net::UdpSocket;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn batch_roundtrip() {
+        let s1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s1_addr = s1.local_addr().unwrap();
+
+        let packets: Vec<BytesMut> = (0..5u8).map(|i| BytesMut::from(&vec![i; 100 + i as usize][..])).collect();
+        send_batch(&s1, &packets, s2.local_addr().unwrap()).await.unwrap();
+
+        let mut batch = RecvBatch::new(4);
+        let mut received = Vec::new();
+        while received.len() < packets.len() {
+            let n = batch.recv(&s2, false).await.unwrap();
+            assert!((1..=4).contains(&n));
+            for i in 0..n {
+                let (packet, addr, ecn) = batch.get_mut(i);
+                assert_eq!(addr, s1_addr);
+                assert_eq!(ecn, EcnCodepoint::NotEct);
+                received.push(packet.to_vec());
+            }
+        }
+        assert_eq!(received, packets);
+    }
+}
@@ -0,0 +1,91 @@
+//! Internals measured by the benchmarks in `benches/` and driven by the fuzz targets in `fuzz/`, not part of the API
+
+use std::{
+    io::{self, ErrorKind},
+    net::SocketAddr,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::Instant,
+};
+
+use kcp::{Error as KcpError, KcpResult};
+
+pub use crate::feedback::{
+    decode as decode_feedback, encode_into as encode_feedback, FeedbackPacketInfo, DATAGRAM_SN_FLAG,
+};
+use crate::{
+    ecn::EcnCodepoint,
+    pacer::{PacerQueue, PacerQueuePolicy},
+    skcp::KcpSocket,
+    transport::DatagramTransport,
+    KcpConfig,
+};
+
+/// `KcpSocket` without a session driving it
+#[derive(Debug)]
+pub struct Socket(KcpSocket);
+
+impl Socket {
+    /// Socket of `conv` talking to `peer` through `transport`, its pacer task needs a runtime
+    pub fn new(
+        config: &KcpConfig,
+        conv: u32,
+        transport: Arc<dyn DatagramTransport>,
+        peer: SocketAddr,
+    ) -> KcpResult<Socket> {
+        let controller = Box::new(config.scream_controller()?);
+        let (socket, _) = KcpSocket::new(config, conv, transport, peer, config.stream_mode, controller)?;
+        Ok(Socket(socket))
+    }
+
+    /// See `KcpSocket::input_packet`
+    pub fn input(&mut self, packet: &[u8]) -> KcpResult<bool> {
+        self.0.input_packet(packet, EcnCodepoint::NotEct)
+    }
+
+    /// Queue `buf` as one message, `WouldBlock` if the send window is full
+    pub fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        match self.0.poll_send(&mut Context::from_waker(Waker::noop()), buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(io::Error::from(ErrorKind::WouldBlock).into()),
+        }
+    }
+
+    pub fn flush(&mut self) -> KcpResult<()> {
+        self.0.flush()
+    }
+
+    pub fn update(&mut self) -> KcpResult<Instant> {
+        self.0.update()
+    }
+
+    /// Next message, `RecvQueueEmpty` if there is none
+    pub fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        match self.0.poll_recv(&mut Context::from_waker(Waker::noop()), buf) {
+            Poll::Ready(result) => result,
+            Poll::Pending => Err(KcpError::RecvQueueEmpty),
+        }
+    }
+}
+
+/// Pacer queue without a pacer task taking packets from it
+#[derive(Debug)]
+pub struct Queue(PacerQueue);
+
+impl Queue {
+    pub fn new(capacity: usize, policy: PacerQueuePolicy, buffer_size: usize) -> Queue {
+        Queue(PacerQueue::new(capacity, policy, buffer_size))
+    }
+
+    /// Queue a copy of `packet` in a pooled buffer
+    pub fn push(&self, packet: &[u8]) -> io::Result<()> {
+        let mut buffer = self.0.buffer();
+        buffer.extend_from_slice(packet);
+        self.0.push(buffer)
+    }
+
+    /// Take the next packet like the pacer does once it may go out, returns its length
+    pub fn pop(&self) -> Option<usize> {
+        self.0.pop_sent()
+    }
+}
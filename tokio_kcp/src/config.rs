@@ -1,9 +1,35 @@
-use std::{io::Write, time::Duration};
+use std::{
+    fs,
+    io::{self, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::Arc,
+    time::Duration,
+};
 
-use kcp::Kcp;
+use kcp::{Error as KcpError, Kcp, KcpResult, KCP_MAX_FRAGMENTS, KCP_OVERHEAD};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    conv::{ConvAllocator, RandomConvAllocator},
+    crypto::{self, PreSharedKey},
+    fec::{self, FecConfig},
+    feedback::{FeedbackFormat, FeedbackPolicy},
+    metrics::{MetricsSink, NoopSink},
+    obfuscation::Obfuscator,
+    pacer::{PacerQueuePolicy, SharedPacer},
+    qlog::QlogWriter,
+    scream::{ScreamCongestionControl, ScreamConfig, MAX_TARGET_BITRATE, MIN_TARGET_BITRATE},
+};
+
+// Smallest MTU KCP accepts
+const MIN_MTU: usize = 50;
+// Largest payload of a UDP datagram over IPv4
+const MAX_UDP_PAYLOAD: usize = 65_507;
 
 /// Kcp Delay Config
-#[derive(Debug, Clone, Copy)]
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+#[serde(default)]
 pub struct KcpNoDelayConfig {
     /// Enable nodelay
     pub nodelay: bool,
@@ -26,6 +52,57 @@ impl Default for KcpNoDelayConfig {
     }
 }
 
+/// Small datagrams sent twice, see `KcpConfig::duplicate`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DuplicateConfig {
+    /// Datagrams carrying KCP data up to this size (bytes) are sent twice
+    pub max_size: usize,
+    /// Time between a datagram and its copy, a loss burst shorter than this spares one of them
+    #[serde(with = "duration_ms")]
+    pub delay: Duration,
+}
+
+impl Default for DuplicateConfig {
+    fn default() -> DuplicateConfig {
+        DuplicateConfig {
+            max_size: 256,
+            delay: Duration::from_millis(5),
+        }
+    }
+}
+
+/// Receiver-side delayed ACKs, see `KcpConfig::delayed_ack`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct DelayedAckConfig {
+    /// ACKs sent at once as soon as this many segments wait to be acknowledged
+    pub max_packets: usize,
+    /// Longest time a segment waits for its ACK, keep it below the peer's RTO
+    #[serde(with = "duration_ms")]
+    pub max_delay: Duration,
+}
+
+impl Default for DelayedAckConfig {
+    fn default() -> DelayedAckConfig {
+        DelayedAckConfig {
+            max_packets: 8,
+            max_delay: Duration::from_millis(20),
+        }
+    }
+}
+
+/// How KCP frames the data of a session, see `KcpConfig::stream_mode`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum StreamMode {
+    /// Sends are merged and split into segments like TCP, `recv_msg` returns whatever arrived
+    ByteStream,
+    /// Every send is delivered whole and alone by `KcpStream::recv_msg`
+    #[default]
+    Message,
+}
+
 impl KcpNoDelayConfig {
     /// Get a fastest configuration
     ///
@@ -59,26 +136,167 @@ impl KcpNoDelayConfig {
 }
 
 /// Kcp Config
-#[derive(Debug, Clone, Copy)]
+///
+/// Durations are written as milliseconds in TOML / JSON, `metrics` and `qlog` can't be loaded and
+/// stay the default. Missing fields take their default value.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
 pub struct KcpConfig {
     /// Max Transmission Unit
     pub mtu: usize,
     /// nodelay
     pub nodelay: KcpNoDelayConfig,
-    /// Send window size
-    pub wnd_size: (u16, u16),
+    /// Largest send window (segments), the congestion controller's window is capped to it
+    pub snd_wnd: u16,
+    /// Receive window (segments) advertised to the peer, at least 128
+    pub rcv_wnd: u16,
+    /// Lower bound of the RTO, `None` keeps the one picked by `nodelay` (30ms / 100ms)
+    #[serde(with = "option_duration_ms")]
+    pub rto_min: Option<Duration>,
+    /// Upper bound of the RTO, retransmission backoff included
+    #[serde(with = "duration_ms")]
+    pub rto_max: Duration,
+    /// Duplicate ACKs that trigger a fast retransmission, `None` keeps `nodelay.resend`
+    pub fast_resend_threshold: Option<u32>,
+    /// RTO growth of every retransmission, at least 1. `None` keeps KCP's (x2, x1.5 with `nodelay`)
+    pub backoff_factor: Option<f32>,
+    /// Retransmissions of a segment before the session fails with `PeerUnreachable`, `None` retries forever.
+    /// The session fails once the last retransmission timed out as well, not as soon as it was sent like
+    /// KCP's `dead_link`
+    pub max_retransmissions: Option<u32>,
     /// Session expire duration, default is 90 seconds
+    #[serde(with = "option_duration_ms")]
     pub session_expire: Option<Duration>,
     /// Flush KCP state immediately after write
     pub flush_write: bool,
     /// Flush ACKs immediately after input
     pub flush_acks_input: bool,
-    /// Stream mode
-    pub stream: bool,
+    /// Batch ACKs until enough segments arrived or the oldest waited long enough, instead of sending them
+    /// every `nodelay.interval`. Saves uplink on asymmetric links, pending ACKs also go out with every
+    /// SCReAM feedback and pending feedback with every ACK batch, so both share one send. `None` by default
+    pub delayed_ack: Option<DelayedAckConfig>,
+    /// Framing of sends, `StreamMode::Message` by default. Both peers should use the same one, a
+    /// listener picks it per connection with `KcpListener::set_config_hook`. The `stream` key of older
+    /// configs is still read, `stream = true` is `byte_stream`
+    #[serde(alias = "stream", deserialize_with = "stream_mode::deserialize")]
+    pub stream_mode: StreamMode,
+    /// Passthrough mode: sends go out as unreliable datagrams with only a sn header, paced and tracked
+    /// by the congestion controller but never retransmitted. For media with its own RTX or FEC, needs
+    /// message mode and messages that fit into one datagram
+    pub passthrough: bool,
+    /// Unread received bytes a session buffers, the advertised receive window shrinks as they
+    /// pile up. `None` only bounds buffering by `rcv_wnd`. In message mode it has to hold the
+    /// largest message, `KCP_MAX_FRAGMENTS` segments
+    pub max_recv_buffer_bytes: Option<usize>,
     /// Allow recv 0 byte packet. KCP Segments with 0 byte data are skipped by default.
     pub allow_recv_empty_packet: bool,
     /// Used to enable or disable the external congestion control (SCReAM)
     pub use_external_congestion_control: bool,
+    /// Shrink the 24 byte KCP header of data and ACK segments to 13-16 bytes, for small payloads like
+    /// voice. Negotiated by the handshake, a side only compresses if the peer enabled it as well
+    pub header_compression: bool,
+    /// Mark outgoing packets ECT(1) and report CE marks to the sender (unix only)
+    pub ecn: bool,
+    /// DSCP of outgoing packets (0..=63), e.g. `DSCP_EF` for media, `None` keeps the socket's (unix only)
+    pub dscp: Option<u8>,
+    /// TTL / hop limit of outgoing packets, `None` keeps the system default
+    pub ttl: Option<u32>,
+    /// `SO_RCVBUF` of the UDP socket in bytes, `None` keeps the system default (unix only)
+    pub socket_recv_buffer: Option<usize>,
+    /// `SO_SNDBUF` of the UDP socket in bytes, `None` keeps the system default (unix only)
+    pub socket_send_buffer: Option<usize>,
+    /// Wire format of the SCReAM feedback, both peers must use the same one
+    pub feedback_format: FeedbackFormat,
+    /// Spacing of SCReAM feedback packets, the shortest one with `FeedbackPolicy::Adaptive`
+    #[serde(with = "duration_ms")]
+    pub feedback_interval: Duration,
+    /// How the receiver spaces SCReAM feedback
+    pub feedback_policy: FeedbackPolicy,
+    /// Send feedback early once this many packets wait to be reported, `None` only waits for the interval
+    pub feedback_max_packets: Option<usize>,
+    /// SCReAM feedback carries a random token the receiving side handed out, feedback without it is dropped,
+    /// so only the peer moves the window. Both peers must use the same setting, `psk` covers feedback anyway
+    pub feedback_auth: bool,
+    /// Tuning of the SCReAM controller created for every session
+    pub scream: ScreamConfig,
+    /// Lowest target bitrate (bps) of a session, whatever its congestion controller reports. The
+    /// peer's `ScreamConfig::max_receive_bitrate` goes below it
+    pub min_target_bitrate: f32,
+    /// Highest target bitrate (bps) of a session, whatever its congestion controller reports. The
+    /// pacing rate keeps its headroom above it
+    pub max_target_bitrate: f32,
+    /// Pad an app-limited flow until the target bitrate reaches this rate (bps), `None` never pads
+    pub probe_bitrate: Option<f32>,
+    /// Bytes the pacer may send back-to-back when it has been idle
+    pub pacing_burst: usize,
+    /// Sleep until this long before a packet is due and busy-wait the rest, for pacing intervals below
+    /// the timer's ~1 ms granularity. `None` only sleeps, packets that came due meanwhile go out as a
+    /// burst of up to `pacing_burst` bytes
+    #[serde(with = "option_duration_ms")]
+    pub pacing_spin: Option<Duration>,
+    /// Packets the pacer queues before `pacer_queue_policy` applies
+    pub pacer_queue_size: usize,
+    /// Handling of packets output while the pacer queue is full
+    pub pacer_queue_policy: PacerQueuePolicy,
+    /// Pace all sessions created with this config from one task instead of one task each, `None` by default
+    #[serde(skip)]
+    pub shared_pacer: Option<SharedPacer>,
+    /// Receives congestion control samples of every session, discarded by default
+    #[serde(skip, default = "default_metrics")]
+    pub metrics: Arc<dyn MetricsSink>,
+    /// Writes congestion control events of every session as qlog, `None` by default
+    #[serde(skip)]
+    pub qlog: Option<Arc<QlogWriter>>,
+    /// Probe the path MTU and adapt `mtu` to it (linux only)
+    pub pmtud: bool,
+    /// Largest MTU path MTU discovery will probe for
+    pub pmtud_max_mtu: usize,
+    /// Reed-Solomon FEC below KCP, both peers must use the same setting
+    pub fec: Option<FecConfig>,
+    /// Encrypt and authenticate every datagram with XChaCha20-Poly1305, both peers must use the same key
+    pub psk: Option<PreSharedKey>,
+    /// Sealed datagrams a datagram may trail the newest one by before it's dropped as stale, duplicates
+    /// are dropped too. Only with `psk`, 0 disables it
+    pub replay_window: usize,
+    /// Scramble and pad every datagram so it doesn't look like KCP, both peers must use the same one
+    #[serde(skip)]
+    pub obfuscation: Option<Arc<dyn Obfuscator>>,
+    /// Send small datagrams twice, the receiver drops the copy by its sn. For control channels that
+    /// rather spend bandwidth, unseen by congestion control, than wait for a retransmission
+    pub duplicate: Option<DuplicateConfig>,
+    /// Probe an idle peer this often, answered by the peer even if it doesn't probe itself
+    #[serde(with = "option_duration_ms")]
+    pub keepalive_interval: Option<Duration>,
+    /// Fail pending and future reads and writes with `TimedOut` after receiving nothing for this long
+    #[serde(with = "option_duration_ms")]
+    pub idle_timeout: Option<Duration>,
+    /// Time `connect_timeout` waits for an answer before asking the peer again
+    #[serde(with = "duration_ms")]
+    pub handshake_retry_interval: Duration,
+    /// Times `connect_timeout` asks the peer before failing with `HandshakeTimeout`
+    pub handshake_max_attempts: u32,
+    /// Longest time a closed stream keeps flushing unsent data and waiting for its FIN to be acknowledged,
+    /// and the pacer keeps sending its queue after the session is gone
+    #[serde(with = "duration_ms")]
+    pub linger: Duration,
+    /// Datagrams sent or received per syscall (`sendmmsg` / `recvmmsg`, linux only), every slot reserves 64 KiB
+    pub udp_batch_size: usize,
+    /// Streams a listener queues for `accept`, peers beyond are refused
+    pub accept_backlog: usize,
+    /// Sessions a listener keeps at once, new peers beyond are refused
+    pub max_connections: Option<usize>,
+    /// Pacing rate (bps) all sessions of a listener share, each is scaled down proportionally while
+    /// their sum exceeds it. `None` paces every session at its own rate
+    pub max_aggregate_rate_bps: Option<f32>,
+    /// A listener moves a session to the new address its conv arrives from. Without `psk` the
+    /// conv is the only proof the datagram belongs to the session
+    pub migration: bool,
+    /// A listener echoes the path probes of `MultipathTransport` clients, only from addresses its sessions
+    /// were seen at and at most 1000 per second
+    pub echo_path_probes: bool,
+    /// Picks the conv of clients connecting with conv `0`, random by default
+    #[serde(skip, default = "default_conv_allocator")]
+    pub conv_allocator: Arc<dyn ConvAllocator>,
 }
 
 impl Default for KcpConfig {
@@ -86,18 +304,390 @@ impl Default for KcpConfig {
         KcpConfig {
             mtu: 1400,
             nodelay: KcpNoDelayConfig::normal(),
-            wnd_size: (256, 256),
+            snd_wnd: 256,
+            rcv_wnd: 256,
+            rto_min: None,
+            rto_max: Duration::from_secs(60),
+            fast_resend_threshold: None,
+            backoff_factor: None,
+            max_retransmissions: None,
             session_expire: Some(Duration::from_secs(90)),
             flush_write: false,
             flush_acks_input: false,
-            stream: false,
+            delayed_ack: None,
+            stream_mode: StreamMode::Message,
+            passthrough: false,
+            max_recv_buffer_bytes: None,
             allow_recv_empty_packet: false,
             use_external_congestion_control: false,
+            header_compression: false,
+            ecn: false,
+            dscp: None,
+            ttl: None,
+            socket_recv_buffer: None,
+            socket_send_buffer: None,
+            feedback_format: FeedbackFormat::Native,
+            feedback_interval: Duration::from_millis(10),
+            feedback_policy: FeedbackPolicy::Fixed,
+            feedback_max_packets: None,
+            feedback_auth: false,
+            scream: ScreamConfig::default(),
+            min_target_bitrate: MIN_TARGET_BITRATE,
+            max_target_bitrate: MAX_TARGET_BITRATE,
+            probe_bitrate: None,
+            pacing_burst: 4 * 1400,
+            pacing_spin: None,
+            pacer_queue_size: 256,
+            pacer_queue_policy: PacerQueuePolicy::DropNewest,
+            shared_pacer: None,
+            metrics: default_metrics(),
+            qlog: None,
+            pmtud: false,
+            pmtud_max_mtu: 1472,
+            fec: None,
+            psk: None,
+            replay_window: 1024,
+            obfuscation: None,
+            duplicate: None,
+            keepalive_interval: None,
+            idle_timeout: None,
+            handshake_retry_interval: Duration::from_millis(500),
+            handshake_max_attempts: 10,
+            linger: Duration::from_secs(5),
+            udp_batch_size: 1,
+            accept_backlog: 1024,
+            max_connections: None,
+            max_aggregate_rate_bps: None,
+            migration: false,
+            echo_path_probes: false,
+            conv_allocator: default_conv_allocator(),
         }
     }
 }
 
+/// Config of a session accepted from a peer address and conv
+pub(crate) type ConfigHook = Arc<dyn Fn(SocketAddr, u32) -> KcpConfig + Send + Sync>;
+
+fn invalid(reason: String) -> KcpResult<()> {
+    Err(KcpError::InvalidConfig(reason))
+}
+
+fn default_conv_allocator() -> Arc<dyn ConvAllocator> {
+    Arc::new(RandomConvAllocator)
+}
+
+fn default_metrics() -> Arc<dyn MetricsSink> {
+    Arc::new(NoopSink)
+}
+
 impl KcpConfig {
+    /// Builder starting from the defaults
+    pub fn builder() -> KcpConfigBuilder {
+        KcpConfigBuilder::default()
+    }
+
+    /// Interactive media and games: 10 ms ticks, fast resend and a low queuing delay target. Stale
+    /// packets are dropped from a full pacer queue first
+    pub fn realtime() -> KcpConfig {
+        KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                nodelay: true,
+                interval: 10,
+                resend: 2,
+                nc: true,
+            },
+            feedback_policy: FeedbackPolicy::Adaptive,
+            scream: ScreamConfig {
+                qdelay_target_lo: 0.04,
+                heavy_loss_rate: Some(0.1),
+                ..ScreamConfig::default()
+            },
+            pacing_burst: 2 * 1400,
+            pacer_queue_policy: PacerQueuePolicy::DropOldest,
+            ..KcpConfig::scream()
+        }
+    }
+
+    /// File transfers: large windows, up to 100 Mbps and a queuing delay target that keeps the
+    /// bottleneck busy. KCP's output waits for the pacer instead of being dropped
+    pub fn bulk() -> KcpConfig {
+        KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                nodelay: false,
+                interval: 20,
+                resend: 0,
+                nc: true,
+            },
+            snd_wnd: 1024,
+            rcv_wnd: 1024,
+            feedback_policy: FeedbackPolicy::Adaptive,
+            scream: ScreamConfig {
+                qdelay_target_lo: 0.1,
+                ..ScreamConfig::default()
+            },
+            min_target_bitrate: 1_000_000.0,
+            max_target_bitrate: 100_000_000.0,
+            pacing_burst: 16 * 1400,
+            pacer_queue_size: 1024,
+            pacer_queue_policy: PacerQueuePolicy::Block,
+            ..KcpConfig::scream()
+        }
+    }
+
+    /// Lowest latency at any cost: KCP's fastest settings, frequent feedback and a window that
+    /// grows more aggressively, up to 50 Mbps
+    pub fn turbo() -> KcpConfig {
+        KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            snd_wnd: 512,
+            rcv_wnd: 512,
+            feedback_interval: Duration::from_millis(5),
+            scream: ScreamConfig {
+                mul_increase_factor: 0.05,
+                ..ScreamConfig::default()
+            },
+            max_target_bitrate: 50_000_000.0,
+            pacing_burst: 8 * 1400,
+            pacer_queue_size: 512,
+            ..KcpConfig::scream()
+        }
+    }
+
+    /// Shared or metered links: small windows, no slow start, a slow increase and a strong back off
+    /// on loss, up to 5 Mbps
+    pub fn conservative() -> KcpConfig {
+        KcpConfig {
+            snd_wnd: 128,
+            rcv_wnd: 128,
+            feedback_interval: Duration::from_millis(20),
+            scream: ScreamConfig {
+                qdelay_target_lo: 0.03,
+                beta_loss: 0.5,
+                heavy_loss_rate: Some(0.05),
+                slow_start: false,
+                mul_increase_factor: 0.01,
+                ..ScreamConfig::default()
+            },
+            max_target_bitrate: 5_000_000.0,
+            ..KcpConfig::scream()
+        }
+    }
+
+    // SCReAM drives the window and the pacer, KCP's own congestion control is off
+    fn scream() -> KcpConfig {
+        KcpConfig {
+            nodelay: KcpNoDelayConfig {
+                nc: true,
+                ..KcpNoDelayConfig::normal()
+            },
+            use_external_congestion_control: true,
+            ..KcpConfig::default()
+        }
+    }
+
+    /// Check for settings that contradict each other and would stall or break sessions
+    ///
+    /// Every `KcpStream::connect*` and `KcpListener` constructor checks it first.
+    pub fn validate(&self) -> KcpResult<()> {
+        let scream = &self.scream;
+        let fec_overhead = if self.fec.is_some() { fec::FEC_OVERHEAD } else { 0 };
+        let crypto_overhead = if self.psk.is_some() { crypto::CRYPTO_OVERHEAD } else { 0 };
+        let obfuscation_overhead = self.obfuscation.as_ref().map_or(0, |obfuscation| obfuscation.overhead());
+        let wrap_overhead = fec_overhead + crypto_overhead + obfuscation_overhead;
+        if self.mtu < MIN_MTU + wrap_overhead || self.mtu > MAX_UDP_PAYLOAD {
+            return invalid(format!(
+                "mtu {} is outside {}..={}, FEC, encryption and obfuscation take {} bytes of it",
+                self.mtu,
+                MIN_MTU + wrap_overhead,
+                MAX_UDP_PAYLOAD,
+                wrap_overhead
+            ));
+        }
+        let mss = self.mtu - wrap_overhead - KCP_OVERHEAD;
+        if let Some(dscp) = self.dscp.filter(|&dscp| dscp > 63) {
+            return invalid(format!("dscp {} doesn't fit into 6 bits", dscp));
+        }
+        if let Some(ttl) = self.ttl.filter(|ttl| !(1..=255).contains(ttl)) {
+            return invalid(format!("ttl {} is outside 1..=255", ttl));
+        }
+        if self.passthrough && self.stream_mode == StreamMode::ByteStream {
+            return invalid("passthrough needs message mode, stream_mode is byte_stream".to_owned());
+        }
+        if self.pmtud && self.pmtud_max_mtu < self.mtu {
+            return invalid(format!("pmtud_max_mtu {} is below mtu {}", self.pmtud_max_mtu, self.mtu));
+        }
+        if let Some(fec) = self.fec {
+            if let Some(err) = fec.check() {
+                return invalid(format!("fec {:?} is unusable: {}", fec, err));
+            }
+        }
+        if self.duplicate.is_some_and(|duplicate| duplicate.max_size <= KCP_OVERHEAD) {
+            return invalid(format!(
+                "duplicate max_size is at most the {} byte KCP header, nothing is sent twice",
+                KCP_OVERHEAD
+            ));
+        }
+
+        if self.rto_min.is_some_and(|rto_min| rto_min > self.rto_max) {
+            return invalid(format!("rto_min {:?} is above rto_max {:?}", self.rto_min, self.rto_max));
+        }
+        if let Some(backoff) = self.backoff_factor.filter(|backoff| backoff.is_nan() || *backoff < 1.0) {
+            return invalid(format!("backoff_factor {} shrinks the RTO of retransmissions", backoff));
+        }
+        if let Some(delayed_ack) = self.delayed_ack {
+            if self.flush_acks_input {
+                return invalid("flush_acks_input sends every ACK at once, delayed_ack holds them back".to_owned());
+            }
+            if delayed_ack.max_packets == 0 {
+                return invalid("delayed_ack max_packets is 0, nothing is delayed".to_owned());
+            }
+            // KCP's own floor applies without an override
+            let rto_min = self.rto_min.unwrap_or(Duration::from_millis(if self.nodelay.nodelay { 30 } else { 100 }));
+            if delayed_ack.max_delay >= rto_min {
+                return invalid(format!(
+                    "delayed_ack max_delay {:?} reaches rto_min {:?}, delayed segments are retransmitted",
+                    delayed_ack.max_delay, rto_min
+                ));
+            }
+        }
+        if let Some(budget) = self.max_recv_buffer_bytes {
+            if budget < mss {
+                return invalid(format!(
+                    "max_recv_buffer_bytes {} is below one segment of {} bytes, the receive window never opens",
+                    budget, mss
+                ));
+            }
+            // The window stays closed while an incomplete message fills the budget
+            let largest_message = KCP_MAX_FRAGMENTS * mss;
+            if self.stream_mode == StreamMode::Message && budget < largest_message {
+                return invalid(format!(
+                    "max_recv_buffer_bytes {} is below the {} bytes of the largest message, receiving it stalls",
+                    budget, largest_message
+                ));
+            }
+        }
+        if let Some(rate) = self.max_aggregate_rate_bps.filter(|rate| rate.is_nan() || *rate <= 0.0) {
+            return invalid(format!("max_aggregate_rate_bps {} lets no session send", rate));
+        }
+
+        if self.pacer_queue_size == 0 {
+            return invalid("pacer_queue_size is 0, nothing can be sent".to_owned());
+        }
+        if self.pacer_queue_policy != PacerQueuePolicy::Block && self.pacer_queue_size < self.snd_wnd as usize {
+            return invalid(format!(
+                "pacer_queue_size {} is below snd_wnd {}, flushing a full window drops packets",
+                self.pacer_queue_size, self.snd_wnd
+            ));
+        }
+        let (min_target_bitrate, max_target_bitrate) = (self.min_target_bitrate, self.max_target_bitrate);
+        if !min_target_bitrate.is_finite()
+            || !max_target_bitrate.is_finite()
+            || min_target_bitrate <= 0.0
+            || min_target_bitrate > max_target_bitrate
+        {
+            return invalid(format!(
+                "target bitrate bounds {}..={} are empty",
+                min_target_bitrate, max_target_bitrate
+            ));
+        }
+        if !scream.packet_pacing_headroom.is_finite() || scream.packet_pacing_headroom <= 0.0 {
+            return invalid(format!(
+                "scream packet_pacing_headroom {} never lets a packet out",
+                scream.packet_pacing_headroom
+            ));
+        }
+        // KCP flushes once per interval, the queue has to hold what the pacer sends meanwhile. A
+        // blocking queue holds the flush back instead of dropping
+        let max_pacing_rate = self.max_target_bitrate as f64 * scream.packet_pacing_headroom as f64;
+        let interval = self.nodelay.interval.clamp(10, 5000) as f64 / 1000.0;
+        let flushed_bytes = max_pacing_rate / 8.0 * interval;
+        let queued_bytes = self.pacer_queue_size.saturating_mul(self.mtu);
+        if self.pacer_queue_policy != PacerQueuePolicy::Block && (queued_bytes as f64) < flushed_bytes {
+            return invalid(format!(
+                "pacer_queue_size {} holds {} bytes, less than the {:.0} bytes max_target_bitrate sends per {} ms",
+                self.pacer_queue_size,
+                queued_bytes,
+                flushed_bytes,
+                self.nodelay.interval
+            ));
+        }
+
+        if let Some(max_receive_bitrate) = scream.max_receive_bitrate {
+            if max_receive_bitrate <= 0.0 {
+                return invalid(format!(
+                    "scream max_receive_bitrate {} lets the peer send nothing",
+                    max_receive_bitrate
+                ));
+            }
+        }
+        if let Some(probe_bitrate) = self.probe_bitrate {
+            if !probe_bitrate.is_finite() || probe_bitrate <= 0.0 {
+                return invalid(format!("probe_bitrate {} sends no padding", probe_bitrate));
+            }
+            if probe_bitrate > self.max_target_bitrate {
+                return invalid(format!(
+                    "probe_bitrate {} is above max_target_bitrate {}, padding never stops",
+                    probe_bitrate, self.max_target_bitrate
+                ));
+            }
+        }
+
+        if let (Some(keepalive), Some(idle_timeout)) = (self.keepalive_interval, self.idle_timeout) {
+            if keepalive >= idle_timeout {
+                return invalid(format!(
+                    "keepalive_interval {:?} is not below idle_timeout {:?}, idle sessions time out",
+                    keepalive, idle_timeout
+                ));
+            }
+        }
+        if self.handshake_max_attempts == 0 {
+            return invalid("handshake_max_attempts is 0, connecting always fails".to_owned());
+        }
+        if self.udp_batch_size == 0 {
+            return invalid("udp_batch_size is 0".to_owned());
+        }
+        Ok(())
+    }
+
+    /// SCReAM controller tuned by `scream` within the target bitrate bounds of this config, fails if
+    /// they are invalid
+    pub fn scream_controller(&self) -> KcpResult<ScreamCongestionControl> {
+        ScreamCongestionControl::with_config(self.scream, self.feedback_format)
+            .with_target_bitrate_bounds(self.min_target_bitrate, self.max_target_bitrate)
+    }
+
+    /// Load a config from a TOML file
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> io::Result<KcpConfig> {
+        KcpConfig::from_toml_str(&fs::read_to_string(path)?)
+    }
+
+    /// Parse a config from TOML
+    pub fn from_toml_str(s: &str) -> io::Result<KcpConfig> {
+        toml::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// Load a config from a JSON file
+    pub fn from_json_file<P: AsRef<Path>>(path: P) -> io::Result<KcpConfig> {
+        KcpConfig::from_json_str(&fs::read_to_string(path)?)
+    }
+
+    /// Parse a config from JSON
+    pub fn from_json_str(s: &str) -> io::Result<KcpConfig> {
+        serde_json::from_str(s).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// `session` with the settings every session of a listener shares, the listener deobfuscates,
+    /// decrypts, routes FEC and reads ECN for all of them
+    pub(crate) fn for_session(&self, session: KcpConfig) -> KcpConfig {
+        KcpConfig {
+            psk: self.psk.clone(),
+            obfuscation: self.obfuscation.clone(),
+            fec: self.fec,
+            ecn: self.ecn,
+            ..session
+        }
+    }
+
     /// Applies config onto `Kcp`
     #[doc(hidden)]
     pub fn apply_config<W: Write>(&self, k: &mut Kcp<W>) {
@@ -110,8 +700,395 @@ impl KcpConfig {
             self.nodelay.nc,
         );
 
-        k.set_wndsize(self.wnd_size.0, self.wnd_size.1);
+        if let Some(rto_min) = self.rto_min {
+            k.set_rx_minrto(rto_min.as_millis() as u32);
+        }
+        k.set_rx_maxrto(self.rto_max.as_millis() as u32);
+        if let Some(threshold) = self.fast_resend_threshold {
+            k.set_fast_resend(threshold);
+        }
+        if let Some(backoff) = self.backoff_factor {
+            k.set_backoff(backoff);
+        }
+        k.set_maximum_resend_times(self.max_retransmissions.unwrap_or(u32::MAX));
+
+        k.set_wndsize(self.snd_wnd, self.rcv_wnd);
 
         k.set_external_congestion_control(self.use_external_congestion_control);
+        k.set_delayed_ack(self.delayed_ack.is_some());
+        k.set_header_compression(self.header_compression);
+    }
+}
+
+macro_rules! setters {
+    ($($field:ident: $ty:ty),* $(,)?) => {
+        $(
+            #[doc = concat!("Set `KcpConfig::", stringify!($field), "`")]
+            pub fn $field(mut self, $field: $ty) -> KcpConfigBuilder {
+                self.config.$field = $field;
+                self
+            }
+        )*
+    };
+}
+
+/// Builds a `KcpConfig` from the defaults, `build` checks that the settings work together
+#[derive(Debug, Clone, Default)]
+pub struct KcpConfigBuilder {
+    config: KcpConfig,
+}
+
+impl KcpConfigBuilder {
+    setters! {
+        mtu: usize,
+        nodelay: KcpNoDelayConfig,
+        snd_wnd: u16,
+        rcv_wnd: u16,
+        rto_min: Option<Duration>,
+        rto_max: Duration,
+        fast_resend_threshold: Option<u32>,
+        backoff_factor: Option<f32>,
+        max_retransmissions: Option<u32>,
+        session_expire: Option<Duration>,
+        flush_write: bool,
+        flush_acks_input: bool,
+        delayed_ack: Option<DelayedAckConfig>,
+        stream_mode: StreamMode,
+        passthrough: bool,
+        max_recv_buffer_bytes: Option<usize>,
+        allow_recv_empty_packet: bool,
+        use_external_congestion_control: bool,
+        header_compression: bool,
+        ecn: bool,
+        dscp: Option<u8>,
+        ttl: Option<u32>,
+        socket_recv_buffer: Option<usize>,
+        socket_send_buffer: Option<usize>,
+        feedback_format: FeedbackFormat,
+        feedback_interval: Duration,
+        feedback_policy: FeedbackPolicy,
+        feedback_max_packets: Option<usize>,
+        feedback_auth: bool,
+        scream: ScreamConfig,
+        min_target_bitrate: f32,
+        max_target_bitrate: f32,
+        probe_bitrate: Option<f32>,
+        pacing_burst: usize,
+        pacing_spin: Option<Duration>,
+        pacer_queue_size: usize,
+        pacer_queue_policy: PacerQueuePolicy,
+        shared_pacer: Option<SharedPacer>,
+        metrics: Arc<dyn MetricsSink>,
+        qlog: Option<Arc<QlogWriter>>,
+        pmtud: bool,
+        pmtud_max_mtu: usize,
+        fec: Option<FecConfig>,
+        psk: Option<PreSharedKey>,
+        replay_window: usize,
+        obfuscation: Option<Arc<dyn Obfuscator>>,
+        duplicate: Option<DuplicateConfig>,
+        keepalive_interval: Option<Duration>,
+        idle_timeout: Option<Duration>,
+        handshake_retry_interval: Duration,
+        handshake_max_attempts: u32,
+        linger: Duration,
+        udp_batch_size: usize,
+        accept_backlog: usize,
+        max_connections: Option<usize>,
+        max_aggregate_rate_bps: Option<f32>,
+        migration: bool,
+        echo_path_probes: bool,
+        conv_allocator: Arc<dyn ConvAllocator>,
+    }
+
+    /// The config, `InvalidConfig` if its settings contradict each other, see `KcpConfig::validate`
+    pub fn build(self) -> KcpResult<KcpConfig> {
+        self.config.validate()?;
+        Ok(self.config)
+    }
+}
+
+/// Settings `KcpStream::reconfigure` changes on an established session, `None` keeps the current value
+#[derive(Debug, Clone, Copy, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct KcpRuntimeConfig {
+    /// KCP nodelay parameters, resets an `rto_min` override
+    pub nodelay: Option<KcpNoDelayConfig>,
+    /// Largest send window in segments, the congestion window stays below it
+    pub snd_wnd: Option<u16>,
+    /// Receive window in segments
+    pub rcv_wnd: Option<u16>,
+    /// Upper bound of the target bitrate and pacing rate (bps), see `KcpConfig::max_target_bitrate`, lowers
+    /// the lower bound if below it
+    pub max_target_bitrate: Option<f32>,
+    /// Pacing rate relative to the target bitrate, see `ScreamConfig::packet_pacing_headroom`
+    pub packet_pacing_headroom: Option<f32>,
+    /// Spacing of SCReAM feedback packets, see `KcpConfig::feedback_interval`
+    #[serde(with = "option_duration_ms")]
+    pub feedback_interval: Option<Duration>,
+    /// Highest bitrate (bps) the peer may send, see `ScreamConfig::max_receive_bitrate`, `f32::INFINITY`
+    /// lifts the limit
+    pub max_receive_bitrate: Option<f32>,
+}
+
+impl KcpRuntimeConfig {
+    /// `InvalidConfig` if a setting would stall the session, checked by `KcpStream::reconfigure`
+    pub fn validate(&self) -> KcpResult<()> {
+        if self.snd_wnd == Some(0) || self.rcv_wnd == Some(0) {
+            return invalid(format!("windows {:?}/{:?} of 0 segments never open", self.snd_wnd, self.rcv_wnd));
+        }
+        if let Some(max_target_bitrate) = self.max_target_bitrate.filter(|bitrate| bitrate.is_nan() || *bitrate <= 0.0) {
+            return invalid(format!("max_target_bitrate {} lets nothing out", max_target_bitrate));
+        }
+        if let Some(headroom) = self.packet_pacing_headroom.filter(|headroom| headroom.is_nan() || *headroom <= 0.0) {
+            return invalid(format!("packet_pacing_headroom {} never lets a packet out", headroom));
+        }
+        if let Some(max_receive_bitrate) = self.max_receive_bitrate.filter(|bitrate| bitrate.is_nan() || *bitrate <= 0.0) {
+            return invalid(format!("max_receive_bitrate {} lets the peer send nothing", max_receive_bitrate));
+        }
+        if self.feedback_interval.is_some_and(|interval| interval.is_zero()) {
+            return invalid("feedback_interval is 0".to_owned());
+        }
+        Ok(())
+    }
+}
+
+pub(crate) mod duration_ms {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &Duration, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u64(d.as_millis() as u64)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Duration, D::Error> {
+        u64::deserialize(deserializer).map(Duration::from_millis)
+    }
+}
+
+pub(crate) mod option_duration_ms {
+    use std::time::Duration;
+
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(d: &Option<Duration>, serializer: S) -> Result<S::Ok, S::Error> {
+        match d {
+            Some(d) => serializer.serialize_some(&(d.as_millis() as u64)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Option<Duration>, D::Error> {
+        Option::<u64>::deserialize(deserializer).map(|ms| ms.map(Duration::from_millis))
+    }
+}
+
+pub(crate) mod stream_mode {
+    use serde::{Deserialize, Deserializer};
+
+    use super::StreamMode;
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Repr {
+        Mode(StreamMode),
+        /// `stream` of older configs
+        Stream(bool),
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<StreamMode, D::Error> {
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Mode(mode) => mode,
+            Repr::Stream(true) => StreamMode::ByteStream,
+            Repr::Stream(false) => StreamMode::Message,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn toml_partial_config() {
+        let config = KcpConfig::from_toml_str(
+            r#"
+            mtu = 1200
+            snd_wnd = 512
+            rcv_wnd = 1024
+            session_expire = 30000
+            feedback_format = "rfc8888"
+            feedback_interval = 5
+            feedback_policy = "adaptive"
+            psk = "000102030405060708090a0b0c0d0e0f101112131415161718191a1b1c1d1e1f"
+            max_target_bitrate = 20000000
+
+            [nodelay]
+            nodelay = true
+            interval = 10
+
+            [fec]
+            data_shards = 8
+
+            [scream]
+            beta_loss = 0.5
+            base_rtt_window = 5000
+            "#,
+        )
+        .unwrap();
+
+        assert_eq!(config.mtu, 1200);
+        assert_eq!((config.snd_wnd, config.rcv_wnd), (512, 1024));
+        assert_eq!(config.session_expire, Some(Duration::from_secs(30)));
+        assert_eq!(config.feedback_format, FeedbackFormat::Rfc8888);
+        assert_eq!(config.feedback_interval, Duration::from_millis(5));
+        assert_eq!(config.feedback_policy, FeedbackPolicy::Adaptive);
+        assert_eq!(config.feedback_max_packets, None);
+        assert!(config.nodelay.nodelay);
+        assert_eq!(config.nodelay.interval, 10);
+        assert_eq!(config.nodelay.resend, KcpNoDelayConfig::default().resend);
+        assert_eq!(config.fec.map(|fec| (fec.data_shards, fec.parity_shards)), Some((8, 3)));
+        assert_eq!(config.scream.beta_loss, 0.5);
+        assert_eq!(config.max_target_bitrate, 20_000_000.0);
+        assert_eq!(config.scream.base_rtt_window, Duration::from_secs(5));
+        assert_eq!(config.scream.beta_ecn, ScreamConfig::default().beta_ecn);
+        let mut key = [0u8; 32];
+        key.iter_mut().enumerate().for_each(|(i, b)| *b = i as u8);
+        assert_eq!(config.psk, Some(PreSharedKey::new(key)));
+        // Untouched fields keep their defaults
+        assert_eq!(config.pacing_burst, KcpConfig::default().pacing_burst);
+        assert_eq!(config.linger, KcpConfig::default().linger);
+    }
+
+    #[test]
+    fn json_roundtrip() {
+        let config = KcpConfig {
+            idle_timeout: Some(Duration::from_millis(1500)),
+            keepalive_interval: None,
+            stream_mode: StreamMode::ByteStream,
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&config).unwrap();
+        let parsed = KcpConfig::from_json_str(&json).unwrap();
+
+        assert_eq!(parsed.idle_timeout, config.idle_timeout);
+        assert_eq!(parsed.keepalive_interval, None);
+        assert_eq!(parsed.stream_mode, StreamMode::ByteStream);
+        assert_eq!(parsed.mtu, config.mtu);
+
+        assert!(KcpConfig::from_json_str(r#"{"psk": "00"}"#).is_err());
+    }
+
+    #[test]
+    fn legacy_stream_key() {
+        let config = KcpConfig::from_toml_str("stream = true").unwrap();
+        assert_eq!(config.stream_mode, StreamMode::ByteStream);
+        let config = KcpConfig::from_json_str(r#"{"stream": false}"#).unwrap();
+        assert_eq!(config.stream_mode, StreamMode::Message);
+        let config = KcpConfig::from_toml_str(r#"stream_mode = "byte_stream""#).unwrap();
+        assert_eq!(config.stream_mode, StreamMode::ByteStream);
+
+        assert!(KcpConfig::from_toml_str("stream = true\nstream_mode = \"message\"").is_err());
+        assert!(KcpConfig::from_toml_str(r#"stream_mode = "bytes""#).is_err());
+    }
+
+    #[test]
+    fn builder_validates() {
+        let config = KcpConfig::builder()
+            .mtu(1200)
+            .snd_wnd(128)
+            .nodelay(KcpNoDelayConfig::fastest())
+            .build()
+            .unwrap();
+        assert_eq!(config.mtu, 1200);
+        assert_eq!(config.snd_wnd, 128);
+        assert!(config.nodelay.nodelay);
+        KcpConfig::default().validate().unwrap();
+
+        let reason = |builder: KcpConfigBuilder| match builder.build() {
+            Err(KcpError::InvalidConfig(reason)) => reason,
+            other => panic!("expected InvalidConfig, got {:?}", other.map(|_| ())),
+        };
+        let psk = PreSharedKey::new([1; 32]);
+        assert!(reason(KcpConfig::builder().mtu(60).psk(Some(psk))).starts_with("mtu 60"));
+        assert!(reason(KcpConfig::builder().max_recv_buffer_bytes(Some(1000))).starts_with("max_recv_buffer_bytes"));
+        let small_budget = KcpConfig::builder().max_recv_buffer_bytes(Some(8 * 1024));
+        assert!(reason(small_budget.clone()).contains("largest message"));
+        small_budget.stream_mode(StreamMode::ByteStream).build().unwrap();
+        assert!(reason(KcpConfig::builder().max_aggregate_rate_bps(Some(0.0))).starts_with("max_aggregate_rate_bps"));
+        assert!(reason(KcpConfig::builder().snd_wnd(1024)).contains("below snd_wnd 1024"));
+        let duplicate = DuplicateConfig {
+            max_size: KCP_OVERHEAD,
+            ..Default::default()
+        };
+        assert!(reason(KcpConfig::builder().duplicate(Some(duplicate))).starts_with("duplicate max_size"));
+        let byte_stream = KcpConfig::builder().stream_mode(StreamMode::ByteStream);
+        assert!(reason(byte_stream.passthrough(true)).starts_with("passthrough"));
+        assert!(reason(KcpConfig::builder().dscp(Some(64))).starts_with("dscp 64"));
+        assert!(reason(KcpConfig::builder().backoff_factor(Some(0.5))).starts_with("backoff_factor"));
+        assert!(reason(KcpConfig::builder().backoff_factor(Some(f32::NAN))).starts_with("backoff_factor"));
+        KcpConfig::builder().backoff_factor(Some(1.0)).build().unwrap();
+        let delayed_ack = Some(DelayedAckConfig::default());
+        KcpConfig::builder().delayed_ack(delayed_ack).build().unwrap();
+        assert!(reason(KcpConfig::builder().delayed_ack(delayed_ack).flush_acks_input(true)).starts_with("flush_acks"));
+        assert!(reason(
+            KcpConfig::builder()
+                .delayed_ack(delayed_ack)
+                .nodelay(KcpNoDelayConfig::fastest())
+                .rto_min(Some(Duration::from_millis(20)))
+        )
+        .starts_with("delayed_ack max_delay 20ms"));
+        let scream = ScreamConfig {
+            max_receive_bitrate: Some(0.0),
+            ..ScreamConfig::default()
+        };
+        assert!(reason(KcpConfig::builder().scream(scream)).starts_with("scream max_receive_bitrate"));
+        assert!(reason(KcpConfig::builder().min_target_bitrate(20_000_000.0)).starts_with("target bitrate bounds"));
+        assert!(reason(KcpConfig::builder().min_target_bitrate(f32::NAN)).starts_with("target bitrate bounds"));
+        assert!(reason(KcpConfig::builder().max_target_bitrate(f32::INFINITY)).starts_with("target bitrate bounds"));
+        let nan_max = KcpConfig::from_toml_str("max_target_bitrate = nan").unwrap();
+        assert!(nan_max.validate().unwrap_err().to_string().contains("target bitrate bounds"));
+        KcpConfig::builder()
+            .max_target_bitrate(50_000_000.0)
+            .pacer_queue_size(1024)
+            .build()
+            .unwrap();
+        assert!(reason(KcpConfig::builder().ttl(Some(0))).starts_with("ttl 0"));
+        KcpConfig::builder()
+            .snd_wnd(1024)
+            .pacer_queue_policy(PacerQueuePolicy::Block)
+            .build()
+            .unwrap();
+
+        let fast = KcpConfig::builder().max_target_bitrate(500_000_000.0);
+        assert!(reason(fast).contains("max_target_bitrate sends per 40 ms"));
+        assert!(reason(KcpConfig::builder().probe_bitrate(Some(20_000_000.0))).starts_with("probe_bitrate"));
+        assert!(reason(KcpConfig::builder().probe_bitrate(Some(f32::NAN))).starts_with("probe_bitrate"));
+        assert!(reason(KcpConfig::builder().probe_bitrate(Some(-1.0))).starts_with("probe_bitrate"));
+        let fec = FecConfig {
+            data_shards: 0,
+            parity_shards: 3,
+        };
+        assert!(reason(KcpConfig::builder().fec(Some(fec))).starts_with("fec"));
+        let idle = KcpConfig::builder()
+            .keepalive_interval(Some(Duration::from_secs(10)))
+            .idle_timeout(Some(Duration::from_secs(5)));
+        assert!(reason(idle).starts_with("keepalive_interval"));
+        KcpConfig::builder().pacer_queue_size(usize::MAX).build().unwrap();
+    }
+
+    #[test]
+    fn presets_use_scream() {
+        for config in [
+            KcpConfig::realtime(),
+            KcpConfig::bulk(),
+            KcpConfig::turbo(),
+            KcpConfig::conservative(),
+        ] {
+            assert!(config.nodelay.nc);
+            assert!(config.use_external_congestion_control);
+            config.validate().unwrap();
+        }
     }
 }
@@ -0,0 +1,186 @@
+//! Pluggable congestion control
+
+use std::{
+    fmt::Debug,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use crate::{config::KcpRuntimeConfig, ecn::EcnCodepoint, event::CongestionEvent, metrics::MetricsSample, KcpConfig};
+
+/// Controller state reported in `KcpStats`, fields a controller doesn't track are zero
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CongestionStats {
+    /// Smoothed RTT
+    pub s_rtt: Duration,
+    /// RTT variation
+    pub rtt_var: Duration,
+    /// Minimum RTT over the base RTT window
+    pub base_rtt: Duration,
+    /// Queuing delay of the last sample
+    pub qdelay: Duration,
+    /// Smoothed queuing delay
+    pub qdelay_avg: Duration,
+    /// Fraction of bytes lost during the last RTT
+    pub loss_rate: f32,
+    /// Fraction of acknowledged bytes marked CE during the last RTT
+    pub ce_rate: f32,
+    /// Congestion window in bytes
+    pub congestion_window: f32,
+    /// Bytes sent and neither acknowledged nor lost
+    pub bytes_in_flight: u32,
+    /// Tracked packets dropped because their feedback never arrived
+    pub orphaned_packets: u64,
+    /// Bytes retransmitted after their RTO during the last RTT
+    pub retransmitted_bytes: u32,
+    /// Retransmissions during the last RTT whose original was acknowledged after all
+    pub spurious_retransmits: u32,
+}
+
+/// Congestion state published to the application every update tick
+///
+/// Richer than the target bitrate alone, e.g. an encoder can drop its frame rate on rising
+/// queuing delay before the bitrate follows.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct CongestionState {
+    /// Bitrate (bps) the application should produce
+    pub target_bitrate: f32,
+    /// Smoothed RTT
+    pub srtt: Duration,
+    /// Smoothed queuing delay
+    pub qdelay_avg: Duration,
+    /// Fraction of bytes lost during the last RTT
+    pub loss_rate: f32,
+    /// Fraction of acknowledged bytes marked CE during the last RTT
+    pub ce_rate: f32,
+    /// Congestion window in bytes
+    pub ref_wnd: f32,
+}
+
+/// Congestion controller driving the send window and the pacer of a KCP session
+///
+/// Sequence numbers are KCP segment `sn`s cut to 31 bits, unreliable datagrams and padding count
+/// separately with the top bit set. Both spaces wrap, compare them with `sn_newer`. Sizes are
+/// payload bytes. Both ends of a session run the same controller: the receiver records incoming
+/// segments and builds feedback, the sender consumes that feedback.
+pub trait CongestionController: Debug + Send {
+    /// A new segment was handed to the pacer
+    fn on_packet_sent(&mut self, sn: u32, size: usize);
+
+    /// A segment was acknowledged by the peer's congestion feedback
+    fn on_ack(&mut self, sn: u32, ack_time: Instant);
+
+    /// A segment was acknowledged by a KCP ACK
+    fn on_kcp_ack(&mut self, _sn: u32) {}
+
+    /// A segment was detected as lost by KCP's retransmission timer
+    fn on_loss(&mut self, sn: u32);
+
+    /// A segment passed its send deadline unacknowledged and won't be retransmitted
+    ///
+    /// The sender gave up on it, this is no congestion signal.
+    fn on_expired(&mut self, _sn: u32) {}
+
+    /// The segment size changed, e.g. after path MTU discovery
+    fn on_mss_changed(&mut self, _mss: usize) {}
+
+    /// Settings changed by `KcpStream::reconfigure`, the ones a controller doesn't have are ignored
+    fn on_reconfigure(&mut self, _config: &KcpRuntimeConfig) {}
+
+    /// Segments waiting for the send window, reported every update tick
+    ///
+    /// Nothing waiting means the sender is limited by the application, not by the window.
+    fn on_send_queue(&mut self, _queued: usize) {}
+
+    /// Called once per smoothed RTT
+    fn on_rtt(&mut self);
+
+    /// Next congestion signal the controller reacted to, polled every update tick until `None`
+    fn poll_congestion_event(&mut self) -> Option<CongestionEvent> {
+        None
+    }
+
+    /// A segment of the peer was received with the ECN codepoint of its datagram
+    fn on_packet_received(&mut self, _sn: u32, _reception_time: Instant, _ecn: EcnCodepoint) {}
+
+    /// Build a feedback payload of at most one segment for the peer, `None` if there is nothing (left) to report
+    fn create_feedback_packet(&mut self) -> Option<Vec<u8>> {
+        None
+    }
+
+    /// Append the feedback payload to `buf`, `false` if there is nothing to report
+    ///
+    /// Called instead of `create_feedback_packet` to reuse the session's buffer. The payload should
+    /// stay within `max_len` bytes, what doesn't fit is reported by the next call. The default
+    /// implementation ignores `max_len`.
+    fn write_feedback(&mut self, buf: &mut Vec<u8>, _max_len: usize) -> bool {
+        match self.create_feedback_packet() {
+            Some(feedback) => {
+                buf.extend_from_slice(&feedback);
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// A feedback payload (without the feedback header) arrived from the peer
+    fn on_feedback(&mut self, _data: &[u8], _arrival_time: Instant) {}
+
+    /// Bitrate (bps) the application should produce
+    fn get_target_bitrate(&self) -> f32;
+
+    /// Rate (bps) the pacer should send at
+    fn get_pacing_rate(&self) -> f32;
+
+    /// Highest bitrate (bps) the peer accepts, it wins over `KcpConfig::min_target_bitrate`
+    fn get_remote_max_bitrate(&self) -> Option<f32> {
+        None
+    }
+
+    /// Congestion window in bytes, used to size KCP's send window
+    fn get_congestion_window(&self) -> f32;
+
+    /// Smoothed RTT in seconds, `0` before the first sample
+    fn get_s_rtt(&self) -> f32;
+
+    /// Snapshot of the controller state
+    fn stats(&self) -> CongestionStats {
+        CongestionStats {
+            s_rtt: Duration::from_secs_f32(self.get_s_rtt().max(0.0)),
+            congestion_window: self.get_congestion_window(),
+            ..CongestionStats::default()
+        }
+    }
+
+    /// State published to the application
+    fn congestion_state(&self) -> CongestionState {
+        let stats = self.stats();
+        CongestionState {
+            target_bitrate: self.get_target_bitrate(),
+            srtt: stats.s_rtt,
+            qdelay_avg: stats.qdelay_avg,
+            loss_rate: stats.loss_rate,
+            ce_rate: stats.ce_rate,
+            ref_wnd: stats.congestion_window,
+        }
+    }
+
+    /// Sample for the `MetricsSink`, taken at the end of every update tick
+    ///
+    /// `timestamp_ms`, `conv` and the session's byte and packet counters are filled in by the session.
+    fn metrics_sample(&mut self) -> MetricsSample {
+        let stats = self.stats();
+        MetricsSample {
+            s_rtt: stats.s_rtt,
+            base_rtt: stats.base_rtt,
+            qdelay: stats.qdelay,
+            target_bitrate: self.get_target_bitrate(),
+            congestion_window: stats.congestion_window,
+            bytes_in_flight: stats.bytes_in_flight,
+            ..MetricsSample::default()
+        }
+    }
+}
+
+/// Creates a fresh controller for every accepted session from the session's config
+pub type CongestionControllerFactory = Arc<dyn Fn(&KcpConfig) -> Box<dyn CongestionController> + Send + Sync>;
@@ -0,0 +1,226 @@
+//! Connector for HTTP and gRPC clients
+//!
+//! `KcpConnector` is a `tower::Service<Uri>` dialing a `KcpStream` to the authority of the URI, the
+//! scheme only picks the default port. Clients built on such a connector (tonic's
+//! `Endpoint::connect_with_connector`, `hyper_util::client::legacy::Client` with the `hyper` feature via
+//! `KcpHyperConnector`) run over KCP and SCReAM without knowing it.
+
+use std::{
+    future::Future,
+    io,
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use http::Uri;
+use tower_service::Service;
+
+use crate::{config::KcpConfig, stream::KcpStream, KcpError, KcpResult};
+
+type ConnectFuture<T> = Pin<Box<dyn Future<Output = KcpResult<T>> + Send>>;
+
+/// `tower::Service<Uri>` connecting a `KcpStream` to every URI it is called with
+#[derive(Debug, Clone)]
+pub struct KcpConnector {
+    config: Arc<KcpConfig>,
+    connect_timeout: Option<Duration>,
+}
+
+impl KcpConnector {
+    /// Connector dialing streams with `config`
+    pub fn new(config: KcpConfig) -> KcpConnector {
+        KcpConnector {
+            config: Arc::new(config),
+            connect_timeout: None,
+        }
+    }
+
+    /// Wait for the handshake before handing out a stream, failing with `HandshakeTimeout` after `timeout`
+    ///
+    /// Without a timeout the stream is returned right away, as UDP cannot tell whether the peer exists.
+    pub fn set_connect_timeout(&mut self, timeout: Option<Duration>) {
+        self.connect_timeout = timeout;
+    }
+
+    /// The config of the dialed streams
+    pub fn config(&self) -> &KcpConfig {
+        &self.config
+    }
+
+    /// Connect a `KcpStream` to the authority of `uri`, see `KcpStream::connect` for hosts with several addresses
+    pub async fn connect(&self, uri: Uri) -> KcpResult<KcpStream> {
+        let addr = authority(&uri)?;
+        match self.connect_timeout {
+            Some(timeout) => KcpStream::connect_timeout(&self.config, addr, timeout).await,
+            None => KcpStream::connect(&self.config, addr).await,
+        }
+    }
+}
+
+impl Service<Uri> for KcpConnector {
+    type Response = KcpStream;
+    type Error = KcpError;
+    type Future = ConnectFuture<KcpStream>;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let connector = self.clone();
+        Box::pin(async move { connector.connect(uri).await })
+    }
+}
+
+// Host and port of the URI, the port defaults by scheme
+fn authority(uri: &Uri) -> KcpResult<(&str, u16)> {
+    let host = match uri.host() {
+        Some(host) => host.trim_start_matches('[').trim_end_matches(']'),
+        None => return Err(invalid_uri(uri, "missing host")),
+    };
+    let port = match (uri.port_u16(), uri.scheme_str()) {
+        (Some(port), _) => port,
+        (None, Some("http")) => 80,
+        (None, Some("https")) => 443,
+        (None, _) => return Err(invalid_uri(uri, "missing port")),
+    };
+    Ok((host, port))
+}
+
+fn invalid_uri(uri: &Uri, reason: &str) -> KcpError {
+    KcpError::IoError(io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("{}: {}", reason, uri),
+    ))
+}
+
+#[cfg(feature = "hyper")]
+pub use self::hyper::KcpHyperConnector;
+
+#[cfg(feature = "hyper")]
+mod hyper {
+    use hyper_util::{
+        client::legacy::connect::{Connected, Connection},
+        rt::TokioIo,
+    };
+
+    use super::*;
+
+    /// `KcpConnector` handing out streams wrapped for `hyper_util::client::legacy::Client`
+    #[derive(Debug, Clone)]
+    pub struct KcpHyperConnector {
+        connector: KcpConnector,
+    }
+
+    impl KcpHyperConnector {
+        /// Connector dialing streams with `config`
+        pub fn new(config: KcpConfig) -> KcpHyperConnector {
+            KcpHyperConnector::from(KcpConnector::new(config))
+        }
+
+        /// The wrapped connector
+        pub fn get_mut(&mut self) -> &mut KcpConnector {
+            &mut self.connector
+        }
+    }
+
+    impl From<KcpConnector> for KcpHyperConnector {
+        fn from(connector: KcpConnector) -> KcpHyperConnector {
+            KcpHyperConnector { connector }
+        }
+    }
+
+    impl Service<Uri> for KcpHyperConnector {
+        type Response = TokioIo<KcpStream>;
+        type Error = KcpError;
+        type Future = ConnectFuture<TokioIo<KcpStream>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, uri: Uri) -> Self::Future {
+            let connector = self.connector.clone();
+            Box::pin(async move { connector.connect(uri).await.map(TokioIo::new) })
+        }
+    }
+
+    impl Connection for KcpStream {
+        fn connected(&self) -> Connected {
+            Connected::new()
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::KcpListener;
+
+    #[tokio::test]
+    async fn connector_dials_uri() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut connector = KcpConnector::new(config);
+        connector.set_connect_timeout(Some(Duration::from_secs(5)));
+        let uri: Uri = format!("http://localhost:{}/echo", server_addr.port()).parse().unwrap();
+        let (client, server) = tokio::join!(connector.call(uri), listener.accept());
+        let (mut client, mut server) = (client.unwrap(), server.unwrap().0);
+
+        client.write_all(b"HELLO").await.unwrap();
+        client.flush().await.unwrap();
+        let mut buf = [0u8; 5];
+        server.read_exact(&mut buf).await.unwrap();
+        assert_eq!(&buf, b"HELLO");
+
+        let uri: Uri = "kcp://localhost/".parse().unwrap();
+        assert!(matches!(
+            connector.call(uri).await,
+            Err(KcpError::IoError(err)) if err.kind() == io::ErrorKind::InvalidInput
+        ));
+    }
+
+    #[cfg(feature = "hyper")]
+    #[tokio::test]
+    async fn hyper_client_over_kcp() {
+        use hyper_util::{client::legacy::Client, rt::TokioExecutor};
+
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut request = Vec::new();
+            let mut buf = [0u8; 1024];
+            while !request.ends_with(b"\r\n\r\n") {
+                let n = stream.read(&mut buf).await.unwrap();
+                request.extend_from_slice(&buf[..n]);
+            }
+            assert!(request.starts_with(b"GET /hello HTTP/1.1\r\n"));
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\nWORLD")
+                .await
+                .unwrap();
+            stream.flush().await.unwrap();
+            let _ = stream.read(&mut buf).await;
+        });
+
+        let client: Client<_, String> = Client::builder(TokioExecutor::new()).build(KcpHyperConnector::new(config));
+        let uri: Uri = format!("http://127.0.0.1:{}/hello", server_addr.port())
+            .parse()
+            .unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), http::StatusCode::OK);
+    }
+}
@@ -0,0 +1,85 @@
+//! Conversation identifiers picked by a listener
+//!
+//! A client connecting with conv `0` lets the listener allocate one. The listener asks its
+//! `KcpConfig::conv_allocator` again while the returned conv is `0`, reserved for packet magics or taken by
+//! another session.
+
+use std::{
+    fmt::{self, Debug},
+    sync::atomic::{AtomicU32, Ordering},
+};
+
+/// Source of the convs a listener allocates
+pub trait ConvAllocator: Debug + Send + Sync {
+    /// Next conv, called again if it is `0`, reserved or in use
+    fn allocate(&self) -> u32;
+}
+
+/// Random convs, the default
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RandomConvAllocator;
+
+impl ConvAllocator for RandomConvAllocator {
+    fn allocate(&self) -> u32 {
+        rand::random()
+    }
+}
+
+/// Convs counting up from a start value, wrapping around and skipping `0`
+#[derive(Debug)]
+pub struct SequentialConvAllocator {
+    next: AtomicU32,
+}
+
+impl SequentialConvAllocator {
+    /// Allocator whose first conv is `first`
+    pub fn new(first: u32) -> SequentialConvAllocator {
+        SequentialConvAllocator {
+            next: AtomicU32::new(first),
+        }
+    }
+}
+
+impl Default for SequentialConvAllocator {
+    fn default() -> SequentialConvAllocator {
+        SequentialConvAllocator::new(1)
+    }
+}
+
+impl ConvAllocator for SequentialConvAllocator {
+    fn allocate(&self) -> u32 {
+        self.next.fetch_add(1, Ordering::Relaxed)
+    }
+}
+
+/// Convs returned by a caller-provided function
+pub struct FnConvAllocator<F>(pub F);
+
+impl<F> Debug for FnConvAllocator<F> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("FnConvAllocator").finish()
+    }
+}
+
+impl<F> ConvAllocator for FnConvAllocator<F>
+where
+    F: Fn() -> u32 + Send + Sync,
+{
+    fn allocate(&self) -> u32 {
+        (self.0)()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn sequential_wraps() {
+        let allocator = SequentialConvAllocator::new(u32::MAX);
+        assert_eq!(allocator.allocate(), u32::MAX);
+        // `0` is rejected by the listener, which asks again
+        assert_eq!(allocator.allocate(), 0);
+        assert_eq!(allocator.allocate(), 1);
+    }
+}
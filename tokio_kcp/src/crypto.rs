@@ -0,0 +1,308 @@
+//! Datagram encryption and authentication
+//!
+//! Every datagram leaving the pacer is sealed with XChaCha20-Poly1305 under a pre-shared key,
+//! datagrams failing authentication are dropped before any header is looked at.
+//!
+//! ```plain
+//! | nonce: counter (8), random (16) | ciphertext | tag (16) |
+//! ```
+//!
+//! The counter numbers the datagrams of a sender, authenticated along with the ciphertext. It starts at the
+//! unix time in microseconds, so a sender restarting on the same address continues above its predecessor.
+//! The receiving session drops counters its `ReplayWindow` has seen before or that trail too far behind.
+
+use std::{
+    fmt::{self, Debug},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+};
+
+use bytes::BytesMut;
+use chacha20poly1305::{aead::{AeadInPlace, KeyInit}, Key, Tag, XChaCha20Poly1305, XNonce};
+use rand::RngCore;
+use serde::{de, Deserialize, Deserializer, Serialize, Serializer};
+use tracing::error;
+
+use crate::utils::unix_millis;
+
+const NONCE_LEN: usize = 24;
+const COUNTER_LEN: usize = 8;
+const TAG_LEN: usize = 16;
+
+/// Bytes added to every datagram
+pub const CRYPTO_OVERHEAD: usize = NONCE_LEN + TAG_LEN;
+
+/// 256-bit key shared by both peers
+#[derive(Clone, PartialEq, Eq)]
+pub struct PreSharedKey([u8; 32]);
+
+impl PreSharedKey {
+    pub const fn new(key: [u8; 32]) -> PreSharedKey {
+        PreSharedKey(key)
+    }
+}
+
+impl From<[u8; 32]> for PreSharedKey {
+    fn from(key: [u8; 32]) -> PreSharedKey {
+        PreSharedKey(key)
+    }
+}
+
+// Written as 64 hex digits in config files
+impl Serialize for PreSharedKey {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let hex: String = self.0.iter().map(|b| format!("{:02x}", b)).collect();
+        serializer.serialize_str(&hex)
+    }
+}
+
+impl<'de> Deserialize<'de> for PreSharedKey {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<PreSharedKey, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        if hex.len() != 64 || !hex.is_ascii() {
+            return Err(de::Error::custom("pre-shared key must be 64 hex digits"));
+        }
+
+        let mut key = [0u8; 32];
+        for (i, b) in key.iter_mut().enumerate() {
+            *b = u8::from_str_radix(&hex[i * 2..i * 2 + 2], 16).map_err(de::Error::custom)?;
+        }
+        Ok(PreSharedKey(key))
+    }
+}
+
+impl Debug for PreSharedKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("PreSharedKey(..)")
+    }
+}
+
+/// Seals outgoing and opens incoming datagrams
+#[derive(Clone)]
+pub struct PacketCipher {
+    aead: XChaCha20Poly1305,
+    // Shared by the clones sealing for the same session
+    counter: Arc<AtomicU64>,
+}
+
+impl Debug for PacketCipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PacketCipher").finish()
+    }
+}
+
+impl PacketCipher {
+    pub fn new(key: &PreSharedKey) -> PacketCipher {
+        PacketCipher {
+            aead: XChaCha20Poly1305::new(Key::from_slice(&key.0)),
+            counter: Arc::new(AtomicU64::new(unix_millis() * 1000)),
+        }
+    }
+
+    /// Encrypt `packet`, the random part keeps nonces of sessions sharing the key apart
+    pub fn seal(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        let mut sealed = BytesMut::with_capacity(CRYPTO_OVERHEAD + packet.len());
+        self.seal_into(packet, &mut sealed).then(|| sealed.into())
+    }
+
+    /// Append the sealed `packet` to `out`, leaves it untouched and returns `false` on failure
+    pub fn seal_into(&self, packet: &[u8], out: &mut BytesMut) -> bool {
+        let start = out.len();
+        out.reserve(CRYPTO_OVERHEAD + packet.len());
+        let counter = self.counter.fetch_add(1, Ordering::Relaxed);
+        out.extend_from_slice(&counter.to_le_bytes());
+        out.resize(start + NONCE_LEN, 0);
+        rand::thread_rng().fill_bytes(&mut out[start + COUNTER_LEN..]);
+        out.extend_from_slice(packet);
+
+        let (nonce, payload) = out[start..].split_at_mut(NONCE_LEN);
+        match self.aead.encrypt_in_place_detached(XNonce::from_slice(nonce), b"", payload) {
+            Ok(tag) => {
+                out.extend_from_slice(&tag);
+                true
+            }
+            Err(err) => {
+                out.truncate(start);
+                error!("datagram encryption failed, error: {}", err);
+                false
+            }
+        }
+    }
+
+    /// Decrypt `packet`, `None` if it was not sealed with our key or got modified
+    pub fn open(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() < CRYPTO_OVERHEAD {
+            return None;
+        }
+        let (nonce, rest) = packet.split_at(NONCE_LEN);
+        let (payload, tag) = rest.split_at(rest.len() - TAG_LEN);
+
+        let mut opened = payload.to_vec();
+        self.aead
+            .decrypt_in_place_detached(XNonce::from_slice(nonce), b"", &mut opened, Tag::from_slice(tag))
+            .ok()?;
+        Some(opened)
+    }
+}
+
+/// Send counter of the sealed `packet`, only to be trusted once `open` accepted it
+pub fn counter(packet: &[u8]) -> u64 {
+    let mut counter = [0u8; COUNTER_LEN];
+    counter.copy_from_slice(&packet[..COUNTER_LEN]);
+    u64::from_le_bytes(counter)
+}
+
+/// Counters a session received lately, rejects duplicates and counters trailing the newest by `size` or more
+#[derive(Debug, Clone)]
+pub struct ReplayWindow {
+    newest: Option<u64>,
+    // Bit `counter % size` marks `counter` as received
+    bitmap: Vec<u64>,
+}
+
+impl ReplayWindow {
+    /// Window over at least `size` counters, rounded up to whole words
+    pub fn new(size: usize) -> ReplayWindow {
+        ReplayWindow {
+            newest: None,
+            bitmap: vec![0; size.max(1).div_ceil(64)],
+        }
+    }
+
+    fn size(&self) -> u64 {
+        self.bitmap.len() as u64 * 64
+    }
+
+    fn bit(&mut self, counter: u64) -> (&mut u64, u64) {
+        let index = counter % self.size();
+        (&mut self.bitmap[(index / 64) as usize], 1 << (index % 64))
+    }
+
+    /// Whether `counter` is new, marks it as received
+    pub fn accept(&mut self, counter: u64) -> bool {
+        let newest = match self.newest {
+            Some(newest) => newest,
+            None => {
+                self.newest = Some(counter);
+                let (word, mask) = self.bit(counter);
+                *word |= mask;
+                return true;
+            }
+        };
+
+        if counter > newest {
+            // The slots of skipped counters still mark counters that just fell out of the window
+            if counter - newest >= self.size() {
+                self.bitmap.iter_mut().for_each(|word| *word = 0);
+            } else {
+                for skipped in newest + 1..counter {
+                    let (word, mask) = self.bit(skipped);
+                    *word &= !mask;
+                }
+            }
+            self.newest = Some(counter);
+            let (word, mask) = self.bit(counter);
+            *word |= mask;
+            return true;
+        }
+
+        if newest - counter >= self.size() {
+            return false;
+        }
+        let (word, mask) = self.bit(counter);
+        let seen = *word & mask != 0;
+        *word |= mask;
+        !seen
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const KEY: PreSharedKey = PreSharedKey::new([7u8; 32]);
+
+    #[test]
+    fn seal_open_roundtrip() {
+        let cipher = PacketCipher::new(&KEY);
+        let packet = b"HELLO KCP";
+
+        let sealed = cipher.seal(packet).unwrap();
+        assert_eq!(sealed.len(), packet.len() + CRYPTO_OVERHEAD);
+        assert_ne!(&sealed[NONCE_LEN..NONCE_LEN + packet.len()], packet);
+        assert_eq!(cipher.open(&sealed).unwrap(), packet);
+
+        // Fresh nonce every time
+        assert_ne!(cipher.seal(packet).unwrap(), sealed);
+
+        // Appended behind whatever the buffer already holds
+        let mut out = BytesMut::from(&b"HEADER"[..]);
+        assert!(cipher.seal_into(packet, &mut out));
+        assert_eq!(&out[..6], b"HEADER");
+        assert_eq!(cipher.open(&out[6..]).unwrap(), packet);
+    }
+
+    #[test]
+    fn tampered_rejected() {
+        let cipher = PacketCipher::new(&KEY);
+        let mut sealed = cipher.seal(b"HELLO KCP").unwrap();
+        sealed[NONCE_LEN] ^= 1;
+        assert!(cipher.open(&sealed).is_none());
+        assert!(cipher.open(&sealed[..CRYPTO_OVERHEAD - 1]).is_none());
+    }
+
+    #[test]
+    fn counter_in_nonce() {
+        let cipher = PacketCipher::new(&KEY);
+        let clone = cipher.clone();
+        let first = counter(&cipher.seal(b"HELLO KCP").unwrap());
+        assert!(first >= 1_600_000_000_000_000);
+        for expected in first + 1..first + 4 {
+            let sealed = if expected % 2 == 0 { &cipher } else { &clone }.seal(b"HELLO KCP").unwrap();
+            assert_eq!(counter(&sealed), expected);
+        }
+
+        // A later session starts above, even while their counters meet the random part keeps nonces apart
+        std::thread::sleep(std::time::Duration::from_millis(2));
+        let other = PacketCipher::new(&KEY).seal(b"HELLO KCP").unwrap();
+        assert!(counter(&other) > first + 4);
+    }
+
+    #[test]
+    fn replay_window() {
+        let mut window = ReplayWindow::new(100);
+        assert_eq!(window.size(), 128);
+
+        assert!(window.accept(10));
+        assert!(!window.accept(10));
+        // Reordered within the window
+        assert!(window.accept(5));
+        assert!(window.accept(12));
+        assert!(window.accept(11));
+        assert!(!window.accept(5));
+        assert!(!window.accept(11));
+
+        // Moving ahead forgets the slots of skipped counters, stale ones are rejected
+        assert!(window.accept(200));
+        assert!(!window.accept(12));
+        assert!(!window.accept(72));
+        assert!(window.accept(73));
+        assert!(window.accept(199));
+        assert!(!window.accept(199));
+
+        // A jump beyond the window clears it all
+        assert!(window.accept(1_000));
+        assert!(window.accept(999));
+        assert!(!window.accept(200));
+        assert!(!window.accept(1_000));
+    }
+
+    #[test]
+    fn wrong_key_rejected() {
+        let sealed = PacketCipher::new(&KEY).seal(b"HELLO KCP").unwrap();
+        let other = PacketCipher::new(&PreSharedKey::new([8u8; 32]));
+        assert!(other.open(&sealed).is_none());
+    }
+}
@@ -0,0 +1,384 @@
+//! Debug HTTP endpoint
+//!
+//! `DebugServer` is a `MetricsSink` keeping the latest state of every session, served as JSON by a
+//! tiny built-in HTTP server. Install it as `KcpConfig::metrics` on the listeners and streams to
+//! inspect, then run `DebugServer::serve`:
+//!
+//! - `GET /sessions` lists all open sessions
+//! - `GET /sessions/<session_id>` returns one of them
+//!
+//! Meant for operators poking at a running relay with `curl`, not for exposure to the internet.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    io,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+
+use serde::Serialize;
+use tokio::{
+    net::{TcpListener, TcpStream, ToSocketAddrs},
+    time,
+};
+use tracing::{debug, error};
+
+use crate::metrics::{MetricsSample, MetricsSink};
+
+// Throughput is averaged over windows of at least this length
+const RATE_WINDOW_MS: u64 = 1000;
+// Requests are a single line and a few headers
+const MAX_REQUEST_LEN: usize = 8192;
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// State of one session, as served by `DebugServer`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize)]
+pub struct DebugSession {
+    /// Conversation of the session
+    pub conv: u32,
+    /// Identifies the session, see `MetricsSample::session_id`
+    pub session_id: u64,
+    /// Address the session sends to
+    pub peer_addr: Option<SocketAddr>,
+    /// Unix time in milliseconds of the latest sample
+    pub updated_ms: u64,
+    /// Smoothed RTT (ms)
+    pub srtt_ms: f64,
+    /// Minimum RTT over the base RTT window (ms)
+    pub base_rtt_ms: f64,
+    /// Queuing delay of the last RTT sample (ms)
+    pub qdelay_ms: f64,
+    /// Smoothed queuing delay (ms)
+    pub qdelay_avg_ms: f64,
+    /// Target bitrate of the congestion controller (bps)
+    pub target_bitrate: f32,
+    /// Congestion window (bytes)
+    pub congestion_window: f32,
+    /// Bytes sent and neither acknowledged nor lost
+    pub bytes_in_flight: u32,
+    /// Packets waiting for the pacer
+    pub pacer_queue: usize,
+    /// Receive window the peer advertised (segments)
+    pub rmt_wnd: u16,
+    /// Segments retransmitted after their RTO expired
+    pub retransmits: u32,
+    /// Segments retransmitted before their RTO, after enough later ones were acknowledged
+    pub fast_retransmits: u32,
+    /// Segments received ahead of a gap
+    pub out_of_order: u32,
+    /// ACKs of segments acknowledged before
+    pub duplicate_acks: u32,
+    /// Bytes of all datagrams sent
+    pub bytes_sent: u64,
+    /// Bytes of all datagrams received
+    pub bytes_received: u64,
+    /// Send throughput of the last full window of about a second (bps)
+    pub send_rate: f64,
+    /// Receive throughput of the last full window of about a second (bps)
+    pub recv_rate: f64,
+}
+
+// A session and the start of its running throughput window
+#[derive(Debug)]
+struct SessionEntry {
+    session: DebugSession,
+    window_start_ms: u64,
+    window_bytes_sent: u64,
+    window_bytes_received: u64,
+}
+
+/// Latest state of all sessions sharing this server, served over HTTP
+///
+/// Clones share the sessions.
+#[derive(Clone, Default)]
+pub struct DebugServer {
+    sessions: Arc<Mutex<HashMap<u64, SessionEntry>>>,
+}
+
+impl Debug for DebugServer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("DebugServer")
+            .field("sessions.len", &self.sessions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl DebugServer {
+    /// Server without sessions, they show up with their first sample
+    pub fn new() -> DebugServer {
+        DebugServer::default()
+    }
+
+    /// Open sessions, ordered by `conv` and `session_id`
+    pub fn sessions(&self) -> Vec<DebugSession> {
+        let mut sessions: Vec<DebugSession> = self.sessions.lock().unwrap().values().map(|e| e.session).collect();
+        sessions.sort_by_key(|s| (s.conv, s.session_id));
+        sessions
+    }
+
+    /// Session `session_id`, `None` if it isn't open
+    pub fn session(&self, session_id: u64) -> Option<DebugSession> {
+        self.sessions.lock().unwrap().get(&session_id).map(|e| e.session)
+    }
+
+    /// Serve the sessions on `addr` until the listener fails
+    pub async fn serve<A: ToSocketAddrs>(self, addr: A) -> io::Result<()> {
+        self.serve_listener(TcpListener::bind(addr).await?).await
+    }
+
+    /// Serve the sessions on connections accepted from `listener` until it fails
+    pub async fn serve_listener(self, listener: TcpListener) -> io::Result<()> {
+        loop {
+            let (stream, peer_addr) = listener.accept().await?;
+            let server = self.clone();
+            tokio::spawn(async move {
+                if let Err(err) = server.handle(stream).await {
+                    debug!("[DEBUG] request of {} failed, error: {}", peer_addr, err);
+                }
+            });
+        }
+    }
+
+    async fn handle(&self, stream: TcpStream) -> io::Result<()> {
+        let request = match time::timeout(REQUEST_TIMEOUT, read_request(&stream)).await {
+            Ok(request) => request?,
+            Err(..) => return Err(io::ErrorKind::TimedOut.into()),
+        };
+        let response = self.respond(&request);
+        write_all(&stream, &response).await
+    }
+
+    fn respond(&self, request: &[u8]) -> Vec<u8> {
+        let line = request.split(|&b| b == b'\r' || b == b'\n').next().unwrap_or_default();
+        let mut parts = line.split(|&b| b == b' ');
+        let (method, path) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+        if method != b"GET" {
+            return response("405 Method Not Allowed", "{\"error\":\"only GET is supported\"}");
+        }
+
+        let path = String::from_utf8_lossy(path);
+        let path = path.split('?').next().unwrap_or_default().trim_end_matches('/');
+        let body = if path.is_empty() || path == "/sessions" {
+            serde_json::to_string(&SessionList {
+                sessions: self.sessions(),
+            })
+        } else if let Some(session_id) = path.strip_prefix("/sessions/") {
+            match session_id.parse().ok().and_then(|session_id| self.session(session_id)) {
+                Some(session) => serde_json::to_string(&session),
+                None => return response("404 Not Found", "{\"error\":\"no such session\"}"),
+            }
+        } else {
+            return response("404 Not Found", "{\"error\":\"no such path\"}");
+        };
+
+        match body {
+            Ok(body) => response("200 OK", &body),
+            Err(err) => {
+                error!("[DEBUG] encoding sessions failed, error: {}", err);
+                response("500 Internal Server Error", "{\"error\":\"encoding failed\"}")
+            }
+        }
+    }
+}
+
+impl MetricsSink for DebugServer {
+    fn record(&self, sample: &MetricsSample) {
+        let mut sessions = self.sessions.lock().unwrap();
+        let entry = sessions.entry(sample.session_id).or_insert_with(|| SessionEntry {
+            session: DebugSession::default(),
+            window_start_ms: sample.timestamp_ms,
+            window_bytes_sent: sample.bytes_sent,
+            window_bytes_received: sample.bytes_received,
+        });
+
+        let elapsed_ms = sample.timestamp_ms.saturating_sub(entry.window_start_ms);
+        let (mut send_rate, mut recv_rate) = (entry.session.send_rate, entry.session.recv_rate);
+        if elapsed_ms >= RATE_WINDOW_MS {
+            let bps = |bytes: u64| bytes as f64 * 8.0 * 1000.0 / elapsed_ms as f64;
+            send_rate = bps(sample.bytes_sent.saturating_sub(entry.window_bytes_sent));
+            recv_rate = bps(sample.bytes_received.saturating_sub(entry.window_bytes_received));
+            entry.window_start_ms = sample.timestamp_ms;
+            entry.window_bytes_sent = sample.bytes_sent;
+            entry.window_bytes_received = sample.bytes_received;
+        }
+
+        entry.session = DebugSession {
+            conv: sample.conv,
+            session_id: sample.session_id,
+            peer_addr: sample.peer_addr,
+            updated_ms: sample.timestamp_ms,
+            srtt_ms: millis(sample.s_rtt),
+            base_rtt_ms: millis(sample.base_rtt),
+            qdelay_ms: millis(sample.qdelay),
+            qdelay_avg_ms: millis(sample.qdelay_avg),
+            target_bitrate: sample.target_bitrate,
+            congestion_window: sample.congestion_window,
+            bytes_in_flight: sample.bytes_in_flight,
+            pacer_queue: sample.pacer_queue,
+            rmt_wnd: sample.rmt_wnd,
+            retransmits: sample.retransmits,
+            fast_retransmits: sample.fast_retransmits,
+            out_of_order: sample.out_of_order,
+            duplicate_acks: sample.duplicate_acks,
+            bytes_sent: sample.bytes_sent,
+            bytes_received: sample.bytes_received,
+            send_rate,
+            recv_rate,
+        };
+    }
+
+    fn close(&self, _conv: u32, session_id: u64) {
+        self.sessions.lock().unwrap().remove(&session_id);
+    }
+}
+
+#[derive(Serialize)]
+struct SessionList {
+    sessions: Vec<DebugSession>,
+}
+
+fn millis(d: Duration) -> f64 {
+    d.as_secs_f64() * 1000.0
+}
+
+fn response(status: &str, body: &str) -> Vec<u8> {
+    format!(
+        "HTTP/1.1 {}\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+// Read up to the end of the request headers, a body is never expected
+async fn read_request(stream: &TcpStream) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0u8; 1024];
+    while !request.windows(4).any(|w| w == b"\r\n\r\n") {
+        if request.len() > MAX_REQUEST_LEN {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "request too long"));
+        }
+        stream.readable().await?;
+        match stream.try_read(&mut buf) {
+            Ok(0) => return Err(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => request.extend_from_slice(&buf[..n]),
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(request)
+}
+
+async fn write_all(stream: &TcpStream, mut buf: &[u8]) -> io::Result<()> {
+    while !buf.is_empty() {
+        stream.writable().await?;
+        match stream.try_write(buf) {
+            Ok(n) => buf = &buf[n..],
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use serde_json::Value;
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+    use crate::{KcpConfig, KcpListener, KcpStream};
+
+    async fn get(addr: std::net::SocketAddr, path: &str) -> (String, Value) {
+        let mut stream = TcpStream::connect(addr).await.unwrap();
+        let request = format!("GET {} HTTP/1.1\r\nhost: localhost\r\n\r\n", path);
+        stream.write_all(request.as_bytes()).await.unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).await.unwrap();
+
+        let (head, body) = response.split_once("\r\n\r\n").unwrap();
+        let status = head.lines().next().unwrap().to_owned();
+        (status, serde_json::from_str(body).unwrap())
+    }
+
+    #[test]
+    fn throughput_per_window() {
+        let server = DebugServer::new();
+        let sample = |timestamp_ms, bytes_sent| MetricsSample {
+            timestamp_ms,
+            conv: 7,
+            session_id: 3,
+            bytes_sent,
+            bytes_received: bytes_sent / 2,
+            ..MetricsSample::default()
+        };
+
+        server.record(&sample(1000, 0));
+        server.record(&sample(1500, 50_000));
+        assert_eq!(server.session(3).unwrap().send_rate, 0.0);
+        server.record(&sample(2000, 125_000));
+        let session = server.session(3).unwrap();
+        assert_eq!(session.send_rate, 1_000_000.0);
+        assert_eq!(session.recv_rate, 500_000.0);
+        assert_eq!(session.bytes_sent, 125_000);
+
+        // Another session on the same conv, e.g. with another peer, is kept apart
+        server.record(&MetricsSample {
+            session_id: 4,
+            ..sample(2000, 0)
+        });
+        assert_eq!(server.sessions().len(), 2);
+        assert_eq!(server.session(3).unwrap().bytes_sent, 125_000);
+
+        server.close(7, 3);
+        assert_eq!(server.sessions().len(), 1);
+        assert_eq!(server.sessions()[0].session_id, 4);
+    }
+
+    #[tokio::test]
+    async fn serves_sessions() {
+        let _ = env_logger::try_init();
+
+        let server = DebugServer::new();
+        let http = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let http_addr = http.local_addr().unwrap();
+        tokio::spawn(server.clone().serve_listener(http));
+
+        let config = KcpConfig {
+            metrics: Arc::new(server.clone()),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let mut stream = KcpStream::connect(&config, listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (sent, accepted) = tokio::join!(stream.send(b"HELLO"), listener.accept());
+        sent.unwrap();
+        let (mut accepted, _) = accepted.unwrap();
+        let mut buf = [0u8; 16];
+        accepted.recv(&mut buf).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        let (status, body) = get(http_addr, "/sessions").await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        // Both ends of the connection record into the same server under the same conv
+        let sessions = body["sessions"].as_array().unwrap();
+        assert_eq!(sessions.len(), 2);
+        assert_eq!(sessions[0]["conv"], sessions[1]["conv"]);
+        assert_ne!(sessions[0]["session_id"], sessions[1]["session_id"]);
+        let session_id = sessions[0]["session_id"].as_u64().unwrap();
+        assert!(sessions[0]["target_bitrate"].as_f64().unwrap() > 0.0);
+        assert!(sessions[0]["peer_addr"].is_string());
+
+        let (status, body) = get(http_addr, &format!("/sessions/{}", session_id)).await;
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert_eq!(body["session_id"].as_u64(), Some(session_id));
+        let (status, _) = get(http_addr, "/sessions/1x").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let (status, _) = get(http_addr, "/metrics").await;
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+    }
+}
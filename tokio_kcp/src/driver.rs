@@ -0,0 +1,257 @@
+//! Shared update driver
+//!
+//! A listener's sessions don't run an update task each: one driver task keeps their next update
+//! deadlines on a timer wheel with millisecond slots and updates all sessions due in the same slot in
+//! one go. Sessions woken early, by input or by a send, are updated on the driver's next turn.
+
+use std::{
+    collections::HashMap,
+    mem,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use spin::Mutex as SpinMutex;
+use tokio::{
+    sync::Notify,
+    time::{self, Instant},
+};
+use tracing::trace;
+
+use crate::session::KcpSession;
+
+// One slot per millisecond, deadlines further out wait for later rotations in their slot
+const WHEEL_SLOTS: u64 = 1024;
+
+struct DriverShared {
+    next_id: AtomicU64,
+    // Sessions added (with the session) or woken (without) since the driver's last turn
+    queue: SpinMutex<Vec<(u64, Option<Arc<KcpSession>>)>>,
+    notify: Notify,
+    stopped: AtomicBool,
+}
+
+/// Driver task of a listener, stops once dropped and all its sessions ended
+pub struct UpdateDriver {
+    shared: Arc<DriverShared>,
+}
+
+impl UpdateDriver {
+    pub fn new() -> UpdateDriver {
+        let shared = Arc::new(DriverShared {
+            next_id: AtomicU64::new(0),
+            queue: SpinMutex::new(Vec::new()),
+            notify: Notify::new(),
+            stopped: AtomicBool::new(false),
+        });
+        tokio::spawn(run(shared.clone()));
+        UpdateDriver { shared }
+    }
+
+    /// Handle for a session about to be created, `register` it once it is
+    pub fn handle(&self) -> DriverHandle {
+        DriverHandle {
+            shared: self.shared.clone(),
+            id: self.shared.next_id.fetch_add(1, Ordering::Relaxed),
+        }
+    }
+
+    /// Hand the session over to the driver, it is updated right away
+    pub fn register(&self, handle: &DriverHandle, session: Arc<KcpSession>) {
+        self.shared.queue.lock().push((handle.id, Some(session)));
+        self.shared.notify.notify_one();
+    }
+}
+
+impl Drop for UpdateDriver {
+    fn drop(&mut self) {
+        self.shared.stopped.store(true, Ordering::Release);
+        self.shared.notify.notify_one();
+    }
+}
+
+/// A session's way to ask the driver for an early update
+pub struct DriverHandle {
+    shared: Arc<DriverShared>,
+    id: u64,
+}
+
+impl DriverHandle {
+    pub fn wake(&self) {
+        self.shared.queue.lock().push((self.id, None));
+        self.shared.notify.notify_one();
+    }
+}
+
+async fn run(shared: Arc<DriverShared>) {
+    let mut sessions = HashMap::new();
+    let mut wheel = TimerWheel::new(Instant::now());
+    let mut due = Vec::new();
+
+    loop {
+        for (id, session) in mem::take(&mut *shared.queue.lock()) {
+            if let Some(session) = session {
+                sessions.insert(id, session);
+            }
+            due.push(id);
+        }
+        wheel.expire(Instant::now(), &mut due);
+        // A session woken several times, or woken and due, is updated once
+        due.sort_unstable();
+        due.dedup();
+
+        for id in due.drain(..) {
+            let Some(session) = sessions.get(&id) else {
+                continue;
+            };
+            match session.update_once() {
+                Some(next) => wheel.insert(id, next),
+                None => {
+                    wheel.remove(id);
+                    tokio::spawn(sessions.remove(&id).unwrap().finish());
+                }
+            }
+        }
+
+        if shared.stopped.load(Ordering::Acquire) && sessions.is_empty() {
+            trace!("[DRIVER] listener gone and all sessions ended");
+            break;
+        }
+
+        match wheel.next_deadline() {
+            Some(deadline) => tokio::select! {
+                _ = time::sleep_until(deadline) => {},
+                _ = shared.notify.notified() => {},
+            },
+            None => shared.notify.notified().await,
+        }
+    }
+}
+
+/// Hashed timer wheel of session update deadlines
+struct TimerWheel {
+    start: Instant,
+    // Last tick expired, ticks are milliseconds since `start`
+    current: u64,
+    slots: Vec<Vec<(u64, u64)>>,
+    // Tick of every scheduled session, wheel entries with another tick are stale
+    deadlines: HashMap<u64, u64>,
+}
+
+impl TimerWheel {
+    fn new(start: Instant) -> TimerWheel {
+        TimerWheel {
+            start,
+            current: 0,
+            slots: (0..WHEEL_SLOTS).map(|_| Vec::new()).collect(),
+            deadlines: HashMap::new(),
+        }
+    }
+
+    /// Schedule `id` at `deadline`, replacing its previous deadline
+    fn insert(&mut self, id: u64, deadline: Instant) {
+        // Rounded up, an update is never run before it is due. Overdue ones run on the next tick
+        let nanos = deadline.saturating_duration_since(self.start).as_nanos() as u64;
+        let tick = nanos.div_ceil(1_000_000).max(self.current + 1);
+        self.deadlines.insert(id, tick);
+        self.slots[(tick % WHEEL_SLOTS) as usize].push((id, tick));
+    }
+
+    fn remove(&mut self, id: u64) {
+        self.deadlines.remove(&id);
+    }
+
+    /// Move every session due at `now` to `due`
+    fn expire(&mut self, now: Instant, due: &mut Vec<u64>) {
+        let now_tick = now.saturating_duration_since(self.start).as_millis() as u64;
+        if now_tick <= self.current {
+            return;
+        }
+        // After a long sleep every slot is visited once
+        let steps = (now_tick - self.current).min(WHEEL_SLOTS);
+        for tick in self.current + 1..=self.current + steps {
+            let deadlines = &mut self.deadlines;
+            self.slots[(tick % WHEEL_SLOTS) as usize].retain(|&(id, tick)| {
+                if deadlines.get(&id) != Some(&tick) {
+                    return false;
+                }
+                if tick > now_tick {
+                    return true;
+                }
+                deadlines.remove(&id);
+                due.push(id);
+                false
+            });
+        }
+        self.current = now_tick;
+    }
+
+    /// When the next session is due, `None` if none is scheduled
+    fn next_deadline(&self) -> Option<Instant> {
+        if self.deadlines.is_empty() {
+            return None;
+        }
+        let next = (self.current + 1..=self.current + WHEEL_SLOTS)
+            .find(|&tick| {
+                self.slots[(tick % WHEEL_SLOTS) as usize]
+                    .iter()
+                    .any(|&(id, entry)| entry == tick && self.deadlines.get(&id) == Some(&tick))
+            })
+            // Everything scheduled lies beyond this rotation, look again after it
+            .unwrap_or(self.current + WHEEL_SLOTS);
+        Some(self.start + Duration::from_millis(next))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn timer_wheel() {
+        let start = Instant::now();
+        let at = |millis: u64| start + Duration::from_millis(millis);
+        let mut wheel = TimerWheel::new(start);
+        assert!(wheel.next_deadline().is_none());
+
+        // Sessions due in the same millisecond expire together
+        wheel.insert(1, at(10));
+        wheel.insert(2, at(10) - Duration::from_micros(300));
+        wheel.insert(3, at(25));
+        assert_eq!(wheel.next_deadline(), Some(at(10)));
+
+        let mut due = Vec::new();
+        wheel.expire(at(9), &mut due);
+        assert!(due.is_empty());
+        wheel.expire(at(10), &mut due);
+        due.sort_unstable();
+        assert_eq!(due, [1, 2]);
+        assert_eq!(wheel.next_deadline(), Some(at(25)));
+
+        // Rescheduling replaces the old deadline
+        due.clear();
+        wheel.insert(3, at(40));
+        wheel.expire(at(30), &mut due);
+        assert!(due.is_empty());
+        assert_eq!(wheel.next_deadline(), Some(at(40)));
+        wheel.remove(3);
+        assert!(wheel.next_deadline().is_none());
+
+        // Beyond one rotation, and after sleeping past several
+        wheel.insert(4, at(30 + 3 * WHEEL_SLOTS + 5));
+        assert_eq!(wheel.next_deadline(), Some(at(30 + WHEEL_SLOTS)));
+        wheel.expire(at(30 + WHEEL_SLOTS), &mut due);
+        assert!(due.is_empty());
+        wheel.expire(at(30 + 5 * WHEEL_SLOTS), &mut due);
+        assert_eq!(due, [4]);
+
+        // Overdue deadlines run on the next tick
+        due.clear();
+        wheel.insert(5, at(0));
+        wheel.expire(at(31 + 5 * WHEEL_SLOTS), &mut due);
+        assert_eq!(due, [5]);
+    }
+}
@@ -0,0 +1,234 @@
+//! ECN marking and CE detection (RFC 3168 / L4S)
+//!
+//! Outgoing packets are marked ECT(1) through `IP_TOS` / `IPV6_TCLASS`, the ECN bits of
+//! received packets are read from the `recvmsg` control messages.
+
+use std::{io, net::SocketAddr};
+
+use tokio::net::UdpSocket;
+
+/// ECN codepoint carried in the two low bits of the IP TOS / traffic class
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum EcnCodepoint {
+    /// Not ECN-capable transport
+    #[default]
+    NotEct = 0b00,
+    /// ECN-capable transport (1), used by L4S
+    Ect1 = 0b01,
+    /// ECN-capable transport (0)
+    Ect0 = 0b10,
+    /// Congestion experienced
+    Ce = 0b11,
+}
+
+impl EcnCodepoint {
+    /// Extract the codepoint from a TOS / traffic class byte
+    pub fn from_bits(tos: u8) -> EcnCodepoint {
+        match tos & 0b11 {
+            0b01 => EcnCodepoint::Ect1,
+            0b10 => EcnCodepoint::Ect0,
+            0b11 => EcnCodepoint::Ce,
+            _ => EcnCodepoint::NotEct,
+        }
+    }
+
+    /// Congestion experienced
+    #[inline]
+    pub fn is_ce(self) -> bool {
+        self == EcnCodepoint::Ce
+    }
+}
+
+/// Mark outgoing packets ECT(1) and ask the kernel to report the TOS of received packets
+#[cfg(unix)]
+pub fn enable_ecn(socket: &UdpSocket) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let fd = socket.as_raw_fd();
+    let ect = EcnCodepoint::Ect1 as libc::c_int;
+
+    match socket.local_addr()? {
+        SocketAddr::V4(..) => {
+            sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, ect)?;
+            sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1)?;
+        }
+        SocketAddr::V6(..) => {
+            sys::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, ect)?;
+            sys::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_RECVTCLASS, 1)?;
+            // Dual-stack sockets also carry IPv4 traffic, which may not be supported everywhere
+            let _ = sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, ect);
+            let _ = sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_RECVTOS, 1);
+        }
+    }
+
+    Ok(())
+}
+
+/// Mark outgoing packets ECT(1) and ask the kernel to report the TOS of received packets
+#[cfg(not(unix))]
+pub fn enable_ecn(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "ECN is only supported on unix"))
+}
+
+#[cfg(unix)]
+pub(crate) mod sys {
+    use std::{
+        io,
+        mem,
+        net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6},
+        os::unix::io::RawFd,
+        ptr,
+    };
+
+    use super::EcnCodepoint;
+
+    pub fn setsockopt(fd: RawFd, level: libc::c_int, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let ret = unsafe {
+            libc::setsockopt(
+                fd,
+                level,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if ret != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(())
+    }
+
+    pub fn recvmsg_ecn(fd: RawFd, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, EcnCodepoint)> {
+        let mut addr: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut iov = libc::iovec {
+            iov_base: buf.as_mut_ptr() as *mut libc::c_void,
+            iov_len: buf.len(),
+        };
+        // Room for a single int-sized control message, aligned for cmsghdr
+        let mut control = [0u64; 8];
+
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut addr as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+        msg.msg_iov = &mut iov;
+        msg.msg_iovlen = 1;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let n = unsafe { libc::recvmsg(fd, &mut msg, 0) };
+        if n < 0 {
+            return Err(io::Error::last_os_error());
+        }
+
+        let ecn = unsafe { cmsg_ecn(&msg) };
+        let addr = sockaddr_to_socket_addr(&addr)?;
+        Ok((n as usize, addr, ecn))
+    }
+
+    /// ECN codepoint from the control messages of a received `msg`
+    ///
+    /// # Safety
+    ///
+    /// `msg` must have been filled by `recvmsg` / `recvmmsg`.
+    pub unsafe fn cmsg_ecn(msg: &libc::msghdr) -> EcnCodepoint {
+        let mut ecn = EcnCodepoint::NotEct;
+        let mut cmsg = libc::CMSG_FIRSTHDR(msg);
+        while !cmsg.is_null() {
+            let level = (*cmsg).cmsg_level;
+            let ty = (*cmsg).cmsg_type;
+            let data = libc::CMSG_DATA(cmsg);
+
+            if level == libc::IPPROTO_IP && (ty == libc::IP_TOS || ty == libc::IP_RECVTOS) {
+                // Linux reports a single byte, BSDs may report an int
+                ecn = EcnCodepoint::from_bits(ptr::read(data));
+            } else if level == libc::IPPROTO_IPV6 && ty == libc::IPV6_TCLASS {
+                let tclass: libc::c_int = ptr::read_unaligned(data as *const libc::c_int);
+                ecn = EcnCodepoint::from_bits(tclass as u8);
+            }
+
+            cmsg = libc::CMSG_NXTHDR(msg, cmsg);
+        }
+        ecn
+    }
+
+    #[cfg(target_os = "linux")]
+    pub fn socket_addr_to_sockaddr(addr: &SocketAddr) -> (libc::sockaddr_storage, libc::socklen_t) {
+        let mut storage: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let len = match addr {
+            SocketAddr::V4(addr) => {
+                let sin = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in) };
+                sin.sin_family = libc::AF_INET as libc::sa_family_t;
+                sin.sin_port = addr.port().to_be();
+                sin.sin_addr.s_addr = u32::from(*addr.ip()).to_be();
+                mem::size_of::<libc::sockaddr_in>()
+            }
+            SocketAddr::V6(addr) => {
+                let sin6 = unsafe { &mut *(&mut storage as *mut libc::sockaddr_storage as *mut libc::sockaddr_in6) };
+                sin6.sin6_family = libc::AF_INET6 as libc::sa_family_t;
+                sin6.sin6_port = addr.port().to_be();
+                sin6.sin6_addr.s6_addr = addr.ip().octets();
+                sin6.sin6_flowinfo = addr.flowinfo();
+                sin6.sin6_scope_id = addr.scope_id();
+                mem::size_of::<libc::sockaddr_in6>()
+            }
+        };
+        (storage, len as libc::socklen_t)
+    }
+
+    pub fn sockaddr_to_socket_addr(addr: &libc::sockaddr_storage) -> io::Result<SocketAddr> {
+        match addr.ss_family as libc::c_int {
+            libc::AF_INET => {
+                let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in) };
+                let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+                Ok(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+            }
+            libc::AF_INET6 => {
+                let addr = unsafe { &*(addr as *const libc::sockaddr_storage as *const libc::sockaddr_in6) };
+                let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+                Ok(SocketAddr::V6(SocketAddrV6::new(
+                    ip,
+                    u16::from_be(addr.sin6_port),
+                    addr.sin6_flowinfo,
+                    addr.sin6_scope_id,
+                )))
+            }
+            family => Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("unsupported address family {}", family),
+            )),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::future;
+
+    use super::*;
+    use crate::transport::DatagramTransport;
+
+    #[test]
+    fn codepoint_from_tos() {
+        assert_eq!(EcnCodepoint::from_bits(0xb8), EcnCodepoint::NotEct);
+        assert_eq!(EcnCodepoint::from_bits(0xb9), EcnCodepoint::Ect1);
+        assert_eq!(EcnCodepoint::from_bits(0x02), EcnCodepoint::Ect0);
+        assert!(EcnCodepoint::from_bits(0x03).is_ce());
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn recv_ect1_marked() {
+        let s1 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let s2 = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        enable_ecn(&s1).unwrap();
+        enable_ecn(&s2).unwrap();
+
+        s1.send_to(b"ping", s2.local_addr().unwrap()).await.unwrap();
+
+        let mut buf = [0u8; 16];
+        let (n, addr, ecn) = future::poll_fn(|cx| DatagramTransport::poll_recv_from(&s2, cx, &mut buf)).await.unwrap();
+        assert_eq!(&buf[..n], b"ping");
+        assert_eq!(addr, s1.local_addr().unwrap());
+        assert_eq!(ecn, EcnCodepoint::Ect1);
+    }
+}
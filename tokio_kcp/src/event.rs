@@ -0,0 +1,62 @@
+//! Congestion and connection events
+//!
+//! A handler installed with `KcpStream::set_event_handler` is called from the session's update
+//! and input paths with the session lock held, it must not block.
+
+use std::{
+    fmt::{self, Debug},
+    net::SocketAddr,
+    sync::Arc,
+    time::Duration,
+};
+
+/// Congestion signal the controller reacted to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CongestionEvent {
+    /// A segment was declared lost
+    Loss,
+    /// The peer reported CE-marked packets
+    EcnCe,
+    /// Queuing delay grew past the controller's target
+    QueueDelay,
+}
+
+/// Event of a KCP session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KcpEvent {
+    /// The congestion controller backed off
+    Congestion(CongestionEvent),
+    /// RTT estimate, reported once per smoothed RTT
+    RttUpdate { srtt: Duration, rttvar: Duration },
+    /// The peer advertised a different receive window (segments)
+    RemoteWindowChanged { rmt_wnd: u16 },
+    /// The session is closed, no more events follow
+    Closed,
+    /// The session's conversation was assigned, e.g. by the server
+    ConvAssigned { conv: u32 },
+    /// The peer's datagrams arrive from a new address, replies follow it
+    PeerMigrated { peer_addr: SocketAddr },
+}
+
+/// Shared event callback
+#[derive(Clone)]
+pub(crate) struct EventHandler(Arc<dyn Fn(KcpEvent) + Send + Sync>);
+
+impl EventHandler {
+    pub fn new<F>(handler: F) -> EventHandler
+    where
+        F: Fn(KcpEvent) + Send + Sync + 'static,
+    {
+        EventHandler(Arc::new(handler))
+    }
+
+    pub fn emit(&self, event: KcpEvent) {
+        (self.0)(event)
+    }
+}
+
+impl Debug for EventHandler {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventHandler").finish()
+    }
+}
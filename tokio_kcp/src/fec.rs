@@ -0,0 +1,408 @@
+//! Reed-Solomon forward error correction below KCP
+//!
+//! Every outgoing datagram becomes a data shard, after `data_shards` of them the encoder
+//! emits `parity_shards` parity shards. Shard `seqid`s are consecutive, so the group of a
+//! shard is `seqid / (data_shards + parity_shards)` and its index the remainder.
+//!
+//! ```plain
+//! data:   | FEC_DATA_HEADER | seqid (u32) | len (u16) | payload |
+//! parity: | FEC_PARITY_HEADER | seqid (u32) | parity over (len, payload) |
+//! ```
+
+use std::{collections::BTreeMap, io, ops::Range};
+
+use bytes::{Buf, BufMut};
+use reed_solomon_erasure::galois_8::ReedSolomon;
+use serde::{Deserialize, Serialize};
+use tracing::{error, trace};
+
+use crate::packet::PacketType;
+
+pub const FEC_DATA_HEADER: u32 = 0x5C4D4644;
+pub const FEC_PARITY_HEADER: u32 = 0x5C4D4650;
+
+/// Bytes added to every datagram
+pub const FEC_OVERHEAD: usize = 4 + 4 + 2;
+
+/// Magic and seqid in front of every shard
+pub const SHARD_HEADER_LEN: usize = 8;
+// Incomplete groups older than this many groups are dropped
+const MAX_PENDING_GROUPS: u32 = 32;
+
+/// Number of data and parity shards per FEC group
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct FecConfig {
+    /// Datagrams protected by one group (N)
+    pub data_shards: usize,
+    /// Parity datagrams per group (K), up to K losses per group are recovered
+    pub parity_shards: usize,
+}
+
+impl Default for FecConfig {
+    fn default() -> FecConfig {
+        FecConfig {
+            data_shards: 10,
+            parity_shards: 3,
+        }
+    }
+}
+
+impl FecConfig {
+    fn group_size(&self) -> u32 {
+        (self.data_shards + self.parity_shards) as u32
+    }
+
+    // Largest seqid after which the sequence restarts at 0 without splitting a group
+    fn max_seqid(&self) -> u32 {
+        u32::MAX - u32::MAX % self.group_size() - 1
+    }
+
+    /// Reason the shard counts can't be used, `None` if they can
+    pub(crate) fn check(&self) -> Option<String> {
+        ReedSolomon::new(self.data_shards, self.parity_shards).err().map(|err| format!("{:?}", err))
+    }
+
+    fn codec(&self) -> Option<ReedSolomon> {
+        match ReedSolomon::new(self.data_shards, self.parity_shards) {
+            Ok(codec) => Some(codec),
+            Err(err) => {
+                error!("invalid FEC config {:?}, error: {:?}", self, err);
+                None
+            }
+        }
+    }
+}
+
+/// Datagram carries a FEC shard
+pub fn is_fec_packet(packet: &[u8]) -> bool {
+    matches!(PacketType::of(packet), PacketType::FecData | PacketType::FecParity)
+}
+
+/// Payload of a data shard, `None` for parity shards and malformed packets
+pub fn data_payload(packet: &[u8]) -> Option<&[u8]> {
+    data_payload_range(packet).map(|range| &packet[range])
+}
+
+/// Position of the payload of a data shard in `packet`
+pub fn data_payload_range(packet: &[u8]) -> Option<Range<usize>> {
+    if packet.len() < FEC_OVERHEAD || (&packet[..4]).get_u32_le() != FEC_DATA_HEADER {
+        return None;
+    }
+    let len = (&packet[8..10]).get_u16_le() as usize;
+    if packet.len() < FEC_OVERHEAD + len {
+        return None;
+    }
+    Some(FEC_OVERHEAD..FEC_OVERHEAD + len)
+}
+
+/// Wraps outgoing datagrams into shards
+pub struct FecEncoder {
+    config: FecConfig,
+    codec: Option<ReedSolomon>,
+    next_seqid: u32,
+    // (len, payload) of the data shards of the current group followed by the parity shards, reused
+    // across groups
+    shards: Vec<Vec<u8>>,
+    filled: usize,
+    packet: Vec<u8>,
+}
+
+impl std::fmt::Debug for FecEncoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FecEncoder")
+            .field("config", &self.config)
+            .field("next_seqid", &self.next_seqid)
+            .field("filled", &self.filled)
+            .finish()
+    }
+}
+
+impl FecEncoder {
+    pub fn new(config: FecConfig) -> FecEncoder {
+        FecEncoder {
+            codec: config.codec(),
+            config,
+            next_seqid: 0,
+            shards: vec![Vec::new(); config.data_shards + config.parity_shards],
+            filled: 0,
+            packet: Vec::new(),
+        }
+    }
+
+    fn take_seqid(&mut self) -> u32 {
+        let seqid = self.next_seqid;
+        self.next_seqid = if seqid >= self.config.max_seqid() { 0 } else { seqid + 1 };
+        seqid
+    }
+
+    /// Wrap `payload`, `emit` gets the data shard followed by the parity shards if it completed a
+    /// group
+    ///
+    /// Packets are only valid for the duration of the call, the buffers are reused.
+    pub fn encode<F>(&mut self, payload: &[u8], mut emit: F) -> io::Result<()>
+    where
+        F: FnMut(&[u8]) -> io::Result<()>,
+    {
+        let seqid = self.take_seqid();
+        self.packet.clear();
+        self.packet.put_u32_le(FEC_DATA_HEADER);
+        self.packet.put_u32_le(seqid);
+        self.packet.put_u16_le(payload.len() as u16);
+        self.packet.extend_from_slice(payload);
+
+        let shard = &mut self.shards[self.filled];
+        shard.clear();
+        shard.extend_from_slice(&self.packet[SHARD_HEADER_LEN..]);
+        self.filled += 1;
+        emit(&self.packet)?;
+
+        if self.filled < self.config.data_shards {
+            return Ok(());
+        }
+        self.filled = 0;
+
+        let codec = match self.codec {
+            Some(ref codec) => codec,
+            None => return Ok(()),
+        };
+        let shard_len = self.shards[..self.config.data_shards].iter().map(Vec::len).max().unwrap_or(0);
+        for shard in self.shards.iter_mut() {
+            shard.resize(shard_len, 0);
+        }
+        if let Err(err) = codec.encode(&mut self.shards) {
+            error!("FEC encode failed, error: {:?}", err);
+            return Ok(());
+        }
+
+        for i in self.config.data_shards..self.shards.len() {
+            let seqid = self.take_seqid();
+            self.packet.clear();
+            self.packet.put_u32_le(FEC_PARITY_HEADER);
+            self.packet.put_u32_le(seqid);
+            self.packet.extend_from_slice(&self.shards[i]);
+            emit(&self.packet)?;
+        }
+        Ok(())
+    }
+}
+
+struct FecGroup {
+    shards: Vec<Option<Vec<u8>>>,
+    received: usize,
+    // Data shards were handed out or recovered, later shards are useless
+    done: bool,
+}
+
+/// Unwraps incoming shards and recovers lost data shards
+pub struct FecDecoder {
+    config: FecConfig,
+    codec: Option<ReedSolomon>,
+    groups: BTreeMap<u32, FecGroup>,
+    newest_group: u32,
+}
+
+impl std::fmt::Debug for FecDecoder {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("FecDecoder")
+            .field("config", &self.config)
+            .field("groups.len", &self.groups.len())
+            .field("newest_group", &self.newest_group)
+            .finish()
+    }
+}
+
+impl FecDecoder {
+    pub fn new(config: FecConfig) -> FecDecoder {
+        FecDecoder {
+            codec: config.codec(),
+            config,
+            groups: BTreeMap::new(),
+            newest_group: 0,
+        }
+    }
+
+    /// Feed a shard, returns the payload of a data shard and any payloads recovered with it
+    pub fn decode(&mut self, packet: &[u8]) -> Vec<Vec<u8>> {
+        let mut payloads = Vec::new();
+        if !is_fec_packet(packet) {
+            return payloads;
+        }
+
+        let is_data = (&packet[..4]).get_u32_le() == FEC_DATA_HEADER;
+        let seqid = (&packet[4..8]).get_u32_le();
+        let shard = &packet[SHARD_HEADER_LEN..];
+
+        let data = if is_data {
+            match data_payload(packet) {
+                Some(payload) => Some(payload),
+                None => return payloads,
+            }
+        } else {
+            None
+        };
+
+        let group_size = self.config.group_size();
+        let group_id = seqid / group_size;
+        let index = (seqid % group_size) as usize;
+
+        // Sequence restarts after `max_seqid`, treat a far backwards jump as the new start
+        if group_id > self.newest_group || self.newest_group - group_id > u32::MAX / group_size / 2 {
+            self.newest_group = group_id;
+        }
+        let newest_group = self.newest_group;
+        self.groups.retain(|&id, _| newest_group.wrapping_sub(id) < MAX_PENDING_GROUPS);
+        if newest_group.wrapping_sub(group_id) >= MAX_PENDING_GROUPS {
+            // Too late for recovery, KCP still wants the data
+            payloads.extend(data.map(<[u8]>::to_vec));
+            return payloads;
+        }
+
+        let data_shards = self.config.data_shards;
+        let group = self.groups.entry(group_id).or_insert_with(|| FecGroup {
+            shards: vec![None; group_size as usize],
+            received: 0,
+            done: false,
+        });
+        // Duplicates, and data shards that were already recovered
+        if group.done || group.shards[index].is_some() {
+            return payloads;
+        }
+        payloads.extend(data.map(<[u8]>::to_vec));
+        group.shards[index] = Some(shard.to_vec());
+        group.received += 1;
+
+        if group.received < data_shards {
+            return payloads;
+        }
+        group.done = true;
+
+        let missing: Vec<usize> = (0..data_shards).filter(|&i| group.shards[i].is_none()).collect();
+        if missing.is_empty() {
+            return payloads;
+        }
+
+        // All parity shards have the length of the longest data shard
+        let shard_len = match group.shards[data_shards..].iter().flatten().next() {
+            Some(parity) => parity.len(),
+            None => return payloads,
+        };
+        for shard in group.shards[..data_shards].iter_mut().flatten() {
+            shard.resize(shard_len, 0);
+        }
+
+        let codec = match self.codec {
+            Some(ref codec) => codec,
+            None => return payloads,
+        };
+        if let Err(err) = codec.reconstruct_data(&mut group.shards) {
+            error!("FEC reconstruct group {} failed, error: {:?}", group_id, err);
+            return payloads;
+        }
+
+        for i in missing {
+            let shard = group.shards[i].as_ref().unwrap();
+            let len = (&shard[..2]).get_u16_le() as usize;
+            if let Some(payload) = shard.get(2..2 + len) {
+                trace!("[FEC] recovered shard {} of group {}, {} bytes", i, group_id, len);
+                payloads.push(payload.to_vec());
+            }
+        }
+
+        payloads
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const CONFIG: FecConfig = FecConfig {
+        data_shards: 4,
+        parity_shards: 2,
+    };
+
+    fn payload(i: u8) -> Vec<u8> {
+        vec![i; 10 + i as usize * 7]
+    }
+
+    fn encode(encoder: &mut FecEncoder, payload: &[u8]) -> Vec<Vec<u8>> {
+        let mut packets = Vec::new();
+        encoder
+            .encode(payload, |packet| {
+                packets.push(packet.to_vec());
+                Ok(())
+            })
+            .unwrap();
+        packets
+    }
+
+    fn encode_group(encoder: &mut FecEncoder, first: u8) -> Vec<Vec<u8>> {
+        (first..first + CONFIG.data_shards as u8)
+            .flat_map(|i| encode(encoder, &payload(i)))
+            .collect()
+    }
+
+    #[test]
+    fn parity_after_full_group() {
+        let mut encoder = FecEncoder::new(CONFIG);
+        for i in 0..3 {
+            assert_eq!(encode(&mut encoder, &payload(i)).len(), 1);
+        }
+        let packets = encode(&mut encoder, &payload(3));
+        assert_eq!(packets.len(), 3);
+        assert_eq!(data_payload(&packets[0]), Some(&payload(3)[..]));
+        assert!(packets[1..].iter().all(|p| is_fec_packet(p) && data_payload(p).is_none()));
+    }
+
+    #[test]
+    fn recover_lost_data_shards() {
+        let mut encoder = FecEncoder::new(CONFIG);
+        let mut decoder = FecDecoder::new(CONFIG);
+
+        let packets = encode_group(&mut encoder, 0);
+        assert_eq!(packets.len(), 6);
+
+        let mut received = Vec::new();
+        // lose data shards 1 and 2
+        for (i, packet) in packets.iter().enumerate() {
+            if i == 1 || i == 2 {
+                continue;
+            }
+            received.extend(decoder.decode(packet));
+        }
+
+        received.sort();
+        let mut expected: Vec<Vec<u8>> = (0..4).map(payload).collect();
+        expected.sort();
+        assert_eq!(received, expected);
+    }
+
+    #[test]
+    fn too_many_losses() {
+        let mut encoder = FecEncoder::new(CONFIG);
+        let mut decoder = FecDecoder::new(CONFIG);
+
+        let packets = encode_group(&mut encoder, 0);
+        let received: Vec<Vec<u8>> = packets
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| ![0, 1, 4].contains(i))
+            .flat_map(|(_, p)| decoder.decode(p))
+            .collect();
+        assert_eq!(received, vec![payload(2), payload(3)]);
+    }
+
+    #[test]
+    fn duplicates_and_late_parity_ignored() {
+        let mut encoder = FecEncoder::new(CONFIG);
+        let mut decoder = FecDecoder::new(CONFIG);
+
+        let packets = encode_group(&mut encoder, 0);
+        assert_eq!(decoder.decode(&packets[0]).len(), 1);
+        assert!(decoder.decode(&packets[0]).is_empty());
+        let total: usize = packets.iter().map(|p| decoder.decode(p).len()).sum();
+        // shard 0 was already seen, 1..4 delivered, parity adds nothing
+        assert_eq!(total, 3);
+    }
+}
@@ -0,0 +1,598 @@
+//! Congestion feedback wire formats
+//!
+//! Feedback travels behind `SCREAM_FEEDBACK_HEADER` and reports which segments the receiver
+//! got, when, and with which ECN codepoint. A receiver that cannot take more than some bitrate
+//! appends it to the report, REMB-like.
+
+use std::{cmp, convert::TryInto};
+
+use bytes::{Buf, BufMut};
+use serde::{Deserialize, Serialize};
+use tracing::debug;
+
+use crate::ecn::EcnCodepoint;
+
+/// Native entry: 4 bytes sn, 8 bytes reception timestamp, 1 byte ECN codepoint
+pub const FEEDBACK_ENTRY_LEN: usize = 13;
+
+/// Sequence numbers of unreliable datagrams, counted apart from KCP's `sn`s
+pub const DATAGRAM_SN_FLAG: u32 = 1 << 31;
+
+/// Segment and datagram sequence numbers are 31 bits wide and wrap within their space
+pub const SN_MASK: u32 = !DATAGRAM_SN_FLAG;
+
+/// Sequence number reported to the congestion controller for KCP segment `sn`
+pub fn segment_sn(sn: u32) -> u32 {
+    sn & SN_MASK
+}
+
+/// Signed distance from `b` to `a` in serial number arithmetic (RFC 1982), both from the same space
+pub fn sn_distance(a: u32, b: u32) -> i32 {
+    ((a.wrapping_sub(b) << 1) as i32) >> 1
+}
+
+/// `a` was sent after `b`, both from the same space
+pub fn sn_newer(a: u32, b: u32) -> bool {
+    sn_distance(a, b) > 0
+}
+
+// RTCP transport layer feedback, FMT 11 (RFC 8888)
+const RFC8888_HEADER_LEN: usize = 8;
+// Media SSRC, begin sequence and number of reports
+const RFC8888_BLOCK_HEADER_LEN: usize = 8;
+// Segments and datagrams are reported as separate media sources
+const RFC8888_SSRC_SEGMENTS: u32 = 0;
+const RFC8888_SSRC_DATAGRAMS: u32 = 1;
+const RFC8888_PT: u8 = 205;
+const RFC8888_FMT: u8 = 11;
+const RFC8888_MAX_REPORTS: usize = 16384;
+// Arrival time offsets are in 1/1024 s, 0x1FFE means "at least this old"
+const RFC8888_ATO_OVERRANGE: u16 = 0x1FFE;
+const RFC8888_ATO_UNAVAILABLE: u16 = 0x1FFF;
+// Seconds between the NTP epoch (1900) and the unix epoch
+const NTP_UNIX_OFFSET_SECS: u64 = 2_208_988_800;
+// Native maximum bitrate: 4 bytes bps behind the entries, never a multiple of an entry
+const NATIVE_MAX_BITRATE_LEN: usize = 4;
+// RTCP payload-specific feedback, application layer FMT 15 (draft-alvestrand-rmcat-remb), for both
+// media SSRCs
+const REMB_LEN: usize = 28;
+const REMB_PT: u8 = 206;
+const REMB_FMT: u8 = 15;
+const REMB_IDENTIFIER: &[u8; 4] = b"REMB";
+const REMB_MANTISSA_BITS: u32 = 18;
+
+/// Encoding of congestion feedback packets
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedbackFormat {
+    /// Flat list of (sn, absolute reception time in ms, ECN)
+    #[default]
+    Native,
+    /// RFC 8888 RTCP congestion control feedback, for interop with reference SCReAM
+    Rfc8888,
+}
+
+/// When a receiver sends congestion feedback
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FeedbackPolicy {
+    /// Every `feedback_interval`
+    #[default]
+    Fixed,
+    /// Every quarter of the smoothed RTT, but at most once per `feedback_interval`
+    Adaptive,
+}
+
+/// A segment reported by the receiver
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeedbackPacketInfo {
+    pub seq_number: u32,
+    pub reception_time_ms: u64,
+    pub ecn: EcnCodepoint,
+}
+
+/// Append `entries` received until `report_time_ms` (unix time) to `buf`
+pub fn encode_into(format: FeedbackFormat, entries: &[FeedbackPacketInfo], report_time_ms: u64, buf: &mut Vec<u8>) {
+    match format {
+        FeedbackFormat::Native => encode_native(entries, buf),
+        FeedbackFormat::Rfc8888 => encode_rfc8888(entries, report_time_ms, buf),
+    }
+}
+
+/// Number of leading `entries` whose encoding fits into `max_len` bytes, at least one
+pub fn fitting_entries(format: FeedbackFormat, entries: &[FeedbackPacketInfo], max_len: usize) -> usize {
+    if entries.is_empty() {
+        return 0;
+    }
+    let fitting = match format {
+        FeedbackFormat::Native => max_len / FEEDBACK_ENTRY_LEN,
+        FeedbackFormat::Rfc8888 => {
+            // The encoding only grows with more entries, search the longest prefix that fits
+            let (mut fits, mut exceeds) = (0, entries.len() + 1);
+            while exceeds - fits > 1 {
+                let n = (fits + exceeds) / 2;
+                if rfc8888_len(&entries[..n]) <= max_len {
+                    fits = n;
+                } else {
+                    exceeds = n;
+                }
+            }
+            fits
+        }
+    };
+    fitting.clamp(1, entries.len())
+}
+
+/// Decode a feedback payload
+///
+/// The highest segment and datagram sequence numbers sent are used to extend truncated ones.
+pub fn decode(
+    format: FeedbackFormat,
+    data: &[u8],
+    highest_sent_sn: u32,
+    highest_sent_datagram_sn: u32,
+) -> Vec<FeedbackPacketInfo> {
+    match format {
+        FeedbackFormat::Native => decode_native(data),
+        FeedbackFormat::Rfc8888 => decode_rfc8888(data, highest_sent_sn, highest_sent_datagram_sn),
+    }
+}
+
+/// Bytes `encode_max_bitrate` appends
+pub fn max_bitrate_len(format: FeedbackFormat) -> usize {
+    match format {
+        FeedbackFormat::Native => NATIVE_MAX_BITRATE_LEN,
+        FeedbackFormat::Rfc8888 => REMB_LEN,
+    }
+}
+
+/// Append the highest bitrate (bps) the receiver accepts to a payload written by `encode_into`
+pub fn encode_max_bitrate(format: FeedbackFormat, max_bitrate: f32, buf: &mut Vec<u8>) {
+    let max_bitrate = max_bitrate.clamp(0.0, u32::MAX as f32) as u32;
+    match format {
+        FeedbackFormat::Native => buf.put_u32_le(max_bitrate),
+        FeedbackFormat::Rfc8888 => {
+            let exp = (32 - max_bitrate.leading_zeros()).saturating_sub(REMB_MANTISSA_BITS);
+            let mantissa = max_bitrate >> exp;
+
+            buf.put_u8(0x80 | REMB_FMT);
+            buf.put_u8(REMB_PT);
+            buf.put_u16((REMB_LEN / 4 - 1) as u16);
+            buf.put_u32(0);
+            // Media SSRC is unused by REMB, the covered SSRCs follow
+            buf.put_u32(0);
+            buf.put_slice(REMB_IDENTIFIER);
+            buf.put_u32((2 << 24) | (exp << REMB_MANTISSA_BITS) | mantissa);
+            buf.put_u32(RFC8888_SSRC_SEGMENTS);
+            buf.put_u32(RFC8888_SSRC_DATAGRAMS);
+        }
+    }
+}
+
+/// Highest bitrate (bps) the receiver accepts, `None` if the payload carries none
+pub fn decode_max_bitrate(format: FeedbackFormat, data: &[u8]) -> Option<f32> {
+    match format {
+        FeedbackFormat::Native => {
+            if data.len() % FEEDBACK_ENTRY_LEN != NATIVE_MAX_BITRATE_LEN {
+                return None;
+            }
+            let trailer = &data[data.len() - NATIVE_MAX_BITRATE_LEN..];
+            Some(u32::from_le_bytes(trailer.try_into().unwrap()) as f32)
+        }
+        FeedbackFormat::Rfc8888 => {
+            // The REMB follows the congestion control feedback in the compound packet
+            if data.len() < RFC8888_HEADER_LEN {
+                return None;
+            }
+            let len = (u16::from_be_bytes([data[2], data[3]]) as usize + 1) * 4;
+            let mut remb = data.get(len..)?;
+            if remb.len() < 20 || remb[0] != 0x80 | REMB_FMT || remb[1] != REMB_PT || &remb[12..16] != REMB_IDENTIFIER {
+                debug!("feedback trailer of {} bytes is no REMB", remb.len());
+                return None;
+            }
+            remb.advance(16);
+            let bitrate = remb.get_u32();
+            let exp = (bitrate >> REMB_MANTISSA_BITS) & 0x3F;
+            let mantissa = bitrate & ((1 << REMB_MANTISSA_BITS) - 1);
+            Some(mantissa as f32 * (1u64 << exp) as f32)
+        }
+    }
+}
+
+fn encode_native(entries: &[FeedbackPacketInfo], buf: &mut Vec<u8>) {
+    buf.reserve(entries.len() * FEEDBACK_ENTRY_LEN);
+    for info in entries {
+        buf.extend_from_slice(&info.seq_number.to_le_bytes());
+        buf.extend_from_slice(&info.reception_time_ms.to_le_bytes());
+        buf.push(info.ecn as u8);
+    }
+}
+
+fn decode_native(data: &[u8]) -> Vec<FeedbackPacketInfo> {
+    data.chunks_exact(FEEDBACK_ENTRY_LEN)
+        .map(|chunk| FeedbackPacketInfo {
+            seq_number: u32::from_le_bytes(chunk[0..4].try_into().unwrap()),
+            reception_time_ms: u64::from_le_bytes(chunk[4..12].try_into().unwrap()),
+            ecn: EcnCodepoint::from_bits(chunk[12]),
+        })
+        .collect()
+}
+
+/// Middle 32 bits of the NTP timestamp (16.16 fixed point seconds)
+fn ntp_short(unix_ms: u64) -> u32 {
+    let ntp_ms = unix_ms + NTP_UNIX_OFFSET_SECS * 1000;
+    ((ntp_ms << 16) / 1000) as u32
+}
+
+fn encode_rfc8888(entries: &[FeedbackPacketInfo], report_time_ms: u64, buf: &mut Vec<u8>) {
+    if entries.is_empty() {
+        return;
+    }
+
+    let start = buf.len();
+    buf.put_u8(0x80 | RFC8888_FMT);
+    buf.put_u8(RFC8888_PT);
+    // Length in 32-bit words minus one, filled in once the blocks are written
+    buf.put_u16(0);
+    // Sender SSRC is meaningless for KCP sessions
+    buf.put_u32(0);
+
+    for (ssrc, datagrams) in [(RFC8888_SSRC_SEGMENTS, false), (RFC8888_SSRC_DATAGRAMS, true)] {
+        let block_entries = entries
+            .iter()
+            .filter(move |e| (e.seq_number & DATAGRAM_SN_FLAG != 0) == datagrams);
+        encode_rfc8888_block(buf, ssrc, block_entries, report_time_ms);
+    }
+    buf.put_u32(ntp_short(report_time_ms));
+
+    let len = buf.len() - start;
+    buf[start + 2..start + 4].copy_from_slice(&((len / 4 - 1) as u16).to_be_bytes());
+}
+
+fn rfc8888_len(entries: &[FeedbackPacketInfo]) -> usize {
+    let mut len = RFC8888_HEADER_LEN + 4;
+    for datagrams in [false, true] {
+        let block_entries = entries
+            .iter()
+            .filter(move |e| (e.seq_number & DATAGRAM_SN_FLAG != 0) == datagrams);
+        if let Some((_, num_reports)) = rfc8888_block_range(block_entries) {
+            len += RFC8888_BLOCK_HEADER_LEN + (num_reports + num_reports % 2) * 2;
+        }
+    }
+    len
+}
+
+// First sequence number and number of reports of a block, `None` for an empty block
+fn rfc8888_block_range<'a, I>(entries: I) -> Option<(u32, usize)>
+where
+    I: Iterator<Item = &'a FeedbackPacketInfo> + Clone,
+{
+    let first_sn = entries.clone().next()?.seq_number;
+    // Reports cover a contiguous range of 16-bit sequence numbers starting at the oldest entry
+    let begin_sn = entries
+        .clone()
+        .map(|e| e.seq_number)
+        .min_by_key(|&sn| sn_distance(sn, first_sn))
+        .unwrap();
+    let num_reports = entries
+        .map(|e| sn_distance(e.seq_number, begin_sn) as usize + 1)
+        .max()
+        .unwrap();
+    Some((begin_sn, cmp::min(num_reports, RFC8888_MAX_REPORTS)))
+}
+
+// Nothing is written for an empty block
+fn encode_rfc8888_block<'a, I>(buf: &mut Vec<u8>, ssrc: u32, entries: I, report_time_ms: u64)
+where
+    I: Iterator<Item = &'a FeedbackPacketInfo> + Clone,
+{
+    let (begin_sn, num_reports) = match rfc8888_block_range(entries.clone()) {
+        Some(range) => range,
+        None => return,
+    };
+
+    buf.put_u32(ssrc);
+    buf.put_u16(begin_sn as u16);
+    buf.put_u16(num_reports as u16);
+
+    // Report blocks are padded to a 32-bit boundary
+    let reports = buf.len();
+    buf.resize(reports + (num_reports + num_reports % 2) * 2, 0);
+    for info in entries {
+        let index = sn_distance(info.seq_number, begin_sn) as usize;
+        if index >= num_reports {
+            continue;
+        }
+        let offset_ms = report_time_ms.saturating_sub(info.reception_time_ms);
+        let ato = cmp::min(offset_ms * 1024 / 1000, RFC8888_ATO_OVERRANGE as u64) as u16;
+        let report = 0x8000 | ((info.ecn as u16) << 13) | ato;
+        buf[reports + index * 2..reports + index * 2 + 2].copy_from_slice(&report.to_be_bytes());
+    }
+}
+
+fn decode_rfc8888(data: &[u8], highest_sent_sn: u32, highest_sent_datagram_sn: u32) -> Vec<FeedbackPacketInfo> {
+    let mut entries = Vec::new();
+
+    if data.len() < RFC8888_HEADER_LEN + RFC8888_BLOCK_HEADER_LEN + 4 {
+        debug!("RFC 8888 feedback too short, {} bytes", data.len());
+        return entries;
+    }
+
+    let mut header = &data[..RFC8888_HEADER_LEN];
+    let first = header.get_u8();
+    let pt = header.get_u8();
+    if first >> 6 != 2 || first & 0x1F != RFC8888_FMT || pt != RFC8888_PT {
+        debug!("not a RFC 8888 feedback, V/FMT {:#x} PT {}", first, pt);
+        return entries;
+    }
+    let len = (header.get_u16() as usize + 1) * 4;
+    let _sender_ssrc = header.get_u32();
+    if data.len() < len {
+        debug!("RFC 8888 feedback truncated, length {} but {} bytes", len, data.len());
+        return entries;
+    }
+    if len < RFC8888_HEADER_LEN + 4 {
+        debug!("RFC 8888 feedback length {} leaves no room for the report timestamp", len);
+        return entries;
+    }
+
+    let report_ntp = (&data[len - 4..len]).get_u32();
+    // Only offsets matter, rebuild absolute times on the unix ms scale of the NTP timestamp
+    let report_time_ms = ((report_ntp as u64) * 1000) >> 16;
+
+    let mut blocks = &data[RFC8888_HEADER_LEN..len - 4];
+    while blocks.len() >= RFC8888_BLOCK_HEADER_LEN {
+        let media_ssrc = blocks.get_u32();
+        let begin_seq = blocks.get_u16();
+        let num_reports = blocks.get_u16() as usize;
+
+        let padded_reports = (num_reports + 1) & !1;
+        if blocks.len() < padded_reports * 2 {
+            debug!(
+                "RFC 8888 feedback truncated, {} reports but {} bytes left",
+                num_reports,
+                blocks.len()
+            );
+            break;
+        }
+        let mut reports = &blocks[..padded_reports * 2];
+        blocks = &blocks[padded_reports * 2..];
+
+        let highest_sn = match media_ssrc {
+            RFC8888_SSRC_SEGMENTS => highest_sent_sn,
+            RFC8888_SSRC_DATAGRAMS => highest_sent_datagram_sn,
+            ssrc => {
+                debug!("RFC 8888 feedback for unknown media SSRC {}", ssrc);
+                continue;
+            }
+        };
+        // Extend 16-bit sequence numbers with the closest sn at or before the highest one sent
+        let begin_sn = highest_sn.wrapping_sub((highest_sn as u16).wrapping_sub(begin_seq) as u32);
+
+        for i in 0..num_reports {
+            let report = reports.get_u16();
+            if report & 0x8000 == 0 {
+                continue;
+            }
+            let ato = report & 0x1FFF;
+            let reception_time_ms = if ato == RFC8888_ATO_UNAVAILABLE {
+                report_time_ms
+            } else {
+                report_time_ms.saturating_sub(ato as u64 * 1000 / 1024)
+            };
+            let mut seq_number = begin_sn.wrapping_add(i as u32) & SN_MASK;
+            if media_ssrc == RFC8888_SSRC_DATAGRAMS {
+                seq_number |= DATAGRAM_SN_FLAG;
+            }
+            entries.push(FeedbackPacketInfo {
+                seq_number,
+                reception_time_ms,
+                ecn: EcnCodepoint::from_bits((report >> 13) as u8),
+            });
+        }
+    }
+
+    entries
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn encode(format: FeedbackFormat, entries: &[FeedbackPacketInfo], report_time_ms: u64) -> Vec<u8> {
+        let mut buf = Vec::new();
+        encode_into(format, entries, report_time_ms, &mut buf);
+        buf
+    }
+
+    fn entry(seq_number: u32, reception_time_ms: u64, ecn: EcnCodepoint) -> FeedbackPacketInfo {
+        FeedbackPacketInfo {
+            seq_number,
+            reception_time_ms,
+            ecn,
+        }
+    }
+
+    #[test]
+    fn native_roundtrip() {
+        let entries = vec![
+            entry(1, 1_700_000_000_000, EcnCodepoint::Ect1),
+            entry(3, 1_700_000_000_005, EcnCodepoint::Ce),
+        ];
+        let data = encode(FeedbackFormat::Native, &entries, 1_700_000_000_010);
+        assert_eq!(data.len(), 2 * FEEDBACK_ENTRY_LEN);
+        assert_eq!(decode(FeedbackFormat::Native, &data, 3, 0), entries);
+    }
+
+    #[test]
+    fn rfc8888_layout() {
+        let now = 1_700_000_000_000;
+        let entries = vec![
+            entry(10, now - 100, EcnCodepoint::NotEct),
+            entry(12, now, EcnCodepoint::Ce),
+        ];
+        let data = encode(FeedbackFormat::Rfc8888, &entries, now);
+
+        // header + begin/num + 3 reports padded to 4 + report timestamp
+        assert_eq!(data.len(), 12 + 4 + 8 + 4);
+        assert_eq!(data[0], 0x8B);
+        assert_eq!(data[1], 205);
+        assert_eq!(u16::from_be_bytes([data[2], data[3]]) as usize, data.len() / 4 - 1);
+        assert_eq!(u16::from_be_bytes([data[12], data[13]]), 10);
+        assert_eq!(u16::from_be_bytes([data[14], data[15]]), 3);
+        // sn 10: received, not ECT, 100 ms old
+        assert_eq!(u16::from_be_bytes([data[16], data[17]]), 0x8000 | 102);
+        // sn 11: missing
+        assert_eq!(u16::from_be_bytes([data[18], data[19]]), 0);
+        // sn 12: received, CE, no offset
+        assert_eq!(u16::from_be_bytes([data[20], data[21]]), 0xE000);
+    }
+
+    #[test]
+    fn rfc8888_roundtrip_across_wrap() {
+        let now = 1_700_000_000_000;
+        let entries = vec![
+            entry(0x0001_FFFF, now - 50, EcnCodepoint::Ect1),
+            entry(0x0002_0001, now - 10, EcnCodepoint::Ce),
+        ];
+        let data = encode(FeedbackFormat::Rfc8888, &entries, now);
+        let decoded = decode(FeedbackFormat::Rfc8888, &data, 0x0002_0005, 0);
+
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].seq_number, 0x0001_FFFF);
+        assert_eq!(decoded[0].ecn, EcnCodepoint::Ect1);
+        assert_eq!(decoded[1].seq_number, 0x0002_0001);
+        assert_eq!(decoded[1].ecn, EcnCodepoint::Ce);
+        // offsets survive with 1/1024 s resolution
+        let delta = decoded[1].reception_time_ms - decoded[0].reception_time_ms;
+        assert!((39..=41).contains(&delta));
+    }
+
+    #[test]
+    fn serial_number_arithmetic() {
+        assert!(sn_newer(1, 0));
+        assert!(!sn_newer(0, 0));
+        assert!(sn_newer(0, SN_MASK));
+        assert!(!sn_newer(SN_MASK, 0));
+        assert_eq!(sn_distance(2, SN_MASK), 3);
+        assert_eq!(sn_distance(SN_MASK, 2), -3);
+        assert!(sn_newer(DATAGRAM_SN_FLAG, DATAGRAM_SN_FLAG | SN_MASK));
+        assert_eq!(segment_sn(u32::MAX), SN_MASK);
+        assert_eq!(segment_sn(u32::MAX.wrapping_add(1)), 0);
+    }
+
+    #[test]
+    fn rfc8888_roundtrip_across_31_bit_wrap() {
+        let now = 1_700_000_000_000;
+        let entries = vec![
+            entry(SN_MASK - 1, now - 5, EcnCodepoint::Ect1),
+            entry(1, now, EcnCodepoint::Ect1),
+            entry(DATAGRAM_SN_FLAG | SN_MASK, now - 5, EcnCodepoint::NotEct),
+            entry(DATAGRAM_SN_FLAG | 1, now, EcnCodepoint::NotEct),
+        ];
+        let data = encode(FeedbackFormat::Rfc8888, &entries, now);
+        // 4 segment reports and 3 datagram reports padded to 4
+        assert_eq!(data.len(), 8 + 8 + 8 + 8 + 8 + 4);
+        let decoded = decode(FeedbackFormat::Rfc8888, &data, 2, DATAGRAM_SN_FLAG | 2);
+        let sns: Vec<u32> = decoded.iter().map(|e| e.seq_number).collect();
+        assert_eq!(sns, vec![SN_MASK - 1, 1, DATAGRAM_SN_FLAG | SN_MASK, DATAGRAM_SN_FLAG | 1]);
+    }
+
+    #[test]
+    fn rfc8888_rejects_garbage() {
+        assert!(decode(FeedbackFormat::Rfc8888, &[0u8; 8], 0, 0).is_empty());
+        assert!(decode(FeedbackFormat::Rfc8888, &[0xFFu8; 32], 0, 0).is_empty());
+        // Valid header whose length leaves no room for the report timestamp
+        for len_words in 0..3u8 {
+            let mut data = [0u8; 16];
+            data[..4].copy_from_slice(&[0x80 | RFC8888_FMT, RFC8888_PT, 0, len_words]);
+            assert!(decode(FeedbackFormat::Rfc8888, &data, 0, 0).is_empty());
+        }
+
+        // Every truncation and single byte corruption of valid payloads decodes to something or nothing
+        let entries = [
+            entry(7, 1_700_000_000_000, EcnCodepoint::Ce),
+            entry(9 | DATAGRAM_SN_FLAG, 0, EcnCodepoint::Ect0),
+        ];
+        for format in [FeedbackFormat::Native, FeedbackFormat::Rfc8888] {
+            let mut data = encode(format, &entries, 1_700_000_000_010);
+            encode_max_bitrate(format, 1_000_000.0, &mut data);
+            for len in 0..data.len() {
+                decode(format, &data[..len], 9, 9);
+                decode_max_bitrate(format, &data[..len]);
+            }
+            for i in 0..data.len() {
+                let mut corrupted = data.clone();
+                corrupted[i] ^= 0xFF;
+                decode(format, &corrupted, u32::MAX, 0);
+                decode_max_bitrate(format, &corrupted);
+            }
+        }
+    }
+
+    #[test]
+    fn fitting_entries_respect_max_len() {
+        let now = 1_700_000_000_000;
+        let entries: Vec<_> = (0..200).map(|sn| entry(sn * 2, now, EcnCodepoint::NotEct)).collect();
+        for format in [FeedbackFormat::Native, FeedbackFormat::Rfc8888] {
+            let n = fitting_entries(format, &entries, 300);
+            assert!(n > 1 && n < entries.len());
+            assert!(encode(format, &entries[..n], now).len() <= 300);
+            assert!(encode(format, &entries[..n + 1], now).len() > 300);
+
+            assert_eq!(fitting_entries(format, &entries[..3], 300), 3);
+            // A single entry is always sent
+            assert_eq!(fitting_entries(format, &entries, 1), 1);
+            assert_eq!(fitting_entries(format, &[], 300), 0);
+        }
+    }
+
+    #[test]
+    fn max_bitrate_roundtrip() {
+        let now = 1_700_000_000_000;
+        let entries = vec![entry(1, now, EcnCodepoint::Ect1), entry(2, now, EcnCodepoint::Ect1)];
+        for format in [FeedbackFormat::Native, FeedbackFormat::Rfc8888] {
+            let mut data = encode(format, &entries, now);
+            assert_eq!(decode_max_bitrate(format, &data), None);
+
+            let len = data.len();
+            encode_max_bitrate(format, 25_000_000.0, &mut data);
+            assert_eq!(data.len(), len + max_bitrate_len(format));
+            // The entries decode as before
+            assert_eq!(decode(format, &data, 2, 0).len(), 2);
+            let max_bitrate = decode_max_bitrate(format, &data).unwrap();
+            // REMB keeps 18 bits of mantissa
+            assert!((max_bitrate - 25_000_000.0).abs() <= 128.0, "{}", max_bitrate);
+        }
+
+        let mut data = encode(FeedbackFormat::Rfc8888, &entries, now);
+        let len = data.len();
+        encode_max_bitrate(FeedbackFormat::Rfc8888, 1_000_000.0, &mut data);
+        assert_eq!(&data[len..len + 2], &[0x8F, 206]);
+        assert_eq!(&data[len + 12..len + 16], b"REMB");
+        // 2 SSRCs, exponent 2, mantissa 250000
+        assert_eq!(
+            &data[len + 16..len + 20],
+            &((2u32 << 24) | (2 << 18) | 250_000).to_be_bytes()
+        );
+        assert_eq!(decode_max_bitrate(FeedbackFormat::Rfc8888, &data), Some(1_000_000.0));
+    }
+
+    #[test]
+    fn rfc8888_separates_datagrams() {
+        let now = 1_700_000_000_000;
+        let entries = vec![
+            entry(5, now - 20, EcnCodepoint::Ect1),
+            entry(DATAGRAM_SN_FLAG | 0x0001_0000, now - 10, EcnCodepoint::NotEct),
+            entry(6, now, EcnCodepoint::Ce),
+        ];
+        let data = encode(FeedbackFormat::Rfc8888, &entries, now);
+
+        // header + segment block with 2 reports + datagram block with 1 report padded + report timestamp
+        assert_eq!(data.len(), 8 + 8 + 4 + 8 + 4 + 4);
+        let decoded = decode(FeedbackFormat::Rfc8888, &data, 6, DATAGRAM_SN_FLAG | 0x0001_0002);
+        let sns: Vec<u32> = decoded.iter().map(|e| e.seq_number).collect();
+        assert_eq!(sns, vec![5, 6, DATAGRAM_SN_FLAG | 0x0001_0000]);
+        assert_eq!(decoded[1].ecn, EcnCodepoint::Ce);
+    }
+}
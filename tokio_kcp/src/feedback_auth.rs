@@ -0,0 +1,186 @@
+//! Feedback tokens
+//!
+//! SCReAM feedback moves the sender's window, anyone able to send it from the peer's address could
+//! inflate the rate. With `KcpConfig::feedback_auth` every side draws a random token and hands it to
+//! the peer, feedback then has to carry the token of the side it is sent to:
+//!
+//! ```plain
+//! | SCREAM_FEEDBACK_HEADER (4) | token of the receiver (8) | report |
+//! ```
+//!
+//! Tokens are exchanged in `FEEDBACK_TOKEN_HEADER` packets, sent again until the peer echoes ours:
+//!
+//! ```plain
+//! | FEEDBACK_TOKEN_HEADER (4) | own token (8) | peer's token or 0 (8) | own token echoed (1) |
+//! ```
+//!
+//! A peer's token only counts once its announcement echoes ours, before that it is merely echoed
+//! back and feedback for the peer is withheld. Off-path attackers never see a token. Against
+//! attackers on the path only `psk` helps, which seals the feedback like every other datagram.
+
+use std::time::Instant;
+
+use bytes::{Buf, BufMut};
+use rand::Rng;
+
+use crate::utils::now;
+
+/// Token exchange: header, own token, echo of the peer's token, whether ours was echoed already
+pub const FEEDBACK_TOKEN_HEADER: u32 = 0x5C4D4654;
+const FEEDBACK_TOKEN_LEN: usize = 21;
+
+/// Bytes the token adds to every feedback packet
+pub const FEEDBACK_TOKEN_OVERHEAD: usize = 8;
+
+/// Token state of one session
+#[derive(Debug)]
+pub struct FeedbackAuth {
+    token: u64,
+    // Echoed back in our announcements, only trusted once confirmed
+    peer_token: Option<u64>,
+    // The peer echoed our token along with `peer_token`
+    confirmed: bool,
+    last_announcement: Option<Instant>,
+}
+
+impl FeedbackAuth {
+    pub fn new() -> FeedbackAuth {
+        FeedbackAuth {
+            // 0 echoes an unknown token
+            token: rand::thread_rng().gen_range(1..=u64::MAX),
+            peer_token: None,
+            confirmed: false,
+            last_announcement: None,
+        }
+    }
+
+    /// Token to prepend to feedback for the peer, `None` until the peer told it along with ours
+    pub fn peer_token(&self) -> Option<u64> {
+        self.peer_token.filter(|_| self.confirmed)
+    }
+
+    pub fn confirmed(&self) -> bool {
+        self.confirmed
+    }
+
+    /// When the last announcement went out
+    pub fn last_announcement(&self) -> Option<Instant> {
+        self.last_announcement
+    }
+
+    /// Announcement of our token, echoing the peer's
+    pub fn announcement(&mut self) -> Vec<u8> {
+        self.last_announcement = Some(now());
+
+        let mut packet = Vec::with_capacity(FEEDBACK_TOKEN_LEN);
+        packet.put_u32_le(FEEDBACK_TOKEN_HEADER);
+        packet.put_u64_le(self.token);
+        packet.put_u64_le(self.peer_token.unwrap_or(0));
+        packet.put_u8(self.confirmed as u8);
+        packet
+    }
+
+    /// Take the peer's announcement, `true` if the peer still waits for the echo of its token
+    pub fn on_announcement(&mut self, packet: &[u8]) -> bool {
+        if packet.len() < FEEDBACK_TOKEN_LEN {
+            return false;
+        }
+        let mut packet = &packet[4..];
+        let token = packet.get_u64_le();
+        let echo = packet.get_u64_le();
+        let peer_confirmed = packet.get_u8() != 0;
+
+        if token == 0 || self.confirmed {
+            return !peer_confirmed;
+        }
+        // Only the peer knows our token, its announcement replaces a spoofed one we echoed so far. The
+        // first confirmed token sticks
+        if echo == self.token {
+            self.peer_token = Some(token);
+            self.confirmed = true;
+        } else if self.peer_token.is_none() {
+            self.peer_token = Some(token);
+        }
+        !peer_confirmed
+    }
+
+    /// Report of the received feedback (without its header), `None` if it doesn't carry our token
+    pub fn verify<'a>(&self, feedback: &'a [u8]) -> Option<&'a [u8]> {
+        if feedback.len() < FEEDBACK_TOKEN_OVERHEAD || (&feedback[..FEEDBACK_TOKEN_OVERHEAD]).get_u64_le() != self.token
+        {
+            return None;
+        }
+        Some(&feedback[FEEDBACK_TOKEN_OVERHEAD..])
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn with_token(token: u64, report: &[u8]) -> Vec<u8> {
+        let mut feedback = token.to_le_bytes().to_vec();
+        feedback.extend_from_slice(report);
+        feedback
+    }
+
+    #[test]
+    fn token_exchange() {
+        let (mut a, mut b) = (FeedbackAuth::new(), FeedbackAuth::new());
+        assert!(a.peer_token().is_none());
+
+        // A announces, B echoes the token along with its own but doesn't trust it yet
+        let announcement = a.announcement();
+        assert!(b.on_announcement(&announcement));
+        assert_eq!(b.peer_token(), None);
+        assert!(!b.confirmed());
+
+        // A sees its token echoed, B still waits for its echo
+        let answer = b.announcement();
+        assert!(a.on_announcement(&answer));
+        assert!(a.confirmed());
+        assert_eq!(a.peer_token(), Some(b.token));
+
+        // A answers once more, B is done and doesn't answer again
+        let answer = a.announcement();
+        assert!(!b.on_announcement(&answer));
+        assert!(b.confirmed());
+        assert_eq!(b.peer_token(), Some(a.token));
+
+        // A spoofed announcement changes nothing
+        let mut spoofed = FeedbackAuth::new().announcement();
+        spoofed[12..20].copy_from_slice(&b.token.to_le_bytes());
+        b.on_announcement(&spoofed);
+        assert_eq!(b.peer_token(), Some(a.token));
+    }
+
+    #[test]
+    fn spoofed_token_replaced() {
+        let (mut a, mut b) = (FeedbackAuth::new(), FeedbackAuth::new());
+
+        // A stranger announces first, B echoes its token
+        b.on_announcement(&FeedbackAuth::new().announcement());
+        assert_eq!(b.peer_token(), None);
+        let answer = b.announcement();
+        assert_ne!((&answer[12..20]).get_u64_le(), 0);
+
+        // A only learns B's token from that, its next announcement echoes it and wins
+        a.on_announcement(&answer);
+        assert!(!a.confirmed());
+        assert!(b.on_announcement(&a.announcement()));
+        assert!(b.confirmed());
+        assert_eq!(b.peer_token(), Some(a.token));
+        a.on_announcement(&b.announcement());
+        assert_eq!(a.peer_token(), Some(b.token));
+    }
+
+    #[test]
+    fn feedback_verified() {
+        let auth = FeedbackAuth::new();
+        let feedback = with_token(auth.token, b"REPORT");
+        assert_eq!(auth.verify(&feedback), Some(&b"REPORT"[..]));
+
+        assert!(auth.verify(&with_token(auth.token ^ 1, b"REPORT")).is_none());
+        assert!(auth.verify(b"REPORT").is_none());
+    }
+}
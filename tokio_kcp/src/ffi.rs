@@ -0,0 +1,328 @@
+//! C bindings
+//!
+//! Opaque handle API over a `KcpStream` for engines written in C or C++, declared in
+//! `include/kcp_scream.h`. Every handle owns a Tokio runtime driving its session, the calls block the
+//! calling thread. `kcp_scream_send` and `kcp_scream_recv` may run concurrently on two threads.
+//!
+//! Failures return NULL or a negative value and are logged with `tracing`. The shared library is only
+//! built on request, `cargo rustc -p tokio_kcp --release --features ffi --crate-type cdylib`.
+
+use std::{
+    ffi::{c_char, c_void, CStr},
+    ptr, slice,
+    sync::Mutex,
+    time::Duration,
+};
+
+use futures_util::future;
+use tokio::{
+    runtime::{Builder, Runtime},
+    sync::watch,
+    task::JoinHandle,
+};
+use tracing::error;
+
+use crate::{
+    config::KcpConfig,
+    split::{KcpReadHalf, KcpWriteHalf},
+    stream::{self, KcpStream},
+    KcpResult,
+};
+
+/// Called with the user data and the new target bitrate in bits per second
+pub type KcpScreamBitrateCallback = extern "C" fn(user_data: *mut c_void, target_bitrate: f32);
+
+// The user data is handed back to C untouched, synchronizing it is up to the caller
+struct UserData(*mut c_void);
+
+unsafe impl Send for UserData {}
+
+/// Connected stream, created by `kcp_scream_connect` and freed by `kcp_scream_close`
+pub struct KcpScreamHandle {
+    runtime: Runtime,
+    read: Mutex<KcpReadHalf>,
+    write: Mutex<KcpWriteHalf>,
+    target_bitrate_rx: watch::Receiver<f32>,
+    bitrate_callback: Mutex<Option<JoinHandle<()>>>,
+}
+
+fn connect(addr: &str, config: Option<&str>, timeout: Duration) -> KcpResult<KcpScreamHandle> {
+    let config = match config {
+        Some(config) => KcpConfig::from_json_str(config)?,
+        None => KcpConfig::default(),
+    };
+    config.validate()?;
+
+    let runtime = Builder::new_multi_thread().worker_threads(1).enable_all().build()?;
+    let stream = runtime.block_on(async {
+        if timeout.is_zero() {
+            KcpStream::connect(&config, addr).await
+        } else {
+            KcpStream::connect_timeout(&config, addr, timeout).await
+        }
+    })?;
+    let target_bitrate_rx = stream.get_target_bitrate_receiver();
+    let (read, write) = stream.into_split();
+
+    Ok(KcpScreamHandle {
+        runtime,
+        read: Mutex::new(read),
+        write: Mutex::new(write),
+        target_bitrate_rx,
+        bitrate_callback: Mutex::new(None),
+    })
+}
+
+/// Connect to `addr` (`"host:port"`), NULL on failure
+///
+/// `config_json` is a `KcpConfig` in JSON or NULL for the default. With a `timeout_ms` the call waits for the
+/// handshake, 0 returns at once.
+///
+/// # Safety
+///
+/// `addr` and a non-NULL `config_json` must be valid NUL-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_scream_connect(
+    addr: *const c_char,
+    config_json: *const c_char,
+    timeout_ms: u32,
+) -> *mut KcpScreamHandle {
+    if addr.is_null() {
+        return ptr::null_mut();
+    }
+    let addr = CStr::from_ptr(addr).to_string_lossy();
+    let config = if config_json.is_null() {
+        None
+    } else {
+        Some(CStr::from_ptr(config_json).to_string_lossy())
+    };
+
+    match connect(&addr, config.as_deref(), Duration::from_millis(timeout_ms as u64)) {
+        Ok(handle) => Box::into_raw(Box::new(handle)),
+        Err(err) => {
+            error!("[FFI] connect {} failed, error: {}", addr, err);
+            ptr::null_mut()
+        }
+    }
+}
+
+/// Send `len` bytes of `data`, the number of bytes queued or -1
+///
+/// In message mode (`"stream_mode": "message"`) one call is one message.
+///
+/// # Safety
+///
+/// `handle` must come from `kcp_scream_connect`, `data` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_scream_send(handle: *mut KcpScreamHandle, data: *const u8, len: usize) -> isize {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return -1,
+    };
+    let data = if len == 0 {
+        &[][..]
+    } else {
+        slice::from_raw_parts(data, len)
+    };
+
+    let mut write = handle.write.lock().unwrap();
+    match handle.runtime.block_on(write.send(data)) {
+        Ok(n) => n as isize,
+        Err(err) => {
+            error!("[FFI] send failed, error: {}", err);
+            -1
+        }
+    }
+}
+
+/// Receive into `buf` of `len` bytes, the number of bytes received or -1
+///
+/// # Safety
+///
+/// `handle` must come from `kcp_scream_connect`, `buf` must point to `len` writable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_scream_recv(handle: *mut KcpScreamHandle, buf: *mut u8, len: usize) -> isize {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return -1,
+    };
+    let buf = if len == 0 {
+        &mut [][..]
+    } else {
+        slice::from_raw_parts_mut(buf, len)
+    };
+
+    let mut read = handle.read.lock().unwrap();
+    match handle.runtime.block_on(read.recv(buf)) {
+        Ok(n) => n as isize,
+        Err(err) => {
+            error!("[FFI] recv failed, error: {}", err);
+            -1
+        }
+    }
+}
+
+/// Current SCReAM target bitrate in bits per second, 0 for a NULL handle
+///
+/// # Safety
+///
+/// `handle` must come from `kcp_scream_connect`.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_scream_get_target_bitrate(handle: *const KcpScreamHandle) -> f32 {
+    match handle.as_ref() {
+        Some(handle) => *handle.target_bitrate_rx.borrow(),
+        None => 0.0,
+    }
+}
+
+/// Call `callback` with `user_data` on every target bitrate change, NULL removes it
+///
+/// The callback runs on the runtime thread of the handle and must not call back into this API.
+///
+/// # Safety
+///
+/// `handle` must come from `kcp_scream_connect`, `user_data` must stay valid until the callback is replaced
+/// or the handle closed.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_scream_set_bitrate_callback(
+    handle: *mut KcpScreamHandle,
+    callback: Option<KcpScreamBitrateCallback>,
+    user_data: *mut c_void,
+) {
+    let handle = match handle.as_ref() {
+        Some(handle) => handle,
+        None => return,
+    };
+
+    let mut current = handle.bitrate_callback.lock().unwrap();
+    if let Some(task) = current.take() {
+        task.abort();
+    }
+    if let Some(callback) = callback {
+        let mut target_bitrate_rx = handle.target_bitrate_rx.clone();
+        let user_data = UserData(user_data);
+        *current = Some(handle.runtime.spawn(async move {
+            let user_data = user_data;
+            while target_bitrate_rx.changed().await.is_ok() {
+                let target_bitrate = *target_bitrate_rx.borrow_and_update();
+                callback(user_data.0, target_bitrate);
+            }
+        }));
+    }
+}
+
+/// Flush and close the stream, then free `handle`
+///
+/// # Safety
+///
+/// `handle` must come from `kcp_scream_connect` and must not be used afterwards.
+#[no_mangle]
+pub unsafe extern "C" fn kcp_scream_close(handle: *mut KcpScreamHandle) {
+    if handle.is_null() {
+        return;
+    }
+    let handle = Box::from_raw(handle);
+    let KcpScreamHandle {
+        runtime,
+        read,
+        write,
+        bitrate_callback,
+        ..
+    } = *handle;
+
+    if let Some(task) = bitrate_callback.into_inner().unwrap() {
+        task.abort();
+    }
+    let write = write.into_inner().unwrap();
+    if let Err(err) = runtime.block_on(future::poll_fn(|cx| stream::poll_shutdown(write.session(), cx))) {
+        error!("[FFI] close failed, error: {}", err);
+    }
+
+    let _guard = runtime.enter();
+    drop(read);
+    drop(write);
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        ffi::CString,
+        sync::atomic::{AtomicU32, Ordering},
+        thread,
+    };
+
+    use super::*;
+    use crate::KcpListener;
+
+    static BITRATE_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    extern "C" fn on_bitrate(user_data: *mut c_void, target_bitrate: f32) {
+        assert_eq!(user_data as usize, 42);
+        assert!(target_bitrate > 0.0);
+        BITRATE_CALLS.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[test]
+    fn ffi_echo() {
+        let _ = env_logger::try_init();
+
+        let runtime = Runtime::new().unwrap();
+        let mut listener = runtime
+            .block_on(KcpListener::bind(KcpConfig::default(), "127.0.0.1:0"))
+            .unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        runtime.spawn(async move {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buf = vec![0u8; 65536];
+            loop {
+                let n = stream.read(&mut buf).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                stream.write_all(&buf[..n]).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let addr = CString::new(server_addr.to_string()).unwrap();
+        let config = CString::new(r#"{"stream_mode": "byte_stream"}"#).unwrap();
+        unsafe {
+            assert!(kcp_scream_connect(ptr::null(), ptr::null(), 0).is_null());
+            let bad = CString::new(r#"{"mtu": 1}"#).unwrap();
+            assert!(kcp_scream_connect(addr.as_ptr(), bad.as_ptr(), 0).is_null());
+
+            let handle = kcp_scream_connect(addr.as_ptr(), config.as_ptr(), 5000);
+            assert!(!handle.is_null());
+            assert!(kcp_scream_get_target_bitrate(handle) > 0.0);
+            kcp_scream_set_bitrate_callback(handle, Some(on_bitrate), 42 as *mut c_void);
+
+            let sender = handle as usize;
+            let payload = vec![7u8; 100_000];
+            let expected = payload.clone();
+            let send = thread::spawn(move || {
+                let handle = sender as *mut KcpScreamHandle;
+                let mut sent = 0;
+                while sent < payload.len() {
+                    let n = kcp_scream_send(handle, payload[sent..].as_ptr(), payload.len() - sent);
+                    assert!(n > 0);
+                    sent += n as usize;
+                }
+            });
+
+            let mut received = Vec::new();
+            let mut buf = [0u8; 4096];
+            while received.len() < expected.len() {
+                let n = kcp_scream_recv(handle, buf.as_mut_ptr(), buf.len());
+                assert!(n > 0);
+                received.extend_from_slice(&buf[..n as usize]);
+            }
+            send.join().unwrap();
+            assert_eq!(received, expected);
+            assert!(BITRATE_CALLS.load(Ordering::Relaxed) > 0);
+
+            kcp_scream_close(handle);
+        }
+    }
+}
@@ -0,0 +1,150 @@
+//! `Stream` and `Sink` of whole messages over a `KcpStream`
+//!
+//! Every item is one KCP message, so the peer's items arrive with their boundaries and no codec is needed.
+//! Byte codecs like `LengthDelimitedCodec` work on `KcpStream` directly through `AsyncRead`/`AsyncWrite`.
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::{ready, Sink, Stream};
+use kcp::{Error as KcpError, KcpResult};
+
+use crate::stream::{self, KcpStream};
+
+/// A `KcpStream` as `Stream<Item = KcpResult<Bytes>>` and `Sink<Bytes>`, created by `KcpStream::into_framed`
+///
+/// The stream ends when the peer closed the session, closing the sink sends a FIN. Empty messages are
+/// empty items.
+#[derive(Debug)]
+pub struct KcpFramed {
+    stream: KcpStream,
+    // Accepted by `start_send`, not yet in KCP's send queue
+    pending: Option<Bytes>,
+}
+
+impl KcpFramed {
+    pub(crate) fn new(stream: KcpStream) -> KcpFramed {
+        KcpFramed { stream, pending: None }
+    }
+
+    /// The underlying stream
+    pub fn get_ref(&self) -> &KcpStream {
+        &self.stream
+    }
+
+    /// The underlying stream, a message taken by `start_send` may still wait to be sent
+    pub fn get_mut(&mut self) -> &mut KcpStream {
+        &mut self.stream
+    }
+
+    /// The underlying stream, flush first or a message taken by `start_send` is lost
+    pub fn into_inner(self) -> KcpStream {
+        self.stream
+    }
+
+    fn poll_send_pending(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        if let Some(msg) = &self.pending {
+            ready!(self.stream.poll_send_msg(cx, msg))?;
+            self.pending = None;
+        }
+        Ok(()).into()
+    }
+}
+
+impl Stream for KcpFramed {
+    type Item = KcpResult<Bytes>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.stream.poll_next_msg(cx).map(Result::transpose)
+    }
+}
+
+impl Sink<Bytes> for KcpFramed {
+    type Error = KcpError;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        self.poll_send_pending(cx)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Bytes) -> KcpResult<()> {
+        debug_assert!(self.pending.is_none(), "start_send without poll_ready");
+        self.pending = Some(item);
+        Ok(())
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        ready!(self.poll_send_pending(cx))?;
+        stream::poll_flush(&self.stream.session, cx)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        ready!(self.poll_send_pending(cx))?;
+        stream::poll_shutdown(&self.stream.session, cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::{stream, SinkExt, StreamExt};
+
+    use super::*;
+    use crate::{KcpConfig, KcpListener};
+
+    #[tokio::test]
+    async fn framed_keeps_messages() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut client = client.into_framed();
+        let messages = vec![Bytes::from(vec![1u8; 4000]), Bytes::from_static(b"HELLO"), Bytes::from(vec![2u8; 100])];
+        client
+            .send_all(&mut stream::iter(messages.clone().into_iter().map(Ok)))
+            .await
+            .unwrap();
+
+        let (server, _) = listener.accept().await.unwrap();
+        let (mut sink, mut source) = server.into_framed().split();
+        for message in &messages {
+            assert_eq!(source.next().await.unwrap().unwrap(), message);
+        }
+
+        // Echo back through the split sink
+        sink.send(Bytes::from_static(b"WORLD")).await.unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), &b"WORLD"[..]);
+
+        // Closing sends the FIN, the peer's stream ends
+        client.close().await.unwrap();
+        assert!(source.next().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn framed_empty_message_is_no_eof() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            allow_recv_empty_packet: true,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap().into_framed();
+        client.send(Bytes::new()).await.unwrap();
+        client.send(Bytes::from_static(b"AFTER")).await.unwrap();
+
+        let (server, _) = listener.accept().await.unwrap();
+        let mut source = server.into_framed();
+        assert_eq!(source.next().await.unwrap().unwrap(), Bytes::new());
+        assert_eq!(source.next().await.unwrap().unwrap(), &b"AFTER"[..]);
+
+        client.close().await.unwrap();
+        assert!(source.next().await.is_none());
+    }
+}
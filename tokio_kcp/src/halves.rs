@@ -0,0 +1,411 @@
+//! Send and receive halves of a `KcpSocket`
+//!
+//! The socket's mutex serializes everything touching KCP: input, updates, reads and writes. The halves
+//! keep what readers and writers need behind locks of their own, so they rarely wait for the socket:
+//!
+//! - `RecvHalf` holds the messages KCP completed. The socket moves them over after input, readers take
+//!   them without locking the socket. Until read they keep their segments in the receive window.
+//! - `SendHalf` stages writes while the socket is locked by someone else, within the send window the
+//!   socket last published. The socket moves them into KCP on its next update, ahead of any later write.
+
+use std::{
+    collections::VecDeque,
+    io::IoSlice,
+    sync::Arc,
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
+};
+
+use bytes::BytesMut;
+use kcp::{Error as KcpError, KcpResult, KCP_MAX_FRAGMENTS};
+use spin::Mutex as SpinMutex;
+
+use crate::{pacer::PacerQueue, skcp, utils::now};
+
+/// Why reading ended before the peer's FIN, in the order `KcpSocket` reports them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RecvEnd {
+    TimedOut,
+    Refused,
+    Unreachable,
+    StagedWriteFailed,
+    Closed,
+}
+
+impl RecvEnd {
+    fn result(self) -> KcpResult<usize> {
+        match self {
+            RecvEnd::TimedOut => Err(skcp::idle_timeout_error()),
+            RecvEnd::Refused => Err(skcp::refused_error()),
+            RecvEnd::Unreachable => Err(KcpError::PeerUnreachable),
+            RecvEnd::StagedWriteFailed => Err(skcp::staged_write_error()),
+            RecvEnd::Closed => Ok(0),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct RecvState {
+    // Complete messages with the segments they took
+    messages: VecDeque<(BytesMut, usize)>,
+    segments: usize,
+    bytes: usize,
+    // The peer's FIN arrived and everything before it was moved here
+    fin: bool,
+    end: Option<RecvEnd>,
+    // The socket left messages in KCP or closed the window, a read has to wake it
+    stalled: bool,
+    wake_socket: bool,
+    last_read: Option<Instant>,
+    pending_receiver: Option<Waker>,
+}
+
+impl RecvState {
+    // `None` if there is nothing to read yet
+    fn recv(&mut self, buf: &mut [u8]) -> Option<KcpResult<usize>> {
+        if let Some(end) = self.end {
+            return Some(end.result());
+        }
+        match self.messages.front() {
+            Some((message, _)) if message.len() > buf.len() => Some(Err(KcpError::UserBufTooSmall)),
+            Some(_) => {
+                let message = self.pop();
+                buf[..message.len()].copy_from_slice(&message);
+                Some(Ok(message.len()))
+            }
+            None if self.fin => Some(Ok(0)),
+            None => None,
+        }
+    }
+
+    fn pop(&mut self) -> BytesMut {
+        let (message, segments) = self.messages.pop_front().unwrap();
+        self.segments -= segments;
+        self.bytes -= message.len();
+        self.last_read = Some(now());
+        if self.stalled {
+            self.stalled = false;
+            self.wake_socket = true;
+        }
+        message
+    }
+
+    fn wait(&mut self, cx: &mut Context<'_>) {
+        if let Some(waker) = self.pending_receiver.replace(cx.waker().clone()) {
+            if !cx.waker().will_wake(&waker) {
+                waker.wake();
+            }
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.pending_receiver.take() {
+            waker.wake();
+        }
+    }
+}
+
+/// Messages ready for the reader
+#[derive(Debug)]
+pub struct RecvHalf {
+    // Receiving goes through the socket's datagram queue instead
+    passthrough: bool,
+    state: SpinMutex<RecvState>,
+}
+
+impl RecvHalf {
+    pub fn new(passthrough: bool) -> RecvHalf {
+        RecvHalf {
+            passthrough,
+            state: SpinMutex::new(RecvState::default()),
+        }
+    }
+
+    pub fn passthrough(&self) -> bool {
+        self.passthrough
+    }
+
+    /// Segments and bytes of the messages not read yet
+    pub fn held(&self) -> (usize, usize) {
+        let state = self.state.lock();
+        (state.segments, state.bytes)
+    }
+
+    pub fn last_read(&self) -> Option<Instant> {
+        self.state.lock().last_read
+    }
+
+    /// Append `messages` read from KCP, wake the reader if there is anything new for it
+    pub fn deliver(&self, messages: Vec<(BytesMut, usize)>, fin: bool, stalled: bool) -> bool {
+        let mut state = self.state.lock();
+        let woken = !messages.is_empty() || (fin && !state.fin);
+        for (message, segments) in messages {
+            state.segments += segments;
+            state.bytes += message.len();
+            state.messages.push_back((message, segments));
+        }
+        state.fin = fin;
+        state.stalled = stalled;
+        if woken && state.pending_receiver.is_some() {
+            state.wake();
+            return true;
+        }
+        false
+    }
+
+    pub fn set_end(&self, end: Option<RecvEnd>) {
+        let mut state = self.state.lock();
+        if state.end != end {
+            state.end = end;
+            state.wake();
+        }
+    }
+
+    /// A read freed room the socket has to give back to the peer
+    pub fn take_wake_socket(&self) -> bool {
+        std::mem::take(&mut self.state.lock().wake_socket)
+    }
+
+    pub fn peek_size(&self) -> KcpResult<usize> {
+        let state = self.state.lock();
+        state.messages.front().map(|(message, _)| message.len()).ok_or(KcpError::RecvQueueEmpty)
+    }
+
+    /// See `KcpSocket::try_recv`
+    pub fn try_recv(&self, buf: &mut [u8]) -> KcpResult<usize> {
+        let mut state = self.state.lock();
+        state.recv(buf).unwrap_or(Err(KcpError::RecvQueueEmpty))
+    }
+
+    /// See `KcpSocket::poll_recv`
+    pub fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        let mut state = self.state.lock();
+        match state.recv(buf) {
+            Some(result) => result.into(),
+            None => {
+                state.wait(cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// See `KcpSocket::poll_recv_msg`
+    pub fn poll_recv_msg(&self, cx: &mut Context<'_>) -> Poll<KcpResult<Option<Vec<u8>>>> {
+        let mut state = self.state.lock();
+        if let Some(end) = state.end {
+            return end.result().map(|_| None).into();
+        }
+        match state.messages.front() {
+            Some(_) => Ok(Some(state.pop().to_vec())).into(),
+            None if state.fin => Ok(None).into(),
+            None => {
+                state.wait(cx);
+                Poll::Pending
+            }
+        }
+    }
+
+    /// See `KcpSocket::poll_peek`
+    pub fn poll_peek(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        let mut state = self.state.lock();
+        if let Some(end) = state.end {
+            return end.result().into();
+        }
+        match state.messages.front() {
+            Some((message, _)) => {
+                let n = message.len().min(buf.len());
+                buf[..n].copy_from_slice(&message[..n]);
+                Ok(n).into()
+            }
+            None if state.fin => Ok(0).into(),
+            None => {
+                state.wait(cx);
+                Poll::Pending
+            }
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+struct SendState {
+    // Writers may stage: the handshake is done and nothing ended the session
+    open: bool,
+    // Segments KCP takes before the send window is full, less what is staged
+    credit: usize,
+    mss: usize,
+    staged: Vec<(BytesMut, Option<Instant>)>,
+    staged_segments: usize,
+    pending_sender: Option<Waker>,
+}
+
+/// Writes waiting for the socket
+#[derive(Debug)]
+pub struct SendHalf {
+    pacer_queue: Arc<PacerQueue>,
+    state: SpinMutex<SendState>,
+}
+
+impl SendHalf {
+    pub(crate) fn new(pacer_queue: Arc<PacerQueue>) -> SendHalf {
+        SendHalf {
+            pacer_queue,
+            state: SpinMutex::new(SendState::default()),
+        }
+    }
+
+    /// Whether writers may stage and how many segments the send window has room for, wakes a waiting writer
+    /// if there is any
+    pub fn publish(&self, open: bool, room: usize, mss: usize) -> bool {
+        let mut state = self.state.lock();
+        state.open = open;
+        state.credit = room.saturating_sub(state.staged_segments);
+        state.mss = mss;
+        if state.credit > 0 && state.pending_sender.is_some() {
+            state.wake();
+            return true;
+        }
+        false
+    }
+
+    /// Writes staged since the last call, oldest first, with their deadlines
+    pub fn take_staged(&self) -> Vec<(BytesMut, Option<Instant>)> {
+        let mut state = self.state.lock();
+        state.staged_segments = 0;
+        std::mem::take(&mut state.staged)
+    }
+
+    /// Put writes the socket didn't take back in front of the staged ones
+    pub fn restage(&self, mut messages: Vec<(BytesMut, Option<Instant>)>) {
+        let mut state = self.state.lock();
+        let mss = state.mss.max(1);
+        state.staged_segments += messages.iter().map(|(message, _)| message.len().div_ceil(mss).max(1)).sum::<usize>();
+        messages.append(&mut state.staged);
+        state.staged = messages;
+    }
+
+    /// Wake the writer once the socket has room, see `KcpSocket::poll_send`
+    pub fn wait(&self, cx: &mut Context<'_>) {
+        self.state.lock().wait(cx);
+    }
+
+    pub fn wake(&self) {
+        self.state.lock().wake();
+    }
+
+    #[cfg(test)]
+    fn has_waiter(&self) -> bool {
+        self.state.lock().pending_sender.is_some()
+    }
+
+    /// Stage `bufs` as one message, `None` if the write has to go through the socket
+    pub fn poll_stage(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+        deadline: Option<Duration>,
+    ) -> Option<Poll<KcpResult<usize>>> {
+        let mut state = self.state.lock();
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        // KCP refuses messages this long, the socket reports it
+        let segments = len.div_ceil(state.mss.max(1)).max(1);
+        // Stream mode refuses deadlines, the socket reports it
+        if !state.open || segments > KCP_MAX_FRAGMENTS || deadline.is_some() {
+            return None;
+        }
+
+        if state.credit == 0 {
+            state.wait(cx);
+            return Some(Poll::Pending);
+        }
+        if self.pacer_queue.poll_writable(cx).is_pending() {
+            return Some(Poll::Pending);
+        }
+
+        let mut message = BytesMut::with_capacity(len);
+        for buf in bufs {
+            message.extend_from_slice(buf);
+        }
+        // Like a window with any room left, one message may overshoot it
+        state.credit = state.credit.saturating_sub(segments);
+        state.staged_segments += segments;
+        state.staged.push((message, deadline.map(|deadline| now() + deadline)));
+        Some(Ok(len).into())
+    }
+}
+
+impl SendState {
+    fn wait(&mut self, cx: &mut Context<'_>) {
+        if let Some(waker) = self.pending_sender.replace(cx.waker().clone()) {
+            if !cx.waker().will_wake(&waker) {
+                waker.wake();
+            }
+        }
+    }
+
+    fn wake(&mut self) {
+        if let Some(waker) = self.pending_sender.take() {
+            waker.wake();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::task::Waker;
+
+    use super::*;
+    use crate::pacer::PacerQueuePolicy;
+
+    #[test]
+    fn recv_half_messages() {
+        let half = RecvHalf::new(false);
+        let mut cx = Context::from_waker(Waker::noop());
+        let mut buf = [0u8; 8];
+        assert!(half.poll_recv(&mut cx, &mut buf).is_pending());
+
+        half.deliver(vec![(BytesMut::from(&b"HELLO"[..]), 1), (BytesMut::from(&b"LONG MESSAGE"[..]), 2)], false, true);
+        assert_eq!(half.held(), (3, 17));
+        assert!(matches!(half.poll_peek(&mut cx, &mut buf[..2]), Poll::Ready(Ok(2))));
+        assert!(matches!(half.poll_recv(&mut cx, &mut buf), Poll::Ready(Ok(5))));
+        assert_eq!(&buf[..5], b"HELLO");
+        // The first read after the socket stalled wakes it once
+        assert!(half.take_wake_socket());
+        assert!(!half.take_wake_socket());
+
+        assert!(matches!(half.poll_recv(&mut cx, &mut buf), Poll::Ready(Err(KcpError::UserBufTooSmall))));
+        assert_eq!(half.peek_size().unwrap(), 12);
+        half.deliver(Vec::new(), true, false);
+        assert!(matches!(half.poll_recv_msg(&mut cx), Poll::Ready(Ok(Some(ref m))) if m == b"LONG MESSAGE"));
+        assert_eq!(half.held(), (0, 0));
+        // EOF after the FIN, unless the session ended otherwise
+        assert!(matches!(half.poll_recv(&mut cx, &mut buf), Poll::Ready(Ok(0))));
+        half.set_end(Some(RecvEnd::Unreachable));
+        assert!(matches!(half.poll_recv(&mut cx, &mut buf), Poll::Ready(Err(KcpError::PeerUnreachable))));
+    }
+
+    #[test]
+    fn send_half_credit() {
+        let half = SendHalf::new(Arc::new(PacerQueue::new(64, PacerQueuePolicy::Block, 1400)));
+        let mut cx = Context::from_waker(Waker::noop());
+        let bufs = [IoSlice::new(&[0u8; 2000])];
+
+        // Closed until the socket opens it
+        assert!(half.poll_stage(&mut cx, &bufs, None).is_none());
+        half.publish(true, 3, 1000);
+        assert!(matches!(half.poll_stage(&mut cx, &bufs, None), Some(Poll::Ready(Ok(2000)))));
+        assert!(matches!(half.poll_stage(&mut cx, &bufs, None), Some(Poll::Ready(Ok(2000)))));
+        assert!(half.poll_stage(&mut cx, &bufs, None).unwrap().is_pending());
+        assert!(half.has_waiter());
+
+        // Room published before the socket took the staged writes excludes them
+        half.publish(true, 4, 1000);
+        assert!(half.poll_stage(&mut cx, &bufs, None).unwrap().is_pending());
+        assert_eq!(half.take_staged().len(), 2);
+        half.publish(true, 4, 1000);
+        assert!(matches!(half.poll_stage(&mut cx, &bufs, None), Some(Poll::Ready(Ok(2000)))));
+
+        // Too long for one KCP message
+        let long = vec![0u8; 1000 * (KCP_MAX_FRAGMENTS + 1)];
+        assert!(half.poll_stage(&mut cx, &[IoSlice::new(&long)], None).is_none());
+    }
+}
@@ -1,17 +1,86 @@
 //! Library of KCP on Tokio
 
 pub use self::{
-    config::{KcpConfig, KcpNoDelayConfig},
+    config::{
+        DelayedAckConfig, DuplicateConfig, KcpConfig, KcpConfigBuilder, KcpNoDelayConfig, KcpRuntimeConfig, StreamMode,
+    },
+    congestion::{CongestionController, CongestionState, CongestionStats},
+    conv::{ConvAllocator, FnConvAllocator, RandomConvAllocator, SequentialConvAllocator},
+    crypto::PreSharedKey,
+    ecn::EcnCodepoint,
+    event::{CongestionEvent, KcpEvent},
+    fec::FecConfig,
+    framed::KcpFramed,
+    feedback::{sn_newer, FeedbackFormat, FeedbackPolicy},
     listener::KcpListener,
+    metrics::{CsvSink, MetricsSample, MetricsSink, NoopSink, RingBufferSink},
+    multipath::{MultipathTransport, PathScheduler, PathStats},
+    mux::{KcpMuxer, MuxRole, MuxStream},
+    obfuscation::{Obfuscator, SalsaObfuscator},
+    pacer::{PacerQueuePolicy, PacerState, SharedPacer},
+    qlog::QlogWriter,
+    rtp::RtpAdapter,
+    scream::{ScreamCongestionControl, ScreamConfig},
+    sim::{EmulatedTransport, SimLinkConfig, SimNetwork, SimSocket},
+    sockopt::DSCP_EF,
+    split::{KcpReadHalf, KcpWriteHalf, ReadHalf, WriteHalf},
+    stats::{KcpStats, ThroughputSample},
     stream::KcpStream,
+    transport::DatagramTransport,
 };
+#[cfg(feature = "metrics")]
+pub use self::registry::MetricsRegistry;
+#[cfg(feature = "debug-server")]
+pub use self::debug_server::{DebugServer, DebugSession};
+#[cfg(feature = "tower")]
+pub use self::connector::KcpConnector;
+#[cfg(feature = "hyper")]
+pub use self::connector::KcpHyperConnector;
+pub use kcp::{Error as KcpError, KcpResult};
 
 
+mod batch;
+#[cfg(feature = "bench")]
+#[doc(hidden)]
+pub mod bench;
 mod config;
+mod congestion;
+#[cfg(feature = "tower")]
+mod connector;
+mod conv;
+mod crypto;
+#[cfg(feature = "debug-server")]
+mod debug_server;
+mod driver;
+mod ecn;
+mod event;
+mod fec;
+#[cfg(feature = "ffi")]
+pub mod ffi;
+mod feedback;
+mod feedback_auth;
+mod framed;
+mod halves;
 mod listener;
+mod metrics;
 mod session;
 mod skcp;
+mod sockopt;
+mod split;
+mod stats;
 mod stream;
 mod utils;
 mod scream;
 mod pacer;
+mod pmtud;
+mod pool;
+mod qlog;
+mod rtp;
+#[cfg(feature = "metrics")]
+mod registry;
+mod mux;
+mod obfuscation;
+mod packet;
+mod multipath;
+mod transport;
+mod sim;
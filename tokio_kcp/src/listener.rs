@@ -1,30 +1,60 @@
 use std::{
-    io::{self, ErrorKind},
+    fmt::{self, Debug},
+    io,
     net::SocketAddr,
+    pin::Pin,
     sync::Arc,
     task::{Context, Poll},
-    time::Duration,
-    time::Instant,
+    time::{Duration, Instant},
 };
 
 use byte_string::ByteStr;
-use bytes::Buf;
+use bytes::BytesMut;
+use futures_util::{task::noop_waker_ref, Stream};
 use kcp::{Error as KcpError, KcpResult};
-use log::{debug, error, trace};
+use spin::Mutex as SpinMutex;
 use tokio::{
     net::{ToSocketAddrs, UdpSocket},
     sync::mpsc,
     task::JoinHandle,
     time::{self},
 };
+use tracing::{debug, error, trace};
+
+use crate::{
+    batch::RecvBatch,
+    config::{ConfigHook, KcpConfig},
+    congestion::{CongestionController, CongestionControllerFactory},
+    crypto::{self, PacketCipher},
+    ecn,
+    fec,
+    multipath,
+    obfuscation::Obfuscator,
+    packet::PacketType,
+    pmtud,
+    session::KcpSessionManager,
+    skcp,
+    sockopt,
+    stream::KcpStream,
+    transport::{self, DatagramTransport},
+};
 
-use crate::{config::KcpConfig, scream, session::KcpSessionManager, stream::KcpStream};
-
-#[derive(Debug)]
 pub struct KcpListener {
-    udp: Arc<UdpSocket>,
+    transport: Arc<dyn DatagramTransport>,
     accept_rx: mpsc::Receiver<(KcpStream, SocketAddr)>,
     task_watcher: JoinHandle<()>,
+    config_hook: Arc<SpinMutex<Option<ConfigHook>>>,
+}
+
+impl Debug for KcpListener {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KcpListener")
+            .field("transport", &self.transport)
+            .field("accept_rx", &self.accept_rx)
+            .field("task_watcher", &self.task_watcher)
+            .field("config_hook", &self.config_hook.lock().is_some())
+            .finish()
+    }
 }
 
 impl Drop for KcpListener {
@@ -35,107 +65,287 @@ impl Drop for KcpListener {
 
 impl KcpListener {
     /// Create an `KcpListener` bound to `addr`
+    ///
+    /// `[::]` accepts IPv4 and IPv6 peers, IPv4 peers are reported by their IPv4 address.
     pub async fn bind<A: ToSocketAddrs>(config: KcpConfig, addr: A) -> KcpResult<KcpListener> {
-        let udp = UdpSocket::bind(addr).await?;
+        let udp = transport::bind_udp(addr).await?;
         KcpListener::from_socket(config, udp).await
     }
 
+    /// Create an `KcpListener` bound to `addr`, running a controller built by `factory` on every session
+    pub async fn bind_with_controller<A, F>(config: KcpConfig, addr: A, factory: F) -> KcpResult<KcpListener>
+    where
+        A: ToSocketAddrs,
+        F: Fn() -> Box<dyn CongestionController> + Send + Sync + 'static,
+    {
+        let udp = transport::bind_udp(addr).await?;
+        KcpListener::from_socket_with_controller(config, udp, factory).await
+    }
+
     /// Create a `KcpListener` from an existed `UdpSocket`
-    pub async fn from_socket(config: KcpConfig, udp: UdpSocket) -> KcpResult<KcpListener> {
-        let udp = Arc::new(udp);
-        let server_udp = udp.clone();
+    ///
+    /// Socket options set by the caller are kept unless `config` sets them, ECN and PMTUD options are added
+    /// when enabled.
+    pub async fn from_socket(config: KcpConfig, udp: impl Into<Arc<UdpSocket>>) -> KcpResult<KcpListener> {
+        let udp: Arc<UdpSocket> = udp.into();
+        KcpListener::from_transport_with_factory(config, udp, Arc::new(scream_factory)).await
+    }
+
+    /// Create a `KcpListener` from an existed `UdpSocket`, running a controller built by `factory` on every session
+    pub async fn from_socket_with_controller<F>(
+        config: KcpConfig,
+        udp: impl Into<Arc<UdpSocket>>,
+        factory: F,
+    ) -> KcpResult<KcpListener>
+    where
+        F: Fn() -> Box<dyn CongestionController> + Send + Sync + 'static,
+    {
+        let udp: Arc<UdpSocket> = udp.into();
+        KcpListener::from_transport_with_controller(config, udp, factory).await
+    }
+
+    /// Create a `KcpListener` receiving from all peers through `transport`
+    pub async fn from_transport(config: KcpConfig, transport: Arc<dyn DatagramTransport>) -> KcpResult<KcpListener> {
+        KcpListener::from_transport_with_factory(config, transport, Arc::new(scream_factory)).await
+    }
+
+    /// Create a `KcpListener` receiving through `transport`, running a controller built by `factory` on every session
+    ///
+    /// Socket options of `config` are only set on `UdpSocket` transports, others take care of their own.
+    pub async fn from_transport_with_controller<F>(
+        config: KcpConfig,
+        transport: Arc<dyn DatagramTransport>,
+        factory: F,
+    ) -> KcpResult<KcpListener>
+    where
+        F: Fn() -> Box<dyn CongestionController> + Send + Sync + 'static,
+    {
+        KcpListener::from_transport_with_factory(config, transport, Arc::new(move |_: &KcpConfig| factory())).await
+    }
 
-        let (accept_tx, accept_rx) = mpsc::channel(1024 /* backlogs */);
+    async fn from_transport_with_factory(
+        config: KcpConfig,
+        transport: Arc<dyn DatagramTransport>,
+        congestion_factory: CongestionControllerFactory,
+    ) -> KcpResult<KcpListener> {
+        config.validate()?;
+        if let Some(udp) = transport.udp_socket() {
+            if config.ecn {
+                ecn::enable_ecn(udp)?;
+            }
+            if config.pmtud {
+                pmtud::enable_dont_fragment(udp)?;
+            }
+            sockopt::apply_socket_options(udp, &config)?;
+        }
+        let server_transport = transport.clone();
+        let cipher = config.psk.as_ref().map(PacketCipher::new);
+        let obfuscation = config.obfuscation.clone();
+
+        let (accept_tx, accept_rx) = mpsc::channel(config.accept_backlog.max(1));
+        let config_hook = Arc::new(SpinMutex::new(None));
+        let sessions_config_hook = config_hook.clone();
         let task_watcher = tokio::spawn(async move {
             let (close_tx, mut close_rx) = mpsc::channel(64);
 
-            let mut sessions = KcpSessionManager::new();
-            let mut packet_buffer = [0u8; 65536];
+            let mut sessions = KcpSessionManager::new(
+                congestion_factory,
+                config.conv_allocator.clone(),
+                config.max_aggregate_rate_bps,
+                sessions_config_hook,
+            );
+            let mut recv_batch = RecvBatch::new(config.udp_batch_size);
+            // Start of the current second and the path probes echoed in it
+            let mut probe_echoes = (Instant::now(), 0);
             loop {
                 tokio::select! {
-                    peer_addr = close_rx.recv() => {
-                        let peer_addr = peer_addr.expect("close_tx closed unexpectedly");
-                        sessions.close_peer(peer_addr);
-                        trace!("session peer_addr: {} removed", peer_addr);
+                    closed = close_rx.recv() => {
+                        let (peer_addr, conv) = closed.expect("close_tx closed unexpectedly");
+                        sessions.close_peer(peer_addr, conv);
+                        trace!("session peer_addr: {}, conv: {} removed", peer_addr, conv);
                     }
 
-                    recv_res = udp.recv_from(&mut packet_buffer) => {
+                    recv_res = recv_batch.recv(&*transport, config.ecn) => {
                         match recv_res {
                             Err(err) => {
                                 error!("udp.recv_from failed, error: {}", err);
                                 time::sleep(Duration::from_secs(1)).await;
                             }
-                            Ok((n, peer_addr)) => {
-                                let packet = &mut packet_buffer[..n];
-                                
-                                // check if it is SCReAMv2 header
-                                if n > 4 && (&packet[..4]).get_u32_le() == scream::SCREAM_FEEDBACK_HEADER {
-                                    if let Some(session) = sessions.get(&peer_addr) {
-                                        let mut kcp_socket = session.kcp_socket().lock();
-                                        kcp_socket.scream.on_feedback(&packet[4..], Instant::now());
-                                        
-                                        kcp_socket.try_wake_pending_waker();
+                            Ok(count) => {
+                                for i in 0..count {
+                                    let (packet_buffer, peer_addr, ecn) = recv_batch.get_mut(i);
+                                    let n = packet_buffer.len();
+                                    // Path probes of a multipath peer are answered before any session sees them
+                                    if PacketType::of(&packet_buffer[..n]) == PacketType::PathProbe {
+                                        if config.echo_path_probes
+                                            && sessions.knows_path(&peer_addr)
+                                            && allow_probe_echo(&mut probe_echoes)
+                                        {
+                                            echo_path_probe(&*transport, &packet_buffer[..n], peer_addr);
+                                        }
+                                        continue;
+                                    }
+                                    let mut revealed;
+                                    let packet_buffer = match obfuscation {
+                                        Some(ref obfuscation) => match obfuscation.deobfuscate(&packet_buffer[..n]) {
+                                            Some(packet) => {
+                                                revealed = packet;
+                                                &mut revealed[..]
+                                            }
+                                            None => {
+                                                trace!("dropped {} bytes from peer: {}, not obfuscated", n, peer_addr);
+                                                continue;
+                                            }
+                                        },
+                                        None => &mut packet_buffer[..n],
+                                    };
+                                    let n = packet_buffer.len();
+                                    let mut opened;
+                                    let mut counter = None;
+                                    let packet = match cipher {
+                                        Some(ref cipher) => match cipher.open(&packet_buffer[..n]) {
+                                            Some(packet) => {
+                                                counter = Some(crypto::counter(&packet_buffer[..n]));
+                                                opened = packet;
+                                                &mut opened[..]
+                                            }
+                                            None => {
+                                                trace!("dropped {} bytes from peer: {}, authentication failed", n, peer_addr);
+                                                continue;
+                                            }
+                                        },
+                                        None => &mut packet_buffer[..n],
+                                    };
+                                    // Replays are dropped before they reach the session or replace it as a first packet
+                                    if let (Some(counter), Some(session)) = (counter, sessions.get(&peer_addr)) {
+                                        if !session.accept_counter(counter) {
+                                            continue;
+                                        }
+                                    }
+                                    let n = packet.len();
+                                    // FEC data shards are routed by their payload
+                                    let kcp_range = fec::data_payload_range(packet).unwrap_or(0..n);
+                                    let kcp_packet = &packet[kcp_range.clone()];
+
+                                    // SCReAMv2 feedback, FEC parity and other packets without a KCP header, the
+                                    // session drops the types it doesn't know
+                                    if PacketType::of(kcp_packet) != PacketType::Kcp {
+                                        if let Some(session) = sessions.get(&peer_addr) {
+                                            if session.input(packet, ecn).await.is_err() {
+                                                trace!("[SESSION] KCP session is closing while listener tries to input");
+                                            }
+                                        }
+                                        continue;
                                     }
-                                    continue;
-                                }
 
-                                // regluar KCP packet
-                                trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(packet));
+                                    // regluar KCP packet
+                                    trace!("received peer: {}, {:?}", peer_addr, ByteStr::new(packet));
 
-                                if packet.len() < kcp::KCP_OVERHEAD {
-                                    error!("packet too short, received {} bytes, but at least {} bytes",
-                                           packet.len(),
-                                           kcp::KCP_OVERHEAD);
-                                    continue;
-                                }
+                                    if kcp_packet.len() < kcp::header_len(kcp_packet) {
+                                        error!("packet too short, received {} bytes, but at least {} bytes",
+                                               kcp_packet.len(),
+                                               kcp::header_len(kcp_packet));
+                                        continue;
+                                    }
 
-                                let mut conv = kcp::get_conv(packet);
-                                let sn = kcp::get_sn(packet);
+                                    let mut conv = kcp::get_conv(kcp_packet);
+                                    let sn = kcp::get_sn(kcp_packet);
+
+                                    // Known conv from a new address, the peer's NAT rebound or it changed networks.
+                                    // A replayed datagram doesn't move the session
+                                    if config.migration
+                                        && conv != 0
+                                        && sessions.get(&peer_addr).is_none()
+                                        && accept_migrating(&sessions, conv, counter)
+                                        && sessions.migrate(conv, peer_addr).is_some()
+                                    {
+                                        debug!("conv: {} migrated to peer: {}", conv, peer_addr);
+                                    }
 
-                                if conv == 0 {
-                                    // Allocate a conv for client.
-                                    conv = sessions.alloc_conv();
-                                    debug!("allocate {} conv for peer: {}", conv, peer_addr);
-                                    kcp::set_conv(packet, conv);
-                                }
+                                    let at_limit = config.max_connections.is_some_and(|max| sessions.session_count() >= max);
+                                    if at_limit && sessions.get(&peer_addr).is_none() {
+                                        debug!("refusing peer: {}, {} sessions open", peer_addr, sessions.session_count());
+                                        refuse(&*transport, cipher.as_ref(), obfuscation.as_deref(), peer_addr).await;
+                                        continue;
+                                    }
 
-                                let session = match sessions.get_or_create(&config, conv, sn, &udp, peer_addr, &close_tx).await {
-                                    Ok((s, created)) => {
-                                        if created {
-                                            // Created a new session, constructed a new accepted client
-                                            let stream = KcpStream::with_session(s.clone());
-                                            if  accept_tx.try_send((stream, peer_addr)).is_err() {
-                                                debug!("failed to create accepted stream due to channel failure");
+                                    // Another peer already talks with this conv
+                                    if sessions.conv_peer(conv).is_some_and(|addr| addr != peer_addr) {
+                                        debug!("refusing peer: {}, conv: {} is in use", peer_addr, conv);
+                                        refuse(&*transport, cipher.as_ref(), obfuscation.as_deref(), peer_addr).await;
+                                        continue;
+                                    }
 
-                                                // remove it from session
-                                                sessions.close_peer(peer_addr);
+                                    if conv == 0 {
+                                        // Allocate a conv for client.
+                                        conv = match sessions.alloc_conv() {
+                                            Some(conv) => conv,
+                                            None => {
+                                                debug!("refusing peer: {}, no free conv", peer_addr);
+                                                refuse(
+                                                    &*transport,
+                                                    cipher.as_ref(),
+                                                    obfuscation.as_deref(),
+                                                    peer_addr,
+                                                )
+                                                .await;
                                                 continue;
                                             }
-                                        } else {
-                                            let session_conv = s.conv().await;
-                                            if session_conv != conv {
-                                                debug!("received peer: {} with conv: {} not match with session conv: {}",
-                                                       peer_addr,
-                                                       conv,
-                                                       session_conv);
-                                                continue;
+                                        };
+                                        debug!("allocate {} conv for peer: {}", conv, peer_addr);
+                                        // Parity covers conv 0, packets recovered from this group get dropped
+                                        kcp::set_conv(&mut packet[kcp_range], conv);
+                                    }
+
+                                    let session = match sessions.get_or_create(&config, conv, sn, &transport, peer_addr, &close_tx).await {
+                                        Ok((s, created)) => {
+                                            if created {
+                                                // The first datagram opens the window of the new session
+                                                if let Some(counter) = counter {
+                                                    s.accept_counter(counter);
+                                                }
+                                                // Created a new session, constructed a new accepted client
+                                                let stream = KcpStream::with_session(s.clone());
+                                                if  accept_tx.try_send((stream, peer_addr)).is_err() {
+                                                    debug!("accept backlog full, refusing peer: {}", peer_addr);
+
+                                                    // remove it from session
+                                                    sessions.close_peer(peer_addr, conv);
+                                                    refuse(
+                                                        &*transport,
+                                                        cipher.as_ref(),
+                                                        obfuscation.as_deref(),
+                                                        peer_addr,
+                                                    )
+                                                    .await;
+                                                    continue;
+                                                }
+                                            } else {
+                                                let session_conv = s.conv().await;
+                                                if session_conv != conv {
+                                                    debug!("received peer: {} with conv: {} not match with session conv: {}",
+                                                           peer_addr,
+                                                           conv,
+                                                           session_conv);
+                                                    continue;
+                                                }
                                             }
-                                        }
 
-                                        s
-                                    },
-                                    Err(err) => {
-                                        error!("failed to create session, error: {}, peer: {}, conv: {}", err, peer_addr, conv);
-                                        continue;
+                                            s
+                                        },
+                                        Err(err) => {
+                                            error!("failed to create session, error: {}, peer: {}, conv: {}", err, peer_addr, conv);
+                                            continue;
+                                        }
+                                    };
+
+                                    // let mut kcp = session.kcp_socket().lock().await;
+                                    // if let Err(err) = kcp.input(packet) {
+                                    //     error!("kcp.input failed, peer: {}, conv: {}, error: {}, packet: {:?}", peer_addr, conv, err, ByteStr::new(packet));
+                                    // }
+                                    if session.input(packet, ecn).await.is_err() {
+                                        trace!("[SESSION] KCP session is closing while listener tries to input");
                                     }
-                                };
-
-                                // let mut kcp = session.kcp_socket().lock().await;
-                                // if let Err(err) = kcp.input(packet) {
-                                //     error!("kcp.input failed, peer: {}, conv: {}, error: {}, packet: {:?}", peer_addr, conv, err, ByteStr::new(packet));
-                                // }
-                                if session.input(packet).await.is_err() {
-                                    trace!("[SESSION] KCP session is closing while listener tries to input");
                                 }
                             }
                         }
@@ -145,18 +355,43 @@ impl KcpListener {
         });
 
         Ok(KcpListener {
-            udp: server_udp,
+            transport: server_transport,
             accept_rx,
             task_watcher,
+            config_hook,
         })
     }
 
+    /// Build the config of every session created from now on by `hook` from the peer's address and conv
+    ///
+    /// `psk`, `fec` and `ecn` stay the listener's, so do the settings only a listener reads, e.g.
+    /// `max_connections`. Sessions waiting to be accepted keep the config they were created with.
+    /// The datagram of a peer is dropped if `hook` panics or returns a config `validate` refuses.
+    pub fn set_config_hook<F>(&mut self, hook: F)
+    where
+        F: Fn(SocketAddr, u32) -> KcpConfig + Send + Sync + 'static,
+    {
+        *self.config_hook.lock() = Some(Arc::new(hook));
+    }
+
+    /// Accept a new connected `KcpStream`, sessions created from now on are configured by `hook`
+    ///
+    /// `hook` replaces the one of earlier calls and keeps configuring sessions after this call returned.
+    /// The returned stream may have been created before, sessions already waiting to be accepted keep
+    /// their config. See `set_config_hook`.
+    pub async fn accept_with<F>(&mut self, hook: F) -> KcpResult<(KcpStream, SocketAddr)>
+    where
+        F: Fn(SocketAddr, u32) -> KcpConfig + Send + Sync + 'static,
+    {
+        self.set_config_hook(hook);
+        self.accept().await
+    }
+
     /// Accept a new connected `KcpStream`
     pub async fn accept(&mut self) -> KcpResult<(KcpStream, SocketAddr)> {
         match self.accept_rx.recv().await {
             Some(s) => Ok(s),
-            None => Err(KcpError::IoError(io::Error::new(
-                ErrorKind::Other,
+            None => Err(KcpError::IoError(io::Error::other(
                 "accept channel closed unexpectedly",
             ))),
         }
@@ -165,37 +400,125 @@ impl KcpListener {
     pub fn poll_accept(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<(KcpStream, SocketAddr)>> {
         self.accept_rx.poll_recv(cx).map(|op_res| {
             op_res
-                .ok_or_else(|| KcpError::IoError(io::Error::new(ErrorKind::Other, "accept channel closed unexpectedly")))
+                .ok_or_else(|| KcpError::IoError(io::Error::other("accept channel closed unexpectedly")))
         })
     }
 
     /// Get the local address of the underlying socket
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
-        self.udp.local_addr()
+        self.transport.local_addr()
+    }
+}
+
+/// Incoming connections, `while let Some(conn) = listener.next().await`
+///
+/// Ends once the listener's task stopped, where `accept` would fail.
+impl Stream for KcpListener {
+    type Item = KcpResult<(KcpStream, SocketAddr)>;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
+        self.accept_rx.poll_recv(cx).map(|op_res| op_res.map(Ok))
+    }
+}
+
+fn scream_factory(config: &KcpConfig) -> Box<dyn CongestionController> {
+    Box::new(config.scream_controller().expect("session config was validated"))
+}
+
+// Whether the session talking with `conv` takes the sealed datagram with `counter`
+fn accept_migrating(sessions: &KcpSessionManager, conv: u32, counter: Option<u64>) -> bool {
+    match (counter, sessions.conv_peer(conv).and_then(|addr| sessions.get(&addr))) {
+        (Some(counter), Some(session)) => session.accept_counter(counter),
+        _ => true,
+    }
+}
+
+/// Tell `peer_addr` no session was created for it, its stream fails with `ConnectionRefused`
+async fn refuse(
+    transport: &dyn DatagramTransport,
+    cipher: Option<&PacketCipher>,
+    obfuscation: Option<&dyn Obfuscator>,
+    peer_addr: SocketAddr,
+) {
+    let mut packet = Vec::with_capacity(8);
+    packet.extend_from_slice(&skcp::REFUSED_HEADER.to_le_bytes());
+    packet.extend_from_slice(&0u32.to_le_bytes());
+    if let Some(cipher) = cipher {
+        match cipher.seal(&packet) {
+            Some(sealed) => packet = sealed,
+            None => return,
+        }
+    }
+    if let Some(obfuscation) = obfuscation {
+        let mut scrambled = BytesMut::new();
+        obfuscation.obfuscate(&packet, &mut scrambled);
+        packet = scrambled.to_vec();
+    }
+    if let Err(err) = transport::send_to(transport, &packet, peer_addr).await {
+        debug!("failed to refuse peer: {}, error: {}", peer_addr, err);
     }
 }
 
-#[cfg(unix)]
-impl std::os::unix::io::AsRawFd for KcpListener {
-    fn as_raw_fd(&self) -> std::os::unix::prelude::RawFd {
-        self.udp.as_raw_fd()
+// Within `MAX_PATH_PROBE_ECHOES` of the current second, the listener doesn't turn into a reflector
+fn allow_probe_echo(echoes: &mut (Instant, u32)) -> bool {
+    let now = Instant::now();
+    if now.duration_since(echoes.0) >= Duration::from_secs(1) {
+        *echoes = (now, 0);
     }
+    echoes.1 += 1;
+    echoes.1 <= multipath::MAX_PATH_PROBE_ECHOES
 }
 
-#[cfg(windows)]
-impl std::os::windows::io::AsRawSocket for KcpListener {
-    fn as_raw_socket(&self) -> std::os::windows::prelude::RawSocket {
-        self.udp.as_raw_socket()
+// Never waits on the socket, an echo it can't take right away is a lost probe
+fn echo_path_probe(transport: &dyn DatagramTransport, probe: &[u8], peer_addr: SocketAddr) {
+    let mut cx = Context::from_waker(noop_waker_ref());
+    if let Poll::Ready(Err(err)) = transport.poll_send_to(&mut cx, probe, peer_addr) {
+        debug!("failed to echo path probe to peer: {}, error: {}", peer_addr, err);
+    }
+}
+
+impl KcpListener {
+    /// File descriptor of the UDP socket, `None` for other transports
+    #[cfg(unix)]
+    pub fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        self.transport.udp_socket().map(|udp| udp.as_raw_fd())
+    }
+
+    /// Handle of the UDP socket, `None` for other transports
+    #[cfg(windows)]
+    pub fn raw_socket(&self) -> Option<std::os::windows::io::RawSocket> {
+        use std::os::windows::io::AsRawSocket;
+
+        self.transport.udp_socket().map(|udp| udp.as_raw_socket())
     }
 }
 
 #[cfg(test)]
 mod test {
-    use futures_util::future;
-    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+    use std::{
+        collections::VecDeque,
+        io::ErrorKind,
+        net::SocketAddr,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+            Mutex,
+        },
+    };
+
+    use futures_util::{future, StreamExt};
+    use kcp::Error as KcpError;
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::UdpSocket,
+        sync::Notify,
+        time::{self, Duration},
+    };
 
     use super::KcpListener;
-    use crate::{config::KcpConfig, stream::KcpStream};
+    use crate::{config::KcpConfig, conv::FnConvAllocator, event::KcpEvent, stream::KcpStream};
 
     #[tokio::test]
     async fn multi_echo() {
@@ -203,7 +526,7 @@ mod test {
 
         let config = KcpConfig::default();
 
-        let mut listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
         let server_addr = listener.local_addr().unwrap();
 
         tokio::spawn(async move {
@@ -228,7 +551,7 @@ mod test {
         let mut vfut = Vec::new();
 
         for _ in 0..100 {
-            vfut.push(async move {
+            vfut.push(async {
                 let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
 
                 for _ in 0..20 {
@@ -245,4 +568,331 @@ mod test {
 
         future::join_all(vfut).await;
     }
+
+    #[tokio::test]
+    async fn incoming_stream() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        tokio::spawn(listener.for_each_concurrent(None, |conn| async move {
+            let (mut stream, _) = conn.unwrap();
+            let mut buffer = [0u8; 1024];
+            while let Ok(n) = stream.recv(&mut buffer).await {
+                if n == 0 || stream.send(&buffer[..n]).await.is_err() {
+                    break;
+                }
+            }
+        }));
+
+        let clients = (0..10).map(|_| async {
+            let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+            stream.send(b"HELLO WORLD").await.unwrap();
+
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            assert_eq!(b"HELLO WORLD", &buffer[..n]);
+        });
+        future::join_all(clients).await;
+    }
+
+    #[tokio::test]
+    async fn sessions_end_with_clients() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            session_expire: Some(Duration::from_millis(200)),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // The listener's driver updates its sessions until the clients are gone, then ends them
+        let mut accepted = Vec::new();
+        for _ in 0..3 {
+            let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+            let (sent, conn) = tokio::join!(client.send(b"HELLO"), listener.accept());
+            sent.unwrap();
+            accepted.push(conn.unwrap().0);
+            drop(client);
+        }
+
+        let mut buffer = [0u8; 16];
+        for mut stream in accepted {
+            assert_eq!(stream.recv(&mut buffer).await.unwrap(), 5);
+            let ended = time::timeout(Duration::from_secs(3), stream.recv(&mut buffer)).await;
+            assert!(matches!(ended, Ok(Ok(0)) | Ok(Err(_))), "{:?}", ended);
+        }
+    }
+
+    async fn assert_refused(config: &KcpConfig, server_addr: std::net::SocketAddr) {
+        let mut stream = KcpStream::connect(config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        let mut buffer = [0u8; 16];
+        match stream.recv(&mut buffer).await {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), ErrorKind::ConnectionRefused),
+            r => panic!("expected refusal, got {:?}", r),
+        }
+    }
+
+    #[tokio::test]
+    async fn max_connections_refused() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            max_connections: Some(1),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut first = KcpStream::connect(&config, server_addr).await.unwrap();
+        first.send(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        assert_refused(&config, server_addr).await;
+
+        // The admitted session keeps working
+        let mut buffer = [0u8; 16];
+        let n = accepted.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"HELLO");
+    }
+
+    #[tokio::test]
+    async fn config_hook_configures_session() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let peers = Arc::new(Mutex::new(Vec::new()));
+        let hook_peers = peers.clone();
+        listener.set_config_hook(move |peer_addr, conv| {
+            hook_peers.lock().unwrap().push((peer_addr, conv));
+            KcpConfig {
+                rcv_wnd: 512,
+                ..KcpConfig::default()
+            }
+        });
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (accepted, peer_addr) = listener.accept().await.unwrap();
+
+        assert_eq!(accepted.stats().rcv_wnd, 512);
+        assert_eq!(client.stats().rcv_wnd, 256);
+        let peers = peers.lock().unwrap();
+        assert_eq!(peers.len(), 1);
+        assert_eq!(peers[0].0, peer_addr);
+        assert_ne!(peers[0].1, 0);
+    }
+
+    #[tokio::test]
+    async fn config_hook_panic_drops_datagram() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let calls = Arc::new(AtomicUsize::new(0));
+        let hook_calls = calls.clone();
+        listener.set_config_hook(move |_, _| {
+            if hook_calls.fetch_add(1, Ordering::Relaxed) == 0 {
+                panic!("first peer refused");
+            }
+            KcpConfig::default()
+        });
+
+        // The retransmission after the panic opens the session
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        let mut buffer = [0u8; 16];
+        let n = accepted.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"HELLO");
+        assert!(calls.load(Ordering::Relaxed) >= 2);
+    }
+
+    #[tokio::test]
+    async fn accept_backlog_refused() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            accept_backlog: 1,
+            ..Default::default()
+        };
+
+        let listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Fills the backlog once acknowledged, nobody accepts
+        let mut first = KcpStream::connect(&config, server_addr).await.unwrap();
+        first.send(b"HELLO").await.unwrap();
+        while first.stats().wait_snd > 0 {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+
+        assert_refused(&config, server_addr).await;
+        drop(listener);
+    }
+
+    #[tokio::test]
+    async fn conv_allocation_race() {
+        let _ = env_logger::try_init();
+
+        // Hands out 42 twice, the second client must not share the first one's session
+        let convs = Mutex::new(VecDeque::from(vec![42, 42, 0, 43]));
+        let config = KcpConfig {
+            conv_allocator: Arc::new(FnConvAllocator(move || convs.lock().unwrap().pop_front().unwrap_or(44))),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 1024];
+                    while let Ok(n) = stream.recv(&mut buffer).await {
+                        stream.send(&buffer[..n]).await.unwrap();
+                    }
+                });
+            }
+        });
+
+        let clients = (0..2u8).map(|i| {
+            let config = config.clone();
+            async move {
+                let mut stream = KcpStream::connect_with_conv(&config, 0, server_addr).await.unwrap();
+                stream.send(&[i; 16]).await.unwrap();
+                let mut buffer = [0u8; 1024];
+                let n = time::timeout(Duration::from_secs(2), stream.recv(&mut buffer))
+                    .await
+                    .expect("no echo")
+                    .unwrap();
+                assert_eq!(&buffer[..n], &[i; 16]);
+                stream.session().conv().await
+            }
+        });
+        let mut convs = future::join_all(clients).await;
+        convs.sort_unstable();
+        assert_eq!(convs, vec![42, 43]);
+    }
+
+    #[tokio::test]
+    async fn conv_collision_refused() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut first = KcpStream::connect_with_conv(&config, 1234, server_addr).await.unwrap();
+        first.send(b"HELLO").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+
+        // Same conv from another address, without migration
+        let mut second = KcpStream::connect_with_conv(&config, 1234, server_addr).await.unwrap();
+        second.send(b"HELLO").await.unwrap();
+        let mut buffer = [0u8; 16];
+        match second.recv(&mut buffer).await {
+            Err(KcpError::IoError(err)) => assert_eq!(err.kind(), ErrorKind::ConnectionRefused),
+            r => panic!("expected refusal, got {:?}", r),
+        }
+
+        let n = accepted.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"HELLO");
+        assert!(time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+    }
+
+    // Forwards one client to `server_addr`, from a fresh outside port every time `rebind` is notified
+    async fn nat(server_addr: SocketAddr, rebind: Arc<Notify>) -> SocketAddr {
+        let inside = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let inside_addr = inside.local_addr().unwrap();
+        tokio::spawn(async move {
+            let mut outside = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            let mut client_addr = None;
+            let mut inside_buf = [0u8; 2048];
+            let mut outside_buf = [0u8; 2048];
+            loop {
+                tokio::select! {
+                    Ok((n, addr)) = inside.recv_from(&mut inside_buf) => {
+                        client_addr = Some(addr);
+                        let _ = outside.send_to(&inside_buf[..n], server_addr).await;
+                    }
+                    Ok((n, _)) = outside.recv_from(&mut outside_buf) => {
+                        if let Some(addr) = client_addr {
+                            let _ = inside.send_to(&outside_buf[..n], addr).await;
+                        }
+                    }
+                    _ = rebind.notified() => {
+                        outside = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                    }
+                }
+            }
+        });
+        inside_addr
+    }
+
+    #[tokio::test]
+    async fn migration_follows_rebinding() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            migration: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let rebind = Arc::new(Notify::new());
+        let nat_addr = nat(server_addr, rebind.clone()).await;
+
+        let mut stream = KcpStream::connect(&config, nat_addr).await.unwrap();
+        stream.send(b"BEFORE").await.unwrap();
+        let (mut accepted, _) = listener.accept().await.unwrap();
+        let migrated = Arc::new(Mutex::new(Vec::new()));
+        {
+            let migrated = migrated.clone();
+            accepted.set_event_handler(move |event| {
+                if let KcpEvent::PeerMigrated { peer_addr } = event {
+                    migrated.lock().unwrap().push(peer_addr);
+                }
+            });
+        }
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 1024];
+            while let Ok(n) = accepted.recv(&mut buffer).await {
+                accepted.send(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut buffer = [0u8; 1024];
+        let n = stream.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"BEFORE");
+
+        rebind.notify_one();
+        time::sleep(Duration::from_millis(50)).await;
+        stream.send(b"AFTER").await.unwrap();
+        let n = time::timeout(Duration::from_secs(2), stream.recv(&mut buffer))
+            .await
+            .expect("reply not sent to the new address")
+            .unwrap();
+        assert_eq!(&buffer[..n], b"AFTER");
+
+        // Re-attached instead of accepted as a new session
+        assert!(time::timeout(Duration::from_millis(100), listener.accept()).await.is_err());
+        assert_eq!(migrated.lock().unwrap().len(), 1);
+    }
 }
@@ -0,0 +1,256 @@
+//! Congestion control metrics output
+//!
+//! Every session records one `MetricsSample` per update tick into the `MetricsSink` of its
+//! `KcpConfig`. Sinks are called with the session lock held and must not block.
+
+use std::{
+    collections::VecDeque,
+    fmt::{self, Debug},
+    fs::OpenOptions,
+    io::{self, BufWriter, Write},
+    net::SocketAddr,
+    path::Path,
+    sync::{mpsc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use tracing::error;
+
+/// State of a session's congestion controller at one update tick
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct MetricsSample {
+    /// Unix time in milliseconds
+    pub timestamp_ms: u64,
+    /// Conversation of the session
+    pub conv: u32,
+    /// Identifies the session within the process, sessions with different peers may share a `conv`
+    pub session_id: u64,
+    /// Address the session sends to
+    pub peer_addr: Option<SocketAddr>,
+    /// Smoothed RTT
+    pub s_rtt: Duration,
+    /// Minimum RTT over the base RTT window
+    pub base_rtt: Duration,
+    /// Queuing delay of the last RTT sample
+    pub qdelay: Duration,
+    /// Smoothed queuing delay
+    pub qdelay_avg: Duration,
+    /// Target bitrate (bps)
+    pub target_bitrate: f32,
+    /// Congestion window (bytes)
+    pub congestion_window: f32,
+    /// Bytes sent and neither acknowledged nor lost
+    pub bytes_in_flight: u32,
+    /// Maximum bytes in flight during the current RTT
+    pub max_bytes_in_flight: u32,
+    /// A loss was detected since the previous sample
+    pub loss: bool,
+    /// Bytes of all datagrams sent by the session
+    pub bytes_sent: u64,
+    /// Bytes of all datagrams received by the session, without the encryption overhead
+    pub bytes_received: u64,
+    /// Segments retransmitted after their RTO expired
+    pub retransmits: u32,
+    /// Segments retransmitted before their RTO, after enough later ones were acknowledged
+    pub fast_retransmits: u32,
+    /// Segments received ahead of a gap
+    pub out_of_order: u32,
+    /// ACKs of segments acknowledged before
+    pub duplicate_acks: u32,
+    /// Packets waiting for the pacer
+    pub pacer_queue: usize,
+    /// Receive window the peer advertised (segments)
+    pub rmt_wnd: u16,
+}
+
+/// Destination of `MetricsSample`s, shared by all sessions created with the same `KcpConfig`
+pub trait MetricsSink: Debug + Send + Sync {
+    /// Record a sample, called from the session's update path
+    fn record(&self, sample: &MetricsSample);
+
+    /// Session `session_id` talking on `conv` closed, no samples of it follow
+    fn close(&self, _conv: u32, _session_id: u64) {}
+}
+
+/// Discards all samples
+#[derive(Debug, Clone, Copy, Default)]
+pub struct NoopSink;
+
+impl MetricsSink for NoopSink {
+    fn record(&self, _sample: &MetricsSample) {}
+}
+
+/// Keeps the latest `capacity` samples in memory
+pub struct RingBufferSink {
+    capacity: usize,
+    samples: Mutex<VecDeque<MetricsSample>>,
+}
+
+impl Debug for RingBufferSink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RingBufferSink")
+            .field("capacity", &self.capacity)
+            .field("samples.len", &self.samples.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl RingBufferSink {
+    pub fn new(capacity: usize) -> RingBufferSink {
+        RingBufferSink {
+            capacity,
+            samples: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Copy of the buffered samples, oldest first
+    pub fn samples(&self) -> Vec<MetricsSample> {
+        self.samples.lock().unwrap().iter().copied().collect()
+    }
+
+    /// Remove and return the buffered samples, oldest first
+    pub fn drain(&self) -> Vec<MetricsSample> {
+        self.samples.lock().unwrap().drain(..).collect()
+    }
+}
+
+impl MetricsSink for RingBufferSink {
+    fn record(&self, sample: &MetricsSample) {
+        if self.capacity == 0 {
+            return;
+        }
+        let mut samples = self.samples.lock().unwrap();
+        if samples.len() == self.capacity {
+            samples.pop_front();
+        }
+        samples.push_back(*sample);
+    }
+}
+
+const CSV_HEADER: &[u8] = b"timestamp_ms,s_rtt_ms,base_rtt_ms,qdelay_ms,qdelay_avg_ms,bitrate_kbps,cwnd_bytes,\
+bytes_in_flight,max_bytes_in_flight,packet_loss,conv\n";
+
+// Samples queued for the writer thread, newer samples are dropped while it is behind
+const CSV_QUEUE_SIZE: usize = 4096;
+
+/// Appends samples to a CSV file (the format read by `visualizer/plotter.py`)
+///
+/// Writes happen on a dedicated thread, which exits once the sink is dropped.
+#[derive(Debug)]
+pub struct CsvSink {
+    sample_tx: mpsc::SyncSender<MetricsSample>,
+}
+
+impl CsvSink {
+    /// Open `path` for appending, the header is written if the file is empty
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<CsvSink> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let is_empty = file.metadata()?.len() == 0;
+
+        let (sample_tx, sample_rx) = mpsc::sync_channel::<MetricsSample>(CSV_QUEUE_SIZE);
+
+        thread::Builder::new().name("kcp-metrics-csv".to_owned()).spawn(move || {
+            let mut writer = BufWriter::new(file);
+            if is_empty {
+                if let Err(err) = writer.write_all(CSV_HEADER) {
+                    error!("metrics CSV write failed, error: {}", err);
+                    return;
+                }
+            }
+
+            while let Ok(sample) = sample_rx.recv() {
+                let mut result = write_csv_line(&mut writer, &sample);
+                // Batch everything that queued up meanwhile into a single flush
+                while let (true, Ok(sample)) = (result.is_ok(), sample_rx.try_recv()) {
+                    result = write_csv_line(&mut writer, &sample);
+                }
+                if let Err(err) = result.and_then(|_| writer.flush()) {
+                    error!("metrics CSV write failed, error: {}", err);
+                    return;
+                }
+            }
+        })?;
+
+        Ok(CsvSink { sample_tx })
+    }
+}
+
+fn write_csv_line<W: Write>(w: &mut W, sample: &MetricsSample) -> io::Result<()> {
+    writeln!(
+        w,
+        "{},{},{},{},{},{},{},{},{},{},{}",
+        sample.timestamp_ms,
+        sample.s_rtt.as_millis(),
+        sample.base_rtt.as_millis(),
+        sample.qdelay.as_millis(),
+        sample.qdelay_avg.as_millis(),
+        sample.target_bitrate / 1000.0,
+        sample.congestion_window,
+        sample.bytes_in_flight,
+        sample.max_bytes_in_flight,
+        sample.loss as u8,
+        sample.conv,
+    )
+}
+
+impl MetricsSink for CsvSink {
+    fn record(&self, sample: &MetricsSample) {
+        // Never block the session, a full queue means the disk can't keep up
+        let _ = self.sample_tx.try_send(*sample);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{fs, time::Instant};
+
+    use super::*;
+
+    fn sample(timestamp_ms: u64) -> MetricsSample {
+        MetricsSample {
+            timestamp_ms,
+            s_rtt: Duration::from_millis(25),
+            loss: timestamp_ms.is_multiple_of(2),
+            ..MetricsSample::default()
+        }
+    }
+
+    #[test]
+    fn ring_buffer_keeps_latest() {
+        let sink = RingBufferSink::new(3);
+        for ts in 0..5 {
+            sink.record(&sample(ts));
+        }
+
+        let timestamps: Vec<u64> = sink.samples().iter().map(|s| s.timestamp_ms).collect();
+        assert_eq!(timestamps, [2, 3, 4]);
+        assert_eq!(sink.drain().len(), 3);
+        assert!(sink.samples().is_empty());
+    }
+
+    #[test]
+    fn csv_written_off_thread() {
+        let path = std::env::temp_dir().join(format!("kcp_metrics_{}.csv", std::process::id()));
+        let _ = fs::remove_file(&path);
+
+        let sink = CsvSink::new(&path).unwrap();
+        sink.record(&sample(1));
+        sink.record(&sample(2));
+
+        let start = Instant::now();
+        let content = loop {
+            let content = fs::read_to_string(&path).unwrap();
+            if content.lines().count() == 3 || start.elapsed() > Duration::from_secs(5) {
+                break content;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let _ = fs::remove_file(&path);
+
+        let mut lines = content.lines();
+        assert!(lines.next().unwrap().starts_with("timestamp_ms,s_rtt_ms,"));
+        assert_eq!(lines.next(), Some("1,25,0,0,0,0,0,0,0,0,0"));
+        assert_eq!(lines.next(), Some("2,25,0,0,0,0,0,0,0,1,0"));
+    }
+}
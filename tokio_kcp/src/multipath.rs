@@ -0,0 +1,514 @@
+//! Several datagram paths bonded under one connection
+//!
+//! `MultipathTransport` carries a single `KcpStream` over any number of paths, each a transport and
+//! the remote address it talks to, e.g. a WiFi and an LTE socket towards the same server. KCP and
+//! SCReAM see one peer and control the bonded flow as a whole, a `PathScheduler` picks the path of
+//! every packet. Each path's RTT and loss are measured with probes the listener echoes, they go out
+//! along with the traffic. The measurements only steer the scheduler, SCReAM doesn't see them.
+//!
+//! The server sees the paths' source addresses alternate, its listener needs `KcpConfig::migration`
+//! and `KcpConfig::echo_path_probes`. It only echoes probes from addresses the session was seen at,
+//! a path carries copies of the traffic until its first probe was answered.
+
+use std::{
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+        Mutex,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut};
+use tokio::net::UdpSocket;
+use tracing::trace;
+
+use crate::{ecn::EcnCodepoint, transport::DatagramTransport, utils::now};
+
+/// Path probe, echoed unchanged by the listener: header, path id, probe sn
+pub const PATH_PROBE_HEADER: u32 = 0x5C4D4D50;
+pub const PATH_PROBE_LEN: usize = 12;
+
+/// Path probes a listener echoes per second at most
+pub(crate) const MAX_PATH_PROBE_ECHOES: u32 = 1000;
+
+// Consecutive unanswered probes until a path counts as down
+const PATH_DOWN_PROBES: u32 = 3;
+const DEFAULT_PROBE_INTERVAL: Duration = Duration::from_millis(200);
+
+/// Packet is a path probe, or its echo
+pub fn is_path_probe(packet: &[u8]) -> bool {
+    packet.len() == PATH_PROBE_LEN && (&packet[..4]).get_u32_le() == PATH_PROBE_HEADER
+}
+
+/// How `MultipathTransport` spreads packets over its paths
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum PathScheduler {
+    /// Every packet takes the path with the lowest RTT that is up
+    #[default]
+    LowestRtt,
+    /// Every packet is duplicated on all paths that are up, the receiver drops the copies
+    Redundant,
+}
+
+/// Measurements of one path
+#[derive(Debug, Clone, Copy)]
+pub struct PathStats {
+    /// Remote address of the path
+    pub remote_addr: SocketAddr,
+    /// Smoothed probe RTT, `None` until a probe was answered
+    pub srtt: Option<Duration>,
+    /// Share of probes lost, smoothed
+    pub loss: f32,
+    /// The last probes were answered, or none was lost so far
+    pub up: bool,
+    /// Packets sent through this path, probes excluded
+    pub packets_sent: u64,
+    /// Packets received through this path, probes excluded
+    pub packets_received: u64,
+}
+
+#[derive(Debug)]
+struct Path {
+    transport: Arc<dyn DatagramTransport>,
+    remote: SocketAddr,
+    srtt: Option<Duration>,
+    loss: f32,
+    lost_in_row: u32,
+    // Sn and send time of the probe waiting for its echo
+    probe: Option<(u32, Instant)>,
+    next_probe_sn: u32,
+    last_probe: Option<Instant>,
+    sent: u64,
+    received: u64,
+}
+
+impl Path {
+    fn up(&self) -> bool {
+        self.lost_in_row < PATH_DOWN_PROBES
+    }
+
+    fn stats(&self) -> PathStats {
+        PathStats {
+            remote_addr: self.remote,
+            srtt: self.srtt,
+            loss: self.loss,
+            up: self.up(),
+            packets_sent: self.sent,
+            packets_received: self.received,
+        }
+    }
+
+    // The previous probe wasn't answered in time if it is still waiting
+    fn start_probe(&mut self, now: Instant) -> u32 {
+        if self.probe.take().is_some() {
+            self.loss = self.loss * 0.9 + 0.1;
+            self.lost_in_row += 1;
+        }
+        let sn = self.next_probe_sn;
+        self.next_probe_sn = self.next_probe_sn.wrapping_add(1);
+        self.probe = Some((sn, now));
+        self.last_probe = Some(now);
+        sn
+    }
+
+    fn probe_answered(&mut self, sn: u32, now: Instant) {
+        let sent_at = match self.probe {
+            Some((probe_sn, sent_at)) if probe_sn == sn => sent_at,
+            _ => return,
+        };
+        self.probe = None;
+        let rtt = now - sent_at;
+        self.srtt = Some(match self.srtt {
+            Some(srtt) => (srtt * 7 + rtt) / 8,
+            None => rtt,
+        });
+        self.loss *= 0.9;
+        self.lost_in_row = 0;
+    }
+
+    // A send or receive failed, the interface may be gone
+    fn failed(&mut self) {
+        self.lost_in_row = self.lost_in_row.max(PATH_DOWN_PROBES);
+    }
+}
+
+// Paths a packet is sent on, in the order to try them
+fn schedule(paths: &[Path], scheduler: PathScheduler) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..paths.len()).filter(|&i| paths[i].up()).collect();
+    if order.is_empty() {
+        // Nothing is up, try the paths that failed the least recently
+        order = (0..paths.len()).collect();
+        order.sort_by_key(|&i| paths[i].lost_in_row);
+        order.truncate(1);
+        return order;
+    }
+    match scheduler {
+        PathScheduler::LowestRtt => {
+            // Unmeasured paths come last, in the order they were added
+            order.sort_by_key(|&i| paths[i].srtt.unwrap_or(Duration::MAX));
+            order.truncate(1);
+        }
+        PathScheduler::Redundant => {}
+    }
+    order
+}
+
+/// Transport sending each packet over one or several of its paths, see the module documentation
+///
+/// Datagrams received on any path are reported from the first path's remote address, connect to
+/// `peer_addr`.
+#[derive(Debug)]
+pub struct MultipathTransport {
+    paths: Mutex<Vec<Path>>,
+    scheduler: Mutex<PathScheduler>,
+    probe_interval: Mutex<Duration>,
+    // Path polled first by the next receive, so a busy path can't starve the others
+    next_recv: AtomicUsize,
+}
+
+impl MultipathTransport {
+    /// Transport without paths, add them with `add_path`
+    pub fn new(scheduler: PathScheduler) -> MultipathTransport {
+        MultipathTransport {
+            paths: Mutex::new(Vec::new()),
+            scheduler: Mutex::new(scheduler),
+            probe_interval: Mutex::new(DEFAULT_PROBE_INTERVAL),
+            next_recv: AtomicUsize::new(0),
+        }
+    }
+
+    /// Bind a `UdpSocket` on every local address, each path sends to its remote address
+    pub async fn bind(scheduler: PathScheduler, paths: &[(SocketAddr, SocketAddr)]) -> io::Result<MultipathTransport> {
+        let transport = MultipathTransport::new(scheduler);
+        for &(local, remote) in paths {
+            let udp = UdpSocket::bind(local).await?;
+            transport.add_path(Arc::new(udp), remote);
+        }
+        Ok(transport)
+    }
+
+    /// Add a path sending to `remote` through `transport`, returns its index
+    pub fn add_path(&self, transport: Arc<dyn DatagramTransport>, remote: SocketAddr) -> usize {
+        let mut paths = self.paths.lock().unwrap();
+        paths.push(Path {
+            transport,
+            remote,
+            srtt: None,
+            loss: 0.0,
+            lost_in_row: 0,
+            probe: None,
+            next_probe_sn: 0,
+            last_probe: None,
+            sent: 0,
+            received: 0,
+        });
+        paths.len() - 1
+    }
+
+    /// Address of the peer as the connection sees it, the first path's remote address
+    pub fn peer_addr(&self) -> Option<SocketAddr> {
+        self.paths.lock().unwrap().first().map(|path| path.remote)
+    }
+
+    /// Change how packets are spread from now on
+    pub fn set_scheduler(&self, scheduler: PathScheduler) {
+        *self.scheduler.lock().unwrap() = scheduler;
+    }
+
+    /// Time between probes of a path, a probe unanswered by the next counts as lost
+    pub fn set_probe_interval(&self, interval: Duration) {
+        *self.probe_interval.lock().unwrap() = interval;
+    }
+
+    /// Measurements of every path, in the order they were added
+    pub fn path_stats(&self) -> Vec<PathStats> {
+        self.paths.lock().unwrap().iter().map(Path::stats).collect()
+    }
+
+    // Probe the paths that are due, best effort
+    fn poll_send_probes(&self, cx: &mut Context<'_>) {
+        let now = now();
+        let interval = *self.probe_interval.lock().unwrap();
+        let mut paths = self.paths.lock().unwrap();
+        for (id, path) in paths.iter_mut().enumerate() {
+            if path.last_probe.is_some_and(|at| now - at < interval) {
+                continue;
+            }
+            let sn = path.start_probe(now);
+            let mut probe = Vec::with_capacity(PATH_PROBE_LEN);
+            probe.put_u32_le(PATH_PROBE_HEADER);
+            probe.put_u32_le(id as u32);
+            probe.put_u32_le(sn);
+            if let Poll::Ready(Err(err)) = path.transport.poll_send_to(cx, &probe, path.remote) {
+                trace!("path {} to {} failed to send probe: {}", id, path.remote, err);
+                path.failed();
+            }
+        }
+    }
+
+    // `packet` is the echo of one of our probes
+    fn probe_answered(&self, packet: &[u8]) {
+        let mut packet = &packet[4..];
+        let id = packet.get_u32_le() as usize;
+        let sn = packet.get_u32_le();
+        if let Some(path) = self.paths.lock().unwrap().get_mut(id) {
+            path.probe_answered(sn, now());
+        }
+    }
+}
+
+impl DatagramTransport for MultipathTransport {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], _target: SocketAddr) -> Poll<io::Result<usize>> {
+        self.poll_send_probes(cx);
+
+        let scheduler = *self.scheduler.lock().unwrap();
+        let targets: Vec<(usize, Arc<dyn DatagramTransport>, SocketAddr)> = {
+            let paths = self.paths.lock().unwrap();
+            let mut order = schedule(&paths, scheduler);
+            if scheduler == PathScheduler::LowestRtt {
+                // The others take over if the chosen path fails right away
+                let fallback: Vec<usize> = (0..paths.len()).filter(|&i| paths[i].up() && !order.contains(&i)).collect();
+                order.extend(fallback);
+            }
+            order
+                .into_iter()
+                .map(|i| (i, paths[i].transport.clone(), paths[i].remote))
+                .collect()
+        };
+
+        let mut sent = false;
+        let mut pending = false;
+        let mut error = None;
+        // The listener echoes the probes of a path once the session was seen on it, until then the path
+        // carries copies, best effort
+        let unmeasured: Vec<(usize, Arc<dyn DatagramTransport>, SocketAddr)> = {
+            let paths = self.paths.lock().unwrap();
+            (0..paths.len())
+                .filter(|&i| paths[i].srtt.is_none() && !targets.iter().any(|target| target.0 == i))
+                .map(|i| (i, paths[i].transport.clone(), paths[i].remote))
+                .collect()
+        };
+        for (i, transport, remote) in unmeasured {
+            if let Poll::Ready(Ok(..)) = transport.poll_send_to(cx, buf, remote) {
+                self.paths.lock().unwrap()[i].sent += 1;
+            }
+        }
+        for (i, transport, remote) in targets {
+            match transport.poll_send_to(cx, buf, remote) {
+                Poll::Ready(Ok(..)) => {
+                    self.paths.lock().unwrap()[i].sent += 1;
+                    sent = true;
+                }
+                Poll::Ready(Err(err)) => {
+                    trace!("path {} to {} failed to send: {}", i, remote, err);
+                    self.paths.lock().unwrap()[i].failed();
+                    error.get_or_insert(err);
+                    continue;
+                }
+                Poll::Pending => pending = true,
+            }
+            if scheduler == PathScheduler::LowestRtt {
+                break;
+            }
+        }
+
+        if sent {
+            Poll::Ready(Ok(buf.len()))
+        } else if pending {
+            Poll::Pending
+        } else {
+            Poll::Ready(Err(error.unwrap_or_else(|| io::Error::other("multipath transport has no paths"))))
+        }
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+        let paths: Vec<_> = {
+            let paths = self.paths.lock().unwrap();
+            paths.iter().map(|path| (path.transport.clone(), path.remote)).collect()
+        };
+        let peer_addr = match paths.first() {
+            Some(&(_, remote)) => remote,
+            None => return Poll::Ready(Err(io::Error::other("multipath transport has no paths"))),
+        };
+
+        let start = self.next_recv.fetch_add(1, Ordering::Relaxed);
+        let mut pending = false;
+        let mut error = None;
+        for offset in 0..paths.len() {
+            let i = (start + offset) % paths.len();
+            let (ref transport, remote) = paths[i];
+            loop {
+                match transport.poll_recv_from(cx, buf) {
+                    Poll::Ready(Ok((n, addr, ecn))) => {
+                        if is_path_probe(&buf[..n]) {
+                            self.probe_answered(&buf[..n]);
+                            continue;
+                        }
+                        if addr != remote {
+                            // The socket may be shared with other traffic
+                            return Poll::Ready(Ok((n, addr, ecn)));
+                        }
+                        self.paths.lock().unwrap()[i].received += 1;
+                        return Poll::Ready(Ok((n, peer_addr, ecn)));
+                    }
+                    Poll::Ready(Err(err)) => {
+                        trace!("path {} to {} failed to receive: {}", i, remote, err);
+                        self.paths.lock().unwrap()[i].failed();
+                        error.get_or_insert(err);
+                        break;
+                    }
+                    Poll::Pending => {
+                        pending = true;
+                        break;
+                    }
+                }
+            }
+        }
+
+        // Only fail once no path can receive anymore
+        match error {
+            Some(err) if !pending => Poll::Ready(Err(err)),
+            _ => Poll::Pending,
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        match self.paths.lock().unwrap().first() {
+            Some(path) => path.transport.local_addr(),
+            None => Err(io::Error::other("multipath transport has no paths")),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        sim::{EmulatedTransport, SimLinkConfig},
+        KcpConfig,
+        KcpListener,
+        KcpStream,
+    };
+
+    async fn path(srtt: Option<u64>, lost_in_row: u32) -> Path {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        Path {
+            transport: Arc::new(udp),
+            remote: "127.0.0.1:9".parse().unwrap(),
+            srtt: srtt.map(Duration::from_millis),
+            loss: 0.0,
+            lost_in_row,
+            probe: None,
+            next_probe_sn: 0,
+            last_probe: None,
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn schedule_paths() {
+        let paths = vec![
+            path(None, 0).await,
+            path(Some(80), 0).await,
+            path(Some(20), 3).await,
+            path(Some(40), 1).await,
+        ];
+        assert_eq!(schedule(&paths, PathScheduler::LowestRtt), vec![3]);
+        assert_eq!(schedule(&paths, PathScheduler::Redundant), vec![0, 1, 3]);
+
+        let down = vec![path(Some(20), 5).await, path(Some(80), 3).await];
+        assert_eq!(schedule(&down, PathScheduler::LowestRtt), vec![1]);
+        assert_eq!(schedule(&down, PathScheduler::Redundant), vec![1]);
+    }
+
+    #[tokio::test]
+    async fn bonded_paths() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            migration: true,
+            echo_path_probes: true,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // A slow path added first and a fast one
+        let transport = Arc::new(MultipathTransport::new(PathScheduler::LowestRtt));
+        let slow_link = SimLinkConfig {
+            delay: Duration::from_millis(40),
+            ..Default::default()
+        };
+        let slow = EmulatedTransport::new(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()), slow_link, 1);
+        transport.add_path(slow, server_addr);
+        transport.add_path(Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap()), server_addr);
+        transport.set_probe_interval(Duration::from_millis(50));
+        assert_eq!(transport.peer_addr(), Some(server_addr));
+
+        let mut client = KcpStream::connect_with_transport(&config, transport.clone(), server_addr)
+            .await
+            .unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+
+        let mut buffer = [0u8; 64];
+        for _ in 0..20 {
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            let n = client.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..n], b"HELLO");
+            time::sleep(Duration::from_millis(30)).await;
+            client.send(b"HELLO").await.unwrap();
+        }
+
+        let stats = transport.path_stats();
+        assert!(stats.iter().all(|path| path.up), "{:?}", stats);
+        assert!(stats[1].packets_received > 0);
+        assert!(stats[0].srtt.unwrap() > stats[1].srtt.unwrap());
+        assert!(stats[1].packets_sent > stats[0].packets_sent * 2);
+
+        // Duplicates on both paths
+        transport.set_scheduler(PathScheduler::Redundant);
+        let slow_sent = stats[0].packets_sent;
+        for _ in 0..10 {
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            let n = client.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..n], b"HELLO");
+            client.send(b"HELLO").await.unwrap();
+        }
+        assert!(transport.path_stats()[0].packets_sent >= slow_sent + 10);
+    }
+
+    #[tokio::test]
+    async fn probes_of_strangers_unanswered() {
+        let _ = env_logger::try_init();
+
+        let mut probe = Vec::with_capacity(PATH_PROBE_LEN);
+        probe.put_u32_le(PATH_PROBE_HEADER);
+        probe.put_u32_le(0);
+        probe.put_u32_le(0);
+
+        for echo_path_probes in [false, true] {
+            let config = KcpConfig {
+                echo_path_probes,
+                ..Default::default()
+            };
+            let listener = KcpListener::bind(config, "127.0.0.1:0").await.unwrap();
+            let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            udp.send_to(&probe, listener.local_addr().unwrap()).await.unwrap();
+
+            // Off by default, on for addresses of sessions only
+            let mut buffer = [0u8; 64];
+            let echo = time::timeout(Duration::from_millis(200), udp.recv_from(&mut buffer)).await;
+            assert!(echo.is_err());
+        }
+    }
+}
@@ -0,0 +1,715 @@
+//! Stream multiplexing over a single `KcpStream`
+//!
+//! Every frame carries the id of the logical stream it belongs to, so control, audio and video
+//! channels can share one congestion controlled connection. Each stream has its own receive
+//! window, a reader falling behind only stalls its own sender.
+//!
+//! ```plain
+//! | type (1) | flags (1) | stream id (4) | length (4) | payload |
+//! ```
+//!
+//! `length` is the payload size for data frames and the granted credit for window updates.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    io,
+    sync::{
+        atomic::{AtomicBool, AtomicU32, Ordering},
+        Arc,
+        Mutex,
+    },
+};
+
+use kcp::{Error as KcpError, KcpResult};
+use tokio::{
+    sync::{mpsc, Notify},
+    task::JoinHandle,
+};
+use tracing::{debug, trace};
+
+use crate::{
+    split::{KcpReadHalf, KcpWriteHalf},
+    stream::KcpStream,
+};
+
+const FRAME_DATA: u8 = 0;
+const FRAME_WINDOW_UPDATE: u8 = 1;
+
+const FLAG_SYN: u8 = 0x01;
+const FLAG_FIN: u8 = 0x02;
+const FLAG_RST: u8 = 0x04;
+
+const HEADER_LEN: usize = 10;
+
+/// Receive window every stream starts with
+const INITIAL_WINDOW: u32 = 256 * 1024;
+/// Largest payload put into a single data frame
+const MAX_FRAME_PAYLOAD: usize = 16 * 1024;
+/// Frames waiting for the writer task
+const FRAME_QUEUE_SIZE: usize = 64;
+/// Opened streams waiting for `accept_stream`
+const ACCEPT_BACKLOG: usize = 64;
+
+/// Which side of the connection a `KcpMuxer` runs on, keeps locally allocated stream ids apart
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MuxRole {
+    /// Opens odd stream ids
+    Client,
+    /// Opens even stream ids
+    Server,
+}
+
+fn encode_frame(ty: u8, flags: u8, id: u32, length: u32, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(HEADER_LEN + payload.len());
+    frame.push(ty);
+    frame.push(flags);
+    frame.extend_from_slice(&id.to_be_bytes());
+    frame.extend_from_slice(&length.to_be_bytes());
+    frame.extend_from_slice(payload);
+    frame
+}
+
+fn mux_closed_error() -> KcpError {
+    KcpError::IoError(io::Error::new(io::ErrorKind::ConnectionAborted, "multiplexed connection closed"))
+}
+
+/// Send side state of a stream, updated by the reader task
+struct SendWindow {
+    state: Mutex<SendWindowState>,
+    notify: Notify,
+}
+
+struct SendWindowState {
+    credit: u32,
+    reset: bool,
+}
+
+impl SendWindow {
+    fn new() -> SendWindow {
+        SendWindow {
+            state: Mutex::new(SendWindowState {
+                credit: INITIAL_WINDOW,
+                reset: false,
+            }),
+            notify: Notify::new(),
+        }
+    }
+
+    fn grant(&self, credit: u32) {
+        let mut state = self.state.lock().unwrap();
+        state.credit = state.credit.saturating_add(credit);
+        self.notify.notify_one();
+    }
+
+    fn reset(&self) {
+        self.state.lock().unwrap().reset = true;
+        self.notify.notify_one();
+    }
+}
+
+struct StreamEntry {
+    // Dropped by the peer's FIN, our side may keep sending
+    data_tx: Option<mpsc::UnboundedSender<Vec<u8>>>,
+    window: Arc<SendWindow>,
+    // Bytes the peer may still send, what we granted minus what arrived
+    recv_credit: u32,
+    fin_sent: bool,
+}
+
+struct MuxShared {
+    streams: Mutex<HashMap<u32, StreamEntry>>,
+    frame_tx: mpsc::Sender<Vec<u8>>,
+    next_id: AtomicU32,
+    closed: AtomicBool,
+}
+
+impl MuxShared {
+    fn register(self: &Arc<Self>, id: u32) -> MuxStream {
+        let (data_tx, data_rx) = mpsc::unbounded_channel();
+        let window = Arc::new(SendWindow::new());
+        self.streams.lock().unwrap().insert(
+            id,
+            StreamEntry {
+                data_tx: Some(data_tx),
+                window: window.clone(),
+                recv_credit: INITIAL_WINDOW,
+                fin_sent: false,
+            },
+        );
+
+        MuxStream {
+            id,
+            shared: self.clone(),
+            window,
+            data_rx,
+            pending: Vec::new(),
+            pending_pos: 0,
+            consumed: 0,
+            fin_sent: false,
+        }
+    }
+
+    // Drop the entry of a stream the peer broke the protocol on, its reader gets `ConnectionReset`
+    fn reset(&self, id: u32) {
+        if let Some(entry) = self.streams.lock().unwrap().remove(&id) {
+            entry.window.reset();
+        }
+        let _ = self.frame_tx.try_send(encode_frame(FRAME_DATA, FLAG_RST, id, 0, &[]));
+    }
+
+    fn close_all(&self) {
+        self.closed.store(true, Ordering::Release);
+        for (_, entry) in self.streams.lock().unwrap().drain() {
+            entry.window.reset();
+        }
+    }
+}
+
+/// Multiplexes many `MuxStream`s over one `KcpStream`
+///
+/// Dropping the muxer closes the connection together with all of its streams.
+pub struct KcpMuxer {
+    shared: Arc<MuxShared>,
+    accept_rx: mpsc::Receiver<MuxStream>,
+    reader_task: JoinHandle<()>,
+    writer_task: JoinHandle<()>,
+}
+
+impl Debug for KcpMuxer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KcpMuxer")
+            .field("streams", &self.shared.streams.lock().unwrap().len())
+            .field("closed", &self.shared.closed.load(Ordering::Acquire))
+            .finish()
+    }
+}
+
+impl Drop for KcpMuxer {
+    fn drop(&mut self) {
+        self.reader_task.abort();
+        self.writer_task.abort();
+        self.shared.close_all();
+    }
+}
+
+impl KcpMuxer {
+    /// Start multiplexing over `stream`, both peers must pick different roles
+    pub fn new(stream: KcpStream, role: MuxRole) -> KcpMuxer {
+        let (reader, writer) = stream.into_split();
+        let (frame_tx, frame_rx) = mpsc::channel(FRAME_QUEUE_SIZE);
+        let (accept_tx, accept_rx) = mpsc::channel(ACCEPT_BACKLOG);
+
+        let shared = Arc::new(MuxShared {
+            streams: Mutex::new(HashMap::new()),
+            frame_tx,
+            next_id: AtomicU32::new(match role {
+                MuxRole::Client => 1,
+                MuxRole::Server => 2,
+            }),
+            closed: AtomicBool::new(false),
+        });
+
+        let reader_task = {
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                if let Err(err) = run_reader(reader, &shared, accept_tx).await {
+                    debug!("[MUX] reader stopped, error: {}", err);
+                }
+                shared.close_all();
+            })
+        };
+
+        let writer_task = {
+            let shared = shared.clone();
+            tokio::spawn(async move {
+                if let Err(err) = run_writer(writer, frame_rx).await {
+                    debug!("[MUX] writer stopped, error: {}", err);
+                }
+                shared.close_all();
+            })
+        };
+
+        KcpMuxer {
+            shared,
+            accept_rx,
+            reader_task,
+            writer_task,
+        }
+    }
+
+    /// Open a new stream, the peer sees it in `accept_stream`
+    pub async fn open_stream(&self) -> KcpResult<MuxStream> {
+        if self.shared.closed.load(Ordering::Acquire) {
+            return Err(mux_closed_error());
+        }
+
+        let id = self.shared.next_id.fetch_add(2, Ordering::Relaxed);
+        let stream = self.shared.register(id);
+        self.shared
+            .frame_tx
+            .send(encode_frame(FRAME_WINDOW_UPDATE, FLAG_SYN, id, 0, &[]))
+            .await
+            .map_err(|_| mux_closed_error())?;
+        Ok(stream)
+    }
+
+    /// Wait for a stream opened by the peer
+    pub async fn accept_stream(&mut self) -> KcpResult<MuxStream> {
+        self.accept_rx.recv().await.ok_or_else(mux_closed_error)
+    }
+}
+
+async fn run_writer(mut writer: KcpWriteHalf, mut frame_rx: mpsc::Receiver<Vec<u8>>) -> KcpResult<()> {
+    while let Some(frame) = frame_rx.recv().await {
+        let mut sent = 0;
+        while sent < frame.len() {
+            sent += writer.send(&frame[sent..]).await?;
+        }
+    }
+    Ok(())
+}
+
+async fn run_reader(
+    mut reader: KcpReadHalf,
+    shared: &Arc<MuxShared>,
+    accept_tx: mpsc::Sender<MuxStream>,
+) -> KcpResult<()> {
+    let mut buffer = vec![0u8; 65536];
+    let mut pending = Vec::new();
+
+    loop {
+        let n = reader.recv(&mut buffer).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        pending.extend_from_slice(&buffer[..n]);
+
+        let mut pos = 0;
+        while pending.len() - pos >= HEADER_LEN {
+            let header = &pending[pos..pos + HEADER_LEN];
+            let ty = header[0];
+            let flags = header[1];
+            let id = u32::from_be_bytes([header[2], header[3], header[4], header[5]]);
+            let length = u32::from_be_bytes([header[6], header[7], header[8], header[9]]);
+
+            let payload_len = if ty == FRAME_DATA { length as usize } else { 0 };
+            if payload_len > MAX_FRAME_PAYLOAD {
+                // Nothing behind the header can be trusted anymore, the whole connection ends
+                debug!("[MUX] stream {} frame of {} bytes exceeds {}", id, length, MAX_FRAME_PAYLOAD);
+                shared.reset(id);
+                return Err(KcpError::IoError(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "multiplexed frame too large",
+                )));
+            }
+            if pending.len() - pos < HEADER_LEN + payload_len {
+                break;
+            }
+            let payload = &pending[pos + HEADER_LEN..pos + HEADER_LEN + payload_len];
+            pos += HEADER_LEN + payload_len;
+
+            handle_frame(shared, &accept_tx, ty, flags, id, length, payload);
+        }
+        pending.drain(..pos);
+    }
+}
+
+fn handle_frame(
+    shared: &Arc<MuxShared>,
+    accept_tx: &mpsc::Sender<MuxStream>,
+    ty: u8,
+    flags: u8,
+    id: u32,
+    length: u32,
+    payload: &[u8],
+) {
+    trace!("[MUX] frame type {} flags {:#x} stream {} length {}", ty, flags, id, length);
+
+    if flags & FLAG_SYN != 0 && !shared.streams.lock().unwrap().contains_key(&id) {
+        let stream = shared.register(id);
+        if accept_tx.try_send(stream).is_err() {
+            debug!("[MUX] accept backlog full, resetting stream {}", id);
+            shared.streams.lock().unwrap().remove(&id);
+            let _ = shared.frame_tx.try_send(encode_frame(FRAME_DATA, FLAG_RST, id, 0, &[]));
+            return;
+        }
+    }
+
+    let mut streams = shared.streams.lock().unwrap();
+    let entry = match streams.get_mut(&id) {
+        Some(entry) => entry,
+        None => {
+            trace!("[MUX] frame for unknown stream {}", id);
+            return;
+        }
+    };
+
+    match ty {
+        FRAME_DATA if !payload.is_empty() => {
+            if payload.len() > entry.recv_credit as usize {
+                debug!("[MUX] stream {} sent {} bytes on {} credit", id, payload.len(), entry.recv_credit);
+                drop(streams);
+                shared.reset(id);
+                return;
+            }
+            entry.recv_credit -= payload.len() as u32;
+            if let Some(ref data_tx) = entry.data_tx {
+                let _ = data_tx.send(payload.to_vec());
+            }
+        }
+        FRAME_WINDOW_UPDATE if length > 0 => entry.window.grant(length),
+        _ => {}
+    }
+
+    if flags & FLAG_RST != 0 {
+        entry.window.reset();
+        streams.remove(&id);
+    } else if flags & FLAG_FIN != 0 {
+        // Dropping the data sender reports EOF once the reader drained what was received, window
+        // updates for our half still need the entry
+        entry.data_tx = None;
+        if entry.fin_sent {
+            streams.remove(&id);
+        }
+    }
+}
+
+/// A logical stream of a `KcpMuxer`
+pub struct MuxStream {
+    id: u32,
+    shared: Arc<MuxShared>,
+    window: Arc<SendWindow>,
+    data_rx: mpsc::UnboundedReceiver<Vec<u8>>,
+    pending: Vec<u8>,
+    pending_pos: usize,
+    consumed: u32,
+    fin_sent: bool,
+}
+
+impl Debug for MuxStream {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MuxStream")
+            .field("id", &self.id)
+            .field("fin_sent", &self.fin_sent)
+            .finish()
+    }
+}
+
+impl Drop for MuxStream {
+    fn drop(&mut self) {
+        if !self.fin_sent {
+            let _ = self
+                .shared
+                .frame_tx
+                .try_send(encode_frame(FRAME_DATA, FLAG_FIN, self.id, 0, &[]));
+        }
+        self.shared.streams.lock().unwrap().remove(&self.id);
+    }
+}
+
+impl MuxStream {
+    /// Stream id, odd for streams opened by the client
+    pub fn id(&self) -> u32 {
+        self.id
+    }
+
+    /// `send` data in `buf`, waits for the peer to grant window when it is exhausted
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        if self.fin_sent {
+            return Err(KcpError::IoError(io::ErrorKind::BrokenPipe.into()));
+        }
+        if buf.is_empty() {
+            return Ok(0);
+        }
+
+        let n = loop {
+            {
+                let mut state = self.window.state.lock().unwrap();
+                if state.reset {
+                    return Err(KcpError::IoError(io::ErrorKind::ConnectionReset.into()));
+                }
+                if state.credit > 0 {
+                    let n = buf.len().min(state.credit as usize).min(MAX_FRAME_PAYLOAD);
+                    state.credit -= n as u32;
+                    break n;
+                }
+            }
+            self.window.notify.notified().await;
+        };
+
+        self.shared
+            .frame_tx
+            .send(encode_frame(FRAME_DATA, 0, self.id, n as u32, &buf[..n]))
+            .await
+            .map_err(|_| mux_closed_error())?;
+        Ok(n)
+    }
+
+    /// `recv` data into `buf`, `Ok(0)` once the peer closed the stream
+    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        if self.pending_pos >= self.pending.len() {
+            match self.data_rx.recv().await {
+                Some(data) => {
+                    self.pending = data;
+                    self.pending_pos = 0;
+                }
+                None => {
+                    if self.window.state.lock().unwrap().reset {
+                        return Err(KcpError::IoError(io::ErrorKind::ConnectionReset.into()));
+                    }
+                    return Ok(0);
+                }
+            }
+        }
+
+        let n = buf.len().min(self.pending.len() - self.pending_pos);
+        buf[..n].copy_from_slice(&self.pending[self.pending_pos..self.pending_pos + n]);
+        self.pending_pos += n;
+
+        // Hand the window back in chunks instead of a frame per read
+        self.consumed += n as u32;
+        if self.consumed >= INITIAL_WINDOW / 2 {
+            let credit = self.consumed;
+            self.consumed = 0;
+            if let Some(entry) = self.shared.streams.lock().unwrap().get_mut(&self.id) {
+                entry.recv_credit = entry.recv_credit.saturating_add(credit);
+            }
+            self.shared
+                .frame_tx
+                .send(encode_frame(FRAME_WINDOW_UPDATE, 0, self.id, credit, &[]))
+                .await
+                .map_err(|_| mux_closed_error())?;
+        }
+        Ok(n)
+    }
+
+    /// Close the sending side, the peer reads EOF after the data sent so far
+    pub async fn close(&mut self) -> KcpResult<()> {
+        if self.fin_sent {
+            return Ok(());
+        }
+        self.fin_sent = true;
+        {
+            let mut streams = self.shared.streams.lock().unwrap();
+            if let Some(entry) = streams.get_mut(&self.id) {
+                // Both halves are closed once the peer's FIN arrived too
+                if entry.data_tx.is_none() {
+                    streams.remove(&self.id);
+                } else {
+                    entry.fin_sent = true;
+                }
+            }
+        }
+        self.shared
+            .frame_tx
+            .send(encode_frame(FRAME_DATA, FLAG_FIN, self.id, 0, &[]))
+            .await
+            .map_err(|_| mux_closed_error())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{KcpConfig, KcpListener};
+
+    // The listener only yields a stream after its first packet, so the client opens a stream first
+    async fn muxer_pair() -> (KcpListener, KcpMuxer, KcpMuxer, MuxStream) {
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let client = KcpMuxer::new(client, MuxRole::Client);
+        let first = client.open_stream().await.unwrap();
+
+        let (server, _) = listener.accept().await.unwrap();
+        let server = KcpMuxer::new(server, MuxRole::Server);
+        (listener, client, server, first)
+    }
+
+    #[tokio::test]
+    async fn mux_independent_streams() {
+        let _ = env_logger::try_init();
+
+        let (_listener, client, mut server, mut audio) = muxer_pair().await;
+
+        let server_hdl = tokio::spawn(async move {
+            for _ in 0..2 {
+                let mut stream = server.accept_stream().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 1024];
+                    loop {
+                        let n = stream.recv(&mut buffer).await.unwrap();
+                        if n == 0 {
+                            break;
+                        }
+                        stream.send(&buffer[..n]).await.unwrap();
+                    }
+                    stream.close().await.unwrap();
+                });
+            }
+            server
+        });
+
+        let mut video = client.open_stream().await.unwrap();
+        assert_eq!(audio.id(), 1);
+        assert_eq!(video.id(), 3);
+
+        let mut buffer = [0u8; 1024];
+        video.send(b"VIDEO").await.unwrap();
+        audio.send(b"AUDIO").await.unwrap();
+        let n = audio.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"AUDIO");
+        let n = video.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"VIDEO");
+
+        audio.close().await.unwrap();
+        assert_eq!(audio.recv(&mut buffer).await.unwrap(), 0);
+        assert!(audio.send(b"AUDIO").await.is_err());
+
+        // Closing one stream leaves the other usable
+        video.send(b"MORE VIDEO").await.unwrap();
+        let n = video.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"MORE VIDEO");
+
+        let _server = server_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mux_flow_control() {
+        let _ = env_logger::try_init();
+
+        let (_listener, client, mut server, mut stalled) = muxer_pair().await;
+
+        let total = INITIAL_WINDOW as usize * 3;
+        let sender_hdl = tokio::spawn(async move {
+            let payload = vec![0xAB; total];
+            let mut sent = 0;
+            while sent < total {
+                sent += stalled.send(&payload[sent..]).await.unwrap();
+            }
+            stalled
+        });
+
+        let mut slow = server.accept_stream().await.unwrap();
+
+        // Sender runs out of window while nobody reads
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+        assert!(!sender_hdl.is_finished());
+
+        // Other streams are not held up by the stalled one
+        let mut control = client.open_stream().await.unwrap();
+        control.send(b"CONTROL").await.unwrap();
+        let mut peer_control = server.accept_stream().await.unwrap();
+        let mut buffer = vec![0u8; 65536];
+        let n = peer_control.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"CONTROL");
+
+        let mut received = 0;
+        while received < total {
+            let n = slow.recv(&mut buffer).await.unwrap();
+            assert!(buffer[..n].iter().all(|&b| b == 0xAB));
+            received += n;
+        }
+        assert_eq!(received, total);
+        let _stalled = sender_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn mux_half_close() {
+        let _ = env_logger::try_init();
+
+        let (_listener, _client, mut server, mut stream) = muxer_pair().await;
+        let mut peer = server.accept_stream().await.unwrap();
+        peer.close().await.unwrap();
+        let mut buffer = vec![0u8; 65536];
+        assert_eq!(stream.recv(&mut buffer).await.unwrap(), 0);
+
+        // The peer's FIN leaves our half open, window updates keep arriving
+        let total = INITIAL_WINDOW as usize * 3;
+        let sender_hdl = tokio::spawn(async move {
+            let payload = vec![0xCD; total];
+            let mut sent = 0;
+            while sent < total {
+                sent += stream.send(&payload[sent..]).await.unwrap();
+            }
+            stream.close().await.unwrap();
+        });
+        let mut received = 0;
+        loop {
+            let n = peer.recv(&mut buffer).await.unwrap();
+            if n == 0 {
+                break;
+            }
+            received += n;
+        }
+        assert_eq!(received, total);
+        sender_hdl.await.unwrap();
+    }
+
+    // Client muxer, its first stream and the raw server side after the stream's SYN
+    async fn raw_peer() -> (KcpListener, KcpMuxer, MuxStream, KcpStream) {
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let client = KcpMuxer::new(client, MuxRole::Client);
+        let stream = client.open_stream().await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut syn = [0u8; HEADER_LEN];
+        assert_eq!(server.recv(&mut syn).await.unwrap(), HEADER_LEN);
+        assert_eq!(syn[1], FLAG_SYN);
+        (listener, client, stream, server)
+    }
+
+    #[tokio::test]
+    async fn mux_credit_exceeded_resets() {
+        let _ = env_logger::try_init();
+
+        let (_listener, _client, mut stream, mut server) = raw_peer().await;
+        let payload = vec![0u8; MAX_FRAME_PAYLOAD];
+        for _ in 0..INITIAL_WINDOW as usize / MAX_FRAME_PAYLOAD {
+            server
+                .send(&encode_frame(FRAME_DATA, 0, stream.id(), payload.len() as u32, &payload))
+                .await
+                .unwrap();
+        }
+        server.send(&encode_frame(FRAME_DATA, 0, stream.id(), 1, &[0])).await.unwrap();
+        // Nothing read yet, so no credit was handed back
+        tokio::time::sleep(std::time::Duration::from_millis(500)).await;
+
+        let mut buffer = vec![0u8; 65536];
+        let mut received = 0;
+        let err = loop {
+            match stream.recv(&mut buffer).await {
+                Ok(n) => received += n,
+                Err(err) => break err,
+            }
+        };
+        assert!(matches!(err, KcpError::IoError(ref err) if err.kind() == io::ErrorKind::ConnectionReset));
+        assert_eq!(received, INITIAL_WINDOW as usize);
+
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(buffer[..2], [FRAME_DATA, FLAG_RST]);
+        assert_eq!(n % HEADER_LEN, 0);
+    }
+
+    #[tokio::test]
+    async fn mux_oversized_frame_closes() {
+        let _ = env_logger::try_init();
+
+        let (_listener, client, mut stream, mut server) = raw_peer().await;
+        server
+            .send(&encode_frame(FRAME_DATA, 0, stream.id(), u32::MAX, &[]))
+            .await
+            .unwrap();
+
+        let mut buffer = [0u8; 16];
+        assert!(stream.recv(&mut buffer).await.is_err());
+        assert!(client.open_stream().await.is_err());
+    }
+}
@@ -0,0 +1,143 @@
+//! Datagram obfuscation
+//!
+//! Scrambles every datagram so middleboxes throttling recognizable KCP traffic see neither the KCP
+//! header nor the magic of SCReAM feedback and the other control packets, random padding blurs the
+//! datagram sizes. It hides, it doesn't protect: `psk` seals the datagram first if set.
+//!
+//! `Obfuscator` is the framing, `SalsaObfuscator` the built-in one:
+//!
+//! ```plain
+//! | nonce (8) | Salsa20(padding length (1) | datagram | padding) |
+//! ```
+//!
+//! Path probes of a `MultipathTransport` stay unscrambled.
+
+use std::fmt::{self, Debug};
+
+use bytes::{BufMut, BytesMut};
+use rand::{Rng, RngCore};
+use salsa20::{
+    cipher::{KeyIvInit, StreamCipher},
+    Salsa20,
+};
+
+const NONCE_LEN: usize = 8;
+
+/// Framing applied to every datagram sent, and undone on every datagram received
+pub trait Obfuscator: Send + Sync + Debug {
+    /// Bytes `obfuscate` adds to a datagram at most, taken from the MTU
+    fn overhead(&self) -> usize;
+
+    /// Append the scrambled `packet` to `out`
+    fn obfuscate(&self, packet: &[u8], out: &mut BytesMut);
+
+    /// The datagram `obfuscate` scrambled into `packet`, `None` if `packet` isn't one
+    fn deobfuscate(&self, packet: &[u8]) -> Option<Vec<u8>>;
+}
+
+/// Salsa20 keystream under a key shared by both peers, with up to `max_padding` random bytes appended
+#[derive(Clone)]
+pub struct SalsaObfuscator {
+    key: [u8; 32],
+    max_padding: u8,
+}
+
+impl SalsaObfuscator {
+    pub fn new(key: [u8; 32], max_padding: u8) -> SalsaObfuscator {
+        SalsaObfuscator { key, max_padding }
+    }
+
+    fn apply_keystream(&self, nonce: &[u8], buf: &mut [u8]) {
+        let mut cipher = Salsa20::new(&self.key.into(), nonce.into());
+        cipher.apply_keystream(buf);
+    }
+}
+
+impl Debug for SalsaObfuscator {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SalsaObfuscator")
+            .field("max_padding", &self.max_padding)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Obfuscator for SalsaObfuscator {
+    fn overhead(&self) -> usize {
+        NONCE_LEN + 1 + self.max_padding as usize
+    }
+
+    fn obfuscate(&self, packet: &[u8], out: &mut BytesMut) {
+        let mut rng = rand::thread_rng();
+        let padding = rng.gen_range(0..=self.max_padding);
+
+        let start = out.len();
+        out.reserve(NONCE_LEN + 1 + packet.len() + padding as usize);
+        out.resize(start + NONCE_LEN, 0);
+        rng.fill_bytes(&mut out[start..]);
+        out.put_u8(padding);
+        out.extend_from_slice(packet);
+        let padding_start = out.len();
+        out.resize(padding_start + padding as usize, 0);
+        rng.fill_bytes(&mut out[padding_start..]);
+
+        let (nonce, payload) = out[start..].split_at_mut(NONCE_LEN);
+        self.apply_keystream(nonce, payload);
+    }
+
+    fn deobfuscate(&self, packet: &[u8]) -> Option<Vec<u8>> {
+        if packet.len() <= NONCE_LEN {
+            return None;
+        }
+        let (nonce, payload) = packet.split_at(NONCE_LEN);
+        let mut revealed = payload.to_vec();
+        self.apply_keystream(nonce, &mut revealed);
+
+        let padding = revealed[0] as usize;
+        if padding > self.max_padding as usize || padding >= revealed.len() {
+            return None;
+        }
+        revealed.truncate(revealed.len() - padding);
+        revealed.remove(0);
+        Some(revealed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn obfuscate_roundtrip() {
+        let obfuscator = SalsaObfuscator::new([7u8; 32], 32);
+        // A KCP header and SCReAM feedback, neither may show up scrambled
+        let feedback = [crate::scream::SCREAM_FEEDBACK_HEADER.to_le_bytes(), [0; 4]].concat();
+        let packets: [&[u8]; 2] = [&[0x44, 0x33, 0x22, 0x11, 81, 0, 0, 1], &feedback];
+
+        for packet in packets {
+            let mut sizes = Vec::new();
+            for _ in 0..64 {
+                let mut out = BytesMut::from(&b"HEADER"[..]);
+                obfuscator.obfuscate(packet, &mut out);
+                assert_eq!(&out[..6], b"HEADER");
+                let scrambled = &out[6..];
+                assert!(scrambled.len() <= packet.len() + obfuscator.overhead());
+                assert!(!scrambled.windows(4).any(|w| w == &packet[..4]));
+                assert_eq!(obfuscator.deobfuscate(scrambled).unwrap(), packet);
+                sizes.push(scrambled.len());
+            }
+            // Random padding, random sizes
+            sizes.dedup();
+            assert!(sizes.len() > 1);
+        }
+
+        // Another key reads garbage, mostly caught by the padding length
+        let sealed = {
+            let mut out = BytesMut::new();
+            obfuscator.obfuscate(b"HELLO KCP", &mut out);
+            out
+        };
+        let other = SalsaObfuscator::new([8u8; 32], 32);
+        assert_ne!(other.deobfuscate(&sealed).as_deref(), Some(&b"HELLO KCP"[..]));
+        assert!(obfuscator.deobfuscate(&sealed[..NONCE_LEN]).is_none());
+    }
+}
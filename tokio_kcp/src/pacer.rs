@@ -1,78 +1,1154 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt::{self, Debug};
+use std::io;
 use std::net::SocketAddr;
-use std::sync::Arc;
-use tokio::net::UdpSocket;
-use tokio::sync::{mpsc, watch};
-use tokio::time::{self, Duration};
-use log::{error, info};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::task::{Context, Poll, Waker};
+use bytes::BytesMut;
+use tokio::sync::{mpsc, watch, Notify};
+use tokio::task;
+use tokio::time::{self, Duration, Instant};
+use serde::{Deserialize, Serialize};
+use tracing::{error, info, trace, trace_span, Instrument, Span};
+
+use crate::{batch, pool::BufferPool, transport::DatagramTransport, KcpConfig};
+
+// Pacing rates below this are treated as 1 KB/s so a stalled controller doesn't freeze the session
+const MIN_PACING_RATE: f64 = 8_000.0;
+// The shared pacer picks up rate changes of its sessions at least this often
+const MAX_SHARED_SLEEP: Duration = Duration::from_millis(20);
+
+/// Byte based token bucket, refilled at the pacing rate
+#[derive(Debug)]
+struct TokenBucket {
+    tokens: f64,
+    max_burst: f64,
+    rate_bytes: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(pacing_rate_bps: f32, max_burst: usize, now: Instant) -> TokenBucket {
+        TokenBucket {
+            tokens: max_burst as f64,
+            max_burst: max_burst as f64,
+            rate_bytes: Self::rate_bytes(pacing_rate_bps),
+            last_refill: now,
+        }
+    }
+
+    fn rate_bytes(pacing_rate_bps: f32) -> f64 {
+        (pacing_rate_bps as f64).max(MIN_PACING_RATE) / 8.0
+    }
+
+    fn set_rate(&mut self, pacing_rate_bps: f32, now: Instant) {
+        // Tokens earned so far are credited at the old rate
+        self.refill(now);
+        self.rate_bytes = Self::rate_bytes(pacing_rate_bps);
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.last_refill).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.rate_bytes).min(self.max_burst);
+        self.last_refill = now;
+    }
+
+    /// Time to wait until a packet of `size` bytes may be sent
+    fn delay_for(&mut self, size: usize, now: Instant) -> Duration {
+        self.refill(now);
+
+        // Packets larger than the burst only wait for a full bucket and borrow the rest
+        let needed = (size as f64).min(self.max_burst);
+        if self.tokens >= needed {
+            return Duration::ZERO;
+        }
+        Duration::from_secs_f64((needed - self.tokens) / self.rate_bytes)
+    }
+
+    fn consume(&mut self, size: usize) {
+        self.tokens -= size as f64;
+    }
+}
+
+/// What happens to packets KCP outputs while the pacer queue is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum PacerQueuePolicy {
+    /// Drop the packet being queued, KCP retransmits it after its RTO
+    #[default]
+    DropNewest,
+    /// Drop the oldest queued packet to make room
+    DropOldest,
+    /// Never drop, `send` stalls until the queue drains below its capacity
+    Block,
+}
+
+/// Occupancy of a session's pacer queue
+///
+/// A queue that stays near `capacity` or a growing `dropped` count means KCP flushes faster than
+/// the pacing rate lets packets out.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PacerState {
+    /// Packets waiting for the pacer
+    pub queued: usize,
+    /// Packets the queue holds before `PacerQueuePolicy` applies
+    pub capacity: usize,
+    /// Packets dropped because the queue was full, since the session started
+    pub dropped: u64,
+}
+
+#[derive(Debug, Default)]
+struct QueueState {
+    // Feedback and ACKs, sent before any queued data
+    urgent: VecDeque<BytesMut>,
+    packets: VecDeque<BytesMut>,
+    closed: bool,
+    // Set once closed, queued packets still going out after it are dropped
+    drain_deadline: Option<Instant>,
+    // The pacer task exited
+    finished: bool,
+    // Packets queued or taken by the pacer task but not handed to the transport yet
+    unsent: usize,
+    // Sender stalled by `PacerQueuePolicy::Block`
+    writer: Option<Waker>,
+    // Waiting for `unsent` to drop to zero
+    drain_waiter: Option<Waker>,
+}
+
+/// Packets waiting for the pacer
+#[derive(Debug)]
+pub(crate) struct PacerQueue {
+    state: Mutex<QueueState>,
+    // Shared by all queues of a `SharedPacer`
+    readable: Arc<Notify>,
+    urgent_readable: Arc<Notify>,
+    capacity: usize,
+    policy: PacerQueuePolicy,
+    dropped: AtomicU64,
+    bytes_sent: AtomicU64,
+    // Packets come back here once they were sent or dropped
+    pool: BufferPool,
+}
+
+impl PacerQueue {
+    /// Queue of up to `capacity` packets, pooled buffers start with room for `buffer_size` bytes
+    pub fn new(capacity: usize, policy: PacerQueuePolicy, buffer_size: usize) -> PacerQueue {
+        PacerQueue::with_notify(capacity, policy, buffer_size, Arc::new(Notify::new()), Arc::new(Notify::new()))
+    }
+
+    fn with_notify(
+        capacity: usize,
+        policy: PacerQueuePolicy,
+        buffer_size: usize,
+        readable: Arc<Notify>,
+        urgent_readable: Arc<Notify>,
+    ) -> PacerQueue {
+        let capacity = capacity.max(1);
+        PacerQueue {
+            state: Mutex::new(QueueState::default()),
+            readable,
+            urgent_readable,
+            capacity,
+            policy,
+            dropped: AtomicU64::new(0),
+            bytes_sent: AtomicU64::new(0),
+            pool: BufferPool::new(capacity, buffer_size),
+        }
+    }
+
+    /// Empty buffer for the next packet
+    pub fn buffer(&self) -> BytesMut {
+        self.pool.take()
+    }
+
+    /// Hand a buffer that won't be queued back to the pool
+    pub fn recycle(&self, buffer: BytesMut) {
+        self.pool.put(buffer);
+    }
+
+    /// Queue `packet`, applying the policy if the queue is full
+    pub fn push(&self, packet: BytesMut) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            drop(state);
+            self.recycle(packet);
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Pacer queue is closed"));
+        }
+
+        if state.packets.len() >= self.capacity {
+            match self.policy {
+                PacerQueuePolicy::DropNewest => {
+                    drop(state);
+                    self.recycle(packet);
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    trace!("pacer queue full, dropped newest packet");
+                    return Ok(());
+                }
+                PacerQueuePolicy::DropOldest => {
+                    if let Some(oldest) = state.packets.pop_front() {
+                        state.unsent -= 1;
+                        self.recycle(oldest);
+                    }
+                    self.dropped.fetch_add(1, Ordering::Relaxed);
+                    trace!("pacer queue full, dropped oldest packet");
+                }
+                // ACKs and retransmits still have to go out, only new data is held back
+                PacerQueuePolicy::Block => {}
+            }
+        }
+
+        state.packets.push_back(packet);
+        state.unsent += 1;
+        drop(state);
+        self.readable.notify_one();
+        Ok(())
+    }
+
+    /// Queue `packet` ahead of all data, it doesn't wait for pacing tokens
+    ///
+    /// The urgent lane holds up to `capacity` packets on its own, beyond that the oldest is dropped.
+    pub fn push_urgent(&self, packet: BytesMut) -> io::Result<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.closed {
+            drop(state);
+            self.recycle(packet);
+            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "Pacer queue is closed"));
+        }
+
+        if state.urgent.len() >= self.capacity {
+            if let Some(oldest) = state.urgent.pop_front() {
+                state.unsent -= 1;
+                self.recycle(oldest);
+            }
+            self.dropped.fetch_add(1, Ordering::Relaxed);
+            trace!("pacer urgent lane full, dropped oldest packet");
+        }
+
+        state.urgent.push_back(packet);
+        state.unsent += 1;
+        drop(state);
+        self.readable.notify_one();
+        self.urgent_readable.notify_one();
+        Ok(())
+    }
+
+    fn try_pop_urgent(&self) -> Option<BytesMut> {
+        self.state.lock().unwrap().urgent.pop_front()
+    }
+
+    // Size of the next data packet
+    fn peek_len(&self) -> Option<usize> {
+        self.state.lock().unwrap().packets.front().map(BytesMut::len)
+    }
+
+    fn try_pop(&self) -> Option<BytesMut> {
+        let mut state = self.state.lock().unwrap();
+        let packet = state.packets.pop_front();
+        if state.packets.len() < self.capacity {
+            if let Some(waker) = state.writer.take() {
+                waker.wake();
+            }
+        }
+        packet
+    }
+
+    /// Wait until either lane has a packet, `false` once the queue is closed and drained
+    async fn readable(&self) -> bool {
+        loop {
+            {
+                let state = self.state.lock().unwrap();
+                if !state.urgent.is_empty() || !state.packets.is_empty() {
+                    return true;
+                }
+                if state.closed {
+                    return false;
+                }
+            }
+            self.readable.notified().await;
+        }
+    }
+
+    /// Ready if new data may be queued, always with the dropping policies
+    pub fn poll_writable(&self, cx: &mut Context<'_>) -> Poll<()> {
+        if self.policy != PacerQueuePolicy::Block {
+            return Poll::Ready(());
+        }
+        let mut state = self.state.lock().unwrap();
+        if state.closed || state.packets.len() < self.capacity {
+            return Poll::Ready(());
+        }
+        state.writer = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    /// Packets waiting for the pacer, in both lanes
+    pub fn queued(&self) -> usize {
+        let state = self.state.lock().unwrap();
+        state.urgent.len() + state.packets.len()
+    }
+
+    /// Packets dropped because the queue was full
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    pub fn state(&self) -> PacerState {
+        PacerState {
+            queued: self.queued(),
+            capacity: self.capacity,
+            dropped: self.dropped(),
+        }
+    }
+
+    /// Bytes handed to the transport by the pacer
+    pub fn bytes_sent(&self) -> u64 {
+        self.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Ready once every queued packet was handed to the transport, or dropped when the pacer task exited
+    pub fn poll_drained(&self, cx: &mut Context<'_>) -> Poll<()> {
+        let mut state = self.state.lock().unwrap();
+        if state.unsent == 0 || state.finished {
+            return Poll::Ready(());
+        }
+        state.drain_waiter = Some(cx.waker().clone());
+        Poll::Pending
+    }
+
+    // `count` packets taken from the queue went out
+    fn sent(&self, count: usize) {
+        let mut state = self.state.lock().unwrap();
+        state.unsent = state.unsent.saturating_sub(count);
+        if state.unsent == 0 {
+            if let Some(waker) = state.drain_waiter.take() {
+                waker.wake();
+            }
+        }
+    }
+
+    /// Take the next packet like the pacer does once it went out, returns its length
+    #[cfg(feature = "bench")]
+    pub fn pop_sent(&self) -> Option<usize> {
+        let packet = self.try_pop()?;
+        let len = packet.len();
+        self.sent(1);
+        self.recycle(packet);
+        Some(len)
+    }
+
+    // Refuse new packets, the queued ones may still go out for `linger`
+    fn close(&self, linger: Duration) {
+        let mut state = self.state.lock().unwrap();
+        if !state.closed {
+            state.closed = true;
+            state.drain_deadline = Some(Instant::now() + linger);
+        }
+        if let Some(waker) = state.writer.take() {
+            waker.wake();
+        }
+        drop(state);
+        self.readable.notify_one();
+    }
+
+    // Closed and past the time to drain the queue
+    fn drain_expired(&self, now: Instant) -> bool {
+        self.state.lock().unwrap().drain_deadline.is_some_and(|deadline| now >= deadline)
+    }
+
+    fn drain_deadline(&self) -> Option<Instant> {
+        self.state.lock().unwrap().drain_deadline
+    }
+
+    // Closed and drained, or out of time to drain
+    fn retired(&self, now: Instant) -> bool {
+        let state = self.state.lock().unwrap();
+        state.closed
+            && ((state.urgent.is_empty() && state.packets.is_empty())
+                || state.drain_deadline.is_some_and(|deadline| now >= deadline))
+    }
+
+    // The pacer task exits, whatever is left won't be sent
+    fn finish(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.closed = true;
+        state.finished = true;
+        let mut left: Vec<BytesMut> = state.urgent.drain(..).collect();
+        left.extend(state.packets.drain(..));
+        if let Some(waker) = state.writer.take() {
+            waker.wake();
+        }
+        if let Some(waker) = state.drain_waiter.take() {
+            waker.wake();
+        }
+        drop(state);
+        if !left.is_empty() {
+            info!("Pacer task exits with {} packets unsent.", left.len());
+        }
+        for packet in left {
+            self.recycle(packet);
+        }
+    }
+}
+
+// Hand `batch` to the transport and return its buffers to the pool
+async fn send(
+    transport: &dyn DatagramTransport,
+    queue: &PacerQueue,
+    batch: &mut Vec<BytesMut>,
+    target_addr: SocketAddr,
+) {
+    let send_span = trace_span!("pacer_send", packets = batch.len());
+    match batch::send_batch(transport, batch, target_addr).instrument(send_span).await {
+        Ok(()) => {
+            let bytes: usize = batch.iter().map(BytesMut::len).sum();
+            queue.bytes_sent.fetch_add(bytes as u64, Ordering::Relaxed);
+        }
+        Err(e) => error!("UDP send_to failed: {}", e),
+    }
+    queue.sent(batch.len());
+    for packet in batch.drain(..) {
+        queue.recycle(packet);
+    }
+}
+
+// Send the whole urgent lane right away, its packets still use up tokens so data backs off.
+// Returns the bytes sent.
+async fn send_urgent(
+    transport: &dyn DatagramTransport,
+    queue: &PacerQueue,
+    bucket: &mut TokenBucket,
+    batch: &mut Vec<BytesMut>,
+    batch_size: usize,
+    target_addr_rx: &watch::Receiver<SocketAddr>,
+) -> usize {
+    let mut bytes = 0;
+    while let Some(packet) = queue.try_pop_urgent() {
+        bucket.consume(packet.len());
+        bytes += packet.len();
+        batch.push(packet);
+        if batch.len() >= batch_size {
+            let target_addr = *target_addr_rx.borrow();
+            send(transport, queue, batch, target_addr).await;
+        }
+    }
+    if !batch.is_empty() {
+        let target_addr = *target_addr_rx.borrow();
+        send(transport, queue, batch, target_addr).await;
+    }
+    bytes
+}
+
+// Sleep until `deadline`, with `spin` the last stretch is busy-waited to get below the timer's
+// millisecond granularity
+async fn sleep_until(deadline: Instant, spin: Option<Duration>) {
+    let spin = match spin {
+        Some(spin) => spin,
+        None => return time::sleep_until(deadline).await,
+    };
+    if let Some(coarse) = deadline.checked_sub(spin) {
+        time::sleep_until(coarse).await;
+    }
+    while Instant::now() < deadline {
+        // Other tasks on this worker still get to run
+        task::yield_now().await;
+        std::hint::spin_loop();
+    }
+}
 
 pub struct PacketPacer {
-    pub(crate) packet_tx: mpsc::Sender<Vec<u8>>,
+    pub(crate) queue: Arc<PacerQueue>,
+    linger: Duration,
+}
+
+impl Drop for PacketPacer {
+    fn drop(&mut self) {
+        self.shutdown();
+    }
 }
 
 impl PacketPacer {
     pub fn new(
-        socket: Arc<UdpSocket>,
-        target_addr: SocketAddr,
-        mut pacing_rate_rx: watch::Receiver<f32>,
-
+        transport: Arc<dyn DatagramTransport>,
+        target_addr_rx: watch::Receiver<SocketAddr>,
+        pacing_rate_rx: watch::Receiver<f32>,
+        queue: Arc<PacerQueue>,
+        config: &KcpConfig,
+        span: Span,
     ) -> Self {
-        let (packet_tx, mut packet_rx) = mpsc::channel::<Vec<u8>>(256);
+        if let Some(ref shared) = config.shared_pacer {
+            shared.register(Flow {
+                transport,
+                target_addr_rx,
+                pacing_rate_rx,
+                queue: queue.clone(),
+                bucket: TokenBucket::new(0.0, config.pacing_burst, Instant::now()),
+                span,
+            });
+            return Self {
+                queue,
+                linger: config.linger,
+            };
+        }
+
+        let packet_rx = queue.clone();
+        let max_burst = config.pacing_burst;
+        let batch_size = config.udp_batch_size;
+        let spin = config.pacing_spin;
+        let linger = config.linger;
 
         tokio::spawn(async move {
             let mut pacing_rate_rx = pacing_rate_rx.clone();
-            let mut pacing_rate = *pacing_rate_rx.borrow();
-            let mut interval = Self::calculate_interval(pacing_rate);
-            let mut timer = time::interval(interval);
-            timer.tick().await;
-
-            loop {
-                tokio::select! {
-                    biased;
-                    Ok(()) = pacing_rate_rx.changed() => {
-                        pacing_rate = *pacing_rate_rx.borrow_and_update();
-                        interval = Self::calculate_interval(pacing_rate);
-                        timer.reset(); 
-                        info!("Pacing rate updated to {} bps, interval is now {:?}.", pacing_rate, interval);
-                    }
+            let pacing_rate = *pacing_rate_rx.borrow();
+            let mut bucket = TokenBucket::new(pacing_rate, max_burst, Instant::now());
+            let batch_size = batch_size.max(1);
+            let mut batch = Vec::with_capacity(batch_size);
+            // Dequeued while filling the previous batch, but not yet allowed out
+            let mut held = None;
 
-                    
-                    _ = timer.tick() => {
-                        match packet_rx.try_recv() {
-                            Ok(packet) => {
-                                if let Err(e) = socket.send_to(&packet, target_addr).await {
-                                    error!("UDP send_to failed: {}", e);
-                                }
-                            }
-                            Err(mpsc::error::TryRecvError::Empty) => {},
-                            Err(mpsc::error::TryRecvError::Disconnected) => {
-                                info!("Packet channel disconnected, pacer task is shutting down.");
+            'pacer: loop {
+                if packet_rx.drain_expired(Instant::now()) {
+                    break;
+                }
+                send_urgent(&*transport, &packet_rx, &mut bucket, &mut batch, batch_size, &target_addr_rx).await;
+
+                let packet = match held.take().or_else(|| packet_rx.try_pop()) {
+                    Some(packet) => packet,
+                    None => tokio::select! {
+                        biased;
+                        Ok(()) = pacing_rate_rx.changed() => {
+                            let pacing_rate = *pacing_rate_rx.borrow_and_update();
+                            bucket.set_rate(pacing_rate, Instant::now());
+                            info!("Pacing rate updated to {} bps.", pacing_rate);
+                            continue;
+                        }
+
+                        readable = packet_rx.readable() => {
+                            if !readable {
+                                info!("Packet queue closed, pacer task is shutting down.");
                                 break;
                             }
+                            continue;
+                        }
+                    },
+                };
+
+                // Wait for enough tokens, rate changes shorten or extend the wait and urgent packets
+                // go out meanwhile
+                loop {
+                    let now = Instant::now();
+                    let delay = bucket.delay_for(packet.len(), now);
+                    if delay.is_zero() {
+                        break;
+                    }
+                    // A closed queue only drains until its deadline
+                    let delay = match packet_rx.drain_deadline() {
+                        Some(deadline) if now >= deadline => break 'pacer,
+                        Some(deadline) => delay.min(deadline - now),
+                        None => delay,
+                    };
+
+                    tokio::select! {
+                        biased;
+                        Ok(()) = pacing_rate_rx.changed() => {
+                            let pacing_rate = *pacing_rate_rx.borrow_and_update();
+                            bucket.set_rate(pacing_rate, Instant::now());
+                        }
+                        _ = packet_rx.urgent_readable.notified() => {
+                            send_urgent(&*transport, &packet_rx, &mut bucket, &mut batch, batch_size, &target_addr_rx)
+                                .await;
                         }
+                        _ = sleep_until(Instant::now() + delay, spin) => {}
                     }
                 }
+
+                bucket.consume(packet.len());
+                batch.push(packet);
+
+                // Packets already queued go out in the same syscall as long as the bucket allows
+                while batch.len() < batch_size {
+                    let packet = match packet_rx.try_pop() {
+                        Some(packet) => packet,
+                        None => break,
+                    };
+                    if !bucket.delay_for(packet.len(), Instant::now()).is_zero() {
+                        held = Some(packet);
+                        break;
+                    }
+                    bucket.consume(packet.len());
+                    batch.push(packet);
+                }
+
+                // Follows the peer when it migrates to another address
+                let target_addr = *target_addr_rx.borrow();
+                send(&*transport, &packet_rx, &mut batch, target_addr).await;
+            }
+            packet_rx.finish();
+        }.instrument(span));
+
+        Self { queue, linger }
+    }
+
+    /// Stop taking packets, the task sends what is queued at the pacing rate for at most
+    /// `KcpConfig::linger` and exits
+    pub fn shutdown(&self) {
+        self.queue.close(self.linger);
+    }
+}
+
+/// One pacer task for the sessions of many streams, set as `KcpConfig::shared_pacer`
+///
+/// Every session keeps its own queue and is paced at its own rate, the task sends for all of them
+/// with a single timer. `rate_cap` bounds their sum, e.g. to the rate of the interface. The task is
+/// spawned with the first session and ends with the last clone of the `SharedPacer`.
+/// `KcpConfig::pacing_spin` doesn't apply.
+#[derive(Clone)]
+pub struct SharedPacer {
+    inner: Arc<SharedPacerInner>,
+}
+
+struct SharedPacerInner {
+    rate_cap: Option<f32>,
+    max_burst: usize,
+    batch_size: usize,
+    // Wakes the task on packets of any session
+    readable: Arc<Notify>,
+    flow_tx: mpsc::UnboundedSender<Flow>,
+    // Taken when the task is spawned
+    flow_rx: Mutex<Option<mpsc::UnboundedReceiver<Flow>>>,
+    bytes_sent: AtomicU64,
+}
+
+impl Debug for SharedPacer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SharedPacer")
+            .field("rate_cap", &self.inner.rate_cap)
+            .field("bytes_sent", &self.bytes_sent())
+            .finish()
+    }
+}
+
+impl SharedPacer {
+    /// Pacer whose sessions together send at most `rate_cap` bps in bursts of up to `max_burst` bytes,
+    /// `None` only paces every session at its own rate. Up to `batch_size` datagrams of a session go
+    /// out per syscall.
+    pub fn new(rate_cap: Option<f32>, max_burst: usize, batch_size: usize) -> SharedPacer {
+        let (flow_tx, flow_rx) = mpsc::unbounded_channel();
+        SharedPacer {
+            inner: Arc::new(SharedPacerInner {
+                rate_cap,
+                max_burst,
+                batch_size: batch_size.max(1),
+                readable: Arc::new(Notify::new()),
+                flow_tx,
+                flow_rx: Mutex::new(Some(flow_rx)),
+                bytes_sent: AtomicU64::new(0),
+            }),
+        }
+    }
+
+    /// Bytes handed to the transports of all sessions
+    pub fn bytes_sent(&self) -> u64 {
+        self.inner.bytes_sent.load(Ordering::Relaxed)
+    }
+
+    /// Queue of a session paced by this pacer
+    pub(crate) fn queue(&self, capacity: usize, policy: PacerQueuePolicy, buffer_size: usize) -> PacerQueue {
+        let readable = self.inner.readable.clone();
+        PacerQueue::with_notify(capacity, policy, buffer_size, readable.clone(), readable)
+    }
+
+    fn register(&self, flow: Flow) {
+        if let Some(flow_rx) = self.inner.flow_rx.lock().unwrap().take() {
+            let inner = Arc::downgrade(&self.inner);
+            tokio::spawn(run_shared(
+                inner,
+                flow_rx,
+                self.inner.readable.clone(),
+                self.inner.rate_cap,
+                self.inner.max_burst,
+                self.inner.batch_size,
+            ));
+        }
+        let _ = self.inner.flow_tx.send(flow);
+        self.inner.readable.notify_one();
+    }
+}
+
+// A session paced by a `SharedPacer`
+struct Flow {
+    transport: Arc<dyn DatagramTransport>,
+    target_addr_rx: watch::Receiver<SocketAddr>,
+    pacing_rate_rx: watch::Receiver<f32>,
+    queue: Arc<PacerQueue>,
+    bucket: TokenBucket,
+    span: Span,
+}
+
+impl Flow {
+    fn update_rate(&mut self, now: Instant) {
+        if self.pacing_rate_rx.has_changed().unwrap_or(false) {
+            let pacing_rate = *self.pacing_rate_rx.borrow_and_update();
+            self.bucket.set_rate(pacing_rate, now);
+            trace!(parent: &self.span, "Pacing rate updated to {} bps.", pacing_rate);
+        }
+    }
+
+    // Send the data packets the session's and the shared bucket allow, up to one batch. Returns when
+    // the next packet may go out, `None` with nothing queued.
+    async fn send_data(
+        &mut self,
+        cap: &mut Option<TokenBucket>,
+        batch: &mut Vec<BytesMut>,
+        batch_size: usize,
+    ) -> Option<Instant> {
+        let now = Instant::now();
+        let mut next = None;
+        while let Some(len) = self.queue.peek_len() {
+            let mut delay = self.bucket.delay_for(len, now);
+            if let Some(cap) = cap {
+                delay = delay.max(cap.delay_for(len, now));
+            }
+            if !delay.is_zero() {
+                next = Some(now + delay);
+                break;
+            }
+            if batch.len() >= batch_size {
+                next = Some(now);
+                break;
+            }
+            let Some(packet) = self.queue.try_pop() else {
+                break;
+            };
+            self.bucket.consume(packet.len());
+            if let Some(cap) = cap {
+                cap.consume(packet.len());
+            }
+            batch.push(packet);
+        }
+        if !batch.is_empty() {
+            let target_addr = *self.target_addr_rx.borrow();
+            send(&*self.transport, &self.queue, batch, target_addr).instrument(self.span.clone()).await;
+        }
+        next
+    }
+}
+
+async fn run_shared(
+    inner: Weak<SharedPacerInner>,
+    mut flow_rx: mpsc::UnboundedReceiver<Flow>,
+    readable: Arc<Notify>,
+    rate_cap: Option<f32>,
+    max_burst: usize,
+    batch_size: usize,
+) {
+    let mut cap = rate_cap.map(|rate| TokenBucket::new(rate, max_burst, Instant::now()));
+    let mut flows: Vec<Flow> = Vec::new();
+    let mut batch = Vec::with_capacity(batch_size);
+    // Sessions take turns being first
+    let mut first = 0;
+
+    loop {
+        while let Ok(mut flow) = flow_rx.try_recv() {
+            let pacing_rate = *flow.pacing_rate_rx.borrow_and_update();
+            flow.bucket.set_rate(pacing_rate, Instant::now());
+            flows.push(flow);
+        }
+        if flows.is_empty() {
+            match flow_rx.recv().await {
+                Some(flow) => {
+                    flows.push(flow);
+                    continue;
+                }
+                None => break,
+            }
+        }
+
+        // Packets queued from here on wake the wait below
+        let notified = readable.notified();
+        tokio::pin!(notified);
+        notified.as_mut().enable();
+
+        let mut sent = 0;
+        let now = Instant::now();
+        for flow in &mut flows {
+            flow.update_rate(now);
+            let bytes = send_urgent(
+                &*flow.transport,
+                &flow.queue,
+                &mut flow.bucket,
+                &mut batch,
+                batch_size,
+                &flow.target_addr_rx,
+            )
+            .await;
+            if let Some(ref mut cap) = cap {
+                cap.consume(bytes);
+            }
+            sent += bytes;
+        }
+
+        let mut next: Option<Instant> = None;
+        first = (first + 1) % flows.len();
+        for i in 0..flows.len() {
+            let index = (first + i) % flows.len();
+            let before = flows[index].queue.bytes_sent();
+            if let Some(at) = flows[index].send_data(&mut cap, &mut batch, batch_size).await {
+                next = Some(next.map_or(at, |next| next.min(at)));
+            }
+            sent += (flows[index].queue.bytes_sent() - before) as usize;
+        }
+        if let Some(inner) = inner.upgrade() {
+            inner.bytes_sent.fetch_add(sent as u64, Ordering::Relaxed);
+        }
+
+        let now = Instant::now();
+        flows.retain(|flow| {
+            if flow.queue.retired(now) {
+                flow.queue.finish();
+                return false;
             }
+            true
         });
 
-        Self { packet_tx }
+        let deadline = next.map_or(now + MAX_SHARED_SLEEP, |next| next.min(now + MAX_SHARED_SLEEP));
+        if deadline <= now {
+            continue;
+        }
+        tokio::select! {
+            _ = notified => {}
+            _ = time::sleep_until(deadline) => {}
+        }
+    }
+    info!("Shared pacer has no sessions left, shutting down.");
+}
+
+/// Pacing rates of the sessions of a listener, scaled down proportionally while the sum of their
+/// demands exceeds `cap`
+///
+/// A session's demand is its pacing rate while it has a backlog and what it recently sent otherwise,
+/// idle and application limited sessions don't hold back the busy ones.
+#[derive(Debug)]
+pub(crate) struct RateBudget {
+    cap: f32,
+    state: Mutex<BudgetState>,
+}
+
+#[derive(Debug, Default)]
+struct BudgetState {
+    demands: HashMap<u64, f32>,
+    total: f64,
+    next_id: u64,
+}
+
+impl RateBudget {
+    pub(crate) fn new(cap: f32) -> Arc<RateBudget> {
+        Arc::new(RateBudget {
+            cap,
+            state: Mutex::new(BudgetState::default()),
+        })
+    }
+
+    /// Share of a new session, released when dropped
+    pub(crate) fn share(self: &Arc<RateBudget>) -> RateShare {
+        let mut state = self.state.lock().unwrap();
+        let id = state.next_id;
+        state.next_id += 1;
+        state.demands.insert(id, 0.0);
+        RateShare { budget: self.clone(), id }
+    }
+}
+
+/// A session's part of a `RateBudget`
+#[derive(Debug)]
+pub(crate) struct RateShare {
+    budget: Arc<RateBudget>,
+    id: u64,
+}
+
+impl RateShare {
+    /// Record the session's demand and return the rate to pace at instead of the controller's `rate`
+    pub(crate) fn update(&self, rate: f32, demand: f32) -> f32 {
+        let demand = demand.clamp(0.0, rate);
+        let mut state = self.budget.state.lock().unwrap();
+        let previous = state.demands.insert(self.id, demand).unwrap_or(0.0);
+        state.total = (state.total + demand as f64 - previous as f64).max(0.0);
+        if state.total > self.budget.cap as f64 {
+            (rate as f64 * self.budget.cap as f64 / state.total) as f32
+        } else {
+            rate
+        }
+    }
+}
+
+impl Drop for RateShare {
+    fn drop(&mut self) {
+        let mut state = self.budget.state.lock().unwrap();
+        if let Some(demand) = state.demands.remove(&self.id) {
+            state.total = (state.total - demand as f64).max(0.0);
+        }
+        if state.demands.is_empty() {
+            // Clear the rounding errors of the running sum
+            state.total = 0.0;
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn packet(i: u8) -> BytesMut {
+        BytesMut::from(&[i][..])
+    }
+
+    #[test]
+    fn bucket_allows_burst() {
+        let now = Instant::now();
+        // 80 kbps = 10 KB/s
+        let mut bucket = TokenBucket::new(80_000.0, 3000, now);
+
+        for _ in 0..3 {
+            assert!(bucket.delay_for(1000, now).is_zero());
+            bucket.consume(1000);
+        }
+        assert_eq!(bucket.delay_for(500, now), Duration::from_millis(50));
+        assert!(bucket.delay_for(500, now + Duration::from_millis(50)).is_zero());
+    }
+
+    #[test]
+    fn bucket_paces_by_packet_size() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(80_000.0, 1000, now);
+        bucket.consume(1000);
+
+        // small packets wait proportionally less
+        assert_eq!(bucket.delay_for(100, now), Duration::from_millis(10));
+        assert_eq!(bucket.delay_for(1000, now), Duration::from_millis(100));
+
+        // refill never exceeds the burst
+        assert!(bucket.delay_for(1000, now + Duration::from_secs(10)).is_zero());
+        bucket.consume(1000);
+        assert_eq!(bucket.delay_for(1000, now + Duration::from_secs(10)), Duration::from_millis(100));
+    }
+
+    #[test]
+    fn queue_drop_policies() {
+        let queue = PacerQueue::new(2, PacerQueuePolicy::DropNewest, 1);
+        for i in 0..3u8 {
+            queue.push(packet(i)).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(
+            queue.state(),
+            PacerState {
+                queued: 2,
+                capacity: 2,
+                dropped: 1
+            }
+        );
+        assert_eq!(queue.try_pop(), Some(packet(0)));
+        assert_eq!(queue.pool.available(), 1);
+
+        let queue = PacerQueue::new(2, PacerQueuePolicy::DropOldest, 1);
+        for i in 0..3u8 {
+            queue.push(packet(i)).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.try_pop(), Some(packet(1)));
+        assert_eq!(queue.pool.available(), 1);
+
+        queue.close(Duration::ZERO);
+        assert!(queue.push(packet(3)).is_err());
+    }
+
+    #[test]
+    fn queue_block_policy() {
+        let queue = PacerQueue::new(1, PacerQueuePolicy::Block, 1);
+        let waker = futures_util::task::noop_waker();
+        let mut cx = Context::from_waker(&waker);
+
+        queue.push(packet(0)).unwrap();
+        assert!(queue.poll_writable(&mut cx).is_pending());
+        // Nothing is dropped, KCP's own output always gets queued
+        queue.push(packet(1)).unwrap();
+        assert_eq!(queue.dropped(), 0);
+
+        queue.try_pop();
+        assert!(queue.poll_writable(&mut cx).is_pending());
+        queue.try_pop();
+        assert!(queue.poll_writable(&mut cx).is_ready());
     }
 
-    pub async fn send(&self, packet: Vec<u8>) -> Result<(), mpsc::error::SendError<Vec<u8>>> {
-        self.packet_tx.send(packet).await
+    #[test]
+    fn queue_urgent_lane() {
+        let queue = PacerQueue::new(2, PacerQueuePolicy::DropNewest, 1);
+        queue.push(packet(0)).unwrap();
+        queue.push(packet(1)).unwrap();
+        // A full data queue doesn't hold back feedback
+        queue.push_urgent(packet(2)).unwrap();
+        assert_eq!(queue.dropped(), 0);
+        assert_eq!(queue.queued(), 3);
+
+        assert_eq!(queue.try_pop_urgent(), Some(packet(2)));
+        assert_eq!(queue.try_pop_urgent(), None);
+        assert_eq!(queue.try_pop(), Some(packet(0)));
+
+        for i in 3..6u8 {
+            queue.push_urgent(packet(i)).unwrap();
+        }
+        assert_eq!(queue.dropped(), 1);
+        assert_eq!(queue.try_pop_urgent(), Some(packet(4)));
     }
 
-    fn calculate_interval(pacing_rate_bps: f32) -> Duration {
-        if pacing_rate_bps < 1.0 {
-            return Duration::from_secs(1);
+    #[tokio::test]
+    async fn spin_below_timer_granularity() {
+        let start = Instant::now();
+        let mut deadline = start;
+        for _ in 0..20 {
+            deadline += Duration::from_micros(200);
+            sleep_until(deadline, Some(Duration::from_millis(1))).await;
+            assert!(Instant::now() >= deadline);
         }
+        // The timer alone rounds every 200 µs sleep up to at least a millisecond
+        assert!(start.elapsed() < Duration::from_millis(20));
+    }
+
+    // Pacer sending 1000 byte packets at 20 KB/s into a simulated network
+    fn sim_pacer(linger: Duration) -> (PacketPacer, Arc<PacerQueue>, Arc<crate::SimSocket>) {
+        let network = crate::SimNetwork::new(crate::SimLinkConfig::default(), 1);
+        let socket = network.bind("127.0.0.1:1".parse().unwrap()).unwrap();
+        let config = KcpConfig {
+            pacing_burst: 1000,
+            linger,
+            ..KcpConfig::default()
+        };
+        let queue = Arc::new(PacerQueue::new(32, PacerQueuePolicy::DropNewest, 1000));
+        let (_, target_addr_rx) = watch::channel("127.0.0.1:2".parse().unwrap());
+        let (_, pacing_rate_rx) = watch::channel(160_000.0);
+        let pacer =
+            PacketPacer::new(socket.clone(), target_addr_rx, pacing_rate_rx, queue.clone(), &config, Span::none());
+        (pacer, queue, socket)
+    }
+
+    #[tokio::test]
+    async fn shutdown_drains_at_pacing_rate() {
+        let (pacer, queue, socket) = sim_pacer(Duration::from_secs(5));
+        for i in 0..5u8 {
+            queue.push(BytesMut::from(&[i; 1000][..])).unwrap();
+        }
+        let start = Instant::now();
+        pacer.shutdown();
+        assert!(queue.push(packet(5)).is_err());
+
+        std::future::poll_fn(|cx| queue.poll_drained(cx)).await;
+        assert_eq!(socket.sent(), 5);
+        assert!(start.elapsed() >= Duration::from_millis(150));
+
+        // The task is gone once the queue is empty
+        time::sleep(Duration::from_millis(20)).await;
+        assert!(queue.state.lock().unwrap().finished);
+    }
+
+    #[tokio::test]
+    async fn shutdown_drops_what_outlasts_linger() {
+        let (pacer, queue, socket) = sim_pacer(Duration::from_millis(100));
+        for i in 0..20u8 {
+            queue.push(BytesMut::from(&[i; 1000][..])).unwrap();
+        }
+        let start = Instant::now();
+        drop(pacer);
+
+        std::future::poll_fn(|cx| queue.poll_drained(cx)).await;
+        assert!(start.elapsed() < Duration::from_millis(500));
+        assert!(socket.sent() < 20);
+        assert_eq!(queue.queued(), 0);
+    }
 
-        //                                             MSS = 1000
-        let packets_per_second = pacing_rate_bps / (1000.0 * 8.0);
-        if packets_per_second < 1.0 {
-            return Duration::from_secs(1);
+    #[tokio::test]
+    async fn shared_pacer_caps_aggregate_rate() {
+        async fn send_both(rate_cap: Option<f32>) -> (Duration, u64) {
+            let shared = SharedPacer::new(rate_cap, 1000, 1);
+            let network = crate::SimNetwork::new(crate::SimLinkConfig::default(), 1);
+            let config = KcpConfig {
+                pacing_burst: 1000,
+                shared_pacer: Some(shared.clone()),
+                ..KcpConfig::default()
+            };
+            let mut pacers = Vec::new();
+            for port in 1..=2 {
+                let socket = network.bind(SocketAddr::from(([127, 0, 0, 1], port))).unwrap();
+                let queue = Arc::new(shared.queue(32, PacerQueuePolicy::DropNewest, 1000));
+                let (_, target_addr_rx) = watch::channel("127.0.0.1:9".parse().unwrap());
+                let (_, pacing_rate_rx) = watch::channel(160_000.0);
+                for i in 0..5u8 {
+                    queue.push(BytesMut::from(&[i; 1000][..])).unwrap();
+                }
+                let pacer =
+                    PacketPacer::new(socket, target_addr_rx, pacing_rate_rx, queue.clone(), &config, Span::none());
+                pacers.push((pacer, queue));
+            }
+
+            let start = Instant::now();
+            for (_, queue) in &pacers {
+                std::future::poll_fn(|cx| queue.poll_drained(cx)).await;
+            }
+            (start.elapsed(), shared.bytes_sent())
         }
 
-        let interval_seconds = 1.0 / packets_per_second;
-        Duration::from_secs_f32(interval_seconds)
+        // Each session at 20 KB/s on its own, together at 20 KB/s under the cap
+        let (uncapped, bytes_sent) = send_both(None).await;
+        assert_eq!(bytes_sent, 10_000);
+        let (capped, _) = send_both(Some(160_000.0)).await;
+        assert!(uncapped < Duration::from_millis(350), "{:?}", uncapped);
+        assert!(capped >= Duration::from_millis(400), "{:?}", capped);
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn bucket_rate_change() {
+        let now = Instant::now();
+        let mut bucket = TokenBucket::new(80_000.0, 1000, now);
+        bucket.consume(1000);
+
+        bucket.set_rate(160_000.0, now + Duration::from_millis(50));
+        // 500 bytes earned at the old rate, 500 more at 20 KB/s
+        assert_eq!(bucket.delay_for(1000, now + Duration::from_millis(50)), Duration::from_millis(25));
+    }
+
+    #[test]
+    fn rate_budget_scales_proportionally() {
+        let budget = RateBudget::new(100_000_000.0);
+        let a = budget.share();
+        let b = budget.share();
+
+        assert_eq!(a.update(30_000_000.0, 30_000_000.0), 30_000_000.0);
+        assert_eq!(b.update(50_000_000.0, 50_000_000.0), 50_000_000.0);
+        // 30 + 120 Mbps asked for, both get two thirds
+        assert_eq!(b.update(120_000_000.0, 120_000_000.0), 80_000_000.0);
+        assert_eq!(a.update(30_000_000.0, 30_000_000.0), 20_000_000.0);
+
+        // The cap is free again once the other session is gone
+        drop(b);
+        assert_eq!(a.update(120_000_000.0, 120_000_000.0), 100_000_000.0);
+        assert_eq!(a.update(60_000_000.0, 60_000_000.0), 60_000_000.0);
+    }
+
+    #[test]
+    fn rate_budget_weights_by_demand() {
+        let budget = RateBudget::new(100_000_000.0);
+        let busy = budget.share();
+        let idle = budget.share();
+        let limited = budget.share();
+
+        // Neither the idle session nor the one sending 10 of its 80 Mbps hold back the busy one
+        assert_eq!(idle.update(80_000_000.0, 0.0), 80_000_000.0);
+        assert_eq!(limited.update(80_000_000.0, 10_000_000.0), 80_000_000.0);
+        assert_eq!(busy.update(90_000_000.0, 90_000_000.0), 90_000_000.0);
+
+        // Once it has a backlog the limited session competes with its full rate
+        assert_eq!(limited.update(90_000_000.0, 90_000_000.0), 50_000_000.0);
+        assert_eq!(busy.update(90_000_000.0, 90_000_000.0), 50_000_000.0);
+        // A demand above the rate counts as the rate
+        assert_eq!(idle.update(20_000_000.0, 40_000_000.0), 10_000_000.0);
+    }
+}
@@ -0,0 +1,114 @@
+//! Datagram types
+//!
+//! Datagrams other than KCP segments start with a little-endian magic `0x5C4Dxxxx`, the upper half
+//! reserves the range: no session uses a conv from it, and datagrams with a lower half this version
+//! doesn't know are counted and dropped instead of being taken for KCP. `PacketType::of` is the one
+//! place magics are told apart, the receive paths dispatch on its result.
+//!
+//! A new packet type takes a free magic from the range and an entry in `REGISTRY`.
+
+use bytes::Buf;
+
+use crate::{feedback_auth, fec, multipath, pmtud, scream, skcp};
+
+const MAGIC_MASK: u32 = 0xFFFF_0000;
+const MAGIC_PREFIX: u32 = 0x5C4D_0000;
+
+/// What a received datagram carries
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PacketType {
+    /// KCP segments, starting with their conv
+    Kcp,
+    /// SCReAM feedback
+    Feedback,
+    /// Feedback token exchange
+    FeedbackToken,
+    PmtuProbe,
+    PmtuProbeAck,
+    Keepalive,
+    KeepaliveAck,
+    /// Unreliable datagram
+    Datagram,
+    /// Probe padding
+    Padding,
+    /// Listener refused the session
+    Refused,
+    FecData,
+    FecParity,
+    /// Multipath path probe, answered by the listener
+    PathProbe,
+    /// Reserved magic this version doesn't know, or too short for its type
+    Unknown,
+}
+
+// Magic and shortest packet of every type
+const REGISTRY: [(u32, usize, PacketType); 12] = [
+    (scream::SCREAM_FEEDBACK_HEADER, 5, PacketType::Feedback),
+    (feedback_auth::FEEDBACK_TOKEN_HEADER, 5, PacketType::FeedbackToken),
+    (pmtud::PMTU_PROBE_HEADER, 5, PacketType::PmtuProbe),
+    (pmtud::PMTU_PROBE_ACK_HEADER, 5, PacketType::PmtuProbeAck),
+    (skcp::KEEPALIVE_HEADER, 5, PacketType::Keepalive),
+    (skcp::KEEPALIVE_ACK_HEADER, 5, PacketType::KeepaliveAck),
+    (skcp::DATAGRAM_HEADER, 5, PacketType::Datagram),
+    (skcp::PADDING_HEADER, 5, PacketType::Padding),
+    (skcp::REFUSED_HEADER, 5, PacketType::Refused),
+    (fec::FEC_DATA_HEADER, fec::SHARD_HEADER_LEN, PacketType::FecData),
+    (fec::FEC_PARITY_HEADER, fec::SHARD_HEADER_LEN, PacketType::FecParity),
+    (multipath::PATH_PROBE_HEADER, multipath::PATH_PROBE_LEN, PacketType::PathProbe),
+];
+
+impl PacketType {
+    /// Type of `packet` by its first four bytes
+    pub fn of(packet: &[u8]) -> PacketType {
+        if packet.len() < 4 {
+            return PacketType::Kcp;
+        }
+        let magic = (&packet[..4]).get_u32_le();
+        if !is_reserved(magic) {
+            return PacketType::Kcp;
+        }
+        REGISTRY
+            .iter()
+            .find(|(registered, min_len, _)| *registered == magic && packet.len() >= *min_len)
+            .map_or(PacketType::Unknown, |(_, _, packet_type)| *packet_type)
+    }
+}
+
+/// `value` lies in the range of packet magics, as a conv it would be taken for one
+pub fn is_reserved(value: u32) -> bool {
+    value & MAGIC_MASK == MAGIC_PREFIX
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn registry_dispatch() {
+        // Magics are unique and reserved
+        for (i, (magic, _, _)) in REGISTRY.iter().enumerate() {
+            assert!(is_reserved(*magic));
+            assert!(REGISTRY[i + 1..].iter().all(|(other, _, _)| other != magic));
+        }
+
+        let with_magic = |magic: u32, len: usize| {
+            let mut packet = magic.to_le_bytes().to_vec();
+            packet.resize(len, 0);
+            packet
+        };
+        assert_eq!(PacketType::of(&with_magic(scream::SCREAM_FEEDBACK_HEADER, 8)), PacketType::Feedback);
+        assert_eq!(PacketType::of(&with_magic(fec::FEC_PARITY_HEADER, 16)), PacketType::FecParity);
+        assert_eq!(PacketType::of(&with_magic(multipath::PATH_PROBE_HEADER, 12)), PacketType::PathProbe);
+
+        // Too short for the type, or a magic nobody registered
+        assert_eq!(PacketType::of(&with_magic(scream::SCREAM_FEEDBACK_HEADER, 4)), PacketType::Unknown);
+        assert_eq!(PacketType::of(&with_magic(fec::FEC_DATA_HEADER, 6)), PacketType::Unknown);
+        assert_eq!(PacketType::of(&with_magic(0x5C4D_0000, 24)), PacketType::Unknown);
+        assert_eq!(PacketType::of(&with_magic(0x5C4D_FFFF, 24)), PacketType::Unknown);
+
+        // Everything else starts with a conv
+        assert_eq!(PacketType::of(&with_magic(1, 24)), PacketType::Kcp);
+        assert_eq!(PacketType::of(&with_magic(0x5C4E_4642, 24)), PacketType::Kcp);
+        assert_eq!(PacketType::of(&[0x42]), PacketType::Kcp);
+    }
+}
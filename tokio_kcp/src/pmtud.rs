@@ -0,0 +1,283 @@
+//! Path MTU discovery (DPLPMTUD, RFC 8899 style)
+//!
+//! Padded probes are sent with the DF bit set, the peer answers every probe it receives.
+//! Confirmed sizes raise the MTU, probes lost `MAX_PROBES` times in a row lower the search
+//! ceiling and, if the MTU in use is affected, fall back to the largest confirmed size.
+
+use std::{
+    io,
+    time::{Duration, Instant},
+};
+
+use bytes::{Buf, BufMut};
+use tokio::net::UdpSocket;
+use tracing::{debug, trace};
+
+/// Probe: header, probe id, zero padding up to the probed size
+pub const PMTU_PROBE_HEADER: u32 = 0x5C4D5050;
+/// Probe ACK: header, probe id, probed size
+pub const PMTU_PROBE_ACK_HEADER: u32 = 0x5C4D5041;
+
+/// Sizes at or below this are assumed to pass any path
+pub const PMTUD_BASE_MTU: usize = 1200;
+
+const PROBE_LEN: usize = 8;
+const PROBE_ACK_LEN: usize = 12;
+const MAX_PROBES: u8 = 3;
+const MIN_PROBE_TIMEOUT: Duration = Duration::from_millis(200);
+// Search stops once the confirmed and failed sizes are this close
+const SEARCH_GRANULARITY: usize = 16;
+// Restart the search after this long, the path may have changed
+const RAISE_TIMER: Duration = Duration::from_secs(600);
+
+#[derive(Debug)]
+struct Probe {
+    id: u32,
+    size: usize,
+    sent_at: Instant,
+    attempts: u8,
+}
+
+/// Search state of one session
+#[derive(Debug)]
+pub struct PmtuDiscovery {
+    /// MTU in use
+    mtu: usize,
+    /// `mtu` was confirmed by a probe
+    verified: bool,
+    /// Largest size known to pass
+    low: usize,
+    /// Largest size not known to fail
+    high: usize,
+    max_mtu: usize,
+    probe: Option<Probe>,
+    next_id: u32,
+    search_done_at: Option<Instant>,
+}
+
+impl PmtuDiscovery {
+    pub fn new(mtu: usize, max_mtu: usize) -> PmtuDiscovery {
+        let max_mtu = max_mtu.max(mtu);
+        PmtuDiscovery {
+            mtu,
+            verified: false,
+            low: PMTUD_BASE_MTU.min(mtu),
+            high: max_mtu,
+            max_mtu,
+            probe: None,
+            next_id: 0,
+            search_done_at: None,
+        }
+    }
+
+    /// MTU that should be in use
+    pub fn mtu(&self) -> usize {
+        self.mtu
+    }
+
+    /// Probes are under way, `false` once the search settled until the raise timer restarts it
+    pub fn is_searching(&self) -> bool {
+        self.search_done_at.is_none()
+    }
+
+    /// Probe to send now, if any. `timeout` is how long to wait for an ACK.
+    pub fn poll_probe(&mut self, now: Instant, timeout: Duration) -> Option<Vec<u8>> {
+        let timeout = timeout.max(MIN_PROBE_TIMEOUT);
+
+        if let Some(done_at) = self.search_done_at {
+            if now.saturating_duration_since(done_at) < RAISE_TIMER {
+                return None;
+            }
+            trace!("[PMTUD] raise timer expired, searching up to {}", self.max_mtu);
+            self.search_done_at = None;
+            self.high = self.max_mtu;
+        }
+
+        let (size, attempts) = match self.probe {
+            Some(ref probe) if now.saturating_duration_since(probe.sent_at) < timeout => return None,
+            Some(ref probe) if probe.attempts < MAX_PROBES => (probe.size, probe.attempts + 1),
+            Some(ref probe) => {
+                let size = probe.size;
+                self.probe = None;
+                self.on_probe_failed(size);
+                (self.next_probe_size(now)?, 1)
+            }
+            None => (self.next_probe_size(now)?, 1),
+        };
+
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+        self.probe = Some(Probe {
+            id,
+            size,
+            sent_at: now,
+            attempts,
+        });
+
+        trace!("[PMTUD] probing {} bytes, attempt {}", size, attempts);
+
+        let mut packet = Vec::with_capacity(size);
+        packet.put_u32_le(PMTU_PROBE_HEADER);
+        packet.put_u32_le(id);
+        packet.resize(size.max(PROBE_LEN), 0);
+        Some(packet)
+    }
+
+    fn next_probe_size(&mut self, now: Instant) -> Option<usize> {
+        if !self.verified {
+            return Some(self.mtu);
+        }
+        if self.high < self.low + SEARCH_GRANULARITY {
+            debug!("[PMTUD] search done, mtu {}", self.mtu);
+            self.search_done_at = Some(now);
+            return None;
+        }
+        Some((self.low + self.high).div_ceil(2))
+    }
+
+    fn on_probe_failed(&mut self, size: usize) {
+        self.high = size.saturating_sub(1).max(self.low);
+        if size <= self.mtu {
+            debug!("[PMTUD] {} bytes blackholed, falling back to {}", size, self.low);
+            self.mtu = self.low;
+            // Only sizes confirmed by a probe or the base MTU become `low`
+            self.verified = true;
+        }
+    }
+
+    /// Handle a probe ACK payload (after the header)
+    pub fn on_probe_ack(&mut self, mut payload: &[u8]) {
+        if payload.len() < PROBE_ACK_LEN - 4 {
+            return;
+        }
+        let id = payload.get_u32_le();
+        let size = payload.get_u32_le() as usize;
+
+        match self.probe {
+            Some(ref probe) if probe.id == id && probe.size == size => {}
+            _ => return,
+        }
+        self.probe = None;
+
+        trace!("[PMTUD] {} bytes confirmed", size);
+        self.low = self.low.max(size);
+        if size >= self.mtu {
+            self.mtu = size;
+            self.verified = true;
+        }
+    }
+}
+
+/// ACK for a received probe (full datagram including the header), `None` if malformed
+pub fn probe_ack(probe: &[u8]) -> Option<Vec<u8>> {
+    if probe.len() < PROBE_LEN {
+        return None;
+    }
+    let id = (&probe[4..]).get_u32_le();
+
+    let mut ack = Vec::with_capacity(PROBE_ACK_LEN);
+    ack.put_u32_le(PMTU_PROBE_ACK_HEADER);
+    ack.put_u32_le(id);
+    ack.put_u32_le(probe.len() as u32);
+    Some(ack)
+}
+
+/// Set the DF bit on outgoing packets and ignore the kernel's PMTU cache
+#[cfg(target_os = "linux")]
+pub fn enable_dont_fragment(socket: &UdpSocket) -> io::Result<()> {
+    use std::{net::SocketAddr, os::unix::io::AsRawFd};
+
+    use crate::ecn::sys;
+
+    let fd = socket.as_raw_fd();
+    match socket.local_addr()? {
+        SocketAddr::V4(..) => {
+            sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE)?;
+        }
+        SocketAddr::V6(..) => {
+            sys::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_MTU_DISCOVER, libc::IPV6_PMTUDISC_PROBE)?;
+            // Dual-stack sockets also carry IPv4 traffic
+            let _ = sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_MTU_DISCOVER, libc::IP_PMTUDISC_PROBE);
+        }
+    }
+    Ok(())
+}
+
+/// Set the DF bit on outgoing packets and ignore the kernel's PMTU cache
+#[cfg(not(target_os = "linux"))]
+pub fn enable_dont_fragment(_socket: &UdpSocket) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "path MTU discovery is only supported on linux",
+    ))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const TIMEOUT: Duration = Duration::from_millis(200);
+
+    fn probe_id_size(probe: &[u8]) -> (u32, usize) {
+        ((&probe[4..]).get_u32_le(), probe.len())
+    }
+
+    fn ack(pmtud: &mut PmtuDiscovery, probe: &[u8]) {
+        let ack = probe_ack(probe).unwrap();
+        assert_eq!((&ack[..]).get_u32_le(), PMTU_PROBE_ACK_HEADER);
+        pmtud.on_probe_ack(&ack[4..]);
+    }
+
+    #[test]
+    fn search_converges_on_path_mtu() {
+        let path_mtu = 1452;
+        let mut pmtud = PmtuDiscovery::new(1400, 1500);
+        let mut now = Instant::now();
+
+        for _ in 0..100 {
+            match pmtud.poll_probe(now, TIMEOUT) {
+                Some(probe) if probe.len() <= path_mtu => ack(&mut pmtud, &probe),
+                None if pmtud.search_done_at.is_some() => break,
+                _ => {}
+            }
+            now += TIMEOUT;
+        }
+
+        assert!(pmtud.search_done_at.is_some());
+        assert!(pmtud.mtu() <= path_mtu);
+        assert!(pmtud.mtu() + SEARCH_GRANULARITY > path_mtu);
+    }
+
+    #[test]
+    fn blackholed_mtu_falls_back() {
+        let mut pmtud = PmtuDiscovery::new(1400, 1500);
+        let mut now = Instant::now();
+
+        // The configured MTU is verified first and every attempt gets lost
+        for attempt in 0..MAX_PROBES {
+            let probe = pmtud.poll_probe(now, TIMEOUT).unwrap();
+            assert_eq!(probe_id_size(&probe), (attempt as u32, 1400));
+            assert!(pmtud.poll_probe(now, TIMEOUT).is_none());
+            now += TIMEOUT;
+        }
+
+        let probe = pmtud.poll_probe(now, TIMEOUT).unwrap();
+        assert_eq!(pmtud.mtu(), PMTUD_BASE_MTU);
+        assert!(probe.len() > PMTUD_BASE_MTU && probe.len() < 1400);
+    }
+
+    #[test]
+    fn stale_ack_ignored() {
+        let mut pmtud = PmtuDiscovery::new(1400, 1500);
+        let now = Instant::now();
+
+        let first = pmtud.poll_probe(now, TIMEOUT).unwrap();
+        let second = pmtud.poll_probe(now + TIMEOUT, TIMEOUT).unwrap();
+
+        ack(&mut pmtud, &first);
+        assert!(pmtud.probe.is_some());
+        ack(&mut pmtud, &second);
+        assert!(pmtud.probe.is_none());
+        assert!(pmtud.verified);
+    }
+}
@@ -0,0 +1,82 @@
+//! Recycled packet buffers
+//!
+//! Outgoing datagrams are copied into buffers taken from the session's pool and handed back once
+//! the pacer sent or dropped them, so a session in steady state doesn't allocate per packet.
+
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+/// Free list of `BytesMut` buffers
+#[derive(Debug)]
+pub(crate) struct BufferPool {
+    buffers: Mutex<Vec<BytesMut>>,
+    max_buffers: usize,
+    buffer_capacity: usize,
+}
+
+impl BufferPool {
+    /// Pool keeping up to `max_buffers` free buffers of at least `buffer_capacity` bytes
+    pub fn new(max_buffers: usize, buffer_capacity: usize) -> BufferPool {
+        BufferPool {
+            // Grows as buffers come back, `max_buffers` is a limit taken from the config, not a size hint
+            buffers: Mutex::new(Vec::new()),
+            max_buffers,
+            buffer_capacity,
+        }
+    }
+
+    /// Empty buffer, allocated only if the pool ran dry
+    pub fn take(&self) -> BytesMut {
+        match self.buffers.lock().unwrap().pop() {
+            Some(buffer) => buffer,
+            None => BytesMut::with_capacity(self.buffer_capacity),
+        }
+    }
+
+    /// Return `buffer` for reuse, it is freed if the pool is full
+    pub fn put(&self, mut buffer: BytesMut) {
+        buffer.clear();
+        // Buffers sharing their allocation with a split off part would reallocate on the next write
+        if buffer.capacity() < self.buffer_capacity {
+            return;
+        }
+        let mut buffers = self.buffers.lock().unwrap();
+        if buffers.len() < self.max_buffers {
+            buffers.push(buffer);
+        }
+    }
+
+    /// Free buffers
+    #[cfg(test)]
+    pub fn available(&self) -> usize {
+        self.buffers.lock().unwrap().len()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn buffers_reused() {
+        let pool = BufferPool::new(2, 1500);
+        let mut buffer = pool.take();
+        buffer.extend_from_slice(&[1u8; 1000]);
+        let ptr = buffer.as_ptr();
+        pool.put(buffer);
+        assert_eq!(pool.available(), 1);
+
+        let buffer = pool.take();
+        assert!(buffer.is_empty());
+        assert_eq!(buffer.as_ptr(), ptr);
+
+        // Only `max_buffers` are kept
+        for _ in 0..3 {
+            pool.put(BytesMut::with_capacity(1500));
+        }
+        assert_eq!(pool.available(), 2);
+        pool.put(BytesMut::with_capacity(100));
+        assert_eq!(pool.available(), 2);
+    }
+}
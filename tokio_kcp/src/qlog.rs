@@ -0,0 +1,189 @@
+//! qlog output for congestion control analysis
+//!
+//! Events are written as NDJSON qlog (one JSON object per line, the first one being the file
+//! header) and can be loaded into qvis and other QUIC tooling. Every event carries the session's
+//! `conv` as `group_id`.
+//!
+//! | event                       | when                                              |
+//! |-----------------------------|---------------------------------------------------|
+//! | `transport:packet_sent`     | a new segment was handed to the pacer             |
+//! | `recovery:packet_acked`     | a segment was acknowledged by a KCP ACK           |
+//! | `recovery:packet_lost`      | a segment was reported lost                       |
+//! | `recovery:metrics_updated`  | congestion window or pacing rate changed          |
+//! | `scream:feedback_sent`      | a SCReAM feedback packet was sent                 |
+//! | `scream:feedback_received`  | a SCReAM feedback packet arrived                  |
+
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use serde_json::{json, Value};
+use tracing::error;
+
+use crate::utils::{elapsed, now, unix_millis};
+
+// Events queued for the writer thread, newer events are dropped while it is behind
+const QLOG_QUEUE_SIZE: usize = 65536;
+
+/// Congestion control event of a session
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub(crate) enum QlogEvent {
+    PacketSent {
+        sn: u32,
+        size: usize,
+    },
+    PacketAcked {
+        sn: u32,
+    },
+    PacketLost {
+        sn: u32,
+    },
+    MetricsUpdated {
+        congestion_window: f32,
+        bytes_in_flight: u32,
+        smoothed_rtt: Duration,
+        pacing_rate: f32,
+    },
+    FeedbackSent {
+        size: usize,
+    },
+    FeedbackReceived {
+        size: usize,
+    },
+}
+
+impl QlogEvent {
+    fn name(&self) -> &'static str {
+        match *self {
+            QlogEvent::PacketSent { .. } => "transport:packet_sent",
+            QlogEvent::PacketAcked { .. } => "recovery:packet_acked",
+            QlogEvent::PacketLost { .. } => "recovery:packet_lost",
+            QlogEvent::MetricsUpdated { .. } => "recovery:metrics_updated",
+            QlogEvent::FeedbackSent { .. } => "scream:feedback_sent",
+            QlogEvent::FeedbackReceived { .. } => "scream:feedback_received",
+        }
+    }
+
+    fn data(&self) -> Value {
+        match *self {
+            QlogEvent::PacketSent { sn, size } => json!({ "header": { "packet_number": sn }, "raw": { "length": size } }),
+            QlogEvent::PacketAcked { sn } | QlogEvent::PacketLost { sn } => json!({ "header": { "packet_number": sn } }),
+            QlogEvent::MetricsUpdated {
+                congestion_window,
+                bytes_in_flight,
+                smoothed_rtt,
+                pacing_rate,
+            } => json!({
+                "congestion_window": congestion_window as u64,
+                "bytes_in_flight": bytes_in_flight,
+                "smoothed_rtt": smoothed_rtt.as_secs_f64() * 1000.0,
+                "pacing_rate": pacing_rate as u64,
+            }),
+            QlogEvent::FeedbackSent { size } | QlogEvent::FeedbackReceived { size } => json!({ "length": size }),
+        }
+    }
+}
+
+/// Writes qlog events of every session sharing it to one file
+///
+/// Writes happen on a dedicated thread, which exits once the writer is dropped.
+#[derive(Debug)]
+pub struct QlogWriter {
+    start: Instant,
+    event_tx: mpsc::SyncSender<String>,
+}
+
+impl QlogWriter {
+    /// Create `path`, truncating it, and write the qlog header
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<QlogWriter> {
+        let file = File::create(path)?;
+        let header = json!({
+            "qlog_format": "NDJSON",
+            "qlog_version": "0.3",
+            "title": "tokio_kcp",
+            "trace": {
+                "vantage_point": { "type": "unknown" },
+                "common_fields": { "time_format": "relative", "reference_time": unix_millis() },
+            },
+        });
+
+        let (event_tx, event_rx) = mpsc::sync_channel::<String>(QLOG_QUEUE_SIZE);
+
+        thread::Builder::new().name("kcp-qlog".to_owned()).spawn(move || {
+            let mut writer = BufWriter::new(file);
+            if let Err(err) = writeln!(writer, "{}", header) {
+                error!("qlog write failed, error: {}", err);
+                return;
+            }
+
+            while let Ok(line) = event_rx.recv() {
+                let mut result = writeln!(writer, "{}", line);
+                // Batch everything that queued up meanwhile into a single flush
+                while let (true, Ok(line)) = (result.is_ok(), event_rx.try_recv()) {
+                    result = writeln!(writer, "{}", line);
+                }
+                if let Err(err) = result.and_then(|_| writer.flush()) {
+                    error!("qlog write failed, error: {}", err);
+                    return;
+                }
+            }
+        })?;
+
+        Ok(QlogWriter {
+            start: now(),
+            event_tx,
+        })
+    }
+
+    /// Record `event` of session `conv`, never blocks
+    pub(crate) fn log(&self, conv: u32, event: QlogEvent) {
+        let line = json!({
+            "time": elapsed(self.start).as_secs_f64() * 1000.0,
+            "name": event.name(),
+            "group_id": conv.to_string(),
+            "data": event.data(),
+        });
+        // A full queue means the disk can't keep up
+        let _ = self.event_tx.try_send(line.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn ndjson_written_off_thread() {
+        let path = std::env::temp_dir().join(format!("kcp_qlog_{}.qlog", std::process::id()));
+
+        let qlog = QlogWriter::new(&path).unwrap();
+        qlog.log(7, QlogEvent::PacketSent { sn: 3, size: 1200 });
+        qlog.log(7, QlogEvent::PacketLost { sn: 3 });
+
+        let start = Instant::now();
+        let content = loop {
+            let content = fs::read_to_string(&path).unwrap();
+            if content.lines().count() == 3 || start.elapsed() > Duration::from_secs(5) {
+                break content;
+            }
+            thread::sleep(Duration::from_millis(10));
+        };
+        let _ = fs::remove_file(&path);
+
+        let lines: Vec<Value> = content.lines().map(|l| serde_json::from_str(l).unwrap()).collect();
+        assert_eq!(lines.len(), 3);
+        assert_eq!(lines[0]["qlog_format"], "NDJSON");
+        assert_eq!(lines[1]["name"], "transport:packet_sent");
+        assert_eq!(lines[1]["group_id"], "7");
+        assert_eq!(lines[1]["data"]["header"]["packet_number"], 3);
+        assert_eq!(lines[1]["data"]["raw"]["length"], 1200);
+        assert_eq!(lines[2]["name"], "recovery:packet_lost");
+    }
+}
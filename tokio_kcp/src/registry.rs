@@ -0,0 +1,298 @@
+//! Prometheus exporter
+//!
+//! `MetricsRegistry` is a `MetricsSink` turning the samples of every session into Prometheus
+//! gauges and counters. Install it as `KcpConfig::metrics` on all listeners and streams that should
+//! be exported, then scrape it with `MetricsRegistry::encode`.
+
+use std::{
+    collections::HashMap,
+    fmt::{self, Debug},
+    sync::{Arc, Mutex},
+};
+
+use prometheus::{Encoder, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry, TextEncoder};
+use tracing::error;
+
+use crate::metrics::{MetricsSample, MetricsSink};
+
+// Counters of a session, the samples carry totals but Prometheus counters are incremented
+#[derive(Debug, Default)]
+struct SessionTotals {
+    bytes_sent: u64,
+    bytes_received: u64,
+    retransmits: u32,
+    fast_retransmits: u32,
+    out_of_order: u32,
+    duplicate_acks: u32,
+}
+
+struct Metrics {
+    sessions_active: IntGauge,
+    bytes_sent: IntCounter,
+    bytes_received: IntCounter,
+    retransmits: IntCounter,
+    fast_retransmits: IntCounter,
+    out_of_order: IntCounter,
+    duplicate_acks: IntCounter,
+    session_bytes_sent: IntCounterVec,
+    session_bytes_received: IntCounterVec,
+    session_retransmits: IntCounterVec,
+    session_fast_retransmits: IntCounterVec,
+    session_out_of_order: IntCounterVec,
+    session_duplicate_acks: IntCounterVec,
+    session_srtt: GaugeVec,
+    session_target_bitrate: GaugeVec,
+    session_pacer_queue: IntGaugeVec,
+}
+
+impl Metrics {
+    fn register(registry: &Registry) -> prometheus::Result<Metrics> {
+        let metrics = Metrics {
+            sessions_active: IntGauge::new("kcp_sessions_active", "Sessions currently open")?,
+            bytes_sent: IntCounter::new("kcp_bytes_sent_total", "Bytes of datagrams sent by all sessions")?,
+            bytes_received: IntCounter::new("kcp_bytes_received_total", "Bytes of datagrams received by all sessions")?,
+            retransmits: IntCounter::new("kcp_retransmits_total", "Segments retransmitted by all sessions")?,
+            fast_retransmits: IntCounter::new(
+                "kcp_fast_retransmits_total",
+                "Segments fast retransmitted by all sessions",
+            )?,
+            out_of_order: IntCounter::new(
+                "kcp_out_of_order_segments_total",
+                "Segments all sessions received ahead of a gap",
+            )?,
+            duplicate_acks: IntCounter::new("kcp_duplicate_acks_total", "Duplicate ACKs received by all sessions")?,
+            session_bytes_sent: IntCounterVec::new(
+                Opts::new("kcp_session_bytes_sent_total", "Bytes of datagrams sent"),
+                &["conv"],
+            )?,
+            session_bytes_received: IntCounterVec::new(
+                Opts::new("kcp_session_bytes_received_total", "Bytes of datagrams received"),
+                &["conv"],
+            )?,
+            session_retransmits: IntCounterVec::new(
+                Opts::new("kcp_session_retransmits_total", "Segments retransmitted"),
+                &["conv"],
+            )?,
+            session_fast_retransmits: IntCounterVec::new(
+                Opts::new("kcp_session_fast_retransmits_total", "Segments fast retransmitted"),
+                &["conv"],
+            )?,
+            session_out_of_order: IntCounterVec::new(
+                Opts::new("kcp_session_out_of_order_segments_total", "Segments received ahead of a gap"),
+                &["conv"],
+            )?,
+            session_duplicate_acks: IntCounterVec::new(
+                Opts::new("kcp_session_duplicate_acks_total", "Duplicate ACKs received"),
+                &["conv"],
+            )?,
+            session_srtt: GaugeVec::new(Opts::new("kcp_session_srtt_seconds", "Smoothed RTT"), &["conv"])?,
+            session_target_bitrate: GaugeVec::new(
+                Opts::new("kcp_session_target_bitrate_bps", "Target bitrate of the congestion controller"),
+                &["conv"],
+            )?,
+            session_pacer_queue: IntGaugeVec::new(
+                Opts::new("kcp_session_pacer_queue_packets", "Packets waiting for the pacer"),
+                &["conv"],
+            )?,
+        };
+
+        registry.register(Box::new(metrics.sessions_active.clone()))?;
+        registry.register(Box::new(metrics.bytes_sent.clone()))?;
+        registry.register(Box::new(metrics.bytes_received.clone()))?;
+        registry.register(Box::new(metrics.retransmits.clone()))?;
+        registry.register(Box::new(metrics.fast_retransmits.clone()))?;
+        registry.register(Box::new(metrics.out_of_order.clone()))?;
+        registry.register(Box::new(metrics.duplicate_acks.clone()))?;
+        registry.register(Box::new(metrics.session_bytes_sent.clone()))?;
+        registry.register(Box::new(metrics.session_bytes_received.clone()))?;
+        registry.register(Box::new(metrics.session_retransmits.clone()))?;
+        registry.register(Box::new(metrics.session_fast_retransmits.clone()))?;
+        registry.register(Box::new(metrics.session_out_of_order.clone()))?;
+        registry.register(Box::new(metrics.session_duplicate_acks.clone()))?;
+        registry.register(Box::new(metrics.session_srtt.clone()))?;
+        registry.register(Box::new(metrics.session_target_bitrate.clone()))?;
+        registry.register(Box::new(metrics.session_pacer_queue.clone()))?;
+        Ok(metrics)
+    }
+}
+
+/// Prometheus metrics of all sessions sharing this registry, labelled by `conv`
+///
+/// Clones share the metrics.
+#[derive(Clone)]
+pub struct MetricsRegistry {
+    registry: Registry,
+    metrics: Arc<Metrics>,
+    sessions: Arc<Mutex<HashMap<u32, SessionTotals>>>,
+}
+
+impl Debug for MetricsRegistry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("MetricsRegistry")
+            .field("sessions.len", &self.sessions.lock().unwrap().len())
+            .finish()
+    }
+}
+
+impl Default for MetricsRegistry {
+    fn default() -> MetricsRegistry {
+        MetricsRegistry::new()
+    }
+}
+
+impl MetricsRegistry {
+    /// Metrics registered in a registry of their own
+    pub fn new() -> MetricsRegistry {
+        MetricsRegistry::with_registry(Registry::new()).expect("metric names are unique")
+    }
+
+    /// Metrics registered in `registry`, fails if it already has metrics of the same names
+    pub fn with_registry(registry: Registry) -> prometheus::Result<MetricsRegistry> {
+        let metrics = Metrics::register(&registry)?;
+        Ok(MetricsRegistry {
+            registry,
+            metrics: Arc::new(metrics),
+            sessions: Arc::new(Mutex::new(HashMap::new())),
+        })
+    }
+
+    /// Registry holding the metrics
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    /// All metrics of the registry in the Prometheus text format
+    pub fn encode(&self) -> String {
+        let mut buffer = Vec::new();
+        if let Err(err) = TextEncoder::new().encode(&self.registry.gather(), &mut buffer) {
+            error!("metrics encoding failed, error: {}", err);
+        }
+        String::from_utf8(buffer).unwrap_or_default()
+    }
+}
+
+impl MetricsSink for MetricsRegistry {
+    fn record(&self, sample: &MetricsSample) {
+        let metrics = &*self.metrics;
+        let conv = sample.conv.to_string();
+        let labels = [conv.as_str()];
+
+        let mut sessions = self.sessions.lock().unwrap();
+        let totals = sessions.entry(sample.conv).or_insert_with(|| {
+            metrics.sessions_active.inc();
+            SessionTotals::default()
+        });
+
+        let bytes_sent = sample.bytes_sent.saturating_sub(totals.bytes_sent);
+        let bytes_received = sample.bytes_received.saturating_sub(totals.bytes_received);
+        let retransmits = u64::from(sample.retransmits.saturating_sub(totals.retransmits));
+        let fast_retransmits = u64::from(sample.fast_retransmits.saturating_sub(totals.fast_retransmits));
+        let out_of_order = u64::from(sample.out_of_order.saturating_sub(totals.out_of_order));
+        let duplicate_acks = u64::from(sample.duplicate_acks.saturating_sub(totals.duplicate_acks));
+        *totals = SessionTotals {
+            bytes_sent: sample.bytes_sent,
+            bytes_received: sample.bytes_received,
+            retransmits: sample.retransmits,
+            fast_retransmits: sample.fast_retransmits,
+            out_of_order: sample.out_of_order,
+            duplicate_acks: sample.duplicate_acks,
+        };
+        drop(sessions);
+
+        metrics.bytes_sent.inc_by(bytes_sent);
+        metrics.bytes_received.inc_by(bytes_received);
+        metrics.retransmits.inc_by(retransmits);
+        metrics.fast_retransmits.inc_by(fast_retransmits);
+        metrics.out_of_order.inc_by(out_of_order);
+        metrics.duplicate_acks.inc_by(duplicate_acks);
+        metrics.session_bytes_sent.with_label_values(&labels).inc_by(bytes_sent);
+        metrics.session_bytes_received.with_label_values(&labels).inc_by(bytes_received);
+        metrics.session_retransmits.with_label_values(&labels).inc_by(retransmits);
+        metrics.session_fast_retransmits.with_label_values(&labels).inc_by(fast_retransmits);
+        metrics.session_out_of_order.with_label_values(&labels).inc_by(out_of_order);
+        metrics.session_duplicate_acks.with_label_values(&labels).inc_by(duplicate_acks);
+        metrics.session_srtt.with_label_values(&labels).set(sample.s_rtt.as_secs_f64());
+        metrics
+            .session_target_bitrate
+            .with_label_values(&labels)
+            .set(f64::from(sample.target_bitrate));
+        metrics.session_pacer_queue.with_label_values(&labels).set(sample.pacer_queue as i64);
+    }
+
+    fn close(&self, conv: u32, _session_id: u64) {
+        if self.sessions.lock().unwrap().remove(&conv).is_none() {
+            return;
+        }
+
+        let metrics = &*self.metrics;
+        metrics.sessions_active.dec();
+
+        // Closed sessions stop being exported, only the aggregates keep their traffic
+        let conv = conv.to_string();
+        let labels = [conv.as_str()];
+        let _ = metrics.session_bytes_sent.remove_label_values(&labels);
+        let _ = metrics.session_bytes_received.remove_label_values(&labels);
+        let _ = metrics.session_retransmits.remove_label_values(&labels);
+        let _ = metrics.session_fast_retransmits.remove_label_values(&labels);
+        let _ = metrics.session_out_of_order.remove_label_values(&labels);
+        let _ = metrics.session_duplicate_acks.remove_label_values(&labels);
+        let _ = metrics.session_srtt.remove_label_values(&labels);
+        let _ = metrics.session_target_bitrate.remove_label_values(&labels);
+        let _ = metrics.session_pacer_queue.remove_label_values(&labels);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn sample(conv: u32, bytes_sent: u64) -> MetricsSample {
+        MetricsSample {
+            conv,
+            s_rtt: Duration::from_millis(25),
+            target_bitrate: 1_000_000.0,
+            bytes_sent,
+            bytes_received: bytes_sent / 2,
+            retransmits: 1,
+            fast_retransmits: 2,
+            duplicate_acks: (bytes_sent / 1000) as u32,
+            pacer_queue: 3,
+            ..MetricsSample::default()
+        }
+    }
+
+    #[test]
+    fn sessions_aggregated() {
+        let registry = MetricsRegistry::new();
+        registry.record(&sample(1, 1000));
+        registry.record(&sample(1, 3000));
+        registry.record(&sample(2, 500));
+
+        let text = registry.encode();
+        assert!(text.contains("kcp_sessions_active 2"));
+        assert!(text.contains("kcp_bytes_sent_total 3500"));
+        assert!(text.contains("kcp_retransmits_total 2"));
+        assert!(text.contains("kcp_fast_retransmits_total 4"));
+        assert!(text.contains("kcp_duplicate_acks_total 3"));
+        assert!(text.contains("kcp_session_duplicate_acks_total{conv=\"1\"} 3"));
+        assert!(text.contains("kcp_session_bytes_sent_total{conv=\"1\"} 3000"));
+        assert!(text.contains("kcp_session_srtt_seconds{conv=\"2\"} 0.025"));
+        assert!(text.contains("kcp_session_pacer_queue_packets{conv=\"1\"} 3"));
+
+        registry.close(1, 0);
+        registry.close(1, 0);
+        let text = registry.encode();
+        assert!(text.contains("kcp_sessions_active 1"));
+        assert!(text.contains("kcp_bytes_sent_total 3500"));
+        assert!(!text.contains("conv=\"1\""));
+    }
+
+    #[test]
+    fn shared_registry_rejects_duplicates() {
+        let registry = Registry::new();
+        assert!(MetricsRegistry::with_registry(registry.clone()).is_ok());
+        assert!(MetricsRegistry::with_registry(registry).is_err());
+    }
+}
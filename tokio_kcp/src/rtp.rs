@@ -0,0 +1,185 @@
+//! Packet adapter for RTP media pipelines
+//!
+//! `RtpAdapter` moves whole RTP packets over a message mode `KcpStream`, one packet per message. Its
+//! shape follows GStreamer's app elements: an appsink callback hands packets to the non-blocking
+//! `push_rtp_packet`, an appsrc is fed from `pull_rtp_packet`, and the encoder bitrate follows
+//! `target_bitrate_receiver`.
+//!
+//! Codecs with their own RTX or FEC should run the stream with `KcpConfig::passthrough`, lost packets
+//! are then left to them instead of being retransmitted by KCP.
+
+use std::{
+    fmt::{self, Debug},
+    io,
+};
+
+use bytes::Bytes;
+use futures_util::future;
+use kcp::{Error as KcpError, KcpResult};
+use tokio::{
+    sync::{mpsc, watch},
+    task::JoinHandle,
+};
+use tracing::debug;
+
+use crate::{
+    config::StreamMode,
+    split::{KcpReadHalf, KcpWriteHalf},
+    stream::{self, KcpStream},
+};
+
+// Packets `push_rtp_packet` queues before it rejects more, unless `with_queue_size` picks another size
+const RTP_QUEUE_SIZE: usize = 256;
+
+/// Push/pull access to the RTP packets carried by a `KcpStream`
+pub struct RtpAdapter {
+    reader: KcpReadHalf,
+    packet_tx: mpsc::Sender<Bytes>,
+    target_bitrate_rx: watch::Receiver<f32>,
+    writer_task: JoinHandle<()>,
+}
+
+impl Debug for RtpAdapter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RtpAdapter")
+            .field("reader", &self.reader)
+            .field("queued", &(self.packet_tx.max_capacity() - self.packet_tx.capacity()))
+            .finish()
+    }
+}
+
+impl RtpAdapter {
+    /// Adapter over `stream`, which must be in `StreamMode::Message`
+    ///
+    /// Spawns the task sending the pushed packets, so it must be called within a Tokio runtime. Up to
+    /// 256 pushed packets are queued.
+    pub fn new(stream: KcpStream) -> KcpResult<RtpAdapter> {
+        RtpAdapter::with_queue_size(stream, RTP_QUEUE_SIZE)
+    }
+
+    /// `new` queueing up to `queue_size` pushed packets
+    pub fn with_queue_size(stream: KcpStream, queue_size: usize) -> KcpResult<RtpAdapter> {
+        if stream.stream_mode() != StreamMode::Message {
+            return Err(KcpError::InvalidConfig(
+                "RTP packets need message mode, stream_mode is byte_stream".to_owned(),
+            ));
+        }
+
+        let target_bitrate_rx = stream.get_target_bitrate_receiver();
+        let (reader, writer) = stream.into_split();
+        let (packet_tx, packet_rx) = mpsc::channel(queue_size);
+        let writer_task = tokio::spawn(async move {
+            if let Err(err) = run_writer(writer, packet_rx).await {
+                debug!("[RTP] writer stopped, error: {}", err);
+            }
+        });
+
+        Ok(RtpAdapter {
+            reader,
+            packet_tx,
+            target_bitrate_rx,
+            writer_task,
+        })
+    }
+
+    /// Queue `packet` for sending without blocking
+    ///
+    /// Fails with `WouldBlock` while the queue is full, the packet should be dropped and the encoder slowed
+    /// down to the target bitrate. `BrokenPipe` once the connection is gone.
+    pub fn push_rtp_packet(&self, packet: Bytes) -> KcpResult<()> {
+        if packet.is_empty() {
+            // An empty message signals the end of the stream
+            return Err(KcpError::IoError(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "empty RTP packet",
+            )));
+        }
+
+        self.packet_tx.try_send(packet).map_err(|err| {
+            let kind = match err {
+                mpsc::error::TrySendError::Full(..) => io::ErrorKind::WouldBlock,
+                mpsc::error::TrySendError::Closed(..) => io::ErrorKind::BrokenPipe,
+            };
+            KcpError::IoError(kind.into())
+        })
+    }
+
+    /// Wait for the next packet, `None` after the peer closed
+    pub async fn pull_rtp_packet(&mut self) -> KcpResult<Option<Bytes>> {
+        let packet = self.reader.recv_msg().await?;
+        Ok(if packet.is_empty() { None } else { Some(packet) })
+    }
+
+    /// Watch of the SCReAM target bitrate in bits per second, to drive the encoder
+    pub fn target_bitrate_receiver(&self) -> watch::Receiver<f32> {
+        self.target_bitrate_rx.clone()
+    }
+
+    /// Send the queued packets, then close the sending direction
+    pub async fn close(self) -> KcpResult<()> {
+        let RtpAdapter {
+            packet_tx, writer_task, ..
+        } = self;
+        drop(packet_tx);
+        writer_task
+            .await
+            .map_err(|err| KcpError::IoError(io::Error::other(err)))
+    }
+}
+
+async fn run_writer(mut writer: KcpWriteHalf, mut packet_rx: mpsc::Receiver<Bytes>) -> KcpResult<()> {
+    while let Some(packet) = packet_rx.recv().await {
+        writer.send_msg(packet).await?;
+    }
+    future::poll_fn(|cx| stream::poll_shutdown(writer.session(), cx)).await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::{KcpConfig, KcpListener};
+
+    #[tokio::test]
+    async fn rtp_push_pull() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            stream_mode: StreamMode::Message,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let sender = RtpAdapter::with_queue_size(client, 4).unwrap();
+        assert!(*sender.target_bitrate_receiver().borrow() > 0.0);
+
+        let packets: Vec<Bytes> = (0..4u8).map(|i| Bytes::from(vec![0x80, i, 0, i])).collect();
+        for packet in &packets {
+            sender.push_rtp_packet(packet.clone()).unwrap();
+        }
+        assert!(sender.push_rtp_packet(Bytes::new()).is_err());
+
+        let (server, _) = listener.accept().await.unwrap();
+        let mut receiver = RtpAdapter::new(server).unwrap();
+        for packet in &packets {
+            assert_eq!(receiver.pull_rtp_packet().await.unwrap().as_ref(), Some(packet));
+        }
+
+        sender.close().await.unwrap();
+        assert_eq!(receiver.pull_rtp_packet().await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn rtp_needs_message_mode() {
+        let config = KcpConfig {
+            stream_mode: StreamMode::ByteStream,
+            ..Default::default()
+        };
+        let listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let client = KcpStream::connect(&config, listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        assert!(matches!(RtpAdapter::new(client), Err(KcpError::InvalidConfig(..))));
+    }
+}
@@ -1,26 +1,115 @@
-use std::{cmp::min, collections::HashMap, convert::TryInto, time::{Duration, Instant, UNIX_EPOCH}};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::time::SystemTime;
+use std::{
+    cmp::min,
+    collections::{HashMap, VecDeque},
+    convert::TryFrom,
+    time::{Duration, Instant},
+};
+
+use kcp::{Error as KcpError, KcpResult};
+use serde::{Deserialize, Serialize};
+use tracing::{debug, trace};
+
+use crate::{
+    config::KcpRuntimeConfig,
+    congestion::{CongestionController, CongestionStats},
+    ecn::EcnCodepoint,
+    event::CongestionEvent,
+    feedback::{self, FeedbackFormat, FeedbackPacketInfo, DATAGRAM_SN_FLAG},
+    metrics::MetricsSample,
+    utils::{elapsed, now, unix_millis},
+};
 
 // scream feedback header to seperate KCP and SCReAMv2 ACK's
 pub const SCREAM_FEEDBACK_HEADER: u32 = 0x5C4D4642; // "SCMFB" in hex
 
 const BASE_RTT_WINDOW: Duration = Duration::from_secs(10);
-const QDELAY_TARGET_LO: f32 = 0.06; 
+// The refresh sends at this share of the window for a few RTTs, the acks of its second half
+// carry RTTs without a standing queue
+const BASE_RTT_REFRESH_SCALE: f32 = 0.5;
+const BASE_RTT_REFRESH_RTTS: f32 = 2.0;
+const MIN_BASE_RTT_REFRESH: Duration = Duration::from_millis(100);
+// Afterwards the window grows back over this many RTTs. Restored at once, the queue builds up faster
+// than KCP's RTO follows and the segments waiting in it time out
+const BASE_RTT_RAMP_RTTS: f32 = 10.0;
+pub(crate) const QDELAY_TARGET_LO: f32 = 0.06; 
 const MIN_REF_WND: u32 = 2000;     
 const BYTES_IN_FLIGHT_HEAD_ROOM: f32 = 1.5;
+// Lets slow start double the window per RTT
+const SLOW_START_HEAD_ROOM: f32 = 2.0;
+// With nothing queued and less than this share of ref_wnd in flight the sender is app-limited
+const APP_LIMITED_IN_FLIGHT: f32 = 0.5;
 const BETA_LOSS: f32 = 0.7;
 const BETA_ECN: f32 = 0.8;
-const MSS: u64 = 1000;
+const BETA_HEAVY_LOSS: f32 = 0.5;
+// Until the session reports the MSS derived from its MTU
+const DEFAULT_MSS: f32 = 1000.0;
 const POST_CONGESTION_DELAY_RTT: f32 = 4.0;
 const MUL_INCREASE_FACTOR: f32 = 0.02;
 const PACKET_PACING_HEADROOM: f32 = 1.25;
+pub(crate) const MIN_TARGET_BITRATE: f32 = 500_000.0;
+pub(crate) const MAX_TARGET_BITRATE: f32 = 10_000_000.0;
+// Datagrams aren't retransmitted, one missing from the feedback this long is lost
+const DATAGRAM_LOSS_RTTS: f32 = 3.0;
+const MIN_DATAGRAM_LOSS_TIMEOUT: Duration = Duration::from_millis(100);
+// Segments neither KCP nor the feedback settled this long, e.g. after lost feedback, are forgotten
+// before their sn comes around again
+const STALE_SEGMENT_RTTS: f32 = 8.0;
+const MIN_STALE_SEGMENT_AGE: Duration = Duration::from_secs(2);
+// Hard cap of tracked packets, the oldest quarter is dropped once it is exceeded
+const MAX_PACKETS_IN_FLIGHT: usize = 1 << 16;
 
-#[derive(Debug, Clone, Copy)]
-pub struct FeedbackPacketInfo {
-    pub seq_number: u32,
-    pub reception_time_ms: u64,
+/// Tuning parameters of `ScreamCongestionControl`
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct ScreamConfig {
+    /// Queuing delay target (s)
+    pub qdelay_target_lo: f32,
+    /// Window reduction factor on loss
+    pub beta_loss: f32,
+    /// Window reduction factor on CE marks
+    pub beta_ecn: f32,
+    /// Loss rate of an RTT above which the window is reduced once more by `beta_heavy_loss`, e.g. `0.1`
+    pub heavy_loss_rate: Option<f32>,
+    /// Additional window reduction factor after an RTT with heavy loss
+    pub beta_heavy_loss: f32,
+    /// Grow the window by the acked bytes, doubling it per RTT, until the first congestion event
+    pub slow_start: bool,
+    /// Share of the window added per RTT by the multiplicative increase
+    pub mul_increase_factor: f32,
+    /// Pacing rate relative to the target bitrate
+    pub packet_pacing_headroom: f32,
+    /// RTTs after a congestion event until the multiplicative increase is fully back
+    pub post_congestion_delay_rtt: f32,
+    /// Window over which the smallest RTT becomes the base RTT
+    #[serde(with = "crate::config::duration_ms")]
+    pub base_rtt_window: Duration,
+    /// Briefly halve the send rate this often to drain the bottleneck queue and measure the base RTT
+    /// afresh, e.g. after a route change. A flow competing during the refresh raises the base RTT
+    /// until the next one, so it is off (`None`) by default, 30 s suits links that change routes
+    #[serde(with = "crate::config::option_duration_ms")]
+    pub base_rtt_refresh_interval: Option<Duration>,
+    /// Highest bitrate (bps) this side accepts, e.g. what its decoder keeps up with. Advertised in
+    /// the feedback, the peer's target bitrate stays below it
+    pub max_receive_bitrate: Option<f32>,
+}
+
+impl Default for ScreamConfig {
+    fn default() -> ScreamConfig {
+        ScreamConfig {
+            qdelay_target_lo: QDELAY_TARGET_LO,
+            beta_loss: BETA_LOSS,
+            beta_ecn: BETA_ECN,
+            heavy_loss_rate: None,
+            beta_heavy_loss: BETA_HEAVY_LOSS,
+            slow_start: true,
+            mul_increase_factor: MUL_INCREASE_FACTOR,
+            packet_pacing_headroom: PACKET_PACING_HEADROOM,
+            post_congestion_delay_rtt: POST_CONGESTION_DELAY_RTT,
+            base_rtt_window: BASE_RTT_WINDOW,
+            base_rtt_refresh_interval: None,
+            max_receive_bitrate: None,
+        }
+    }
 }
 
 #[derive(Debug)]
@@ -30,13 +119,40 @@ struct PacketInfo {
     acked_by_kcp: bool,
 }
 
+// A segment KCP retransmitted after its RTO, in flight once no matter how often it is resent
+#[derive(Debug)]
+struct Retransmission {
+    first_sent: Instant,
+    retransmitted: Instant,
+    size: usize,
+    // Acked by KCP or the feedback, no longer in flight
+    settled: bool,
+    // The ack came too soon for the retransmission, the first transmission arrived after all
+    spurious: bool,
+}
+
+/// SCReAMv2 congestion controller, the default `CongestionController`
 #[derive(Debug)]
 pub struct ScreamCongestionControl {
+    config: ScreamConfig,
     s_rtt: f32,
     rtt_var: f32,
     base_rtt: Duration,
     min_rtt_in_window: Duration,
     base_rtt_update_time: Instant,
+    // End of the running base RTT refresh and the smallest RTT seen during it
+    base_rtt_refresh_until: Option<Instant>,
+    base_rtt_refresh_min_rtt: Duration,
+    // End of the last refresh, the window grows back from it
+    base_rtt_refresh_ended: Option<Instant>,
+    // Minimum one-way delay (ms), including the offset between the clocks of the peers
+    base_owd_ms: Option<i64>,
+    min_owd_in_window: i64,
+    base_owd_update_time: Instant,
+    base_owd_refresh_min: i64,
+    // Instant and unix time (ms) taken together, to date send times on this host's clock
+    clock_anchor: (Instant, u64),
+    last_base_rtt_refresh: Instant,
     qdelay: Duration,
     qdelay_avg: f32,
     qdelay_target: f32,
@@ -44,6 +160,8 @@ pub struct ScreamCongestionControl {
     // ref_wnd and bytes in flight
     ref_wnd: f32,
     ref_wnd_i: f32, 
+    in_slow_start: bool,
+    mss: f32,
     bytes_in_flight: u32,
     max_bytes_in_flight: u32,
     max_bytes_in_flight_prev: u32,
@@ -52,12 +170,27 @@ pub struct ScreamCongestionControl {
     bytes_newly_acked: u32,
     bytes_newly_acked_ce: u32, // Für ECN
     loss_occured_in_rtt: bool,
+    bytes_lost: u32,
+    // Loss and CE fractions of the last RTT that carried any acks or losses
+    loss_rate: f32,
+    ce_rate: f32,
+    // Segments retransmitted after their RTO, until the feedback reports them
+    retransmissions: HashMap<u32, Retransmission>,
+    bytes_retransmitted: u32,
+    spurious_in_rtt: u32,
+    // Retransmission counts of the last RTT
+    retransmitted_bytes: u32,
+    spurious_retransmits: u32,
+    // No tick of the current RTT was window-limited, ref_wnd is frozen
+    app_limited_in_rtt: bool,
     last_congestion_detected_time: Instant,
     last_ref_wnd_i_update_time: Instant,
-    last_periodic_update_time: Instant,
+    congestion_events: VecDeque<CongestionEvent>,
     
     // packet-tracking
     packets_in_flight: HashMap<u32, PacketInfo>,
+    // Entries dropped without feedback ever reporting them
+    orphaned_packets: u64,
 
     // logging and small helpers
     first_rtt_measurement: bool,
@@ -65,24 +198,58 @@ pub struct ScreamCongestionControl {
 
     // for packet feedback
     received_packets_for_feedback: Vec<FeedbackPacketInfo>,
-    last_feedback_time: Instant,
+    feedback_format: FeedbackFormat,
+    highest_sent_sn: u32,
+    highest_sent_datagram_sn: u32,
+    // `max_receive_bitrate` of the peer, from its last feedback
+    remote_max_bitrate: Option<f32>,
+    min_target_bitrate: f32,
+    max_target_bitrate: f32,
+}
+
+impl Default for ScreamCongestionControl {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl ScreamCongestionControl {
     pub fn new() -> Self {
-        let now = Instant::now();
+        Self::with_feedback_format(FeedbackFormat::default())
+    }
+
+    /// Controller exchanging feedback encoded as `feedback_format`, both peers must agree
+    pub fn with_feedback_format(feedback_format: FeedbackFormat) -> Self {
+        Self::with_config(ScreamConfig::default(), feedback_format)
+    }
+
+    /// Controller tuned by `config`
+    pub fn with_config(config: ScreamConfig, feedback_format: FeedbackFormat) -> Self {
+        let now = now();
         Self {
+            config,
             s_rtt: 0.0,
             rtt_var: 0.0,
             base_rtt: Duration::from_secs(10), 
             min_rtt_in_window: Duration::from_secs(10),
             base_rtt_update_time: now,
+            base_rtt_refresh_until: None,
+            base_rtt_refresh_ended: None,
+            base_rtt_refresh_min_rtt: Duration::MAX,
+            base_owd_ms: None,
+            min_owd_in_window: i64::MAX,
+            base_owd_update_time: now,
+            base_owd_refresh_min: i64::MAX,
+            clock_anchor: (now, unix_millis()),
+            last_base_rtt_refresh: now,
             qdelay: Duration::ZERO,
             qdelay_avg: 0.0,
-            qdelay_target: QDELAY_TARGET_LO,
+            qdelay_target: config.qdelay_target_lo,
 
-            ref_wnd: 2.0 * MSS as f32, 
-            ref_wnd_i: 2.0 * MSS as f32,
+            ref_wnd: 2.0 * DEFAULT_MSS,
+            ref_wnd_i: 2.0 * DEFAULT_MSS,
+            in_slow_start: config.slow_start,
+            mss: DEFAULT_MSS,
             bytes_in_flight: 0,
             max_bytes_in_flight: 0,
             max_bytes_in_flight_prev: 0,
@@ -90,18 +257,47 @@ impl ScreamCongestionControl {
             bytes_newly_acked: 0,
             bytes_newly_acked_ce: 0,
             loss_occured_in_rtt: false,
+            bytes_lost: 0,
+            loss_rate: 0.0,
+            ce_rate: 0.0,
+            retransmissions: HashMap::new(),
+            bytes_retransmitted: 0,
+            spurious_in_rtt: 0,
+            retransmitted_bytes: 0,
+            spurious_retransmits: 0,
+            app_limited_in_rtt: true,
             last_congestion_detected_time: now,
             last_ref_wnd_i_update_time: now,
-            last_periodic_update_time: now,
+            congestion_events: VecDeque::new(),
 
             packets_in_flight: HashMap::new(),
+            orphaned_packets: 0,
 
             first_rtt_measurement: true,
             loss_for_log: false,   
             
             received_packets_for_feedback: Vec::new(),
-            last_feedback_time: Instant::now(),             
+            feedback_format,
+            highest_sent_sn: 0,
+            highest_sent_datagram_sn: DATAGRAM_SN_FLAG,
+            remote_max_bitrate: None,
+            min_target_bitrate: MIN_TARGET_BITRATE,
+            max_target_bitrate: MAX_TARGET_BITRATE,
+        }
+    }
+
+    /// Keep the target bitrate within `min..=max` bps instead of 500 kbps to 10 Mbps, see
+    /// `KcpConfig::min_target_bitrate`. Fails on bounds that aren't finite, positive and ordered
+    pub fn with_target_bitrate_bounds(mut self, min: f32, max: f32) -> KcpResult<Self> {
+        if !min.is_finite() || !max.is_finite() || min <= 0.0 || min > max {
+            return Err(KcpError::InvalidConfig(format!(
+                "target bitrate bounds {}..={} are empty",
+                min, max
+            )));
         }
+        self.min_target_bitrate = min;
+        self.max_target_bitrate = max;
+        Ok(self)
     }
 
 
@@ -120,14 +316,16 @@ impl ScreamCongestionControl {
 
         // loss or ECN based reduction
         if is_loss {
-            reduction_factor = reduction_factor.min(BETA_LOSS);
+            reduction_factor = reduction_factor.min(self.config.beta_loss);
             congestion_event = true;
         } else if is_ce {
-            reduction_factor = reduction_factor.min(BETA_ECN);
+            reduction_factor = reduction_factor.min(self.config.beta_ecn);
             congestion_event = true;
         }
 
         if congestion_event {
+            self.exit_slow_start();
+
             // renew ref_wnd when enough time has passed since last renewal (typically 10 rtt's)
             if now.saturating_duration_since(self.last_ref_wnd_i_update_time).as_secs_f32() > 10.0 * self.s_rtt {
                 self.ref_wnd_i = self.ref_wnd;
@@ -137,6 +335,164 @@ impl ScreamCongestionControl {
             self.ref_wnd *= reduction_factor;
             self.ref_wnd = self.ref_wnd.max(MIN_REF_WND as f32);
             self.last_congestion_detected_time = now;
+
+            self.congestion_events.push_back(if is_loss {
+                CongestionEvent::Loss
+            } else if is_ce {
+                CongestionEvent::EcnCe
+            } else {
+                CongestionEvent::QueueDelay
+            });
+        }
+    }
+
+    // KCP reports lost segments, datagrams are lost once the feedback skipped them for a few RTTs
+    fn detect_datagram_loss(&mut self, now: Instant) {
+        let timeout = Duration::from_secs_f32(self.s_rtt * DATAGRAM_LOSS_RTTS).max(MIN_DATAGRAM_LOSS_TIMEOUT);
+        let lost: Vec<u32> = self
+            .packets_in_flight
+            .iter()
+            .filter(|(sn, info)| *sn & DATAGRAM_SN_FLAG != 0 && now.saturating_duration_since(info.timestamp) >= timeout)
+            .map(|(sn, _)| *sn)
+            .collect();
+        if lost.is_empty() {
+            return;
+        }
+
+        for sn in lost {
+            if let Some(info) = self.packets_in_flight.remove(&sn) {
+                self.bytes_in_flight = self.bytes_in_flight.saturating_sub(info.size as u32);
+                self.bytes_lost += info.size as u32;
+            }
+        }
+        self.loss_occured_in_rtt = true;
+        self.loss_for_log = true;
+        self.decrease_window(now, true, false);
+    }
+
+    fn purge_stale_segments(&mut self, now: Instant) {
+        let max_age = Duration::from_secs_f32(self.s_rtt * STALE_SEGMENT_RTTS).max(MIN_STALE_SEGMENT_AGE);
+        let mut bytes_in_flight = self.bytes_in_flight;
+        let before = self.packets_in_flight.len();
+        self.packets_in_flight.retain(|sn, info| {
+            let stale = *sn & DATAGRAM_SN_FLAG == 0 && now.saturating_duration_since(info.timestamp) >= max_age;
+            if stale && !info.acked_by_kcp {
+                bytes_in_flight = bytes_in_flight.saturating_sub(info.size as u32);
+            }
+            !stale
+        });
+        let before = before + self.retransmissions.len();
+        self.retransmissions.retain(|_, retransmission| {
+            let stale = now.saturating_duration_since(retransmission.retransmitted) >= max_age;
+            if stale && !retransmission.settled {
+                bytes_in_flight = bytes_in_flight.saturating_sub(retransmission.size as u32);
+            }
+            !stale
+        });
+        self.bytes_in_flight = bytes_in_flight;
+        let purged = before - self.packets_in_flight.len() - self.retransmissions.len();
+        if purged > 0 {
+            self.orphaned_packets += purged as u64;
+            trace!("purged {} stale segments, bytes_in_flight={}", purged, self.bytes_in_flight);
+        }
+    }
+
+    // Feedback stopped arriving for longer than the stale timeout can catch, drop the oldest entries
+    fn evict_oldest(&mut self) {
+        let mut by_age: Vec<(Instant, u32)> =
+            self.packets_in_flight.iter().map(|(sn, info)| (info.timestamp, *sn)).collect();
+        let evict = by_age.len() - MAX_PACKETS_IN_FLIGHT * 3 / 4;
+        by_age.select_nth_unstable(evict - 1);
+        for (_, sn) in &by_age[..evict] {
+            if let Some(info) = self.packets_in_flight.remove(sn) {
+                if !info.acked_by_kcp {
+                    self.bytes_in_flight = self.bytes_in_flight.saturating_sub(info.size as u32);
+                }
+            }
+        }
+        self.orphaned_packets += evict as u64;
+        debug!("evicted {} packets in flight, bytes_in_flight={}", evict, self.bytes_in_flight);
+    }
+
+    // The first ack of a retransmitted segment takes it out of flight. One sooner than the base RTT
+    // after the retransmission acknowledges the first transmission.
+    fn settle_retransmission(&mut self, seq_number: u32, ack_time: Instant) {
+        let Some(retransmission) = self.retransmissions.get_mut(&seq_number) else {
+            return;
+        };
+        if retransmission.settled {
+            return;
+        }
+        retransmission.settled = true;
+        self.bytes_in_flight = self.bytes_in_flight.saturating_sub(retransmission.size as u32);
+        let ack_delay = ack_time.saturating_duration_since(retransmission.retransmitted);
+        if !self.first_rtt_measurement && ack_delay < self.base_rtt {
+            trace!("spurious retransmission sn={}", seq_number);
+            retransmission.spurious = true;
+            self.spurious_in_rtt += 1;
+        }
+    }
+
+    fn drop_retransmission(&mut self, seq_number: u32) {
+        if let Some(retransmission) = self.retransmissions.remove(&seq_number) {
+            if !retransmission.settled {
+                self.bytes_in_flight = self.bytes_in_flight.saturating_sub(retransmission.size as u32);
+            }
+        }
+    }
+
+    fn exit_slow_start(&mut self) {
+        if self.in_slow_start {
+            self.in_slow_start = false;
+            self.ref_wnd_i = self.ref_wnd;
+            trace!("slow start done, ref_wnd {}", self.ref_wnd);
+        }
+    }
+
+    // A standing queue hides in the minimum RTT, only an RTT sample after draining it shows a longer path
+    fn refresh_base_rtt(&mut self, now: Instant) {
+        if let Some(until) = self.base_rtt_refresh_until {
+            if now < until {
+                return;
+            }
+            self.base_rtt_refresh_until = None;
+            self.base_rtt_refresh_ended = Some(now);
+            if self.base_rtt_refresh_min_rtt != Duration::MAX {
+                self.base_rtt = self.base_rtt_refresh_min_rtt;
+                self.min_rtt_in_window = self.base_rtt_refresh_min_rtt;
+                self.base_rtt_update_time = now;
+            }
+            if self.base_owd_refresh_min != i64::MAX {
+                self.base_owd_ms = Some(self.base_owd_refresh_min);
+                self.min_owd_in_window = self.base_owd_refresh_min;
+                self.base_owd_update_time = now;
+            }
+            trace!("base RTT refresh done, base_rtt {:?}", self.base_rtt);
+        }
+
+        let Some(interval) = self.config.base_rtt_refresh_interval else {
+            return;
+        };
+        if !self.first_rtt_measurement && now.saturating_duration_since(self.last_base_rtt_refresh) >= interval {
+            let duration = Duration::from_secs_f32(self.s_rtt * BASE_RTT_REFRESH_RTTS).max(MIN_BASE_RTT_REFRESH);
+            self.base_rtt_refresh_until = Some(now + duration);
+            self.base_rtt_refresh_min_rtt = Duration::MAX;
+            self.base_owd_refresh_min = i64::MAX;
+            self.last_base_rtt_refresh = now;
+            trace!("base RTT refresh for {:?}", duration);
+        }
+    }
+
+    // Window and pacing rate shrink while the base RTT is refreshed and ramp up after, ref_wnd itself is kept
+    fn refresh_scale(&self) -> f32 {
+        if self.base_rtt_refresh_until.is_some() {
+            BASE_RTT_REFRESH_SCALE
+        } else if let Some(ended) = self.base_rtt_refresh_ended {
+            let ramp = Duration::from_secs_f32(self.s_rtt * BASE_RTT_RAMP_RTTS).max(MIN_BASE_RTT_REFRESH);
+            let progress = (elapsed(ended).as_secs_f32() / ramp.as_secs_f32()).min(1.0);
+            BASE_RTT_REFRESH_SCALE + (1.0 - BASE_RTT_REFRESH_SCALE) * progress
+        } else {
+            1.0
         }
     }
 
@@ -145,13 +501,25 @@ impl ScreamCongestionControl {
             return;
         }
 
+        if self.in_slow_start {
+            // Leave before the queue grows far enough to cause a congestion event
+            if self.qdelay_avg > self.qdelay_target / 4.0 {
+                self.exit_slow_start();
+            } else {
+                let max_bytes_in_flight = self.max_bytes_in_flight.max(self.max_bytes_in_flight_prev);
+                let max_allowed_wnd = (max_bytes_in_flight as f32 * SLOW_START_HEAD_ROOM).max(self.ref_wnd);
+                self.ref_wnd = (self.ref_wnd + self.bytes_newly_acked as f32).min(max_allowed_wnd);
+                return;
+            }
+        }
+
 
         // scaling factor -> throttle up slowly after congestion event
-        let post_congestion_scale = (self.last_congestion_detected_time.elapsed().as_secs_f32()
-            / (POST_CONGESTION_DELAY_RTT * self.s_rtt.max(0.01))).clamp(0.0, 1.0);
+        let post_congestion_scale = (elapsed(self.last_congestion_detected_time).as_secs_f32()
+            / (self.config.post_congestion_delay_rtt * self.s_rtt.max(0.01))).clamp(0.0, 1.0);
         
-        let additive_increase = self.bytes_newly_acked as f32 * (MSS as f32 / self.ref_wnd.max(MSS as f32));
-        let multiplicative_increase = self.ref_wnd * MUL_INCREASE_FACTOR * (self.bytes_newly_acked as f32 / self.ref_wnd.max(1.0));
+        let additive_increase = self.bytes_newly_acked as f32 * (self.mss / self.ref_wnd.max(self.mss));
+        let multiplicative_increase = self.ref_wnd * self.config.mul_increase_factor * (self.bytes_newly_acked as f32 / self.ref_wnd.max(1.0));
 
         let mut increment = additive_increase + multiplicative_increase * post_congestion_scale;
 
@@ -171,55 +539,238 @@ impl ScreamCongestionControl {
     }
 
 
-    pub fn on_packet_sent(&mut self, seq_number: u32, size: usize) {
-        let now = Instant::now();
-        let info = PacketInfo{ timestamp: now, size: size, acked_by_kcp: false };
-        self.packets_in_flight.insert(seq_number, info);
+    // `reception_time_ms` is the peer's unix time the packet arrived at, if the feedback carried it
+    fn ack(&mut self, seq_number: u32, ack_timestamp: Instant, reception_time_ms: Option<u64>) {
+        let sent = match self.packets_in_flight.remove(&seq_number) {
+            Some(info) => {
+                if !info.acked_by_kcp {
+                    // remove from bytes in flight
+                    self.bytes_in_flight = self.bytes_in_flight.saturating_sub(info.size as u32);
+                }
+
+                // add ACK'ed bytes to the list for this rtt
+                self.bytes_newly_acked += info.size as u32;
+                info.timestamp
+            }
+            None => {
+                self.settle_retransmission(seq_number, ack_timestamp);
+                let Some(retransmission) = self.retransmissions.remove(&seq_number) else {
+                    return;
+                };
+                self.bytes_newly_acked += retransmission.size as u32;
+                // Either transmission may have arrived, only one known to be the first is an RTT sample
+                if !retransmission.spurious {
+                    return;
+                }
+                retransmission.first_sent
+            }
+        };
+
+        let latest_rtt = ack_timestamp.saturating_duration_since(sent);
+        if latest_rtt.is_zero() { return; }
+        
+
+        if self.first_rtt_measurement {
+            self.s_rtt = latest_rtt.as_secs_f32();
+            self.base_rtt = latest_rtt; // set first base_rtt for first 10 seconds
+            self.rtt_var = self.s_rtt / 2.0;
+            self.first_rtt_measurement = false;
+        } else {
+            let alpha = 0.125;
+            let beta = 0.25;
+            let rtt_now_secs = latest_rtt.as_secs_f32();
+            self.rtt_var = (1.0 - beta) * self.rtt_var + beta * (self.s_rtt - rtt_now_secs).abs();
+            self.s_rtt = (1.0 - alpha) * self.s_rtt + alpha * rtt_now_secs;
+        }
+
+        // update base_rtt every base_rtt_window
+        self.min_rtt_in_window = min(self.min_rtt_in_window, latest_rtt);
+        if self.base_rtt_refresh_until.is_some() {
+            self.base_rtt_refresh_min_rtt = min(self.base_rtt_refresh_min_rtt, latest_rtt);
+        }
+        if elapsed(self.base_rtt_update_time) >= self.config.base_rtt_window {
+            self.base_rtt = self.min_rtt_in_window;       
+            self.min_rtt_in_window = Duration::from_secs(10);
+            self.base_rtt_update_time = now();
+        }
+        // The forward path alone, congestion on the way back inflates the RTT but not this
+        self.qdelay = match reception_time_ms.and_then(|ms| self.one_way_qdelay(sent, ms)) {
+            Some(qdelay) => qdelay,
+            None => latest_rtt.saturating_sub(self.base_rtt),
+        };
+        let qdelay_sample = self.qdelay.as_secs_f32();
+        let q_alpha = 0.1;
+        self.qdelay_avg = (1.0 - q_alpha) * self.qdelay_avg + q_alpha * qdelay_sample;
+    }
+
+    // Unix time (ms) of this host at `instant`
+    fn unix_millis_at(&self, instant: Instant) -> i64 {
+        let (anchor, anchor_ms) = self.clock_anchor;
+        if instant >= anchor {
+            anchor_ms as i64 + instant.duration_since(anchor).as_millis() as i64
+        } else {
+            anchor_ms as i64 - anchor.duration_since(instant).as_millis() as i64
+        }
+    }
+
+    // Queuing delay from the one-way delay measured against the peer's clock. The clock offset is
+    // part of every sample, the minimum over the base RTT window cancels it, renewing that minimum
+    // keeps up with a slowly drifting clock.
+    fn one_way_qdelay(&mut self, sent: Instant, reception_time_ms: u64) -> Option<Duration> {
+        if reception_time_ms == 0 {
+            return None;
+        }
+        // Times beyond i64 are garbage, not worth a sample
+        let owd_ms = i64::try_from(reception_time_ms).ok()?.checked_sub(self.unix_millis_at(sent))?;
+
+        self.min_owd_in_window = self.min_owd_in_window.min(owd_ms);
+        if self.base_rtt_refresh_until.is_some() {
+            self.base_owd_refresh_min = self.base_owd_refresh_min.min(owd_ms);
+        }
+        let base_owd_ms = match self.base_owd_ms {
+            Some(base) if elapsed(self.base_owd_update_time) < self.config.base_rtt_window => base.min(owd_ms),
+            _ => {
+                self.base_owd_update_time = now();
+                std::mem::replace(&mut self.min_owd_in_window, i64::MAX).min(owd_ms)
+            }
+        };
+        self.base_owd_ms = Some(base_owd_ms);
+        Some(Duration::from_millis(owd_ms.saturating_sub(base_owd_ms) as u64))
+    }
+
+    pub fn get_ref_wnd(&self) -> f32 {
+        self.ref_wnd
+    }
+}
+
+impl CongestionController for ScreamCongestionControl {
+    fn on_packet_sent(&mut self, seq_number: u32, size: usize) {
+        let now = now();
+        let info = PacketInfo{ timestamp: now, size, acked_by_kcp: false };
+        // A leftover of the previous round of the sn space leaves the flight
+        if let Some(stale) = self.packets_in_flight.insert(seq_number, info) {
+            if !stale.acked_by_kcp {
+                self.bytes_in_flight = self.bytes_in_flight.saturating_sub(stale.size as u32);
+            }
+        }
+        self.drop_retransmission(seq_number);
+        let highest_sent_sn = if seq_number & DATAGRAM_SN_FLAG != 0 {
+            &mut self.highest_sent_datagram_sn
+        } else {
+            &mut self.highest_sent_sn
+        };
+        if feedback::sn_newer(seq_number, *highest_sent_sn) {
+            *highest_sent_sn = seq_number;
+        }
         self.bytes_in_flight += size as u32;
         self.max_bytes_in_flight = self.max_bytes_in_flight.max(self.bytes_in_flight);
+        if self.packets_in_flight.len() > MAX_PACKETS_IN_FLIGHT {
+            self.evict_oldest();
+        }
     }
 
-     pub fn on_packet_received(&mut self, seq_number: u32, reception_time: Instant) {
-        let reception_time_ms = SystemTime::now()
-            .duration_since(UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis() as u64;
+    fn on_packet_received(&mut self, seq_number: u32, _reception_time: Instant, ecn: EcnCodepoint) {
+        let reception_time_ms = unix_millis();
         self.received_packets_for_feedback.push(FeedbackPacketInfo { 
-            seq_number: seq_number,
-            reception_time_ms: reception_time_ms,
+            seq_number,
+            reception_time_ms,
+            ecn,
         });
     }
 
-    pub fn create_feedback_packet(&mut self) -> Option<Vec<u8>>  {
+    fn create_feedback_packet(&mut self) -> Option<Vec<u8>>  {
+        let mut feedback_data = Vec::new();
+        // Entries beyond one segment wait for the next call
+        self.write_feedback(&mut feedback_data, self.mss as usize).then_some(feedback_data)
+    }
+
+    fn write_feedback(&mut self, buf: &mut Vec<u8>, max_len: usize) -> bool {
         if self.received_packets_for_feedback.is_empty() {
-            return None;
+            return false;
         }
 
-        // 12 bytes per entry -> 4 for sn and 8 for timestamp
-        let mut feedback_data = Vec::with_capacity(self.received_packets_for_feedback.len() * 12);
-
-        for info in &self.received_packets_for_feedback {
-            feedback_data.extend_from_slice((&info.seq_number.to_le_bytes()));
-            feedback_data.extend_from_slice(&info.reception_time_ms.to_le_bytes());
+        // Every payload carries the limit, the peer forgets it with the first one without
+        let max_receive_bitrate = self.config.max_receive_bitrate.filter(|bitrate| bitrate.is_finite());
+        let max_len = match max_receive_bitrate {
+            Some(..) => max_len.saturating_sub(feedback::max_bitrate_len(self.feedback_format)),
+            None => max_len,
+        };
+        let entries = &self.received_packets_for_feedback;
+        let n = feedback::fitting_entries(self.feedback_format, entries, max_len);
+        feedback::encode_into(self.feedback_format, &entries[..n], unix_millis(), buf);
+        if let Some(max_receive_bitrate) = max_receive_bitrate {
+            feedback::encode_max_bitrate(self.feedback_format, max_receive_bitrate, buf);
         }
 
-        self.received_packets_for_feedback.clear();
-        self.last_feedback_time = Instant::now();
-        Some(feedback_data)
+        self.received_packets_for_feedback.drain(..n);
+        true
     }
 
     // when an SCReAMv2 feedback header packet is delivered
-    pub fn on_feedback(&mut self, data: &[u8], feedback_arrival_time: Instant) {
-        for chunk in data.chunks_exact(12) {
-            let seq_number = u32::from_le_bytes(chunk[0..4].try_into().unwrap());
-            //let _reception_time_ms = u64::from_le_bytes(chunk[4..12].try_into().unwrap());
-            self.on_ack_scream(seq_number, feedback_arrival_time);
+    fn on_feedback(&mut self, data: &[u8], feedback_arrival_time: Instant) {
+        let mut ce_marked = false;
+        let entries = feedback::decode(self.feedback_format, data, self.highest_sent_sn, self.highest_sent_datagram_sn);
+        let remote_max_bitrate = feedback::decode_max_bitrate(self.feedback_format, data);
+        if remote_max_bitrate != self.remote_max_bitrate {
+            debug!("peer accepts at most {:?} bps", remote_max_bitrate);
+            self.remote_max_bitrate = remote_max_bitrate;
         }
+        for info in entries {
+            let seq_number = info.seq_number;
+            if info.ecn.is_ce() {
+                if let Some(info) = self.packets_in_flight.get(&seq_number) {
+                    self.bytes_newly_acked_ce += info.size as u32;
+                    ce_marked = true;
+                }
+            }
+            self.ack(seq_number, feedback_arrival_time, Some(info.reception_time_ms));
+        }
+
+        // react to CE at most once per RTT, like a loss
+        if ce_marked
+            && feedback_arrival_time
+                .saturating_duration_since(self.last_congestion_detected_time)
+                .as_secs_f32()
+                > self.s_rtt
+        {
+            self.decrease_window(feedback_arrival_time, false, true);
+        }
+    }
+
+    fn on_send_queue(&mut self, queued: usize) {
+        let app_limited = queued == 0 && (self.bytes_in_flight as f32) < self.ref_wnd * APP_LIMITED_IN_FLIGHT;
+        self.app_limited_in_rtt &= app_limited;
     }
 
-    pub fn on_rtt(&mut self) {
-        self.increase_window();
-        self.decrease_window(Instant::now(), false, false);
+    fn on_rtt(&mut self) {
+        let now = now();
+        self.detect_datagram_loss(now);
+        self.purge_stale_segments(now);
+        self.refresh_base_rtt(now);
+        // Acks of an app-limited RTT say nothing about spare capacity, growing on them bursts after idle
+        if self.app_limited_in_rtt {
+            trace!("app-limited RTT, ref_wnd {} frozen", self.ref_wnd);
+        } else if self.base_rtt_refresh_until.is_some() {
+            trace!("base RTT refresh, ref_wnd {} frozen", self.ref_wnd);
+        } else {
+            self.increase_window();
+        }
+        self.decrease_window(now, false, false);
+
+        let bytes_settled = self.bytes_newly_acked + self.bytes_lost;
+        if bytes_settled > 0 {
+            self.loss_rate = self.bytes_lost as f32 / bytes_settled as f32;
+            self.ce_rate = self.bytes_newly_acked_ce as f32 / self.bytes_newly_acked.max(1) as f32;
+        }
+        // The per-loss backoff is rate limited, an RTT losing this much needs more
+        if let Some(heavy_loss_rate) = self.config.heavy_loss_rate {
+            if self.bytes_lost > 0 && self.loss_rate > heavy_loss_rate {
+                self.ref_wnd = (self.ref_wnd * self.config.beta_heavy_loss).max(MIN_REF_WND as f32);
+                trace!("heavy loss {:.2}, ref_wnd {}", self.loss_rate, self.ref_wnd);
+            }
+        }
+        self.retransmitted_bytes = self.bytes_retransmitted;
+        self.spurious_retransmits = self.spurious_in_rtt;
 
         self.max_bytes_in_flight_prev = self.max_bytes_in_flight;
         self.max_bytes_in_flight = self.bytes_in_flight; 
@@ -227,12 +778,38 @@ impl ScreamCongestionControl {
         // reset values
         self.bytes_newly_acked = 0;
         self.bytes_newly_acked_ce = 0;
+        self.bytes_lost = 0;
+        self.bytes_retransmitted = 0;
+        self.spurious_in_rtt = 0;
         self.loss_occured_in_rtt = false;
-        self.last_periodic_update_time = Instant::now();
+        self.app_limited_in_rtt = true;
+    }
+
+    fn poll_congestion_event(&mut self) -> Option<CongestionEvent> {
+        self.congestion_events.pop_front()
     }
 
     // gets called everytime there is an KCP ACK 
-    pub fn on_ack_kcp(&mut self, seq_number: u32) {
+    fn on_mss_changed(&mut self, mss: usize) {
+        self.mss = mss as f32;
+    }
+
+    fn on_reconfigure(&mut self, config: &KcpRuntimeConfig) {
+        if let Some(max_target_bitrate) = config.max_target_bitrate {
+            // Throttling below the lower bound takes it along
+            self.max_target_bitrate = max_target_bitrate;
+            self.min_target_bitrate = self.min_target_bitrate.min(max_target_bitrate);
+        }
+        if let Some(headroom) = config.packet_pacing_headroom {
+            self.config.packet_pacing_headroom = headroom;
+        }
+        if let Some(max_receive_bitrate) = config.max_receive_bitrate {
+            self.config.max_receive_bitrate = Some(max_receive_bitrate);
+        }
+    }
+
+    fn on_kcp_ack(&mut self, seq_number: u32) {
+        self.settle_retransmission(seq_number, now());
         if let Some(info) = self.packets_in_flight.get_mut(&seq_number) {
             if !info.acked_by_kcp {
                 self.bytes_in_flight = self.bytes_in_flight.saturating_sub(info.size as u32);
@@ -242,119 +819,756 @@ impl ScreamCongestionControl {
     }
 
     // everytime a SCReAMv2 feedback packet arrives
-    pub fn on_ack_scream(&mut self, seq_number: u32, ack_timestamp: Instant) {
+    fn on_ack(&mut self, seq_number: u32, ack_timestamp: Instant) {
+        self.ack(seq_number, ack_timestamp, None);
+    }
+
+    fn on_loss(&mut self, seq_number: u32) {
+        let now = now();
+        // remove bytes in flight
+        if let Some(info) = self.packets_in_flight.remove(&seq_number) {
+            // The retransmission takes the place of the lost segment in flight
+            self.bytes_lost += info.size as u32;
+            self.bytes_retransmitted += info.size as u32;
+            self.retransmissions.insert(
+                seq_number,
+                Retransmission {
+                    first_sent: info.timestamp,
+                    retransmitted: now,
+                    size: info.size,
+                    settled: info.acked_by_kcp,
+                    spurious: false,
+                },
+            );
+            self.loss_occured_in_rtt = true;
+            self.loss_for_log = true; 
+            self.decrease_window(now, true, false);
+        } else if let Some(retransmission) = self.retransmissions.get_mut(&seq_number) {
+            // The retransmission timed out as well, it is still in flight once
+            retransmission.retransmitted = now;
+            self.bytes_retransmitted += retransmission.size as u32;
+        } else {
+            trace!("lost sn={} not in flight, bytes_in_flight={}", seq_number, self.bytes_in_flight);
+        }
+    }
+
+    fn on_expired(&mut self, seq_number: u32) {
+        self.drop_retransmission(seq_number);
+        // Leaves the flight without touching the window
         if let Some(info) = self.packets_in_flight.remove(&seq_number) {
             if !info.acked_by_kcp {
-                // remove from bytes in flight
                 self.bytes_in_flight = self.bytes_in_flight.saturating_sub(info.size as u32);
             }
+        }
+    }
 
-            // add ACK'ed bytes to the list for this rtt
-            self.bytes_newly_acked += info.size as u32;
+    fn metrics_sample(&mut self) -> MetricsSample {
+        let loss = self.loss_for_log;
+        self.loss_for_log = false;
 
-            let latest_rtt = ack_timestamp.saturating_duration_since(info.timestamp);
-            if latest_rtt.is_zero() { return; }
-            
+        MetricsSample {
+            s_rtt: Duration::from_secs_f32(self.s_rtt),
+            base_rtt: self.base_rtt,
+            qdelay: self.qdelay,
+            qdelay_avg: Duration::from_secs_f32(self.qdelay_avg),
+            target_bitrate: self.get_target_bitrate(),
+            congestion_window: self.ref_wnd,
+            bytes_in_flight: self.bytes_in_flight,
+            max_bytes_in_flight: self.max_bytes_in_flight,
+            loss,
+            ..MetricsSample::default()
+        }
+    }
 
-            if self.first_rtt_measurement {
-                self.s_rtt = latest_rtt.as_secs_f32();
-                self.base_rtt = latest_rtt; // set first base_rtt for first 10 seconds
-                self.rtt_var = self.s_rtt / 2.0;
-                self.first_rtt_measurement = false;
-            } else {
-                let alpha = 0.125;
-                let beta = 0.25;
-                let rtt_now_secs = latest_rtt.as_secs_f32();
-                self.rtt_var = (1.0 - beta) * self.rtt_var + beta * (self.s_rtt - rtt_now_secs).abs();
-                self.s_rtt = (1.0 - alpha) * self.s_rtt + alpha * rtt_now_secs;
+    fn get_target_bitrate(&self) -> f32 {
+        let target_bitrate = if self.s_rtt <= 0.0 {
+            self.min_target_bitrate
+        } else {
+            (self.ref_wnd * 8.0 / self.s_rtt)
+                .max(self.min_target_bitrate)
+                .min(self.max_target_bitrate)
+        };
+        // The receiver's limit goes below the lower bound, more would only queue up at its end
+        target_bitrate.min(self.remote_max_bitrate.unwrap_or(f32::INFINITY))
+    }
 
-                println!("{}", s_rtt)
-            }
+    fn get_pacing_rate(&self) -> f32 {
+        self.get_target_bitrate() * self.config.packet_pacing_headroom * self.refresh_scale()
+    }
 
-            // update base_rtt every 10 seconds
-            self.min_rtt_in_window = min(self.min_rtt_in_window, latest_rtt);
-            if self.base_rtt_update_time.elapsed() >= BASE_RTT_WINDOW {
-                self.base_rtt = self.min_rtt_in_window;       
-                self.min_rtt_in_window = Duration::from_secs(10);
-                self.base_rtt_update_time = Instant::now();
-            }
-            self.qdelay = latest_rtt.saturating_sub(self.base_rtt);
-            let qdelay_sample = self.qdelay.as_secs_f32();
-            let q_alpha = 0.1;
-            self.qdelay_avg = (1.0 - q_alpha) * self.qdelay_avg + q_alpha * qdelay_sample;
+    fn get_remote_max_bitrate(&self) -> Option<f32> {
+        self.remote_max_bitrate
+    }
+
+    fn get_congestion_window(&self) -> f32 {
+        self.ref_wnd * self.refresh_scale()
+    }
+
+    fn get_s_rtt(&self) -> f32 {
+        self.s_rtt
+    }
+
+    fn stats(&self) -> CongestionStats {
+        CongestionStats {
+            s_rtt: Duration::from_secs_f32(self.s_rtt),
+            rtt_var: Duration::from_secs_f32(self.rtt_var),
+            base_rtt: self.base_rtt,
+            qdelay: self.qdelay,
+            qdelay_avg: Duration::from_secs_f32(self.qdelay_avg),
+            loss_rate: self.loss_rate,
+            ce_rate: self.ce_rate,
+            congestion_window: self.ref_wnd,
+            bytes_in_flight: self.bytes_in_flight,
+            orphaned_packets: self.orphaned_packets,
+            retransmitted_bytes: self.retransmitted_bytes,
+            spurious_retransmits: self.spurious_retransmits,
         }
     }
+}
 
-    pub fn on_packet_loss(&mut self, seq_number: u32) {
-        // remove bytes in flight
-        if let Some(info) = self.packets_in_flight.remove(&seq_number) {
-            self.bytes_in_flight = self.bytes_in_flight.saturating_sub(info.size as u32);
-            self.loss_occured_in_rtt = true;
-            self.loss_for_log = true; 
-            self.decrease_window(Instant::now(), true, false);
-        } else {
-            print!("MAYDAY MAYDAY, lost packet not removed from bytes in flight: {}", self.bytes_in_flight);
+#[cfg(test)]
+mod test {
+    use std::time::{Duration, Instant};
+
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::feedback::FEEDBACK_ENTRY_LEN;
+
+    fn feedback_entry(sn: u32, ecn: EcnCodepoint) -> Vec<u8> {
+        let mut entry = Vec::with_capacity(FEEDBACK_ENTRY_LEN);
+        entry.extend_from_slice(&sn.to_le_bytes());
+        entry.extend_from_slice(&0u64.to_le_bytes());
+        entry.push(ecn as u8);
+        entry
+    }
+
+    #[test]
+    fn one_way_qdelay_ignores_clock_offset_and_reverse_path() {
+        let mut scream = ScreamCongestionControl::new();
+        // The peer's clock runs 5 s behind
+        let send_and_ack = |scream: &mut ScreamCongestionControl, sn: u32, owd_ms: i64, rtt_ms: u64| {
+            scream.on_packet_sent(sn, 1000);
+            let sent_ms = scream.unix_millis_at(scream.packets_in_flight[&sn].timestamp);
+            let mut entry = feedback_entry(sn, EcnCodepoint::Ect1);
+            entry[4..12].copy_from_slice(&((sent_ms - 5000 + owd_ms) as u64).to_le_bytes());
+            scream.on_feedback(&entry, Instant::now() + Duration::from_millis(rtt_ms));
+            scream.qdelay
+        };
+
+        assert_eq!(send_and_ack(&mut scream, 0, 30, 60), Duration::ZERO);
+        // Congestion on the way back only
+        assert_eq!(send_and_ack(&mut scream, 1, 30, 300), Duration::ZERO);
+        assert_eq!(send_and_ack(&mut scream, 2, 50, 80), Duration::from_millis(20));
+        // A smaller delay becomes the new base right away
+        assert_eq!(send_and_ack(&mut scream, 3, 20, 50), Duration::ZERO);
+
+        // Without reception times the RTT is used
+        let rtt_qdelay = {
+            scream.on_packet_sent(4, 1000);
+            scream.on_feedback(&feedback_entry(4, EcnCodepoint::Ect1), Instant::now() + Duration::from_millis(300));
+            scream.qdelay
+        };
+        assert!(rtt_qdelay >= Duration::from_millis(200));
+
+        // Reception times no clock shows are dropped or saturate
+        for (sn, reception_time_ms) in [(5, u64::MAX), (6, 1 << 63), (7, i64::MAX as u64), (8, 1)] {
+            scream.on_packet_sent(sn, 1000);
+            let mut entry = feedback_entry(sn, EcnCodepoint::Ect1);
+            entry[4..12].copy_from_slice(&reception_time_ms.to_le_bytes());
+            scream.on_feedback(&entry, Instant::now() + Duration::from_millis(50));
+            assert!(!scream.packets_in_flight.contains_key(&sn));
         }
     }
-    
 
-    pub fn log_data(&mut self) {
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap_or_default()
-            .as_millis();
-        let packet_loss_indicator = if self.loss_for_log { 1 } else { 0 };
-        self.loss_for_log = false;
+    #[test]
+    fn ce_feedback_reduces_window() {
+        let mut scream = ScreamCongestionControl::new();
+        scream.ref_wnd = 20_000.0;
+        scream.last_congestion_detected_time = Instant::now() - Duration::from_secs(1);
 
-        let log_line = format!(
-            "{},{},{},{},{},{},{},{},{},{}\n",
-            timestamp,
-            (self.s_rtt * 1000.0) as u128, 
-            (self.base_rtt.as_secs_f32() * 1000.0) as u128, 
-            (self.qdelay.as_secs_f32() * 1000.0) as u128, 
-            (self.qdelay_avg * 1000.0) as u128,
-            self.get_target_bitrate() / 1000.0, 
-            self.ref_wnd, 
-            self.bytes_in_flight,
-            self.max_bytes_in_flight,
-            packet_loss_indicator,
-        );
-
-        if let Ok(mut file) = OpenOptions::new()
-            .create(true)
-            .append(true)
-            .open("scream_log.csv")
-        {
-            if file.metadata().unwrap().len() == 0 {
-                let _ = file.write_all(b"timestamp_ms,s_rtt_ms,base_rtt_ms,qdelay_ms,qdelay_avg_ms,bitrate_kbps,cwnd_bytes,bytes_in_flight,max_bytes_in_flight,packet_loss\n");
+        for sn in 0..4 {
+            scream.on_packet_sent(sn, 1000);
+        }
+
+        let ack_time = Instant::now() + Duration::from_millis(20);
+        let mut feedback = feedback_entry(0, EcnCodepoint::Ect1);
+        feedback.extend(feedback_entry(1, EcnCodepoint::Ce));
+        scream.on_feedback(&feedback, ack_time);
+
+        assert_eq!(scream.bytes_newly_acked, 2000);
+        assert_eq!(scream.bytes_newly_acked_ce, 1000);
+        assert_eq!(scream.bytes_in_flight, 2000);
+        assert!(scream.ref_wnd <= 20_000.0 * BETA_ECN);
+
+        // a second CE mark within the same RTT doesn't cut the window again
+        let ref_wnd = scream.ref_wnd;
+        scream.on_feedback(&feedback_entry(2, EcnCodepoint::Ce), ack_time);
+        assert_eq!(scream.ref_wnd, ref_wnd);
+    }
+
+    #[test]
+    fn rtt_loss_and_ce_rates() {
+        let mut scream = ScreamCongestionControl::new();
+        for sn in 0..4 {
+            scream.on_packet_sent(sn, 1000);
+        }
+
+        let ack_time = Instant::now() + Duration::from_millis(20);
+        let mut feedback = feedback_entry(0, EcnCodepoint::Ect1);
+        feedback.extend(feedback_entry(1, EcnCodepoint::Ect1));
+        feedback.extend(feedback_entry(2, EcnCodepoint::Ce));
+        scream.on_feedback(&feedback, ack_time);
+        scream.on_loss(3);
+        scream.on_rtt();
+
+        let stats = scream.stats();
+        assert_eq!(stats.loss_rate, 0.25);
+        assert!((stats.ce_rate - 1.0 / 3.0).abs() < 1e-6);
+
+        // An RTT without acks or losses keeps the last rates
+        scream.on_rtt();
+        assert_eq!(scream.stats().loss_rate, 0.25);
+    }
+
+    #[test]
+    fn rtt_retransmissions_and_spurious() {
+        let mut scream = ScreamCongestionControl::new();
+        scream.on_packet_sent(0, 1000);
+        scream.on_ack(0, Instant::now() + Duration::from_millis(50));
+
+        scream.on_packet_sent(1, 1000);
+        scream.on_packet_sent(2, 1000);
+        scream.on_loss(1);
+        scream.on_loss(2);
+        scream.on_loss(2);
+        // The original of sn 1 arrives right after its retransmission
+        scream.on_kcp_ack(1);
+        scream.on_ack(2, Instant::now() + Duration::from_millis(200));
+        scream.on_rtt();
+
+        let stats = scream.stats();
+        assert_eq!(stats.retransmitted_bytes, 3000);
+        assert_eq!(stats.spurious_retransmits, 1);
+        assert_eq!(scream.bytes_in_flight, 0);
+
+        scream.on_rtt();
+        assert_eq!(scream.stats().retransmitted_bytes, 0);
+        assert_eq!(scream.stats().spurious_retransmits, 0);
+    }
+
+    #[test]
+    fn retransmissions_stay_in_flight_once() {
+        let mut scream = ScreamCongestionControl::new();
+        scream.on_packet_sent(0, 1000);
+        scream.on_ack(0, Instant::now() + Duration::from_millis(50));
+
+        scream.on_packet_sent(1, 1000);
+        scream.on_packet_sent(2, 1000);
+        scream.on_loss(1);
+        scream.on_loss(1);
+        scream.on_loss(2);
+        assert_eq!(scream.bytes_in_flight, 2000);
+
+        // Both acks of a retransmission settle it once
+        scream.on_kcp_ack(1);
+        scream.on_ack(1, Instant::now() + Duration::from_millis(200));
+        assert_eq!(scream.bytes_in_flight, 1000);
+        assert_eq!(scream.bytes_newly_acked, 2000);
+
+        // A late ack of the first transmission is an RTT sample from its send time
+        let first_sent = scream.retransmissions[&2].first_sent;
+        let s_rtt = scream.s_rtt;
+        scream.on_ack(2, first_sent + Duration::from_millis(40));
+        assert_eq!(scream.bytes_in_flight, 0);
+        assert!(scream.s_rtt < s_rtt);
+
+        // Unacknowledged retransmissions leave the flight with the stale entries
+        scream.on_packet_sent(3, 1000);
+        scream.on_loss(3);
+        scream.purge_stale_segments(Instant::now() + MIN_STALE_SEGMENT_AGE);
+        assert_eq!(scream.bytes_in_flight, 0);
+        assert!(scream.retransmissions.is_empty());
+    }
+
+    #[test]
+    fn heavy_loss_backs_off_further() {
+        let config = ScreamConfig {
+            heavy_loss_rate: Some(0.1),
+            ..ScreamConfig::default()
+        };
+        let mut plain = ScreamCongestionControl::new();
+        let mut heavy = ScreamCongestionControl::with_config(config, FeedbackFormat::Native);
+        for scream in [&mut plain, &mut heavy] {
+            scream.ref_wnd = 100_000.0;
+            for sn in 0..4 {
+                scream.on_packet_sent(sn, 1000);
             }
-            let _ = file.write_all(log_line.as_bytes());
+            scream.on_feedback(&feedback_entry(0, EcnCodepoint::Ect1), Instant::now() + Duration::from_millis(20));
+            scream.on_loss(1);
+            scream.on_rtt();
         }
+        assert_eq!(heavy.ref_wnd, plain.ref_wnd * BETA_HEAVY_LOSS);
     }
 
+    fn ack_round(scream: &mut ScreamCongestionControl, first_sn: &mut u32) {
+        let packets = (scream.ref_wnd / 1000.0) as u32;
+        let mut feedback = Vec::new();
+        for sn in *first_sn..*first_sn + packets {
+            scream.on_packet_sent(sn, 1000);
+            feedback.extend(feedback_entry(sn, EcnCodepoint::Ect1));
+        }
+        *first_sn += packets;
+        scream.on_send_queue(1);
+        scream.on_feedback(&feedback, Instant::now() + Duration::from_millis(20));
+        scream.on_rtt();
+    }
+
+    #[test]
+    fn slow_start_doubles_until_congestion() {
+        let mut scream = ScreamCongestionControl::new();
+        let mut sn = 0;
+        for _ in 0..5 {
+            let ref_wnd = scream.ref_wnd;
+            ack_round(&mut scream, &mut sn);
+            assert_eq!(scream.ref_wnd, 2.0 * ref_wnd);
+        }
+
+        scream.on_packet_sent(sn, 1000);
+        scream.on_loss(sn);
+        sn += 1;
+        assert!(!scream.in_slow_start);
+        let ref_wnd = scream.ref_wnd;
+        ack_round(&mut scream, &mut sn);
+        assert!(scream.ref_wnd < 1.5 * ref_wnd);
 
-    pub fn get_last_feedback_time(&self) -> Instant {
-        return self.last_feedback_time
+        let config = ScreamConfig {
+            slow_start: false,
+            ..ScreamConfig::default()
+        };
+        let mut scream = ScreamCongestionControl::with_config(config, FeedbackFormat::Native);
+        let mut sn = 0;
+        for _ in 0..5 {
+            ack_round(&mut scream, &mut sn);
+        }
+        assert!(scream.ref_wnd < 8.0 * 2.0 * DEFAULT_MSS);
     }
 
-    pub fn get_target_bitrate(&self) -> f32 {
-        if self.s_rtt <= 0.0 { return 500_000.0; }
-        (self.ref_wnd * 8.0 / self.s_rtt).clamp(500_000.0, 10_000_000.0)
-    }  
+    #[test]
+    fn base_rtt_refresh_follows_longer_path() {
+        let interval = Duration::from_secs(30);
+        let config = ScreamConfig {
+            base_rtt_refresh_interval: Some(interval),
+            ..ScreamConfig::default()
+        };
+        let mut scream = ScreamCongestionControl::with_config(config, FeedbackFormat::Native);
+        scream.ref_wnd = 20_000.0;
+        scream.on_packet_sent(0, 1000);
+        scream.on_ack(0, Instant::now() + Duration::from_millis(100));
+        assert!(scream.base_rtt >= Duration::from_millis(100));
 
-    pub fn get_pacing_rate(&self) -> f32 {
-        self.get_target_bitrate() * PACKET_PACING_HEADROOM
-    } 
+        // Not due yet
+        scream.on_rtt();
+        assert_eq!(scream.get_congestion_window(), 20_000.0);
 
-    pub fn get_ref_wnd(&self) -> f32 {
-        self.ref_wnd
+        scream.last_base_rtt_refresh = Instant::now() - interval;
+        let pacing_rate = scream.get_pacing_rate();
+        scream.on_rtt();
+        assert_eq!(scream.get_congestion_window(), 20_000.0 * BASE_RTT_REFRESH_SCALE);
+        assert_eq!(scream.get_pacing_rate(), pacing_rate * BASE_RTT_REFRESH_SCALE);
+
+        // The route got longer, the drained queue shows it
+        scream.on_packet_sent(1, 1000);
+        scream.on_ack(1, Instant::now() + Duration::from_millis(200));
+        scream.base_rtt_refresh_until = Some(Instant::now());
+        scream.on_rtt();
+        assert!(scream.base_rtt >= Duration::from_millis(200));
+        // The window grows back over a few RTTs
+        assert_eq!(scream.ref_wnd, 20_000.0);
+        assert!(scream.get_congestion_window() < 20_000.0 * 0.6);
+        let ended = scream.base_rtt_refresh_ended.unwrap();
+        scream.base_rtt_refresh_ended = Some(ended - Duration::from_secs_f32(scream.s_rtt * BASE_RTT_RAMP_RTTS / 2.0));
+        let window = scream.get_congestion_window();
+        assert!(window > 20_000.0 * 0.7 && window < 20_000.0 * 0.8);
+        scream.base_rtt_refresh_ended = Some(ended - Duration::from_secs_f32(scream.s_rtt * BASE_RTT_RAMP_RTTS));
+        assert_eq!(scream.get_congestion_window(), 20_000.0);
+
+        // Off by default
+        let mut scream = ScreamCongestionControl::new();
+        scream.on_packet_sent(0, 1000);
+        scream.on_ack(0, Instant::now() + Duration::from_millis(100));
+        scream.last_base_rtt_refresh = Instant::now() - interval;
+        scream.on_rtt();
+        assert!(scream.base_rtt_refresh_until.is_none());
     }
 
-    pub fn get_last_periodic_update_time(&self) -> Instant {
-        self.last_periodic_update_time
+    #[test]
+    fn config_tunes_loss_backoff() {
+        let config = ScreamConfig {
+            beta_loss: 0.5,
+            ..ScreamConfig::default()
+        };
+        let mut scream = ScreamCongestionControl::with_config(config, FeedbackFormat::Native);
+        scream.ref_wnd = 20_000.0;
+
+        scream.on_packet_sent(0, 1000);
+        scream.on_loss(0);
+        assert_eq!(scream.ref_wnd, 10_000.0);
+        assert_eq!(scream.poll_congestion_event(), Some(CongestionEvent::Loss));
+        assert_eq!(scream.poll_congestion_event(), None);
+
+        // Bitrate bounds apply before the first RTT sample
+        assert_eq!(scream.get_target_bitrate(), MIN_TARGET_BITRATE);
     }
 
-    pub fn get_s_rtt(&self) -> f32 {
-        self.s_rtt
+    #[test]
+    fn feedback_roundtrip_carries_ecn() {
+        let mut receiver = ScreamCongestionControl::new();
+        receiver.on_packet_received(7, Instant::now(), EcnCodepoint::Ce);
+        receiver.on_packet_received(8, Instant::now(), EcnCodepoint::NotEct);
+
+        let feedback = receiver.create_feedback_packet().unwrap();
+        assert_eq!(feedback.len(), 2 * FEEDBACK_ENTRY_LEN);
+        assert_eq!(EcnCodepoint::from_bits(feedback[12]), EcnCodepoint::Ce);
+        assert_eq!(EcnCodepoint::from_bits(feedback[25]), EcnCodepoint::NotEct);
+        assert!(receiver.create_feedback_packet().is_none());
+    }
+
+    #[test]
+    fn rfc8888_feedback_acks_packets() {
+        let mut sender = ScreamCongestionControl::with_feedback_format(FeedbackFormat::Rfc8888);
+        let mut receiver = ScreamCongestionControl::with_feedback_format(FeedbackFormat::Rfc8888);
+
+        for sn in 0..3 {
+            sender.on_packet_sent(sn, 1000);
+        }
+        receiver.on_packet_received(0, Instant::now(), EcnCodepoint::Ect1);
+        receiver.on_packet_received(2, Instant::now(), EcnCodepoint::Ect1);
+
+        let feedback = receiver.create_feedback_packet().unwrap();
+        sender.on_feedback(&feedback, Instant::now() + Duration::from_millis(20));
+
+        assert_eq!(sender.bytes_newly_acked, 2000);
+        assert_eq!(sender.bytes_in_flight, 1000);
+        assert!(sender.packets_in_flight.contains_key(&1));
+    }
+
+    #[test]
+    fn feedback_split_across_sn_wrap() {
+        let mut sender = ScreamCongestionControl::with_feedback_format(FeedbackFormat::Rfc8888);
+        let mut receiver = ScreamCongestionControl::with_feedback_format(FeedbackFormat::Rfc8888);
+        receiver.on_mss_changed(200);
+
+        // 16-bit RFC 8888 sequence numbers wrap in the middle
+        let sns = 0xFF80..0x1_0080;
+        for sn in sns.clone() {
+            sender.on_packet_sent(sn, 1000);
+            receiver.on_packet_received(sn, Instant::now(), EcnCodepoint::Ect1);
+        }
+
+        let mut chunks = 0;
+        while let Some(feedback) = receiver.create_feedback_packet() {
+            assert!(feedback.len() <= 200);
+            let entries = feedback::decode(FeedbackFormat::Rfc8888, &feedback, sns.end - 1, 0);
+            assert!(!entries.is_empty());
+            sender.on_feedback(&feedback, Instant::now() + Duration::from_millis(20));
+            chunks += 1;
+        }
+
+        assert!(chunks > 1);
+        assert_eq!(sender.bytes_newly_acked, sns.len() as u32 * 1000);
+        assert_eq!(sender.bytes_in_flight, 0);
+    }
+
+    #[test]
+    fn remote_max_bitrate_caps_target() {
+        for format in [FeedbackFormat::Native, FeedbackFormat::Rfc8888] {
+            let mut sender = ScreamCongestionControl::with_feedback_format(format);
+            let config = ScreamConfig {
+                max_receive_bitrate: Some(300_000.0),
+                ..ScreamConfig::default()
+            };
+            let mut receiver = ScreamCongestionControl::with_config(config, format);
+
+            sender.on_packet_sent(0, 1000);
+            receiver.on_packet_received(0, Instant::now(), EcnCodepoint::Ect1);
+            let feedback = receiver.create_feedback_packet().unwrap();
+            sender.on_feedback(&feedback, Instant::now() + Duration::from_millis(20));
+            assert_eq!(sender.bytes_newly_acked, 1000);
+            assert_eq!(sender.get_target_bitrate(), 300_000.0);
+            assert_eq!(sender.get_pacing_rate(), 300_000.0 * PACKET_PACING_HEADROOM);
+
+            // Lifting the limit at runtime stops advertising it
+            receiver.on_reconfigure(&KcpRuntimeConfig {
+                max_receive_bitrate: Some(f32::INFINITY),
+                ..KcpRuntimeConfig::default()
+            });
+            sender.on_packet_sent(1, 1000);
+            receiver.on_packet_received(1, Instant::now(), EcnCodepoint::Ect1);
+            let feedback = receiver.create_feedback_packet().unwrap();
+            sender.on_feedback(&feedback, Instant::now() + Duration::from_millis(20));
+            assert!(sender.get_target_bitrate() >= MIN_TARGET_BITRATE);
+        }
+    }
+
+    #[test]
+    fn target_bitrate_bounds() {
+        let scream = ScreamCongestionControl::new().with_target_bitrate_bounds(1_000_000.0, 2_000_000.0).unwrap();
+        assert_eq!(scream.get_target_bitrate(), 1_000_000.0);
+
+        for (min, max) in [(2_000_000.0, 1_000_000.0), (1_000_000.0, f32::NAN), (f32::NAN, 1_000_000.0), (0.0, 1.0)] {
+            assert!(ScreamCongestionControl::new().with_target_bitrate_bounds(min, max).is_err());
+        }
+        assert!(ScreamCongestionControl::new().with_target_bitrate_bounds(1.0, f32::INFINITY).is_err());
+    }
+
+    #[test]
+    fn bookkeeping_across_sn_wrap() {
+        let mut sender = ScreamCongestionControl::with_feedback_format(FeedbackFormat::Rfc8888);
+        let mut receiver = ScreamCongestionControl::with_feedback_format(FeedbackFormat::Rfc8888);
+
+        // KCP sns around 2^32 as the controllers see them
+        let sns: Vec<u32> = (u32::MAX - 4..=u32::MAX).chain(0..5).map(feedback::segment_sn).collect();
+        for &sn in &sns {
+            sender.on_packet_sent(sn, 1000);
+            receiver.on_packet_received(sn, Instant::now(), EcnCodepoint::Ect1);
+        }
+        assert_eq!(sender.highest_sent_sn, 4);
+
+        let feedback = receiver.create_feedback_packet().unwrap();
+        sender.on_feedback(&feedback, Instant::now() + Duration::from_millis(20));
+        assert_eq!(sender.bytes_newly_acked, 10_000);
+        assert_eq!(sender.bytes_in_flight, 0);
+        assert!(sender.packets_in_flight.is_empty());
+
+        // Datagrams wrap within their own 31 bits
+        sender.on_packet_sent(DATAGRAM_SN_FLAG | feedback::SN_MASK, 100);
+        sender.on_packet_sent(DATAGRAM_SN_FLAG, 100);
+        assert_eq!(sender.highest_sent_datagram_sn, DATAGRAM_SN_FLAG);
+    }
+
+    #[test]
+    fn stale_segments_are_purged() {
+        let mut scream = ScreamCongestionControl::new();
+        scream.on_packet_sent(1, 1000);
+        scream.on_packet_sent(2, 1000);
+        scream.on_kcp_ack(1);
+        assert_eq!(scream.bytes_in_flight, 1000);
+
+        scream.purge_stale_segments(Instant::now());
+        assert_eq!(scream.packets_in_flight.len(), 2);
+        scream.purge_stale_segments(Instant::now() + MIN_STALE_SEGMENT_AGE);
+        assert!(scream.packets_in_flight.is_empty());
+        assert_eq!(scream.bytes_in_flight, 0);
+        assert_eq!(scream.stats().orphaned_packets, 2);
+        assert!(!scream.loss_occured_in_rtt);
+
+        // An sn coming around again replaces its leftover
+        scream.on_packet_sent(3, 1000);
+        scream.on_packet_sent(3, 500);
+        assert_eq!(scream.bytes_in_flight, 500);
+    }
+
+    #[test]
+    fn packets_in_flight_are_capped() {
+        let mut scream = ScreamCongestionControl::new();
+        for sn in 0..=MAX_PACKETS_IN_FLIGHT as u32 {
+            scream.on_packet_sent(sn, 10);
+            scream.on_kcp_ack(sn);
+        }
+        assert_eq!(scream.packets_in_flight.len(), MAX_PACKETS_IN_FLIGHT * 3 / 4);
+        assert_eq!(scream.stats().orphaned_packets, (MAX_PACKETS_IN_FLIGHT / 4 + 1) as u64);
+        assert_eq!(scream.bytes_in_flight, 0);
+        // The newest entries survive
+        assert!(scream.packets_in_flight.contains_key(&(MAX_PACKETS_IN_FLIGHT as u32)));
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn expired_is_not_loss() {
+        let mut scream = ScreamCongestionControl::new();
+        scream.ref_wnd = 20_000.0;
+
+        scream.on_packet_sent(0, 1000);
+        scream.on_packet_sent(1, 1000);
+        scream.on_expired(0);
+
+        assert_eq!(scream.bytes_in_flight, 1000);
+        assert!(!scream.packets_in_flight.contains_key(&0));
+        assert_eq!(scream.ref_wnd, 20_000.0);
+        assert!(!scream.loss_occured_in_rtt);
+    }
+
+    #[test]
+    fn unreported_datagrams_are_lost() {
+        let mut scream = ScreamCongestionControl::new();
+        scream.ref_wnd = 20_000.0;
+
+        scream.on_packet_sent(0, 1000);
+        scream.on_packet_sent(DATAGRAM_SN_FLAG, 1000);
+        scream.on_packet_sent(DATAGRAM_SN_FLAG | 1, 1000);
+        assert_eq!(scream.bytes_in_flight, 3000);
+
+        scream.on_feedback(&feedback_entry(DATAGRAM_SN_FLAG | 1, EcnCodepoint::NotEct), Instant::now());
+        scream.detect_datagram_loss(Instant::now() + MIN_DATAGRAM_LOSS_TIMEOUT);
+
+        // The KCP segment waits for KCP to decide, the unreported datagram is gone
+        assert_eq!(scream.bytes_in_flight, 1000);
+        assert!(scream.packets_in_flight.contains_key(&0));
+        assert_eq!(scream.ref_wnd, 20_000.0 * BETA_LOSS);
+    }
+
+    // One RTT with 10 KB sent, acknowledged and `queued` segments waiting for the window
+    fn run_rtt(scream: &mut ScreamCongestionControl, first_sn: u32, queued: usize) {
+        for sn in first_sn..first_sn + 10 {
+            scream.on_packet_sent(sn, 1000);
+        }
+        scream.on_send_queue(queued);
+        let feedback: Vec<u8> = (first_sn..first_sn + 10)
+            .flat_map(|sn| feedback_entry(sn, EcnCodepoint::NotEct))
+            .collect();
+        scream.on_feedback(&feedback, Instant::now() + Duration::from_millis(20));
+        scream.on_rtt();
+    }
+
+    #[test]
+    fn app_limited_freezes_window() {
+        let mut scream = ScreamCongestionControl::new();
+        scream.ref_wnd = 12_000.0;
+        scream.ref_wnd_i = 12_000.0;
+        scream.in_slow_start = false;
+        scream.last_congestion_detected_time = Instant::now() - Duration::from_secs(1);
+
+        // Window-limited, the window grows
+        run_rtt(&mut scream, 0, 5);
+        run_rtt(&mut scream, 10, 5);
+        let ref_wnd = scream.ref_wnd;
+        assert!(ref_wnd > 12_000.0);
+
+        // Nothing queued and 10 KB in flight is still most of the window
+        run_rtt(&mut scream, 20, 0);
+        assert!(scream.ref_wnd > ref_wnd);
+
+        // Little in flight and nothing queued, acks don't grow the window
+        scream.ref_wnd = 40_000.0;
+        run_rtt(&mut scream, 30, 0);
+        assert_eq!(scream.ref_wnd, 40_000.0);
+    }
+
+    #[derive(Debug, Clone)]
+    enum Op {
+        Send(u32, usize),
+        KcpAck(u32),
+        Feedback(u32, bool),
+        Loss(u32),
+        Expired(u32),
+        SendQueue(usize),
+        Rtt,
+        Wait(u64),
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        let sn = (0u32..48, any::<bool>()).prop_map(|(sn, datagram)| if datagram { sn | DATAGRAM_SN_FLAG } else { sn });
+        prop_oneof![
+            4 => (sn.clone(), 1usize..1400).prop_map(|(sn, size)| Op::Send(sn, size)),
+            2 => sn.clone().prop_map(Op::KcpAck),
+            3 => (sn.clone(), any::<bool>()).prop_map(|(sn, ce)| Op::Feedback(sn, ce)),
+            1 => sn.clone().prop_map(Op::Loss),
+            1 => sn.prop_map(Op::Expired),
+            1 => (0usize..3).prop_map(Op::SendQueue),
+            1 => Just(Op::Rtt),
+            1 => (0u64..200).prop_map(Op::Wait),
+        ]
+    }
+
+    // What the flight holds, whatever `bytes_in_flight` says
+    fn accounted_bytes_in_flight(scream: &ScreamCongestionControl) -> u32 {
+        let packets: usize =
+            scream.packets_in_flight.values().filter(|info| !info.acked_by_kcp).map(|info| info.size).sum();
+        let retransmissions: usize =
+            scream.retransmissions.values().filter(|retransmission| !retransmission.settled).map(|r| r.size).sum();
+        (packets + retransmissions) as u32
+    }
+
+    proptest! {
+        #[test]
+        fn window_and_flight_invariants(ops in prop::collection::vec(op(), 1..300)) {
+            let mut scream = ScreamCongestionControl::new();
+            // Feedback arrives on a clock of its own, `Wait` moves it on
+            let mut clock = Instant::now();
+
+            for op in ops {
+                match op {
+                    Op::Send(sn, size) => scream.on_packet_sent(sn, size),
+                    Op::KcpAck(sn) => scream.on_kcp_ack(sn),
+                    Op::Feedback(sn, ce) => {
+                        let ecn = if ce { EcnCodepoint::Ce } else { EcnCodepoint::Ect1 };
+                        scream.on_feedback(&feedback_entry(sn, ecn), clock);
+                    }
+                    Op::Loss(sn) => scream.on_loss(sn),
+                    Op::Expired(sn) => scream.on_expired(sn),
+                    Op::SendQueue(queued) => scream.on_send_queue(queued),
+                    Op::Rtt => {
+                        let ref_wnd = scream.ref_wnd;
+                        let in_slow_start = scream.in_slow_start;
+                        let prev = scream.max_bytes_in_flight_prev as f32;
+                        let max = scream.max_bytes_in_flight as f32;
+                        scream.on_rtt();
+                        // Growth stops at the headroom over what was in flight
+                        if scream.ref_wnd > ref_wnd {
+                            let mut cap = prev * BYTES_IN_FLIGHT_HEAD_ROOM;
+                            if in_slow_start {
+                                cap = cap.max(max.max(prev) * SLOW_START_HEAD_ROOM);
+                            }
+                            prop_assert!(scream.ref_wnd <= cap * 1.0001, "ref_wnd {} > cap {}", scream.ref_wnd, cap);
+                        }
+                    }
+                    Op::Wait(millis) => clock += Duration::from_millis(millis),
+                }
+                prop_assert!(scream.ref_wnd >= MIN_REF_WND as f32, "ref_wnd {}", scream.ref_wnd);
+                prop_assert!(scream.ref_wnd.is_finite());
+                // Saturating arithmetic would hide a count going below zero
+                prop_assert_eq!(scream.bytes_in_flight, accounted_bytes_in_flight(&scream));
+            }
+        }
+
+        #[test]
+        fn decrease_window_stays_in_bounds(
+            ref_wnd in (MIN_REF_WND as f32)..1e7,
+            qdelay_avg in 0f32..1.0,
+            is_loss in any::<bool>(),
+            is_ce in any::<bool>(),
+        ) {
+            let mut scream = ScreamCongestionControl::new();
+            scream.ref_wnd = ref_wnd;
+            scream.qdelay_avg = qdelay_avg;
+            scream.decrease_window(Instant::now() + Duration::from_secs(1), is_loss, is_ce);
+
+            prop_assert!(scream.ref_wnd <= ref_wnd);
+            prop_assert!(scream.ref_wnd >= MIN_REF_WND as f32);
+            // No single event takes more than the largest backoff
+            let beta = BETA_LOSS.min(BETA_ECN).min(0.5);
+            prop_assert!(scream.ref_wnd >= ref_wnd * beta * 0.9999);
+        }
+
+        #[test]
+        fn target_bitrate_monotone_in_ref_wnd(
+            ref_wnd in (MIN_REF_WND as f32)..1e7,
+            more in 0f32..1e7,
+            s_rtt in 0.001f32..2.0,
+            remote_max_bitrate in prop::option::of(1e4f32..1e8),
+        ) {
+            let mut scream = ScreamCongestionControl::new();
+            scream.s_rtt = s_rtt;
+            scream.remote_max_bitrate = remote_max_bitrate;
+            scream.ref_wnd = ref_wnd;
+            let lower = scream.get_target_bitrate();
+            scream.ref_wnd = ref_wnd + more;
+            let higher = scream.get_target_bitrate();
+
+            prop_assert!(lower <= higher, "{} bps at {} B, {} bps at {} B", lower, ref_wnd, higher, ref_wnd + more);
+            let max = MAX_TARGET_BITRATE.min(remote_max_bitrate.unwrap_or(f32::INFINITY));
+            prop_assert!(higher <= max);
+            prop_assert!(lower >= MIN_TARGET_BITRATE.min(max));
+        }
+    }
+}
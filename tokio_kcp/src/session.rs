@@ -1,36 +1,66 @@
 use std::{
-    collections::{hash_map::Entry, HashMap},
+    collections::{HashMap, VecDeque},
     fmt::{self, Debug},
+    io::IoSlice,
     net::SocketAddr,
     ops::Deref,
+    panic::{self, AssertUnwindSafe},
     sync::{
         atomic::{AtomicBool, Ordering},
         Arc,
     },
+    task::{Context, Poll},
     time::Duration,
 };
 
 use byte_string::ByteStr;
-use bytes::Buf;
-use kcp::KcpResult;
-use log::{error, trace};
+use kcp::{Error as KcpError, KcpResult};
 use spin::Mutex as SpinMutex;
 use tokio::{
-    net::UdpSocket,
     sync::{mpsc, watch, Notify},
+    task::JoinHandle,
     time::{self, Instant},
 };
+use tracing::{error, trace, Instrument, Span};
+
+use crate::{
+    batch::RecvBatch,
+    config::ConfigHook,
+    congestion::CongestionControllerFactory,
+    conv::ConvAllocator,
+    crypto,
+    driver::{DriverHandle, UpdateDriver},
+    ecn::EcnCodepoint,
+    fec,
+    halves::{RecvHalf, SendHalf},
+    pacer::RateBudget,
+    packet::{self, PacketType},
+    skcp::KcpSocket,
+    transport::DatagramTransport,
+    utils,
+    KcpConfig,
+};
 
-use crate::{skcp::KcpSocket, KcpConfig};
+// Convs asked from the allocator for one client before it is refused
+const MAX_CONV_ALLOC_ATTEMPTS: usize = 16;
+// Former addresses kept per session, the paths of a multipath peer
+const MAX_FORMER_ADDRS: usize = 8;
 
 pub struct KcpSession {
     socket: SpinMutex<KcpSocket>,
+    // Readers and writers go through these first, see `halves`
+    recv_half: Arc<RecvHalf>,
+    send_half: Arc<SendHalf>,
     pub target_bitrate_rx: watch::Receiver<f32>,
     closed: AtomicBool,
     session_expire: Option<Duration>,
-    session_close_notifier: Option<(mpsc::Sender<SocketAddr>, SocketAddr)>,
-    input_tx: mpsc::Sender<Vec<u8>>,
+    session_close_notifier: Option<mpsc::Sender<(SocketAddr, u32)>>,
+    input_tx: mpsc::Sender<(Vec<u8>, EcnCodepoint)>,
     notifier: Notify,
+    // Listener sessions are updated by the listener's driver, client sessions by a task of their own
+    driver: Option<DriverHandle>,
+    io_task: SpinMutex<Option<JoinHandle<()>>>,
+    span: Span,
 }
 
 impl Drop for KcpSession {
@@ -61,30 +91,45 @@ impl KcpSession {
         socket: KcpSocket,
         target_bitrate_rx: watch::Receiver<f32>,
         session_expire: Option<Duration>,
-        session_close_notifier: Option<(mpsc::Sender<SocketAddr>, SocketAddr)>,
-        input_tx: mpsc::Sender<Vec<u8>>,
+        session_close_notifier: Option<mpsc::Sender<(SocketAddr, u32)>>,
+        input_tx: mpsc::Sender<(Vec<u8>, EcnCodepoint)>,
+        driver: Option<DriverHandle>,
+        span: Span,
     ) -> KcpSession {
+        let (recv_half, send_half) = socket.halves();
         KcpSession {
             socket: SpinMutex::new(socket),
+            recv_half,
+            send_half,
             target_bitrate_rx,
             closed: AtomicBool::new(false),
             session_expire,
             session_close_notifier,
             input_tx,
             notifier: Notify::new(),
+            driver,
+            io_task: SpinMutex::new(None),
+            span,
         }
     }
 
     pub fn new_shared(
         (socket, target_bitrate_rx): (KcpSocket, watch::Receiver<f32>),
         session_expire: Option<Duration>,
-        session_close_notifier: Option<(mpsc::Sender<SocketAddr>, SocketAddr)>,
+        session_close_notifier: Option<mpsc::Sender<(SocketAddr, u32)>>,
+        driver: Option<&UpdateDriver>,
     ) -> Arc<KcpSession> {
         let is_client = session_close_notifier.is_none();
 
         let (input_tx, mut input_rx) = mpsc::channel(64);
 
-        let udp_socket = socket.udp_socket().clone();
+        let transport = socket.transport().clone();
+        let ecn = socket.ecn();
+        let cipher = socket.cipher().cloned();
+        let obfuscation = socket.obfuscation().cloned();
+        let udp_batch_size = socket.udp_batch_size();
+        let peer_addr = socket.peer_addr();
+        let span = socket.span().clone();
 
         let session = Arc::new(KcpSession::new(
             socket,
@@ -92,61 +137,108 @@ impl KcpSession {
             session_expire,
             session_close_notifier,
             input_tx,
+            driver.map(UpdateDriver::handle),
+            span.clone(),
         ));
+        let driver = driver.zip(session.driver.as_ref());
 
         let io_task_handle = {
             let session = session.clone();
             tokio::spawn(async move {
-                let mut input_buffer = [0u8; 65536];
+                let mut recv_batch = RecvBatch::new(udp_batch_size);
 
                 loop {
                     tokio::select! {
                         // recv() then input()
                         // Drives the KCP machine forward
-                        recv_result = udp_socket.recv(&mut input_buffer), if is_client => {
+                        recv_result = recv_batch.recv(&*transport, ecn), if is_client => {
                             match recv_result {
                                 Err(err) => {
                                     error!("[SESSION] UDP recv failed, error: {}", err);
                                     session.closed.store(true, Ordering::Release);
                                     break;
                                 }
-                                Ok(n) => {
-                                    let input_buffer = &input_buffer[..n];
+                                Ok(count) => {
+                                    for i in 0..count {
+                                        let (input_buffer, addr, ecn) = recv_batch.get_mut(i);
+                                        let n = input_buffer.len();
+                                        // The socket may be shared with other traffic
+                                        if addr != peer_addr {
+                                            trace!("[SESSION] UDP recv {} bytes from {}, not our peer, dropped", n, addr);
+                                            continue;
+                                        }
+                                        let revealed;
+                                        let input_buffer = match obfuscation {
+                                            Some(ref obfuscation) => match obfuscation.deobfuscate(&input_buffer[..n]) {
+                                                Some(packet) => {
+                                                    revealed = packet;
+                                                    &revealed[..]
+                                                }
+                                                None => {
+                                                    trace!("[SESSION] UDP recv {} bytes not obfuscated, dropped", n);
+                                                    continue;
+                                                }
+                                            },
+                                            None => &input_buffer[..n],
+                                        };
+                                        let n = input_buffer.len();
+                                        let opened;
+                                        let mut counter = None;
+                                        let input_buffer = match cipher {
+                                            Some(ref cipher) => match cipher.open(&input_buffer[..n]) {
+                                                Some(packet) => {
+                                                    counter = Some(crypto::counter(&input_buffer[..n]));
+                                                    opened = packet;
+                                                    &opened[..]
+                                                }
+                                                None => {
+                                                    trace!("[SESSION] UDP recv {} bytes failed authentication, dropped", n);
+                                                    continue;
+                                                }
+                                            },
+                                            None => &input_buffer[..n],
+                                        };
+                                        // FEC data shards are checked by their payload
+                                        let kcp_packet = fec::data_payload(input_buffer).unwrap_or(input_buffer);
 
-                                    if n > 4 && (&input_buffer[..4]).get_u32_le() == crate::scream::SCREAM_FEEDBACK_HEADER {
                                         let mut socket = session.socket.lock();
-                                        socket.scream.on_feedback(&input_buffer[4..], std::time::Instant::now());
-                                        socket.try_wake_pending_waker();
-                                        continue;
-                                    } 
-                                    
-                                    if input_buffer.len() < kcp::KCP_OVERHEAD {
-                                        error!("packet too short, received {} bytes, but at least {} bytes",
-                                               input_buffer.len(),
-                                               kcp::KCP_OVERHEAD);
-                                        continue;
-                                    }
-
-                                    let input_conv = kcp::get_conv(input_buffer);
-                                    trace!("[SESSION] UDP recv {} bytes, conv: {}, going to input {:?}",
-                                           n, input_conv, ByteStr::new(input_buffer));
-
-                                    let mut socket = session.socket.lock();
+                                        if counter.is_some_and(|counter| !socket.accept_counter(counter)) {
+                                            continue;
+                                        }
 
-                                    // Server may allocate another conv for this client.
-                                    if !socket.waiting_conv() && socket.conv() != input_conv {
-                                        trace!("[SESSION] UDP input conv: {} replaces session conv: {}", input_conv, socket.conv());
-                                        socket.set_conv(input_conv);
-                                    }
+                                        // SCReAMv2 feedback, FEC parity and other packets without a KCP header
+                                        if PacketType::of(kcp_packet) == PacketType::Kcp {
+                                            if kcp_packet.len() < kcp::header_len(kcp_packet) {
+                                                error!("packet too short, received {} bytes, but at least {} bytes",
+                                                       kcp_packet.len(),
+                                                       kcp::header_len(kcp_packet));
+                                                continue;
+                                            }
+
+                                            let input_conv = kcp::get_conv(kcp_packet);
+                                            trace!("[SESSION] UDP recv {} bytes, conv: {}, going to input {:?}",
+                                                   n, input_conv, ByteStr::new(kcp_packet));
+
+                                            // Server may allocate another conv for this client.
+                                            if !socket.waiting_conv() && socket.conv() != input_conv {
+                                                trace!("[SESSION] UDP input conv: {} replaces session conv: {}", input_conv, socket.conv());
+                                                socket.set_conv(input_conv);
+                                            }
+                                        }
 
-                                    match socket.input(input_buffer) {
-                                        Ok(true) => {
-                                            trace!("[SESSION] UDP input {} bytes and waked sender/receiver", n);
+                                        let result = socket.input_packet(input_buffer, ecn);
+                                        if socket.take_update_wakeup() {
+                                            session.notify();
                                         }
-                                        Ok(false) => {}
-                                        Err(err) => {
-                                            error!("[SESSION] UDP input {} bytes error: {}, input buffer {:?}",
-                                                   n, err, ByteStr::new(input_buffer));
+                                        match result {
+                                            Ok(true) => {
+                                                trace!("[SESSION] UDP input {} bytes and waked sender/receiver", n);
+                                            }
+                                            Ok(false) => {}
+                                            Err(err) => {
+                                                error!("[SESSION] UDP input {} bytes error: {}, input buffer {:?}",
+                                                       n, err, ByteStr::new(input_buffer));
+                                            }
                                         }
                                     }
                                 }
@@ -155,9 +247,13 @@ impl KcpSession {
 
                         // bytes received from listener socket
                         input_opt = input_rx.recv() => {
-                            if let Some(input_buffer) = input_opt {
+                            if let Some((input_buffer, ecn)) = input_opt {
                                 let mut socket = session.socket.lock();
-                                match socket.input(&input_buffer) {
+                                let result = socket.input_packet(&input_buffer, ecn);
+                                if socket.take_update_wakeup() {
+                                    session.notify();
+                                }
+                                match result {
                                     Ok(waked) => {
                                         // trace!("[SESSION] UDP input {} bytes from channel {:?}",
                                         //        input_buffer.len(), ByteStr::new(&input_buffer));
@@ -173,106 +269,223 @@ impl KcpSession {
                         }
                     }
                 }
-            })
+            }.instrument(span.clone()))
         };
 
-        // Per-session updater
-        {
-            let session = session.clone();
-            tokio::spawn(async move {
-                while !session.closed.load(Ordering::Relaxed) {
-                    let next = {
-                        let mut socket = session.socket.lock();
-
-                        let is_closed = session.closed.load(Ordering::Acquire);
-                        if is_closed && socket.can_close() {
-                            trace!("[SESSION] KCP session closing");
-                            break;
+        *session.io_task.lock() = Some(io_task_handle);
+
+        match driver {
+            Some((driver, handle)) => driver.register(handle, session.clone()),
+            // Per-session updater
+            None => {
+                let session = session.clone();
+                tokio::spawn(async move {
+                    // Runs past `close` until the FIN handshake finished or lingered
+                    while let Some(next) = session.update_once() {
+                        tokio::select! {
+                            _ = time::sleep_until(next) => {},
+                            _ = session.notifier.notified() => {},
                         }
+                    }
+                    session.finish().await;
+                }.instrument(span));
+            }
+        }
 
-                        // server socket expires
-                        if !is_client {
-                            // If this is a server stream, close it automatically after a period of time
-                            let last_update_time = socket.last_update_time();
-                            let elapsed = last_update_time.elapsed();
-
-                            if let Some(session_expire) = session.session_expire {
-                                if elapsed > session_expire {
-                                    if elapsed > session_expire * 2 {
-                                        // Force close. Client may have already gone.
-                                        trace!(
-                                            "[SESSION] force close inactive session, conv: {}, last_update: {}s ago",
-                                            socket.conv(),
-                                            elapsed.as_secs()
-                                        );
-                                        break;
-                                    }
+        session
+    }
 
-                                    if !is_closed {
-                                        trace!(
-                                            "[SESSION] closing inactive session, conv: {}, last_update: {}s ago",
-                                            socket.conv(),
-                                            elapsed.as_secs()
-                                        );
-                                        session.closed.store(true, Ordering::Release);
-                                    }
-                                }
-                            }
-                        }
+    /// Update the socket, `None` once the session ended
+    pub fn update_once(&self) -> Option<Instant> {
+        let _span = self.span.enter();
+        let mut socket = self.socket.lock();
 
-                        // If window is full, flush it immediately
-                        if socket.need_flush() {
-                            let _ = socket.flush();
-                        }
+        let is_closed = self.closed.load(Ordering::Acquire);
+        if is_closed {
+            socket.shutdown();
+        }
+        if is_closed && socket.can_close() {
+            trace!("[SESSION] KCP session closing");
+            return None;
+        }
 
-                        match socket.update() {
-                            Ok(next_next) => Instant::from_std(next_next),
-                            Err(err) => {
-                                error!("[SESSION] KCP update failed, error: {}", err);
-                                Instant::now() + Duration::from_millis(10)
-                            }
-                        }
-                    };
+        // Dead link or no session on the other side, nothing left to deliver
+        if socket.timed_out() || socket.refused() || socket.unreachable() {
+            trace!("[SESSION] KCP session timed out, refused or unreachable, conv: {}", socket.conv());
+            return None;
+        }
 
-                    tokio::select! {
-                        _ = time::sleep_until(next) => {},
-                        _ = session.notifier.notified() => {},
+        // server socket expires
+        if self.session_close_notifier.is_some() {
+            // If this is a server stream, close it automatically after a period of time
+            let last_update_time = socket.last_update_time();
+            let elapsed = utils::elapsed(last_update_time);
+
+            if let Some(session_expire) = self.session_expire {
+                if elapsed > session_expire {
+                    if elapsed > session_expire * 2 {
+                        // Force close. Client may have already gone.
+                        trace!(
+                            "[SESSION] force close inactive session, conv: {}, last_update: {}s ago",
+                            socket.conv(),
+                            elapsed.as_secs()
+                        );
+                        return None;
+                    }
+
+                    if !is_closed {
+                        trace!(
+                            "[SESSION] closing inactive session, conv: {}, last_update: {}s ago",
+                            socket.conv(),
+                            elapsed.as_secs()
+                        );
+                        self.closed.store(true, Ordering::Release);
                     }
                 }
+            }
+        }
 
-                {
-                    // Close the socket.
-                    // Wake all pending tasks and let all send/recv return EOF
+        // If window is full, flush it immediately
+        if socket.need_flush() {
+            let _ = socket.flush();
+        }
 
-                    let mut socket = session.socket.lock();
-                    socket.close();
-                }
+        match socket.update() {
+            Ok(next_next) => Some(Instant::from_std(next_next)),
+            Err(err) => {
+                error!("[SESSION] KCP update failed, error: {}", err);
+                Some(Instant::now() + Duration::from_millis(10))
+            }
+        }
+    }
 
-                if let Some((ref notifier, peer_addr)) = session.session_close_notifier {
-                    let _ = notifier.send(peer_addr).await;
-                }
+    /// Tear the session down after `update_once` returned `None`
+    pub async fn finish(self: Arc<Self>) {
+        {
+            // Close the socket.
+            // Wake all pending tasks and let all send/recv return EOF
 
-                session.closed.store(true, Ordering::Release);
-                io_task_handle.abort();
+            let mut socket = self.socket.lock();
+            socket.close();
+        }
 
-                trace!("[SESSION] KCP session closed");
-            });
+        if let Some(ref notifier) = self.session_close_notifier {
+            // The peer may have migrated since the session was created
+            let (peer_addr, conv) = {
+                let socket = self.socket.lock();
+                (socket.peer_addr(), socket.conv())
+            };
+            let _ = notifier.send((peer_addr, conv)).await;
         }
 
-        session
+        self.closed.store(true, Ordering::Release);
+        if let Some(io_task) = self.io_task.lock().take() {
+            io_task.abort();
+        }
+
+        trace!(parent: &self.span, "[SESSION] KCP session closed");
     }
 
     pub fn kcp_socket(&self) -> &SpinMutex<KcpSocket> {
         &self.socket
     }
 
+    /// See `KcpSocket::poll_recv`, the socket stays unlocked unless it is a passthrough session
+    pub fn poll_recv(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if self.recv_half.passthrough() {
+            let mut socket = self.socket.lock();
+            let result = socket.poll_recv(cx, buf);
+            if socket.take_update_wakeup() {
+                self.notify();
+            }
+            return result;
+        }
+        let result = self.recv_half.poll_recv(cx, buf);
+        self.reopen_window();
+        result
+    }
+
+    /// See `KcpSocket::poll_recv_msg`
+    pub fn poll_recv_msg(&self, cx: &mut Context<'_>) -> Poll<KcpResult<Option<Vec<u8>>>> {
+        if self.recv_half.passthrough() {
+            let mut socket = self.socket.lock();
+            let result = socket.poll_recv_msg(cx);
+            if socket.take_update_wakeup() {
+                self.notify();
+            }
+            return result;
+        }
+        let result = self.recv_half.poll_recv_msg(cx);
+        self.reopen_window();
+        result
+    }
+
+    /// See `KcpSocket::poll_peek`
+    pub fn poll_peek(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if self.recv_half.passthrough() {
+            return self.socket.lock().poll_peek(cx, buf);
+        }
+        self.recv_half.poll_peek(cx, buf)
+    }
+
+    /// See `KcpSocket::peek_size`
+    pub fn peek_size(&self) -> KcpResult<usize> {
+        if self.recv_half.passthrough() {
+            return self.socket.lock().peek_size();
+        }
+        self.recv_half.peek_size()
+    }
+
+    // A read freed room in the window, the peer has to hear about it
+    fn reopen_window(&self) {
+        if self.recv_half.take_wake_socket() {
+            self.notify();
+        }
+    }
+
+    /// Send the concatenation of `bufs` as one message, staged if the socket is busy
+    pub fn poll_send(
+        &self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+        deadline: Option<Duration>,
+    ) -> Poll<KcpResult<usize>> {
+        let result = match self.socket.try_lock() {
+            Some(mut socket) => socket.poll_send_segments(cx, bufs, deadline),
+            None => match self.send_half.poll_stage(cx, bufs, deadline) {
+                Some(result) => result,
+                None => self.socket.lock().poll_send_segments(cx, bufs, deadline),
+            },
+        };
+        if result.is_ready() {
+            self.notify();
+        }
+        result
+    }
+
+    /// See `KcpSocket::poll_send_msg`
+    pub fn poll_send_msg(&self, cx: &mut Context<'_>, msg: &[u8]) -> Poll<KcpResult<()>> {
+        // Before the handshake the socket has to split it, it can't be staged then anyway
+        let result = match self.socket.try_lock() {
+            Some(mut socket) => socket.poll_send_msg(cx, msg),
+            None => match self.send_half.poll_stage(cx, &[IoSlice::new(msg)], None) {
+                Some(result) => result.map_ok(|_| ()),
+                None => self.socket.lock().poll_send_msg(cx, msg),
+            },
+        };
+        if result.is_ready() {
+            self.notify();
+        }
+        result
+    }
+
     pub fn close(&self) {
         self.closed.store(true, Ordering::Release);
         self.notify();
     }
 
-    pub async fn input(&self, buf: &[u8]) -> Result<(), SessionClosedError> {
-        self.input_tx.send(buf.to_owned()).await.map_err(|_| SessionClosedError)
+    pub async fn input(&self, buf: &[u8], ecn: EcnCodepoint) -> Result<(), SessionClosedError> {
+        self.input_tx.send((buf.to_owned(), ecn)).await.map_err(|_| SessionClosedError)
     }
 
     pub async fn conv(&self) -> u32 {
@@ -280,8 +493,16 @@ impl KcpSession {
         socket.conv()
     }
 
+    /// See `KcpSocket::accept_counter`
+    pub fn accept_counter(&self, counter: u64) -> bool {
+        self.socket.lock().accept_counter(counter)
+    }
+
     pub fn notify(&self) {
-        self.notifier.notify_one();
+        match self.driver {
+            Some(ref driver) => driver.wake(),
+            None => self.notifier.notify_one(),
+        }
     }
 }
 
@@ -305,30 +526,109 @@ impl Deref for KcpSessionUniq {
 
 pub struct KcpSessionManager {
     sessions: HashMap<SocketAddr, KcpSessionUniq>,
+    // Address of every session by conv, to find peers that migrated
+    convs: HashMap<u32, SocketAddr>,
+    // Addresses sessions migrated away from, the paths of multipath peers, by conv and as a queue per conv
+    former_addrs: HashMap<SocketAddr, u32>,
+    former_addr_queues: HashMap<u32, VecDeque<SocketAddr>>,
+    congestion_factory: CongestionControllerFactory,
+    conv_allocator: Arc<dyn ConvAllocator>,
+    // Shared by all sessions with `max_aggregate_rate_bps`
+    rate_budget: Option<Arc<RateBudget>>,
+    // Per-session config of `KcpListener::set_config_hook`
+    config_hook: Arc<SpinMutex<Option<ConfigHook>>>,
+    driver: UpdateDriver,
 }
 
 impl KcpSessionManager {
-    pub fn new() -> KcpSessionManager {
+    pub fn new(
+        congestion_factory: CongestionControllerFactory,
+        conv_allocator: Arc<dyn ConvAllocator>,
+        max_aggregate_rate_bps: Option<f32>,
+        config_hook: Arc<SpinMutex<Option<ConfigHook>>>,
+    ) -> KcpSessionManager {
         KcpSessionManager {
             sessions: HashMap::new(),
+            convs: HashMap::new(),
+            former_addrs: HashMap::new(),
+            former_addr_queues: HashMap::new(),
+            congestion_factory,
+            conv_allocator,
+            rate_budget: max_aggregate_rate_bps.map(RateBudget::new),
+            config_hook,
+            driver: UpdateDriver::new(),
         }
     }
 
-    #[inline]
-    pub fn alloc_conv(&mut self) -> u32 {
-        let mut conv = rand::random();
-        while conv == 0 {
-            conv = rand::random()
-        }
-        conv
+    /// Conv for a client that asked for one, `None` if the allocator keeps returning convs in use
+    pub fn alloc_conv(&mut self) -> Option<u32> {
+        (0..MAX_CONV_ALLOC_ATTEMPTS)
+            .map(|_| self.conv_allocator.allocate())
+            .find(|conv| *conv != 0 && !packet::is_reserved(*conv) && !self.convs.contains_key(conv))
+    }
+
+    /// Address of the session using `conv`
+    pub fn conv_peer(&self, conv: u32) -> Option<SocketAddr> {
+        self.convs.get(&conv).copied()
     }
 
     pub fn get(&self, peer_addr: &SocketAddr) -> Option<Arc<KcpSession>> {
         self.sessions.get(peer_addr).map(|s| s.0.clone())
     }
 
-    pub fn close_peer(&mut self, peer_addr: SocketAddr) {
-        self.sessions.remove(&peer_addr);
+    /// Sessions currently open
+    pub fn session_count(&self) -> usize {
+        self.sessions.len()
+    }
+
+    /// Remove the session of `conv` at `peer_addr`, a session that replaced it on that address stays
+    pub fn close_peer(&mut self, peer_addr: SocketAddr, conv: u32) {
+        if self.sessions.get(&peer_addr).is_some_and(|s| s.socket.lock().conv() == conv) {
+            self.sessions.remove(&peer_addr);
+        }
+        if self.convs.get(&conv) == Some(&peer_addr) {
+            self.convs.remove(&conv);
+            self.forget_former_addrs(conv);
+        }
+    }
+
+    /// Move the session of `conv` to `peer_addr`, the peer's address changed
+    pub fn migrate(&mut self, conv: u32, peer_addr: SocketAddr) -> Option<Arc<KcpSession>> {
+        let old_addr = *self.convs.get(&conv)?;
+        let session = self.sessions.remove(&old_addr)?;
+        session.socket.lock().set_peer_addr(peer_addr);
+        let migrated = session.0.clone();
+        self.sessions.insert(peer_addr, session);
+        self.convs.insert(conv, peer_addr);
+
+        self.forget_former_addr(&peer_addr);
+        self.former_addrs.insert(old_addr, conv);
+        let queue = self.former_addr_queues.entry(conv).or_default();
+        queue.push_back(old_addr);
+        if queue.len() > MAX_FORMER_ADDRS {
+            let oldest = queue.pop_front().unwrap();
+            self.former_addrs.remove(&oldest);
+        }
+        Some(migrated)
+    }
+
+    /// A session is at `peer_addr` or migrated away from it and is still open
+    pub fn knows_path(&self, peer_addr: &SocketAddr) -> bool {
+        self.sessions.contains_key(peer_addr) || self.former_addrs.contains_key(peer_addr)
+    }
+
+    fn forget_former_addrs(&mut self, conv: u32) {
+        for addr in self.former_addr_queues.remove(&conv).unwrap_or_default() {
+            self.former_addrs.remove(&addr);
+        }
+    }
+
+    fn forget_former_addr(&mut self, addr: &SocketAddr) {
+        if let Some(conv) = self.former_addrs.remove(addr) {
+            if let Some(queue) = self.former_addr_queues.get_mut(&conv) {
+                queue.retain(|former| former != addr);
+            }
+        }
     }
 
     pub async fn get_or_create(
@@ -336,50 +636,77 @@ impl KcpSessionManager {
         config: &KcpConfig,
         conv: u32,
         sn: u32,
-        udp: &Arc<UdpSocket>,
+        transport: &Arc<dyn DatagramTransport>,
         peer_addr: SocketAddr,
-        session_close_notifier: &mpsc::Sender<SocketAddr>,
+        session_close_notifier: &mpsc::Sender<(SocketAddr, u32)>,
     ) -> KcpResult<(Arc<KcpSession>, bool)> {
-        match self.sessions.entry(peer_addr) {
-            Entry::Occupied(mut occ) => {
-                let session = occ.get();
-
-                if sn == 0 && session.conv().await != conv {
-                    // This is the first packet received from this peer.
-                    // Recreate a new session for this specific client.
-
-                    let (socket, target_bitrate_rx) = KcpSocket::new(config, conv, udp.clone(), peer_addr, config.stream)?;
-                    let session = KcpSession::new_shared(
-                        (socket, target_bitrate_rx),
-                        config.session_expire,
-                        Some((session_close_notifier.clone(), peer_addr)),
-                    );
-
-                    let old_session = occ.insert(KcpSessionUniq(session.clone()));
-                    let old_conv = old_session.conv().await;
-                    trace!(
-                        "replaced session with conv: {} (old: {}), peer: {}",
-                        conv,
-                        old_conv,
-                        peer_addr
-                    );
-
-                    Ok((session, true))
-                } else {
-                    Ok((session.0.clone(), false))
-                }
+        if let Some(session) = self.sessions.get(&peer_addr) {
+            // Only the first packet of a client replaces the session of its address
+            if sn != 0 || session.conv().await == conv {
+                return Ok((session.0.clone(), false));
             }
-            Entry::Vacant(vac) => {
-                let (socket, target_bitrate_rx) = KcpSocket::new(config, conv, udp.clone(), peer_addr, config.stream)?;
-                let session = KcpSession::new_shared(
-                    (socket, target_bitrate_rx),
-                    config.session_expire,
-                    Some((session_close_notifier.clone(), peer_addr)),
+        }
+
+        let session = self.create_session(config, conv, transport, peer_addr, session_close_notifier)?;
+        match self.sessions.insert(peer_addr, KcpSessionUniq(session.clone())) {
+            Some(old_session) => {
+                let old_conv = old_session.conv().await;
+                if self.convs.get(&old_conv) == Some(&peer_addr) {
+                    self.convs.remove(&old_conv);
+                    self.forget_former_addrs(old_conv);
+                }
+                trace!(
+                    "replaced session with conv: {} (old: {}), peer: {}",
+                    conv,
+                    old_conv,
+                    peer_addr
                 );
-                trace!("created session for conv: {}, peer: {}", conv, peer_addr);
-                vac.insert(KcpSessionUniq(session.clone()));
-                Ok((session, true))
             }
+            None => trace!("created session for conv: {}, peer: {}", conv, peer_addr),
+        }
+        self.forget_former_addr(&peer_addr);
+        self.convs.insert(conv, peer_addr);
+        Ok((session, true))
+    }
+
+    fn create_session(
+        &self,
+        config: &KcpConfig,
+        conv: u32,
+        transport: &Arc<dyn DatagramTransport>,
+        peer_addr: SocketAddr,
+        session_close_notifier: &mpsc::Sender<(SocketAddr, u32)>,
+    ) -> KcpResult<Arc<KcpSession>> {
+        let hook = self.config_hook.lock().clone();
+        let overridden = match hook {
+            // A panicking hook refuses the peer instead of taking the listener's task down
+            Some(hook) => match panic::catch_unwind(AssertUnwindSafe(|| hook(peer_addr, conv))) {
+                Ok(session_config) => Some(config.for_session(session_config)),
+                Err(..) => return Err(KcpError::InvalidConfig("config hook panicked".to_owned())),
+            },
+            None => None,
+        };
+        if let Some(ref config) = overridden {
+            config.validate()?;
+        }
+        let config = overridden.as_ref().unwrap_or(config);
+
+        let (mut socket, target_bitrate_rx) = KcpSocket::new(
+            config,
+            conv,
+            transport.clone(),
+            peer_addr,
+            config.stream_mode,
+            (self.congestion_factory)(config),
+        )?;
+        if let Some(ref budget) = self.rate_budget {
+            socket.set_rate_share(budget.share());
         }
+        Ok(KcpSession::new_shared(
+            (socket, target_bitrate_rx),
+            config.session_expire,
+            Some(session_close_notifier.clone()),
+            Some(&self.driver),
+        ))
     }
 }
@@ -0,0 +1,731 @@
+//! Simulated network for tests
+//!
+//! `SimSocket`s bound on a `SimNetwork` exchange datagrams in memory. Every datagram passes the
+//! sender's link: it is serialized at the link bandwidth, waits in its queue, is delayed and
+//! possibly dropped. Loss and jitter come from a seeded RNG so runs are reproducible.
+//!
+//! `EmulatedTransport` puts the same link in front of a real transport, like netem on the sender.
+//!
+//! With the `test-util` feature a runtime with paused time (`tokio::time::pause`) runs simulations on
+//! virtual time: sessions, their congestion control and the links all read tokio's clock, which jumps to
+//! the next timer whenever every task waits. Minutes of a session pass in a fraction of a second. Runs
+//! don't repeat exactly though, convs and feedback tokens are random and the tasks may interleave
+//! differently.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+        Mutex,
+        Weak,
+    },
+    task::{Context, Poll, Waker},
+    time::Duration,
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use tokio::{net::UdpSocket, sync::mpsc, time::Instant};
+use tracing::trace;
+
+use crate::{
+    ecn::EcnCodepoint,
+    transport::{self, DatagramTransport},
+};
+
+/// Properties of the link every datagram leaving a `SimSocket` passes
+#[derive(Debug, Clone, Copy)]
+pub struct SimLinkConfig {
+    /// One-way propagation delay
+    pub delay: Duration,
+    /// Extra delay drawn uniformly from `0..=jitter`, datagrams are never reordered
+    pub jitter: Duration,
+    /// Probability a datagram is lost, `0.0..=1.0`
+    pub loss: f64,
+    /// Bottleneck rate (bps), `None` is unlimited
+    pub bandwidth: Option<u64>,
+    /// Datagrams that would queue longer than this at the bottleneck are dropped
+    pub queue_limit: Duration,
+    /// Datagrams queued longer than this are marked CE, the others ECT(1). `None` marks nothing
+    pub ce_threshold: Option<Duration>,
+}
+
+impl Default for SimLinkConfig {
+    fn default() -> SimLinkConfig {
+        SimLinkConfig {
+            delay: Duration::ZERO,
+            jitter: Duration::ZERO,
+            loss: 0.0,
+            bandwidth: None,
+            queue_limit: Duration::from_millis(500),
+            ce_threshold: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+struct NetworkState {
+    link: SimLinkConfig,
+    rng: StdRng,
+    sockets: HashMap<SocketAddr, Weak<SimSocket>>,
+}
+
+/// In-memory network `SimSocket`s are bound on
+#[derive(Debug, Clone)]
+pub struct SimNetwork {
+    state: Arc<Mutex<NetworkState>>,
+}
+
+impl SimNetwork {
+    /// Network whose links all behave as `link`, loss and jitter are drawn from `seed`
+    pub fn new(link: SimLinkConfig, seed: u64) -> SimNetwork {
+        SimNetwork {
+            state: Arc::new(Mutex::new(NetworkState {
+                link,
+                rng: StdRng::seed_from_u64(seed),
+                sockets: HashMap::new(),
+            })),
+        }
+    }
+
+    /// Change the link for datagrams sent from now on
+    pub fn set_link(&self, link: SimLinkConfig) {
+        self.state.lock().unwrap().link = link;
+    }
+
+    /// Create a socket receiving on `addr`
+    ///
+    /// Must be called within a tokio runtime, which delivers the datagrams it sends.
+    pub fn bind(&self, addr: SocketAddr) -> io::Result<Arc<SimSocket>> {
+        let mut state = self.state.lock().unwrap();
+        if state.sockets.get(&addr).and_then(Weak::upgrade).is_some() {
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, format!("{} is already bound", addr)));
+        }
+
+        let (link_tx, mut link_rx) = mpsc::unbounded_channel::<InFlight>();
+        let socket = Arc::new(SimSocket {
+            addr,
+            state: self.state.clone(),
+            link_tx,
+            link: Mutex::new(LinkState::new()),
+            inbox: Mutex::new(Inbox::default()),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        });
+        state.sockets.insert(addr, Arc::downgrade(&socket));
+
+        // Datagrams leave the link in order, ends when the socket is dropped
+        let state = Arc::downgrade(&self.state);
+        tokio::spawn(async move {
+            while let Some(datagram) = link_rx.recv().await {
+                tokio::time::sleep_until(datagram.deliver_at).await;
+
+                let state = match state.upgrade() {
+                    Some(state) => state,
+                    None => break,
+                };
+                let target = state.lock().unwrap().sockets.get(&datagram.target).and_then(Weak::upgrade);
+                if let Some(target) = target {
+                    target.deliver(datagram.data, addr, datagram.ecn);
+                }
+            }
+        });
+
+        Ok(socket)
+    }
+}
+
+#[derive(Debug)]
+struct InFlight {
+    data: Vec<u8>,
+    target: SocketAddr,
+    ecn: EcnCodepoint,
+    deliver_at: Instant,
+}
+
+#[derive(Debug)]
+struct LinkState {
+    busy_until: Instant,
+    last_delivery: Instant,
+}
+
+impl LinkState {
+    fn new() -> LinkState {
+        LinkState {
+            busy_until: Instant::now(),
+            last_delivery: Instant::now(),
+        }
+    }
+
+    // Delivery time and ECN mark of a `len` bytes datagram entering the link now, `None` if it is
+    // dropped
+    fn transmit(
+        &mut self,
+        link: &SimLinkConfig,
+        len: usize,
+        lost: bool,
+        jitter: Duration,
+    ) -> Option<(Instant, EcnCodepoint)> {
+        let now = Instant::now();
+        let start = self.busy_until.max(now);
+        let queued = start - now;
+        if queued > link.queue_limit {
+            return None;
+        }
+
+        // Lost datagrams still took their time on the wire
+        let serialization = match link.bandwidth {
+            Some(bps) => Duration::from_secs_f64(len as f64 * 8.0 / bps as f64),
+            None => Duration::ZERO,
+        };
+        self.busy_until = start + serialization;
+        if lost {
+            return None;
+        }
+
+        let deliver_at = (self.busy_until + link.delay + jitter).max(self.last_delivery);
+        self.last_delivery = deliver_at;
+
+        let ecn = match link.ce_threshold {
+            Some(threshold) if queued > threshold => EcnCodepoint::Ce,
+            Some(..) => EcnCodepoint::Ect1,
+            None => EcnCodepoint::NotEct,
+        };
+        Some((deliver_at, ecn))
+    }
+}
+
+// Whether the next datagram is lost and its extra delay
+fn draw(link: &SimLinkConfig, rng: &mut StdRng) -> (bool, Duration) {
+    let lost = link.loss > 0.0 && rng.gen_bool(link.loss.min(1.0));
+    let jitter = if link.jitter.is_zero() {
+        Duration::ZERO
+    } else {
+        rng.gen_range(Duration::ZERO..=link.jitter)
+    };
+    (lost, jitter)
+}
+
+#[derive(Debug, Default)]
+struct Inbox {
+    datagrams: VecDeque<(Vec<u8>, SocketAddr, EcnCodepoint)>,
+    waker: Option<Waker>,
+}
+
+/// Endpoint on a `SimNetwork`
+#[derive(Debug)]
+pub struct SimSocket {
+    addr: SocketAddr,
+    state: Arc<Mutex<NetworkState>>,
+    link_tx: mpsc::UnboundedSender<InFlight>,
+    link: Mutex<LinkState>,
+    inbox: Mutex<Inbox>,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl SimSocket {
+    /// Datagrams sent through this socket
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams sent through this socket that were lost or dropped by a full queue
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+
+    fn deliver(&self, data: Vec<u8>, from: SocketAddr, ecn: EcnCodepoint) {
+        let mut inbox = self.inbox.lock().unwrap();
+        inbox.datagrams.push_back((data, from, ecn));
+        if let Some(waker) = inbox.waker.take() {
+            waker.wake();
+        }
+    }
+}
+
+impl DatagramTransport for SimSocket {
+    fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+
+        let (link, lost, jitter) = {
+            let mut state = self.state.lock().unwrap();
+            let link = state.link;
+            let (lost, jitter) = draw(&link, &mut state.rng);
+            (link, lost, jitter)
+        };
+
+        let (deliver_at, ecn) = match self.link.lock().unwrap().transmit(&link, buf.len(), lost, jitter) {
+            Some(delivery) => delivery,
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+                return Poll::Ready(Ok(buf.len()));
+            }
+        };
+
+        let _ = self.link_tx.send(InFlight {
+            data: buf.to_vec(),
+            target,
+            ecn,
+            deliver_at,
+        });
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+        let mut inbox = self.inbox.lock().unwrap();
+        match inbox.datagrams.pop_front() {
+            Some((data, from, ecn)) => {
+                // Truncated like a UDP datagram that didn't fit
+                let n = data.len().min(buf.len());
+                buf[..n].copy_from_slice(&data[..n]);
+                Poll::Ready(Ok((n, from, ecn)))
+            }
+            None => {
+                inbox.waker = Some(cx.waker().clone());
+                Poll::Pending
+            }
+        }
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.addr)
+    }
+}
+
+/// Transport whose outgoing datagrams pass a `SimLinkConfig` link before `inner` sends them
+///
+/// Only the sending direction is emulated, wrap the transports of both peers for a symmetric
+/// path. `ce_threshold` is ignored, marks can't be set on datagrams of a real socket.
+#[derive(Debug)]
+pub struct EmulatedTransport {
+    inner: Arc<dyn DatagramTransport>,
+    link_config: Mutex<(SimLinkConfig, StdRng)>,
+    link_tx: mpsc::UnboundedSender<InFlight>,
+    link: Mutex<LinkState>,
+    sent: AtomicU64,
+    dropped: AtomicU64,
+}
+
+impl EmulatedTransport {
+    /// Emulate `link` in front of `inner`, loss and jitter are drawn from `seed`
+    ///
+    /// Must be called within a tokio runtime, which sends the delayed datagrams.
+    pub fn new(inner: Arc<dyn DatagramTransport>, link: SimLinkConfig, seed: u64) -> Arc<EmulatedTransport> {
+        let (link_tx, mut link_rx) = mpsc::unbounded_channel::<InFlight>();
+
+        // Datagrams leave the link in order, ends when the transport is dropped
+        let sender = inner.clone();
+        tokio::spawn(async move {
+            while let Some(datagram) = link_rx.recv().await {
+                tokio::time::sleep_until(datagram.deliver_at).await;
+                if let Err(err) = transport::send_to(&*sender, &datagram.data, datagram.target).await {
+                    trace!("emulated link failed to send to {}: {}", datagram.target, err);
+                }
+            }
+        });
+
+        Arc::new(EmulatedTransport {
+            inner,
+            link_config: Mutex::new((link, StdRng::seed_from_u64(seed))),
+            link_tx,
+            link: Mutex::new(LinkState::new()),
+            sent: AtomicU64::new(0),
+            dropped: AtomicU64::new(0),
+        })
+    }
+
+    /// Change the link for datagrams sent from now on
+    pub fn set_link(&self, link: SimLinkConfig) {
+        self.link_config.lock().unwrap().0 = link;
+    }
+
+    /// Datagrams sent through this transport
+    pub fn sent(&self) -> u64 {
+        self.sent.load(Ordering::Relaxed)
+    }
+
+    /// Datagrams sent through this transport that were lost or dropped by a full queue
+    pub fn dropped(&self) -> u64 {
+        self.dropped.load(Ordering::Relaxed)
+    }
+}
+
+impl DatagramTransport for EmulatedTransport {
+    fn poll_send_to(&self, _cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        self.sent.fetch_add(1, Ordering::Relaxed);
+
+        let (link, lost, jitter) = {
+            let mut guard = self.link_config.lock().unwrap();
+            let (link, ref mut rng) = *guard;
+            let (lost, jitter) = draw(&link, rng);
+            (link, lost, jitter)
+        };
+
+        match self.link.lock().unwrap().transmit(&link, buf.len(), lost, jitter) {
+            Some((deliver_at, ecn)) => {
+                let _ = self.link_tx.send(InFlight {
+                    data: buf.to_vec(),
+                    target,
+                    ecn,
+                    deliver_at,
+                });
+            }
+            None => {
+                self.dropped.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+        self.inner.poll_recv_from(cx, buf)
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.inner.local_addr()
+    }
+
+    fn poll_recv_batch(
+        &self,
+        cx: &mut Context<'_>,
+        buffers: &mut [Vec<u8>],
+        meta: &mut [(usize, SocketAddr, EcnCodepoint)],
+    ) -> Poll<io::Result<usize>> {
+        self.inner.poll_recv_batch(cx, buffers, meta)
+    }
+
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        self.inner.udp_socket()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use futures_util::future;
+    use tokio::time;
+
+    use super::*;
+    use crate::{
+        config::{KcpConfig, KcpNoDelayConfig},
+        listener::KcpListener,
+        scream::QDELAY_TARGET_LO,
+        stats::KcpStats,
+        stream::KcpStream,
+    };
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([10, 0, 0, 1], port))
+    }
+
+    async fn recv(socket: &SimSocket, buf: &mut [u8]) -> (usize, SocketAddr, EcnCodepoint) {
+        future::poll_fn(|cx| socket.poll_recv_from(cx, buf)).await.unwrap()
+    }
+
+    async fn send(socket: &SimSocket, buf: &[u8], target: SocketAddr) {
+        future::poll_fn(|cx| socket.poll_send_to(cx, buf, target)).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn sim_delay_bandwidth() {
+        let network = SimNetwork::new(
+            SimLinkConfig {
+                delay: Duration::from_millis(50),
+                bandwidth: Some(1_000_000),
+                ..Default::default()
+            },
+            0,
+        );
+        let s1 = network.bind(addr(1)).unwrap();
+        let s2 = network.bind(addr(2)).unwrap();
+        assert!(network.bind(addr(2)).is_err());
+
+        // 10 ms each at 1 Mbps
+        let start = Instant::now();
+        for i in 0..10u8 {
+            send(&s1, &[i; 1250], addr(2)).await;
+        }
+
+        let mut buf = [0u8; 2048];
+        for i in 0..10u8 {
+            let (n, from, ecn) = recv(&s2, &mut buf).await;
+            assert_eq!(&buf[..n], &[i; 1250][..]);
+            assert_eq!(from, addr(1));
+            assert_eq!(ecn, EcnCodepoint::NotEct);
+        }
+        assert!(start.elapsed() >= Duration::from_millis(150));
+    }
+
+    #[tokio::test]
+    async fn sim_loss_reproducible() {
+        async fn run(seed: u64) -> (u64, Vec<u8>) {
+            let network = SimNetwork::new(
+                SimLinkConfig {
+                    loss: 0.3,
+                    ..Default::default()
+                },
+                seed,
+            );
+            let s1 = network.bind(addr(1)).unwrap();
+            let s2 = network.bind(addr(2)).unwrap();
+            for i in 0..100u8 {
+                send(&s1, &[i], addr(2)).await;
+            }
+            send(&s1, b"END", addr(2)).await;
+
+            let mut received = Vec::new();
+            let mut buf = [0u8; 16];
+            loop {
+                let (n, ..) = recv(&s2, &mut buf).await;
+                if &buf[..n] == b"END" {
+                    break;
+                }
+                received.push(buf[0]);
+            }
+            (s1.dropped(), received)
+        }
+
+        let (dropped, received) = run(7).await;
+        assert!((10..50).contains(&dropped));
+        assert_eq!(received.len() as u64 + dropped, 100);
+        assert_eq!(run(7).await, (dropped, received));
+    }
+
+    #[tokio::test]
+    async fn emulated_udp_delay_loss() {
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let emulated = EmulatedTransport::new(
+            Arc::new(udp),
+            SimLinkConfig {
+                delay: Duration::from_millis(50),
+                ..Default::default()
+            },
+            0,
+        );
+        let receiver = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let target = receiver.local_addr().unwrap();
+
+        let start = Instant::now();
+        future::poll_fn(|cx| emulated.poll_send_to(cx, b"DELAYED", target)).await.unwrap();
+        let mut buf = [0u8; 16];
+        let (n, from) = receiver.recv_from(&mut buf).await.unwrap();
+        assert_eq!(&buf[..n], b"DELAYED");
+        assert_eq!(from, emulated.local_addr().unwrap());
+        assert!(start.elapsed() >= Duration::from_millis(50));
+
+        emulated.set_link(SimLinkConfig {
+            loss: 1.0,
+            ..Default::default()
+        });
+        future::poll_fn(|cx| emulated.poll_send_to(cx, b"LOST", target)).await.unwrap();
+        assert_eq!((emulated.sent(), emulated.dropped()), (2, 1));
+    }
+
+    #[tokio::test]
+    async fn sim_kcp_lossy_transfer() {
+        let _ = env_logger::try_init();
+
+        let network = SimNetwork::new(
+            SimLinkConfig {
+                delay: Duration::from_millis(10),
+                jitter: Duration::from_millis(5),
+                loss: 0.05,
+                bandwidth: Some(20_000_000),
+                ..Default::default()
+            },
+            1,
+        );
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::from_transport(config.clone(), network.bind(addr(1)).unwrap())
+            .await
+            .unwrap();
+        let client = network.bind(addr(2)).unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, client.clone(), addr(1))
+            .await
+            .unwrap();
+
+        let payload: Vec<u8> = (0..100_000u32).map(|i| i as u8).collect();
+        let sender = {
+            let payload = payload.clone();
+            tokio::spawn(async move {
+                for chunk in payload.chunks(1000) {
+                    stream.send(chunk).await.unwrap();
+                }
+                stream
+            })
+        };
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut received = Vec::new();
+        let mut buffer = [0u8; 4096];
+        while received.len() < payload.len() {
+            let n = time::timeout(Duration::from_secs(10), server.recv(&mut buffer))
+                .await
+                .expect("transfer stalled")
+                .unwrap();
+            received.extend_from_slice(&buffer[..n]);
+        }
+        assert_eq!(received, payload);
+        assert!(client.dropped() > 0);
+
+        let stream = sender.await.unwrap();
+        assert!(stream.stats().packets_lost > 0);
+    }
+
+    #[tokio::test]
+    async fn sim_scream_bottleneck() {
+        let _ = env_logger::try_init();
+
+        let network = SimNetwork::new(
+            SimLinkConfig {
+                delay: Duration::from_millis(10),
+                bandwidth: Some(300_000),
+                queue_limit: Duration::from_millis(200),
+                ..Default::default()
+            },
+            2,
+        );
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            use_external_congestion_control: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::from_transport(config.clone(), network.bind(addr(1)).unwrap())
+            .await
+            .unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, network.bind(addr(2)).unwrap(), addr(1))
+            .await
+            .unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        tokio::spawn(async move {
+            let mut buffer = [0u8; 8192];
+            while let Ok(n) = server.recv(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+            }
+        });
+
+        // Saturate the link for a while
+        let chunk = [0u8; 1000];
+        let deadline = Instant::now() + Duration::from_secs(2);
+        while Instant::now() < deadline {
+            stream.send(&chunk).await.unwrap();
+        }
+
+        // The controller sees the path delay and the queue at the bottleneck
+        let stats = stream.stats();
+        assert!(stats.base_rtt >= Duration::from_millis(20));
+        assert!(stats.qdelay > Duration::ZERO);
+        assert!(stats.srtt < stats.base_rtt + Duration::from_millis(200));
+    }
+
+    // A sender saturating `link` for `duration`, its stats and the bytes the receiver got so far, once a second
+    async fn saturate(link: SimLinkConfig, seed: u64, duration: Duration) -> (Vec<KcpStats>, Vec<u64>) {
+        let network = SimNetwork::new(link, seed);
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            use_external_congestion_control: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::from_transport(config.clone(), network.bind(addr(1)).unwrap())
+            .await
+            .unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, network.bind(addr(2)).unwrap(), addr(1))
+            .await
+            .unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        let (mut server, _) = listener.accept().await.unwrap();
+        let received = Arc::new(AtomicU64::new(0));
+        let receiver = {
+            let received = received.clone();
+            tokio::spawn(async move {
+                let mut buffer = [0u8; 8192];
+                while let Ok(n) = server.recv(&mut buffer).await {
+                    received.fetch_add(n as u64, Ordering::Relaxed);
+                }
+            })
+        };
+
+        let chunk = [0u8; 1000];
+        let start = Instant::now();
+        let mut samples = Vec::new();
+        let mut received_samples = Vec::new();
+        while samples.len() < duration.as_secs() as usize {
+            stream.send(&chunk).await.unwrap();
+            if start.elapsed() >= Duration::from_secs(samples.len() as u64 + 1) {
+                samples.push(stream.stats());
+                received_samples.push(received.load(Ordering::Relaxed));
+            }
+        }
+        receiver.abort();
+        (samples, received_samples)
+    }
+
+    fn lossy_bottleneck() -> SimLinkConfig {
+        SimLinkConfig {
+            delay: Duration::from_millis(20),
+            loss: 0.02,
+            bandwidth: Some(1_000_000),
+            queue_limit: Duration::from_millis(500),
+            ..Default::default()
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_virtual_time_scream_converges() {
+        let (samples, received) = saturate(lossy_bottleneck(), 3, Duration::from_secs(600)).await;
+
+        // Converged, the queue at the bottleneck stays short despite the loss. SCReAM keeps the link
+        // about full, a single sample may catch the queue of a burst and exceed 100 ms, the mean stays
+        // below the target
+        for (second, stats) in samples.iter().enumerate().skip(30) {
+            assert!(stats.qdelay < Duration::from_millis(200), "qdelay {:?} after {} s", stats.qdelay, second);
+            assert!(stats.srtt < Duration::from_millis(200), "srtt {:?} after {} s", stats.srtt, second);
+        }
+        let mean = samples[30..].iter().map(|stats| stats.qdelay).sum::<Duration>() / (samples.len() - 30) as u32;
+        assert!(mean < Duration::from_secs_f32(QDELAY_TARGET_LO), "mean qdelay {:?}", mean);
+        assert!(samples.last().unwrap().packets_lost > 0);
+        // The transfer never stalls on the losses
+        for pair in samples.windows(60) {
+            assert!(pair[59].bytes_sent > pair[0].bytes_sent);
+        }
+        let goodput = *received.last().unwrap() as f64 * 8.0 / 600.0;
+        assert!(goodput > 600_000.0, "goodput {} bps", goodput);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sim_virtual_time_fills_bottleneck() {
+        let bandwidth = 5_000_000;
+        let link = SimLinkConfig {
+            delay: Duration::from_millis(20),
+            bandwidth: Some(bandwidth),
+            ..Default::default()
+        };
+        let (samples, received) = saturate(link, 7, Duration::from_secs(60)).await;
+
+        // Converged within a few seconds, through the base RTT refresh as well
+        for (second, pair) in received.windows(2).enumerate().skip(3) {
+            let goodput = (pair[1] - pair[0]) * 8;
+            assert!(goodput >= bandwidth * 8 / 10, "goodput {} bps in second {}", goodput, second + 2);
+        }
+        let goodput = (received[59] - received[3]) * 8 / 56;
+        assert!(goodput >= bandwidth * 9 / 10 && goodput <= bandwidth, "goodput {} bps", goodput);
+        // The queue at the bottleneck stays around the target
+        let target = Duration::from_secs_f32(QDELAY_TARGET_LO);
+        let converged = &samples[4..];
+        for stats in converged {
+            assert!(stats.qdelay < target * 2, "qdelay {:?}", stats.qdelay);
+        }
+        let mean = converged.iter().map(|stats| stats.qdelay).sum::<Duration>() / converged.len() as u32;
+        assert!(mean < target, "mean qdelay {:?}", mean);
+    }
+}
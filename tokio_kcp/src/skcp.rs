@@ -1,44 +1,176 @@
 use std::{
-    error, io::{self, ErrorKind, Write}, net::SocketAddr, sync::Arc, task::{Context, Poll, Waker}, time::{Duration, Instant}
-};
-use std::convert::TryInto;
-
-use bytes::BufMut;
-use futures_util::future;
-use kcp::{Error as KcpError, Kcp, KcpResult, KCP_OVERHEAD};
-use log::{trace, error};
-use tokio::{
-    net::UdpSocket,
+    cmp,
+    collections::VecDeque,
+    io::{self, ErrorKind, IoSlice, Write},
+    iter,
+    net::SocketAddr,
     sync::{
-        mpsc,
-        watch,
-    }
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll, Waker},
+    time::{Duration, Instant},
 };
+
+use bytes::{Buf, BufMut};
+use futures_util::{future, ready};
+use kcp::{Error as KcpError, FlushResult, Kcp, KcpResult, KCP_MAX_FRAGMENTS};
+use tokio::sync::watch;
+use tracing::{error, field::display, info_span, trace, trace_span, Span};
 use crate::{
-    pacer::PacketPacer, scream::{self, ScreamCongestionControl}, utils::now_millis, KcpConfig
+    config::{DelayedAckConfig, DuplicateConfig, KcpRuntimeConfig, StreamMode},
+    congestion::{CongestionController, CongestionState},
+    crypto::{self, PacketCipher, ReplayWindow},
+    ecn::EcnCodepoint,
+    event::{EventHandler, KcpEvent},
+    fec::{self, FecDecoder, FecEncoder},
+    feedback::{self, FeedbackPolicy, DATAGRAM_SN_FLAG},
+    feedback_auth::{FeedbackAuth, FEEDBACK_TOKEN_OVERHEAD},
+    halves::{RecvEnd, RecvHalf, SendHalf},
+    metrics::MetricsSink,
+    obfuscation::Obfuscator,
+    packet::{self, PacketType},
+    pacer::{PacerQueue, PacerState, PacketPacer, RateShare},
+    pmtud::{self, PmtuDiscovery},
+    qlog::{QlogEvent, QlogWriter},
+    scream,
+    stats::{KcpStats, ThroughputSample},
+    transport::DatagramTransport,
+    utils::{elapsed, now, now_millis, unix_millis},
+    KcpConfig,
 };
 
-
-
+/// Keepalive probe: header, probe id
+pub const KEEPALIVE_HEADER: u32 = 0x5C4D4B50;
+/// Keepalive answer: header, id of the probe
+pub const KEEPALIVE_ACK_HEADER: u32 = 0x5C4D4B41;
+
+/// Listener refused to create a session for us: header, reserved
+pub const REFUSED_HEADER: u32 = 0x5C4D5246;
+
+/// Unreliable datagram, never retransmitted: header, datagram sn, payload
+pub const DATAGRAM_HEADER: u32 = 0x5C4D4447;
+const DATAGRAM_HEADER_LEN: usize = 8;
+// Received datagrams not picked up by `recv_unreliable`, the oldest are dropped beyond
+const DATAGRAM_QUEUE_SIZE: usize = 256;
+
+/// Probe padding, acknowledged in the feedback and discarded: header, datagram sn, zeros
+pub const PADDING_HEADER: u32 = 0x5C4D5044;
+const MAX_PADDING_PER_TICK: usize = 16;
+
+// Longest sleep of an idle session between updates, bounds how late expiry and PMTU raises are noticed
+const IDLE_UPDATE_INTERVAL: Duration = Duration::from_secs(1);
+
+// `ThroughputSample`s are published this often, idle sessions included
+const THROUGHPUT_INTERVAL: Duration = Duration::from_secs(1);
+// Room of an application limited session in the listener's rate budget to grow its sending rate
+const APP_LIMITED_DEMAND_MARGIN: f32 = 2.0;
+
+// `MetricsSample::session_id` of the next session
+static NEXT_SESSION_ID: AtomicU64 = AtomicU64::new(1);
+
+/// Packet goes through the pacer's urgent lane instead of waiting behind queued data
+///
+/// SCReAM feedback delayed by a full queue would inflate the peer's RTT estimate, the same goes for
+/// keepalive answers, PMTU probe answers and KCP datagrams carrying nothing but ACKs.
+pub fn is_urgent_packet(packet: &[u8]) -> bool {
+    match PacketType::of(packet) {
+        PacketType::Feedback
+        | PacketType::Keepalive
+        | PacketType::KeepaliveAck
+        | PacketType::PmtuProbeAck
+        | PacketType::Refused => true,
+        PacketType::Kcp => kcp::is_ack_only(packet),
+        _ => false,
+    }
+}
 
 struct PacerOutput {
     pacer: PacketPacer,
+    fec: Option<FecEncoder>,
+    cipher: Option<PacketCipher>,
+    obfuscation: Option<Arc<dyn Obfuscator>>,
+    duplicate: Option<DuplicateConfig>,
+    // Copies of small datagrams and when they are due
+    duplicates: VecDeque<(Instant, Vec<u8>)>,
 }
 
+// Copy `packet` into a pooled buffer, sealing and scrambling it on the way
+fn queue_packet(
+    queue: &PacerQueue,
+    cipher: Option<&PacketCipher>,
+    obfuscation: Option<&dyn Obfuscator>,
+    packet: &[u8],
+    urgent: bool,
+) -> io::Result<()> {
+    let mut buffer = queue.buffer();
+    match cipher {
+        Some(cipher) => {
+            if !cipher.seal_into(packet, &mut buffer) {
+                queue.recycle(buffer);
+                return Ok(());
+            }
+        }
+        None => buffer.extend_from_slice(packet),
+    }
+    if let Some(obfuscation) = obfuscation {
+        let wrapped = buffer.split();
+        obfuscation.obfuscate(&wrapped, &mut buffer);
+    }
+    if urgent {
+        queue.push_urgent(buffer)
+    } else {
+        queue.push(buffer)
+    }
+}
+
+impl PacerOutput {
+    fn queue(&mut self, buf: &[u8]) -> io::Result<()> {
+        let queue = &*self.pacer.queue;
+        let cipher = self.cipher.as_ref();
+        let obfuscation = self.obfuscation.as_deref();
+        let urgent = is_urgent_packet(buf);
+        match self.fec {
+            Some(ref mut fec) => {
+                // Only the data shard comes first, parity shards wait with the data
+                let mut first = true;
+                fec.encode(buf, |packet| {
+                    let result = queue_packet(queue, cipher, obfuscation, packet, urgent && first);
+                    first = false;
+                    result
+                })
+            }
+            None => queue_packet(queue, cipher, obfuscation, buf, urgent),
+        }
+    }
+
+    // Queue the copies that are due, returns when the next one is
+    fn queue_duplicates(&mut self, now: Instant) -> io::Result<Option<Instant>> {
+        while let Some(&(due, _)) = self.duplicates.front() {
+            if due > now {
+                return Ok(Some(due));
+            }
+            let (_, packet) = self.duplicates.pop_front().unwrap();
+            self.queue(&packet)?;
+        }
+        Ok(None)
+    }
+}
 
 impl Write for PacerOutput {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        match self.pacer.packet_tx.try_send(buf.to_vec()) {
-            Ok(()) => Ok(buf.len()),
-            Err(e) => {
-                if let tokio::sync::mpsc::error::TrySendError::Closed(_) = e {
-                    eprint!("Pacer channel is closed");
-                    Err(io::Error::new(ErrorKind::BrokenPipe, "Pacer channel is closed"))
+        self.queue(buf)?;
+        // Only KCP data is worth a copy, control packets and ACKs are sent again anyway
+        if let Some(duplicate) = self.duplicate {
+            if buf.len() <= duplicate.max_size && PacketType::of(buf) == PacketType::Kcp && !kcp::is_ack_only(buf) {
+                if duplicate.delay.is_zero() {
+                    self.queue(buf)?;
                 } else {
-                    Ok(buf.len())
+                    self.duplicates.push_back((now() + duplicate.delay, buf.to_vec()));
                 }
-            },
+            }
         }
+        Ok(buf.len())
     }
     fn flush(&mut self) -> io::Result<()> {
         Ok(())
@@ -48,39 +180,170 @@ impl Write for PacerOutput {
 #[derive(Debug)]
 pub struct KcpSocket {
     kcp: Kcp<PacerOutput>,
-    pub(crate) scream: ScreamCongestionControl,
+    congestion: Box<dyn CongestionController>,
+    metrics: Arc<dyn MetricsSink>,
+    session_id: u64,
+    last_feedback_time: Instant,
+    // Packets received since the last feedback
+    feedback_pending: usize,
+    feedback_interval: Duration,
+    feedback_policy: FeedbackPolicy,
+    feedback_max_packets: Option<usize>,
+    delayed_ack: Option<DelayedAckConfig>,
+    // Arrival of the oldest segment KCP holds an ACK for
+    ack_pending_since: Option<Instant>,
+    // `update` found nothing to do and scheduled a long sleep
+    idle: bool,
+    // Feedback, datagrams and padding are built here before being copied into a pooled buffer
+    output_buf: Vec<u8>,
+    last_rtt_tick: Instant,
     pacing_rate_tx: watch::Sender<f32>,
+    // Part of the listener's `max_aggregate_rate_bps`
+    rate_share: Option<RateShare>,
+    // Bytes sent up to the last RTT tick and the rate since the one before, the demand of the
+    // session's share while it has no backlog
+    rtt_tick_bytes_sent: u64,
+    recent_send_rate: f32,
+    // `KcpConfig` bounds of the target bitrate and pacing rate, applied to any controller
+    min_target_bitrate: f32,
+    max_target_bitrate: f32,
     target_bitrate_tx: watch::Sender<f32>,
+    congestion_state_tx: watch::Sender<CongestionState>,
+    pacer_state_tx: watch::Sender<PacerState>,
+    throughput_tx: watch::Sender<ThroughputSample>,
+    // Start of the running throughput interval and the byte counters at that time
+    throughput_start: (Instant, u64, u64, u64),
+    pacer_queue: Arc<PacerQueue>,
+    packets_lost: u64,
+    segments_expired: u64,
+    probe_bitrate: Option<f32>,
+    padding_sent: u64,
+    bytes_received: u64,
+    pmtud: Option<PmtuDiscovery>,
+    peer_seen: bool,
+    keepalive_interval: Option<Duration>,
+    feedback_auth: Option<FeedbackAuth>,
+    idle_timeout: Option<Duration>,
+    last_recv: Instant,
+    last_keepalive: Instant,
+    next_keepalive_id: u32,
+    timed_out: bool,
+    refused: bool,
+    unreachable: bool,
+    // A staged write, already reported as sent, no longer fit into KCP
+    staged_failed: bool,
+    handshake_retry_interval: Duration,
+    handshake_max_attempts: u32,
+    handshake_attempts: u32,
+    last_handshake: Option<Instant>,
+    pending_handshake: Option<Waker>,
+    linger: Duration,
+    shutdown_at: Option<Instant>,
+    // The application shut down its side, not the session after the peer went away
+    local_shutdown: bool,
+    // The FIN was queued behind our data
+    fin_sent: bool,
+    pending_shutdown: Option<Waker>,
+    // Keepalive probe of `send_ping` waiting for its answer: id and when it went out
+    ping: Option<(u32, Instant)>,
+    ping_attempts: u32,
+    // Round trip of the answered probe, or `None` once the last attempt went unanswered, until
+    // `poll_ping` takes it
+    ping_result: Option<Option<Duration>>,
+    pending_ping: Option<Waker>,
+    next_datagram_sn: u32,
+    datagrams: VecDeque<Vec<u8>>,
+    pending_datagram_receiver: Option<Waker>,
+    // Sends and receives go through the datagram path, KCP only carries the handshake and FIN
+    passthrough: bool,
+    fec: Option<FecDecoder>,
+    cipher: Option<PacketCipher>,
+    obfuscation: Option<Arc<dyn Obfuscator>>,
+    replay: Option<ReplayWindow>,
+    replays_dropped: u64,
+    unknown_packets: u64,
     last_update: Instant,
-    socket: Arc<UdpSocket>,
+    socket: Arc<dyn DatagramTransport>,
     flush_write: bool,
     flush_ack_input: bool,
     sent_first: bool,
-    pending_sender: Option<Waker>,
-    pending_receiver: Option<Waker>,
+    // Shared with the session, readers and staging writers don't need the socket's lock
+    recv_half: Arc<RecvHalf>,
+    send_half: Arc<SendHalf>,
     closed: bool,
     allow_recv_empty_packet: bool,
+    // Configured receive window and the unread bytes that close it
+    rcv_wnd: u16,
+    max_recv_buffer_bytes: Option<usize>,
+    max_snd_wnd: u16,
+    ecn: bool,
+    udp_batch_size: usize,
+    peer_addr_tx: watch::Sender<SocketAddr>,
+    event_handler: Option<EventHandler>,
+    last_rmt_wnd: u16,
+    span: Span,
+    qlog: Option<Arc<QlogWriter>>,
+    // Congestion window and pacing rate last written to the qlog
+    qlog_metrics: (f32, f32),
 }
 
 impl KcpSocket {
     pub fn new(
         c: &KcpConfig,
         conv: u32,
-        socket: Arc<UdpSocket>,
+        socket: Arc<dyn DatagramTransport>,
         target_addr: SocketAddr,
-        stream: bool,
+        stream_mode: StreamMode,
+        congestion: Box<dyn CongestionController>,
     ) -> KcpResult<(KcpSocket, watch::Receiver<f32>)> {
+        // Datagrams of such a session would be taken for control packets
+        if packet::is_reserved(conv) {
+            return Err(KcpError::InvalidConfig(format!("conv {:#x} is reserved for packet magics", conv)));
+        }
+        let span = info_span!("kcp", conv, peer = %target_addr);
         let (pacing_rate_tx, pacing_rate_rx) = watch::channel(1_000_000.0);
         let (target_bitrate_tx, target_bitrate_rx) = watch::channel(500_000.0);
-        let pacer = PacketPacer::new(socket.clone(), target_addr, pacing_rate_rx);
-        let output = PacerOutput { pacer };
+        let pacer_queue = Arc::new(match c.shared_pacer {
+            Some(ref shared) => shared.queue(c.pacer_queue_size, c.pacer_queue_policy, c.mtu),
+            None => PacerQueue::new(c.pacer_queue_size, c.pacer_queue_policy, c.mtu),
+        });
+        let (peer_addr_tx, peer_addr_rx) = watch::channel(target_addr);
+        let pacer = PacketPacer::new(
+            socket.clone(),
+            peer_addr_rx,
+            pacing_rate_rx,
+            pacer_queue.clone(),
+            c,
+            span.clone(),
+        );
+        let cipher = c.psk.as_ref().map(PacketCipher::new);
+        let output = PacerOutput {
+            pacer,
+            fec: c.fec.map(FecEncoder::new),
+            cipher: cipher.clone(),
+            obfuscation: c.obfuscation.clone(),
+            duplicate: c.duplicate,
+            duplicates: VecDeque::new(),
+        };
         
-        let mut kcp = if stream {
-            Kcp::new_stream(conv, output)
-        } else {
-            Kcp::new(conv, output)
+        let mut kcp = match stream_mode {
+            StreamMode::ByteStream => Kcp::new_stream(conv, output),
+            StreamMode::Message => Kcp::new(conv, output),
         };
         c.apply_config(&mut kcp);
+        // The window probe and its answer carry the wish for compressed headers, also without a handshake
+        if c.header_compression {
+            kcp.ask_window();
+        }
+
+        // Leave room for the shard header, nonce, tag and obfuscation framing in every datagram
+        let fec_overhead = if c.fec.is_some() { fec::FEC_OVERHEAD } else { 0 };
+        let crypto_overhead = if c.psk.is_some() { crypto::CRYPTO_OVERHEAD } else { 0 };
+        let obfuscation_overhead = c.obfuscation.as_ref().map_or(0, |obfuscation| obfuscation.overhead());
+        let wrap_overhead = fec_overhead + crypto_overhead + obfuscation_overhead;
+        if wrap_overhead > 0 {
+            kcp.set_mtu(c.mtu - wrap_overhead)?;
+        }
 
         // Ask server to allocate one
         if conv == 0 {
@@ -89,51 +352,262 @@ impl KcpSocket {
 
         kcp.update(now_millis())?;
 
-        let socket = KcpSocket {
+        let mut congestion = congestion;
+        congestion.on_mss_changed(kcp.mss());
+        let rcv_wnd = kcp.rcv_wnd();
+        let send_half = Arc::new(SendHalf::new(pacer_queue.clone()));
+
+        let mut socket = KcpSocket {
             kcp,
-            scream: ScreamCongestionControl::new(),
+            congestion,
+            metrics: c.metrics.clone(),
+            session_id: NEXT_SESSION_ID.fetch_add(1, Ordering::Relaxed),
+            last_feedback_time: now(),
+            feedback_pending: 0,
+            feedback_interval: c.feedback_interval,
+            feedback_policy: c.feedback_policy,
+            feedback_max_packets: c.feedback_max_packets,
+            delayed_ack: c.delayed_ack,
+            ack_pending_since: None,
+            idle: false,
+            output_buf: Vec::with_capacity(c.mtu),
+            last_rtt_tick: now(),
             pacing_rate_tx,
+            rate_share: None,
+            rtt_tick_bytes_sent: 0,
+            recent_send_rate: 0.0,
+            min_target_bitrate: c.min_target_bitrate,
+            max_target_bitrate: c.max_target_bitrate,
             target_bitrate_tx,
-            last_update: Instant::now(),
+            pacer_state_tx: watch::Sender::new(pacer_queue.state()),
+            throughput_tx: watch::Sender::new(ThroughputSample::default()),
+            throughput_start: (now(), 0, 0, 0),
+            congestion_state_tx: watch::Sender::new(CongestionState {
+                target_bitrate: 500_000.0,
+                ..CongestionState::default()
+            }),
+            pacer_queue,
+            packets_lost: 0,
+            segments_expired: 0,
+            probe_bitrate: c.probe_bitrate,
+            padding_sent: 0,
+            bytes_received: 0,
+            pmtud: if c.pmtud {
+                Some(PmtuDiscovery::new(c.mtu - wrap_overhead, c.pmtud_max_mtu - wrap_overhead))
+            } else {
+                None
+            },
+            peer_seen: false,
+            keepalive_interval: c.keepalive_interval,
+            feedback_auth: c.feedback_auth.then(FeedbackAuth::new),
+            idle_timeout: c.idle_timeout,
+            last_recv: now(),
+            last_keepalive: now(),
+            next_keepalive_id: 0,
+            timed_out: false,
+            refused: false,
+            unreachable: false,
+            staged_failed: false,
+            handshake_retry_interval: c.handshake_retry_interval,
+            handshake_max_attempts: c.handshake_max_attempts,
+            handshake_attempts: 0,
+            last_handshake: None,
+            pending_handshake: None,
+            linger: c.linger,
+            shutdown_at: None,
+            local_shutdown: false,
+            fin_sent: false,
+            pending_shutdown: None,
+            ping: None,
+            ping_attempts: 0,
+            ping_result: None,
+            pending_ping: None,
+            next_datagram_sn: 0,
+            datagrams: VecDeque::new(),
+            passthrough: c.passthrough,
+            pending_datagram_receiver: None,
+            fec: c.fec.map(FecDecoder::new),
+            cipher,
+            obfuscation: c.obfuscation.clone(),
+            replay: (c.psk.is_some() && c.replay_window > 0).then(|| ReplayWindow::new(c.replay_window)),
+            replays_dropped: 0,
+            unknown_packets: 0,
+            last_update: now(),
             socket,
             flush_write: c.flush_write,
             flush_ack_input: c.flush_acks_input,
             sent_first: false,
-            pending_sender: None,
-            pending_receiver: None,
+            recv_half: Arc::new(RecvHalf::new(c.passthrough)),
+            send_half,
             closed: false,
             allow_recv_empty_packet: c.allow_recv_empty_packet,
+            rcv_wnd,
+            max_recv_buffer_bytes: c.max_recv_buffer_bytes,
+            max_snd_wnd: c.snd_wnd.max(2),
+            ecn: c.ecn,
+            udp_batch_size: c.udp_batch_size,
+            peer_addr_tx,
+            event_handler: None,
+            last_rmt_wnd: 0,
+            span,
+            qlog: c.qlog.clone(),
+            qlog_metrics: (0.0, 0.0),
         };
+        socket.last_rmt_wnd = socket.kcp.rmt_wnd();
+        socket.kcp.set_peer_ack_delay(socket.peer_ack_delay());
         Ok((socket, target_bitrate_rx))
     }
 
+    /// Cipher datagrams have to be opened with before they are passed to `input_packet`
+    pub fn cipher(&self) -> Option<&PacketCipher> {
+        self.cipher.as_ref()
+    }
+
+    /// Obfuscation datagrams have to be undone with before they are opened
+    pub fn obfuscation(&self) -> Option<&Arc<dyn Obfuscator>> {
+        self.obfuscation.as_ref()
+    }
+
+    /// Whether the datagram opened with send `counter` is new, duplicates and stale ones are to be dropped
+    pub fn accept_counter(&mut self, counter: u64) -> bool {
+        if self.replay.as_mut().is_none_or(|replay| replay.accept(counter)) {
+            return true;
+        }
+        trace!("[SESSION] replayed or stale datagram, counter: {}", counter);
+        self.replays_dropped += 1;
+        false
+    }
+
     /// Call every time you got data from transmission
     pub fn input(&mut self, buf: &[u8]) -> KcpResult<bool> {
-        let now = Instant::now();
+        let _span = trace_span!(parent: &self.span, "input", len = buf.len()).entered();
+        self.input_with_ecn(buf, EcnCodepoint::NotEct)
+    }
+
+    /// Call every time you got a datagram from transmission, whatever it carries
+    pub fn input_packet(&mut self, packet: &[u8], ecn: EcnCodepoint) -> KcpResult<bool> {
+        let _span = trace_span!(parent: &self.span, "input", len = packet.len()).entered();
+        self.bytes_received += packet.len() as u64;
+        if !fec::is_fec_packet(packet) {
+            return self.input_unwrapped(packet, ecn);
+        }
+
+        let payloads = match self.fec {
+            Some(ref mut fec) => fec.decode(packet),
+            // Peer protects its packets, but we can't recover anything
+            None => fec::data_payload(packet).map(|p| vec![p.to_vec()]).unwrap_or_default(),
+        };
+
+        let mut waked = false;
+        let mut result = Ok(());
+        for payload in payloads {
+            match self.input_unwrapped(&payload, ecn) {
+                Ok(w) => waked |= w,
+                Err(err) => result = Err(err),
+            }
+        }
+        result.map(|_| waked)
+    }
+
+    fn input_unwrapped(&mut self, packet: &[u8], ecn: EcnCodepoint) -> KcpResult<bool> {
+        match PacketType::of(packet) {
+            PacketType::Kcp => self.input_with_ecn(packet, ecn),
+            PacketType::Datagram => Ok(self.input_datagram(packet, ecn)),
+            PacketType::Padding => Ok(self.input_padding(packet, ecn)),
+            _ => Ok(self.input_control(packet)),
+        }
+    }
+
+    /// Call every time you got data from transmission, with the ECN codepoint of the datagram
+    pub fn input_with_ecn(&mut self, buf: &[u8], ecn: EcnCodepoint) -> KcpResult<bool> {
+        let now = now();
+        let waiting_conv = self.kcp.waiting_conv();
+        // Acks arriving between updates would otherwise measure RTTs up to an interval short
+        self.kcp.advance(now_millis());
         let (acked_sns, received_push_sns) = self.kcp.input(buf)?;
+        let delivered = self.sync_recv();
+
+        if waiting_conv && !self.kcp.waiting_conv() {
+            self.span.record("conv", self.kcp.conv());
+            self.emit(KcpEvent::ConvAssigned { conv: self.kcp.conv() });
+        }
+        if self.kcp.rmt_wnd() != self.last_rmt_wnd {
+            self.last_rmt_wnd = self.kcp.rmt_wnd();
+            self.emit(KcpEvent::RemoteWindowChanged { rmt_wnd: self.last_rmt_wnd });
+        }
 
         for (seq_number, _size) in acked_sns {
-            self.scream.on_ack_kcp(seq_number);
+            self.congestion.on_kcp_ack(feedback::segment_sn(seq_number));
+            self.qlog(QlogEvent::PacketAcked { sn: seq_number });
         }
         
         for seq_number in received_push_sns {
-            self.scream.on_packet_received(seq_number, now);
+            self.congestion.on_packet_received(feedback::segment_sn(seq_number), now, ecn);
+            self.feedback_pending += 1;
         }
 
         self.last_update = now;
+        self.last_recv = now;
+        self.peer_seen = true;
 
+        if self.delayed_ack.is_some() && self.kcp.ack_pending() > 0 {
+            self.ack_pending_since.get_or_insert(now);
+        }
         if self.flush_ack_input {
             self.kcp.flush_ack()?;
         }
 
-        Ok(self.try_wake_pending_waker())
+        Ok(self.try_wake_pending_waker() | delivered)
     }
 
     /// Call if you want to send some data
-    pub fn poll_send(&mut self, cx: &mut Context<'_>, mut buf: &[u8]) -> Poll<KcpResult<usize>> {
-        if self.closed {
-            return Err(io::Error::from(ErrorKind::BrokenPipe).into()).into();
+    pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        self.poll_send_with_deadline(cx, buf, None)
+    }
+
+    /// Call if you want to send some data, retransmissions stop once `deadline` passed
+    pub fn poll_send_with_deadline(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        deadline: Option<Duration>,
+    ) -> Poll<KcpResult<usize>> {
+        self.poll_send_segments(cx, &[IoSlice::new(buf)], deadline)
+    }
+
+    /// Send the concatenation of `bufs` as one message, copied into KCP without joining them first
+    pub fn poll_send_vectored(&mut self, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<KcpResult<usize>> {
+        self.poll_send_segments(cx, bufs, None)
+    }
+
+    /// Send the concatenation of `bufs` as one message, retransmissions stop once `deadline` passed
+    pub fn poll_send_segments(
+        &mut self,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+        deadline: Option<Duration>,
+    ) -> Poll<KcpResult<usize>> {
+        if self.passthrough {
+            return self.poll_send_passthrough(cx, bufs);
+        }
+        if self.timed_out {
+            return Err(idle_timeout_error()).into();
         }
+        if self.refused {
+            return Err(refused_error()).into();
+        }
+        if self.unreachable {
+            return Err(KcpError::PeerUnreachable).into();
+        }
+        if self.staged_failed {
+            return Err(staged_write_error()).into();
+        }
+        if self.closed || self.shutdown_at.is_some() {
+            return Err(self.closed_error()).into();
+        }
+        // Staged writes came first, our segments carry the window reads reopened
+        self.drain_staged()?;
+        self.sync_recv();
 
         // If:
         //     1. Have sent the first packet (asking for conv)
@@ -151,19 +625,39 @@ impl KcpSocket {
                 self.kcp.waiting_conv()
             );
 
-            if let Some(waker) = self.pending_sender.replace(cx.waker().clone()) {
-                if !cx.waker().will_wake(&waker) {
-                    waker.wake();
-                }
-            }
+            self.send_half.wait(cx);
             return Poll::Pending;
         }
 
-        if !self.sent_first && self.kcp.waiting_conv() && buf.len() > self.kcp.mss() {
-            buf = &buf[..self.kcp.mss()];
+        // Pacer queue full with `PacerQueuePolicy::Block`, woken once it drains
+        if self.pacer_queue.poll_writable(cx).is_pending() {
+            trace!("[SEND] pacer queue full");
+            return Poll::Pending;
         }
 
-        let n = self.kcp.send(buf)?;
+        // Only one segment goes out before the conv is known, this copy happens once per session
+        let first: Vec<u8>;
+        let first_bufs: [IoSlice<'_>; 1];
+        let len: usize = bufs.iter().map(|b| b.len()).sum();
+        let bufs = if !self.sent_first && self.kcp.waiting_conv() && len > self.kcp.mss() {
+            first = bufs.iter().flat_map(|b| b.iter().copied()).take(self.kcp.mss()).collect();
+            first_bufs = [IoSlice::new(&first)];
+            &first_bufs[..]
+        } else {
+            bufs
+        };
+
+        let n = match deadline {
+            Some(deadline) => {
+                // On KCP's clock, `flush` doesn't advance it
+                let deadline_ms = self
+                    .kcp
+                    .current()
+                    .wrapping_add(deadline.as_millis().min(u32::MAX as u128) as u32);
+                self.kcp.send_vectored_with_deadline(bufs, deadline_ms)?
+            }
+            None => self.kcp.send_vectored(bufs)?,
+        };
         self.sent_first = true;
 
         if self.kcp.wait_snd() >= self.kcp.snd_wnd() as usize || self.kcp.wait_snd() >= self.kcp.rmt_wnd() as usize {
@@ -171,118 +665,909 @@ impl KcpSocket {
             self.process_flush_result(Ok(flush_result))?;
         }
 
-        self.last_update = Instant::now();
+        self.last_update = now();
 
         if self.flush_write {
             let flush_result = self.kcp.flush()?;
             self.process_flush_result(Ok(flush_result))?;
         }
+        self.publish_send();
+
+        Ok(n).into()
+    }
+
+    // Move the writes staged in the send half into KCP, in the order they were staged
+    //
+    // The writers were told their data is sent, a write KCP refuses ends the session. It and the
+    // writes after it stay staged.
+    fn drain_staged(&mut self) -> KcpResult<()> {
+        let mut staged = self.send_half.take_staged().into_iter();
+        if staged.len() == 0 {
+            return Ok(());
+        }
+        let now = now();
+        while let Some((message, deadline)) = staged.next() {
+            if let Err(err) = self.send_staged(&message, deadline, now) {
+                error!(
+                    "[SEND] conv {} staged write of {} bytes failed, error: {}",
+                    self.kcp.conv(),
+                    message.len(),
+                    err
+                );
+                self.send_half.restage(iter::once((message, deadline)).chain(staged).collect());
+                self.staged_failed = true;
+                self.wake_all();
+                break;
+            }
+        }
+        self.last_update = now;
+
+        if self.need_flush() {
+            let flush_result = self.kcp.flush()?;
+            self.process_flush_result(Ok(flush_result))?;
+        }
+        Ok(())
+    }
+
+    // The MSS may have shrunk since the write was staged. A byte stream is cut to KCP's fragment
+    // limit, a message has to fit as a whole
+    fn send_staged(&mut self, message: &[u8], deadline: Option<Instant>, now: Instant) -> KcpResult<()> {
+        let max_len = KCP_MAX_FRAGMENTS * self.kcp.mss();
+        if message.len() > max_len && !self.kcp.is_stream() {
+            return Err(KcpError::UserBufTooBig);
+        }
+        let mut rest = message;
+        loop {
+            let (chunk, tail) = rest.split_at(rest.len().min(max_len));
+            match deadline {
+                Some(deadline) => {
+                    let remaining = deadline.saturating_duration_since(now).as_millis().min(u32::MAX as u128);
+                    let deadline_ms = self.kcp.current().wrapping_add(remaining as u32);
+                    self.kcp.send_with_deadline(chunk, deadline_ms)?
+                }
+                None => self.kcp.send(chunk)?,
+            };
+            if tail.is_empty() {
+                return Ok(());
+            }
+            rest = tail;
+        }
+    }
+
+    // Tell staging writers whether they may stage and how much room the send window has
+    fn publish_send(&mut self) -> bool {
+        let open = !self.passthrough
+            && self.sent_first
+            && !self.kcp.waiting_conv()
+            && !self.flush_write
+            && !self.closed
+            && self.shutdown_at.is_none()
+            && !(self.timed_out || self.refused || self.unreachable || self.staged_failed);
+        let room = if self.kcp.waiting_conv() {
+            0
+        } else {
+            let wnd = cmp::min(self.kcp.snd_wnd(), self.kcp.rmt_wnd()) as usize;
+            wnd.saturating_sub(self.kcp.wait_snd())
+        };
+        self.send_half.publish(open, room, self.kcp.mss())
+    }
+
+    /// Send and receive halves, see `halves`
+    pub fn halves(&self) -> (Arc<RecvHalf>, Arc<SendHalf>) {
+        (self.recv_half.clone(), self.send_half.clone())
+    }
+
+    // One datagram per send once the peer answered, there is nothing to retransmit so deadlines don't apply
+    fn poll_send_passthrough(&mut self, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<KcpResult<usize>> {
+        ready!(self.poll_handshake(cx))?;
+
+        let joined: Vec<u8>;
+        let buf = match bufs {
+            [buf] => &buf[..],
+            _ => {
+                joined = bufs.iter().flat_map(|b| b.iter().copied()).collect();
+                &joined[..]
+            }
+        };
+        // The receiver couldn't tell an empty datagram from EOF
+        if buf.is_empty() {
+            return Ok(0).into();
+        }
+        self.poll_send_unreliable(cx, buf)
+    }
+
+    /// Send `msg` whole, before the conv is known a message longer than a segment waits for the handshake
+    pub fn poll_send_msg(&mut self, cx: &mut Context<'_>, msg: &[u8]) -> Poll<KcpResult<()>> {
+        // Only the first segment would go out, the rest would become a message of its own
+        if self.kcp.waiting_conv() && msg.len() > self.kcp.mss() {
+            ready!(self.poll_handshake(cx))?;
+        }
+        let n = ready!(self.poll_send(cx, msg))?;
+        debug_assert_eq!(n, msg.len());
+        Ok(()).into()
+    }
+
+    /// Call if you want to send some data
+    #[allow(dead_code)]
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+    }
+
+    #[allow(dead_code)]
+    pub fn try_recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        if self.closed {
+            return Ok(0);
+        }
+        let result = self.recv_half.try_recv(buf);
+        if self.recv_half.take_wake_socket() {
+            self.sync_recv();
+        }
+        result
+    }
+
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if self.passthrough {
+            return self.poll_recv_unreliable(cx, buf);
+        }
+        let result = self.recv_half.poll_recv(cx, buf);
+        // Reading reopens the window
+        if self.recv_half.take_wake_socket() {
+            self.sync_recv();
+        }
+        result
+    }
+
+    /// Receive the next message whole, `None` at EOF
+    pub fn poll_recv_msg(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Option<Vec<u8>>>> {
+        if self.passthrough {
+            // Empty datagrams are messages too
+            let at_end = self.datagrams.is_empty() && (self.closed || self.kcp.fin_received());
+            let mut msg = vec![0u8; self.datagrams.front().map_or(0, Vec::len)];
+            let n = ready!(self.poll_recv_unreliable(cx, &mut msg))?;
+            msg.truncate(n);
+            return Ok((!at_end).then_some(msg)).into();
+        }
+        let result = self.recv_half.poll_recv_msg(cx);
+        if self.recv_half.take_wake_socket() {
+            self.sync_recv();
+        }
+        result
+    }
+
+    #[allow(dead_code)]
+    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_recv(cx, buf)).await
+    }
+
+    /// Like `poll_recv`, but the message stays queued and is cut to `buf`'s length
+    pub fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if !self.passthrough {
+            return self.recv_half.poll_peek(cx, buf);
+        }
+        if self.timed_out {
+            return Err(idle_timeout_error()).into();
+        }
+        if self.refused {
+            return Err(refused_error()).into();
+        }
+        if self.unreachable {
+            return Err(KcpError::PeerUnreachable).into();
+        }
+        self.poll_peek_datagram(cx, buf)
+    }
+
+    // Move complete messages to the receive half, wake the reader if it waits for them
+    fn sync_recv(&mut self) -> bool {
+        if self.passthrough {
+            return false;
+        }
+        let (mut segments, mut bytes) = self.recv_half.held();
+        self.update_rcv_wnd(segments, bytes);
+
+        let mut messages = Vec::new();
+        while let Ok((message, n)) = self.kcp.recv_message() {
+            if message.is_empty() && !self.allow_recv_empty_packet {
+                // Skipped, messages queued behind it are read right away
+                trace!("[RECV] empty message skipped");
+            } else {
+                segments += n;
+                bytes += message.len();
+                messages.push((message, n));
+            }
+            self.update_rcv_wnd(segments, bytes);
+        }
+
+        // Everything before the peer's FIN was moved, readers get EOF after it
+        let fin = self.kcp.fin_received() && self.kcp.rcv_queue_len() == 0;
+        let stalled = self.kcp.rcv_wnd() < self.rcv_wnd;
+        self.recv_half.deliver(messages, fin, stalled)
+    }
+
+    // Messages held by the receive half keep their segments in the window until read, the advertised
+    // window closes further as unread data fills `max_recv_buffer_bytes`
+    fn update_rcv_wnd(&mut self, held_segments: usize, held_bytes: usize) {
+        let rcv_wnd = self.rcv_wnd_for(held_segments, held_bytes);
+        self.kcp.set_rcv_wnd(rcv_wnd);
+    }
+
+    fn rcv_wnd_for(&self, held_segments: usize, held_bytes: usize) -> u16 {
+        let mut rcv_wnd = (self.rcv_wnd as usize).saturating_sub(held_segments);
+        if let Some(budget) = self.max_recv_buffer_bytes {
+            let free = budget.saturating_sub(self.kcp.rcv_buffered() + held_bytes) / self.kcp.mss();
+            rcv_wnd = cmp::min(self.kcp.rcv_queue_len() + free, rcv_wnd);
+        }
+        // Closed only while the reader has something to read, reading it reopens the window. Otherwise
+        // segments KCP already buffered have to be able to move on
+        if held_segments == 0 {
+            rcv_wnd = rcv_wnd.max(1);
+        }
+        rcv_wnd as u16
+    }
+
+    /// Call every time you got a congestion feedback packet (without its header) from transmission
+    pub fn input_feedback(&mut self, buf: &[u8]) -> bool {
+        self.qlog(QlogEvent::FeedbackReceived { size: buf.len() });
+        self.congestion.on_feedback(buf, now());
+        self.try_wake_pending_waker()
+    }
+
+    /// Call every time you got a packet whose `PacketType` isn't `Kcp`
+    pub fn input_control(&mut self, packet: &[u8]) -> bool {
+        let packet_type = PacketType::of(packet);
+        if packet_type == PacketType::Unknown {
+            // Sent by a newer peer, or garbage: either way nothing for KCP
+            self.unknown_packets += 1;
+            trace!(
+                "[SESSION] conv {} dropped packet with unknown header {:#x}",
+                self.kcp.conv(),
+                (&packet[..4]).get_u32_le()
+            );
+            return false;
+        }
+        self.last_recv = now();
+
+        match packet_type {
+            PacketType::Feedback => match self.feedback_auth {
+                Some(ref auth) => match auth.verify(&packet[4..]) {
+                    Some(report) => self.input_feedback(report),
+                    None => {
+                        trace!("[SESSION] conv {} feedback without our token, dropped", self.kcp.conv());
+                        false
+                    }
+                },
+                None => self.input_feedback(&packet[4..]),
+            },
+            PacketType::FeedbackToken => {
+                if let Some(ref mut auth) = self.feedback_auth {
+                    if auth.on_announcement(packet) {
+                        let answer = auth.announcement();
+                        if let Err(err) = self.kcp.output_raw(&answer) {
+                            error!("Failed to answer feedback token: {}", err);
+                        }
+                    }
+                }
+                false
+            }
+            PacketType::PmtuProbe => {
+                // Answer even if discovery is disabled here, the peer may use it
+                if let Some(ack) = pmtud::probe_ack(packet) {
+                    if let Err(err) = self.kcp.output_raw(&ack) {
+                        error!("Failed to send PMTU probe ACK: {}", err);
+                    }
+                }
+                false
+            }
+            PacketType::PmtuProbeAck => {
+                if let Some(ref mut pmtud) = self.pmtud {
+                    pmtud.on_probe_ack(&packet[4..]);
+                }
+                if let Err(err) = self.apply_discovered_mtu() {
+                    error!("Failed to apply discovered MTU: {}", err);
+                }
+                false
+            }
+            PacketType::Keepalive => {
+                let mut ack = Vec::with_capacity(8);
+                ack.put_u32_le(KEEPALIVE_ACK_HEADER);
+                ack.extend_from_slice(&packet[4..]);
+                if let Err(err) = self.kcp.output_raw(&ack) {
+                    error!("Failed to send keepalive ACK: {}", err);
+                }
+                false
+            }
+            // Refreshes `last_recv`, answers `send_ping`
+            PacketType::KeepaliveAck => {
+                let id = packet.get(4..8).map(|mut id| id.get_u32_le());
+                if let Some((ping_id, sent)) = self.ping {
+                    if id == Some(ping_id) {
+                        self.finish_ping(Some(elapsed(sent)));
+                    }
+                }
+                false
+            }
+            PacketType::Datagram => self.input_datagram(packet, EcnCodepoint::NotEct),
+            PacketType::Padding => self.input_padding(packet, EcnCodepoint::NotEct),
+            // Only meaningful before the listener answered anything else
+            PacketType::Refused if !self.peer_seen => {
+                trace!("[SESSION] conv {} refused by listener", self.kcp.conv());
+                self.refused = true;
+                self.wake_all();
+                true
+            }
+            // A late refusal, or a type the listener handles before us
+            _ => false,
+        }
+    }
+
+    fn input_datagram(&mut self, packet: &[u8], ecn: EcnCodepoint) -> bool {
+        if packet.len() < DATAGRAM_HEADER_LEN {
+            trace!("[DATAGRAM] truncated datagram, {} bytes", packet.len());
+            return false;
+        }
+        let sn = (&packet[4..8]).get_u32_le();
+
+        let now = now();
+        self.last_recv = now;
+        self.peer_seen = true;
+        self.congestion.on_packet_received(sn, now, ecn);
+        self.feedback_pending += 1;
+
+        if self.datagrams.len() >= DATAGRAM_QUEUE_SIZE {
+            trace!("[DATAGRAM] receive queue full, dropping oldest");
+            self.datagrams.pop_front();
+        }
+        self.datagrams.push_back(packet[DATAGRAM_HEADER_LEN..].to_vec());
+
+        match self.pending_datagram_receiver.take() {
+            Some(w) => {
+                w.wake();
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn input_padding(&mut self, packet: &[u8], ecn: EcnCodepoint) -> bool {
+        if packet.len() < DATAGRAM_HEADER_LEN {
+            trace!("[PADDING] truncated padding, {} bytes", packet.len());
+            return false;
+        }
+        let sn = (&packet[4..8]).get_u32_le();
+
+        let now = now();
+        self.last_recv = now;
+        self.peer_seen = true;
+        self.congestion.on_packet_received(sn, now, ecn);
+        self.feedback_pending += 1;
+        false
+    }
+
+    // Datagrams and padding share a sequence number space apart from KCP's
+    fn next_datagram_sn(&mut self) -> u32 {
+        let sn = DATAGRAM_SN_FLAG | self.next_datagram_sn;
+        self.next_datagram_sn = (self.next_datagram_sn + 1) & !DATAGRAM_SN_FLAG;
+        sn
+    }
+
+    /// Fill the congestion window with padding while the application leaves it empty
+    ///
+    /// The acknowledged padding lets the target bitrate ramp up before the application needs it.
+    fn poll_padding(&mut self) -> KcpResult<()> {
+        let probe_bitrate = match self.probe_bitrate {
+            Some(probe_bitrate) => probe_bitrate,
+            None => return Ok(()),
+        };
+        // Nobody acknowledges padding before the peer answered, real data always goes first
+        if !self.peer_seen
+            || self.closed
+            || self.shutdown_at.is_some()
+            || self.kcp.snd_queue_len() > 0
+            || self.pacer_queue.queued() > 0
+            || self.congestion.get_target_bitrate() >= probe_bitrate
+        {
+            return Ok(());
+        }
+
+        let size = self.kcp.mss();
+        let window = self.congestion.get_congestion_window();
+        let mut in_flight = self.congestion.stats().bytes_in_flight as f32;
+        // The last packet may overshoot the window, otherwise it could never grow past it
+        for _ in 0..MAX_PADDING_PER_TICK {
+            if in_flight >= window {
+                break;
+            }
+
+            let sn = self.next_datagram_sn();
+            self.output_buf.clear();
+            self.output_buf.put_u32_le(PADDING_HEADER);
+            self.output_buf.put_u32_le(sn);
+            self.output_buf.resize(DATAGRAM_HEADER_LEN + size, 0);
+            self.kcp.output_raw(&self.output_buf)?;
+            self.congestion.on_packet_sent(sn, size);
+
+            in_flight += size as f32;
+            self.padding_sent += size as u64;
+        }
+        Ok(())
+    }
+
+    /// Send `buf` as a single datagram, bypassing KCP's retransmissions
+    ///
+    /// It is paced and counted by the congestion controller like any segment, but may be lost
+    /// or reordered. The peer drops datagrams until it has a session for us.
+    pub fn poll_send_unreliable(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        if self.timed_out {
+            return Err(idle_timeout_error()).into();
+        }
+        if self.refused {
+            return Err(refused_error()).into();
+        }
+        if self.unreachable {
+            return Err(KcpError::PeerUnreachable).into();
+        }
+        if self.staged_failed {
+            return Err(staged_write_error()).into();
+        }
+        if self.closed || self.shutdown_at.is_some() {
+            return Err(self.closed_error()).into();
+        }
+        if DATAGRAM_HEADER_LEN + buf.len() > self.kcp.mtu() {
+            return Err(KcpError::UserBufTooBig).into();
+        }
+
+        if self.pacer_queue.poll_writable(cx).is_pending() {
+            trace!("[DATAGRAM] pacer queue full");
+            return Poll::Pending;
+        }
+
+        let sn = self.next_datagram_sn();
+
+        self.output_buf.clear();
+        self.output_buf.put_u32_le(DATAGRAM_HEADER);
+        self.output_buf.put_u32_le(sn);
+        self.output_buf.extend_from_slice(buf);
+        self.kcp.output_raw(&self.output_buf)?;
+        self.congestion.on_packet_sent(sn, buf.len());
+
+        Ok(buf.len()).into()
+    }
+
+    // `poll_peek` of passthrough mode, the datagram stays queued
+    fn poll_peek_datagram(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        match self.datagrams.front() {
+            Some(datagram) => {
+                let n = datagram.len().min(buf.len());
+                buf[..n].copy_from_slice(&datagram[..n]);
+                return Ok(n).into();
+            }
+            None if self.closed || self.kcp.fin_received() => return Ok(0).into(),
+            None => {}
+        }
+
+        if let Some(waker) = self.pending_datagram_receiver.replace(cx.waker().clone()) {
+            if !cx.waker().will_wake(&waker) {
+                waker.wake();
+            }
+        }
+        Poll::Pending
+    }
+
+    /// Receive a datagram sent with `poll_send_unreliable`
+    pub fn poll_recv_unreliable(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        if self.timed_out {
+            return Err(idle_timeout_error()).into();
+        }
+        if self.refused {
+            return Err(refused_error()).into();
+        }
+        if self.unreachable {
+            return Err(KcpError::PeerUnreachable).into();
+        }
+
+        match self.datagrams.front() {
+            Some(datagram) if datagram.len() > buf.len() => return Err(KcpError::UserBufTooSmall).into(),
+            Some(_) => {
+                let datagram = self.datagrams.pop_front().unwrap();
+                buf[..datagram.len()].copy_from_slice(&datagram);
+                return Ok(datagram.len()).into();
+            }
+            None if self.closed || self.kcp.fin_received() => return Ok(0).into(),
+            None => {}
+        }
+
+        if let Some(waker) = self.pending_datagram_receiver.replace(cx.waker().clone()) {
+            if !cx.waker().will_wake(&waker) {
+                waker.wake();
+            }
+        }
+        Poll::Pending
+    }
+
+    fn apply_discovered_mtu(&mut self) -> KcpResult<()> {
+        let mtu = match self.pmtud {
+            Some(ref pmtud) => pmtud.mtu(),
+            None => return Ok(()),
+        };
+        if mtu != self.kcp.mtu() {
+            // Segments already queued keep their size
+            self.kcp.set_mtu(mtu)?;
+            self.congestion.on_mss_changed(self.kcp.mss());
+            trace!("[PMTUD] conv {} mtu changed to {}", self.kcp.conv(), mtu);
+        }
+        Ok(())
+    }
+
+    fn poll_pmtu_probe(&mut self) -> KcpResult<()> {
+        // The peer drops control packets until it has a session for us
+        if !self.peer_seen {
+            return Ok(());
+        }
+        let timeout = Duration::from_secs_f32(self.congestion.get_s_rtt().max(0.0) * 3.0);
+        let probe = match self.pmtud {
+            Some(ref mut pmtud) => pmtud.poll_probe(now(), timeout),
+            None => return Ok(()),
+        };
+        if let Some(probe) = probe {
+            self.kcp.output_raw(&probe)?;
+        }
+        self.apply_discovered_mtu()
+    }
+
+    fn poll_keepalive(&mut self) -> KcpResult<()> {
+        let interval = match self.keepalive_interval {
+            // The peer drops control packets until it has a session for us
+            Some(interval) if self.peer_seen => interval,
+            _ => return Ok(()),
+        };
+        if elapsed(self.last_recv) < interval || elapsed(self.last_keepalive) < interval {
+            return Ok(());
+        }
+
+        self.send_keepalive_probe()?;
+        Ok(())
+    }
+
+    // Id of the probe sent
+    fn send_keepalive_probe(&mut self) -> KcpResult<u32> {
+        let id = self.next_keepalive_id;
+        let mut probe = Vec::with_capacity(8);
+        probe.put_u32_le(KEEPALIVE_HEADER);
+        probe.put_u32_le(id);
+        self.next_keepalive_id = self.next_keepalive_id.wrapping_add(1);
+        self.kcp.output_raw(&probe)?;
+        self.last_keepalive = now();
+        Ok(id)
+    }
+
+    // Probe of `send_ping` again if its answer doesn't come, the answer of the lost one would be ignored.
+    // Gives up after `handshake_max_attempts` probes
+    fn poll_ping_retry(&mut self) -> KcpResult<()> {
+        let sent = match self.ping {
+            Some((_, sent)) => sent,
+            None => return Ok(()),
+        };
+        let timeout = Duration::from_secs_f32(self.congestion.get_s_rtt().max(0.0) * 3.0);
+        if elapsed(sent) < timeout.max(self.handshake_retry_interval) {
+            return Ok(());
+        }
+        if self.ping_attempts >= self.handshake_max_attempts {
+            trace!("[PING] conv {} no answer after {} probes", self.kcp.conv(), self.ping_attempts);
+            self.finish_ping(None);
+            return Ok(());
+        }
+        trace!("[PING] conv {} no answer, probing again", self.kcp.conv());
+        let id = self.send_keepalive_probe()?;
+        self.ping = Some((id, now()));
+        self.ping_attempts += 1;
+        Ok(())
+    }
+
+    fn finish_ping(&mut self, rtt: Option<Duration>) {
+        self.ping = None;
+        self.ping_result = Some(rtt);
+        if let Some(w) = self.pending_ping.take() {
+            w.wake();
+        }
+    }
+
+    // Tell the peer our feedback token until it echoed it
+    fn poll_feedback_token(&mut self) -> KcpResult<()> {
+        let retry_interval = self.handshake_retry_interval;
+        let auth = match self.feedback_auth {
+            Some(ref mut auth) if !auth.confirmed() => auth,
+            _ => return Ok(()),
+        };
+        if auth.last_announcement().is_some_and(|at| elapsed(at) < retry_interval) {
+            return Ok(());
+        }
+        let announcement = auth.announcement();
+        self.kcp.output_raw(&announcement)?;
+        Ok(())
+    }
+
+    /// Close our sending side, the FIN is queued behind the unsent data
+    ///
+    /// Receiving goes on until the peer closes its side as well.
+    pub fn shutdown(&mut self) {
+        if self.shutdown_at.is_none() {
+            trace!("[FIN] conv {} shutting down, wait_snd {}", self.kcp.conv(), self.kcp.wait_snd());
+            self.shutdown_at = Some(now());
+            // No more staging, what was staged goes out ahead of the FIN
+            self.publish_send();
+            if let Err(err) = self.drain_staged() {
+                error!("Failed to send staged data: {}", err);
+            }
+            self.queue_fin();
+        }
+    }
+
+    fn queue_fin(&mut self) {
+        // Before the conv is known the FIN could open a session of its own
+        if self.fin_sent || self.never_heard_of_us() || self.kcp.waiting_conv() {
+            return;
+        }
+        trace!("[FIN] conv {} queueing FIN", self.kcp.conv());
+        self.kcp.send_fin();
+        self.fin_sent = true;
+        let result = self.kcp.flush();
+        if let Err(err) = self.process_flush_result(result) {
+            error!("Failed to flush FIN: {}", err);
+        }
+    }
+
+    /// `shutdown` and wait for the peer to acknowledge the FIN
+    pub fn poll_shutdown(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        self.local_shutdown = true;
+        self.shutdown();
+        if self.fin_done() {
+            // The last ACKs still waiting for the pacer go out first
+            if self.lingered() || self.pacer_queue.poll_drained(cx).is_ready() {
+                return Ok(()).into();
+            }
+        } else if self.lingered()
+            || self.timed_out
+            || self.refused
+            || self.unreachable
+            || self.staged_failed
+            || self.closed
+        {
+            return Err(io::Error::new(ErrorKind::TimedOut, "peer didn't acknowledge FIN").into()).into();
+        }
+
+        if let Some(waker) = self.pending_shutdown.replace(cx.waker().clone()) {
+            if !cx.waker().will_wake(&waker) {
+                waker.wake();
+            }
+        }
+        Poll::Pending
+    }
 
-        Ok(n).into()
+    fn never_heard_of_us(&self) -> bool {
+        !self.peer_seen && !self.sent_first
     }
 
-    /// Call if you want to send some data
-    #[allow(dead_code)]
-    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
-        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+    // Nothing to wait for anymore, the peer acknowledged everything up to the FIN or never heard of us
+    fn fin_done(&self) -> bool {
+        self.shutdown_at.is_some() && (self.never_heard_of_us() || (self.fin_sent && self.kcp.wait_snd() == 0))
     }
 
-    #[allow(dead_code)]
-    pub fn try_recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
-        if self.closed {
-            return Ok(0);
+    fn lingered(&self) -> bool {
+        self.shutdown_at.is_some_and(|at| elapsed(at) >= self.linger)
+    }
+
+    // Queue a FIN held back for the conv, wake `poll_shutdown` once it was acknowledged or lingering is over
+    fn poll_fin(&mut self) {
+        if self.shutdown_at.is_none() {
+            return;
+        }
+        self.queue_fin();
+        if self.fin_done() || self.lingered() {
+            if let Some(w) = self.pending_shutdown.take() {
+                w.wake();
+            }
         }
-        self.kcp.recv(buf)
     }
 
-    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+    /// Send a keepalive probe, `poll_ping` is ready with the round trip once the peer answered it
+    ///
+    /// The probe goes out ahead of queued data and the peer answers it at once, a lost probe is sent
+    /// again up to `handshake_max_attempts` probes in all. A probe still waiting for its answer is forgotten.
+    pub fn send_ping(&mut self) -> KcpResult<()> {
+        self.cancel_ping();
+        let id = self.send_keepalive_probe()?;
+        self.ping = Some((id, now()));
+        self.ping_attempts = 1;
+        Ok(())
+    }
+
+    /// Stop waiting for the answer of `send_ping`'s probe, nobody waits for the round trip anymore
+    pub fn cancel_ping(&mut self) {
+        self.ping = None;
+        self.ping_result = None;
+        self.pending_ping = None;
+    }
+
+    /// Round trip of the probe of `send_ping`, fails with `TimedOut` if none of its probes was answered
+    pub fn poll_ping(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Duration>> {
+        match self.ping_result.take() {
+            Some(Some(rtt)) => return Ok(rtt).into(),
+            Some(None) => return Err(io::Error::new(ErrorKind::TimedOut, "peer didn't answer the ping").into()).into(),
+            None => {}
+        }
+        if self.timed_out {
+            return Err(idle_timeout_error()).into();
+        }
+        if self.refused {
+            return Err(refused_error()).into();
+        }
+        if self.unreachable {
+            return Err(KcpError::PeerUnreachable).into();
+        }
+        if self.staged_failed {
+            return Err(staged_write_error()).into();
+        }
         if self.closed {
-            return Ok(0).into();
+            return Err(self.closed_error()).into();
+        }
+        if self.ping.is_none() {
+            return Err(io::Error::other("no ping sent").into()).into();
         }
 
-        match self.kcp.recv(buf) {
-            e @ (Err(KcpError::RecvQueueEmpty) | Err(KcpError::ExpectingFragment)) => {
-                trace!(
-                    "[RECV] rcvwnd={} peeksize={} r={:?}",
-                    self.kcp.rcv_wnd(),
-                    self.kcp.peeksize().unwrap_or(0),
-                    e
-                );
-            }
-            Err(err) => return Err(err).into(),
-            Ok(n) => {
-                if n == 0 && !self.allow_recv_empty_packet {
-                    trace!(
-                        "[RECV] rcvwnd={} peeksize={} r=Ok(0)",
-                        self.kcp.rcv_wnd(),
-                        self.kcp.peeksize().unwrap_or(0),
-                    );
-                } else {
-                    self.last_update = Instant::now();
-                    return Ok(n).into();
-                }
+        if let Some(waker) = self.pending_ping.replace(cx.waker().clone()) {
+            if !cx.waker().will_wake(&waker) {
+                waker.wake();
             }
         }
+        Poll::Pending
+    }
+
+    /// Ask the peer for its window until it answers, the answer assigns the `conv` if the server allocates it
+    pub fn poll_handshake(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        if self.peer_seen {
+            return Ok(()).into();
+        }
+        if self.refused {
+            return Err(refused_error()).into();
+        }
+        if self.unreachable {
+            return Err(KcpError::PeerUnreachable).into();
+        }
+        if self.handshake_attempts == 0 {
+            self.send_handshake()?;
+        } else if self.handshake_exhausted() {
+            return Err(KcpError::HandshakeTimeout).into();
+        }
 
-        if let Some(waker) = self.pending_receiver.replace(cx.waker().clone()) {
+        if let Some(waker) = self.pending_handshake.replace(cx.waker().clone()) {
             if !cx.waker().will_wake(&waker) {
                 waker.wake();
             }
         }
-
         Poll::Pending
     }
 
-    #[allow(dead_code)]
-    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
-        future::poll_fn(|cx| self.poll_recv(cx, buf)).await
+    // The last attempt went unanswered for a whole retry interval
+    fn handshake_exhausted(&self) -> bool {
+        self.handshake_attempts >= self.handshake_max_attempts
+            && self.last_handshake.is_some_and(|at| elapsed(at) >= self.handshake_retry_interval)
+    }
+
+    fn send_handshake(&mut self) -> KcpResult<()> {
+        trace!("[HANDSHAKE] conv {} attempt {}", self.kcp.conv(), self.handshake_attempts + 1);
+        self.kcp.ask_window();
+        let result = self.kcp.flush();
+        self.process_flush_result(result)?;
+        self.handshake_attempts += 1;
+        self.last_handshake = Some(now());
+        Ok(())
+    }
+
+    fn poll_handshake_retry(&mut self) -> KcpResult<()> {
+        let last_handshake = match self.last_handshake {
+            Some(at) if !self.peer_seen => at,
+            _ => return Ok(()),
+        };
+        if elapsed(last_handshake) < self.handshake_retry_interval {
+            return Ok(());
+        }
+        if self.handshake_attempts < self.handshake_max_attempts {
+            self.send_handshake()
+        } else {
+            // Out of attempts, `poll_handshake` fails
+            if let Some(w) = self.pending_handshake.take() {
+                w.wake();
+            }
+            Ok(())
+        }
+    }
+
+    fn check_dead_link(&mut self) {
+        if !self.unreachable && self.kcp.is_dead_link() {
+            trace!("[SESSION] conv {} retransmissions exhausted, peer unreachable", self.kcp.conv());
+            self.unreachable = true;
+            self.wake_all();
+        }
+    }
+
+    fn check_idle_timeout(&mut self) {
+        let idle_timeout = match self.idle_timeout {
+            Some(idle_timeout) => idle_timeout,
+            None => return,
+        };
+        if !self.timed_out && elapsed(self.last_recv) >= idle_timeout {
+            trace!(
+                "[KEEPALIVE] conv {} received nothing for {:?}, timed out",
+                self.kcp.conv(),
+                elapsed(self.last_recv)
+            );
+            self.timed_out = true;
+            self.wake_all();
+        }
     }
 
     pub fn flush(&mut self) -> KcpResult<()> {
+        let _span = trace_span!(parent: &self.span, "flush").entered();
+        self.drain_staged()?;
+        self.sync_recv();
         let flush_result = self.kcp.flush()?;
         self.process_flush_result(Ok(flush_result))?;
-        self.last_update = Instant::now();
+        self.last_update = now();
         Ok(())
     }
 
+    /// `flush` and wait until the pacer handed everything to the transport
+    pub fn poll_flush(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+        self.flush()?;
+        self.pacer_queue.poll_drained(cx).map(Ok)
+    }
+
     pub fn try_wake_pending_waker(&mut self) -> bool {
-        let mut waked = false;
+        // Room in the send window wakes the writer
+        let mut waked = self.publish_send();
 
-        if self.pending_sender.is_some()
-            && self.kcp.wait_snd() < self.kcp.snd_wnd() as usize
-            && self.kcp.wait_snd() < self.kcp.rmt_wnd() as usize
-            && !self.kcp.waiting_conv()
-        {
-            let waker = self.pending_sender.take().unwrap();
-            waker.wake();
+        // Passthrough readers get EOF, the receive half tells its reader itself
+        if self.kcp.fin_received() {
+            if let Some(waker) = self.pending_datagram_receiver.take() {
+                waker.wake();
+                waked = true;
+            }
+        }
 
+        if self.pending_shutdown.is_some() && self.fin_done() {
+            self.pending_shutdown.take().unwrap().wake();
             waked = true;
         }
 
-        if self.pending_receiver.is_some() {
-            if let Ok(peek) = self.kcp.peeksize() {
-                if self.allow_recv_empty_packet || peek > 0 {
-                    let waker = self.pending_receiver.take().unwrap();
-                    waker.wake();
+        if self.pending_ping.is_some() && self.ping_result.is_some() {
+            self.pending_ping.take().unwrap().wake();
+            waked = true;
+        }
 
-                    waked = true;
-                }
-            }
+        if self.pending_handshake.is_some() && self.peer_seen {
+            self.pending_handshake.take().unwrap().wake();
+            waked = true;
         }
 
         waked
     }
 
-    fn process_flush_result(&mut self, result: KcpResult<((bool, Vec<u32>), Vec<(u32, usize)>)>) -> KcpResult<()> {
+    fn process_flush_result(&mut self, result: KcpResult<FlushResult>) -> KcpResult<()> {
         match result {
             Ok((packet_loss_detected, new_packets)) => {
                 if packet_loss_detected.0 {
                     for sn in packet_loss_detected.1 {
-                        self.scream.on_packet_loss(sn);
+                        self.congestion.on_loss(feedback::segment_sn(sn));
+                        self.packets_lost += 1;
+                        self.qlog(QlogEvent::PacketLost { sn });
                     }
                 }
                 for (seq_number, size) in new_packets {
-                    self.scream.on_packet_sent(seq_number, size);
+                    self.congestion.on_packet_sent(feedback::segment_sn(seq_number), size);
+                    self.qlog(QlogEvent::PacketSent { sn: seq_number, size });
+                }
+                for sn in self.kcp.take_expired() {
+                    self.congestion.on_expired(feedback::segment_sn(sn));
+                    self.segments_expired += 1;
                 }
                 Ok(())
             }
@@ -291,71 +1576,456 @@ impl KcpSocket {
     }
 
     pub fn update(&mut self) -> KcpResult<Instant> {
-        let now = now_millis();
-        let update_result = self.kcp.update(now);
+        let _span = trace_span!(parent: &self.span, "update").entered();
+        // Staged writes and the window reopened by reads go out with this update
+        self.drain_staged()?;
+        self.sync_recv();
+        let current = now_millis();
+        let update_result = self.kcp.update(current);
         self.process_flush_result(update_result)?;
-
-        if self.scream.get_last_feedback_time().elapsed() >= Duration::from_millis(10) {
-            if let Some(feedback_data) = self.scream.create_feedback_packet() {
-                let mut scream_packet = Vec::with_capacity(4 + feedback_data.len());
-                scream_packet.put_u32_le(scream::SCREAM_FEEDBACK_HEADER);
-                scream_packet.extend_from_slice(&feedback_data);
-
+        let next_duplicate = self.kcp.output_mut().queue_duplicates(now())?;
+
+        // Due ACKs and feedback take each other along
+        let acks_due = self.acks_due();
+        let send_feedback = self.feedback_due() || (acks_due && self.feedback_pending > 0);
+        // The peer would drop feedback without its token, the report waits until it told us
+        let token = match self.feedback_auth {
+            Some(ref auth) => auth.peer_token().map(Some),
+            None => Some(None),
+        };
+        if send_feedback {
+            self.feedback_pending = 0;
+            self.last_feedback_time = now();
+            if token.is_none() {
+                trace!("[SESSION] conv {} feedback withheld, peer token unconfirmed", self.kcp.conv());
+            }
+        }
+        if let (true, Some(token)) = (send_feedback, token) {
+            let header_len = 4 + if token.is_some() { FEEDBACK_TOKEN_OVERHEAD } else { 0 };
+            // Reports beyond one MTU are split across packets
+            let max_len = self.kcp.mtu() - header_len;
+            loop {
+                self.output_buf.clear();
+                self.output_buf.put_u32_le(scream::SCREAM_FEEDBACK_HEADER);
+                if let Some(token) = token {
+                    self.output_buf.put_u64_le(token);
+                }
+                if !self.congestion.write_feedback(&mut self.output_buf, max_len) {
+                    break;
+                }
                 // send directly through pacer -> no kcp header
-                if let Err(e) = self.kcp.output_raw(&scream_packet) {
+                if let Err(e) = self.kcp.output_raw(&self.output_buf) {
                     error!("Failed to send raw SCReAM feedback packet: {}", e);
                 }
+                self.qlog(QlogEvent::FeedbackSent { size: self.output_buf.len() - header_len });
             }
         }
+        if acks_due || (send_feedback && self.ack_pending_since.is_some()) {
+            self.ack_pending_since = None;
+            self.kcp.flush_ack()?;
+        }
+
+        self.poll_pmtu_probe()?;
+        self.poll_keepalive()?;
+        self.poll_ping_retry()?;
+        self.poll_feedback_token()?;
+        self.poll_handshake_retry()?;
+        self.poll_fin();
+        self.check_idle_timeout();
+        self.check_dead_link();
+        self.poll_throughput();
+
+        // Controller, window and metrics have nothing new to work with until input or a send
+        // wakes the session
+        let idle = self.is_idle() && next_duplicate.is_none();
+        if idle && self.idle {
+            self.try_wake_pending_waker();
+            return Ok(self.idle_deadline());
+        }
 
-        let s_rtt_duration = Duration::from_secs_f32(self.scream.get_s_rtt().max(0.02)); 
-        if self.scream.get_last_periodic_update_time().elapsed() >= s_rtt_duration {
-            self.scream.on_rtt();
+        self.poll_padding()?;
+        self.congestion.on_send_queue(self.kcp.snd_queue_len());
+
+        let s_rtt_duration = Duration::from_secs_f32(self.congestion.get_s_rtt().max(0.02));
+        let since_rtt_tick = elapsed(self.last_rtt_tick);
+        if since_rtt_tick >= s_rtt_duration {
+            self.congestion.on_rtt();
+            self.last_rtt_tick = now();
+            let bytes_sent = self.pacer_queue.bytes_sent();
+            let sent = bytes_sent.saturating_sub(self.rtt_tick_bytes_sent);
+            self.recent_send_rate = (sent * 8) as f32 / since_rtt_tick.as_secs_f32();
+            self.rtt_tick_bytes_sent = bytes_sent;
+
+            let stats = self.congestion.stats();
+            if !stats.s_rtt.is_zero() {
+                self.emit(KcpEvent::RttUpdate {
+                    srtt: stats.s_rtt,
+                    rttvar: stats.rtt_var,
+                });
+            }
+        }
+        // Drained even without a handler, controllers queue them
+        while let Some(event) = self.congestion.poll_congestion_event() {
+            self.emit(KcpEvent::Congestion(event));
         }
 
         let mss = self.kcp.mss() as u32;
         if mss > 0 {
-            let ref_wnd = self.scream.get_ref_wnd();  
-            let new_snd_window = (ref_wnd / mss as f32).max(2.0) as u16;
-            self.kcp.set_wndsize(new_snd_window, self.kcp.rcv_wnd());
+            let ref_wnd = self.congestion.get_congestion_window();
+            // Rounded up, segments below the MSS could otherwise never fill the window and grow it
+            let new_snd_window = (ref_wnd / mss as f32).ceil().clamp(2.0, self.max_snd_wnd as f32) as u16;
+            // 0 keeps the receive window, which may be below the floor of `set_wndsize`
+            self.kcp.set_wndsize(new_snd_window, 0);
         }
 
-        self.scream.log_data();
-
-        let new_pacing_rate = self.scream.get_pacing_rate();
+        let mut sample = self.congestion.metrics_sample();
+        sample.timestamp_ms = unix_millis();
+        sample.conv = self.kcp.conv();
+        sample.session_id = self.session_id;
+        sample.peer_addr = Some(self.peer_addr());
+        sample.bytes_sent = self.pacer_queue.bytes_sent();
+        sample.bytes_received = self.bytes_received;
+        sample.retransmits = self.kcp.xmit();
+        sample.fast_retransmits = self.kcp.fast_xmit();
+        sample.out_of_order = self.kcp.out_of_order();
+        sample.duplicate_acks = self.kcp.dup_acks();
+        sample.pacer_queue = self.pacer_queue.queued();
+        sample.rmt_wnd = self.kcp.rmt_wnd();
+        self.metrics.record(&sample);
+
+        // The pacing rate keeps the controller's headroom over the limited target bitrate
+        let target_bitrate = self.congestion.get_target_bitrate();
+        let new_target_bitrate = self.limit_rate(target_bitrate);
+        let mut new_pacing_rate = if target_bitrate > 0.0 {
+            self.congestion.get_pacing_rate() * (new_target_bitrate / target_bitrate)
+        } else {
+            self.limit_rate(self.congestion.get_pacing_rate())
+        };
+        if let Some(ref share) = self.rate_share {
+            // Without a backlog the session only claims a margin over what it sent lately
+            let demand = if idle {
+                0.0
+            } else if self.kcp.snd_queue_len() > 0 || self.pacer_queue.queued() > 0 {
+                new_pacing_rate
+            } else {
+                self.recent_send_rate * APP_LIMITED_DEMAND_MARGIN
+            };
+            new_pacing_rate = share.update(new_pacing_rate, demand);
+        }
         if self.pacing_rate_tx.send(new_pacing_rate).is_err() {
             error!("Pacer task seems to have died.");
         }
 
+        let qlog_metrics = (sample.congestion_window, new_pacing_rate);
+        if self.qlog.is_some() && qlog_metrics != self.qlog_metrics {
+            self.qlog_metrics = qlog_metrics;
+            self.qlog(QlogEvent::MetricsUpdated {
+                congestion_window: sample.congestion_window,
+                bytes_in_flight: sample.bytes_in_flight,
+                smoothed_rtt: sample.s_rtt,
+                pacing_rate: new_pacing_rate,
+            });
+        }
 
-        let new_target_bitrate = self.scream.get_target_bitrate();
         if self.target_bitrate_tx.send(new_target_bitrate).is_err() {
             error!("Target bitrate could not be sent.");
         }
 
+        // Receivers are only woken when something changed, idle sessions stay quiet
+        let mut state = self.congestion.congestion_state();
+        state.target_bitrate = new_target_bitrate;
+        self.congestion_state_tx.send_if_modified(|current| {
+            let modified = *current != state;
+            *current = state;
+            modified
+        });
+        let pacer_state = self.pacer_queue.state();
+        self.pacer_state_tx.send_if_modified(|current| {
+            let modified = *current != pacer_state;
+            *current = pacer_state;
+            modified
+        });
+
 
-        let next = self.kcp.check(now);
         self.try_wake_pending_waker();
-        Ok(Instant::now() + Duration::from_millis(next as u64))
+        self.idle = idle;
+        if idle {
+            return Ok(self.idle_deadline());
+        }
+
+        let mut next = now() + Duration::from_millis(self.kcp.check(current) as u64);
+        if self.feedback_pending > 0 {
+            next = next.min(self.feedback_deadline());
+        }
+        if let (Some(since), Some(delayed_ack)) = (self.ack_pending_since, self.delayed_ack) {
+            next = next.min(since + delayed_ack.max_delay);
+        }
+        if let Some(due) = next_duplicate {
+            next = next.min(due);
+        }
+        let announced = self.feedback_auth.as_ref().filter(|auth| !auth.confirmed());
+        if let Some(at) = announced.and_then(|auth| auth.last_announcement()) {
+            next = next.min(at + self.handshake_retry_interval);
+        }
+        Ok(next.min(self.last_rtt_tick + s_rtt_duration))
+    }
+
+    // Earliest time the next feedback may go out
+    fn feedback_deadline(&self) -> Instant {
+        let interval = match self.feedback_policy {
+            FeedbackPolicy::Fixed => self.feedback_interval,
+            // Until the first RTT sample the interval is the fixed one
+            FeedbackPolicy::Adaptive => {
+                let quarter_rtt = Duration::from_secs_f32(self.congestion.get_s_rtt().max(0.0) / 4.0);
+                self.feedback_interval.max(quarter_rtt)
+            }
+        };
+        self.last_feedback_time + interval
+    }
+
+    fn feedback_due(&self) -> bool {
+        self.feedback_pending > 0
+            && (now() >= self.feedback_deadline()
+                || self.feedback_max_packets.is_some_and(|max| self.feedback_pending >= max))
+    }
+
+    // Longest time (ms) the peer holds an ACK, assuming it runs our config: until its next update unless it
+    // flushes them on input, delayed ones up to their delay
+    fn peer_ack_delay(&self) -> u32 {
+        match self.delayed_ack {
+            Some(delayed_ack) => delayed_ack.max_delay.as_millis().min(u32::MAX as u128) as u32,
+            None if self.flush_ack_input => 0,
+            None => self.kcp.interval(),
+        }
+    }
+
+    // The batch of delayed ACKs is full or its oldest one waited `max_delay`
+    fn acks_due(&self) -> bool {
+        match (self.ack_pending_since, self.delayed_ack) {
+            (Some(since), Some(delayed_ack)) => {
+                self.kcp.ack_pending() >= delayed_ack.max_packets || elapsed(since) >= delayed_ack.max_delay
+            }
+            _ => false,
+        }
+    }
+
+    // Nothing to send, acknowledge, report or probe, `update` may sleep until input or a send
+    fn is_idle(&self) -> bool {
+        self.kcp.is_idle()
+            && self.feedback_pending == 0
+            && self.peer_seen
+            && self.shutdown_at.is_none()
+            && self.ping.is_none()
+            && self.pacer_queue.queued() == 0
+            && self.probe_bitrate.is_none_or(|probe| self.congestion.get_target_bitrate() >= probe)
+            && self.pmtud.as_ref().is_none_or(|pmtud| !pmtud.is_searching())
+    }
+
+    // Publish the rates of the interval once it is over
+    fn poll_throughput(&mut self) {
+        let now = now();
+        let (start, bytes_sent, bytes_received, retransmitted_bytes) = self.throughput_start;
+        let interval = now.saturating_duration_since(start);
+        if interval < THROUGHPUT_INTERVAL {
+            return;
+        }
+
+        let counters = (
+            self.pacer_queue.bytes_sent(),
+            self.bytes_received,
+            self.kcp.retransmitted_bytes(),
+        );
+        let bps = |bytes: u64| (bytes * 8) as f32 / interval.as_secs_f32();
+        self.throughput_tx.send_replace(ThroughputSample {
+            sent_bps: bps(counters.0.saturating_sub(bytes_sent)),
+            recv_bps: bps(counters.1.saturating_sub(bytes_received)),
+            retransmit_bps: bps(counters.2.saturating_sub(retransmitted_bytes)),
+            interval,
+        });
+        self.throughput_start = (now, counters.0, counters.1, counters.2);
     }
 
+    // Next keepalive or idle timeout of an idle session
+    fn idle_deadline(&self) -> Instant {
+        let mut deadline = now() + IDLE_UPDATE_INTERVAL;
+        if let Some(interval) = self.keepalive_interval {
+            deadline = deadline.min(self.last_recv.max(self.last_keepalive) + interval);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            deadline = deadline.min(self.last_recv + idle_timeout);
+        }
+        deadline
+    }
+
+    /// `update` has to run before the time it returned: work arrived while it sleeps through an idle
+    /// period, or enough packets wait for feedback or delayed ACKs to send them early
+    pub fn take_update_wakeup(&mut self) -> bool {
+        if self.idle && !self.is_idle() {
+            self.idle = false;
+            return true;
+        }
+        self.feedback_max_packets.is_some_and(|max| self.feedback_pending >= max)
+            || self.delayed_ack.is_some_and(|delayed_ack| self.kcp.ack_pending() >= delayed_ack.max_packets)
+    }
+
+
+    // Writing after we shut down or closed, or after the peer ended the session
+    fn closed_error(&self) -> KcpError {
+        if !self.local_shutdown && self.kcp.fin_received() {
+            KcpError::PeerClosed
+        } else {
+            KcpError::Closed
+        }
+    }
 
     pub fn close(&mut self) {
+        if !self.closed {
+            self.metrics.close(self.kcp.conv(), self.session_id);
+            self.emit(KcpEvent::Closed);
+        }
         self.closed = true;
-        if let Some(w) = self.pending_sender.take() {
+        self.wake_all();
+    }
+
+    /// Apply the settings given in `config`, the windows take effect with the next update
+    ///
+    /// Nothing changes if `config` is invalid, see `KcpRuntimeConfig::validate`.
+    pub fn reconfigure(&mut self, config: &KcpRuntimeConfig) -> KcpResult<()> {
+        config.validate()?;
+        if let Some(nodelay) = config.nodelay {
+            self.kcp.set_nodelay(nodelay.nodelay, nodelay.interval, nodelay.resend, nodelay.nc);
+            self.kcp.set_peer_ack_delay(self.peer_ack_delay());
+        }
+        if let Some(snd_wnd) = config.snd_wnd {
+            self.max_snd_wnd = snd_wnd.max(2);
+        }
+        if let Some(rcv_wnd) = config.rcv_wnd {
+            self.kcp.set_wndsize(0, rcv_wnd);
+            self.rcv_wnd = self.kcp.rcv_wnd();
+            self.sync_recv();
+        }
+        if let Some(feedback_interval) = config.feedback_interval {
+            self.feedback_interval = feedback_interval;
+        }
+        if let Some(max_target_bitrate) = config.max_target_bitrate {
+            self.max_target_bitrate = max_target_bitrate;
+            self.min_target_bitrate = self.min_target_bitrate.min(max_target_bitrate);
+        }
+        self.congestion.on_reconfigure(config);
+        // The next update publishes the new window and pacing rate even while idle
+        self.idle = false;
+        Ok(())
+    }
+
+    fn limit_rate(&self, rate: f32) -> f32 {
+        // Not `clamp`, an unvalidated config may have the bounds crossed. The peer's limit goes below
+        // the lower bound, more would only queue up at its end
+        let remote_max_bitrate = self.congestion.get_remote_max_bitrate().unwrap_or(f32::INFINITY);
+        rate.max(self.min_target_bitrate).min(self.max_target_bitrate).min(remote_max_bitrate)
+    }
+
+    /// Call `handler` with every event of this session, replacing the previous handler
+    pub(crate) fn set_event_handler(&mut self, handler: EventHandler) {
+        self.event_handler = Some(handler);
+    }
+
+    fn qlog(&self, event: QlogEvent) {
+        if let Some(ref qlog) = self.qlog {
+            qlog.log(self.kcp.conv(), event);
+        }
+    }
+
+    fn emit(&self, event: KcpEvent) {
+        if let Some(ref handler) = self.event_handler {
+            handler.emit(event);
+        }
+    }
+
+    fn wake_all(&mut self) {
+        if let Some(w) = self.pending_shutdown.take() {
             w.wake();
         }
-        if let Some(w) = self.pending_receiver.take() {
+        if let Some(w) = self.pending_ping.take() {
             w.wake();
         }
+        if let Some(w) = self.pending_handshake.take() {
+            w.wake();
+        }
+        self.publish_send();
+        self.send_half.wake();
+        self.recv_half.set_end(self.recv_end());
+        if let Some(w) = self.pending_datagram_receiver.take() {
+            w.wake();
+        }
+    }
+
+    // What readers get instead of data, in this order
+    fn recv_end(&self) -> Option<RecvEnd> {
+        if self.timed_out {
+            Some(RecvEnd::TimedOut)
+        } else if self.refused {
+            Some(RecvEnd::Refused)
+        } else if self.unreachable {
+            Some(RecvEnd::Unreachable)
+        } else if self.staged_failed {
+            Some(RecvEnd::StagedWriteFailed)
+        } else if self.closed {
+            Some(RecvEnd::Closed)
+        } else {
+            None
+        }
     }
 
-    pub fn udp_socket(&self) -> &Arc<UdpSocket> {
+    pub fn transport(&self) -> &Arc<dyn DatagramTransport> {
         &self.socket
     }
 
+    /// Address every datagram is sent to
+    pub fn peer_addr(&self) -> SocketAddr {
+        *self.peer_addr_tx.borrow()
+    }
+
+    /// Pace within `share` of an aggregate rate from the next update on
+    pub(crate) fn set_rate_share(&mut self, share: RateShare) {
+        self.rate_share = Some(share);
+    }
+
+    /// Send to `peer_addr` from now on, the peer's NAT rebound or it moved to another network
+    pub fn set_peer_addr(&mut self, peer_addr: SocketAddr) {
+        trace!("[MIGRATE] conv {} peer {} -> {}", self.kcp.conv(), self.peer_addr(), peer_addr);
+        self.peer_addr_tx.send_replace(peer_addr);
+        self.span.record("peer", display(peer_addr));
+        self.emit(KcpEvent::PeerMigrated { peer_addr });
+    }
+
+    /// ECN marking and CE reporting enabled
+    pub fn ecn(&self) -> bool {
+        self.ecn
+    }
+
+    /// Nothing was received within `KcpConfig::idle_timeout`
+    pub fn timed_out(&self) -> bool {
+        self.timed_out
+    }
+
+    /// The listener refused to create a session for us
+    pub fn refused(&self) -> bool {
+        self.refused
+    }
+
+    /// A segment ran out of `KcpConfig::max_retransmissions`
+    pub fn unreachable(&self) -> bool {
+        self.unreachable
+    }
+
+    /// Datagrams per `recvmmsg` call
+    pub fn udp_batch_size(&self) -> usize {
+        self.udp_batch_size
+    }
+
+    /// Unsent data and the FIN were acknowledged, or the linger time is over
     pub fn can_close(&self) -> bool {
-        self.kcp.wait_snd() == 0
+        self.fin_done() || self.lingered()
     }
 
     pub fn conv(&self) -> u32 {
@@ -363,19 +2033,97 @@ impl KcpSocket {
     }
 
     pub fn set_conv(&mut self, conv: u32) {
+        // Samples continue under the new conv
+        self.metrics.close(self.kcp.conv(), self.session_id);
         self.kcp.set_conv(conv);
+        self.span.record("conv", conv);
+        self.emit(KcpEvent::ConvAssigned { conv });
+    }
+
+    /// Span of this session, keyed by conv and peer address
+    pub fn span(&self) -> &Span {
+        &self.span
     }
 
     pub fn waiting_conv(&self) -> bool {
         self.kcp.waiting_conv()
     }
 
+    /// Framing of sends, see `KcpConfig::stream_mode`
+    pub fn stream_mode(&self) -> StreamMode {
+        if self.kcp.is_stream() {
+            StreamMode::ByteStream
+        } else {
+            StreamMode::Message
+        }
+    }
+
     pub fn peek_size(&self) -> KcpResult<usize> {
-        self.kcp.peeksize()
+        if self.passthrough {
+            return self.datagrams.front().map(Vec::len).ok_or(KcpError::RecvQueueEmpty);
+        }
+        self.recv_half.peek_size()
     }
 
     pub fn last_update_time(&self) -> Instant {
-        self.last_update
+        // Reads don't take the socket's lock
+        self.recv_half.last_read().map_or(self.last_update, |last_read| last_read.max(self.last_update))
+    }
+
+    /// Watch of the congestion state, updated every update tick
+    pub fn congestion_state_receiver(&self) -> watch::Receiver<CongestionState> {
+        self.congestion_state_tx.subscribe()
+    }
+
+    /// Watch of the pacer queue, updated every update tick
+    pub fn pacer_state_receiver(&self) -> watch::Receiver<PacerState> {
+        self.pacer_state_tx.subscribe()
+    }
+
+    /// Watch of the throughput, updated once per second
+    pub fn throughput_receiver(&self) -> watch::Receiver<ThroughputSample> {
+        self.throughput_tx.subscribe()
+    }
+
+    /// Snapshot of the KCP and congestion control state
+    pub fn stats(&self) -> KcpStats {
+        let congestion = self.congestion.stats();
+        // Counting the messages held for the reader, as if they still waited in KCP
+        let (held_segments, held_bytes) = self.recv_half.held();
+        let rcv_wnd = cmp::min(self.rcv_wnd_for(0, held_bytes) + held_segments as u16, self.rcv_wnd);
+        KcpStats {
+            srtt: congestion.s_rtt,
+            rttvar: congestion.rtt_var,
+            base_rtt: congestion.base_rtt,
+            qdelay: congestion.qdelay,
+            ref_wnd: congestion.congestion_window,
+            bytes_in_flight: congestion.bytes_in_flight,
+            retransmits: self.kcp.xmit(),
+            fast_retransmits: self.kcp.fast_xmit(),
+            out_of_order: self.kcp.out_of_order(),
+            duplicate_acks: self.kcp.dup_acks(),
+            packets_lost: self.packets_lost,
+            loss_rate: congestion.loss_rate,
+            retransmitted_bytes: congestion.retransmitted_bytes,
+            spurious_retransmits: congestion.spurious_retransmits,
+            segments_expired: self.segments_expired,
+            orphaned_packets: congestion.orphaned_packets,
+            wait_snd: self.kcp.wait_snd(),
+            snd_wnd: self.kcp.snd_wnd(),
+            rcv_wnd,
+            rcv_buffered: self.kcp.rcv_buffered() + held_bytes,
+            rmt_wnd: self.kcp.rmt_wnd(),
+            mtu: self.kcp.mtu(),
+            pacing_rate: *self.pacing_rate_tx.borrow(),
+            target_bitrate: *self.target_bitrate_tx.borrow(),
+            pacer_queued: self.pacer_queue.queued(),
+            pacer_dropped: self.pacer_queue.dropped(),
+            padding_sent: self.padding_sent,
+            bytes_sent: self.pacer_queue.bytes_sent(),
+            bytes_received: self.bytes_received,
+            replays_dropped: self.replays_dropped,
+            unknown_packets: self.unknown_packets,
+        }
     }
 
     pub fn need_flush(&self) -> bool {
@@ -384,11 +2132,23 @@ impl KcpSocket {
     }
 }
 
+pub fn refused_error() -> KcpError {
+    KcpError::IoError(io::Error::new(ErrorKind::ConnectionRefused, "listener refused the connection"))
+}
+
+pub fn idle_timeout_error() -> KcpError {
+    KcpError::IoError(io::Error::new(ErrorKind::TimedOut, "no packets received within idle timeout"))
+}
+
+pub fn staged_write_error() -> KcpError {
+    KcpError::IoError(io::Error::other("staged write no longer fits into KCP, the session lost it"))
+}
+
 #[cfg(test)]
 mod test {
 
     use kcp::Error as KcpError;
-    use log::trace;
+    use tracing::trace;
     use std::sync::Arc;
     use tokio::{
         net::UdpSocket,
@@ -397,7 +2157,11 @@ mod test {
     };
 
     use super::KcpSocket;
-    use crate::config::KcpConfig;
+    use crate::{
+        config::{DelayedAckConfig, KcpConfig, StreamMode},
+        ecn::EcnCodepoint,
+        scream::ScreamCongestionControl,
+    };
 
     #[tokio::test]
     async fn kcp_echo() {
@@ -416,8 +2180,24 @@ mod test {
         let s2 = Arc::new(s2);
 
         let config = KcpConfig::default();
-        let kcp1 = KcpSocket::new(&config, 0, s1.clone(), s2_addr, true).unwrap();
-        let kcp2 = KcpSocket::new(&config, CONV, s2.clone(), s1_addr, true).unwrap();
+        let kcp1 = KcpSocket::new(
+            &config,
+            0,
+            s1.clone(),
+            s2_addr,
+            StreamMode::ByteStream,
+            Box::new(ScreamCongestionControl::new()),
+        )
+        .unwrap();
+        let kcp2 = KcpSocket::new(
+            &config,
+            CONV,
+            s2.clone(),
+            s1_addr,
+            StreamMode::ByteStream,
+            Box::new(ScreamCongestionControl::new()),
+        )
+        .unwrap();
 
         let kcp1 = Arc::new(Mutex::new(kcp1));
         let kcp2 = Arc::new(Mutex::new(kcp2));
@@ -467,7 +2247,7 @@ mod test {
                 }
 
                 let mut kcp2 = kcp2.lock().await;
-                kcp2.0.input(packet).unwrap();
+                kcp2.0.input_packet(packet, EcnCodepoint::NotEct).unwrap();
 
                 match kcp2.0.try_recv(&mut buf) {
                     Ok(n) => {
@@ -493,7 +2273,7 @@ mod test {
                 let packet = &buf[..n];
 
                 let mut kcp1 = kcp1.lock().await;
-                kcp1.0.input(packet).unwrap();
+                kcp1.0.input_packet(packet, EcnCodepoint::NotEct).unwrap();
 
                 match kcp1.0.try_recv(&mut buf) {
                     Ok(n) => {
@@ -515,4 +2295,334 @@ mod test {
         kcp1_task.abort();
         kcp2_task.abort();
     }
+
+    #[tokio::test]
+    async fn feedback_split_at_mtu() {
+        use bytes::{Buf, BufMut};
+        use std::time::Duration;
+
+        let receiver = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Only the packet count triggers feedback
+        let config = KcpConfig {
+            feedback_interval: Duration::from_secs(3600),
+            feedback_max_packets: Some(200),
+            ..KcpConfig::default()
+        };
+        let (mut kcp, _) = KcpSocket::new(
+            &config,
+            1,
+            receiver,
+            peer.local_addr().unwrap(),
+            StreamMode::Message,
+            Box::new(ScreamCongestionControl::new()),
+        )
+        .unwrap();
+
+        for sn in 0..200u32 {
+            let mut padding = Vec::new();
+            padding.put_u32_le(super::PADDING_HEADER);
+            padding.put_u32_le(super::DATAGRAM_SN_FLAG | sn);
+            kcp.input_packet(&padding, EcnCodepoint::NotEct).unwrap();
+            assert_eq!(kcp.take_update_wakeup(), sn == 199);
+        }
+        kcp.update().unwrap();
+        assert!(!kcp.take_update_wakeup());
+
+        let mtu = kcp.kcp.mtu();
+        let mut buf = [0u8; 2048];
+        let (mut packets, mut entries) = (0, 0);
+        while entries < 200 {
+            let n = time::timeout(Duration::from_secs(1), peer.recv(&mut buf)).await.unwrap().unwrap();
+            if (&buf[..4]).get_u32_le() != crate::scream::SCREAM_FEEDBACK_HEADER {
+                continue;
+            }
+            assert!(n <= mtu);
+            packets += 1;
+            entries += (n - 4) / crate::feedback::FEEDBACK_ENTRY_LEN;
+        }
+        assert_eq!(entries, 200);
+        assert_eq!(packets, 2);
+    }
+
+    #[tokio::test]
+    async fn delayed_ack_batches() {
+        use bytes::{Buf, BufMut};
+        use std::time::Duration;
+
+        let receiver = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = KcpConfig {
+            delayed_ack: Some(DelayedAckConfig {
+                max_packets: 4,
+                max_delay: Duration::from_millis(20),
+            }),
+            feedback_interval: Duration::from_secs(3600),
+            ..KcpConfig::default()
+        };
+        let (mut kcp, _) = KcpSocket::new(
+            &config,
+            1,
+            receiver,
+            peer.local_addr().unwrap(),
+            StreamMode::Message,
+            Box::new(ScreamCongestionControl::new()),
+        )
+        .unwrap();
+        kcp.update().unwrap();
+
+        let push = |sn: u32| {
+            let mut buf = Vec::new();
+            buf.put_u32_le(1);
+            buf.put_u8(81);
+            buf.put_u8(0);
+            buf.put_u16_le(256);
+            buf.put_u32_le(0);
+            buf.put_u32_le(sn);
+            buf.put_u32_le(0);
+            buf.put_u32_le(4);
+            buf.extend_from_slice(b"data");
+            buf
+        };
+        // ACK and feedback datagrams that arrive within 50ms
+        let mut buf = [0u8; 2048];
+        let mut received = || {
+            let (mut acks, mut feedback) = (0, 0);
+            while let Ok(n) = peer.try_recv(&mut buf) {
+                if (&buf[..4]).get_u32_le() == crate::scream::SCREAM_FEEDBACK_HEADER {
+                    feedback += 1;
+                } else {
+                    acks += n / kcp::KCP_OVERHEAD;
+                }
+            }
+            (acks, feedback)
+        };
+
+        for sn in 0..4 {
+            kcp.input_packet(&push(sn), EcnCodepoint::NotEct).unwrap();
+            assert_eq!(kcp.take_update_wakeup(), sn == 3);
+            if sn < 3 {
+                kcp.update().unwrap();
+            }
+        }
+        time::sleep(Duration::from_millis(5)).await;
+        assert_eq!(received(), (0, 0));
+
+        // The full batch goes out in one datagram, the feedback of the four packets with it
+        kcp.update().unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(received(), (4, 1));
+
+        // A single segment waits for max_delay
+        kcp.input_packet(&push(4), EcnCodepoint::NotEct).unwrap();
+        let next = kcp.update().unwrap();
+        assert!(next <= std::time::Instant::now() + Duration::from_millis(20));
+        time::sleep_until(next.into()).await;
+        kcp.update().unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert_eq!(received(), (1, 1));
+    }
+
+    #[tokio::test]
+    async fn feedback_auth_tokens() {
+        use bytes::{Buf, BufMut};
+        use std::time::Duration;
+
+        use crate::feedback_auth::FEEDBACK_TOKEN_HEADER;
+
+        let receiver = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = KcpConfig {
+            feedback_auth: true,
+            feedback_max_packets: Some(1),
+            ..KcpConfig::default()
+        };
+        let (mut kcp, _) = KcpSocket::new(
+            &config,
+            1,
+            receiver,
+            peer.local_addr().unwrap(),
+            StreamMode::Message,
+            Box::new(ScreamCongestionControl::new()),
+        )
+        .unwrap();
+
+        let padding = |sn: u32| {
+            let mut padding = Vec::new();
+            padding.put_u32_le(super::PADDING_HEADER);
+            padding.put_u32_le(super::DATAGRAM_SN_FLAG | sn);
+            padding
+        };
+        let mut buf = [0u8; 2048];
+        let mut received = || {
+            let mut packets = Vec::new();
+            while let Ok(n) = peer.try_recv(&mut buf) {
+                packets.push(buf[..n].to_vec());
+            }
+            packets
+        };
+
+        // Our token goes out, the feedback waits for the peer's
+        kcp.input_packet(&padding(0), EcnCodepoint::NotEct).unwrap();
+        kcp.update().unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        let packets = received();
+        assert_eq!(packets.len(), 1);
+        let mut announcement = &packets[0][..];
+        assert_eq!(announcement.get_u32_le(), FEEDBACK_TOKEN_HEADER);
+        let token = announcement.get_u64_le();
+        assert_eq!((announcement.get_u64_le(), announcement.get_u8()), (0, 0));
+
+        // The peer echoes it along with its own and needs no answer
+        let mut answer = Vec::new();
+        answer.put_u32_le(FEEDBACK_TOKEN_HEADER);
+        answer.put_u64_le(42);
+        answer.put_u64_le(token);
+        answer.put_u8(1);
+        kcp.input_packet(&answer, EcnCodepoint::NotEct).unwrap();
+        kcp.input_packet(&padding(1), EcnCodepoint::NotEct).unwrap();
+        kcp.update().unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        let packets = received();
+        assert_eq!(packets.len(), 1);
+        let mut feedback = &packets[0][..];
+        assert_eq!(feedback.get_u32_le(), crate::scream::SCREAM_FEEDBACK_HEADER);
+        assert_eq!(feedback.get_u64_le(), 42);
+
+        // Confirmed, nothing is announced anymore
+        time::sleep(config.handshake_retry_interval).await;
+        kcp.update().unwrap();
+        time::sleep(Duration::from_millis(50)).await;
+        assert!(received().is_empty());
+    }
+
+    #[tokio::test]
+    async fn unknown_packets_dropped() {
+        use bytes::BufMut;
+
+        let socket = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer_addr = "127.0.0.1:9".parse().unwrap();
+        let config = KcpConfig::default();
+
+        // A conv from the magic range would be taken for control packets
+        let reserved = KcpSocket::new(
+            &config,
+            crate::scream::SCREAM_FEEDBACK_HEADER,
+            socket.clone(),
+            peer_addr,
+            StreamMode::Message,
+            Box::new(ScreamCongestionControl::new()),
+        );
+        assert!(matches!(reserved, Err(KcpError::InvalidConfig(..))));
+
+        let (mut kcp, _) = KcpSocket::new(
+            &config,
+            1,
+            socket,
+            peer_addr,
+            StreamMode::Message,
+            Box::new(ScreamCongestionControl::new()),
+        )
+        .unwrap();
+
+        // A packet type from a newer peer is counted, not fed to KCP
+        let mut packet = Vec::new();
+        packet.put_u32_le(0x5C4D_FFFF);
+        packet.extend_from_slice(&[0u8; 20]);
+        assert!(!kcp.input_packet(&packet, EcnCodepoint::NotEct).unwrap());
+        // Known type, but truncated
+        assert!(!kcp.input_packet(&crate::fec::FEC_DATA_HEADER.to_le_bytes(), EcnCodepoint::NotEct).unwrap());
+        assert_eq!(kcp.stats().unknown_packets, 2);
+        assert_eq!(kcp.stats().bytes_received, 28);
+    }
+
+    #[test]
+    fn urgent_packets() {
+        use bytes::BufMut;
+
+        fn segment(cmd: u8, data: &[u8]) -> Vec<u8> {
+            let mut buf = Vec::new();
+            buf.put_u32_le(1);
+            buf.put_u8(cmd);
+            buf.put_u8(0);
+            buf.put_u16_le(256);
+            buf.put_u32_le(0);
+            buf.put_u32_le(0);
+            buf.put_u32_le(0);
+            buf.put_u32_le(data.len() as u32);
+            buf.extend_from_slice(data);
+            buf
+        }
+
+        let ack = segment(82, &[]);
+        let push = segment(81, b"data");
+        assert!(super::is_urgent_packet(&ack));
+        assert!(super::is_urgent_packet(&[ack.clone(), ack.clone()].concat()));
+        assert!(!super::is_urgent_packet(&push));
+        assert!(!super::is_urgent_packet(&[ack.clone(), push].concat()));
+        assert!(!super::is_urgent_packet(&ack[..20]));
+
+        let mut feedback = Vec::new();
+        feedback.put_u32_le(crate::scream::SCREAM_FEEDBACK_HEADER);
+        feedback.put_u32_le(0);
+        assert!(super::is_urgent_packet(&feedback));
+        let mut datagram = Vec::new();
+        datagram.put_u32_le(super::DATAGRAM_HEADER);
+        datagram.put_u32_le(0);
+        assert!(!super::is_urgent_packet(&datagram));
+    }
+
+    #[tokio::test]
+    async fn staged_write_beyond_shrunk_mss() {
+        use std::{
+            io::IoSlice,
+            task::{Context, Poll, Waker},
+        };
+
+        use kcp::KCP_MAX_FRAGMENTS;
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let peer = udp.local_addr().unwrap();
+        let mut cx = Context::from_waker(Waker::noop());
+        for mode in [StreamMode::Message, StreamMode::ByteStream] {
+            let config = KcpConfig::default();
+            let (mut kcp, _) = KcpSocket::new(
+                &config,
+                1,
+                udp.clone(),
+                peer,
+                mode,
+                Box::new(ScreamCongestionControl::new()),
+            )
+            .unwrap();
+            let (recv_half, send_half) = kcp.halves();
+
+            // Staged at the largest length the MSS of the time allows, the MSS shrinks before it is drained
+            let mss = kcp.kcp.mss();
+            send_half.publish(true, 2 * KCP_MAX_FRAGMENTS, mss);
+            let message = vec![0u8; KCP_MAX_FRAGMENTS * mss];
+            for buf in [&message[..], b"AFTER"] {
+                assert!(send_half.poll_stage(&mut cx, &[IoSlice::new(buf)], None).unwrap().is_ready());
+            }
+            kcp.kcp.set_mtu(config.mtu - 100).unwrap();
+            kcp.flush().unwrap();
+
+            let mut buf = [0u8; 16];
+            if mode == StreamMode::ByteStream {
+                // Cut to the fragment limit, nothing is lost
+                assert!(kcp.recv_end().is_none());
+                assert!(send_half.take_staged().is_empty());
+                assert!(kcp.kcp.wait_snd() > KCP_MAX_FRAGMENTS);
+            } else {
+                // Both writes stay staged, writers and readers learn the session lost them
+                assert!(matches!(kcp.poll_send(&mut cx, b"NEXT"), Poll::Ready(Err(..))));
+                assert!(matches!(recv_half.poll_recv(&mut cx, &mut buf), Poll::Ready(Err(..))));
+                assert!(send_half.poll_stage(&mut cx, &[IoSlice::new(b"MORE")], None).is_none());
+                assert_eq!(send_half.take_staged().len(), 2);
+            }
+        }
+    }
 }
@@ -0,0 +1,139 @@
+//! Socket options from `KcpConfig`
+//!
+//! DSCP, TTL and the kernel buffer sizes are set on the `UdpSocket` of listeners and streams. The DSCP
+//! shares the TOS / traffic class byte with the ECN bits, both are written together.
+
+use std::io;
+
+use tokio::net::UdpSocket;
+
+use crate::config::KcpConfig;
+
+/// Expedited Forwarding (RFC 3246), the usual DSCP of interactive media
+pub const DSCP_EF: u8 = 46;
+
+/// Set the DSCP, TTL and buffer sizes of `config` on `socket`, after ECN was enabled
+#[cfg(unix)]
+pub fn apply_socket_options(socket: &UdpSocket, config: &KcpConfig) -> io::Result<()> {
+    use std::{net::SocketAddr, os::unix::io::AsRawFd};
+
+    use crate::ecn::{sys, EcnCodepoint};
+
+    let fd = socket.as_raw_fd();
+    let v6 = matches!(socket.local_addr()?, SocketAddr::V6(..));
+
+    if let Some(dscp) = config.dscp {
+        let ecn = if config.ecn {
+            EcnCodepoint::Ect1
+        } else {
+            EcnCodepoint::NotEct
+        };
+        let tos = ((dscp as libc::c_int) << 2) | ecn as libc::c_int;
+        if v6 {
+            sys::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_TCLASS, tos)?;
+            // Dual-stack sockets also carry IPv4 traffic
+            let _ = sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, tos);
+        } else {
+            sys::setsockopt(fd, libc::IPPROTO_IP, libc::IP_TOS, tos)?;
+        }
+    }
+
+    if let Some(ttl) = config.ttl {
+        if v6 {
+            sys::setsockopt(fd, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS, ttl as libc::c_int)?;
+            let _ = socket.set_ttl(ttl);
+        } else {
+            socket.set_ttl(ttl)?;
+        }
+    }
+
+    // The kernel caps both at net.core.{r,w}mem_max
+    if let Some(size) = config.socket_recv_buffer {
+        sys::setsockopt(fd, libc::SOL_SOCKET, libc::SO_RCVBUF, clamp_buffer_size(size))?;
+    }
+    if let Some(size) = config.socket_send_buffer {
+        sys::setsockopt(fd, libc::SOL_SOCKET, libc::SO_SNDBUF, clamp_buffer_size(size))?;
+    }
+
+    Ok(())
+}
+
+/// Set the DSCP, TTL and buffer sizes of `config` on `socket`, after ECN was enabled
+#[cfg(not(unix))]
+pub fn apply_socket_options(socket: &UdpSocket, config: &KcpConfig) -> io::Result<()> {
+    if config.dscp.is_some() || config.socket_recv_buffer.is_some() || config.socket_send_buffer.is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "DSCP and socket buffer sizes are only supported on unix",
+        ));
+    }
+    if let Some(ttl) = config.ttl {
+        socket.set_ttl(ttl)?;
+    }
+    Ok(())
+}
+
+#[cfg(unix)]
+fn clamp_buffer_size(size: usize) -> libc::c_int {
+    size.min(libc::c_int::MAX as usize) as libc::c_int
+}
+
+#[cfg(all(test, unix))]
+mod test {
+    use std::{mem, os::unix::io::AsRawFd};
+
+    use super::*;
+
+    fn getsockopt(socket: &UdpSocket, level: libc::c_int, name: libc::c_int) -> libc::c_int {
+        let mut value: libc::c_int = 0;
+        let mut len = mem::size_of::<libc::c_int>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                socket.as_raw_fd(),
+                level,
+                name,
+                &mut value as *mut libc::c_int as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        assert_eq!(ret, 0, "{}", io::Error::last_os_error());
+        value
+    }
+
+    #[tokio::test]
+    async fn socket_options_applied() {
+        let socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let default_rcvbuf = getsockopt(&socket, libc::SOL_SOCKET, libc::SO_RCVBUF);
+
+        let config = KcpConfig {
+            ecn: true,
+            dscp: Some(DSCP_EF),
+            ttl: Some(32),
+            socket_recv_buffer: Some(default_rcvbuf as usize / 4),
+            socket_send_buffer: Some(16 * 1024),
+            ..Default::default()
+        };
+        apply_socket_options(&socket, &config).unwrap();
+
+        assert_eq!(getsockopt(&socket, libc::IPPROTO_IP, libc::IP_TOS), (46 << 2) | 0b01);
+        assert_eq!(socket.ttl().unwrap(), 32);
+        // Linux doubles the sizes for its bookkeeping
+        assert!(getsockopt(&socket, libc::SOL_SOCKET, libc::SO_RCVBUF) < default_rcvbuf);
+        assert!(getsockopt(&socket, libc::SOL_SOCKET, libc::SO_SNDBUF) >= 16 * 1024);
+
+        // Nothing configured, nothing touched
+        let socket = UdpSocket::bind("[::1]:0").await.unwrap();
+        let ttl = getsockopt(&socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS);
+        apply_socket_options(&socket, &KcpConfig::default()).unwrap();
+        assert_eq!(getsockopt(&socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS), ttl);
+
+        let config = KcpConfig {
+            ttl: Some(7),
+            dscp: Some(DSCP_EF),
+            ..Default::default()
+        };
+        apply_socket_options(&socket, &config).unwrap();
+        assert_eq!(getsockopt(&socket, libc::IPPROTO_IPV6, libc::IPV6_UNICAST_HOPS), 7);
+        assert_eq!(getsockopt(&socket, libc::IPPROTO_IPV6, libc::IPV6_TCLASS), 46 << 2);
+    }
+}
@@ -0,0 +1,301 @@
+//! Read and write halves of a `KcpStream`
+//!
+//! Both halves share the session, reads and writes only contend on the socket lock.
+
+use std::{
+    fmt::{self, Debug},
+    io::{self, IoSlice},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use bytes::Bytes;
+use futures_util::{future, ready};
+use kcp::KcpResult;
+use tokio::{
+    io::{AsyncRead, AsyncWrite, ReadBuf},
+    sync::watch,
+};
+
+use crate::{
+    congestion::CongestionState,
+    pacer::PacerState,
+    session::KcpSession,
+    stats::ThroughputSample,
+    stream::{self, RecvBuffer, StreamSession},
+};
+
+/// Borrowed read half of a `KcpStream`, created by `KcpStream::split`
+pub struct ReadHalf<'a> {
+    pub(crate) session: &'a StreamSession,
+    pub(crate) recv_buffer: &'a mut RecvBuffer,
+}
+
+/// Borrowed write half of a `KcpStream`, created by `KcpStream::split`
+pub struct WriteHalf<'a> {
+    pub(crate) session: &'a StreamSession,
+    pub(crate) target_bitrate_rx: &'a watch::Receiver<f32>,
+}
+
+/// Owned read half of a `KcpStream`, created by `KcpStream::into_split`
+pub struct KcpReadHalf {
+    pub(crate) session: Arc<StreamSession>,
+    pub(crate) recv_buffer: RecvBuffer,
+}
+
+/// Owned write half of a `KcpStream`, created by `KcpStream::into_split`
+pub struct KcpWriteHalf {
+    pub(crate) session: Arc<StreamSession>,
+    pub(crate) target_bitrate_rx: watch::Receiver<f32>,
+}
+
+impl Debug for ReadHalf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ReadHalf").field("recv_buffer", &self.recv_buffer).finish()
+    }
+}
+
+impl Debug for WriteHalf<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WriteHalf").finish()
+    }
+}
+
+impl Debug for KcpReadHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KcpReadHalf").field("recv_buffer", &self.recv_buffer).finish()
+    }
+}
+
+impl Debug for KcpWriteHalf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KcpWriteHalf").finish()
+    }
+}
+
+impl ReadHalf<'_> {
+    /// `recv` data into `buf`
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.recv_buffer.poll_recv(self.session, cx, buf)
+    }
+
+    /// `recv` data into `buf`
+    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_recv(cx, buf)).await
+    }
+
+    /// Copy the data the next `recv` would return into `buf` without consuming it, see `KcpStream::poll_peek`
+    pub fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.recv_buffer.poll_peek(self.session, cx, buf)
+    }
+
+    /// Copy the data the next `recv` would return into `buf` without consuming it
+    pub async fn peek(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_peek(cx, buf)).await
+    }
+
+    /// Receive the next message whole, see `KcpStream::poll_recv_msg`
+    pub fn poll_recv_msg(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Bytes>> {
+        self.recv_buffer.poll_recv_msg(self.session, cx)
+    }
+
+    /// Receive the next message whole
+    pub async fn recv_msg(&mut self) -> KcpResult<Bytes> {
+        future::poll_fn(|cx| self.poll_recv_msg(cx)).await
+    }
+}
+
+impl WriteHalf<'_> {
+    /// `send` data in `buf`
+    pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        stream::poll_send(self.session, cx, buf)
+    }
+
+    /// `send` data in `buf`
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+    }
+
+    /// Send `msg` as one message, see `KcpStream::poll_send_msg`
+    pub fn poll_send_msg(&mut self, cx: &mut Context<'_>, msg: &[u8]) -> Poll<KcpResult<()>> {
+        stream::poll_send_msg(self.session, cx, msg)
+    }
+
+    /// Send `msg` as one message
+    pub async fn send_msg(&mut self, msg: Bytes) -> KcpResult<()> {
+        future::poll_fn(|cx| self.poll_send_msg(cx, &msg)).await
+    }
+
+    pub fn get_target_bitrate_receiver(&self) -> watch::Receiver<f32> {
+        self.target_bitrate_rx.clone()
+    }
+
+    /// Watch of the full congestion state, a superset of the target bitrate
+    pub fn congestion_state_receiver(&self) -> watch::Receiver<CongestionState> {
+        self.session.kcp_socket().lock().congestion_state_receiver()
+    }
+
+    /// Watch of the pacer queue, shows whether the pacing rate keeps up with KCP's output
+    pub fn pacer_state_receiver(&self) -> watch::Receiver<PacerState> {
+        self.session.kcp_socket().lock().pacer_state_receiver()
+    }
+
+    /// Watch of the send, receive and retransmission rates, published once per second
+    pub fn throughput_receiver(&self) -> watch::Receiver<ThroughputSample> {
+        self.session.kcp_socket().lock().throughput_receiver()
+    }
+}
+
+impl KcpReadHalf {
+    /// `recv` data into `buf`
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.recv_buffer.poll_recv(&self.session, cx, buf)
+    }
+
+    /// `recv` data into `buf`
+    pub async fn recv(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_recv(cx, buf)).await
+    }
+
+    /// Copy the data the next `recv` would return into `buf` without consuming it, see `KcpStream::poll_peek`
+    pub fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.recv_buffer.poll_peek(&self.session, cx, buf)
+    }
+
+    /// Copy the data the next `recv` would return into `buf` without consuming it
+    pub async fn peek(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_peek(cx, buf)).await
+    }
+
+    /// Receive the next message whole, see `KcpStream::poll_recv_msg`
+    pub fn poll_recv_msg(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Bytes>> {
+        self.recv_buffer.poll_recv_msg(&self.session, cx)
+    }
+
+    /// Receive the next message whole
+    pub async fn recv_msg(&mut self) -> KcpResult<Bytes> {
+        future::poll_fn(|cx| self.poll_recv_msg(cx)).await
+    }
+
+    /// Get the `KcpSession` shared by both halves
+    pub fn session(&self) -> &KcpSession {
+        &self.session
+    }
+}
+
+impl KcpWriteHalf {
+    /// `send` data in `buf`
+    pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        stream::poll_send(&self.session, cx, buf)
+    }
+
+    /// `send` data in `buf`
+    pub async fn send(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_send(cx, buf)).await
+    }
+
+    /// Send `msg` as one message, see `KcpStream::poll_send_msg`
+    pub fn poll_send_msg(&mut self, cx: &mut Context<'_>, msg: &[u8]) -> Poll<KcpResult<()>> {
+        stream::poll_send_msg(&self.session, cx, msg)
+    }
+
+    /// Send `msg` as one message
+    pub async fn send_msg(&mut self, msg: Bytes) -> KcpResult<()> {
+        future::poll_fn(|cx| self.poll_send_msg(cx, &msg)).await
+    }
+
+    pub fn get_target_bitrate_receiver(&self) -> watch::Receiver<f32> {
+        self.target_bitrate_rx.clone()
+    }
+
+    /// Watch of the full congestion state, a superset of the target bitrate
+    pub fn congestion_state_receiver(&self) -> watch::Receiver<CongestionState> {
+        self.session.kcp_socket().lock().congestion_state_receiver()
+    }
+
+    /// Watch of the pacer queue, shows whether the pacing rate keeps up with KCP's output
+    pub fn pacer_state_receiver(&self) -> watch::Receiver<PacerState> {
+        self.session.kcp_socket().lock().pacer_state_receiver()
+    }
+
+    /// Watch of the send, receive and retransmission rates, published once per second
+    pub fn throughput_receiver(&self) -> watch::Receiver<ThroughputSample> {
+        self.session.kcp_socket().lock().throughput_receiver()
+    }
+
+    /// Get the `KcpSession` shared by both halves
+    pub fn session(&self) -> &KcpSession {
+        &self.session
+    }
+}
+
+impl AsyncRead for ReadHalf<'_> {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let result = ready!(self.poll_recv(cx, buf.initialize_unfilled()));
+        stream::poll_read_result(result, buf)
+    }
+}
+
+impl AsyncRead for KcpReadHalf {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let result = ready!(self.poll_recv(cx, buf.initialize_unfilled()));
+        stream::poll_read_result(result, buf)
+    }
+}
+
+impl AsyncWrite for WriteHalf<'_> {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = ready!(self.poll_send(cx, buf));
+        stream::poll_write_result(result)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = ready!(stream::poll_send_vectored(self.session, cx, bufs));
+        stream::poll_write_result(result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        stream::poll_flush(self.session, cx).map_err(Into::into)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        stream::poll_shutdown(self.session, cx).map_err(Into::into)
+    }
+}
+
+impl AsyncWrite for KcpWriteHalf {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = ready!(self.poll_send(cx, buf));
+        stream::poll_write_result(result)
+    }
+
+    fn poll_write_vectored(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = ready!(stream::poll_send_vectored(&self.session, cx, bufs));
+        stream::poll_write_result(result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        stream::poll_flush(&self.session, cx).map_err(Into::into)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        stream::poll_shutdown(&self.session, cx).map_err(Into::into)
+    }
+}
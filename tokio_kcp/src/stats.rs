@@ -0,0 +1,85 @@
+//! Per-connection statistics
+
+use std::time::Duration;
+
+/// Throughput of a connection over about a second, published by `KcpStream::throughput_receiver`
+///
+/// Rates count whole datagrams, headers included.
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct ThroughputSample {
+    /// Rate of all datagrams sent (bps), including retransmissions
+    pub sent_bps: f32,
+    /// Rate of all datagrams received (bps)
+    pub recv_bps: f32,
+    /// Rate of retransmitted segments (bps), part of `sent_bps`
+    pub retransmit_bps: f32,
+    /// Time the rates are averaged over
+    pub interval: Duration,
+}
+
+/// Snapshot of a connection, returned by `KcpStream::stats`
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct KcpStats {
+    /// Smoothed RTT measured by the congestion controller
+    pub srtt: Duration,
+    /// RTT variation
+    pub rttvar: Duration,
+    /// Minimum RTT over the base RTT window
+    pub base_rtt: Duration,
+    /// Queuing delay of the last RTT sample
+    pub qdelay: Duration,
+    /// Congestion window (bytes)
+    pub ref_wnd: f32,
+    /// Bytes sent and neither acknowledged nor lost
+    pub bytes_in_flight: u32,
+    /// Segments retransmitted after their RTO expired
+    pub retransmits: u32,
+    /// Segments retransmitted before their RTO, after enough later ones were acknowledged
+    pub fast_retransmits: u32,
+    /// Segments received ahead of a gap
+    pub out_of_order: u32,
+    /// ACKs of segments acknowledged before
+    pub duplicate_acks: u32,
+    /// Segments reported lost to the congestion controller
+    pub packets_lost: u64,
+    /// Fraction of bytes lost during the last RTT
+    pub loss_rate: f32,
+    /// Bytes retransmitted after their RTO during the last RTT
+    pub retransmitted_bytes: u32,
+    /// Retransmissions during the last RTT whose original was acknowledged after all
+    pub spurious_retransmits: u32,
+    /// Segments dropped unacknowledged at their send deadline
+    pub segments_expired: u64,
+    /// Packets the congestion controller stopped tracking because their feedback never arrived
+    pub orphaned_packets: u64,
+    /// Segments queued or waiting for an ACK
+    pub wait_snd: usize,
+    /// Send window (segments)
+    pub snd_wnd: u16,
+    /// Receive window (segments), shrinks with unread data under `KcpConfig::max_recv_buffer_bytes`
+    pub rcv_wnd: u16,
+    /// Bytes received but not yet read
+    pub rcv_buffered: usize,
+    /// Remote receive window (segments)
+    pub rmt_wnd: u16,
+    /// MTU in use, may change with path MTU discovery
+    pub mtu: usize,
+    /// Current pacing rate (bps)
+    pub pacing_rate: f32,
+    /// Current target bitrate (bps)
+    pub target_bitrate: f32,
+    /// Packets waiting for the pacer
+    pub pacer_queued: usize,
+    /// Packets dropped because the pacer queue was full
+    pub pacer_dropped: u64,
+    /// Padding bytes sent to probe for spare capacity
+    pub padding_sent: u64,
+    /// Bytes of all datagrams sent, including headers and retransmissions
+    pub bytes_sent: u64,
+    /// Bytes of all datagrams received, without the encryption overhead
+    pub bytes_received: u64,
+    /// Sealed datagrams dropped as duplicates or stale by the replay window
+    pub replays_dropped: u64,
+    /// Datagrams dropped because their header names a packet type this version doesn't know
+    pub unknown_packets: u64,
+}
@@ -1,52 +1,342 @@
 use std::{
     fmt::{self, Debug},
-    io::{self, ErrorKind},
+    future::Future,
+    io::{self, IoSlice},
     net::{IpAddr, SocketAddr},
-    pin::Pin,
+    ops::Deref,
+    pin::{pin, Pin},
     sync::Arc,
     task::{Context, Poll},
+    time::Duration,
 };
 
-use futures_util::{future, ready};
+use bytes::Bytes;
+use futures_util::{future, ready, stream::FuturesUnordered, StreamExt};
 use kcp::{Error as KcpError, KcpResult};
-use log::trace;
 use tokio::{
     io::{AsyncRead, AsyncWrite, ReadBuf},
-    net::UdpSocket, sync::watch,
+    net::{self, ToSocketAddrs, UdpSocket}, sync::watch, time,
 };
+use tracing::trace;
 
-use crate::{config::KcpConfig, session::KcpSession, skcp::KcpSocket};
+use crate::{
+    config::{KcpConfig, KcpRuntimeConfig, StreamMode},
+    congestion::{CongestionController, CongestionState},
+    ecn,
+    event::{EventHandler, KcpEvent},
+    framed::KcpFramed,
+    pacer::PacerState,
+    packet,
+    pmtud,
+    session::KcpSession,
+    skcp::KcpSocket,
+    sockopt,
+    split::{KcpReadHalf, KcpWriteHalf, ReadHalf, WriteHalf},
+    stats::{KcpStats, ThroughputSample},
+    transport::DatagramTransport,
+};
 
-pub struct KcpStream {
-    session: Arc<KcpSession>,
-    target_bitrate_rx: watch::Receiver<f32>,
-    recv_buffer: Vec<u8>,
-    recv_buffer_pos: usize,
-    recv_buffer_cap: usize,
+// Head start of every happy eyeballs attempt over the next one
+const CONNECTION_ATTEMPT_DELAY: Duration = Duration::from_millis(250);
+
+fn no_addresses() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, "no addresses to connect to")
+}
+
+// Addresses alternating between IPv6 and IPv4, starting with IPv6
+fn happy_eyeballs_order(addrs: impl Iterator<Item = SocketAddr>) -> Vec<SocketAddr> {
+    let (v6, v4): (Vec<_>, Vec<_>) = addrs.partition(SocketAddr::is_ipv6);
+    let mut ordered = Vec::with_capacity(v6.len() + v4.len());
+    let (mut v6, mut v4) = (v6.into_iter(), v4.into_iter());
+    loop {
+        match (v6.next(), v4.next()) {
+            (None, None) => return ordered,
+            (a, b) => ordered.extend(a.into_iter().chain(b)),
+        }
+    }
 }
 
-impl Drop for KcpStream {
+/// Forgets the probe of `KcpStream::ping` once nobody waits for its answer, so a dropped ping doesn't
+/// keep the session probing
+struct PingGuard<'a>(&'a KcpSession);
+
+impl Drop for PingGuard<'_> {
+    fn drop(&mut self) {
+        self.0.kcp_socket().lock().cancel_ping();
+    }
+}
+
+/// Closes the session when the stream and all of its halves are dropped
+pub(crate) struct StreamSession(Arc<KcpSession>);
+
+impl Drop for StreamSession {
     fn drop(&mut self) {
-        self.session.close();
+        self.0.close();
+    }
+}
+
+impl Deref for StreamSession {
+    type Target = KcpSession;
+
+    fn deref(&self) -> &KcpSession {
+        &self.0
+    }
+}
+
+/// Data received from KCP that didn't fit into the user's buffer
+#[derive(Default)]
+pub(crate) struct RecvBuffer {
+    buffer: Vec<u8>,
+    pos: usize,
+    cap: usize,
+}
+
+impl Debug for RecvBuffer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("RecvBuffer")
+            .field("buffer.len", &self.buffer.len())
+            .field("pos", &self.pos)
+            .field("cap", &self.cap)
+            .finish()
+    }
+}
+
+impl RecvBuffer {
+    /// `recv` data from `session` into `buf`
+    pub(crate) fn poll_recv(
+        &mut self,
+        session: &KcpSession,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<KcpResult<usize>> {
+        ready!(poll_budget(cx));
+        loop {
+            // Consumes all data in buffer
+            if self.pos < self.cap {
+                let remaining = self.cap - self.pos;
+                let copy_length = remaining.min(buf.len());
+
+                buf[..copy_length].copy_from_slice(&self.buffer[self.pos..self.pos + copy_length]);
+                self.pos += copy_length;
+                return Ok(copy_length).into();
+            }
+
+            // Try to read from KCP
+            // 1. Read directly with user provided `buf`
+            let peek_size = session.peek_size().unwrap_or(0);
+
+            // 1.1. User's provided buffer is larger than available buffer's size
+            if peek_size > 0 && peek_size <= buf.len() {
+                match ready!(session.poll_recv(cx, buf)) {
+                    Ok(n) => {
+                        trace!("[CLIENT] recv directly {} bytes", n);
+                        return Ok(n).into();
+                    }
+                    Err(KcpError::UserBufTooSmall) => {}
+                    Err(err) => return Err(err).into(),
+                }
+            }
+
+            // 2. User `buf` too small, read to recv_buffer
+            let required_size = peek_size;
+            if self.buffer.len() < required_size {
+                self.buffer.resize(required_size, 0);
+            }
+
+            match ready!(session.poll_recv(cx, &mut self.buffer)) {
+                Ok(0) => return Ok(0).into(),
+                Ok(n) => {
+                    trace!("[CLIENT] recv buffered {} bytes", n);
+                    self.pos = 0;
+                    self.cap = n;
+                }
+                Err(err) => return Err(err).into(),
+            }
+        }
+    }
+
+    /// Receive the next message from `session` whole, or what is left of one `poll_recv` read in part
+    ///
+    /// `None` at EOF, unlike an empty message.
+    pub(crate) fn poll_next_msg(
+        &mut self,
+        session: &KcpSession,
+        cx: &mut Context<'_>,
+    ) -> Poll<KcpResult<Option<Bytes>>> {
+        ready!(poll_budget(cx));
+        if self.pos < self.cap {
+            let rest = Bytes::copy_from_slice(&self.buffer[self.pos..self.cap]);
+            self.pos = self.cap;
+            return Ok(Some(rest)).into();
+        }
+
+        let result = ready!(session.poll_recv_msg(cx));
+        Ok(result?.map(Bytes::from)).into()
+    }
+
+    /// `poll_next_msg`, empty at EOF
+    pub(crate) fn poll_recv_msg(&mut self, session: &KcpSession, cx: &mut Context<'_>) -> Poll<KcpResult<Bytes>> {
+        self.poll_next_msg(session, cx).map_ok(Option::unwrap_or_default)
+    }
+
+    /// Copy the next data `poll_recv` would return into `buf` without consuming it
+    pub(crate) fn poll_peek(
+        &mut self,
+        session: &KcpSession,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<KcpResult<usize>> {
+        // The rest of a message read only in part comes first
+        if self.pos < self.cap {
+            let copy_length = (self.cap - self.pos).min(buf.len());
+            buf[..copy_length].copy_from_slice(&self.buffer[self.pos..self.pos + copy_length]);
+            return Ok(copy_length).into();
+        }
+
+        session.poll_peek(cx, buf)
+    }
+}
+
+/// `send` data in `buf` through `session`
+pub(crate) fn poll_send(session: &KcpSession, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+    poll_send_with_deadline(session, cx, buf, None)
+}
+
+/// Send `buf` through `session`, retransmissions stop once `deadline` passed
+pub(crate) fn poll_send_with_deadline(
+    session: &KcpSession,
+    cx: &mut Context<'_>,
+    buf: &[u8],
+    deadline: Option<Duration>,
+) -> Poll<KcpResult<usize>> {
+    ready!(poll_budget(cx));
+    session.poll_send(cx, &[IoSlice::new(buf)], deadline)
+}
+
+/// Send `msg` through `session` as one message
+pub(crate) fn poll_send_msg(session: &KcpSession, cx: &mut Context<'_>, msg: &[u8]) -> Poll<KcpResult<()>> {
+    ready!(poll_budget(cx));
+    session.poll_send_msg(cx, msg)
+}
+
+/// Send the concatenation of `bufs` through `session` as one message
+pub(crate) fn poll_send_vectored(
+    session: &KcpSession,
+    cx: &mut Context<'_>,
+    bufs: &[IoSlice<'_>],
+) -> Poll<KcpResult<usize>> {
+    ready!(poll_budget(cx));
+    session.poll_send(cx, bufs, None)
+}
+
+/// Flush KCP's send queue of `session`, ready once the pacer handed every packet to the transport
+pub(crate) fn poll_flush(session: &KcpSession, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+    // Mutex doesn't have poll_lock, spinning on it.
+    let mut kcp = session.kcp_socket().lock();
+    let result = kcp.poll_flush(cx);
+    session.notify();
+    result
+}
+
+/// Take a unit of the task's coop budget, a peer that always has data ready can't starve the worker
+pub(crate) fn poll_budget(cx: &mut Context<'_>) -> Poll<()> {
+    pin!(tokio::task::coop::consume_budget()).poll(cx)
+}
+
+/// Half-close `session`, the FIN follows the pending data and reading goes on until the peer closes
+pub(crate) fn poll_shutdown(session: &KcpSession, cx: &mut Context<'_>) -> Poll<KcpResult<()>> {
+    // Mutex doesn't have poll_lock, spinning on it.
+    let mut kcp = session.kcp_socket().lock();
+    let result = kcp.poll_shutdown(cx);
+    session.notify();
+    result
+}
+
+/// Convert a `recv` result for `AsyncRead`
+pub(crate) fn poll_read_result(result: KcpResult<usize>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+    match result {
+        Ok(n) => {
+            buf.advance(n);
+            Ok(()).into()
+        }
+        Err(err) => Err(err.into()).into(),
+    }
+}
+
+/// Convert a `send` result for `AsyncWrite`
+pub(crate) fn poll_write_result(result: KcpResult<usize>) -> Poll<io::Result<usize>> {
+    match result {
+        Ok(n) => Ok(n).into(),
+        Err(err) => Err(err.into()).into(),
     }
 }
 
+/// A KCP session to one peer
+///
+/// In message mode, the default, every `send_msg` or `send` arrives as one message and `recv_msg` returns it
+/// whole, so datagram protocols need no framing of their own. With `KcpConfig::stream_mode` set to
+/// `StreamMode::ByteStream` sends are merged into a byte stream like TCP and only `recv`/`AsyncRead` make sense,
+/// `stream_mode` tells which mode is in effect.
+/// `KcpConfig::passthrough` keeps the messages but sends them as unreliable datagrams, see `send_unreliable`.
+///
+/// As `AsyncWrite`, `flush` returns once the pacer handed every packet to the socket and `shutdown` once the
+/// peer acknowledged the FIN. Reads and writes take from tokio's coop budget.
+pub struct KcpStream {
+    pub(crate) session: Arc<StreamSession>,
+    target_bitrate_rx: watch::Receiver<f32>,
+    recv_buffer: RecvBuffer,
+}
+
 impl Debug for KcpStream {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("KcpStream")
-            .field("session", self.session.as_ref())
-            .field("recv_buffer.len", &self.recv_buffer.len())
-            .field("recv_buffer_pos", &self.recv_buffer_pos)
-            .field("recv_buffer_cap", &self.recv_buffer_cap)
+            .field("session", self.session.0.as_ref())
+            .field("recv_buffer", &self.recv_buffer)
             .finish()
     }
 }
 
 impl KcpStream {
-    /// Create a `KcpStream` connecting to `addr`
+    /// Create a `KcpStream` connecting to `addr`, host names are resolved
+    ///
+    /// Connects to the first resolved address right away, without waiting for the peer. `connect_timeout`
+    /// tries all of them.
+    ///
+    /// NOTE: `conv` will be randomly generated
+    pub async fn connect<A: ToSocketAddrs>(config: &KcpConfig, addr: A) -> KcpResult<KcpStream> {
+        match net::lookup_host(addr).await?.next() {
+            Some(addr) => KcpStream::connect_addr(config, addr).await,
+            None => Err(no_addresses().into()),
+        }
+    }
+
+    /// Create a `KcpStream` connecting to `addr` once the peer answered, failing with `HandshakeTimeout` after `timeout`
+    ///
+    /// The peer is asked every `handshake_retry_interval`, up to `handshake_max_attempts` times. Several
+    /// resolved addresses are raced happy eyeballs style (RFC 8305): IPv6 first, alternating families, the
+    /// next attempt starting every 250 ms or as soon as one fails. The first to finish the handshake wins.
     ///
     /// NOTE: `conv` will be randomly generated
-    pub async fn connect(config: &KcpConfig, addr: SocketAddr) -> KcpResult<KcpStream> {
+    pub async fn connect_timeout<A: ToSocketAddrs>(
+        config: &KcpConfig,
+        addr: A,
+        timeout: Duration,
+    ) -> KcpResult<KcpStream> {
+        let connect = async {
+            let addrs = happy_eyeballs_order(net::lookup_host(addr).await?);
+            match addrs[..] {
+                [] => Err(no_addresses().into()),
+                [addr] => KcpStream::connect_addr(config, addr).await?.handshake().await,
+                _ => KcpStream::connect_racing(config, addrs).await,
+            }
+        };
+        match time::timeout(timeout, connect).await {
+            Ok(result) => result,
+            Err(..) => Err(KcpError::HandshakeTimeout),
+        }
+    }
+
+    async fn connect_addr(config: &KcpConfig, addr: SocketAddr) -> KcpResult<KcpStream> {
         let udp = match addr.ip() {
             IpAddr::V4(..) => UdpSocket::bind("0.0.0.0:0").await?,
             IpAddr::V6(..) => UdpSocket::bind("[::]:0").await?,
@@ -55,6 +345,67 @@ impl KcpStream {
         KcpStream::connect_with_socket(config, udp, addr).await
     }
 
+    // Handshakes with `addrs` in order, started `CONNECTION_ATTEMPT_DELAY` apart
+    async fn connect_racing(config: &KcpConfig, addrs: Vec<SocketAddr>) -> KcpResult<KcpStream> {
+        let mut addrs = addrs.into_iter();
+        let mut attempts = FuturesUnordered::new();
+        let mut last_err = None;
+        let next_attempt = time::sleep(Duration::ZERO);
+        tokio::pin!(next_attempt);
+
+        loop {
+            tokio::select! {
+                _ = &mut next_attempt, if addrs.len() > 0 => {
+                    let addr = addrs.next().unwrap();
+                    trace!("[CONNECT] trying {}", addr);
+                    attempts.push(async move { KcpStream::connect_addr(config, addr).await?.handshake().await });
+                    next_attempt.as_mut().reset(time::Instant::now() + CONNECTION_ATTEMPT_DELAY);
+                }
+                Some(result) = attempts.next(), if !attempts.is_empty() => match result {
+                    Ok(stream) => return Ok(stream),
+                    Err(err) => {
+                        trace!("[CONNECT] attempt failed, error: {}", err);
+                        last_err = Some(err);
+                        next_attempt.as_mut().reset(time::Instant::now());
+                    }
+                },
+                else => return Err(last_err.unwrap_or(KcpError::HandshakeTimeout)),
+            }
+        }
+    }
+
+    // Ready once the peer answered, `HandshakeTimeout` after `handshake_max_attempts` went unanswered
+    async fn handshake(self) -> KcpResult<KcpStream> {
+        future::poll_fn(|cx| {
+            let mut kcp = self.session.kcp_socket().lock();
+            let result = kcp.poll_handshake(cx);
+            self.session.notify();
+            result
+        })
+        .await?;
+        Ok(self)
+    }
+
+    /// Create a `KcpStream` connecting to `addr`, driven by `controller` instead of SCReAM
+    ///
+    /// NOTE: `conv` will be randomly generated
+    pub async fn connect_with_controller(
+        config: &KcpConfig,
+        addr: SocketAddr,
+        controller: Box<dyn CongestionController>,
+    ) -> KcpResult<KcpStream> {
+        let udp = match addr.ip() {
+            IpAddr::V4(..) => UdpSocket::bind("0.0.0.0:0").await?,
+            IpAddr::V6(..) => UdpSocket::bind("[::]:0").await?,
+        };
+
+        let mut conv = rand::random();
+        while conv == 0 || packet::is_reserved(conv) {
+            conv = rand::random();
+        }
+        KcpStream::connect_with_socket_conv_controller(config, conv, udp, addr, controller).await
+    }
+
     /// Create a `KcpStream` connecting to `addr`
     ///
     /// `conv` is the conversation identifier, setting to `0` will let server to randomly generate one for you.
@@ -69,10 +420,16 @@ impl KcpStream {
 
     /// Create a `KcpStream` with an existed `UdpSocket` connecting to `addr`
     ///
+    /// The socket may be shared, datagrams from other peers are ignored.
+    ///
     /// NOTE: `conv` will be randomly generated
-    pub async fn connect_with_socket(config: &KcpConfig, udp: UdpSocket, addr: SocketAddr) -> KcpResult<KcpStream> {
+    pub async fn connect_with_socket(
+        config: &KcpConfig,
+        udp: impl Into<Arc<UdpSocket>>,
+        addr: SocketAddr,
+    ) -> KcpResult<KcpStream> {
         let mut conv = rand::random();
-        while conv == 0 {
+        while conv == 0 || packet::is_reserved(conv) {
             conv = rand::random();
         }
         KcpStream::connect_with_socket_conv(config, conv, udp, addr).await
@@ -84,13 +441,79 @@ impl KcpStream {
     pub async fn connect_with_socket_conv(
         config: &KcpConfig,
         conv: u32,
-        udp: UdpSocket,
+        udp: impl Into<Arc<UdpSocket>>,
+        addr: SocketAddr,
+    ) -> KcpResult<KcpStream> {
+        KcpStream::connect_with_socket_conv_controller(
+            config,
+            conv,
+            udp,
+            addr,
+            Box::new(config.scream_controller()?),
+        )
+        .await
+    }
+
+    /// Create a `KcpStream` with an existed `UdpSocket` connecting to `addr`, driven by `controller`
+    ///
+    /// `conv` is the conversation identifier, setting to `0` will let server to randomly generate one for you.
+    pub async fn connect_with_socket_conv_controller(
+        config: &KcpConfig,
+        conv: u32,
+        udp: impl Into<Arc<UdpSocket>>,
+        addr: SocketAddr,
+        controller: Box<dyn CongestionController>,
+    ) -> KcpResult<KcpStream> {
+        let udp: Arc<UdpSocket> = udp.into();
+        KcpStream::connect_with_transport_conv_controller(config, conv, udp, addr, controller).await
+    }
+
+    /// Create a `KcpStream` sending to and receiving from `addr` through `transport`
+    ///
+    /// NOTE: `conv` will be randomly generated
+    pub async fn connect_with_transport(
+        config: &KcpConfig,
+        transport: Arc<dyn DatagramTransport>,
+        addr: SocketAddr,
+    ) -> KcpResult<KcpStream> {
+        let mut conv = rand::random();
+        while conv == 0 || packet::is_reserved(conv) {
+            conv = rand::random();
+        }
+        KcpStream::connect_with_transport_conv_controller(
+            config,
+            conv,
+            transport,
+            addr,
+            Box::new(config.scream_controller()?),
+        )
+        .await
+    }
+
+    /// Create a `KcpStream` talking to `addr` through `transport`, driven by `controller`
+    ///
+    /// Socket options of `config` are only set on `UdpSocket` transports, others take care of their own.
+    pub async fn connect_with_transport_conv_controller(
+        config: &KcpConfig,
+        conv: u32,
+        transport: Arc<dyn DatagramTransport>,
         addr: SocketAddr,
+        controller: Box<dyn CongestionController>,
     ) -> KcpResult<KcpStream> {
-        let udp = Arc::new(udp);
-        let (socket, target_bitrate_rx) = KcpSocket::new(config, conv, udp, addr, config.stream)?;
+        config.validate()?;
+        if let Some(udp) = transport.udp_socket() {
+            if config.ecn {
+                ecn::enable_ecn(udp)?;
+            }
+            if config.pmtud {
+                pmtud::enable_dont_fragment(udp)?;
+            }
+            sockopt::apply_socket_options(udp, config)?;
+        }
+        let (socket, target_bitrate_rx) =
+            KcpSocket::new(config, conv, transport, addr, config.stream_mode, controller)?;
 
-        let session = KcpSession::new_shared((socket, target_bitrate_rx.clone()), config.session_expire, None);
+        let session = KcpSession::new_shared((socket, target_bitrate_rx.clone()), config.session_expire, None, None);
 
         Ok(KcpStream::with_session(session))
     }
@@ -98,20 +521,14 @@ impl KcpStream {
     pub(crate) fn with_session(session: Arc<KcpSession>) -> KcpStream {
         KcpStream {
             target_bitrate_rx: session.target_bitrate_rx.clone(),
-            session,
-            recv_buffer: Vec::new(),
-            recv_buffer_pos: 0,
-            recv_buffer_cap: 0,
+            session: Arc::new(StreamSession(session)),
+            recv_buffer: RecvBuffer::default(),
         }
     }
 
     /// `send` data in `buf`
     pub fn poll_send(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
-        // Mutex doesn't have poll_lock, spinning on it.
-        let mut kcp = self.session.kcp_socket().lock();
-        let result = ready!(kcp.poll_send(cx, buf));
-        self.session.notify();
-        result.into()
+        poll_send(&self.session, cx, buf)
     }
 
     /// `send` data in `buf`
@@ -119,55 +536,38 @@ impl KcpStream {
         future::poll_fn(|cx| self.poll_send(cx, buf)).await
     }
 
-    /// `recv` data into `buf`
-    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
-        loop {
-            // Consumes all data in buffer
-            if self.recv_buffer_pos < self.recv_buffer_cap {
-                let remaining = self.recv_buffer_cap - self.recv_buffer_pos;
-                let copy_length = remaining.min(buf.len());
-
-                buf[..copy_length]
-                    .copy_from_slice(&self.recv_buffer[self.recv_buffer_pos..self.recv_buffer_pos + copy_length]);
-                self.recv_buffer_pos += copy_length;
-                return Ok(copy_length).into();
-            }
-
-            // Mutex doesn't have poll_lock, spinning on it.
-            let mut kcp = self.session.kcp_socket().lock();
+    /// `send` the concatenation of `bufs` as one message, without joining them first
+    pub fn poll_send_vectored(&mut self, cx: &mut Context<'_>, bufs: &[IoSlice<'_>]) -> Poll<KcpResult<usize>> {
+        poll_send_vectored(&self.session, cx, bufs)
+    }
 
-            // Try to read from KCP
-            // 1. Read directly with user provided `buf`
-            let peek_size = kcp.peek_size().unwrap_or(0);
+    /// `send` the concatenation of `bufs` as one message, without joining them first
+    pub async fn send_vectored(&mut self, bufs: &[IoSlice<'_>]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_send_vectored(cx, bufs)).await
+    }
 
-            // 1.1. User's provided buffer is larger than available buffer's size
-            if peek_size > 0 && peek_size <= buf.len() {
-                match ready!(kcp.poll_recv(cx, buf)) {
-                    Ok(n) => {
-                        trace!("[CLIENT] recv directly {} bytes", n);
-                        return Ok(n).into();
-                    }
-                    Err(KcpError::UserBufTooSmall) => {}
-                    Err(err) => return Err(err).into(),
-                }
-            }
+    /// `send` data in `buf`, giving up on it once `deadline` passed
+    ///
+    /// Segments still unacknowledged at the deadline aren't retransmitted anymore and the peer
+    /// skips the whole message. Fails with `InvalidConfig` in `StreamMode::ByteStream`, which has
+    /// no messages to skip. The congestion controller doesn't count them as lost.
+    pub fn poll_send_with_deadline(
+        &mut self,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+        deadline: Duration,
+    ) -> Poll<KcpResult<usize>> {
+        poll_send_with_deadline(&self.session, cx, buf, Some(deadline))
+    }
 
-            // 2. User `buf` too small, read to recv_buffer
-            let required_size = peek_size;
-            if self.recv_buffer.len() < required_size {
-                self.recv_buffer.resize(required_size, 0);
-            }
+    /// `send` data in `buf`, giving up on it once `deadline` passed
+    pub async fn send_with_deadline(&mut self, buf: &[u8], deadline: Duration) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_send_with_deadline(cx, buf, deadline)).await
+    }
 
-            match ready!(kcp.poll_recv(cx, &mut self.recv_buffer)) {
-                Ok(0) => return Ok(0).into(),
-                Ok(n) => {
-                    trace!("[CLIENT] recv buffered {} bytes", n);
-                    self.recv_buffer_pos = 0;
-                    self.recv_buffer_cap = n;
-                }
-                Err(err) => return Err(err).into(),
-            }
-        }
+    /// `recv` data into `buf`
+    pub fn poll_recv(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.recv_buffer.poll_recv(&self.session, cx, buf)
     }
 
     /// `recv` data into `buf`
@@ -175,102 +575,299 @@ impl KcpStream {
         future::poll_fn(|cx| self.poll_recv(cx, buf)).await
     }
 
-    pub fn get_target_bitrate_receiver(&self) -> watch::Receiver<f32> {
-        self.target_bitrate_rx.clone()
+    /// Send `msg` as one message, the peer's `recv_msg` gets it whole unless the session is in stream mode
+    pub fn poll_send_msg(&mut self, cx: &mut Context<'_>, msg: &[u8]) -> Poll<KcpResult<()>> {
+        poll_send_msg(&self.session, cx, msg)
     }
 
-    /// Get the `KcpSession` for this `KcpStream`
-    pub fn session(&self) -> &KcpSession {
-        &self.session
+    /// Send `msg` as one message, see `poll_send_msg`
+    pub async fn send_msg(&mut self, msg: Bytes) -> KcpResult<()> {
+        future::poll_fn(|cx| self.poll_send_msg(cx, &msg)).await
     }
-}
 
-impl AsyncRead for KcpStream {
-    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
-        match ready!(self.poll_recv(cx, buf.initialize_unfilled())) {
-            Ok(n) => {
-                buf.advance(n);
-                Ok(()).into()
-            }
-            Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
-        }
+    /// Receive the next message whole, empty at the end of the stream
+    ///
+    /// Empty messages of peers sending them look the same, `into_framed` tells the two apart.
+    /// In stream mode the boundaries of the peer's sends are lost, this returns the next segments.
+    /// After a `recv` that read a message in part, this returns the rest of it.
+    pub fn poll_recv_msg(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Bytes>> {
+        self.recv_buffer.poll_recv_msg(&self.session, cx)
     }
-}
 
-impl AsyncWrite for KcpStream {
-    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
-        match ready!(self.poll_send(cx, buf)) {
-            Ok(n) => Ok(n).into(),
-            Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
-        }
+    /// Receive the next message whole, see `poll_recv_msg`
+    pub async fn recv_msg(&mut self) -> KcpResult<Bytes> {
+        future::poll_fn(|cx| self.poll_recv_msg(cx)).await
     }
 
-    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        // Mutex doesn't have poll_lock, spinning on it.
-        let mut kcp = self.session.kcp_socket().lock();
-        match kcp.flush() {
-            Ok(..) => {
-                self.session.notify();
-                Ok(()).into()
-            }
-            Err(KcpError::IoError(err)) => Err(err).into(),
-            Err(err) => Err(io::Error::new(ErrorKind::Other, err)).into(),
-        }
+    // `None` at the end of the stream, an empty message otherwise
+    pub(crate) fn poll_next_msg(&mut self, cx: &mut Context<'_>) -> Poll<KcpResult<Option<Bytes>>> {
+        self.recv_buffer.poll_next_msg(&self.session, cx)
     }
 
-    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
-        Ok(()).into()
+    /// Framing of sends, `recv_msg` only keeps their boundaries with `StreamMode::Message`
+    pub fn stream_mode(&self) -> StreamMode {
+        self.session.kcp_socket().lock().stream_mode()
     }
-}
 
-#[cfg(unix)]
-impl std::os::unix::io::AsRawFd for KcpStream {
-    fn as_raw_fd(&self) -> std::os::unix::prelude::RawFd {
-        let kcp_socket = self.session.kcp_socket().lock();
-        kcp_socket.udp_socket().as_raw_fd()
+    /// Round trip of a keepalive probe, a health check without payload
+    ///
+    /// The probe is a control packet with an id the peer echoes: it doesn't wait behind queued data,
+    /// isn't delivered to the peer's application and works the same in both stream modes. A lost
+    /// probe is sent again, after `KcpConfig::handshake_max_attempts` unanswered ones the ping fails with
+    /// `TimedOut`.
+    pub async fn ping(&mut self) -> KcpResult<Duration> {
+        self.session.kcp_socket().lock().send_ping()?;
+        self.session.notify();
+        let _guard = PingGuard(&self.session);
+        future::poll_fn(|cx| self.session.kcp_socket().lock().poll_ping(cx)).await
     }
-}
 
-#[cfg(windows)]
-impl std::os::windows::io::AsRawSocket for KcpStream {
-    fn as_raw_socket(&self) -> std::os::windows::prelude::RawSocket {
-        let kcp_socket = self.session.kcp_socket().lock();
-        kcp_socket.udp_socket().as_raw_socket()
+    /// Copy the data the next `recv` would return into `buf` without consuming it
+    ///
+    /// Like `UdpSocket::peek`, bytes that don't fit into `buf` are left out. `0` is the end of the stream.
+    pub fn poll_peek(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        self.recv_buffer.poll_peek(&self.session, cx, buf)
     }
-}
 
-#[cfg(test)]
-mod test {
-    use crate::KcpListener;
+    /// Copy the data the next `recv` would return into `buf` without consuming it, see `poll_peek`
+    pub async fn peek(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_peek(cx, buf)).await
+    }
 
-    use super::*;
+    /// Send `buf` as one datagram without retransmissions, it must fit into a single packet
+    pub fn poll_send_unreliable(&mut self, cx: &mut Context<'_>, buf: &[u8]) -> Poll<KcpResult<usize>> {
+        // Mutex doesn't have poll_lock, spinning on it.
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.poll_send_unreliable(cx, buf)
+    }
 
-    #[tokio::test]
-    async fn test_stream_echo() {
-        let _ = env_logger::try_init();
+    /// Send `buf` as one datagram without retransmissions, it must fit into a single packet
+    pub async fn send_unreliable(&mut self, buf: &[u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_send_unreliable(cx, buf)).await
+    }
 
-        let config = KcpConfig::default();
-        let server_addr = "127.0.0.1:5555".parse::<SocketAddr>().unwrap();
+    /// `recv` one datagram into `buf`
+    pub fn poll_recv_unreliable(&mut self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<KcpResult<usize>> {
+        // Mutex doesn't have poll_lock, spinning on it.
+        let mut kcp = self.session.kcp_socket().lock();
+        kcp.poll_recv_unreliable(cx, buf)
+    }
 
-        let mut listener = KcpListener::bind(config.clone(), server_addr).await.unwrap();
-        let listener_hdl = tokio::spawn(async move {
+    /// `recv` one datagram into `buf`
+    pub async fn recv_unreliable(&mut self, buf: &mut [u8]) -> KcpResult<usize> {
+        future::poll_fn(|cx| self.poll_recv_unreliable(cx, buf)).await
+    }
+
+    pub fn get_target_bitrate_receiver(&self) -> watch::Receiver<f32> {
+        self.target_bitrate_rx.clone()
+    }
+
+    /// Watch of the full congestion state, a superset of the target bitrate
+    pub fn congestion_state_receiver(&self) -> watch::Receiver<CongestionState> {
+        self.session.kcp_socket().lock().congestion_state_receiver()
+    }
+
+    /// Watch of the pacer queue, shows whether the pacing rate keeps up with KCP's output
+    pub fn pacer_state_receiver(&self) -> watch::Receiver<PacerState> {
+        self.session.kcp_socket().lock().pacer_state_receiver()
+    }
+
+    /// Watch of the send, receive and retransmission rates, published once per second
+    pub fn throughput_receiver(&self) -> watch::Receiver<ThroughputSample> {
+        self.session.kcp_socket().lock().throughput_receiver()
+    }
+
+    /// Call `handler` with congestion and connection events of this stream
+    ///
+    /// Replaces the previous handler. It is called with the session lock held and must not block.
+    pub fn set_event_handler<F>(&self, handler: F)
+    where
+        F: Fn(KcpEvent) + Send + Sync + 'static,
+    {
+        self.session.kcp_socket().lock().set_event_handler(EventHandler::new(handler));
+    }
+
+    /// Change the settings of the established session, e.g. to throttle it
+    ///
+    /// `InvalidConfig` leaves the session unchanged, see `KcpRuntimeConfig::validate`.
+    pub fn reconfigure(&self, config: &KcpRuntimeConfig) -> KcpResult<()> {
+        self.session.kcp_socket().lock().reconfigure(config)?;
+        self.session.notify();
+        Ok(())
+    }
+
+    /// Snapshot of the connection statistics
+    pub fn stats(&self) -> KcpStats {
+        self.session.kcp_socket().lock().stats()
+    }
+
+    /// Get the `KcpSession` for this `KcpStream`
+    pub fn session(&self) -> &KcpSession {
+        &self.session
+    }
+
+    /// Split into a read half and a write half borrowing this stream
+    pub fn split(&mut self) -> (ReadHalf<'_>, WriteHalf<'_>) {
+        let read = ReadHalf {
+            session: &self.session,
+            recv_buffer: &mut self.recv_buffer,
+        };
+        let write = WriteHalf {
+            session: &self.session,
+            target_bitrate_rx: &self.target_bitrate_rx,
+        };
+        (read, write)
+    }
+
+    /// `Stream` and `Sink` of whole messages, see `KcpFramed`
+    pub fn into_framed(self) -> KcpFramed {
+        KcpFramed::new(self)
+    }
+
+    /// Split into owned halves which can be moved into different tasks
+    ///
+    /// The session is closed once both halves are dropped.
+    pub fn into_split(self) -> (KcpReadHalf, KcpWriteHalf) {
+        let read = KcpReadHalf {
+            session: self.session.clone(),
+            recv_buffer: self.recv_buffer,
+        };
+        let write = KcpWriteHalf {
+            session: self.session,
+            target_bitrate_rx: self.target_bitrate_rx,
+        };
+        (read, write)
+    }
+}
+
+impl AsyncRead for KcpStream {
+    fn poll_read(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &mut ReadBuf<'_>) -> Poll<io::Result<()>> {
+        let result = ready!(self.poll_recv(cx, buf.initialize_unfilled()));
+        poll_read_result(result, buf)
+    }
+}
+
+impl AsyncWrite for KcpStream {
+    fn poll_write(mut self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<io::Result<usize>> {
+        let result = ready!(self.poll_send(cx, buf));
+        poll_write_result(result)
+    }
+
+    fn poll_write_vectored(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        bufs: &[IoSlice<'_>],
+    ) -> Poll<io::Result<usize>> {
+        let result = ready!(self.poll_send_vectored(cx, bufs));
+        poll_write_result(result)
+    }
+
+    fn is_write_vectored(&self) -> bool {
+        true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        poll_flush(&self.session, cx).map_err(Into::into)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        poll_shutdown(&self.session, cx).map_err(Into::into)
+    }
+}
+
+impl KcpStream {
+    /// File descriptor of the UDP socket, `None` for other transports
+    #[cfg(unix)]
+    pub fn raw_fd(&self) -> Option<std::os::unix::io::RawFd> {
+        use std::os::unix::io::AsRawFd;
+
+        let kcp_socket = self.session.kcp_socket().lock();
+        kcp_socket.transport().udp_socket().map(|udp| udp.as_raw_fd())
+    }
+
+    /// Handle of the UDP socket, `None` for other transports
+    #[cfg(windows)]
+    pub fn raw_socket(&self) -> Option<std::os::windows::io::RawSocket> {
+        use std::os::windows::io::AsRawSocket;
+
+        let kcp_socket = self.session.kcp_socket().lock();
+        kcp_socket.transport().udp_socket().map(|udp| udp.as_raw_socket())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        sync::Mutex,
+        time::{Duration, Instant},
+    };
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        time,
+    };
+
+    use crate::{
+        DuplicateConfig, FecConfig, KcpListener, KcpNoDelayConfig, Obfuscator, PacerQueuePolicy, PreSharedKey,
+        QlogWriter, SalsaObfuscator, SharedPacer, SimLinkConfig, SimNetwork,
+    };
+
+    use super::*;
+
+    /// Fixed-rate controller without any feedback
+    #[derive(Debug)]
+    struct FixedRate;
+
+    impl CongestionController for FixedRate {
+        fn on_packet_sent(&mut self, _sn: u32, _size: usize) {}
+
+        fn on_ack(&mut self, _sn: u32, _ack_time: Instant) {}
+
+        fn on_loss(&mut self, _sn: u32) {}
+
+        fn on_rtt(&mut self) {}
+
+        fn get_target_bitrate(&self) -> f32 {
+            1_000_000.0
+        }
+
+        fn get_pacing_rate(&self) -> f32 {
+            1_000_000.0
+        }
+
+        fn get_congestion_window(&self) -> f32 {
+            64_000.0
+        }
+
+        fn get_s_rtt(&self) -> f32 {
+            0.0
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_echo() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let server_addr = "127.0.0.1:5555".parse::<SocketAddr>().unwrap();
+
+        let mut listener = KcpListener::bind(config.clone(), server_addr).await.unwrap();
+        let listener_hdl = tokio::spawn(async move {
             loop {
                 let (mut stream, peer_addr) = listener.accept().await.unwrap();
-                println!("accepted {}", peer_addr);
+                trace!("accepted {}", peer_addr);
 
                 tokio::spawn(async move {
                     let mut buffer = [0u8; 8192];
                     loop {
                         match stream.recv(&mut buffer).await {
                             Ok(n) => {
-                                println!("server recv: {:?}", &buffer[..n]);
+                                trace!("server recv: {:?}", &buffer[..n]);
                                 let send_n = stream.send(&buffer[..n]).await.unwrap();
-                                println!("server sent: {}", send_n);
+                                trace!("server sent: {}", send_n);
                             }
                             Err(err) => {
-                                println!("recv error: {}", err);
+                                trace!("recv error: {}", err);
                                 break;
                             }
                         }
@@ -283,14 +880,2020 @@ mod test {
 
         let test_payload = b"HELLO WORLD";
         stream.send(test_payload).await.unwrap();
-        println!("client sent: {:?}", test_payload);
+        trace!("client sent: {:?}", test_payload);
 
         let mut recv_buffer = [0u8; 1024];
         let recv_n = stream.recv(&mut recv_buffer).await.unwrap();
-        println!("client recv: {:?}", &recv_buffer[..recv_n]);
+        trace!("client recv: {:?}", &recv_buffer[..recv_n]);
         assert_eq!(recv_n, test_payload.len());
         assert_eq!(&recv_buffer[..recv_n], test_payload);
 
         listener_hdl.abort();
     }
+
+    #[tokio::test]
+    async fn test_stream_idle_update() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            while let Ok(n) = stream.recv(&mut buffer).await {
+                stream.send(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut recv_buffer = [0u8; 1024];
+        stream.send(b"HELLO WORLD").await.unwrap();
+        stream.recv(&mut recv_buffer).await.unwrap();
+
+        // Once everything is acknowledged the session sleeps far beyond KCP's interval
+        time::sleep(Duration::from_millis(300)).await;
+        {
+            let mut socket = stream.session.kcp_socket().lock();
+            socket.update().unwrap();
+            let next = socket.update().unwrap();
+            assert!(next >= std::time::Instant::now() + Duration::from_millis(500));
+        }
+
+        // Input and sends still wake both sides right away
+        stream.send(b"HELLO AGAIN").await.unwrap();
+        let n = time::timeout(Duration::from_millis(500), stream.recv(&mut recv_buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&recv_buffer[..n], b"HELLO AGAIN");
+
+        listener_hdl.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_stats() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO WORLD").await.unwrap();
+
+        let mut recv_buffer = [0u8; 1024];
+        stream.recv(&mut recv_buffer).await.unwrap();
+
+        let stats = stream.stats();
+        assert_eq!(stats.rcv_wnd, config.rcv_wnd);
+        assert!(stats.snd_wnd >= 2);
+        assert!(stats.ref_wnd >= 2000.0);
+        assert!(stats.pacing_rate > 0.0);
+        assert_eq!(stats.retransmits, 0);
+        assert_eq!(stats.packets_lost, 0);
+        assert!(stats.bytes_sent > 0);
+        assert!(stats.bytes_received > 0);
+
+        let mut state_rx = stream.congestion_state_receiver();
+        let state = *time::timeout(Duration::from_secs(1), state_rx.wait_for(|s| s.srtt > Duration::ZERO))
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(state.target_bitrate > 0.0);
+        assert!(state.ref_wnd >= 2000.0);
+        assert_eq!(state.loss_rate, 0.0);
+        assert_eq!(state.ce_rate, 0.0);
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_asymmetric_windows() {
+        let _ = env_logger::try_init();
+
+        let server_config = KcpConfig {
+            rcv_wnd: 300,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = 0;
+            let mut buffer = [0u8; 4096];
+            while received < 64 * 1024 {
+                received += stream.recv(&mut buffer).await.unwrap();
+            }
+            stream.send(b"DONE").await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let config = KcpConfig {
+            snd_wnd: 4,
+            rcv_wnd: 512,
+            ..Default::default()
+        };
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        for _ in 0..64 {
+            stream.send(&[0u8; 1024]).await.unwrap();
+            // The congestion window never lifts the send window above its maximum
+            assert!(stream.stats().snd_wnd <= 4);
+        }
+
+        let mut recv_buffer = [0u8; 16];
+        let n = time::timeout(Duration::from_secs(5), stream.recv(&mut recv_buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&recv_buffer[..n], b"DONE");
+
+        let stats = stream.stats();
+        assert_eq!(stats.rcv_wnd, 512);
+        assert_eq!(stats.rmt_wnd, 300);
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_events() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            while let Ok(n) = stream.recv(&mut buffer).await {
+                if n == 0 {
+                    break;
+                }
+                stream.send(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (event_tx, mut event_rx) = tokio::sync::mpsc::unbounded_channel();
+        stream.set_event_handler(move |event| {
+            let _ = event_tx.send(event);
+        });
+
+        let mut recv_buffer = [0u8; 1024];
+        for _ in 0..4 {
+            stream.send(b"HELLO EVENTS").await.unwrap();
+            stream.recv(&mut recv_buffer).await.unwrap();
+        }
+
+        let srtt = time::timeout(Duration::from_secs(2), async {
+            loop {
+                if let Some(KcpEvent::RttUpdate { srtt, .. }) = event_rx.recv().await {
+                    return srtt;
+                }
+            }
+        })
+        .await
+        .unwrap();
+        assert!(srtt > Duration::ZERO);
+
+        stream.shutdown().await.unwrap();
+        drop(stream);
+        time::timeout(Duration::from_secs(5), async {
+            while event_rx.recv().await != Some(KcpEvent::Closed) {}
+        })
+        .await
+        .unwrap();
+
+        listener_hdl.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_qlog() {
+        let _ = env_logger::try_init();
+
+        let path = std::env::temp_dir().join(format!("kcp_stream_{}.qlog", std::process::id()));
+        let config = KcpConfig {
+            qlog: Some(Arc::new(QlogWriter::new(&path).unwrap())),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO QLOG").await.unwrap();
+        let mut recv_buffer = [0u8; 1024];
+        stream.recv(&mut recv_buffer).await.unwrap();
+        listener_hdl.await.unwrap();
+
+        let start = Instant::now();
+        let content = loop {
+            let content = std::fs::read_to_string(&path).unwrap();
+            if content.contains("recovery:packet_acked") || start.elapsed() > Duration::from_secs(5) {
+                break content;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        };
+        let _ = std::fs::remove_file(&path);
+
+        assert!(content.starts_with("{\"qlog_format\":\"NDJSON\""));
+        assert!(content.contains("transport:packet_sent"));
+        assert!(content.contains("recovery:packet_acked"));
+        assert!(content.contains("recovery:metrics_updated"));
+    }
+
+    #[tokio::test]
+    async fn test_stream_vectored() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 4096];
+            for _ in 0..2 {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                stream.send(&buffer[..n]).await.unwrap();
+            }
+            stream.flush().await.unwrap();
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        assert!(stream.is_write_vectored());
+
+        let mut recv_buffer = [0u8; 4096];
+        let n = stream
+            .send_vectored(&[IoSlice::new(b"HEADER "), IoSlice::new(b"PAYLOAD")])
+            .await
+            .unwrap();
+        assert_eq!(n, 14);
+        let n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..n], b"HEADER PAYLOAD");
+
+        // One message per write, larger than a segment
+        let payload = [3u8; 2000];
+        let n = stream
+            .write_vectored(&[IoSlice::new(b"HEADER "), IoSlice::new(&payload)])
+            .await
+            .unwrap();
+        assert_eq!(n, 2007);
+        let n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..7], b"HEADER ");
+        assert_eq!(&recv_buffer[7..n], &payload[..]);
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_into_split_relay() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+
+        let writer_hdl = tokio::spawn(async move {
+            for i in 0..16u8 {
+                writer.send(&[i; 100]).await.unwrap();
+            }
+            writer
+        });
+
+        let mut recv_buffer = [0u8; 1024];
+        for i in 0..16u8 {
+            let n = reader.recv(&mut recv_buffer).await.unwrap();
+            assert_eq!(&recv_buffer[..n], &[i; 100]);
+        }
+
+        let _writer = writer_hdl.await.unwrap();
+        listener_hdl.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_split() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.split();
+            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (mut reader, mut writer) = stream.split();
+
+        let test_payload = b"HELLO WORLD";
+        let (sent, received) = tokio::join!(writer.send(test_payload), async {
+            let mut recv_buffer = [0u8; 1024];
+            let n = reader.recv(&mut recv_buffer).await.unwrap();
+            recv_buffer[..n].to_vec()
+        });
+        assert_eq!(sent.unwrap(), test_payload.len());
+        assert_eq!(received, test_payload);
+
+        listener_hdl.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_custom_controller() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind_with_controller(config.clone(), "127.0.0.1:0", || {
+            Box::new(FixedRate) as Box<dyn CongestionController>
+        })
+        .await
+        .unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let mut stream = KcpStream::connect_with_controller(&config, server_addr, Box::new(FixedRate))
+            .await
+            .unwrap();
+
+        let test_payload = b"HELLO WORLD";
+        stream.send(test_payload).await.unwrap();
+
+        let mut recv_buffer = [0u8; 1024];
+        let recv_n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..recv_n], test_payload);
+        assert_eq!(*stream.get_target_bitrate_receiver().borrow(), 1_000_000.0);
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_stream_pmtud() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            mtu: 1200,
+            pmtud: true,
+            pmtud_max_mtu: 1472,
+            ..KcpConfig::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 8192];
+            while let Ok(n) = stream.recv(&mut buffer).await {
+                stream.send(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut recv_buffer = [0u8; 8192];
+
+        // Loopback passes everything, the search ends close to the maximum
+        let start = Instant::now();
+        while stream.stats().mtu + 16 <= config.pmtud_max_mtu {
+            assert!(start.elapsed() < Duration::from_secs(10), "mtu {}", stream.stats().mtu);
+            stream.send(b"HELLO WORLD").await.unwrap();
+            stream.recv(&mut recv_buffer).await.unwrap();
+            time::sleep(Duration::from_millis(50)).await;
+        }
+
+        // Segments of the new size still get through
+        let payload = vec![7u8; 4000];
+        stream.send(&payload).await.unwrap();
+        let n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..n], &payload[..]);
+
+        listener_hdl.abort();
+    }
+
+    #[cfg(target_os = "linux")]
+    #[tokio::test]
+    async fn test_stream_echo_ecn() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            ecn: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+
+        let test_payload = b"HELLO ECN";
+        stream.send(test_payload).await.unwrap();
+
+        let mut recv_buffer = [0u8; 1024];
+        let recv_n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..recv_n], test_payload);
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_echo_fec() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            fec: Some(FecConfig {
+                data_shards: 4,
+                parity_shards: 2,
+            }),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 8192];
+            loop {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                stream.send(&buffer[..n]).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+
+        // Enough packets to span several FEC groups
+        let mut recv_buffer = [0u8; 8192];
+        for i in 0..20u8 {
+            let test_payload = vec![i; 100 + i as usize * 50];
+            stream.send(&test_payload).await.unwrap();
+
+            let recv_n = stream.recv(&mut recv_buffer).await.unwrap();
+            assert_eq!(&recv_buffer[..recv_n], &test_payload[..]);
+        }
+
+        listener_hdl.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_echo_psk() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            psk: Some(PreSharedKey::new([0x42; 32])),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+            listener
+        });
+
+        // Datagrams under another key never reach the listener's sessions
+        let intruder_config = KcpConfig {
+            psk: Some(PreSharedKey::new([0x24; 32])),
+            ..Default::default()
+        };
+        let mut intruder = KcpStream::connect(&intruder_config, server_addr).await.unwrap();
+        intruder.send(b"INJECTED").await.unwrap();
+        intruder.flush().await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert!(!listener_hdl.is_finished());
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+
+        let test_payload = b"HELLO PSK";
+        stream.send(test_payload).await.unwrap();
+
+        let mut recv_buffer = [0u8; 1024];
+        let recv_n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..recv_n], test_payload);
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_idle_timeout() {
+        let _ = env_logger::try_init();
+
+        // Peer that never answers
+        let peer = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        let config = KcpConfig {
+            keepalive_interval: Some(Duration::from_millis(50)),
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+        let mut stream = KcpStream::connect(&config, peer.local_addr().unwrap()).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        let mut recv_buffer = [0u8; 1024];
+        let err = time::timeout(Duration::from_secs(5), stream.recv(&mut recv_buffer))
+            .await
+            .expect("recv hangs")
+            .unwrap_err();
+        match err {
+            KcpError::IoError(err) => assert_eq!(err.kind(), io::ErrorKind::TimedOut),
+            err => panic!("unexpected error {}", err),
+        }
+        assert!(stream.send(b"HELLO").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_keepalive() {
+        let _ = env_logger::try_init();
+
+        // Only the client probes, the server answers
+        let server_config = KcpConfig::default();
+        let config = KcpConfig {
+            keepalive_interval: Some(Duration::from_millis(50)),
+            idle_timeout: Some(Duration::from_millis(300)),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(server_config, "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            for _ in 0..2 {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                stream.send(&buffer[..n]).await.unwrap();
+                stream.flush().await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut recv_buffer = [0u8; 1024];
+
+        stream.send(b"HELLO").await.unwrap();
+        let n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..n], b"HELLO");
+
+        // Idle for longer than the idle timeout
+        time::sleep(Duration::from_secs(1)).await;
+
+        stream.send(b"STILL HERE").await.unwrap();
+        let n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..n], b"STILL HERE");
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_shutdown() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut received = Vec::new();
+            let mut buffer = [0u8; 1024];
+            loop {
+                let n = time::timeout(Duration::from_secs(1), stream.recv(&mut buffer))
+                    .await
+                    .expect("FIN not delivered")
+                    .unwrap();
+                if n == 0 {
+                    break;
+                }
+                received.extend_from_slice(&buffer[..n]);
+            }
+            // Half-closed, our side can still send
+            stream.send(&received).await.unwrap();
+            stream.flush().await.unwrap();
+            listener
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        stream.send(b" WORLD").await.unwrap();
+        time::timeout(Duration::from_secs(2), stream.shutdown())
+            .await
+            .expect("FIN not acknowledged")
+            .unwrap();
+        assert!(matches!(stream.send(b"AFTER SHUTDOWN").await, Err(KcpError::Closed)));
+        // `AsyncWrite` keeps the kind of a closed pipe, the cause is inside
+        let err = stream.write(b"AFTER SHUTDOWN").await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert!(matches!(err.get_ref().and_then(|err| err.downcast_ref()), Some(KcpError::Closed)));
+
+        let mut recv_buffer = [0u8; 1024];
+        let n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..n], b"HELLO WORLD");
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_peek() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        let body = vec![7u8; 3000];
+        let mut message = (body.len() as u32).to_le_bytes().to_vec();
+        message.extend_from_slice(&body);
+        client.send(&message).await.unwrap();
+        client.send(b"NEXT").await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        // Length prefix first, then a buffer of the right size
+        let mut prefix = [0u8; 4];
+        assert_eq!(stream.peek(&mut prefix).await.unwrap(), 4);
+        assert_eq!(stream.peek(&mut prefix).await.unwrap(), 4);
+        let len = u32::from_le_bytes(prefix) as usize;
+        assert_eq!(len, 3000);
+
+        // A message read in part is peeked from where reading stopped
+        let mut head = [0u8; 4];
+        assert_eq!(stream.recv(&mut head).await.unwrap(), 4);
+        let mut peeked = vec![0u8; len + 100];
+        assert_eq!(stream.peek(&mut peeked).await.unwrap(), len);
+        let mut buffer = vec![0u8; len];
+        assert_eq!(stream.recv(&mut buffer).await.unwrap(), len);
+        assert_eq!(buffer, body);
+
+        let mut next = [0u8; 16];
+        assert_eq!(stream.peek(&mut next).await.unwrap(), 4);
+        assert_eq!(stream.recv(&mut next).await.unwrap(), 4);
+        assert_eq!(&next[..4], b"NEXT");
+    }
+
+    #[tokio::test]
+    async fn test_stream_messages() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        assert_eq!(client.stream_mode(), StreamMode::Message);
+        // Longer than a segment before the conv is known
+        let messages = [vec![1u8; 5000], vec![2u8; 10], Vec::new(), vec![3u8; 1400]];
+        for message in &messages[..2] {
+            client.send_msg(Bytes::from(message.clone())).await.unwrap();
+        }
+        // Empty messages are dropped by the receiver unless `allow_recv_empty_packet`
+        client.send_msg(Bytes::new()).await.unwrap();
+        client.send_msg(Bytes::from(messages[3].clone())).await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        for message in messages.iter().filter(|m| !m.is_empty()) {
+            assert_eq!(stream.recv_msg().await.unwrap(), &message[..]);
+        }
+
+        // The rest of a message read in part
+        client.send_msg(Bytes::from_static(b"HELLO WORLD")).await.unwrap();
+        let mut head = [0u8; 6];
+        assert_eq!(stream.recv(&mut head).await.unwrap(), 6);
+        assert_eq!(stream.recv_msg().await.unwrap(), &b"WORLD"[..]);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn test_stream_ping() {
+        let _ = env_logger::try_init();
+
+        for stream_mode in [StreamMode::Message, StreamMode::ByteStream] {
+            let link = SimLinkConfig {
+                delay: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let network = SimNetwork::new(link, 0);
+            let server_addr = "10.0.0.1:1".parse().unwrap();
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                stream_mode,
+                ..Default::default()
+            };
+
+            let mut listener = KcpListener::from_transport(config.clone(), network.bind(server_addr).unwrap())
+                .await
+                .unwrap();
+            let client_socket = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+            let mut client = KcpStream::connect_with_transport(&config, client_socket.clone(), server_addr)
+                .await
+                .unwrap();
+            client.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            // One round trip, also with data queued ahead of the probe
+            let data = vec![0x42u8; 64 * 1024];
+            client.send(&data).await.unwrap();
+            for rtt in [client.ping().await.unwrap(), server.ping().await.unwrap()] {
+                assert!(rtt >= Duration::from_millis(100), "{:?} {:?}", stream_mode, rtt);
+                assert!(rtt < Duration::from_millis(110), "{:?} {:?}", stream_mode, rtt);
+            }
+
+            // A lost probe is sent again
+            network.set_link(SimLinkConfig { loss: 1.0, ..link });
+            let restore = network.clone();
+            tokio::spawn(async move {
+                time::sleep(Duration::from_millis(200)).await;
+                restore.set_link(link);
+            });
+            let start = time::Instant::now();
+            let rtt = client.ping().await.unwrap();
+            assert!(rtt < Duration::from_millis(110), "{:?} {:?}", stream_mode, rtt);
+            assert!(start.elapsed() >= Duration::from_millis(500), "{:?}", stream_mode);
+
+            // The probes never reach the application, neither as messages nor as EOF
+            client.send(b"WORLD").await.unwrap();
+            let mut received = Vec::new();
+            while received.len() < 5 + data.len() + 5 {
+                let msg = server.recv_msg().await.unwrap();
+                assert!(!msg.is_empty(), "{:?}", stream_mode);
+                received.extend_from_slice(&msg);
+            }
+            assert_eq!(&received[..5], b"HELLO");
+            assert_eq!(&received[5..5 + data.len()], &data[..]);
+            assert_eq!(&received[5 + data.len()..], b"WORLD");
+
+            // A dead peer fails the ping after `handshake_max_attempts` probes
+            time::sleep(Duration::from_secs(1)).await;
+            network.set_link(SimLinkConfig { loss: 1.0, ..link });
+            let start = time::Instant::now();
+            let err = client.ping().await.unwrap_err();
+            assert!(matches!(err, KcpError::IoError(ref err) if err.kind() == io::ErrorKind::TimedOut));
+            assert!(start.elapsed() >= Duration::from_millis(500) * 9, "{:?}", stream_mode);
+
+            // A ping nobody waits for anymore stops probing
+            let sent = client_socket.sent();
+            assert!(time::timeout(Duration::from_millis(50), client.ping()).await.is_err());
+            time::sleep(Duration::from_secs(5)).await;
+            assert_eq!(client_socket.sent(), sent + 1, "{:?}", stream_mode);
+            network.set_link(link);
+
+            // Only our side is closed, the peer still answers
+            client.shutdown().await.unwrap();
+            assert!(client.ping().await.is_ok());
+        }
+    }
+
+    #[tokio::test]
+    async fn test_message_mode_boundaries() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::builder().stream_mode(StreamMode::Message).build().unwrap();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Queued back to back, small ones would share a segment in byte stream mode
+        let messages: Vec<Vec<u8>> = (0..60u32).map(|i| vec![i as u8; (i * 397 % 4000 + 1) as usize]).collect();
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        for message in &messages {
+            client.send(message).await.unwrap();
+        }
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(stream.stream_mode(), StreamMode::Message);
+        for message in &messages {
+            let received = time::timeout(Duration::from_secs(5), stream.recv_msg())
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(received, &message[..]);
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_mode_per_connection() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let udp = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let byte_stream_addr = udp.local_addr().unwrap();
+        listener.set_config_hook(move |peer_addr, _| KcpConfig {
+            stream_mode: if peer_addr == byte_stream_addr {
+                StreamMode::ByteStream
+            } else {
+                StreamMode::Message
+            },
+            ..KcpConfig::default()
+        });
+
+        let byte_stream_config = KcpConfig {
+            stream_mode: StreamMode::ByteStream,
+            ..KcpConfig::default()
+        };
+        let mut client = KcpStream::connect_with_socket(&byte_stream_config, udp, server_addr)
+            .await
+            .unwrap();
+        assert_eq!(client.stream_mode(), StreamMode::ByteStream);
+        for chunk in [&b"HELLO "[..], b"BYTE ", b"STREAM"] {
+            client.send(chunk).await.unwrap();
+        }
+        let (mut stream, peer_addr) = listener.accept().await.unwrap();
+        assert_eq!(peer_addr, byte_stream_addr);
+        assert_eq!(stream.stream_mode(), StreamMode::ByteStream);
+        let mut received = Vec::new();
+        while received.len() < 17 {
+            let chunk = time::timeout(Duration::from_secs(5), stream.recv_msg())
+                .await
+                .unwrap()
+                .unwrap();
+            received.extend_from_slice(&chunk);
+        }
+        assert_eq!(received, b"HELLO BYTE STREAM");
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"MESSAGE").await.unwrap();
+        let (mut stream, _) = listener.accept().await.unwrap();
+        assert_eq!(stream.stream_mode(), StreamMode::Message);
+        assert_eq!(stream.recv_msg().await.unwrap(), &b"MESSAGE"[..]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_coop_budget() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        for _ in 0..200 {
+            client.write_all(b"DATA").await.unwrap();
+        }
+        client.flush().await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 4];
+        let mut received = 0;
+        while received < 200 {
+            time::sleep(Duration::from_millis(100)).await;
+            // Data is always ready, the budget runs out before it does
+            let ready = future::poll_fn(|cx| {
+                let mut ready = 0;
+                while let Poll::Ready(n) = stream.poll_recv(cx, &mut buffer) {
+                    assert_eq!(n.unwrap(), 4);
+                    ready += 1;
+                }
+                Poll::Ready(ready)
+            })
+            .await;
+            assert!(ready <= 128);
+            received += ready;
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(received, 200);
+    }
+
+    #[tokio::test]
+    async fn test_stream_duplicate() {
+        let _ = env_logger::try_init();
+
+        // Datagrams the client sent for 20 echoed messages
+        async fn client_datagrams(duplicate: Option<DuplicateConfig>) -> u64 {
+            let network = SimNetwork::new(
+                SimLinkConfig {
+                    delay: Duration::from_millis(5),
+                    ..Default::default()
+                },
+                0,
+            );
+            let server_addr = "10.0.0.1:1".parse().unwrap();
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                duplicate,
+                ..Default::default()
+            };
+
+            let mut listener = KcpListener::from_transport(config.clone(), network.bind(server_addr).unwrap())
+                .await
+                .unwrap();
+            let client_socket = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+            let mut client = KcpStream::connect_with_transport(&config, client_socket.clone(), server_addr)
+                .await
+                .unwrap();
+            client.send_msg(Bytes::from_static(b"0")).await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            for i in 0..20 {
+                // Every message arrives once, the copies are dropped
+                assert_eq!(server.recv_msg().await.unwrap(), i.to_string().as_bytes());
+                server.send_msg(Bytes::from(i.to_string())).await.unwrap();
+                assert_eq!(client.recv_msg().await.unwrap(), i.to_string().as_bytes());
+                client.send_msg(Bytes::from((i + 1).to_string())).await.unwrap();
+            }
+            time::sleep(Duration::from_millis(100)).await;
+            client_socket.sent()
+        }
+
+        let single = client_datagrams(None).await;
+        let duplicate = DuplicateConfig {
+            max_size: 256,
+            delay: Duration::from_millis(2),
+        };
+        let doubled = client_datagrams(Some(duplicate)).await;
+        assert!(doubled >= single + 20, "{} datagrams with copies, {} without", doubled, single);
+    }
+
+    #[tokio::test]
+    async fn test_stream_peer_closed() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            session_expire: Some(Duration::from_millis(100)),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"BYE").await.unwrap();
+        client.shutdown().await.unwrap();
+
+        let (mut stream, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 16];
+        while stream.recv(&mut buffer).await.unwrap() > 0 {}
+        drop(client);
+
+        // The session expires once the peer is gone
+        let err = time::timeout(Duration::from_secs(3), async {
+            loop {
+                if let Err(err) = stream.send(b"ANYONE?").await {
+                    return err;
+                }
+                // Sending keeps the session alive
+                time::sleep(Duration::from_millis(300)).await;
+            }
+        })
+        .await
+        .unwrap();
+        assert!(matches!(err, KcpError::PeerClosed), "{:?}", err);
+    }
+
+    #[tokio::test]
+    async fn test_stream_half_close_request_response() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.unwrap();
+            let (mut reader, mut writer) = stream.into_split();
+            let mut request = Vec::new();
+            reader.read_to_end(&mut request).await.unwrap();
+            // Reply with many segments after the request was complete
+            for _ in 0..64 {
+                writer.write_all(&request).await.unwrap();
+            }
+            writer.shutdown().await.unwrap();
+            listener
+        });
+
+        let stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (mut reader, mut writer) = stream.into_split();
+        let request = [7u8; 3000];
+        writer.write_all(&request).await.unwrap();
+        time::timeout(Duration::from_secs(2), writer.shutdown())
+            .await
+            .expect("FIN not acknowledged")
+            .unwrap();
+
+        let mut reply = Vec::new();
+        time::timeout(Duration::from_secs(5), reader.read_to_end(&mut reply))
+            .await
+            .expect("reply not complete")
+            .unwrap();
+        assert_eq!(reply.len(), 64 * request.len());
+        assert!(reply.iter().all(|&b| b == 7));
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_connect_timeout() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            handshake_retry_interval: Duration::from_millis(50),
+            handshake_max_attempts: 3,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            stream.flush().await.unwrap();
+            listener
+        });
+
+        // Accepted as soon as the handshake is answered
+        let mut stream = KcpStream::connect_timeout(&config, server_addr, Duration::from_secs(2))
+            .await
+            .unwrap();
+        stream.send(b"PING").await.unwrap();
+        let mut recv_buffer = [0u8; 1024];
+        let n = stream.recv(&mut recv_buffer).await.unwrap();
+        assert_eq!(&recv_buffer[..n], b"PING");
+        listener_hdl.await.unwrap();
+
+        // Nobody answers, the attempts run out before the timeout
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let start = Instant::now();
+        let err = KcpStream::connect_timeout(&config, dead.local_addr().unwrap(), Duration::from_secs(5))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, KcpError::HandshakeTimeout));
+        assert!(start.elapsed() < Duration::from_secs(2));
+        let mut attempts = 0;
+        while dead.try_recv(&mut recv_buffer).is_ok() {
+            attempts += 1;
+        }
+        assert_eq!(attempts, 3);
+
+        // The timeout caps the attempts
+        let config = KcpConfig {
+            handshake_max_attempts: 100,
+            ..config
+        };
+        let err = KcpStream::connect_timeout(&config, dead.local_addr().unwrap(), Duration::from_millis(200))
+            .await
+            .unwrap_err();
+        assert!(matches!(err, KcpError::HandshakeTimeout));
+    }
+
+    #[tokio::test]
+    async fn test_stream_peer_unreachable() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            rto_max: Duration::from_millis(100),
+            backoff_factor: Some(1.0),
+            max_retransmissions: Some(2),
+            ..Default::default()
+        };
+
+        let dead = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let mut stream = KcpStream::connect(&config, dead.local_addr().unwrap()).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+
+        let mut recv_buffer = [0u8; 1024];
+        let err = time::timeout(Duration::from_secs(2), stream.recv(&mut recv_buffer))
+            .await
+            .expect("retransmissions not capped")
+            .unwrap_err();
+        assert!(matches!(err, KcpError::PeerUnreachable));
+        assert!(matches!(stream.send(b"AGAIN").await, Err(KcpError::PeerUnreachable)));
+    }
+
+    #[tokio::test]
+    async fn test_stream_udp_batch() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            udp_batch_size: 8,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 8192];
+            loop {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                stream.send(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+
+        // Several MSS per message, so segments queue up for the same batch
+        let test_payload: Vec<u8> = (0..6000).map(|i| i as u8).collect();
+        let mut recv_buffer = [0u8; 8192];
+        for _ in 0..5 {
+            stream.send(&test_payload).await.unwrap();
+            let n = stream.recv(&mut recv_buffer).await.unwrap();
+            assert_eq!(&recv_buffer[..n], &test_payload[..]);
+        }
+
+        listener_hdl.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_pacer_backpressure() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            pacer_queue_size: 4,
+            pacer_queue_policy: PacerQueuePolicy::Block,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let mut received = 0;
+            while received < 50 * 1000 {
+                received += stream.recv(&mut buffer).await.unwrap();
+            }
+            received
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut pacer_state_rx = stream.pacer_state_receiver();
+        let states = Arc::new(Mutex::new(Vec::new()));
+        let watcher = {
+            let states = states.clone();
+            tokio::spawn(async move {
+                while pacer_state_rx.changed().await.is_ok() {
+                    states.lock().unwrap().push(*pacer_state_rx.borrow_and_update());
+                }
+            })
+        };
+        for i in 0..50u8 {
+            stream.send(&[i; 1000]).await.unwrap();
+            stream.flush().await.unwrap();
+        }
+
+        let received = time::timeout(Duration::from_secs(10), listener_hdl).await.unwrap().unwrap();
+        assert_eq!(received, 50 * 1000);
+        assert_eq!(stream.stats().pacer_dropped, 0);
+
+        watcher.abort();
+        let states = states.lock().unwrap();
+        assert!(states.iter().any(|s| s.queued > 0));
+        assert!(states.iter().all(|s| s.capacity == 4 && s.queued <= 4 && s.dropped == 0));
+    }
+
+    #[tokio::test]
+    async fn test_stream_recv_buffer_budget() {
+        let _ = env_logger::try_init();
+
+        const BUDGET: usize = 8 * 1024;
+        let config = KcpConfig {
+            max_recv_buffer_bytes: Some(BUDGET),
+            stream_mode: StreamMode::ByteStream,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let (mtu, rcv_wnd) = (config.mtu, config.rcv_wnd);
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let mut received = stream.recv(&mut buffer).await.unwrap();
+
+            // A slow reader, the sender has to stop at the budget
+            time::sleep(Duration::from_millis(500)).await;
+            let stats = stream.stats();
+            assert!(stats.rcv_buffered > 0);
+            assert!(stats.rcv_buffered <= BUDGET + mtu, "{} bytes buffered", stats.rcv_buffered);
+            assert!(stats.rcv_wnd < rcv_wnd);
+
+            // Reading reopens the window
+            while received < 100 * 1000 {
+                received += stream.recv(&mut buffer).await.unwrap();
+            }
+            received
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        for i in 0..100u8 {
+            stream.send(&[i; 1000]).await.unwrap();
+        }
+
+        let received = time::timeout(Duration::from_secs(10), listener_hdl).await.unwrap().unwrap();
+        assert_eq!(received, 100 * 1000);
+    }
+
+    #[tokio::test]
+    async fn test_stream_staged_writes() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 64];
+            let mut messages = Vec::new();
+            while messages.len() < 3 {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                messages.push(buffer[..n].to_vec());
+                if messages.len() == 1 {
+                    stream.send(b"OK").await.unwrap();
+                }
+            }
+            messages
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut recv_buffer = [0u8; 16];
+        stream.send(b"FIRST").await.unwrap();
+        stream.recv(&mut recv_buffer).await.unwrap();
+
+        // Writes while the socket is locked don't wait for it, they go out in order with its next update
+        {
+            let socket = stream.session.kcp_socket().lock();
+            let mut cx = Context::from_waker(std::task::Waker::noop());
+            for msg in [&b"SECOND"[..], b"THIRD"] {
+                let result = stream.session.poll_send(&mut cx, &[IoSlice::new(msg)], None);
+                assert!(matches!(result, Poll::Ready(Ok(n)) if n == msg.len()));
+            }
+            assert_eq!(socket.stats().wait_snd, 0);
+        }
+
+        let messages = time::timeout(Duration::from_secs(5), listener_hdl).await.unwrap().unwrap();
+        assert_eq!(messages, [&b"FIRST"[..], b"SECOND", b"THIRD"]);
+    }
+
+    #[tokio::test]
+    async fn test_stream_unreliable() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 2048];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+            loop {
+                let n = stream.recv_unreliable(&mut buffer).await.unwrap();
+                if n == 0 {
+                    break;
+                }
+                stream.send_unreliable(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        // The listener drops datagrams until the reliable handshake created our session
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let mut buffer = [0u8; 2048];
+        stream.recv(&mut buffer).await.unwrap();
+
+        let mtu = stream.stats().mtu;
+        assert!(stream.send_unreliable(&vec![0u8; mtu]).await.is_err());
+
+        for i in 0..8u8 {
+            stream.send_unreliable(&[i; 100]).await.unwrap();
+        }
+        let mut received = Vec::new();
+        while received.len() < 8 {
+            let n = time::timeout(Duration::from_secs(5), stream.recv_unreliable(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(n, 100);
+            received.push(buffer[0]);
+        }
+        received.sort_unstable();
+        assert_eq!(received, (0..8u8).collect::<Vec<_>>());
+
+        // Datagrams are counted by the congestion controller, not by KCP
+        let stats = stream.stats();
+        assert_eq!(stats.retransmits, 0);
+        assert_eq!(stats.wait_snd, 0);
+
+        listener_hdl.abort();
+    }
+
+    #[tokio::test]
+    async fn test_stream_send_deadline() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            for _ in 0..2 {
+                let n = stream.recv(&mut buffer).await.unwrap();
+                stream.send(&buffer[..n]).await.unwrap();
+            }
+        });
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut buffer = [0u8; 1024];
+        stream.send(b"HELLO").await.unwrap();
+        stream.recv(&mut buffer).await.unwrap();
+
+        // Expires before the first flush, the peer only sees its tombstone
+        stream.send_with_deadline(b"TOO LATE", Duration::ZERO).await.unwrap();
+        stream.send(b"ON TIME").await.unwrap();
+
+        let n = time::timeout(Duration::from_secs(5), stream.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer[..n], b"ON TIME");
+
+        let stats = stream.stats();
+        assert_eq!(stats.segments_expired, 1);
+        assert_eq!(stats.packets_lost, 0);
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_deadline_needs_message_mode() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            stream_mode: StreamMode::ByteStream,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, _) = listener.accept().await.unwrap();
+        let mut buffer = [0u8; 16];
+        assert_eq!(server.recv(&mut buffer).await.unwrap(), 5);
+
+        let err = stream.send_with_deadline(b"LATE", Duration::from_secs(1)).await.unwrap_err();
+        assert!(matches!(err, KcpError::InvalidConfig(..)));
+        stream.send(b"WORLD").await.unwrap();
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"WORLD");
+    }
+    #[tokio::test]
+    async fn test_stream_shared_socket() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+
+        let server_udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        server_udp.set_ttl(16).unwrap();
+        let server_addr = server_udp.local_addr().unwrap();
+        let mut listener = KcpListener::from_socket(config.clone(), server_udp.clone()).await.unwrap();
+        #[cfg(unix)]
+        assert_eq!(listener.raw_fd(), Some(std::os::unix::io::AsRawFd::as_raw_fd(&*server_udp)));
+        assert_eq!(server_udp.ttl().unwrap(), 16);
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+        });
+
+        let udp = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let mut stream = KcpStream::connect_with_socket(&config, udp.clone(), server_addr)
+            .await
+            .unwrap();
+        stream.send(b"HELLO SHARED").await.unwrap();
+
+        // Stray traffic on the shared socket is not fed to KCP
+        let stray = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        stray.send_to(b"NOT KCP AT ALL, JUST NOISE", udp.local_addr().unwrap()).await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let n = time::timeout(Duration::from_secs(5), stream.recv(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(&buffer[..n], b"HELLO SHARED");
+
+        listener_hdl.await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_dual_stack() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "[::]:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+
+        for client_addr in ["127.0.0.1", "::1"] {
+            let client_addr: IpAddr = client_addr.parse().unwrap();
+            let mut stream = KcpStream::connect(&config, (client_addr, port)).await.unwrap();
+            stream.send(b"HELLO").await.unwrap();
+
+            // IPv4 peers aren't seen v4-mapped
+            let (mut server, peer_addr) = listener.accept().await.unwrap();
+            assert_eq!(peer_addr.ip(), client_addr);
+            let mut buffer = [0u8; 64];
+            let n = server.recv(&mut buffer).await.unwrap();
+            server.send(&buffer[..n]).await.unwrap();
+            let n = time::timeout(Duration::from_secs(5), stream.recv(&mut buffer))
+                .await
+                .unwrap()
+                .unwrap();
+            assert_eq!(&buffer[..n], b"HELLO");
+        }
+    }
+
+    #[tokio::test]
+    async fn test_stream_happy_eyeballs() {
+        let _ = env_logger::try_init();
+
+        let v6: SocketAddr = "[2001:db8::1]:80".parse().unwrap();
+        let v4: SocketAddr = "192.0.2.1:80".parse().unwrap();
+        let v4b: SocketAddr = "192.0.2.2:80".parse().unwrap();
+        assert_eq!(happy_eyeballs_order(vec![v4, v4b, v6].into_iter()), vec![v6, v4, v4b]);
+
+        let config = KcpConfig {
+            handshake_retry_interval: Duration::from_millis(100),
+            handshake_max_attempts: 3,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // Nobody listens on IPv6, the IPv4 attempt wins
+        let addrs = [SocketAddr::new("::1".parse().unwrap(), server_addr.port()), server_addr];
+        let timeout = Duration::from_secs(5);
+        let mut stream = KcpStream::connect_timeout(&config, &addrs[..], timeout).await.unwrap();
+        stream.send(b"HELLO").await.unwrap();
+        let (mut server, peer_addr) = listener.accept().await.unwrap();
+        assert!(peer_addr.is_ipv4());
+        let mut buffer = [0u8; 64];
+        let n = server.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"HELLO");
+
+        // Nobody at all, the last failure is reported
+        let addrs = [SocketAddr::new("::1".parse().unwrap(), 9), "127.0.0.1:9".parse().unwrap()];
+        assert!(KcpStream::connect_timeout(&config, &addrs[..], timeout).await.is_err());
+
+        // `connect` takes the first address without asking the peer
+        KcpStream::connect(&config, &addrs[..]).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_stream_rate_limit() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            min_target_bitrate: 2_000_000.0,
+            max_target_bitrate: 2_000_000.0,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let target_bitrate_rx = stream.get_target_bitrate_receiver();
+        let (sent, accepted) = tokio::join!(stream.send(b"HELLO"), listener.accept());
+        sent.unwrap();
+        let (mut server, _) = accepted.unwrap();
+        let mut buffer = [0u8; 64];
+        server.recv(&mut buffer).await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        // The pacing rate keeps SCReAM's headroom over the bounds
+        assert_eq!(*target_bitrate_rx.borrow(), 2_000_000.0);
+        let stats = stream.stats();
+        assert_eq!(stats.pacing_rate, 2_000_000.0 * config.scream.packet_pacing_headroom);
+        assert_eq!(stream.congestion_state_receiver().borrow().target_bitrate, 2_000_000.0);
+
+        // A runtime cap takes the lower bound along
+        stream.reconfigure(&KcpRuntimeConfig {
+            max_target_bitrate: Some(1_000_000.0),
+            ..Default::default()
+        })
+        .unwrap();
+        stream.send(b"AGAIN").await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*target_bitrate_rx.borrow(), 1_000_000.0);
+
+        // The receiver's limit wins over the lower bound
+        server
+            .reconfigure(&KcpRuntimeConfig {
+                max_receive_bitrate: Some(300_000.0),
+                ..Default::default()
+            })
+            .unwrap();
+        stream.send(b"LIMIT").await.unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+        assert_eq!(*target_bitrate_rx.borrow(), 300_000.0);
+        assert_eq!(stream.stats().pacing_rate, 300_000.0 * config.scream.packet_pacing_headroom);
+    }
+
+    #[tokio::test]
+    async fn test_stream_header_compression() {
+        let _ = env_logger::try_init();
+
+        // Bytes the server receives for 50 voice-sized messages
+        async fn voice_bytes(header_compression: bool) -> u64 {
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                header_compression,
+                ..Default::default()
+            };
+            let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+            let server_addr = listener.local_addr().unwrap();
+
+            let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+            let (sent, accepted) = tokio::join!(stream.send(&[0u8; 20]), listener.accept());
+            sent.unwrap();
+            let (mut server, _) = accepted.unwrap();
+            let mut buffer = [0u8; 64];
+            assert_eq!(server.recv(&mut buffer).await.unwrap(), 20);
+            for i in 1..50u8 {
+                time::sleep(Duration::from_millis(5)).await;
+                stream.send(&[i; 20]).await.unwrap();
+                assert_eq!(server.recv(&mut buffer).await.unwrap(), 20);
+                assert_eq!(buffer[0], i);
+            }
+            server.stats().bytes_received
+        }
+
+        let whole = voice_bytes(false).await;
+        let compressed = voice_bytes(true).await;
+        // 8 or 9 bytes less per segment, once the first ones were acknowledged
+        assert!(compressed + 40 * 8 <= whole, "{} / {}", compressed, whole);
+    }
+
+    /// Relay between one client and `server_addr`, recording every datagram with its sender
+    async fn recording_relay(server_addr: SocketAddr) -> (Arc<UdpSocket>, Arc<Mutex<Vec<(SocketAddr, Vec<u8>)>>>) {
+        let relay = Arc::new(UdpSocket::bind("127.0.0.1:0").await.unwrap());
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        {
+            let (relay, seen) = (relay.clone(), seen.clone());
+            tokio::spawn(async move {
+                let mut client_addr = None;
+                let mut buf = vec![0u8; 65536];
+                loop {
+                    let (n, addr) = relay.recv_from(&mut buf).await.unwrap();
+                    seen.lock().unwrap().push((addr, buf[..n].to_vec()));
+                    let target = if addr == server_addr {
+                        match client_addr {
+                            Some(client_addr) => client_addr,
+                            None => continue,
+                        }
+                    } else {
+                        client_addr = Some(addr);
+                        server_addr
+                    };
+                    let _ = relay.send_to(&buf[..n], target).await;
+                }
+            });
+        }
+        (relay, seen)
+    }
+
+    #[tokio::test]
+    async fn test_stream_obfuscation() {
+        let _ = env_logger::try_init();
+
+        let obfuscation: Arc<dyn Obfuscator> = Arc::new(SalsaObfuscator::new([3; 32], 64));
+        let config = KcpConfig {
+            obfuscation: Some(obfuscation.clone()),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        // A relay in between records what a middlebox sees
+        let (relay, seen) = recording_relay(server_addr).await;
+        let mut stream = KcpStream::connect(&config, relay.local_addr().unwrap()).await.unwrap();
+        let message = (0..3000).map(|i| i as u8).collect::<Vec<_>>();
+        let (sent, accepted) = tokio::join!(stream.send(&message), listener.accept());
+        sent.unwrap();
+        let (mut server, _) = accepted.unwrap();
+        let mut buffer = [0u8; 4096];
+        assert_eq!(server.recv(&mut buffer).await.unwrap(), message.len());
+        assert_eq!(&buffer[..message.len()], &message[..]);
+        server.send(b"WORLD").await.unwrap();
+        assert_eq!(stream.recv(&mut buffer).await.unwrap(), 5);
+        assert_eq!(&buffer[..5], b"WORLD");
+
+        // Not even the conv shows up on the wire, though every datagram reveals a KCP packet
+        for (_, datagram) in seen.lock().unwrap().iter() {
+            let revealed = obfuscation.deobfuscate(datagram).unwrap();
+            assert!(!datagram.windows(4).any(|w| w == &revealed[..4]));
+        }
+        assert!(seen.lock().unwrap().len() >= 3);
+
+        // Peers without the obfuscation are dropped before any session sees them
+        let mut plain = KcpStream::connect(&KcpConfig::default(), server_addr).await.unwrap();
+        plain.send(b"HELLO").await.unwrap();
+        assert!(time::timeout(Duration::from_millis(500), listener.accept()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_stream_feedback_auth() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            feedback_auth: true,
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let (sent, accepted) = tokio::join!(stream.send(&[1u8; 1000]), listener.accept());
+        sent.unwrap();
+        let (mut server, _) = accepted.unwrap();
+
+        // Both directions get their feedback through once the tokens are exchanged
+        let mut buffer = [0u8; 2048];
+        for i in 0..20u8 {
+            assert_eq!(server.recv(&mut buffer).await.unwrap(), 1000);
+            server.send(&[i; 1000]).await.unwrap();
+            assert_eq!(stream.recv(&mut buffer).await.unwrap(), 1000);
+            stream.send(&[i; 1000]).await.unwrap();
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(!stream.stats().srtt.is_zero());
+        assert!(!server.stats().srtt.is_zero());
+    }
+
+    #[tokio::test]
+    async fn test_stream_replay_window() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            psk: Some(PreSharedKey::new([5; 32])),
+            ..Default::default()
+        };
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+        let (relay, seen) = recording_relay(server_addr).await;
+
+        let mut stream = KcpStream::connect(&config, relay.local_addr().unwrap()).await.unwrap();
+        let (sent, accepted) = tokio::join!(stream.send(b"HELLO"), listener.accept());
+        sent.unwrap();
+        let (mut server, _) = accepted.unwrap();
+        let mut buffer = [0u8; 64];
+        for i in 0..10u8 {
+            assert_eq!(server.recv(&mut buffer).await.unwrap(), 5);
+            stream.send(&[i; 5]).await.unwrap();
+        }
+        assert_eq!(server.recv(&mut buffer).await.unwrap(), 5);
+        assert_eq!(server.stats().replays_dropped, 0);
+
+        // Everything the client sent, SCReAM feedback and the first packet included, once more
+        let replayed = seen
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(addr, _)| *addr != server_addr)
+            .map(|(_, datagram)| datagram.clone())
+            .collect::<Vec<_>>();
+        assert!(replayed.len() > 10);
+        for datagram in &replayed {
+            relay.send_to(datagram, server_addr).await.unwrap();
+        }
+
+        let start = Instant::now();
+        while server.stats().replays_dropped < replayed.len() as u64 && start.elapsed() < Duration::from_secs(2) {
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(server.stats().replays_dropped, replayed.len() as u64);
+        // Neither a new session nor data delivered twice
+        assert!(time::timeout(Duration::from_millis(200), listener.accept()).await.is_err());
+        assert!(time::timeout(Duration::from_millis(200), server.recv(&mut buffer)).await.is_err());
+
+        stream.send(b"WORLD").await.unwrap();
+        assert_eq!(server.recv(&mut buffer).await.unwrap(), 5);
+        assert_eq!(&buffer[..5], b"WORLD");
+    }
+
+    #[tokio::test]
+    async fn test_stream_throughput() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut stream = KcpStream::connect(&config, server_addr).await.unwrap();
+        let mut throughput_rx = stream.throughput_receiver();
+        let (sent, accepted) = tokio::join!(stream.send(&[1u8; 1000]), listener.accept());
+        sent.unwrap();
+        let (mut server, _) = accepted.unwrap();
+        let mut server_throughput_rx = server.throughput_receiver();
+
+        let mut buffer = [0u8; 2048];
+        let mut received = server.recv(&mut buffer).await.unwrap();
+        for _ in 0..9 {
+            stream.send(&[1u8; 1000]).await.unwrap();
+        }
+        while received < 10_000 {
+            received += server.recv(&mut buffer).await.unwrap();
+        }
+
+        time::timeout(Duration::from_secs(3), throughput_rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        let sample = *throughput_rx.borrow_and_update();
+        assert!(sample.interval >= Duration::from_secs(1));
+        // Payload and headers of the first second
+        assert!(sample.sent_bps >= 80_000.0 / sample.interval.as_secs_f32());
+        assert_eq!(sample.retransmit_bps, 0.0);
+
+        time::timeout(Duration::from_secs(3), server_throughput_rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(server_throughput_rx.borrow().recv_bps >= 80_000.0 / 2.0);
+
+        // Idle sessions keep publishing, the rates fall to nothing
+        time::timeout(Duration::from_secs(3), throughput_rx.changed())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(throughput_rx.borrow().sent_bps < sample.sent_bps);
+    }
+
+    #[tokio::test]
+    async fn test_stream_passthrough() {
+        let _ = env_logger::try_init();
+
+        let network = SimNetwork::new(
+            SimLinkConfig {
+                delay: Duration::from_millis(10),
+                loss: 0.1,
+                ..Default::default()
+            },
+            7,
+        );
+        let server_addr = "10.0.0.1:1".parse().unwrap();
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            use_external_congestion_control: true,
+            passthrough: true,
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::from_transport(config.clone(), network.bind(server_addr).unwrap())
+            .await
+            .unwrap();
+        let client = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+        let mut stream = KcpStream::connect_with_transport(&config, client, server_addr)
+            .await
+            .unwrap();
+        #[cfg(unix)]
+        assert!(stream.raw_fd().is_none() && listener.raw_fd().is_none());
+
+        // The first send waits for the handshake, the listener has no session to take datagrams before
+        let mtu = stream.stats().mtu;
+        let ((), accepted) = tokio::join!(
+            async {
+                for i in 0..200u8 {
+                    stream.send_msg(Bytes::from(vec![i; 500])).await.unwrap();
+                    time::sleep(Duration::from_millis(2)).await;
+                }
+                assert!(stream.send_msg(Bytes::from(vec![0u8; mtu])).await.is_err());
+                time::sleep(Duration::from_millis(500)).await;
+                stream.shutdown().await.unwrap();
+            },
+            listener.accept()
+        );
+        let (mut server, _) = accepted.unwrap();
+
+        let mut received = Vec::new();
+        loop {
+            let msg = time::timeout(Duration::from_secs(5), server.recv_msg())
+                .await
+                .unwrap()
+                .unwrap();
+            if msg.is_empty() {
+                break;
+            }
+            assert_eq!(msg.len(), 500);
+            received.push(msg[0]);
+        }
+        // Lost datagrams stay lost, the congestion controller still saw them go. Only the FIN is a KCP
+        // segment that may be retransmitted
+        assert!(received.len() > 100 && received.len() < 200, "{}", received.len());
+        let stats = stream.stats();
+        assert!(stats.retransmits <= 2, "{:?}", stats);
+        assert!(stats.loss_rate > 0.0, "{:?}", stats);
+    }
+
+    #[tokio::test]
+    async fn test_stream_probe_padding() {
+        let _ = env_logger::try_init();
+
+        async fn idle_target_bitrate(probe_bitrate: Option<f32>) -> KcpStats {
+            let network = SimNetwork::new(
+                SimLinkConfig {
+                    delay: Duration::from_millis(20),
+                    ..Default::default()
+                },
+                0,
+            );
+            let server_addr = "10.0.0.1:1".parse().unwrap();
+            let config = KcpConfig {
+                nodelay: KcpNoDelayConfig::fastest(),
+                use_external_congestion_control: true,
+                probe_bitrate,
+                ..Default::default()
+            };
+
+            let mut listener = KcpListener::from_transport(config.clone(), network.bind(server_addr).unwrap())
+                .await
+                .unwrap();
+            let client = network.bind("10.0.0.2:1".parse().unwrap()).unwrap();
+            let mut stream = KcpStream::connect_with_transport(&config, client, server_addr)
+                .await
+                .unwrap();
+            stream.send(b"HELLO").await.unwrap();
+            let (mut server, _) = listener.accept().await.unwrap();
+
+            // Quiet application
+            time::sleep(Duration::from_secs(2)).await;
+            stream.send(b"AGAIN").await.unwrap();
+
+            // Padding never reaches the application
+            let mut buffer = [0u8; 1024];
+            let n = server.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..n], b"HELLO");
+            let n = server.recv(&mut buffer).await.unwrap();
+            assert_eq!(&buffer[..n], b"AGAIN");
+
+            stream.stats()
+        }
+
+        let (idle, probed) = tokio::join!(idle_target_bitrate(None), idle_target_bitrate(Some(2_000_000.0)));
+        assert_eq!(idle.padding_sent, 0);
+        assert!(probed.padding_sent > 0);
+        assert!(probed.target_bitrate > idle.target_bitrate);
+        assert!(probed.target_bitrate >= 1_500_000.0, "{:?}", probed);
+    }
+
+    #[tokio::test]
+    async fn test_stream_shared_pacer() {
+        let _ = env_logger::try_init();
+
+        let network = SimNetwork::new(SimLinkConfig::default(), 0);
+        let server_addr = "10.0.0.1:1".parse().unwrap();
+        let pacer = SharedPacer::new(None, 4 * 1400, 1);
+        let config = KcpConfig {
+            nodelay: KcpNoDelayConfig::fastest(),
+            shared_pacer: Some(pacer.clone()),
+            ..Default::default()
+        };
+
+        let mut listener = KcpListener::from_transport(config.clone(), network.bind(server_addr).unwrap())
+            .await
+            .unwrap();
+        let server = tokio::spawn(async move {
+            for _ in 0..4 {
+                let (mut stream, _) = listener.accept().await.unwrap();
+                tokio::spawn(async move {
+                    let mut buffer = [0u8; 1024];
+                    let n = stream.recv(&mut buffer).await.unwrap();
+                    stream.send(&buffer[..n]).await.unwrap();
+                    stream.flush().await.unwrap();
+                    time::sleep(Duration::from_millis(500)).await;
+                });
+            }
+        });
+
+        let mut clients = Vec::new();
+        for i in 0..4u8 {
+            let config = config.clone();
+            let client = network.bind(format!("10.0.0.2:{}", i + 1).parse().unwrap()).unwrap();
+            clients.push(tokio::spawn(async move {
+                let mut stream = KcpStream::connect_with_transport(&config, client, server_addr).await.unwrap();
+                stream.send(&[i; 100]).await.unwrap();
+                let mut buffer = [0u8; 1024];
+                let n = time::timeout(Duration::from_secs(5), stream.recv(&mut buffer))
+                    .await
+                    .unwrap()
+                    .unwrap();
+                assert_eq!(&buffer[..n], &[i; 100][..]);
+            }));
+        }
+        for client in clients {
+            client.await.unwrap();
+        }
+        server.await.unwrap();
+        assert!(pacer.bytes_sent() > 8 * 100);
+    }
+
+    #[tokio::test]
+    async fn test_stream_reconfigure() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig::default();
+        let mut listener = KcpListener::bind(config.clone(), "127.0.0.1:0").await.unwrap();
+        let server_addr = listener.local_addr().unwrap();
+
+        let mut client = KcpStream::connect(&config, server_addr).await.unwrap();
+        client.send(b"HELLO").await.unwrap();
+        let (_accepted, _) = listener.accept().await.unwrap();
+        assert!(client.stats().target_bitrate > 100_000.0);
+
+        client.reconfigure(&KcpRuntimeConfig {
+            nodelay: Some(KcpNoDelayConfig::fastest()),
+            snd_wnd: Some(8),
+            rcv_wnd: Some(512),
+            max_target_bitrate: Some(100_000.0),
+            packet_pacing_headroom: Some(1.0),
+            ..Default::default()
+        })
+        .unwrap();
+        time::sleep(Duration::from_millis(100)).await;
+
+        // Refused as a whole, the valid window of the same config is not applied either
+        let err = client
+            .reconfigure(&KcpRuntimeConfig {
+                rcv_wnd: Some(1024),
+                packet_pacing_headroom: Some(0.0),
+                ..Default::default()
+            })
+            .unwrap_err();
+        assert!(matches!(err, KcpError::InvalidConfig(..)), "{}", err);
+
+        let stats = client.stats();
+        assert!(stats.target_bitrate <= 100_000.0, "{}", stats.target_bitrate);
+        assert!(stats.pacing_rate <= 100_000.0, "{}", stats.pacing_rate);
+        assert!(stats.snd_wnd <= 8, "{}", stats.snd_wnd);
+        assert_eq!(stats.rcv_wnd, 512);
+    }
 }
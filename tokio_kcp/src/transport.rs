@@ -0,0 +1,296 @@
+//! Datagram transports KCP runs over
+//!
+//! `UdpSocket` is the default, anything that moves addressed datagrams (a DTLS socket, a TUN
+//! device wrapper, an in-memory link) can carry KCP by implementing `DatagramTransport`.
+
+use std::{
+    fmt::Debug,
+    io,
+    net::SocketAddr,
+    task::{Context, Poll},
+};
+
+use bytes::BytesMut;
+use futures_util::ready;
+use socket2::{Domain, Protocol, Socket, Type};
+use tokio::net::{self, ToSocketAddrs, UdpSocket};
+
+use crate::ecn::EcnCodepoint;
+
+/// Unreliable, addressed datagram I/O
+///
+/// Both directions are used concurrently through a shared reference, a listener receives from
+/// all of its peers through a single transport.
+pub trait DatagramTransport: Debug + Send + Sync + 'static {
+    /// Send one datagram to `target`
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>>;
+
+    /// Receive one datagram, returns its length, source address and ECN codepoint
+    ///
+    /// Transports without ECN support report `NotEct`.
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>>;
+
+    /// Local address datagrams are received on
+    fn local_addr(&self) -> io::Result<SocketAddr>;
+
+    /// Send a prefix of `packets` to `target`, returns how many were sent
+    fn poll_send_batch(&self, cx: &mut Context<'_>, packets: &[BytesMut], target: SocketAddr) -> Poll<io::Result<usize>> {
+        let mut sent = 0;
+        for packet in packets {
+            match self.poll_send_to(cx, packet, target) {
+                Poll::Ready(Ok(..)) => sent += 1,
+                // Reported by the next call, which starts with the failed packet
+                Poll::Ready(Err(..)) | Poll::Pending if sent > 0 => break,
+                Poll::Ready(Err(err)) => return Poll::Ready(Err(err)),
+                Poll::Pending => return Poll::Pending,
+            }
+        }
+        Poll::Ready(Ok(sent))
+    }
+
+    /// Receive at least one datagram into `buffers`, filling `meta` with their length, source and
+    /// ECN codepoint, returns the number received
+    fn poll_recv_batch(
+        &self,
+        cx: &mut Context<'_>,
+        buffers: &mut [Vec<u8>],
+        meta: &mut [(usize, SocketAddr, EcnCodepoint)],
+    ) -> Poll<io::Result<usize>> {
+        meta[0] = ready!(self.poll_recv_from(cx, &mut buffers[0]))?;
+        Poll::Ready(Ok(1))
+    }
+
+    /// `UdpSocket` underneath, ECN and PMTUD socket options and raw handles need one
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        None
+    }
+}
+
+/// `UdpSocket` reports peers by their canonical address, IPv4 peers of a dual-stack socket included
+impl DatagramTransport for UdpSocket {
+    fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+        UdpSocket::poll_send_to(self, cx, buf, send_target(self, target))
+    }
+
+    #[cfg(unix)]
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::Interest;
+
+        use crate::ecn::sys;
+
+        // Without IP_RECVTOS there are no control messages and packets read as `NotEct`
+        let fd = self.as_raw_fd();
+        loop {
+            ready!(self.poll_recv_ready(cx))?;
+            match self.try_io(Interest::READABLE, || sys::recvmsg_ecn(fd, buf)) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                r => return Poll::Ready(r.map(|(n, addr, ecn)| (n, canonical_addr(addr), ecn))),
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+        let mut buf = tokio::io::ReadBuf::new(buf);
+        let addr = ready!(UdpSocket::poll_recv_from(self, cx, &mut buf))?;
+        Poll::Ready(Ok((buf.filled().len(), canonical_addr(addr), EcnCodepoint::NotEct)))
+    }
+
+    fn local_addr(&self) -> io::Result<SocketAddr> {
+        UdpSocket::local_addr(self)
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_send_batch(&self, cx: &mut Context<'_>, packets: &[BytesMut], target: SocketAddr) -> Poll<io::Result<usize>> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::Interest;
+
+        use crate::batch::sys;
+
+        if packets.len() == 1 {
+            return DatagramTransport::poll_send_to(self, cx, &packets[0], target).map_ok(|_| 1);
+        }
+
+        let fd = self.as_raw_fd();
+        loop {
+            ready!(self.poll_send_ready(cx))?;
+            match self.try_io(Interest::WRITABLE, || sys::sendmmsg(fd, packets, &target)) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                r => return Poll::Ready(r),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn poll_recv_batch(
+        &self,
+        cx: &mut Context<'_>,
+        buffers: &mut [Vec<u8>],
+        meta: &mut [(usize, SocketAddr, EcnCodepoint)],
+    ) -> Poll<io::Result<usize>> {
+        use std::os::unix::io::AsRawFd;
+        use tokio::io::Interest;
+
+        use crate::batch::sys;
+
+        if buffers.len() == 1 {
+            meta[0] = ready!(DatagramTransport::poll_recv_from(self, cx, &mut buffers[0]))?;
+            return Poll::Ready(Ok(1));
+        }
+
+        let fd = self.as_raw_fd();
+        loop {
+            ready!(self.poll_recv_ready(cx))?;
+            match self.try_io(Interest::READABLE, || sys::recvmmsg(fd, buffers, meta)) {
+                Err(ref err) if err.kind() == io::ErrorKind::WouldBlock => continue,
+                Ok(count) => {
+                    for (_, addr, _) in &mut meta[..count] {
+                        *addr = canonical_addr(*addr);
+                    }
+                    return Poll::Ready(Ok(count));
+                }
+                r => return Poll::Ready(r),
+            }
+        }
+    }
+
+    fn udp_socket(&self) -> Option<&UdpSocket> {
+        Some(self)
+    }
+}
+
+/// `addr` with a v4-mapped IPv6 address replaced by the IPv4 one, as a dual-stack socket's IPv4 peers
+/// would otherwise have two addresses
+pub fn canonical_addr(addr: SocketAddr) -> SocketAddr {
+    match addr {
+        SocketAddr::V6(v6) => match v6.ip().to_ipv4_mapped() {
+            Some(ip) => SocketAddr::new(ip.into(), v6.port()),
+            None => addr,
+        },
+        SocketAddr::V4(..) => addr,
+    }
+}
+
+// Linux sends to IPv4 addresses through a dual-stack socket as they are, other systems want them v4-mapped
+#[cfg(target_os = "linux")]
+fn send_target(_socket: &UdpSocket, target: SocketAddr) -> SocketAddr {
+    target
+}
+
+#[cfg(not(target_os = "linux"))]
+fn send_target(socket: &UdpSocket, target: SocketAddr) -> SocketAddr {
+    match target {
+        SocketAddr::V4(v4) if socket.local_addr().is_ok_and(|local| local.is_ipv6()) => {
+            SocketAddr::new(v4.ip().to_ipv6_mapped().into(), v4.port())
+        }
+        _ => target,
+    }
+}
+
+/// Bind a `UdpSocket` to the first of `addr`'s addresses that works
+///
+/// `[::]` listens dual-stack whatever the system default is, IPv4 peers are reported by their IPv4 address.
+pub async fn bind_udp<A: ToSocketAddrs>(addr: A) -> io::Result<UdpSocket> {
+    let mut last_err = None;
+    for addr in net::lookup_host(addr).await? {
+        match bind_udp_addr(addr) {
+            Ok(udp) => return Ok(udp),
+            Err(err) => last_err = Some(err),
+        }
+    }
+    Err(last_err.unwrap_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no addresses to bind to")))
+}
+
+fn bind_udp_addr(addr: SocketAddr) -> io::Result<UdpSocket> {
+    let socket = Socket::new(Domain::for_address(addr), Type::DGRAM, Some(Protocol::UDP))?;
+    if addr.is_ipv6() && addr.ip().is_unspecified() {
+        socket.set_only_v6(false)?;
+    }
+    socket.set_nonblocking(true)?;
+    socket.bind(&addr.into())?;
+    UdpSocket::from_std(socket.into())
+}
+
+/// Send `buf` to `target` through `transport`
+pub async fn send_to(transport: &dyn DatagramTransport, buf: &[u8], target: SocketAddr) -> io::Result<usize> {
+    futures_util::future::poll_fn(|cx| transport.poll_send_to(cx, buf, target)).await
+}
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    };
+
+    use super::*;
+    use crate::{config::KcpConfig, listener::KcpListener, stream::KcpStream};
+
+    /// Counts datagrams and hides the socket, so only the trait defaults are used
+    #[derive(Debug)]
+    struct CountingTransport {
+        udp: UdpSocket,
+        sent: AtomicUsize,
+    }
+
+    impl DatagramTransport for CountingTransport {
+        fn poll_send_to(&self, cx: &mut Context<'_>, buf: &[u8], target: SocketAddr) -> Poll<io::Result<usize>> {
+            let n = ready!(self.udp.poll_send_to(cx, buf, target))?;
+            self.sent.fetch_add(1, Ordering::Relaxed);
+            Poll::Ready(Ok(n))
+        }
+
+        fn poll_recv_from(&self, cx: &mut Context<'_>, buf: &mut [u8]) -> Poll<io::Result<(usize, SocketAddr, EcnCodepoint)>> {
+            let mut buf = tokio::io::ReadBuf::new(buf);
+            let addr = ready!(self.udp.poll_recv_from(cx, &mut buf))?;
+            Poll::Ready(Ok((buf.filled().len(), addr, EcnCodepoint::NotEct)))
+        }
+
+        fn local_addr(&self) -> io::Result<SocketAddr> {
+            self.udp.local_addr()
+        }
+    }
+
+    async fn counting_transport() -> Arc<CountingTransport> {
+        Arc::new(CountingTransport {
+            udp: UdpSocket::bind("127.0.0.1:0").await.unwrap(),
+            sent: AtomicUsize::new(0),
+        })
+    }
+
+    #[tokio::test]
+    async fn custom_transport_echo() {
+        let _ = env_logger::try_init();
+
+        let config = KcpConfig {
+            udp_batch_size: 8,
+            ..Default::default()
+        };
+
+        let server = counting_transport().await;
+        let server_addr = server.local_addr().unwrap();
+        let mut listener = KcpListener::from_transport(config.clone(), server.clone()).await.unwrap();
+
+        let listener_hdl = tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let mut buffer = [0u8; 1024];
+            let n = stream.recv(&mut buffer).await.unwrap();
+            stream.send(&buffer[..n]).await.unwrap();
+        });
+
+        let client = counting_transport().await;
+        let mut stream = KcpStream::connect_with_transport(&config, client.clone(), server_addr)
+            .await
+            .unwrap();
+        stream.send(b"HELLO TRANSPORT").await.unwrap();
+
+        let mut buffer = [0u8; 1024];
+        let n = stream.recv(&mut buffer).await.unwrap();
+        assert_eq!(&buffer[..n], b"HELLO TRANSPORT");
+
+        listener_hdl.await.unwrap();
+        assert!(client.sent.load(Ordering::Relaxed) > 0);
+        assert!(server.sent.load(Ordering::Relaxed) > 0);
+    }
+}
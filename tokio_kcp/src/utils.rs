@@ -1,9 +1,54 @@
-use std::time::{SystemTime, UNIX_EPOCH};
+#[cfg(any(test, feature = "test-util"))]
+use std::sync::OnceLock;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+/// Current time of tokio's clock, virtual while the runtime's time is paused
+///
+/// Every session clock reads this, so tests with `tokio::time::pause` (the `test-util` feature) fast-forward
+/// sessions, their congestion control and a `SimNetwork` alike. Outside a paused runtime it is `Instant::now`.
+#[inline]
+pub fn now() -> Instant {
+    tokio::time::Instant::now().into_std()
+}
+
+/// Time passed since `since` on the clock of `now`
+#[inline]
+pub fn elapsed(since: Instant) -> Duration {
+    now().saturating_duration_since(since)
+}
+
+// Unix time (ms) of one instant, later unix times follow `now` from there
+#[cfg(any(test, feature = "test-util"))]
+fn unix_anchor() -> (Instant, u64) {
+    static ANCHOR: OnceLock<(Instant, u64)> = OnceLock::new();
+    *ANCHOR.get_or_init(|| (Instant::now(), system_millis()))
+}
+
+fn system_millis() -> u64 {
+    SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis() as u64
+}
 
 #[inline]
 pub fn now_millis() -> u32 {
-    let start = SystemTime::now();
-    let since_the_epoch = start.duration_since(UNIX_EPOCH).expect("time went afterwards");
-    // (since_the_epoch.as_secs() * 1000 + since_the_epoch.subsec_millis() as u64) as u32
-    since_the_epoch.as_millis() as u32
+    unix_millis() as u32
+}
+
+/// Unix time in milliseconds, without truncation
+///
+/// The system clock. With the `test-util` feature, which lets runtimes pause their time, it follows
+/// `now` from the system time of one instant instead.
+#[inline]
+pub fn unix_millis() -> u64 {
+    #[cfg(any(test, feature = "test-util"))]
+    {
+        let (anchor, anchor_ms) = unix_anchor();
+        let now = now();
+        if now >= anchor {
+            anchor_ms + (now - anchor).as_millis() as u64
+        } else {
+            anchor_ms.saturating_sub((anchor - now).as_millis() as u64)
+        }
+    }
+    #[cfg(not(any(test, feature = "test-util")))]
+    system_millis()
 }